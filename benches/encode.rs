@@ -0,0 +1,177 @@
+//! The encode path a sink's audio takes every PipeWire cycle: buffering into
+//! frames and encoding them, then fanning the results out. Throughput is in
+//! audio frames, so anything under the sample rate can't keep up in real time.
+use bytes::Bytes;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use pipewire_streaming::bounded::{self, Overflow};
+use pipewire_streaming::capture::SinkOutputs;
+use pipewire_streaming::capture::xrun::CaptureGlitches;
+use pipewire_streaming::config::Config;
+use pipewire_streaming::control::{Access, ControlBus};
+use pipewire_streaming::encode::Encoder;
+use pipewire_streaming::encode::compress::{CapturedAudio, EncodedPacket};
+use pipewire_streaming::health::Health;
+use std::f32::consts::TAU;
+use std::ffi::OsString;
+use std::sync::Arc;
+use streaming_protocol::Codec;
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
+
+/// Frames handed over at a time, PipeWire's usual quantum. It rarely lines up
+/// with encoded frames, so some are left buffered for the next cycle.
+const QUANTUM: usize = 1024;
+
+fn config(args: &[&str]) -> Arc<Config> {
+    let args: Vec<OsString> = ["pwtester", "--no-mdns"]
+        .iter()
+        .chain(args)
+        .map(OsString::from)
+        .collect();
+    let (config, _) = Config::load(&args).expect("Benchmark options are valid");
+    Arc::new(config)
+}
+
+/// A second of a 440 Hz sine at half of full scale, in quanta of `channels`
+/// interleaved channels.
+fn sine_quanta(rate: u32, channels: usize) -> Vec<Vec<i16>> {
+    let samples: Vec<i16> = (0..rate as usize)
+        .flat_map(|frame| {
+            let phase = (frame as f64 * 440.0 / rate as f64).fract() as f32;
+            let sample = ((phase * TAU).sin() * i16::MAX as f32 / 2.0) as i16;
+            std::iter::repeat_n(sample, channels)
+        })
+        .collect();
+    samples
+        .chunks_exact(QUANTUM * channels)
+        .map(<[i16]>::to_vec)
+        .collect()
+}
+
+/// Feeds an Opus encoder started with `args` a quantum per iteration and
+/// waits for every frame the quanta so far complete.
+fn bench_opus(c: &mut Criterion, runtime: &Runtime, name: &str, value: &str, args: &[&str]) {
+    let config = config(args);
+    let control = ControlBus::new(
+        config.encoder_settings(),
+        Vec::new(),
+        None,
+        Access {
+            key: Arc::from(config.access_key.as_str()),
+            password: None,
+        },
+    );
+    let sink = &config.sinks()[0];
+    let Encoder { input, mut output } = {
+        let _guard = runtime.enter();
+        Encoder::spawn(&config, sink, Codec::Opus, &control, &Health::new())
+    };
+    let quanta = sine_quanta(config.sample_rate, config.stream_channels() as usize);
+    let frame_samples = config.samples_per_frame();
+    let (mut sent_frames, mut received) = (0, 0);
+
+    let mut group = c.benchmark_group(format!("encode/{name}"));
+    group.throughput(Throughput::Elements(QUANTUM as u64));
+    group.bench_function(BenchmarkId::from_parameter(value), |b| {
+        b.iter(|| {
+            let samples = quanta[sent_frames / QUANTUM % quanta.len()].clone();
+            let captured_at_us = (sent_frames * 1_000_000 / config.sample_rate as usize) as u64;
+            input
+                .send(CapturedAudio {
+                    captured_at_us,
+                    samples,
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .expect("The encoder is running");
+            sent_frames += QUANTUM;
+            runtime.block_on(async {
+                while received < sent_frames / frame_samples {
+                    output
+                        .receiver
+                        .recv()
+                        .await
+                        .expect("The encoder is running");
+                    received += 1;
+                }
+            });
+        })
+    });
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Couldn't start tokio!");
+    for frame_ms in ["2.5", "10", "20", "60"] {
+        bench_opus(c, &runtime, "frame_ms", frame_ms, &["--frame-ms", frame_ms]);
+    }
+    for channels in ["1", "2"] {
+        bench_opus(c, &runtime, "channels", channels, &["--channels", channels]);
+    }
+    for bitrate in ["16000", "64000", "256000"] {
+        bench_opus(c, &runtime, "bitrate", bitrate, &["--bitrate", bitrate]);
+    }
+}
+
+fn fan_out(c: &mut Criterion) {
+    let quantum = sine_quanta(streaming_protocol::SAMPLE_RATE, 2).swap_remove(0);
+    let mut group = c.benchmark_group("fan_out/codecs");
+    group.throughput(Throughput::Elements(QUANTUM as u64));
+    for codecs in [1, 2, 4] {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..codecs)
+            .map(|_| bounded::channel("Benchmark encoder", 64, Overflow::DropOldest))
+            .unzip();
+        let (level_sender, _level_receiver) =
+            bounded::channel("Benchmark levels", 1, Overflow::DropNewest);
+        let outputs = SinkOutputs {
+            senders,
+            level_sender,
+            glitches: CaptureGlitches::new("bench"),
+        };
+        group.bench_function(BenchmarkId::from_parameter(codecs), |b| {
+            b.iter(|| {
+                outputs.send(CapturedAudio {
+                    captured_at_us: 0,
+                    samples: quantum.clone(),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                });
+                for receiver in &receivers {
+                    receiver.try_iter().for_each(drop);
+                }
+            })
+        });
+    }
+    group.finish();
+
+    // Every session gets each packet off its own receiver.
+    let mut group = c.benchmark_group("fan_out/sessions");
+    let packet = EncodedPacket {
+        codec: Codec::Opus,
+        sequence: 0,
+        captured_at_us: 0,
+        frame_samples: 480,
+        payloads: [Some(Bytes::from(vec![0; 160])), None, None],
+        surround: None,
+        decoder_reset: false,
+    };
+    for sessions in [1, 10, 100, 1000] {
+        let (sender, _) = broadcast::channel(200);
+        let mut receivers: Vec<_> = (0..sessions).map(|_| sender.subscribe()).collect();
+        group.throughput(Throughput::Elements(sessions as u64));
+        group.bench_function(BenchmarkId::from_parameter(sessions), |b| {
+            b.iter(|| {
+                sender
+                    .send(packet.clone())
+                    .expect("Sessions are subscribed");
+                for receiver in &mut receivers {
+                    receiver.try_recv().expect("The packet was sent");
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, fan_out);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+//! Embeds the web client in `web/` into the binary, see `src/transport/web_assets.rs`.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Lists the files under `dir`, with their paths relative to `root`.
+fn files(root: &Path, dir: &Path, found: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            files(root, &path, found);
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            let name = relative.to_string_lossy().replace('\\', "/");
+            found.push((name, path.canonicalize().expect("Web client file exists")));
+        }
+    }
+}
+
+fn main() {
+    let root = Path::new("web");
+    println!("cargo::rerun-if-changed=web");
+    let mut found = Vec::new();
+    files(root, root, &mut found);
+    found.sort();
+    let mut code = String::from("pub static WEB_ASSETS: &[(&str, &[u8])] = &[\n");
+    for (name, path) in &found {
+        code.push_str(&format!("    ({name:?}, include_bytes!({path:?})),\n"));
+    }
+    code.push_str("];\n");
+    let out = PathBuf::from(std::env::var("OUT_DIR").expect("Cargo sets OUT_DIR"));
+    fs::write(out.join("web_assets.rs"), code).expect("Couldn't write the web assets list");
+}
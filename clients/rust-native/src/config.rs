@@ -0,0 +1,274 @@
+use crate::gain::MAX_VOLUME_PERCENT;
+use crate::output::Backend;
+use clap::Parser;
+use std::path::PathBuf;
+use std::str::FromStr;
+use streaming_protocol::{
+    ClientCommand, MAX_TARGET_LATENCY_MS, MIN_TARGET_LATENCY_MS, WEBTRANSPORT_PORT,
+};
+use wtransport::tls::Sha256Digest;
+
+fn default_server() -> String {
+    format!("https://localhost:{}", WEBTRANSPORT_PORT)
+}
+
+/// Another server played alongside `--server`, as `--mix` takes it:
+/// `<url>[,key=<key>][,gain=<percent>]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixedServer {
+    /// WebTransport URL of the server, with the sink's id as its path to
+    /// play another sink than the first.
+    pub url: String,
+    /// Its access key, if not `--key`.
+    pub key: Option<String>,
+    /// Level it's mixed at in percent, up to 400.
+    pub gain_percent: u32,
+}
+
+impl FromStr for MixedServer {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut options = spec.split(',');
+        let url = options.next().unwrap_or_default().to_string();
+        if url.is_empty() {
+            return Err(String::from("expected a server URL"));
+        }
+        let mut server = Self {
+            url,
+            key: None,
+            gain_percent: 100,
+        };
+        for option in options {
+            match option.split_once('=') {
+                Some(("key", key)) => server.key = Some(key.to_string()),
+                Some(("gain", percent)) => {
+                    server.gain_percent = percent
+                        .parse()
+                        .ok()
+                        .filter(|&percent| percent <= MAX_VOLUME_PERCENT)
+                        .ok_or_else(|| format!("gain must be 0 to {}", MAX_VOLUME_PERCENT))?
+                }
+                _ => return Err(format!("unknown option {:?}, expected key or gain", option)),
+            }
+        }
+        Ok(server)
+    }
+}
+
+/// Plays a sink of a pipewire-streaming server. Commands typed on stdin while
+/// playing are sent to the server: pause, resume, restart, volume <percent>,
+/// source <sink>, latency <ms>, tier <n|auto> and surround <on|off>. gain <percent>, + and - set
+/// this client's own volume. With `--daemon` they come over D-Bus instead.
+#[derive(Parser, Debug, Clone)]
+pub struct Config {
+    /// Id of the server's sink to play, its first sink by default.
+    pub sink: Option<String>,
+
+    /// WebTransport URL of the server.
+    #[arg(long, default_value_t = default_server())]
+    pub server: String,
+
+    /// The server's access key, used to sign the session token.
+    #[arg(long, required_unless_present_any = ["discover", "list_devices", "replay"])]
+    pub key: Option<String>,
+
+    /// Another server to play, mixed with this one, as
+    /// `<url>[,key=<key>][,gain=<percent>]`, e.g. to monitor several rooms.
+    /// The URL's path picks the sink, and the key defaults to `--key`. It
+    /// shares the other options. Repeat for more servers.
+    #[arg(long, conflicts_with = "replay")]
+    pub mix: Vec<MixedServer>,
+
+    /// The server's `--password`, proven to it when it asks rather than sent.
+    #[arg(long)]
+    pub password: Option<String>,
+
+    /// SHA-256 hash of the server's self-signed certificate, as served at
+    /// `/api/cert-hash` (`[13,168,...]`) or as colon-separated hex. Without
+    /// it the certificate must be trusted by the system.
+    #[arg(long, conflicts_with = "insecure")]
+    pub cert_hash: Option<Sha256Digest>,
+
+    /// Accept any server certificate.
+    #[arg(long)]
+    pub insecure: bool,
+
+    /// Ask for audio as datagrams, trading reliability for latency.
+    #[arg(long)]
+    pub datagrams: bool,
+
+    /// Send the default input device to the server's virtual microphone.
+    #[arg(long)]
+    pub mic: bool,
+
+    /// Audio buffered ahead of playback to ride out network jitter.
+    #[arg(long, default_value_t = 40)]
+    pub jitter_ms: u32,
+
+    /// End-to-end latency to aim for, asked of the server: 30 ms suits a
+    /// wired desktop, 150 ms flaky Wi-Fi. The jitter buffer then holds what
+    /// the network delay leaves of it, in place of `--jitter-ms`.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(MIN_TARGET_LATENCY_MS as i64..=MAX_TARGET_LATENCY_MS as i64))]
+    pub latency_ms: Option<u32>,
+
+    /// Bitrate tier to stay on, 0 being the highest, rather than the one the
+    /// server picks for the link, e.g. a low one on mobile data.
+    #[arg(long)]
+    pub tier: Option<usize>,
+
+    /// Ask for the sink's 5.1 channels, for outputs with six or more, when
+    /// the server streams them (its `--surround`).
+    #[arg(long)]
+    pub surround: bool,
+
+    /// Playback volume in percent, up to 400. Only this client is affected,
+    /// unlike the `volume` command.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(..=MAX_VOLUME_PERCENT as i64))]
+    pub volume: u32,
+
+    /// Turn loud peaks down rather than letting them clip.
+    #[arg(long)]
+    pub limiter: bool,
+
+    /// What plays the audio: the system's audio API (ALSA on Linux), a JACK
+    /// server or a PipeWire stream. JACK and PipeWire need the client built
+    /// with the `jack` or `pipewire` feature.
+    #[arg(long, value_enum, default_value_t = Backend::Cpal)]
+    pub backend: Backend,
+
+    /// Output device to play through, the default one unless given. See
+    /// `--list-devices`. With `--backend pipewire` it's the name of the node
+    /// to play to.
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// Frames in each period the output plays, the backend's default unless
+    /// given. Smaller periods lower the latency but may crackle. A JACK
+    /// server's period is its own.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub buffer_frames: Option<u32>,
+
+    /// Print the output devices of `--backend`, then exit.
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Run headless, leaving stdin alone and taking play, pause and volume
+    /// over D-Bus as an MPRIS player, for desktops and remote controllers.
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Write the audio packets received, with the times they arrived, to this
+    /// file for `--replay`.
+    #[arg(long, conflicts_with = "replay")]
+    pub dump: Option<PathBuf>,
+
+    /// Play a file `--dump` wrote, at the pace its packets arrived, rather
+    /// than connecting to a server, then exit.
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// List the servers advertised on the LAN, then exit.
+    #[arg(long)]
+    pub discover: bool,
+}
+
+impl Config {
+    /// URL of the server's WebTransport endpoint for the sink.
+    pub fn sink_url(&self) -> String {
+        format!(
+            "{}/{}",
+            self.server.trim_end_matches('/'),
+            self.sink.as_deref().unwrap_or("")
+        )
+    }
+
+    /// The config of a session playing `server` into the mix, sending it
+    /// neither the microphone nor what's typed on stdin.
+    pub fn mixed(&self, server: &MixedServer) -> Self {
+        let (url, sink) = match server.url.trim_end_matches('/').rsplit_once('/') {
+            // Just the scheme's slashes, so no sink.
+            Some((url, _)) if url.ends_with('/') => (server.url.as_str(), None),
+            Some((url, sink)) => (url, Some(sink.to_string())),
+            None => (server.url.as_str(), None),
+        };
+        Self {
+            sink,
+            server: url.to_string(),
+            key: server.key.clone().or_else(|| self.key.clone()),
+            mix: Vec::new(),
+            mic: false,
+            dump: None,
+            ..self.clone()
+        }
+    }
+
+    /// Commands asked for on the command line, sent as each session starts.
+    pub fn initial_commands(&self) -> Vec<ClientCommand> {
+        let latency = self
+            .latency_ms
+            .map(|ms| ClientCommand::SetTargetLatency { ms });
+        let tier = self
+            .tier
+            .map(|tier| ClientCommand::SelectTier { tier: Some(tier) });
+        let surround = self
+            .surround
+            .then_some(ClientCommand::SetSurround { enabled: true });
+        latency.into_iter().chain(tier).chain(surround).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cert_hash_takes_both_formats() {
+        let hash = "[13,168,113,2,213,136,124,10,80,208,200,56,29,68,119,16,194,119,112,219,4,\
+                    102,187,137,91,248,119,10,167,127,119,240]";
+        let bytes = Config::try_parse_from(["client", "--key=k", "--cert-hash", hash])
+            .unwrap()
+            .cert_hash
+            .unwrap();
+        let hex = bytes
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        let hex = Config::try_parse_from(["client", "--key=k", "--cert-hash", &hex])
+            .unwrap()
+            .cert_hash
+            .unwrap();
+        assert_eq!(bytes, hex);
+        assert!(Config::try_parse_from(["client", "--key=k", "--cert-hash", "[1,2]"]).is_err());
+        assert!(Config::try_parse_from(["client", "--list-devices"]).is_ok());
+        assert!(Config::try_parse_from(["client", "--replay", "dump"]).is_ok());
+        assert!(Config::try_parse_from(["client"]).is_err());
+    }
+
+    #[test]
+    fn mixed_servers_take_a_key_and_gain() {
+        let config = Config::try_parse_from([
+            "client",
+            "--key=k",
+            "--mic",
+            "--mix=https://kitchen:13345/radio,gain=50",
+            "--mix=https://garage:13345/,key=other",
+        ])
+        .unwrap();
+        let kitchen = config.mixed(&config.mix[0]);
+        assert_eq!(config.mix[0].gain_percent, 50);
+        assert_eq!(kitchen.sink_url(), "https://kitchen:13345/radio");
+        assert_eq!(kitchen.key.as_deref(), Some("k"));
+        assert!(!kitchen.mic);
+        let garage = config.mixed(&config.mix[1]);
+        assert_eq!(config.mix[1].gain_percent, 100);
+        assert_eq!(garage.sink_url(), "https://garage:13345/");
+        assert_eq!(garage.key.as_deref(), Some("other"));
+
+        assert!("https://a,gain=500".parse::<MixedServer>().is_err());
+        assert!("https://a,volume=5".parse::<MixedServer>().is_err());
+        assert!(",gain=5".parse::<MixedServer>().is_err());
+    }
+}
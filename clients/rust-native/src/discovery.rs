@@ -0,0 +1,43 @@
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use std::time::{Duration, Instant};
+use streaming_protocol as protocol;
+
+/// How long `--discover` listens for servers.
+const BROWSE_TIME: Duration = Duration::from_secs(3);
+
+/// Prints the servers advertising themselves on the LAN.
+pub fn print_servers() -> Result<()> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(protocol::MDNS_SERVICE_TYPE)?;
+    println!("Looking for servers...");
+    let deadline = Instant::now() + BROWSE_TIME;
+    let mut found = 0;
+    while let Ok(event) = events.recv_deadline(deadline) {
+        let ServiceEvent::ServiceResolved(info) = event else {
+            continue;
+        };
+        found += 1;
+        let property = |key| info.get_property_val_str(key).unwrap_or("?");
+        let mut addresses: Vec<String> = info
+            .get_addresses()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        addresses.sort();
+        println!(
+            "{}\n  addresses: {}\n  WebTransport port: {}, HTTPS port: {}\n  protocol version: {}, sinks: {}",
+            info.get_fullname(),
+            addresses.join(", "),
+            info.get_port(),
+            property(protocol::MDNS_HTTP_PORT_KEY),
+            property(protocol::MDNS_VERSION_KEY),
+            property(protocol::MDNS_SINKS_KEY),
+        );
+    }
+    if found == 0 {
+        println!("No servers found");
+    }
+    let _ = daemon.shutdown();
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use anyhow::{Context, Result, bail};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use streaming_protocol::{Codec, HEADER_LEN, PacketHeader};
+
+/// Starts every dump, the last byte being the format's version.
+const MAGIC: [u8; 8] = *b"PWSDUMP\x01";
+const RECORD_SESSION: u8 = 0;
+const RECORD_PACKET: u8 = 1;
+
+/// What a dump holds, each record tagged with when it arrived.
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    /// A session started with this stream info.
+    Session {
+        codec: Codec,
+        sample_rate: u32,
+        channels: u8,
+    },
+    /// A packet as the server sent it.
+    Packet(PacketHeader, Vec<u8>),
+}
+
+/// Writes the packets received to a file for `--replay`. A record is the
+/// time it arrived in microseconds since the Unix epoch, its kind, then the
+/// stream info or the packet framed as on the wire, all big-endian.
+pub struct DumpWriter {
+    file: File,
+    record: Vec<u8>,
+}
+
+impl DumpWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create dump file {}", path.display()))?;
+        file.write_all(&MAGIC)?;
+        Ok(Self {
+            file,
+            record: Vec::new(),
+        })
+    }
+
+    pub fn write_session(
+        &mut self,
+        arrived_at_us: u64,
+        codec: Codec,
+        sample_rate: u32,
+        channels: u8,
+    ) -> Result<()> {
+        self.start_record(arrived_at_us, RECORD_SESSION);
+        self.record.push(codec as u8);
+        self.record.extend_from_slice(&sample_rate.to_be_bytes());
+        self.record.push(channels);
+        self.finish_record()
+    }
+
+    pub fn write_packet(
+        &mut self,
+        arrived_at_us: u64,
+        header: &PacketHeader,
+        payload: &[u8],
+    ) -> Result<()> {
+        self.start_record(arrived_at_us, RECORD_PACKET);
+        self.record.extend_from_slice(&header.encode());
+        self.record.extend_from_slice(payload);
+        self.finish_record()
+    }
+
+    fn start_record(&mut self, arrived_at_us: u64, kind: u8) {
+        self.record.clear();
+        self.record.extend_from_slice(&arrived_at_us.to_be_bytes());
+        self.record.push(kind);
+    }
+
+    /// Writes the record whole, so a client killed mid-stream leaves a dump
+    /// that replays up to its last packet.
+    fn finish_record(&mut self) -> Result<()> {
+        Ok(self.file.write_all(&self.record)?)
+    }
+}
+
+/// Reads back what a `DumpWriter` wrote.
+pub struct DumpReader<R> {
+    reader: R,
+}
+
+impl DumpReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open dump file {}", path.display()))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> DumpReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .context("Dump file is too short")?;
+        if magic != MAGIC {
+            bail!("Not a dump file, or one of another version");
+        }
+        Ok(Self { reader })
+    }
+
+    /// Returns the next record and when it arrived, or `None` at the end. A
+    /// record cut short, as by a client killed while writing it, ends the
+    /// dump too.
+    pub fn next_record(&mut self) -> Result<Option<(u64, Record)>> {
+        let mut start = [0; 9];
+        if !self.read_whole(&mut start)? {
+            return Ok(None);
+        }
+        let arrived_at_us = u64::from_be_bytes(start[..8].try_into().unwrap());
+        let record = match start[8] {
+            RECORD_SESSION => {
+                let mut info = [0; 6];
+                if !self.read_whole(&mut info)? {
+                    return Ok(None);
+                }
+                Record::Session {
+                    codec: Codec::try_from(info[0])?,
+                    sample_rate: u32::from_be_bytes(info[1..5].try_into().unwrap()),
+                    channels: info[5],
+                }
+            }
+            RECORD_PACKET => {
+                let mut header = [0; HEADER_LEN];
+                if !self.read_whole(&mut header)? {
+                    return Ok(None);
+                }
+                let header = PacketHeader::decode(&header).context("Malformed packet header")?;
+                let mut payload = vec![0; header.payload_len as usize];
+                if !self.read_whole(&mut payload)? {
+                    return Ok(None);
+                }
+                Record::Packet(header, payload)
+            }
+            other => bail!("Unknown dump record kind {}", other),
+        };
+        Ok(Some((arrived_at_us, record)))
+    }
+
+    /// Fills `buffer`, returning false if the dump ends first.
+    fn read_whole(&mut self, buffer: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buffer) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let path = std::env::temp_dir().join(format!("pws-dump-{}", std::process::id()));
+        let rate = streaming_protocol::SAMPLE_RATE;
+        let session = Record::Session {
+            codec: Codec::Opus,
+            sample_rate: rate,
+            channels: 2,
+        };
+        let header = PacketHeader {
+            codec: Codec::Pcm,
+            sequence: 7,
+            captured_at_us: 1_000,
+            frame_samples: 1,
+            payload_len: 3,
+            decoder_reset: true,
+        };
+        let mut writer = DumpWriter::create(&path).unwrap();
+        writer.write_session(10, Codec::Opus, rate, 2).unwrap();
+        writer.write_packet(20, &header, &[1, 2, 3]).unwrap();
+        drop(writer);
+        let mut bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The last packet was cut short.
+        bytes.pop();
+        let mut reader = DumpReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.next_record().unwrap(), Some((10, session)));
+        assert_eq!(reader.next_record().unwrap(), None);
+        bytes.push(3);
+        let mut reader = DumpReader::new(&bytes[..]).unwrap();
+        reader.next_record().unwrap();
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some((20, Record::Packet(header, vec![1, 2, 3])))
+        );
+        assert_eq!(reader.next_record().unwrap(), None);
+        assert!(DumpReader::new(&b"PWSDUMP\x00"[..]).is_err());
+    }
+}
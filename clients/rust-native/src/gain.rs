@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The loudest `--volume` and the `gain` command go, four times amplified.
+pub const MAX_VOLUME_PERCENT: u32 = 400;
+/// Peaks the limiter holds the output under, just below full scale.
+const LIMIT: f32 = 0.9 * i16::MAX as f32;
+/// Share of the limiter's envelope kept per sample, letting the gain recover
+/// within about 50 ms at 48 kHz.
+const RELEASE: f32 = 0.9996;
+
+/// The playback volume in percent, shared by the playback thread and the
+/// commands typed on stdin.
+#[derive(Clone)]
+pub struct Volume(Arc<AtomicU32>);
+
+impl Volume {
+    pub fn new(percent: u32) -> Self {
+        Self(Arc::new(AtomicU32::new(percent.min(MAX_VOLUME_PERCENT))))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Sets the volume, capped at `MAX_VOLUME_PERCENT`, and returns it.
+    pub fn set(&self, percent: u32) -> u32 {
+        let percent = percent.min(MAX_VOLUME_PERCENT);
+        self.0.store(percent, Ordering::Relaxed);
+        percent
+    }
+}
+
+/// Scales the mixed audio by the volume. Samples pushed beyond full scale
+/// clip, unless the limiter turns the gain down just enough to hold the peaks
+/// under `LIMIT`, recovering gradually afterwards.
+pub struct Gain {
+    volume: Volume,
+    limiter: bool,
+    /// Recent peak level of the scaled audio, across channels.
+    envelope: f32,
+}
+
+impl Gain {
+    pub fn new(volume: Volume, limiter: bool) -> Self {
+        Self {
+            volume,
+            limiter,
+            envelope: 0.0,
+        }
+    }
+
+    /// Writes `mixed`, which may go beyond full scale, to `out` at the volume.
+    pub fn apply(&mut self, mixed: &[f32], out: &mut [i16]) {
+        let gain = self.volume.get() as f32 / 100.0;
+        for (&sample, out) in mixed.iter().zip(out) {
+            let mut scaled = sample * gain;
+            if self.limiter {
+                self.envelope = (self.envelope * RELEASE).max(scaled.abs());
+                if self.envelope > LIMIT {
+                    scaled *= LIMIT / self.envelope;
+                }
+            }
+            *out = scaled.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_and_clips_without_the_limiter() {
+        let volume = Volume::new(50);
+        let mut gain = Gain::new(volume.clone(), false);
+        let mut samples = [0; 3];
+        gain.apply(&[1000.0, -1000.0, 20_000.0], &mut samples);
+        assert_eq!(samples, [500, -500, 10_000]);
+
+        assert_eq!(volume.set(1000), MAX_VOLUME_PERCENT);
+        gain.apply(&[500.0, -500.0, 10_000.0], &mut samples);
+        assert_eq!(samples, [2000, -2000, i16::MAX]);
+        // Streams mixed beyond full scale come back at a lower volume.
+        volume.set(50);
+        gain.apply(&[60_000.0], &mut samples);
+        assert_eq!(samples[0], 30_000);
+    }
+
+    #[test]
+    fn limiter_holds_peaks_under_the_limit_and_recovers() {
+        let mut gain = Gain::new(Volume::new(300), true);
+        let mut loud = [0; 4];
+        gain.apply(&[-20_000.0, 20_000.0, 1000.0, -1000.0], &mut loud);
+        assert!(loud.iter().all(|&sample| (sample as f32).abs() <= LIMIT));
+        // The quiet samples right after the peak are turned down with it.
+        assert!(loud[2] < 3000);
+
+        let mut quiet = vec![0; 48_000];
+        gain.apply(&[1000.0; 48_000], &mut quiet);
+        assert_eq!(quiet.last(), Some(&3000));
+    }
+}
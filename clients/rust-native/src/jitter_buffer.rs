@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use streaming_protocol::DriftEstimator;
+
+/// The target depth grows by this much after each underrun, and shrinks back
+/// by it once playback has been stable for `STABLE_PERIOD`.
+const TARGET_STEP_MS: u32 = 10;
+/// The target never grows beyond this many times the configured depth.
+const MAX_TARGET_FACTOR: usize = 4;
+const STABLE_PERIOD: Duration = Duration::from_secs(10);
+/// Most the playback rate is stretched to steer the depth toward the target,
+/// about 17 cents of pitch.
+const MAX_STRETCH: f64 = 0.01;
+/// Depth error, relative to the target, at which the stretch is largest.
+const FULL_STRETCH_ERROR: f64 = 0.5;
+/// Depth errors below this, relative to the target, are left alone.
+const SETTLED_ERROR: f64 = 0.1;
+/// Weight of each pull in the running average of the depth.
+const DEPTH_SMOOTHING: f64 = 0.05;
+/// Beyond this many times the target, the excess is skipped at once rather
+/// than played out faster.
+const SKIP_FACTOR: usize = 3;
+
+/// Decoded audio waiting to be played. Fills up to a target depth before
+/// playing, then resamples to the output's rate and to the rate of the
+/// server's clock as estimated from packet timestamps, and stretches playback slightly further to keep the depth
+/// near the target, so clock drift between server and client can't starve or
+/// flood it. The target grows when the network underruns it and shrinks back
+/// while it doesn't.
+pub struct JitterBuffer {
+    channels: usize,
+    /// Interleaved samples.
+    queue: VecDeque<i16>,
+    /// Depths in frames.
+    target: usize,
+    min_target: usize,
+    max_target: usize,
+    step: usize,
+    /// Set until the target depth is reached, at the start and after an
+    /// underrun.
+    buffering: bool,
+    /// Set once no more audio is coming, so the rest is played regardless.
+    draining: bool,
+    /// Position between the first two queued frames.
+    phase: f64,
+    average_depth: f64,
+    sample_rate: u32,
+    /// Rate the output plays at, the stream's unless it can't.
+    output_rate: u32,
+    /// Frames handed to the output, which is the playback clock.
+    played_frames: u64,
+    drift: DriftEstimator,
+    /// Frames played since the last change of the target.
+    stable_frames: usize,
+    stable_period: usize,
+    underruns: u64,
+    skipped_frames: u64,
+}
+
+fn frames(sample_rate: u32, ms: u32) -> usize {
+    (sample_rate as u64 * ms as u64 / 1000) as usize
+}
+
+impl JitterBuffer {
+    pub fn new(channels: usize, sample_rate: u32, target_ms: u32) -> Self {
+        let target = frames(sample_rate, target_ms).max(1);
+        Self {
+            channels,
+            queue: VecDeque::new(),
+            target,
+            min_target: target,
+            max_target: target * MAX_TARGET_FACTOR,
+            step: frames(sample_rate, TARGET_STEP_MS),
+            buffering: true,
+            draining: false,
+            phase: 0.0,
+            average_depth: 0.0,
+            sample_rate,
+            output_rate: sample_rate,
+            played_frames: 0,
+            drift: DriftEstimator::default(),
+            stable_frames: 0,
+            stable_period: sample_rate as usize * STABLE_PERIOD.as_secs() as usize,
+            underruns: 0,
+            skipped_frames: 0,
+        }
+    }
+
+    /// Plays at `rate` rather than the stream's rate. Set before playing.
+    pub fn set_output_rate(&mut self, rate: u32) {
+        self.output_rate = rate;
+        self.stable_period = rate as usize * STABLE_PERIOD.as_secs() as usize;
+    }
+
+    /// Queues samples the server captured at `captured_at_us`.
+    pub fn push(&mut self, samples: &[i16], captured_at_us: u64) {
+        let played_us = self.played_frames * 1_000_000 / self.output_rate as u64;
+        self.drift.observe(captured_at_us, played_us);
+        self.queue.extend(samples);
+    }
+
+    /// Aims for `target_ms` of audio from now on, as if created with it. The
+    /// queued audio is stretched toward the new depth rather than cut.
+    pub fn set_target_ms(&mut self, target_ms: u32) {
+        self.target = frames(self.sample_rate, target_ms).max(1);
+        self.min_target = self.target;
+        self.max_target = self.target * MAX_TARGET_FACTOR;
+        self.stable_frames = 0;
+    }
+
+    /// Plays out whatever is left, even below the target depth.
+    pub fn drain(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Frames waiting to be played.
+    pub fn depth(&self) -> usize {
+        self.queue.len() / self.channels
+    }
+
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped_frames
+    }
+
+    /// How much faster the playback clock runs than the server's, in parts
+    /// per million.
+    pub fn drift_ppm(&self) -> f64 {
+        (self.drift.ratio() - 1.0) * 1e6
+    }
+
+    /// Fills `out` with interleaved frames, and silence while buffering.
+    pub fn pull(&mut self, out: &mut [i16]) {
+        self.played_frames += (out.len() / self.channels) as u64;
+        if self.buffering {
+            if self.depth() < self.target && !self.draining {
+                out.fill(0);
+                return;
+            }
+            self.buffering = false;
+            self.average_depth = self.depth() as f64;
+        }
+        if self.depth() > self.target * SKIP_FACTOR {
+            let excess = self.depth() - self.target;
+            self.queue.drain(..excess * self.channels);
+            self.skipped_frames += excess as u64;
+            self.average_depth = self.target as f64;
+        }
+        self.average_depth += (self.depth() as f64 - self.average_depth) * DEPTH_SMOOTHING;
+        let error = (self.average_depth - self.target as f64) / self.target as f64;
+        let settled = error.abs() < SETTLED_ERROR || self.draining;
+        // Frames consumed per frame played, at the server's clock rate.
+        let drift_rate = self.sample_rate as f64 / self.output_rate as f64 / self.drift.ratio();
+        // Without drift or rate conversion, settled audio plays unfiltered.
+        let unstretched = settled && drift_rate == 1.0;
+        let rate = if unstretched {
+            // Finishes the current stretch first.
+            if self.phase == 0.0 {
+                1.0
+            } else {
+                1.0 + MAX_STRETCH
+            }
+        } else if settled {
+            drift_rate
+        } else {
+            drift_rate * (1.0 + (error / FULL_STRETCH_ERROR).clamp(-1.0, 1.0) * MAX_STRETCH)
+        };
+
+        let channels = self.channels;
+        for start in (0..out.len()).step_by(channels) {
+            // Interpolating needs the frame after the current one.
+            if self.depth() < 2 {
+                out[start..].fill(0);
+                if self.draining {
+                    self.queue.clear();
+                } else {
+                    self.underrun();
+                }
+                return;
+            }
+            for (channel, sample) in out[start..start + channels].iter_mut().enumerate() {
+                let current = self.queue[channel] as f64;
+                let next = self.queue[channels + channel] as f64;
+                *sample = (current + (next - current) * self.phase).round() as i16;
+            }
+            self.phase += rate;
+            while self.phase >= 1.0 {
+                self.queue.drain(..channels);
+                self.phase = if unstretched { 0.0 } else { self.phase - 1.0 };
+            }
+        }
+
+        self.stable_frames += out.len() / channels;
+        if self.stable_frames >= self.stable_period && self.target > self.min_target {
+            self.target = self.target.saturating_sub(self.step).max(self.min_target);
+            self.stable_frames = 0;
+        }
+    }
+
+    fn underrun(&mut self) {
+        self.underruns += 1;
+        self.buffering = true;
+        self.phase = 0.0;
+        self.target = (self.target + self.step).min(self.max_target);
+        self.stable_frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+    /// 10 ms of stereo.
+    const BLOCK: usize = 960;
+
+    fn ramp(start: usize, len: usize) -> Vec<i16> {
+        (start..start + len).map(|n| (n % 1000) as i16).collect()
+    }
+
+    /// Capture time of the `block`th 10 ms block.
+    fn at(block: usize) -> u64 {
+        block as u64 * 10_000
+    }
+
+    #[test]
+    fn holds_back_until_the_target_is_reached() {
+        let mut buffer = JitterBuffer::new(2, RATE, 40);
+        let mut out = vec![1; BLOCK];
+        buffer.push(&ramp(0, BLOCK * 3), at(0));
+        buffer.pull(&mut out);
+        assert!(out.iter().all(|&sample| sample == 0));
+        buffer.push(&ramp(BLOCK * 3, BLOCK), at(3));
+        buffer.pull(&mut out);
+        // At the target depth audio plays unstretched.
+        assert_eq!(out, ramp(0, BLOCK));
+    }
+
+    #[test]
+    fn underruns_raise_the_target_until_playback_is_stable() {
+        let mut buffer = JitterBuffer::new(2, RATE, 40);
+        let mut out = vec![0; BLOCK];
+        buffer.push(&ramp(0, BLOCK * 4), at(0));
+        for _ in 0..5 {
+            buffer.pull(&mut out);
+        }
+        assert_eq!(buffer.underruns(), 1);
+        assert_eq!(buffer.target(), 2400);
+
+        // Steady delivery for long enough brings the target back down.
+        for block in 0..1100 {
+            buffer.push(&ramp(block * BLOCK, BLOCK), at(block + 4));
+            buffer.pull(&mut out);
+        }
+        assert_eq!(buffer.underruns(), 1);
+        assert_eq!(buffer.target(), 1920);
+    }
+
+    #[test]
+    fn a_new_target_is_reached_by_stretching() {
+        let mut buffer = JitterBuffer::new(2, RATE, 40);
+        let mut out = vec![0; BLOCK];
+        buffer.push(&ramp(0, BLOCK * 4), at(0));
+        buffer.set_target_ms(100);
+        assert_eq!(buffer.target(), 4800);
+        for block in 0..1000 {
+            buffer.push(&ramp((block + 4) * BLOCK, BLOCK), at(block + 4));
+            buffer.pull(&mut out);
+        }
+        assert_eq!((buffer.underruns(), buffer.skipped_frames()), (0, 0));
+        // Settled within a tenth of the target.
+        assert!(buffer.depth().abs_diff(4800) <= 480, "{}", buffer.depth());
+    }
+
+    #[test]
+    fn drift_is_absorbed_by_stretching() {
+        for extra_frames in [-3, 3] {
+            let mut buffer = JitterBuffer::new(2, RATE, 40);
+            let mut out = vec![0; BLOCK];
+            let pushed = (BLOCK as isize + extra_frames * 2) as usize;
+            // A minute of audio from a sample clock 0.6% off the playback
+            // clock, with timestamps that don't show it.
+            for block in 0..6000 {
+                buffer.pull(&mut out);
+                buffer.push(&ramp(block * pushed, pushed), at(block));
+            }
+            assert_eq!(buffer.skipped_frames(), 0);
+            assert!(buffer.underruns() <= 1, "{} underruns", buffer.underruns());
+            let depth = buffer.depth() as f64 / buffer.target() as f64;
+            assert!((0.5..1.5).contains(&depth), "depth {depth} of the target");
+        }
+    }
+
+    #[test]
+    fn drift_is_estimated_from_timestamps_and_resampled() {
+        for extra_frames in [-1, 1] {
+            let mut buffer = JitterBuffer::new(2, RATE, 40);
+            let mut out = vec![0; BLOCK];
+            let mut odd_out = vec![0; (BLOCK as isize + extra_frames * 2) as usize];
+            // Two minutes of 10 ms blocks played by a clock off by a frame
+            // every fourth block, about 500 ppm.
+            for block in 0..12_000 {
+                if block % 4 == 0 {
+                    buffer.pull(&mut odd_out);
+                } else {
+                    buffer.pull(&mut out);
+                }
+                buffer.push(&ramp(block * BLOCK, BLOCK), at(block));
+            }
+            let expected = extra_frames as f64 / 1920.0 * 1e6;
+            let drift = buffer.drift_ppm();
+            assert!((drift - expected).abs() < 50.0, "{drift} ppm");
+            assert_eq!(buffer.skipped_frames(), 0);
+            assert!(buffer.underruns() <= 1, "{} underruns", buffer.underruns());
+            // Once the drift is known, resampling alone holds the depth in
+            // the settled zone around the target.
+            let depth = buffer.depth() as f64 / buffer.target() as f64;
+            assert!((0.85..1.15).contains(&depth), "depth {depth} of the target");
+        }
+    }
+
+    #[test]
+    fn plays_at_the_output_rate() {
+        let mut buffer = JitterBuffer::new(1, RATE, 40);
+        buffer.set_output_rate(44_100);
+        // A second of 48 kHz audio in 10 ms blocks takes as long at 44.1 kHz.
+        let mut out = vec![0; 441];
+        for block in 0..100 {
+            buffer.push(&ramp(block * 480, 480), at(block));
+            buffer.pull(&mut out);
+        }
+        assert_eq!((buffer.underruns(), buffer.skipped_frames()), (0, 0));
+        let depth = buffer.depth() as f64 / buffer.target() as f64;
+        assert!((0.5..1.5).contains(&depth), "depth {depth} of the target");
+    }
+
+    #[test]
+    fn bursts_far_beyond_the_target_are_skipped() {
+        let mut buffer = JitterBuffer::new(1, RATE, 20);
+        let mut out = vec![0; 480];
+        buffer.push(&ramp(0, 960), at(0));
+        buffer.pull(&mut out);
+        buffer.push(&ramp(960, 4800), at(2));
+        buffer.pull(&mut out);
+        assert_eq!(buffer.skipped_frames(), 4800 + 480 - 960);
+        assert!(buffer.depth() < 960);
+    }
+
+    #[test]
+    fn draining_plays_out_the_rest() {
+        let mut buffer = JitterBuffer::new(1, RATE, 40);
+        let mut out = vec![0; 480];
+        buffer.push(&ramp(1, 480), at(0));
+        buffer.drain();
+        buffer.pull(&mut out);
+        assert_eq!(&out[..479], &ramp(1, 479)[..]);
+        assert_eq!(buffer.depth(), 0);
+    }
+}
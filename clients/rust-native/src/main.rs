@@ -1,123 +1,1184 @@
-use anyhow::{Context, Result, bail};
-use rodio::{OutputStream, Sink, buffer::SamplesBuffer};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::Parser;
+use config::Config;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use dump::{DumpReader, DumpWriter, Record};
+use gain::{Gain, Volume};
+use jitter_buffer::JitterBuffer;
+use mpris::PlayerState;
+use output::{Backend, Mixer, Output};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use streaming_protocol::{
+    self as protocol, Backoff, ClientCommand, Codec, ControlMessage, FRAME_MS, MAX_FRAME_MS,
+    PacketHeader, ReceiverReport, ReceptionStats, SAMPLE_RATE, SURROUND_CHANNELS, SequenceEvent,
+};
+use surround::{OpusDecode, SurroundDecoder};
+use tokio::task::JoinSet;
 use wtransport::ClientConfig;
-use wtransport::tls::Sha256Digest;
 
-const SERVER_URL: &str = "https://localhost:13345";
-const SAMPLE_RATE: u32 = 48_000;
-const OPUS_CHANNELS: opus::Channels = opus::Channels::Mono;
-const PLAYBACK_CHANNELS: u16 = 1;
-const OPUS_FRAME_MS_SERVER: u32 = 10;
-const SAMPLES_PER_FRAME_EXPECTED: usize = (SAMPLE_RATE * OPUS_FRAME_MS_SERVER / 1000) as usize;
+mod config;
+mod discovery;
+mod dump;
+mod gain;
+mod jitter_buffer;
+mod mpris;
+mod output;
+#[cfg(feature = "pipewire")]
+mod pipewire_output;
+mod surround;
 
-const SERVER_CERT_HASH_BYTES: [u8; 32] = [
-    13, 168, 113, 2, 213, 136, 124, 10, 80, 208, 200, 56, 29, 68, 119, 16, 194, 119, 112, 219, 4,
-    102, 187, 137, 91, 248, 119, 10, 167, 127, 119, 240,
-];
+/// Samples in each microphone frame. Stream frames say how long they are.
+const MIC_FRAME_SAMPLES: usize = (SAMPLE_RATE * FRAME_MS / 1000) as usize;
 
-const MAX_PCM_SAMPLES_PER_FRAME: usize = (48_000 * 120) / 1000;
+const LATENCY_REPORT_INTERVAL: u64 = 100;
 
-fn playback_thread(
-    pcm_receiver: crossbeam_channel::Receiver<Vec<i16>>,
-    sample_rate: u32,
+/// Codecs this client can play, in the order it prefers them.
+const DECODABLE_CODECS: [Codec; 3] = [Codec::Opus, Codec::Flac, Codec::Pcm];
+
+/// Lost packets beyond this many are skipped rather than concealed.
+const MAX_CONCEALED_PACKETS: u32 = 5;
+
+/// Room for the longest frame of surround audio, the most channels sent.
+const MAX_PCM_SAMPLES_PER_FRAME: usize =
+    (SAMPLE_RATE * MAX_FRAME_MS * SURROUND_CHANNELS as u32 / 1000) as usize;
+
+/// Decoded, interleaved PCM tagged with its channel count.
+struct PcmChunk {
     channels: u16,
+    samples: Vec<i16>,
+    /// Server capture time in microseconds since the Unix epoch.
+    captured_at_us: u64,
+}
+
+/// What the network side hands the playback thread.
+enum Playback {
+    /// A session started streaming at this rate. Whatever an earlier session
+    /// left in the jitter buffer is dropped.
+    Session {
+        sample_rate: u32,
+    },
+    /// Audio to buffer from now on, making up the target latency the server
+    /// granted.
+    JitterTarget {
+        ms: u32,
+    },
+    Audio(PcmChunk),
+}
+
+/// Hands the playback thread what a session plays, as one of the streams it
+/// mixes: the first for `--server`, then one for each `--mix`.
+#[derive(Clone)]
+struct PlaybackSender {
+    stream: usize,
+    sender: crossbeam_channel::Sender<(usize, Playback)>,
+}
+
+impl PlaybackSender {
+    fn send(
+        &self,
+        playback: Playback,
+    ) -> Result<(), crossbeam_channel::SendError<(usize, Playback)>> {
+        self.sender.send((self.stream, playback))
+    }
+}
+
+/// A stream the playback thread plays.
+struct StreamPlayback {
+    /// The channel count of the jitter buffer playing, which starts over with
+    /// every session and when the stream's channel count changes.
+    channels: Option<u16>,
+    sample_rate: u32,
+    jitter_ms: u32,
+    chunk_count: u64,
+    /// What the jitter buffer holds, for the receiver reports.
+    buffered_ms: Arc<AtomicU32>,
+}
+
+fn unix_time_us() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Plays the decoded audio of every stream, mixed at `gains_percent` and
+/// scaled by `gain`, keeping each of `buffered_ms` at what the stream's
+/// jitter buffer holds for the receiver reports.
+#[allow(clippy::too_many_arguments)]
+fn playback_thread(
+    receiver: crossbeam_channel::Receiver<(usize, Playback)>,
+    buffered_ms: Vec<Arc<AtomicU32>>,
+    gains_percent: &[u32],
+    jitter_ms: u32,
+    backend: Backend,
+    device: Option<String>,
+    buffer_frames: Option<u32>,
+    gain: Gain,
 ) -> Result<()> {
-    let (_stream, stream_handle) =
-        OutputStream::try_default().context("Failed to get default audio output stream")?;
-    let sink = Sink::try_new(&stream_handle).context("Failed to create audio sink")?;
+    let mixer = Mixer::new(gains_percent, gain);
+    let output = Output::open(backend, device.as_deref(), buffer_frames, mixer)?;
+    let mut streams: Vec<_> = buffered_ms
+        .into_iter()
+        .map(|buffered_ms| StreamPlayback {
+            channels: None,
+            sample_rate: SAMPLE_RATE,
+            jitter_ms,
+            chunk_count: 0,
+            buffered_ms,
+        })
+        .collect();
 
-    for pcm_data in pcm_receiver {
-        if pcm_data.is_empty() {
+    for (stream, event) in receiver {
+        let playback = &mut streams[stream];
+        let PcmChunk {
+            channels,
+            samples,
+            captured_at_us,
+        } = match event {
+            Playback::Session { sample_rate } => {
+                output.stop(stream);
+                playback.channels = None;
+                playback.sample_rate = sample_rate;
+                continue;
+            }
+            Playback::JitterTarget { ms } => {
+                playback.jitter_ms = ms;
+                if let Some(buffer) = output.mixer().buffer(stream) {
+                    buffer.set_target_ms(ms);
+                }
+                continue;
+            }
+            Playback::Audio(chunk) => chunk,
+        };
+        if samples.is_empty() {
             println!("[PlaybackThread] Received empty PCM data, skipping.");
             continue;
         }
-        println!(
-            "[PlaybackThread] Received {} PCM samples. Queue size: {}",
-            pcm_data.len(),
-            sink.len()
+        let StreamPlayback {
+            sample_rate,
+            jitter_ms,
+            ..
+        } = *playback;
+        if playback.channels != Some(channels) {
+            output.play(
+                stream,
+                JitterBuffer::new(channels as usize, sample_rate, jitter_ms),
+            );
+            playback.channels = Some(channels);
+            println!(
+                "[PlaybackThread] Playing {} channel(s) of stream {} through a {} ms jitter buffer.",
+                channels, stream, jitter_ms
+            );
+        }
+        let mut mixer = output.mixer();
+        let buffer = mixer
+            .buffer(stream)
+            .expect("A jitter buffer plays once audio arrives");
+        buffer.push(&samples, captured_at_us);
+        playback.buffered_ms.store(
+            (buffer.depth() as u64 * 1000 / sample_rate as u64) as u32,
+            Ordering::Relaxed,
         );
 
-        let source = SamplesBuffer::new(channels, sample_rate, pcm_data);
-        sink.append(source);
-        sink.play();
+        // Everything buffered ahead of this chunk plays first.
+        playback.chunk_count += 1;
+        if playback.chunk_count.is_multiple_of(LATENCY_REPORT_INTERVAL) {
+            let frames_to_ms = |frames: usize| frames as u64 * 1000 / sample_rate as u64;
+            let queued_ms = frames_to_ms(
+                buffer
+                    .depth()
+                    .saturating_sub(samples.len() / channels as usize),
+            );
+            let latency_ms =
+                unix_time_us().saturating_sub(captured_at_us) as f64 / 1000.0 + queued_ms as f64;
+            println!(
+                "[PlaybackThread] Stream {}: End-to-end latency: {:.0} ms (clocks must be in sync). Jitter buffer: {} ms, target {} ms, {} underrun(s), {} ms skipped. Clock drift: {:+.0} ppm.",
+                stream,
+                latency_ms,
+                frames_to_ms(buffer.depth()),
+                frames_to_ms(buffer.target()),
+                buffer.underruns(),
+                frames_to_ms(buffer.skipped_frames() as usize),
+                buffer.drift_ppm()
+            );
+        }
+    }
+    output.mixer().buffers().for_each(JitterBuffer::drain);
+    while output.mixer().buffers().any(|buffer| buffer.depth() > 0) {
+        thread::sleep(Duration::from_millis(FRAME_MS as u64));
     }
-    sink.sleep_until_end();
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    println!("Connecting to: {}", SERVER_URL);
-    let server_cert_hash = Sha256Digest::new(SERVER_CERT_HASH_BYTES);
-    let config = ClientConfig::builder()
-        .with_bind_default()
-        .with_no_cert_validation()
-        .build();
-    let endpoint = wtransport::Endpoint::client(config)
-        .context("Failed to create WebTransport client endpoint")?;
-    let connection = endpoint
-        .connect(SERVER_URL)
+/// Captures the default input device, downmixed to mono, and encodes it into
+/// Opus packets for the server's virtual microphone.
+fn mic_thread(packet_sender: tokio::sync::mpsc::UnboundedSender<Vec<u8>>) -> Result<()> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .context("No default input device")?;
+    let channels = device
+        .default_input_config()
+        .context("Failed to query input device config")?
+        .channels();
+    let stream_config = cpal::StreamConfig {
+        channels,
+        sample_rate: cpal::SampleRate(SAMPLE_RATE),
+        buffer_size: cpal::BufferSize::Default,
+    };
+    let (sample_sender, sample_receiver) = crossbeam_channel::unbounded::<Vec<f32>>();
+    let stream = device
+        .build_input_stream(
+            &stream_config,
+            move |data: &[f32], _| {
+                let mono = data
+                    .chunks_exact(channels as usize)
+                    .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+                    .collect();
+                let _ = sample_sender.send(mono);
+            },
+            |e| eprintln!("[Mic] Input stream error: {:?}", e),
+            None,
+        )
+        .context("Failed to open input stream")?;
+    stream.play().context("Failed to start input stream")?;
+    println!("[Mic] Capturing from {}.", device.name()?);
+
+    let mut encoder =
+        opus::Encoder::new(SAMPLE_RATE, opus::Channels::Mono, opus::Application::Voip)
+            .context("Failed to create Opus encoder")?;
+    let mut pending = Vec::new();
+    let mut packet = vec![0u8; 4000];
+    for samples in sample_receiver {
+        pending.extend(samples);
+        while pending.len() >= MIC_FRAME_SAMPLES {
+            let frame: Vec<f32> = pending.drain(..MIC_FRAME_SAMPLES).collect();
+            let len = encoder
+                .encode_float(&frame, &mut packet)
+                .context("Failed to encode microphone audio")?;
+            if packet_sender.send(packet[..len].to_vec()).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends the microphone to the server on a bidirectional stream, framing every
+/// Opus packet like the server frames the media stream.
+async fn send_mic(connection: wtransport::Connection) -> Result<()> {
+    let (packet_sender, mut packet_receiver) = tokio::sync::mpsc::unbounded_channel();
+    thread::spawn(move || {
+        if let Err(e) = mic_thread(packet_sender) {
+            eprintln!("[Mic] Error: {:?}", e);
+        }
+    });
+    let (mut send_stream, _) = connection
+        .open_bi()
+        .await?
+        .await
+        .context("Failed to open microphone stream")?;
+    send_stream.write_all(&[protocol::STREAM_MIC]).await?;
+    let mut sequence: u32 = 0;
+    while let Some(packet) = packet_receiver.recv().await {
+        send_stream
+            .write_all(&protocol::frame(
+                Codec::Opus,
+                sequence,
+                unix_time_us(),
+                MIC_FRAME_SAMPLES as u16,
+                &packet,
+            ))
+            .await?;
+        sequence = sequence.wrapping_add(1);
+    }
+    Ok(())
+}
+
+/// Reads a command typed on stdin: `pause`, `resume`, `restart`,
+/// `volume <percent>`, `source <sink>`, `latency <ms>`, `tier <n|auto>` or
+/// `surround <on|off>`.
+fn parse_command(line: &str) -> Option<ClientCommand> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next()?, words.next()) {
+        ("pause", None) => ClientCommand::Pause,
+        ("resume", None) => ClientCommand::Resume,
+        ("restart", None) => ClientCommand::Restart,
+        ("volume", Some(percent)) => ClientCommand::SetVolume {
+            percent: percent.parse().ok()?,
+        },
+        ("source", Some(sink)) => ClientCommand::SelectSource {
+            sink: sink.to_string(),
+        },
+        ("latency", Some(ms)) => ClientCommand::SetTargetLatency {
+            ms: ms.parse().ok()?,
+        },
+        ("tier", Some("auto")) => ClientCommand::SelectTier { tier: None },
+        ("tier", Some(tier)) => ClientCommand::SelectTier {
+            tier: Some(tier.parse().ok()?),
+        },
+        ("surround", Some("on")) => ClientCommand::SetSurround { enabled: true },
+        ("surround", Some("off")) => ClientCommand::SetSurround { enabled: false },
+        _ => return None,
+    };
+    words.next().is_none().then_some(command)
+}
+
+/// Commands typed on stdin, shared by the sessions one after another.
+type Commands = Arc<tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<ClientCommand>>>;
+
+/// Reads a command changing this client's own volume: `gain <percent>`, or
+/// `+` and `-` to step it by 10%. Returns the new volume.
+fn parse_gain_command(line: &str, percent: u32) -> Option<u32> {
+    let mut words = line.split_whitespace();
+    let percent = match (words.next()?, words.next()) {
+        ("gain", Some(percent)) => percent.parse().ok()?,
+        ("+", None) => percent + 10,
+        ("-", None) => percent.saturating_sub(10),
+        _ => return None,
+    };
+    words.next().is_none().then_some(percent)
+}
+
+/// Reads the commands typed on stdin, applying volume changes and queueing
+/// the rest for the sessions to send.
+async fn read_commands(
+    command_sender: tokio::sync::mpsc::UnboundedSender<ClientCommand>,
+    volume: Volume,
+) -> Result<()> {
+    let mut lines =
+        tokio::io::AsyncBufReadExt::lines(tokio::io::BufReader::new(tokio::io::stdin()));
+    while let Some(line) = lines.next_line().await? {
+        if let Some(percent) = parse_gain_command(&line, volume.get()) {
+            println!("[Commands] Playing at {}% volume.", volume.set(percent));
+            continue;
+        }
+        match parse_command(&line) {
+            Some(command) => {
+                if command_sender.send(command).is_err() {
+                    return Ok(());
+                }
+            }
+            None if line.trim().is_empty() => {}
+            None => eprintln!(
+                "[Commands] Unknown command {:?}, expected pause, resume, restart, volume <percent>, source <sink>, latency <ms>, tier <n|auto>, surround <on|off>, gain <percent>, + or -.",
+                line
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Sends the commands typed on stdin to the server, after the `initial` ones
+/// the command line asks for, along with the latest of the `reports` every
+/// `RECEIVER_REPORT_INTERVAL`.
+async fn send_commands(
+    connection: wtransport::Connection,
+    commands: Commands,
+    initial: Vec<ClientCommand>,
+    reports: tokio::sync::watch::Receiver<ReceiverReport>,
+) -> Result<()> {
+    let (mut send_stream, _) = connection
+        .open_bi()
+        .await?
         .await
-        .context(format!("Failed to connect to server at {}", SERVER_URL))?;
-    println!("Waiting for incoming unidirectional stream...");
-    let mut stream_reader = connection
+        .context("Failed to open command stream")?;
+    send_stream.write_all(&[protocol::STREAM_CONTROL]).await?;
+    for command in initial {
+        send_stream.write_all(&command.encode()).await?;
+    }
+    let mut commands = commands.lock().await;
+    let mut report_ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + protocol::RECEIVER_REPORT_INTERVAL,
+        protocol::RECEIVER_REPORT_INTERVAL,
+    );
+    loop {
+        let command = tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => return Ok(()),
+            },
+            _ = report_ticker.tick() => ClientCommand::ReceiverReport(reports.borrow().clone()),
+        };
+        send_stream.write_all(&command.encode()).await?;
+    }
+}
+
+/// Accepts the next stream the server opens, returning the kind it starts
+/// with along with it.
+async fn accept_server_stream(
+    connection: &wtransport::Connection,
+) -> Result<(u8, wtransport::RecvStream)> {
+    let mut stream = connection
         .accept_uni()
         .await
         .context("Failed to accept unidirectional stream from server")?;
+    let mut kind = [0u8; 1];
+    stream
+        .read_exact(&mut kind)
+        .await
+        .context("Failed to read stream kind from server")?;
+    Ok((kind[0], stream))
+}
 
-    let (pcm_sender, pcm_receiver) = crossbeam_channel::unbounded::<Vec<i16>>();
+/// An audio packet's header and payload.
+type Packet = (PacketHeader, Vec<u8>);
 
-    let playback_handle = thread::spawn(move || {
-        if let Err(e) = playback_thread(pcm_receiver, SAMPLE_RATE, PLAYBACK_CHANNELS) {
-            eprintln!("[PlaybackThread] Error: {:?}", e);
+/// Reads packets off the media stream until it ends.
+async fn read_stream(
+    mut stream: wtransport::RecvStream,
+    packet_sender: tokio::sync::mpsc::UnboundedSender<Packet>,
+) -> Result<()> {
+    let mut header_bytes = [0u8; protocol::HEADER_LEN];
+    loop {
+        if let Err(e) = stream.read_exact(&mut header_bytes).await {
+            println!("[NetworkRead] Stream ended: {}", e);
+            return Ok(());
         }
-    });
-    let mut opus_decoder =
-        opus::Decoder::new(SAMPLE_RATE, OPUS_CHANNELS).context("Failed to create Opus decoder")?;
-    let mut pcm_out_buffer = vec![0i16; MAX_PCM_SAMPLES_PER_FRAME];
-    let mut pcm_in_buffer = vec![0u8; MAX_PCM_SAMPLES_PER_FRAME];
+        let header = PacketHeader::decode(&header_bytes).context("Malformed packet header")?;
+        let mut packet = vec![0u8; header.payload_len as usize];
+        stream
+            .read_exact(&mut packet)
+            .await
+            .context("Stream ended mid-packet")?;
+        if packet_sender.send((header, packet)).is_err() {
+            return Ok(());
+        }
+    }
+}
 
-    let mut packet_count = 0;
-    println!("[NetworkRead] Reading Opus packets from stream...");
+/// Forwards the audio packets the server sends as datagrams.
+async fn datagram_task(
+    connection: wtransport::Connection,
+    packet_sender: tokio::sync::mpsc::UnboundedSender<Packet>,
+) {
+    while let Ok(datagram) = connection.receive_datagram().await {
+        match protocol::parse_packet(&datagram) {
+            Ok(Some((header, payload))) => {
+                if packet_sender.send((header, payload.to_vec())).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => eprintln!("[Datagram] Truncated audio datagram."),
+            Err(e) => eprintln!("[Datagram] Malformed audio datagram: {}", e),
+        }
+    }
+}
 
-    loop {
-        if let Ok(Some(no)) = stream_reader.read(&mut pcm_in_buffer).await {
-            packet_count += 1;
-            match opus_decoder.decode(&pcm_in_buffer[..no], &mut pcm_out_buffer, false) {
-                Ok(decoded_sample_count) => {
-                    if decoded_sample_count > 0 {
-                        if decoded_sample_count != SAMPLES_PER_FRAME_EXPECTED {
-                            println!(
-                                "[NetworkRead] WARN: Decoded {} samples, expected {}.",
-                                decoded_sample_count, SAMPLES_PER_FRAME_EXPECTED
-                            );
-                        }
-                        let pcm_to_send = pcm_out_buffer[..decoded_sample_count].to_vec();
-                        if pcm_sender.send(pcm_to_send).is_err() {
-                            println!(
-                                "[NetworkRead] Playback thread seems to have exited. Stopping."
-                            );
-                            break;
-                        }
-                    } else {
+type ControlLines = tokio::io::Lines<tokio::io::BufReader<wtransport::RecvStream>>;
+
+async fn next_control_message(lines: &mut ControlLines) -> Result<Option<ControlMessage>> {
+    let Some(line) = lines.next_line().await? else {
+        return Ok(None);
+    };
+    Ok(Some(ControlMessage::decode(line.as_bytes())?))
+}
+
+/// Reports the stream state and stats the server sends on the control stream,
+/// sizes the jitter buffer for the target latency it grants and keeps the
+/// `player_state` up to date.
+async fn control_task(
+    mut lines: ControlLines,
+    connection: wtransport::Connection,
+    playback_sender: PlaybackSender,
+    player_state: tokio::sync::watch::Sender<PlayerState>,
+) -> Result<()> {
+    let mut last_tier = 0;
+    while let Some(message) = next_control_message(&mut lines).await? {
+        match message {
+            ControlMessage::State { paused } => {
+                match paused {
+                    true => println!("[Control] Stream paused by server."),
+                    false => println!("[Control] Stream live."),
+                }
+                player_state.send_modify(|state| state.paused = paused);
+            }
+            ControlMessage::Idle { idle: true } => {
+                println!("[Control] Sink is silent, waiting for sound.")
+            }
+            ControlMessage::Idle { idle: false } => println!("[Control] Sound is back."),
+            ControlMessage::Stats {
+                tier,
+                rtt_ms,
+                missed_packets,
+            } => {
+                if tier != last_tier {
+                    println!(
+                        "[Control] Moved to bitrate tier {} (RTT {:.0} ms).",
+                        tier, rtt_ms
+                    );
+                    last_tier = tier;
+                }
+                if missed_packets > 0 {
+                    eprintln!(
+                        "[Control] WARN: Server skipped {} packets to catch up.",
+                        missed_packets
+                    );
+                }
+            }
+            // Sent again after a restart or a switch to another sink. Packets
+            // carry their codec and channel count, so decoding just goes on.
+            ControlMessage::StreamInfo { sink, codec, .. } => {
+                println!("[Control] Now playing {} audio of sink {}.", codec, sink);
+                player_state.send_modify(|state| {
+                    state.sink = sink;
+                    state.codec = Some(codec);
+                });
+            }
+            ControlMessage::CertificateRenewed => {
+                println!("[Control] Server renewed its certificate.")
+            }
+            // Only ever sent first, and answered before this task starts.
+            ControlMessage::PasswordChallenge { .. } => {
+                eprintln!("[Control] WARN: Server sent a password challenge mid-session.")
+            }
+            ControlMessage::NowPlaying(track) => {
+                match (&track.title, &track.artist) {
+                    (Some(title), Some(artist)) => {
+                        println!("[Control] Now playing {} by {}.", title, artist)
+                    }
+                    (Some(title), None) => println!("[Control] Now playing {}.", title),
+                    _ => println!("[Control] Nothing's playing."),
+                }
+                player_state.send_modify(|state| state.track = track);
+            }
+            ControlMessage::TargetLatency { ms } => {
+                let rtt_ms = connection.rtt().as_secs_f64() * 1000.0;
+                let jitter_ms = protocol::jitter_buffer_ms(ms, rtt_ms);
+                println!(
+                    "[Control] Playing {} ms behind capture, {} ms of it buffered (RTT {:.0} ms).",
+                    ms, jitter_ms, rtt_ms
+                );
+                if playback_sender
+                    .send(Playback::JitterTarget { ms: jitter_ms })
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes one FLAC frame into its channel count and interleaved samples.
+fn decode_flac(frame: &[u8]) -> Result<(u16, Vec<i16>)> {
+    let block = claxon::frame::FrameReader::new(std::io::Cursor::new(frame))
+        .read_next_or_eof(Vec::new())?
+        .context("Empty FLAC packet")?;
+    let block = &block;
+    let samples = (0..block.duration())
+        .flat_map(|i| (0..block.channels()).map(move |ch| block.sample(ch, i) as i16))
+        .collect();
+    Ok((block.channels() as u16, samples))
+}
+
+/// Decodes a session's packets, concealing the ones lost, and queues the
+/// audio for the playback thread.
+struct PacketPlayer<'a> {
+    playback_sender: &'a PlaybackSender,
+    sample_rate: u32,
+    // Created for the stream info's channel count, and recreated should the
+    // stereo flag of the Opus packets say otherwise.
+    opus_decoder: Option<(opus::Decoder, opus::Channels)>,
+    // Created with the first surround packet.
+    surround_decoder: Option<SurroundDecoder>,
+    pcm_out_buffer: Vec<i16>,
+    reception: ReceptionStats,
+    packet_count: u64,
+}
+
+impl<'a> PacketPlayer<'a> {
+    fn new(
+        codec: Codec,
+        sample_rate: u32,
+        channels: u8,
+        playback_sender: &'a PlaybackSender,
+    ) -> Result<Self> {
+        let mut opus_decoder = None;
+        if codec == Codec::Opus {
+            let channels = if channels == 1 {
+                opus::Channels::Mono
+            } else {
+                opus::Channels::Stereo
+            };
+            let decoder = opus::Decoder::new(sample_rate, channels)
+                .context("Failed to create Opus decoder")?;
+            opus_decoder = Some((decoder, channels));
+        }
+        Ok(Self {
+            playback_sender,
+            sample_rate,
+            opus_decoder,
+            surround_decoder: None,
+            pcm_out_buffer: vec![0i16; MAX_PCM_SAMPLES_PER_FRAME],
+            reception: ReceptionStats::default(),
+            packet_count: 0,
+        })
+    }
+
+    /// Queues the audio of a packet that arrived at `arrived_at_us`. Returns
+    /// false once the playback thread has exited.
+    fn play(&mut self, header: &PacketHeader, packet: &[u8], arrived_at_us: u64) -> Result<bool> {
+        let captured_at_us = header.captured_at_us;
+        self.packet_count += 1;
+        let packet_count = self.packet_count;
+        let mut concealed = 0;
+        match self
+            .reception
+            .track(header.sequence, captured_at_us, arrived_at_us)
+        {
+            SequenceEvent::InOrder => {}
+            SequenceEvent::Gap(missed) => {
+                concealed = missed.min(MAX_CONCEALED_PACKETS);
+                eprintln!(
+                    "[NetworkRead] WARN: {} packets lost before packet {} ({} in total).",
+                    missed,
+                    header.sequence,
+                    self.reception.lost()
+                );
+            }
+            SequenceEvent::Late => {
+                eprintln!(
+                    "[NetworkRead] Packet {} arrived late. Skipping packet.",
+                    header.sequence
+                );
+                return Ok(true);
+            }
+        }
+        let send = |channels: u16, samples: Vec<i16>, captured_at_us: u64| {
+            let chunk = PcmChunk {
+                channels,
+                samples,
+                captured_at_us,
+            };
+            self.playback_sender.send(Playback::Audio(chunk)).is_ok()
+        };
+        // FLAC and PCM have no concealment, so lost frames are just skipped.
+        // Silence packets need no decoding.
+        if !matches!(header.codec, Codec::Opus | Codec::OpusSurround) {
+            let decoded = match header.codec {
+                Codec::Flac => decode_flac(packet),
+                Codec::Pcm | Codec::Silence => protocol::decode_uncompressed(header.codec, packet)
+                    .map(|(channels, samples)| (channels as u16, samples))
+                    .with_context(|| format!("Malformed {} payload", header.codec)),
+                other => Err(anyhow!("No decoder for {} packets", other)),
+            };
+            match decoded {
+                Ok((channels, samples)) => return Ok(send(channels, samples, captured_at_us)),
+                Err(e) => eprintln!(
+                    "[NetworkRead] {} decoding error for packet {}: {:?}. Skipping packet.",
+                    header.codec, packet_count, e
+                ),
+            }
+            return Ok(true);
+        }
+        let (decoder, channels): (&mut dyn OpusDecode, usize) =
+            if header.codec == Codec::OpusSurround {
+                let decoder = match &mut self.surround_decoder {
+                    Some(decoder) => decoder,
+                    None => {
+                        println!("[NetworkRead] Stream has surround audio.");
+                        self.surround_decoder
+                            .insert(SurroundDecoder::new(self.sample_rate)?)
+                    }
+                };
+                (decoder, SURROUND_CHANNELS as usize)
+            } else {
+                let channels = match opus::packet::get_nb_channels(packet) {
+                    Ok(channels) => channels,
+                    Err(e) => {
+                        eprintln!(
+                            "[NetworkRead] Malformed Opus packet {}: {:?}. Skipping packet.",
+                            packet_count, e
+                        );
+                        return Ok(true);
+                    }
+                };
+                let decoder = match &mut self.opus_decoder {
+                    Some((decoder, decoder_channels)) if *decoder_channels == channels => decoder,
+                    _ => {
+                        println!("[NetworkRead] Stream has {:?} audio.", channels);
+                        let decoder = opus::Decoder::new(self.sample_rate, channels)
+                            .context("Failed to create Opus decoder")?;
+                        &mut self.opus_decoder.insert((decoder, channels)).0
+                    }
+                };
+                (decoder, channels as usize)
+            };
+        // The server's encoder started afresh, or switched between surround
+        // and the downmix, so the state this decoder kept would only garble
+        // the packet, as would concealing what came before it.
+        if header.decoder_reset {
+            decoder.reset().context("Failed to reset Opus decoder")?;
+            concealed = 0;
+        }
+        let pcm_out_buffer = &mut self.pcm_out_buffer;
+        // Let Opus conceal the lost frames rather than skipping over them. The
+        // frame just before this packet is recovered from the packet's inband
+        // FEC data when the server sends it (`--fec`), and the rest are
+        // interpolated by packet loss concealment.
+        let frame_samples = header.frame_samples as usize;
+        let frame_len = (frame_samples * channels).min(pcm_out_buffer.len());
+        for lost in (1..=concealed).rev() {
+            let (fec_source, fec): (&[u8], bool) = if lost == 1 {
+                (packet, true)
+            } else {
+                (&[], false)
+            };
+            if let Ok(len) = decoder.decode_frame(fec_source, &mut pcm_out_buffer[..frame_len], fec)
+            {
+                let samples = pcm_out_buffer[..len * channels].to_vec();
+                let captured_at_us = captured_at_us.saturating_sub(
+                    (lost as f64 * header.frame_duration_us(self.sample_rate)) as u64,
+                );
+                if !send(channels as u16, samples, captured_at_us) {
+                    return Ok(false);
+                }
+            }
+        }
+        match decoder.decode_frame(packet, pcm_out_buffer, false) {
+            Ok(decoded_sample_count) => {
+                if decoded_sample_count > 0 {
+                    if decoded_sample_count != frame_samples {
                         println!(
-                            "[NetworkRead] Opus decoder returned 0 samples for packet {}.",
-                            packet_count
+                            "[NetworkRead] WARN: Decoded {} samples, expected {}.",
+                            decoded_sample_count, frame_samples
                         );
                     }
+                    let samples = pcm_out_buffer[..decoded_sample_count * channels].to_vec();
+                    return Ok(send(channels as u16, samples, captured_at_us));
                 }
-                Err(e) => {
-                    eprintln!(
-                        "[NetworkRead] Opus decoding error for packet {}: {:?}. Skipping packet.",
-                        packet_count, e
-                    );
+                println!(
+                    "[NetworkRead] Opus decoder returned 0 samples for packet {}.",
+                    packet_count
+                );
+            }
+            Err(e) => {
+                eprintln!(
+                    "[NetworkRead] Opus decoding error for packet {}: {:?}. Skipping packet.",
+                    packet_count, e
+                );
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Writes to the `--dump` file if there's one, giving up on it should that
+/// fail.
+fn write_dump(dump: &mut Option<DumpWriter>, write: impl FnOnce(&mut DumpWriter) -> Result<()>) {
+    if let Some(writer) = dump.as_mut()
+        && let Err(e) = write(writer)
+    {
+        eprintln!("[Dump] WARN: Stopped dumping packets: {:?}", e);
+        *dump = None;
+    }
+}
+
+/// Connects to the server and plays its stream until the connection is lost.
+/// The backoff starts over once the server starts streaming.
+#[allow(clippy::too_many_arguments)]
+async fn run_session(
+    config: &Config,
+    endpoint: &wtransport::Endpoint<wtransport::endpoint::endpoint_side::Client>,
+    commands: &Commands,
+    playback_sender: &PlaybackSender,
+    buffered_ms: &AtomicU32,
+    player_state: &tokio::sync::watch::Sender<PlayerState>,
+    dump: &mut Option<DumpWriter>,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let access_key = config
+        .key
+        .as_deref()
+        .expect("clap requires --key without --discover, --list-devices or --replay");
+    let expires_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System clock is before the Unix epoch")?
+        .as_secs()
+        + protocol::TOKEN_LIFETIME_SECS;
+    let mut server_url = format!(
+        "{}?{}&{}={}",
+        config.sink_url(),
+        protocol::codecs_query(&DECODABLE_CODECS),
+        protocol::TOKEN_QUERY_KEY,
+        protocol::session_token(access_key, expires_at)
+    );
+    if config.datagrams {
+        server_url = format!("{}&{}", server_url, protocol::DATAGRAM_QUERY);
+    }
+    println!("Connecting to: {}", server_url);
+    let connection = endpoint
+        .connect(&server_url)
+        .await
+        .context(format!("Failed to connect to server at {}", server_url))?;
+    // Aborted when the session ends.
+    let mut tasks = JoinSet::new();
+    println!("Waiting for the control stream...");
+    let mut media_stream = None;
+    let control_stream = loop {
+        match accept_server_stream(&connection).await? {
+            (protocol::STREAM_CONTROL, stream) => break stream,
+            (protocol::STREAM_MEDIA, stream) => media_stream = Some(stream),
+            (other, _) => bail!("Server opened a stream of unknown kind {}", other),
+        }
+    };
+    let mut control_lines =
+        tokio::io::AsyncBufReadExt::lines(tokio::io::BufReader::new(control_stream));
+    let mut message = next_control_message(&mut control_lines).await?;
+    // The proof goes first on the command stream, and the stream info only
+    // follows once the server checked it.
+    let mut initial_commands = config.initial_commands();
+    if let Some(ControlMessage::PasswordChallenge { nonce }) = &message {
+        let Some(password) = &config.password else {
+            bail!("Server needs a password, pass --password");
+        };
+        let proof = protocol::password_proof(password, nonce);
+        initial_commands.insert(0, ClientCommand::Authenticate { proof });
+        message = None;
+    }
+    let command_connection = connection.clone();
+    let commands = commands.clone();
+    let (report_sender, reports) = tokio::sync::watch::channel(ReceiverReport::default());
+    tasks.spawn(async move {
+        if let Err(e) = send_commands(command_connection, commands, initial_commands, reports).await
+        {
+            eprintln!("[Commands] Error: {:?}", e);
+        }
+    });
+    if message.is_none() {
+        message = next_control_message(&mut control_lines).await?;
+    }
+    let Some(ControlMessage::StreamInfo {
+        sink,
+        codec,
+        sample_rate,
+        channels,
+        frame_samples,
+        datagrams,
+        surround,
+    }) = message
+    else {
+        bail!("Server didn't start the control stream with the stream info");
+    };
+    println!(
+        "[Control] Sink {}: {} at {} Hz, {} channel(s), {} samples per frame.",
+        sink, codec, sample_rate, channels, frame_samples
+    );
+    if !DECODABLE_CODECS.contains(&codec) {
+        bail!(
+            "Server sends {} audio, which this client can't decode",
+            codec
+        );
+    }
+    if config.surround && !surround {
+        eprintln!("[Control] WARN: Server doesn't stream this sink in surround.");
+    }
+    backoff.reset();
+    write_dump(dump, |writer| {
+        writer.write_session(unix_time_us(), codec, sample_rate, channels)
+    });
+    player_state.send_replace(PlayerState {
+        connected: true,
+        paused: false,
+        sink,
+        codec: Some(codec),
+        track: Default::default(),
+    });
+    if playback_sender
+        .send(Playback::Session { sample_rate })
+        .is_err()
+    {
+        return Ok(());
+    }
+    let (control_connection, control_playback) = (connection.clone(), playback_sender.clone());
+    let control_player_state = player_state.clone();
+    tasks.spawn(async move {
+        if let Err(e) = control_task(
+            control_lines,
+            control_connection,
+            control_playback,
+            control_player_state,
+        )
+        .await
+        {
+            eprintln!("[Control] Error: {:?}", e);
+        }
+    });
+    // Opened after the command stream, which must come first on servers
+    // with a password.
+    if config.mic {
+        let connection = connection.clone();
+        tasks.spawn(async move {
+            if let Err(e) = send_mic(connection).await {
+                eprintln!("[Mic] Error: {:?}", e);
+            }
+        });
+    }
+    let (packet_sender, mut packet_receiver) = tokio::sync::mpsc::unbounded_channel();
+    if datagrams {
+        println!("[NetworkRead] Receiving audio as datagrams.");
+        tasks.spawn(datagram_task(connection.clone(), packet_sender));
+    } else {
+        if config.datagrams {
+            println!("[NetworkRead] Server can't send datagrams, using the stream instead.");
+        }
+        let media_stream = match media_stream {
+            Some(stream) => stream,
+            None => match accept_server_stream(&connection).await? {
+                (protocol::STREAM_MEDIA, stream) => stream,
+                (other, _) => bail!("Server opened a stream of unknown kind {}", other),
+            },
+        };
+        tasks.spawn(async move {
+            if let Err(e) = read_stream(media_stream, packet_sender).await {
+                eprintln!("[NetworkRead] Error: {:?}", e);
+            }
+        });
+    }
+    let mut player = PacketPlayer::new(codec, sample_rate, channels, playback_sender)?;
+    println!("[NetworkRead] Reading audio packets...");
+    while let Some((header, packet)) = packet_receiver.recv().await {
+        let arrived_at_us = unix_time_us();
+        write_dump(dump, |writer| {
+            writer.write_packet(arrived_at_us, &header, &packet)
+        });
+        let playing = player.play(&header, &packet, arrived_at_us)?;
+        report_sender.send_replace(
+            player
+                .reception
+                .report(buffered_ms.load(Ordering::Relaxed) as f64),
+        );
+        if !playing {
+            println!("[NetworkRead] Playback thread seems to have exited. Stopping.");
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Plays the packets `--dump` wrote to `path` at the pace they arrived, as
+/// though the server was sending them now.
+async fn replay(path: &Path, playback_sender: &PlaybackSender) -> Result<()> {
+    let mut reader = DumpReader::open(path)?;
+    let mut player = None;
+    // When the first record arrived, on the dump's clock and on ours.
+    let mut start = None;
+    let mut records = 0;
+    println!("[Replay] Replaying {}.", path.display());
+    while let Some((arrived_at_us, record)) = reader.next_record()? {
+        let (first_arrival_us, started, started_us) =
+            *start.get_or_insert((arrived_at_us, tokio::time::Instant::now(), unix_time_us()));
+        let offset_us = arrived_at_us.saturating_sub(first_arrival_us);
+        tokio::time::sleep_until(started + Duration::from_micros(offset_us)).await;
+        records += 1;
+        match record {
+            Record::Session {
+                codec,
+                sample_rate,
+                channels,
+            } => {
+                println!(
+                    "[Replay] Session: {} at {} Hz, {} channel(s).",
+                    codec, sample_rate, channels
+                );
+                if playback_sender
+                    .send(Playback::Session { sample_rate })
+                    .is_err()
+                {
+                    return Ok(());
+                }
+                player = Some(PacketPlayer::new(
+                    codec,
+                    sample_rate,
+                    channels,
+                    playback_sender,
+                )?);
+            }
+            Record::Packet(mut header, packet) => {
+                let Some(player) = player.as_mut() else {
+                    bail!("Dump has packets before the stream info");
+                };
+                // Captured as long before playing now as it was before
+                // arriving then, so the latency and drift come out the same.
+                header.captured_at_us =
+                    (header.captured_at_us + started_us).saturating_sub(first_arrival_us);
+                if !player.play(&header, &packet, started_us + offset_us)? {
+                    break;
                 }
             }
         }
     }
-    drop(pcm_sender);
+    println!("[Replay] Replayed {} records.", records);
+    Ok(())
+}
+
+/// Plays a `--mix` server into the mix for as long as the client runs,
+/// reconnecting like the session playing `--server`.
+async fn play_mixed(
+    config: Config,
+    endpoint: Arc<wtransport::Endpoint<wtransport::endpoint::endpoint_side::Client>>,
+    playback_sender: PlaybackSender,
+    buffered_ms: Arc<AtomicU32>,
+) {
+    // Nothing typed goes to mixed servers, so their command streams only
+    // carry the commands the command line asks for and receiver reports.
+    let (_command_sender, command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let commands = Arc::new(tokio::sync::Mutex::new(command_receiver));
+    let (player_state, _) = tokio::sync::watch::channel(PlayerState::default());
+    let mut backoff = Backoff::default();
+    loop {
+        match run_session(
+            &config,
+            &endpoint,
+            &commands,
+            &playback_sender,
+            &buffered_ms,
+            &player_state,
+            &mut None,
+            &mut backoff,
+        )
+        .await
+        {
+            Ok(()) => println!("[Session] Connection to {} lost.", config.server),
+            Err(e) => eprintln!("[Session] Error playing {}: {:?}", config.server, e),
+        }
+        let delay = backoff.next_delay();
+        println!(
+            "[Session] Reconnecting to {} in {:.1} s...",
+            config.server,
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Config::parse();
+    if config.discover {
+        return discovery::print_servers();
+    }
+    if config.list_devices {
+        return output::print_devices(config.backend);
+    }
+    let (sender, playback_receiver) = crossbeam_channel::unbounded();
+    let playback_sender = PlaybackSender {
+        stream: 0,
+        sender: sender.clone(),
+    };
+    let jitter_ms = config.jitter_ms;
+    let backend = config.backend;
+    let device = config.device.clone();
+    let buffer_frames = config.buffer_frames;
+    let volume = Volume::new(config.volume);
+    let gain = Gain::new(volume.clone(), config.limiter);
+    // The first stream plays `--server`, the rest `--mix`.
+    let gains_percent: Vec<u32> = std::iter::once(100)
+        .chain(config.mix.iter().map(|server| server.gain_percent))
+        .collect();
+    let stream_buffered_ms: Vec<_> = gains_percent
+        .iter()
+        .map(|_| Arc::new(AtomicU32::new(0)))
+        .collect();
+    let buffered_ms = stream_buffered_ms[0].clone();
+    let playback_buffered_ms = stream_buffered_ms.clone();
+    let playback_handle = thread::spawn(move || {
+        if let Err(e) = playback_thread(
+            playback_receiver,
+            playback_buffered_ms,
+            &gains_percent,
+            jitter_ms,
+            backend,
+            device,
+            buffer_frames,
+            gain,
+        ) {
+            eprintln!("[PlaybackThread] Error: {:?}", e);
+        }
+    });
+    if let Some(path) = &config.replay {
+        let replayed = replay(path, &playback_sender).await;
+        drop(playback_sender);
+        if playback_handle.join().is_err() {
+            eprintln!("Playback thread panicked.");
+        }
+        return replayed;
+    }
+    let client_config = ClientConfig::builder().with_bind_default();
+    let client_config = match &config.cert_hash {
+        _ if config.insecure => client_config.with_no_cert_validation(),
+        Some(hash) => client_config.with_server_certificate_hashes([hash.clone()]),
+        None => client_config.with_native_certs(),
+    }
+    .build();
+    let endpoint = Arc::new(
+        wtransport::Endpoint::client(client_config)
+            .context("Failed to create WebTransport client endpoint")?,
+    );
+    for (index, server) in config.mix.iter().enumerate() {
+        let playback_sender = PlaybackSender {
+            stream: index + 1,
+            sender: sender.clone(),
+        };
+        tokio::spawn(play_mixed(
+            config.mixed(server),
+            endpoint.clone(),
+            playback_sender,
+            stream_buffered_ms[index + 1].clone(),
+        ));
+    }
+    drop(sender);
+
+    let (command_sender, command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let (player_state, player_state_receiver) = tokio::sync::watch::channel(PlayerState::default());
+    if config.daemon {
+        mpris::spawn_mpris_task(
+            command_sender,
+            volume,
+            player_state_receiver,
+            config.server.clone(),
+        );
+    } else {
+        tokio::spawn(async move {
+            if let Err(e) = read_commands(command_sender, volume).await {
+                eprintln!("[Commands] Error: {:?}", e);
+            }
+        });
+    }
+    let commands = Arc::new(tokio::sync::Mutex::new(command_receiver));
+
+    let mut dump = config.dump.as_deref().map(DumpWriter::create).transpose()?;
+    let mut backoff = Backoff::default();
+    loop {
+        match run_session(
+            &config,
+            &endpoint,
+            &commands,
+            &playback_sender,
+            &buffered_ms,
+            &player_state,
+            &mut dump,
+            &mut backoff,
+        )
+        .await
+        {
+            Ok(()) => println!("[Session] Connection lost."),
+            Err(e) => eprintln!("[Session] Error: {:?}", e),
+        }
+        player_state.send_if_modified(|state| std::mem::replace(&mut state.connected, false));
+        if playback_handle.is_finished() {
+            break;
+        }
+        let delay = backoff.next_delay();
+        println!("[Session] Reconnecting in {:.1} s...", delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+    }
+    drop(playback_sender);
     if playback_handle.join().is_err() {
         eprintln!("Playback thread panicked.");
     }
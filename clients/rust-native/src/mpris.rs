@@ -0,0 +1,309 @@
+use crate::gain::{MAX_VOLUME_PERCENT, Volume};
+use std::collections::HashMap;
+use streaming_protocol::{ClientCommand, Codec, NowPlaying};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use zbus::fdo;
+use zbus::zvariant::{ObjectPath, OwnedValue, Str, Value};
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+/// The only track there is, the stream.
+const TRACK_ID: &str = "/io/github/actuday6418/PipewireStreaming/Stream";
+
+/// What the sessions tell the MPRIS player about the stream.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlayerState {
+    /// Whether a session is streaming.
+    pub connected: bool,
+    /// Whether the server paused the stream.
+    pub paused: bool,
+    pub sink: String,
+    pub codec: Option<Codec>,
+    /// What the server says its sink plays.
+    pub track: NowPlaying,
+}
+
+impl PlayerState {
+    fn playback_status(&self) -> &'static str {
+        match self {
+            Self {
+                connected: false, ..
+            } => "Stopped",
+            Self { paused: true, .. } => "Paused",
+            _ => "Playing",
+        }
+    }
+}
+
+/// The MPRIS volume, 1.0 being 100%, in percent.
+fn volume_percent(volume: f64) -> u32 {
+    (volume * 100.0)
+        .round()
+        .clamp(0.0, MAX_VOLUME_PERCENT as f64) as u32
+}
+
+struct Root;
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "pipewire-streaming"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct Player {
+    commands: mpsc::UnboundedSender<ClientCommand>,
+    volume: Volume,
+    state: watch::Receiver<PlayerState>,
+    server: String,
+}
+
+impl Player {
+    fn send(&self, command: ClientCommand) -> fdo::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| fdo::Error::Failed(String::from("Client is shutting down")))
+    }
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn play(&self) -> fdo::Result<()> {
+        self.send(ClientCommand::Resume)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.send(ClientCommand::Pause)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        match self.state.borrow().paused {
+            true => self.send(ClientCommand::Resume),
+            false => self.send(ClientCommand::Pause),
+        }
+    }
+
+    /// A live stream has nothing to stop at, so it's paused.
+    fn stop(&self) -> fdo::Result<()> {
+        self.send(ClientCommand::Pause)
+    }
+
+    fn next(&self) {}
+
+    fn previous(&self) {}
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) {}
+
+    fn open_uri(&self, _uri: String) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(String::from(
+            "Only the server's stream can be played",
+        )))
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        self.state.borrow().playback_status()
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    /// The track the server's sink plays, or else the sink as the title and
+    /// the server as the artist.
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let state = self.state.borrow();
+        let mut metadata = HashMap::from([(
+            String::from("mpris:trackid"),
+            ObjectPath::from_static_str_unchecked(TRACK_ID).into(),
+        )]);
+        if state.connected {
+            let title = state.track.title.as_ref().unwrap_or(&state.sink);
+            let artist = state.track.artist.as_ref().unwrap_or(&self.server);
+            let artists = Value::from(vec![artist.as_str()])
+                .try_into()
+                .expect("Arrays of strings have no file descriptors");
+            metadata.insert(String::from("xesam:title"), Str::from(title.clone()).into());
+            metadata.insert(String::from("xesam:artist"), artists);
+        }
+        if let Some(codec) = state.codec {
+            metadata.insert(
+                String::from("xesam:comment"),
+                Value::from(vec![codec.to_string()])
+                    .try_into()
+                    .expect("Arrays of strings have no file descriptors"),
+            );
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.volume.get() as f64 / 100.0
+    }
+
+    #[zbus(property)]
+    fn set_volume(&self, volume: f64) {
+        let percent = self.volume.set(volume_percent(volume));
+        println!("[Mpris] Playing at {}% volume.", percent);
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Serves the client as an MPRIS player on the session bus, queueing play
+/// and pause for the sessions to send and announcing each change of `state`.
+/// Keeps `commands` open even when there's no bus, so the sessions keep
+/// sending their receiver reports.
+pub fn spawn_mpris_task(
+    commands: mpsc::UnboundedSender<ClientCommand>,
+    volume: Volume,
+    state: watch::Receiver<PlayerState>,
+    server: String,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // Several clients may run at once.
+        let name = format!(
+            "org.mpris.MediaPlayer2.pipewire_streaming.instance{}",
+            std::process::id()
+        );
+        let mut changes = state.clone();
+        let player = Player {
+            commands: commands.clone(),
+            volume,
+            state,
+            server,
+        };
+        let connection = async {
+            zbus::connection::Builder::session()?
+                .name(name.as_str())?
+                .serve_at(MPRIS_PATH, Root)?
+                .serve_at(MPRIS_PATH, player)?
+                .build()
+                .await
+        };
+        let connection = match connection.await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("[Mpris] WARN: MPRIS control unavailable: {e}");
+                let _commands = commands;
+                return std::future::pending().await;
+            }
+        };
+        println!("[Mpris] Controllable as {name}.");
+        let player = connection
+            .object_server()
+            .interface::<_, Player>(MPRIS_PATH)
+            .await
+            .expect("Player was served at MPRIS_PATH");
+        while changes.changed().await.is_ok() {
+            let emitter = player.signal_emitter();
+            let player = player.get().await;
+            let emitted = async {
+                player.playback_status_changed(emitter).await?;
+                player.metadata_changed(emitter).await
+            };
+            if let Err(e) = emitted.await {
+                eprintln!("[Mpris] WARN: Failed to announce the player state: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_and_volume_map_to_mpris() {
+        let mut state = PlayerState::default();
+        assert_eq!(state.playback_status(), "Stopped");
+        state.paused = true;
+        assert_eq!(state.playback_status(), "Stopped");
+        state.connected = true;
+        assert_eq!(state.playback_status(), "Paused");
+        state.paused = false;
+        assert_eq!(state.playback_status(), "Playing");
+
+        assert_eq!(volume_percent(0.5), 50);
+        assert_eq!(volume_percent(-1.0), 0);
+        assert_eq!(volume_percent(10.0), MAX_VOLUME_PERCENT);
+    }
+}
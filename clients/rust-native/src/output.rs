@@ -0,0 +1,368 @@
+use crate::gain::Gain;
+use crate::jitter_buffer::JitterBuffer;
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, Device, FromSample, Host, SampleFormat, SizedSample, Stream, StreamConfig};
+use std::sync::{Arc, Mutex, MutexGuard};
+use streaming_protocol::SAMPLE_RATE;
+
+/// What plays the audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// The system's audio API through cpal, ALSA on Linux.
+    Cpal,
+    /// A JACK server, through cpal. Needs the `jack` feature.
+    Jack,
+    /// A PipeWire stream. Needs the `pipewire` feature.
+    Pipewire,
+}
+
+/// The streams being played, shared with the output's callback.
+pub type Playing = Arc<Mutex<Mixer>>;
+
+/// A server's stream in the mix.
+struct MixedStream {
+    /// The jitter buffer being played, if any.
+    buffer: Option<JitterBuffer>,
+    /// Share of its level the stream is mixed at.
+    gain: f32,
+}
+
+/// Mixes the jitter buffers of the servers being played, each at its own
+/// gain, and scales the mix by the client's volume.
+pub struct Mixer {
+    streams: Vec<MixedStream>,
+    gain: Gain,
+    // Scratch space of the output's callback.
+    pulled: Vec<i16>,
+    mapped: Vec<i16>,
+    mixed: Vec<f32>,
+    scaled: Vec<i16>,
+}
+
+impl Mixer {
+    /// A mixer of streams at `gains_percent`, silent until they're played.
+    pub fn new(gains_percent: &[u32], gain: Gain) -> Self {
+        let streams = gains_percent
+            .iter()
+            .map(|&percent| MixedStream {
+                buffer: None,
+                gain: percent as f32 / 100.0,
+            })
+            .collect();
+        Self {
+            streams,
+            gain,
+            pulled: Vec::new(),
+            mapped: Vec::new(),
+            mixed: Vec::new(),
+            scaled: Vec::new(),
+        }
+    }
+
+    /// The jitter buffer `stream` plays, if any.
+    pub fn buffer(&mut self, stream: usize) -> Option<&mut JitterBuffer> {
+        self.streams[stream].buffer.as_mut()
+    }
+
+    /// The jitter buffers being played.
+    pub fn buffers(&mut self) -> impl Iterator<Item = &mut JitterBuffer> {
+        self.streams
+            .iter_mut()
+            .filter_map(|stream| stream.buffer.as_mut())
+    }
+
+    /// Mixes `frames` frames of `out_channels` into `scaled`.
+    fn mix(&mut self, frames: usize, out_channels: usize) {
+        self.mixed.clear();
+        self.mixed.resize(frames * out_channels, 0.0);
+        self.mapped.resize(frames * out_channels, 0);
+        for stream in &mut self.streams {
+            let Some(buffer) = stream.buffer.as_mut() else {
+                continue;
+            };
+            self.pulled.resize(frames * buffer.channels(), 0);
+            buffer.pull(&mut self.pulled);
+            map_channels(
+                &self.pulled,
+                buffer.channels(),
+                &mut self.mapped,
+                out_channels,
+            );
+            for (mixed, &sample) in self.mixed.iter_mut().zip(&self.mapped) {
+                *mixed += sample as f32 * stream.gain;
+            }
+        }
+        self.scaled.resize(frames * out_channels, 0);
+        self.gain.apply(&self.mixed, &mut self.scaled);
+    }
+}
+
+fn host(backend: Backend) -> Result<Host> {
+    match backend {
+        Backend::Cpal => Ok(cpal::default_host()),
+        #[cfg(feature = "jack")]
+        Backend::Jack => cpal::host_from_id(cpal::HostId::Jack).context("JACK isn't available"),
+        #[cfg(not(feature = "jack"))]
+        Backend::Jack => bail!("This client was built without the jack feature"),
+        Backend::Pipewire => bail!("PipeWire streams aren't played through cpal"),
+    }
+}
+
+/// Prints the output devices `--device` can pick.
+pub fn print_devices(backend: Backend) -> Result<()> {
+    if backend == Backend::Pipewire {
+        bail!(
+            "PipeWire plays to the default sink, or to the node --device names as `pw-cli ls Node` lists it"
+        );
+    }
+    let host = host(backend)?;
+    let default = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    for device in host.output_devices()? {
+        let name = device.name()?;
+        let marker = if default.as_ref() == Some(&name) {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("{}{}", name, marker);
+    }
+    Ok(())
+}
+
+fn find_device(host: &Host, name: Option<&str>) -> Result<Device> {
+    let Some(name) = name else {
+        return host
+            .default_output_device()
+            .context("No default output device");
+    };
+    host.output_devices()?
+        .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+        .ok_or_else(|| anyhow!("No output device named {:?}, see --list-devices", name))
+}
+
+/// Copies interleaved `input` of `in_channels` to `output` of `out_channels`:
+/// mono to every channel, anything to mono as the average, and otherwise
+/// channel by channel, leaving extra output channels silent.
+fn map_channels<T: FromSample<i16>>(
+    input: &[i16],
+    in_channels: usize,
+    output: &mut [T],
+    out_channels: usize,
+) {
+    let frames = input.chunks_exact(in_channels);
+    for (frame, out) in frames.zip(output.chunks_exact_mut(out_channels)) {
+        if out_channels == 1 {
+            let sum: i32 = frame.iter().map(|&sample| sample as i32).sum();
+            out[0] = T::from_sample_((sum / in_channels as i32) as i16);
+            continue;
+        }
+        for (channel, sample) in out.iter_mut().enumerate() {
+            let value = match in_channels {
+                1 => frame[0],
+                _ => frame.get(channel).copied().unwrap_or(0),
+            };
+            *sample = T::from_sample_(value);
+        }
+    }
+}
+
+/// Fills `out` with the mix of the jitter buffers being played, or with
+/// silence.
+pub fn fill<T: FromSample<i16>>(playing: &Playing, out: &mut [T], out_channels: usize) {
+    let mut mixer = playing.lock().expect("Mixer lock poisoned");
+    mixer.mix(out.len() / out_channels, out_channels);
+    for (out, &sample) in out.iter_mut().zip(&mixer.scaled) {
+        *out = T::from_sample_(sample);
+    }
+}
+
+fn build_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    playing: Playing,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<i16>,
+{
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| fill(&playing, data, channels),
+        |e| eprintln!("[PlaybackThread] Output stream error: {:?}", e),
+        None,
+    )
+}
+
+/// Opens a cpal device at the stream's rate if it takes it, at its own
+/// otherwise.
+fn open_cpal(
+    backend: Backend,
+    name: Option<&str>,
+    buffer_frames: Option<u32>,
+    playing: Playing,
+) -> Result<(Stream, u32)> {
+    let device = find_device(&host(backend)?, name)?;
+    let supported = device
+        .default_output_config()
+        .context("Failed to query output device config")?;
+    let takes_stream_rate = device
+        .supported_output_configs()
+        .context("Failed to query output device configs")?
+        .any(|range| {
+            range.channels() == supported.channels()
+                && range.sample_format() == supported.sample_format()
+                && (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&SAMPLE_RATE)
+        });
+    let rate = if takes_stream_rate {
+        SAMPLE_RATE
+    } else {
+        supported.sample_rate().0
+    };
+    let config = StreamConfig {
+        channels: supported.channels(),
+        sample_rate: cpal::SampleRate(rate),
+        buffer_size: buffer_frames.map_or(BufferSize::Default, BufferSize::Fixed),
+    };
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, playing),
+        SampleFormat::F64 => build_stream::<f64>(&device, &config, playing),
+        SampleFormat::I8 => build_stream::<i8>(&device, &config, playing),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, playing),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, playing),
+        SampleFormat::I64 => build_stream::<i64>(&device, &config, playing),
+        SampleFormat::U8 => build_stream::<u8>(&device, &config, playing),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, playing),
+        SampleFormat::U32 => build_stream::<u32>(&device, &config, playing),
+        SampleFormat::U64 => build_stream::<u64>(&device, &config, playing),
+        other => return Err(anyhow!("Output sample format {} isn't supported", other)),
+    }
+    .context("Failed to open output stream")?;
+    stream.play().context("Failed to start output stream")?;
+    println!(
+        "[PlaybackThread] Playing through {} at {} Hz ({}).",
+        device.name()?,
+        rate,
+        match config.buffer_size {
+            BufferSize::Fixed(frames) => format!("{} frame buffers", frames),
+            BufferSize::Default => String::from("default buffers"),
+        }
+    );
+    Ok((stream, rate))
+}
+
+/// Keeps the backend playing until dropped.
+enum Handle {
+    Cpal {
+        _stream: Stream,
+    },
+    #[cfg(feature = "pipewire")]
+    Pipewire {
+        _output: crate::pipewire_output::PipewireOutput,
+    },
+}
+
+/// An output playing a jitter buffer per stream it mixes, pulled straight
+/// from the backend's callback.
+pub struct Output {
+    playing: Playing,
+    rate: u32,
+    _handle: Handle,
+}
+
+impl Output {
+    /// Opens the output device named `name`, or the default one, playing
+    /// what `mixer` mixes `buffer_frames` frames at a time if given.
+    pub fn open(
+        backend: Backend,
+        name: Option<&str>,
+        buffer_frames: Option<u32>,
+        mixer: Mixer,
+    ) -> Result<Self> {
+        let playing = Arc::new(Mutex::new(mixer));
+        let (handle, rate) = match backend {
+            #[cfg(feature = "pipewire")]
+            Backend::Pipewire => {
+                let output = crate::pipewire_output::PipewireOutput::open(
+                    name,
+                    buffer_frames,
+                    playing.clone(),
+                )?;
+                (Handle::Pipewire { _output: output }, SAMPLE_RATE)
+            }
+            #[cfg(not(feature = "pipewire"))]
+            Backend::Pipewire => bail!("This client was built without the pipewire feature"),
+            backend => {
+                let (stream, rate) = open_cpal(backend, name, buffer_frames, playing.clone())?;
+                (Handle::Cpal { _stream: stream }, rate)
+            }
+        };
+        Ok(Self {
+            playing,
+            rate,
+            _handle: handle,
+        })
+    }
+
+    /// Plays `buffer` for `stream` from now on, in place of the one before.
+    pub fn play(&self, stream: usize, mut buffer: JitterBuffer) {
+        buffer.set_output_rate(self.rate);
+        self.mixer().streams[stream].buffer = Some(buffer);
+    }
+
+    /// Leaves `stream` out of the mix.
+    pub fn stop(&self, stream: usize) {
+        self.mixer().streams[stream].buffer = None;
+    }
+
+    /// The mixer of the jitter buffers being played. The output waits while
+    /// it's held.
+    pub fn mixer(&self) -> MutexGuard<'_, Mixer> {
+        self.playing.lock().expect("Mixer lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gain::Volume;
+
+    #[test]
+    fn channels_are_spread_and_mixed_down() {
+        let mut out = [0i16; 6];
+        map_channels(&[1, 2], 1, &mut out, 3);
+        assert_eq!(out, [1, 1, 1, 2, 2, 2]);
+        map_channels(&[1, 2, 3, 4], 2, &mut out, 3);
+        assert_eq!(out, [1, 2, 0, 3, 4, 0]);
+        let mut mono = [0i16; 2];
+        map_channels(&[10, 20, -4, 4], 2, &mut mono, 1);
+        assert_eq!(mono, [15, 0]);
+
+        let mut floats = [1.0f32; 4];
+        let mixer = Mixer::new(&[100], Gain::new(Volume::new(100), false));
+        fill(&Arc::new(Mutex::new(mixer)), &mut floats, 2);
+        assert_eq!(floats, [0.0; 4]);
+    }
+
+    #[test]
+    fn streams_are_mixed_at_their_gains() {
+        let mut mixer = Mixer::new(&[100, 50], Gain::new(Volume::new(100), false));
+        let mut stereo = JitterBuffer::new(2, SAMPLE_RATE, 10);
+        stereo.push(&[1000, 2000].repeat(480), 0);
+        let mut mono = JitterBuffer::new(1, SAMPLE_RATE, 10);
+        mono.push(&[-4000; 480], 0);
+        mixer.streams[0].buffer = Some(stereo);
+        mixer.streams[1].buffer = Some(mono);
+        let playing = Arc::new(Mutex::new(mixer));
+
+        let mut out = [0i16; 4];
+        fill(&playing, &mut out, 2);
+        assert_eq!(out, [-1000, 0, -1000, 0]);
+        let mut mixed_down = [0i16; 2];
+        fill(&playing, &mut mixed_down, 1);
+        assert_eq!(mixed_down, [-500, -500]);
+    }
+}
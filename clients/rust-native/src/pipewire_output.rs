@@ -0,0 +1,175 @@
+use crate::output::{Playing, fill};
+use anyhow::{Context, Result, anyhow};
+use pipewire as pw;
+use pw::spa::param::audio::{AudioFormat, AudioInfoRaw, MAX_CHANNELS};
+use pw::spa::pod::{self, Pod};
+use pw::spa::utils::Direction;
+use std::thread::{self, JoinHandle};
+use streaming_protocol::SAMPLE_RATE;
+
+/// The stream plays stereo S16 at the stream's rate, and PipeWire converts it
+/// for the sink.
+const CHANNELS: usize = 2;
+const STRIDE: usize = CHANNELS * size_of::<i16>();
+
+/// Scratch space of the process callback.
+#[derive(Default)]
+struct ProcessData {
+    samples: Vec<i16>,
+}
+
+fn format_param() -> Result<Vec<u8>> {
+    let mut audio_info = AudioInfoRaw::new();
+    audio_info.set_format(AudioFormat::S16LE);
+    audio_info.set_channels(CHANNELS as u32);
+    audio_info.set_rate(SAMPLE_RATE);
+    let mut positions = [0; MAX_CHANNELS];
+    positions[0] = pw::spa::sys::SPA_AUDIO_CHANNEL_FL;
+    positions[1] = pw::spa::sys::SPA_AUDIO_CHANNEL_FR;
+    audio_info.set_position(positions);
+    Ok(pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pod::Value::Object(pod::Object {
+            type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+            id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+            properties: audio_info.into(),
+        }),
+    )
+    .context("Failed to serialize the PipeWire stream format")?
+    .0
+    .into_inner())
+}
+
+/// Runs a PipeWire playback stream until told to quit, reporting through
+/// `opened` whether it started.
+fn run(
+    target: Option<String>,
+    buffer_frames: Option<u32>,
+    playing: Playing,
+    quit: pw::channel::Receiver<()>,
+    opened: std::sync::mpsc::Sender<Result<()>>,
+) -> Result<()> {
+    let main_loop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&main_loop)?;
+    let core = context.connect(None)?;
+    let mut properties = pw::properties::properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::AUDIO_CHANNELS => CHANNELS.to_string(),
+        *pw::keys::NODE_NAME => "pipewire-streaming-client",
+        *pw::keys::NODE_DESCRIPTION => "pipewire-streaming client",
+    };
+    if let Some(frames) = buffer_frames {
+        properties.insert(
+            *pw::keys::NODE_LATENCY,
+            format!("{}/{}", frames, SAMPLE_RATE),
+        );
+    }
+    if let Some(target) = &target {
+        properties.insert(*pw::keys::TARGET_OBJECT, target.as_str());
+    }
+    let stream = pw::stream::Stream::new(&core, "pipewire-streaming-client", properties)?;
+    let _listener = stream
+        .add_local_listener_with_user_data(ProcessData::default())
+        .process(move |stream, data| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return;
+            };
+            let requested = buffer.requested() as usize;
+            let buffer_data = &mut buffer.datas_mut()[0];
+            let mut frames = 0;
+            if let Some(bytes) = buffer_data.data() {
+                frames = bytes.len() / STRIDE;
+                if requested > 0 {
+                    frames = frames.min(requested);
+                }
+                data.samples.resize(frames * CHANNELS, 0);
+                fill(&playing, &mut data.samples, CHANNELS);
+                let samples = bytes.chunks_exact_mut(size_of::<i16>());
+                for (bytes, sample) in samples.zip(&data.samples) {
+                    bytes.copy_from_slice(&sample.to_le_bytes());
+                }
+            }
+            let chunk = buffer_data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = STRIDE as i32;
+            *chunk.size_mut() = (frames * STRIDE) as u32;
+        })
+        .register()?;
+    let values = format_param()?;
+    let mut params = [Pod::from_bytes(&values).context("Malformed PipeWire stream format")?];
+    stream.connect(
+        Direction::Output,
+        None,
+        pw::stream::StreamFlags::AUTOCONNECT
+            | pw::stream::StreamFlags::MAP_BUFFERS
+            | pw::stream::StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+    let quit_loop = main_loop.clone();
+    let _quit = quit.attach(main_loop.loop_(), move |()| quit_loop.quit());
+    println!(
+        "[PlaybackThread] Playing through PipeWire to {} ({}).",
+        target.as_deref().unwrap_or("the default sink"),
+        match buffer_frames {
+            Some(frames) => format!("{} frame buffers", frames),
+            None => String::from("default buffers"),
+        }
+    );
+    // The caller is waiting on this, so it's there to receive it.
+    let _ = opened.send(Ok(()));
+    main_loop.run();
+    Ok(())
+}
+
+/// A PipeWire playback stream, running on a thread of its own for its loop.
+pub struct PipewireOutput {
+    quit: pw::channel::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PipewireOutput {
+    /// Plays to the node named `target`, or the default sink, in periods of
+    /// `buffer_frames` if given.
+    pub fn open(
+        target: Option<&str>,
+        buffer_frames: Option<u32>,
+        playing: Playing,
+    ) -> Result<Self> {
+        pw::init();
+        let (quit, quit_receiver) = pw::channel::channel();
+        let (opened_sender, opened) = std::sync::mpsc::channel();
+        let target = target.map(str::to_string);
+        let thread = thread::spawn(move || {
+            let result = run(
+                target,
+                buffer_frames,
+                playing,
+                quit_receiver,
+                opened_sender.clone(),
+            );
+            if let Err(e) = result {
+                // Only fails before the stream opened, which the caller waits for.
+                let _ = opened_sender.send(Err(e));
+            }
+        });
+        opened
+            .recv()
+            .map_err(|_| anyhow!("PipeWire output thread died"))??;
+        Ok(Self {
+            quit,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for PipewireOutput {
+    fn drop(&mut self) {
+        // Fails only if the loop already stopped.
+        let _ = self.quit.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::{Result, bail};
+use audiopus_sys as ffi;
+use std::ffi::{CStr, c_int};
+use std::ptr::{self, NonNull};
+use streaming_protocol::{
+    SURROUND_CHANNELS, SURROUND_COUPLED_STREAMS, SURROUND_MAPPING, SURROUND_STREAMS,
+};
+
+/// What `PacketPlayer` needs of an Opus decoder, stereo or surround.
+pub trait OpusDecode {
+    /// Decodes `packet` into `output`, returning the samples per channel. An
+    /// empty packet conceals a lost one, as does `fec`, from the inband FEC
+    /// data of the packet after it.
+    fn decode_frame(&mut self, packet: &[u8], output: &mut [i16], fec: bool) -> Result<usize>;
+
+    fn reset(&mut self) -> Result<()>;
+}
+
+impl OpusDecode for opus::Decoder {
+    fn decode_frame(&mut self, packet: &[u8], output: &mut [i16], fec: bool) -> Result<usize> {
+        Ok(self.decode(packet, output, fec)?)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        Ok(self.reset_state()?)
+    }
+}
+
+/// Decodes `Codec::OpusSurround` packets, which the `opus` crate has no
+/// multistream decoder for, into the six 5.1 channels.
+pub struct SurroundDecoder {
+    ptr: NonNull<ffi::OpusMSDecoder>,
+}
+
+// SAFETY: libopus decoder state isn't tied to the thread that created it, and
+// every call goes through `&mut self`.
+unsafe impl Send for SurroundDecoder {}
+
+fn check(function: &str, code: c_int) -> Result<c_int> {
+    if code < ffi::OPUS_OK {
+        // SAFETY: opus_strerror returns a static, nul terminated string.
+        let message = unsafe { CStr::from_ptr(ffi::opus_strerror(code)) };
+        bail!("{} failed: {}", function, message.to_string_lossy());
+    }
+    Ok(code)
+}
+
+impl SurroundDecoder {
+    pub fn new(sample_rate: u32) -> Result<Self> {
+        let mut error = 0;
+        // SAFETY: the mapping holds one entry per channel, and `error`
+        // outlives the call.
+        let ptr = unsafe {
+            ffi::opus_multistream_decoder_create(
+                sample_rate as i32,
+                SURROUND_CHANNELS as c_int,
+                SURROUND_STREAMS as c_int,
+                SURROUND_COUPLED_STREAMS as c_int,
+                SURROUND_MAPPING.as_ptr(),
+                &mut error,
+            )
+        };
+        check("opus_multistream_decoder_create", error)?;
+        let Some(ptr) = NonNull::new(ptr) else {
+            bail!("opus_multistream_decoder_create returned no decoder");
+        };
+        Ok(Self { ptr })
+    }
+}
+
+impl OpusDecode for SurroundDecoder {
+    fn decode_frame(&mut self, packet: &[u8], output: &mut [i16], fec: bool) -> Result<usize> {
+        // A null packet is how libopus is told one was lost.
+        let data = match packet.is_empty() {
+            true => ptr::null(),
+            false => packet.as_ptr(),
+        };
+        // SAFETY: the frame size keeps libopus within `output`, and the
+        // length within `packet`.
+        let len = unsafe {
+            ffi::opus_multistream_decode(
+                self.ptr.as_ptr(),
+                data,
+                packet.len() as i32,
+                output.as_mut_ptr(),
+                (output.len() / SURROUND_CHANNELS as usize) as c_int,
+                fec as c_int,
+            )
+        };
+        Ok(check("opus_multistream_decode", len)? as usize)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // SAFETY: OPUS_RESET_STATE takes no arguments.
+        let code =
+            unsafe { ffi::opus_multistream_decoder_ctl(self.ptr.as_ptr(), ffi::OPUS_RESET_STATE) };
+        check("opus_multistream_decoder_ctl", code).map(|_| ())
+    }
+}
+
+impl Drop for SurroundDecoder {
+    fn drop(&mut self) {
+        // SAFETY: the decoder was created by opus_multistream_decoder_create
+        // and is only destroyed here.
+        unsafe { ffi::opus_multistream_decoder_destroy(self.ptr.as_ptr()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_protocol::SAMPLE_RATE;
+
+    #[test]
+    fn lost_frames_are_concealed_in_every_channel() {
+        let mut decoder = SurroundDecoder::new(SAMPLE_RATE).unwrap();
+        let mut output = vec![1; 480 * SURROUND_CHANNELS as usize];
+        assert_eq!(decoder.decode_frame(&[], &mut output, false).unwrap(), 480);
+        assert!(output.iter().all(|&sample| sample == 0));
+        decoder.reset().unwrap();
+        assert!(decoder.decode_frame(&[0xff], &mut output, false).is_err());
+    }
+}
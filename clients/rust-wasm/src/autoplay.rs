@@ -0,0 +1,92 @@
+use crate::AUDIO_CONTEXT;
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AudioContext, AudioContextState, HtmlButtonElement, console};
+
+thread_local! {
+    /// The page's `#audioPrompt`, shown while the browser keeps audio
+    /// suspended.
+    static PROMPT: RefCell<Option<HtmlButtonElement>> = const { RefCell::new(None) };
+}
+
+fn audio_context() -> Option<AudioContext> {
+    AUDIO_CONTEXT.with(|cell| cell.borrow().clone())
+}
+
+/// Whether `audio_context` is held back from playing, suspended or, in
+/// Safari, interrupted.
+pub fn suspended(audio_context: &AudioContext) -> bool {
+    !matches!(
+        audio_context.state(),
+        AudioContextState::Running | AudioContextState::Closed
+    )
+}
+
+/// Shows the prompt while there's audio the browser won't play.
+fn update_prompt() {
+    let suspended = audio_context().is_some_and(|context| suspended(&context));
+    PROMPT.with(|cell| {
+        if let Some(prompt) = cell.borrow().as_ref() {
+            prompt.set_hidden(!suspended);
+        }
+    });
+}
+
+/// Browsers only let audio start once the listener interacted with the page,
+/// and may suspend it again later, e.g. on phones while another app plays.
+/// Any tap resumes it, and so does the page getting focus back where the
+/// browser allows it. Until then `#audioPrompt`, if the page has one, asks
+/// for a tap.
+pub fn init(document: &web_sys::Document) -> Result<(), JsValue> {
+    let prompt = document
+        .get_element_by_id("audioPrompt")
+        .and_then(|element| element.dyn_into::<HtmlButtonElement>().ok());
+    if let Some(prompt) = &prompt {
+        prompt.set_hidden(true);
+        // Taps reach the document too, but keys only the button.
+        let closure = Closure::wrap(Box::new(resume) as Box<dyn FnMut()>);
+        prompt.set_onclick(Some(closure.as_ref().unchecked_ref()));
+        closure.forget();
+    }
+    PROMPT.with(|cell| *cell.borrow_mut() = prompt);
+
+    let closure = Closure::wrap(Box::new(resume) as Box<dyn FnMut()>);
+    document.add_event_listener_with_callback("pointerdown", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+    let window = web_sys::window().expect("no global `window` exists");
+    let closure = Closure::wrap(Box::new(resume) as Box<dyn FnMut()>);
+    window.add_event_listener_with_callback("focus", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+    Ok(())
+}
+
+/// Keeps the prompt in step with `audio_context`, whose state the browser
+/// may change at any time.
+pub fn watch(audio_context: &AudioContext) {
+    let closure = Closure::wrap(Box::new(update_prompt) as Box<dyn FnMut()>);
+    audio_context.set_onstatechange(Some(closure.as_ref().unchecked_ref()));
+    closure.forget();
+}
+
+/// Resumes audio the browser suspended, prompting for a tap if it refuses.
+pub fn resume() {
+    let Some(audio_context) = audio_context() else {
+        return;
+    };
+    // Browsers may leave the resumption pending until a tap, so the prompt
+    // shows meanwhile.
+    update_prompt();
+    if !suspended(&audio_context) {
+        return;
+    }
+    let Ok(resumed) = audio_context.resume() else {
+        return;
+    };
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(e) = JsFuture::from(resumed).await {
+            console::warn_1(&format!("The browser won't play audio yet: {:?}", e).into());
+        }
+        update_prompt();
+    });
+}
@@ -1,32 +1,135 @@
-use js_sys::{Array, Object, Reflect, Uint8Array};
+use js_sys::{Array, Float32Array, Object, Reflect, Uint8Array};
+use ring_buffer::RingBuffer;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::panic;
+use std::time::Duration;
+use streaming_protocol::{
+    self as protocol, Backoff, ClientCommand, Codec, ControlMessage, DriftEstimator, NowPlaying,
+    PacketHeader, ReceptionStats, SAMPLE_RATE, SequenceEvent, WEBTRANSPORT_PORT,
+};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
     AudioContext, AudioContextOptions, AudioData, AudioDataCopyToOptions, AudioDecoder,
-    AudioDecoderConfig, AudioDecoderInit, EncodedAudioChunk, EncodedAudioChunkInit,
-    EncodedAudioChunkType, HtmlButtonElement, HtmlParagraphElement, ReadableStreamDefaultReader,
-    WebTransport, WebTransportOptions, console,
+    AudioDecoderConfig, AudioDecoderInit, AudioSampleFormat, AudioWorkletNode,
+    AudioWorkletNodeOptions, EncodedAudioChunk, EncodedAudioChunkInit, EncodedAudioChunkType,
+    GainNode, HtmlButtonElement, HtmlInputElement, HtmlParagraphElement,
+    ReadableStreamDefaultReader, Storage, WebTransport, WebTransportBidirectionalStream,
+    WebTransportOptions, console,
 };
 
-const SERVER_CERT_HASH_BYTES: [u8; 32] = [
-    13, 168, 113, 2, 213, 136, 124, 10, 80, 208, 200, 56, 29, 68, 119, 16, 194, 119, 112, 219, 4,
-    102, 187, 137, 91, 248, 119, 10, 167, 127, 119, 240,
-];
-const SAMPLE_RATE: f32 = 48000.0;
-const NUMBER_OF_CHANNELS: u32 = 1;
-const FRAME_DURATION_MS: u32 = 5;
+const LATENCY_REPORT_INTERVAL: u64 = 100;
+/// Stereo flag in the Opus TOC byte, which tells us the stream's channel count.
+const OPUS_TOC_STEREO_FLAG: u8 = 0x04;
+/// Codecs this client can play if the browser decodes them, in the order it
+/// prefers them. PCM needs no decoder.
+const PREFERRED_CODECS: [Codec; 4] = [Codec::Opus, Codec::Aac, Codec::Flac, Codec::Pcm];
+/// Silence packets held back beyond this many are played regardless, in case
+/// the decoder swallowed a frame rather than output it.
+const MAX_PENDING_SILENCE: usize = 50;
+/// The longest an Opus packet lasts.
+const MAX_OPUS_PACKET_MS: u32 = 120;
+/// localStorage keys the volume slider and mute button are remembered under.
+const VOLUME_STORAGE_KEY: &str = "volume";
+const MUTED_STORAGE_KEY: &str = "muted";
+/// localStorage key of the credential the page got by pairing with the server.
+const CREDENTIAL_STORAGE_KEY: &str = "credential";
+/// What the server answers token requests with while the page's credential
+/// waits for approval, and how often the page asks again.
+const APPROVAL_PENDING: &str = "Waiting for approval";
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const STATS_INTERVAL_MS: i32 = 1000;
+/// The AudioWorklet module playing decoded audio, and its processor.
+const WORKLET_URL: &str = "playback-worklet.js";
+const WORKLET_PROCESSOR: &str = "ring-buffer-player";
+/// Audio the ring buffer holds at most.
+const RING_SECONDS: u32 = 2;
+/// Audio queued before playback starts, and resumes after running dry, unless
+/// the page asks for a target latency.
+const PREBUFFER_MS: u32 = 20;
+/// Depth the queue is steered toward unless the page asks for a target
+/// latency: twice the prebuffer, leaving room for packets arriving in bursts.
+const DEFAULT_TARGET_MS: u32 = 2 * PREBUFFER_MS;
+/// Most the playback rate is stretched to steer the queue toward its target
+/// depth, about 17 cents of pitch.
+const MAX_STRETCH: f64 = 0.01;
+/// Depth error, relative to the target, at which the stretch is largest.
+const FULL_STRETCH_ERROR: f64 = 0.5;
+/// Depth errors below this, relative to the target, are left alone.
+const SETTLED_ERROR: f64 = 0.1;
+/// Weight of each chunk's depth in the average the stretch follows, so the
+/// rate doesn't swing with every burst of packets. Around 20 chunks, 200 ms
+/// of Opus, make up most of it.
+const DEPTH_SMOOTHING: f64 = 0.05;
+
+/// What the stats panel shows, counted since the page loaded.
+#[derive(Default)]
+struct PlaybackStats {
+    packets: u64,
+    /// Packets counted at the panel's last update, for the rate.
+    packets_at_last_update: u64,
+    lost_packets: u64,
+    late_packets: u64,
+    /// Chunks dropped for lack of room in the ring buffer, or while the
+    /// browser kept audio suspended.
+    dropped_chunks: u64,
+    /// How much faster than the stream's clock the queue plays to reach its
+    /// target depth, in percent.
+    stretch_percent: f64,
+    latency_ms: Option<f64>,
+}
 
 thread_local! {
-    static AUDIO_CONTEXT: RefCell<Option<AudioContext>> = RefCell::new(None);
-    static AUDIO_DECODER: RefCell<Option<AudioDecoder>> = RefCell::new(None);
-    static NEXT_PLAY_TIME: RefCell<f64> = RefCell::new(0.0);
-    static RECEIVED_CHUNK_COUNT: RefCell<u64> = RefCell::new(0);
-    static STATUS_ELEMENT: RefCell<Option<HtmlParagraphElement>> = RefCell::new(None);
-    static TRANSPORT: RefCell<Option<WebTransport>> = RefCell::new(None);
+    static AUDIO_CONTEXT: RefCell<Option<AudioContext>> = const { RefCell::new(None) };
+    /// Every buffer plays through this, for the volume controls.
+    static GAIN_NODE: RefCell<Option<GainNode>> = const { RefCell::new(None) };
+    /// Volume in percent, and whether it's muted.
+    static VOLUME: RefCell<(u8, bool)> = const { RefCell::new((100, false)) };
+    static AUDIO_DECODER: RefCell<Option<AudioDecoder>> = const { RefCell::new(None) };
+    static DECODER_FORMAT: RefCell<Option<(Codec, u32)>> = const { RefCell::new(None) };
+    /// Decodes Opus in browsers without WebCodecs.
+    static OPUS_DECODER: RefCell<Option<opus::Decoder>> = const { RefCell::new(None) };
+    /// The stream's sample rate, from the server's stream info.
+    static STREAM_SAMPLE_RATE: RefCell<u32> = const { RefCell::new(SAMPLE_RATE) };
+    /// Decoded audio waiting for the playback worklet.
+    static RING: RefCell<Option<RingBuffer>> = const { RefCell::new(None) };
+    /// The AudioContext's clock against the server's, from packet arrivals.
+    static DRIFT: RefCell<DriftEstimator> = RefCell::new(DriftEstimator::default());
+    static DECODED_CHUNK_COUNT: RefCell<u64> = const { RefCell::new(0) };
+    /// What the decoder outputs is copied into, reused from chunk to chunk as
+    /// long as they fit.
+    static DECODE_BUFFER: RefCell<Option<Float32Array>> = const { RefCell::new(None) };
+    /// The planes of the last decoded chunk, reused for the next one.
+    static DECODED_PLANES: RefCell<Vec<Vec<f32>>> = const { RefCell::new(Vec::new()) };
+    /// Packets handed to the decoder and not yet output.
+    static DECODES_IN_FLIGHT: RefCell<u32> = const { RefCell::new(0) };
+    /// Silence packets waiting for the audio being decoded ahead of them, as
+    /// capture time, channel count and samples per channel.
+    static PENDING_SILENCE: RefCell<VecDeque<(u64, u8, u32)>> = const { RefCell::new(VecDeque::new()) };
+    static STATUS_ELEMENT: RefCell<Option<HtmlParagraphElement>> = const { RefCell::new(None) };
+    static LATENCY_ELEMENT: RefCell<Option<HtmlParagraphElement>> = const { RefCell::new(None) };
+    static NOW_PLAYING_ELEMENT: RefCell<Option<HtmlParagraphElement>> = const { RefCell::new(None) };
+    static STATS: RefCell<PlaybackStats> = RefCell::new(PlaybackStats::default());
+    /// The session's packets so far, for its receiver reports.
+    static RECEPTION: RefCell<ReceptionStats> = RefCell::new(ReceptionStats::default());
+    static TRANSPORT: RefCell<Option<WebTransport>> = const { RefCell::new(None) };
+    /// The server's password, once it let a session start with it.
+    static PASSWORD: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Round trip time the server last reported.
+    static RTT_MS: RefCell<f64> = const { RefCell::new(0.0) };
+    /// Ring depth in frames the queue is steered toward, once the server
+    /// granted the target latency the page asked for.
+    static TARGET_DEPTH: RefCell<Option<u32>> = const { RefCell::new(None) };
+    /// Ring depth in frames, averaged over the last chunks queued.
+    static AVERAGE_DEPTH: RefCell<f64> = const { RefCell::new(0.0) };
 }
 
+mod autoplay;
+mod media_session;
+mod opus;
+mod ring_buffer;
+
 #[wasm_bindgen(start)]
 pub fn main_js() -> Result<(), JsValue> {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -50,24 +153,160 @@ pub fn main_js() -> Result<(), JsValue> {
                 .unwrap(),
         );
     });
+    LATENCY_ELEMENT.with(|cell| {
+        *cell.borrow_mut() = document
+            .get_element_by_id("latency")
+            .and_then(|element| element.dyn_into::<HtmlParagraphElement>().ok());
+    });
+    NOW_PLAYING_ELEMENT.with(|cell| {
+        *cell.borrow_mut() = document
+            .get_element_by_id("now-playing")
+            .and_then(|element| element.dyn_into::<HtmlParagraphElement>().ok());
+    });
+    init_volume_controls(&document)?;
+    autoplay::init(&document)?;
+    media_session::init(&document)?;
+    if let Some(stats_element) = document.get_element_by_id("stats") {
+        let closure =
+            Closure::wrap(Box::new(move || show_stats(&stats_element)) as Box<dyn FnMut()>);
+        window.set_interval_with_callback_and_timeout_and_arguments_0(
+            closure.as_ref().unchecked_ref(),
+            STATS_INTERVAL_MS,
+        )?;
+        closure.forget();
+    }
 
+    let button = connect_button.clone();
     let closure = Closure::wrap(Box::new(move || {
         console::log_1(&"Connect button clicked (Rust)".into());
         update_status("Connecting...");
-        wasm_bindgen_futures::spawn_local(async {
-            if let Err(e) = connect_and_receive().await {
-                console::error_1(&format!("Connection error: {:?}", e).into());
-                update_status(&format!("Error: {:?}", e));
-            }
-        });
+        // Connections are retried from here on, so one click is all it takes.
+        button.set_disabled(true);
+        wasm_bindgen_futures::spawn_local(connect_with_retries());
     }) as Box<dyn FnMut()>);
 
     connect_button.set_onclick(Some(closure.as_ref().unchecked_ref()));
     closure.forget(); // To keep the closure alive
 
+    // `?autoconnect=1`, as in the link of the server's `/api/qr`, connects
+    // without waiting for the button.
+    let page_params = web_sys::UrlSearchParams::new_with_str(&window.location().search()?)?;
+    if page_params.get("autoconnect").as_deref() == Some("1") {
+        connect_button.click();
+    }
+
     Ok(())
 }
 
+fn local_storage() -> Option<Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+/// Sets the gain from the volume controls, if playback started.
+fn apply_volume() {
+    let (percent, muted) = VOLUME.with(|cell| *cell.borrow());
+    GAIN_NODE.with(|cell| {
+        if let Some(gain_node) = cell.borrow().as_ref() {
+            let gain = if muted { 0.0 } else { percent as f32 / 100.0 };
+            gain_node.gain().set_value(gain);
+        }
+    });
+}
+
+/// Wires the page's `#volume` slider and `#muteButton`, if it has them, to the
+/// gain, starting from the settings remembered in localStorage.
+fn init_volume_controls(document: &web_sys::Document) -> Result<(), JsValue> {
+    if let Some(storage) = local_storage() {
+        let stored = |key| storage.get_item(key).ok().flatten();
+        VOLUME.with(|cell| {
+            let mut volume = cell.borrow_mut();
+            if let Some(percent) =
+                stored(VOLUME_STORAGE_KEY).and_then(|value| value.parse::<u8>().ok())
+            {
+                volume.0 = percent.min(100);
+            }
+            volume.1 = stored(MUTED_STORAGE_KEY).as_deref() == Some("true");
+        });
+    }
+    let (percent, muted) = VOLUME.with(|cell| *cell.borrow());
+
+    if let Some(slider) = document
+        .get_element_by_id("volume")
+        .and_then(|element| element.dyn_into::<HtmlInputElement>().ok())
+    {
+        slider.set_value(&percent.to_string());
+        let input = slider.clone();
+        let closure = Closure::wrap(Box::new(move || {
+            let Ok(percent) = input.value().parse::<u8>() else {
+                return;
+            };
+            VOLUME.with(|cell| cell.borrow_mut().0 = percent.min(100));
+            if let Some(storage) = local_storage() {
+                let _ = storage.set_item(VOLUME_STORAGE_KEY, &percent.to_string());
+            }
+            apply_volume();
+        }) as Box<dyn FnMut()>);
+        slider.set_oninput(Some(closure.as_ref().unchecked_ref()));
+        closure.forget();
+    }
+
+    if let Some(mute_button) = document
+        .get_element_by_id("muteButton")
+        .and_then(|element| element.dyn_into::<HtmlButtonElement>().ok())
+    {
+        let label = |muted| if muted { "Unmute" } else { "Mute" };
+        mute_button.set_text_content(Some(label(muted)));
+        let button = mute_button.clone();
+        let closure = Closure::wrap(Box::new(move || {
+            let muted = VOLUME.with(|cell| {
+                let mut volume = cell.borrow_mut();
+                volume.1 = !volume.1;
+                volume.1
+            });
+            button.set_text_content(Some(label(muted)));
+            if let Some(storage) = local_storage() {
+                let _ = storage.set_item(MUTED_STORAGE_KEY, &muted.to_string());
+            }
+            apply_volume();
+        }) as Box<dyn FnMut()>);
+        mute_button.set_onclick(Some(closure.as_ref().unchecked_ref()));
+        closure.forget();
+    }
+    Ok(())
+}
+
+/// Fills the stats panel, called every `STATS_INTERVAL_MS`.
+fn show_stats(stats_element: &web_sys::Element) {
+    let (queued_frames, underruns) = RING.with(|cell| {
+        cell.borrow()
+            .as_ref()
+            .map_or((0, 0), |ring| (ring.depth(), ring.underruns()))
+    });
+    let queued_ms = queued_frames as f64 * 1000.0 / SAMPLE_RATE as f64;
+    let decodes_in_flight = DECODES_IN_FLIGHT.with(|cell| *cell.borrow());
+    let text = STATS.with(|cell| {
+        let mut stats = cell.borrow_mut();
+        let packets_per_sec = (stats.packets - stats.packets_at_last_update) as f64 * 1000.0
+            / STATS_INTERVAL_MS as f64;
+        stats.packets_at_last_update = stats.packets;
+        format!(
+            "Packets: {:.0}/s\nDecode queue: {} packet(s)\nQueued for playback: {:.0} ms, playing {:+.1}% fast\nLost: {}, late: {}, dropped: {}, underruns: {}\nEnd-to-end latency: {}",
+            packets_per_sec,
+            decodes_in_flight,
+            queued_ms,
+            stats.stretch_percent,
+            stats.lost_packets,
+            stats.late_packets,
+            stats.dropped_chunks,
+            underruns,
+            stats
+                .latency_ms
+                .map_or(String::from("-"), |latency_ms| format!("{:.0} ms", latency_ms)),
+        )
+    });
+    stats_element.set_text_content(Some(&text));
+}
+
 fn update_status(message: &str) {
     STATUS_ELEMENT.with(|cell| {
         if let Some(status_el) = cell.borrow().as_ref() {
@@ -77,17 +316,62 @@ fn update_status(message: &str) {
     console::log_1(&message.into());
 }
 
-fn init_audio() -> Result<(), JsValue> {
-    console::log_1(&"Initializing AudioContext and AudioDecoder (Rust)...".into());
+/// Shows the track the sink plays, or nothing when no player is playing.
+fn show_now_playing(track: &NowPlaying) {
+    let text = match (&track.title, &track.artist) {
+        (Some(title), Some(artist)) => format!("Now playing: {} by {}", title, artist),
+        (Some(title), None) => format!("Now playing: {}", title),
+        _ => String::new(),
+    };
+    NOW_PLAYING_ELEMENT.with(|cell| {
+        if let Some(now_playing_el) = cell.borrow().as_ref() {
+            now_playing_el.set_text_content(Some(&text));
+        }
+    });
+    media_session::set_track(None, Some(track));
+}
 
-    let context_options = AudioContextOptions::new();
-    context_options.set_sample_rate(SAMPLE_RATE);
-    let audio_context = AudioContext::new_with_context_options(&context_options)?;
+/// Loads the worklet playing the ring buffer into the context, playing into
+/// `destination`.
+async fn start_playback_worklet(
+    audio_context: &AudioContext,
+    destination: &GainNode,
+) -> Result<(), JsValue> {
+    JsFuture::from(audio_context.audio_worklet()?.add_module(WORKLET_URL)?).await?;
+    let ring = RingBuffer::new(
+        SAMPLE_RATE * RING_SECONDS,
+        SAMPLE_RATE * PREBUFFER_MS / 1000,
+    );
+    let options = AudioWorkletNodeOptions::new();
+    options.set_number_of_inputs(0);
+    options.set_output_channel_count(&Array::of1(&2.into()));
+    options.set_processor_options(Some(&ring.processor_options()?));
+    let node = AudioWorkletNode::new_with_options(audio_context, WORKLET_PROCESSOR, &options)?;
+    node.connect_with_audio_node(destination)?;
+    RING.with(|cell| *cell.borrow_mut() = Some(ring));
+    Ok(())
+}
 
-    if audio_context.state() == web_sys::AudioContextState::Suspended {
-        console::log_1(&"AudioContext suspended, attempting to resume...".into());
-        let _ = audio_context.resume()?;
-    }
+async fn init_audio() -> Result<(), JsValue> {
+    console::log_1(&"Initializing AudioContext and AudioDecoder (Rust)...".into());
+
+    // The context outlives sessions, since browsers only let it start playing
+    // right after a click.
+    let audio_context = match AUDIO_CONTEXT.with(|cell| cell.borrow().clone()) {
+        Some(audio_context) => audio_context,
+        None => {
+            let context_options = AudioContextOptions::new();
+            context_options.set_sample_rate(SAMPLE_RATE as f32);
+            let audio_context = AudioContext::new_with_context_options(&context_options)?;
+            let gain_node = audio_context.create_gain()?;
+            gain_node.connect_with_audio_node(&audio_context.destination())?;
+            GAIN_NODE.with(|cell| *cell.borrow_mut() = Some(gain_node.clone()));
+            apply_volume();
+            start_playback_worklet(&audio_context, &gain_node).await?;
+            autoplay::watch(&audio_context);
+            audio_context
+        }
+    };
 
     let handle_decoded_chunk_closure = Closure::wrap(Box::new(move |audio_data: AudioData| {
         if let Err(e) = handle_decoded_chunk_internal(audio_data) {
@@ -102,6 +386,15 @@ fn init_audio() -> Result<(), JsValue> {
         update_status(&format!("Decoder Error: {:?}", e));
     }) as Box<dyn FnMut(JsValue)>);
 
+    // Browsers without WebCodecs decode Opus in Rust and play PCM as is.
+    if !has_web_codecs() {
+        console::warn_1(&"WebCodecs is unavailable, decoding Opus in Rust".into());
+        AUDIO_CONTEXT.with(|cell| *cell.borrow_mut() = Some(audio_context.clone()));
+        autoplay::resume();
+        reset_decoding();
+        return Ok(());
+    }
+
     let decoder_init = AudioDecoderInit::new(
         decoder_error_closure.as_ref().unchecked_ref(),
         handle_decoded_chunk_closure.as_ref().unchecked_ref(),
@@ -111,98 +404,825 @@ fn init_audio() -> Result<(), JsValue> {
 
     let audio_decoder = AudioDecoder::new(&decoder_init)?;
 
-    let decoder_config = AudioDecoderConfig::new("opus", NUMBER_OF_CHANNELS, SAMPLE_RATE as u32);
-    audio_decoder.configure(&decoder_config)?;
-
     AUDIO_CONTEXT.with(|cell| *cell.borrow_mut() = Some(audio_context.clone()));
+    autoplay::resume();
     AUDIO_DECODER.with(|cell| *cell.borrow_mut() = Some(audio_decoder));
-    NEXT_PLAY_TIME.with(|cell| *cell.borrow_mut() = audio_context.current_time());
-    RECEIVED_CHUNK_COUNT.with(|cell| *cell.borrow_mut() = 0);
+    reset_decoding();
+
+    Ok(())
+}
+
+/// Whether the browser has a WebCodecs `AudioDecoder`.
+fn has_web_codecs() -> bool {
+    Reflect::has(&js_sys::global(), &"AudioDecoder".into()).unwrap_or(false)
+}
+
+/// Clears the decoding state left by the previous session.
+fn reset_decoding() {
+    DECODED_CHUNK_COUNT.with(|cell| *cell.borrow_mut() = 0);
+    DECODES_IN_FLIGHT.with(|cell| *cell.borrow_mut() = 0);
+    PENDING_SILENCE.with(|cell| cell.borrow_mut().clear());
+    DRIFT.with(|cell| *cell.borrow_mut() = DriftEstimator::default());
+    DECODER_FORMAT.with(|cell| *cell.borrow_mut() = None);
+    OPUS_DECODER.with(|cell| *cell.borrow_mut() = None);
+    TARGET_DEPTH.with(|cell| *cell.borrow_mut() = None);
+    AVERAGE_DEPTH.with(|cell| *cell.borrow_mut() = 0.0);
+}
+
+fn stream_sample_rate() -> u32 {
+    STREAM_SAMPLE_RATE.with(|cell| *cell.borrow())
+}
+
+/// The WebCodecs configuration decoding `codec` with `channels` at
+/// `sample_rate`, or an error if the codec can't describe them.
+fn decoder_config(
+    codec: Codec,
+    channels: u32,
+    sample_rate: u32,
+) -> Result<AudioDecoderConfig, JsValue> {
+    let unsupported = || {
+        JsValue::from_str(&format!(
+            "{} doesn't support {} channel(s) at {} Hz",
+            codec, channels, sample_rate
+        ))
+    };
+    let channel_count = u8::try_from(channels).map_err(|_| unsupported())?;
+    Ok(match codec {
+        // Raw AAC-LC frames, described by their AudioSpecificConfig.
+        Codec::Aac => {
+            let config = AudioDecoderConfig::new("mp4a.40.2", channels, sample_rate);
+            let description = protocol::aac_audio_specific_config(sample_rate, channel_count)
+                .ok_or_else(unsupported)?;
+            config.set_description(&Uint8Array::from(&description[..]));
+            config
+        }
+        // FLAC decoders expect the stream header the packets go without.
+        Codec::Flac => {
+            let config = AudioDecoderConfig::new("flac", channels, sample_rate);
+            let header =
+                protocol::flac_stream_header(sample_rate, channel_count).ok_or_else(unsupported)?;
+            config.set_description(&Uint8Array::from(&header[..]));
+            config
+        }
+        codec => AudioDecoderConfig::new(&codec.to_string(), channels, sample_rate),
+    })
+}
 
+/// The preferred codecs the browser can decode. Safari, for one, often lacks
+/// Opus, and only Opus, decoded in Rust, and PCM are left without WebCodecs.
+async fn decodable_codecs() -> Result<Vec<Codec>, JsValue> {
+    if !has_web_codecs() {
+        return Ok(vec![Codec::Opus, Codec::Pcm]);
+    }
+    let mut codecs = Vec::new();
+    for codec in PREFERRED_CODECS {
+        if codec != Codec::Pcm {
+            let support = JsFuture::from(AudioDecoder::is_config_supported(&decoder_config(
+                codec,
+                2,
+                SAMPLE_RATE,
+            )?))
+            .await?;
+            if !Reflect::get(&support, &"supported".into())?.is_truthy() {
+                continue;
+            }
+        }
+        codecs.push(codec);
+    }
+    Ok(codecs)
+}
+
+/// Configures the decoder for `codec` with `channels`, unless it already is.
+/// PCM and silence are played without it, and Opus is decoded in Rust when
+/// there's none.
+fn configure_decoder(
+    audio_decoder: Option<&AudioDecoder>,
+    codec: Codec,
+    channels: u32,
+) -> Result<(), JsValue> {
+    if matches!(codec, Codec::Pcm | Codec::Silence)
+        || DECODER_FORMAT.with(|cell| *cell.borrow()) == Some((codec, channels))
+    {
+        return Ok(());
+    }
+    let Some(audio_decoder) = audio_decoder else {
+        if codec != Codec::Opus {
+            return Err(JsValue::from_str(&format!(
+                "Can't decode {} without WebCodecs",
+                codec
+            )));
+        }
+        let decoder = opus::Decoder::new(stream_sample_rate(), channels as usize).map_err(|e| {
+            JsValue::from_str(&format!(
+                "Can't decode Opus at {} Hz: {}",
+                stream_sample_rate(),
+                e
+            ))
+        })?;
+        OPUS_DECODER.with(|cell| *cell.borrow_mut() = Some(decoder));
+        DECODER_FORMAT.with(|cell| *cell.borrow_mut() = Some((codec, channels)));
+        return Ok(());
+    };
+    console::log_1(&format!("Configuring {} decoder for {} channel(s)", codec, channels).into());
+    audio_decoder.configure(&decoder_config(codec, channels, stream_sample_rate())?)?;
+    DECODER_FORMAT.with(|cell| *cell.borrow_mut() = Some((codec, channels)));
+    Ok(())
+}
+
+/// Reconfigures the decoder when the packet's codec, or the channel count
+/// signalled by its Opus TOC byte or FLAC or AAC frame header, differs from
+/// the stream info's.
+fn configure_decoder_for(
+    audio_decoder: Option<&AudioDecoder>,
+    codec: Codec,
+    payload: &[u8],
+) -> Result<(), JsValue> {
+    let channels = match codec {
+        Codec::Opus if payload[0] & OPUS_TOC_STEREO_FLAG != 0 => 2,
+        Codec::Opus => 1,
+        Codec::Flac => match protocol::flac_frame_channels(payload) {
+            Some(channels) => channels as u32,
+            None => return Err(JsValue::from_str("Malformed FLAC frame header")),
+        },
+        Codec::Aac => match protocol::aac_frame_channels(payload) {
+            Some(channels) => channels as u32,
+            None => return Err(JsValue::from_str("Unsupported AAC frame")),
+        },
+        Codec::Pcm | Codec::Silence => return Ok(()),
+        // Only sent to clients that ask for surround, which this one doesn't.
+        Codec::OpusSurround => return Err(JsValue::from_str("Unexpected surround packet")),
+    };
+    configure_decoder(audio_decoder, codec, channels)
+}
+
+/// Copies each channel of `audio_data` into `planes`, as f32 samples, through
+/// `buffer`. Both only grow when a chunk doesn't fit.
+fn copy_planes(
+    audio_data: &AudioData,
+    buffer: &mut Option<Float32Array>,
+    planes: &mut Vec<Vec<f32>>,
+) -> Result<(), JsValue> {
+    let frames = audio_data.number_of_frames();
+    let buffer = match buffer {
+        Some(buffer) if buffer.length() >= frames => buffer,
+        _ => buffer.insert(Float32Array::new_with_length(frames)),
+    };
+    let view = buffer.subarray(0, frames);
+    planes.resize_with(audio_data.number_of_channels() as usize, Vec::new);
+    for (channel, plane) in planes.iter_mut().enumerate() {
+        let copy_to_options = AudioDataCopyToOptions::new(channel as u32);
+        copy_to_options.set_format(AudioSampleFormat::F32Planar);
+        audio_data.copy_to_with_buffer_source(&view, &copy_to_options)?;
+        plane.resize(frames as usize, 0.0);
+        view.copy_to(plane);
+    }
     Ok(())
 }
 
 fn handle_decoded_chunk_internal(audio_data: AudioData) -> Result<(), JsValue> {
+    let mut planes = DECODED_PLANES.with(|cell| std::mem::take(&mut *cell.borrow_mut()));
+    let copied =
+        DECODE_BUFFER.with(|cell| copy_planes(&audio_data, &mut cell.borrow_mut(), &mut planes));
+    let timestamp_us = audio_data.timestamp();
+    audio_data.close();
+    copied?;
+    let in_flight = DECODES_IN_FLIGHT.with(|cell| {
+        let mut in_flight = cell.borrow_mut();
+        *in_flight = in_flight.saturating_sub(1);
+        *in_flight
+    });
+    play_pending_silence(|captured_at_us| (captured_at_us as f64) < timestamp_us)?;
+    let queued = queue_audio(&planes, timestamp_us);
+    DECODED_PLANES.with(|cell| *cell.borrow_mut() = planes);
+    queued?;
+    if in_flight == 0 {
+        play_pending_silence(|_| true)?;
+    }
+    Ok(())
+}
+
+/// Plays a PCM packet's samples, which need no decoding.
+fn play_pcm(header: PacketHeader, payload: &[u8]) -> Result<(), JsValue> {
+    let (channels, samples) = protocol::parse_pcm_payload(payload)
+        .ok_or_else(|| JsValue::from_str("Malformed PCM payload"))?;
+    let planes: Vec<Vec<f32>> = (0..channels as usize)
+        .map(|channel| {
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(channels as usize)
+                .map(|&sample| sample as f32 / 32768.0)
+                .collect()
+        })
+        .collect();
+    queue_audio(&planes, header.captured_at_us as f64)
+}
+
+/// Decodes an Opus packet with the Rust decoder and queues it.
+fn play_opus(header: PacketHeader, payload: &[u8]) -> Result<(), JsValue> {
+    let planes = OPUS_DECODER.with(|cell| {
+        let mut decoder = cell.borrow_mut();
+        let decoder = decoder
+            .as_mut()
+            .ok_or_else(|| JsValue::from_str("Opus decoder not initialized"))?;
+        let channels = decoder.channels();
+        let max_frames = (stream_sample_rate() * MAX_OPUS_PACKET_MS / 1000) as usize;
+        let mut samples = vec![0.0; max_frames * channels];
+        let frames = decoder
+            .decode(payload, &mut samples, max_frames)
+            .map_err(|e| JsValue::from_str(&format!("Opus decode error: {}", e)))?;
+        Ok::<_, JsValue>(
+            (0..channels)
+                .map(|channel| {
+                    samples[..frames * channels]
+                        .iter()
+                        .skip(channel)
+                        .step_by(channels)
+                        .copied()
+                        .collect()
+                })
+                .collect::<Vec<Vec<f32>>>(),
+        )
+    })?;
+    queue_audio(&planes, header.captured_at_us as f64)
+}
+
+/// Keeps time through a silence packet, which needs no decoding. Audio still
+/// in the decoder plays first.
+fn play_silence(header: PacketHeader, payload: &[u8]) -> Result<(), JsValue> {
+    let (channels, frames) = protocol::parse_silence_payload(payload)
+        .ok_or_else(|| JsValue::from_str("Malformed silence payload"))?;
+    let pending = PENDING_SILENCE.with(|cell| {
+        let mut pending = cell.borrow_mut();
+        pending.push_back((header.captured_at_us, channels, frames));
+        pending.len()
+    });
+    if DECODES_IN_FLIGHT.with(|cell| *cell.borrow()) == 0 || pending > MAX_PENDING_SILENCE {
+        DECODES_IN_FLIGHT.with(|cell| *cell.borrow_mut() = 0);
+        play_pending_silence(|_| true)?;
+    }
+    Ok(())
+}
+
+/// Queues the held back silence packets captured at times `due` accepts.
+fn play_pending_silence(due: impl Fn(u64) -> bool) -> Result<(), JsValue> {
+    while let Some((captured_at_us, channels, frames)) = PENDING_SILENCE.with(|cell| {
+        let mut pending = cell.borrow_mut();
+        pending
+            .front()
+            .is_some_and(|&(at, _, _)| due(at))
+            .then(|| pending.pop_front())
+            .flatten()
+    }) {
+        let planes = vec![vec![0.0; frames as usize]; channels as usize];
+        queue_audio(&planes, captured_at_us as f64)?;
+    }
+    Ok(())
+}
+
+/// Queues audio, one plane per channel, behind what the worklet has yet to
+/// play, reporting the latency from `timestamp_us`, the server's capture
+/// time. The worklet plays it resampled to the server's clock rate, so drift
+/// between the clocks neither builds up latency nor runs the queue dry.
+fn queue_audio(planes: &[Vec<f32>], timestamp_us: f64) -> Result<(), JsValue> {
     let audio_context = AUDIO_CONTEXT.with(|cell| {
         cell.borrow()
             .clone()
             .ok_or_else(|| JsValue::from_str("AudioContext not initialized"))
     })?;
+    // A suspended context plays nothing, so queuing would only build up
+    // latency to play through once it resumes.
+    if autoplay::suspended(&audio_context) {
+        STATS.with(|cell| cell.borrow_mut().dropped_chunks += 1);
+        return Ok(());
+    }
+    let drift_ratio = DRIFT.with(|cell| cell.borrow().ratio());
+    let queued_ms = RING.with(|cell| {
+        let ring = cell.borrow();
+        let ring = ring
+            .as_ref()
+            .ok_or_else(|| JsValue::from_str("Playback worklet not initialized"))?;
+        let depth = AVERAGE_DEPTH.with(|cell| {
+            let mut average = cell.borrow_mut();
+            *average += (ring.depth() as f64 - *average) * DEPTH_SMOOTHING;
+            *average
+        });
+        let target = TARGET_DEPTH.with(|cell| {
+            cell.borrow()
+                .unwrap_or(stream_sample_rate() * DEFAULT_TARGET_MS / 1000)
+        });
+        let stretch = depth_stretch(depth, target);
+        STATS.with(|cell| cell.borrow_mut().stretch_percent = (stretch - 1.0) * 100.0);
+        ring.set_rate(
+            (stream_sample_rate() as f64 / audio_context.sample_rate() as f64 / drift_ratio
+                * stretch) as f32,
+        );
+        let queued_ms = ring.depth() as f64 * 1000.0 / audio_context.sample_rate() as f64;
+        if !ring.push(planes) {
+            STATS.with(|cell| cell.borrow_mut().dropped_chunks += 1);
+        }
+        Ok::<_, JsValue>(queued_ms)
+    })?;
 
-    let copy_to_options = AudioDataCopyToOptions::new(0);
-
-    let allocation_size = audio_data.allocation_size(&copy_to_options)?;
-    let mut pcm_data = vec![0; 10240];
-
-    audio_data.copy_to_with_u8_slice(&mut pcm_data, &copy_to_options)?;
-    let audio_buffer = audio_context.create_buffer(
-        audio_data.number_of_channels(),
-        audio_data.number_of_frames(),
-        audio_data.sample_rate(),
-    )?;
-    let float_buffer = unsafe {
-        std::slice::from_raw_parts(
-            pcm_data[..allocation_size as usize * 4].as_ptr() as *const f32,
-            allocation_size as usize,
-        )
-    };
-    audio_buffer.copy_to_channel(&float_buffer, 0)?;
+    // The chunk timestamp is the server's capture time, so this spans capture
+    // to playback (assuming both clocks are in sync).
+    let decoded_chunk_count = DECODED_CHUNK_COUNT.with(|cell| {
+        let mut count = cell.borrow_mut();
+        *count += 1;
+        *count
+    });
+    let latency_ms = js_sys::Date::now() + queued_ms - timestamp_us / 1000.0;
+    STATS.with(|cell| cell.borrow_mut().latency_ms = Some(latency_ms));
+    if decoded_chunk_count.is_multiple_of(LATENCY_REPORT_INTERVAL) {
+        LATENCY_ELEMENT.with(|cell| {
+            if let Some(latency_el) = cell.borrow().as_ref() {
+                latency_el.set_text_content(Some(&format!(
+                    "End-to-end latency: {:.0} ms, clock drift: {:+.0} ppm",
+                    latency_ms,
+                    (drift_ratio - 1.0) * 1e6
+                )));
+            }
+        });
+    }
+    Ok(())
+}
 
-    let source_node = audio_context.create_buffer_source()?;
-    source_node.set_buffer(Some(&audio_buffer));
-    source_node.connect_with_audio_node(&audio_context.destination())?;
+/// How much faster than the stream's clock to play for the ring's `depth` to
+/// approach `target` frames, so the queue neither runs dry nor builds up
+/// latency while the clocks drift apart.
+fn depth_stretch(depth: f64, target: u32) -> f64 {
+    let error = (depth - target as f64) / target as f64;
+    if error.abs() < SETTLED_ERROR {
+        return 1.0;
+    }
+    1.0 + (error / FULL_STRETCH_ERROR).clamp(-1.0, 1.0) * MAX_STRETCH
+}
 
-    let current_audio_context_time = audio_context.current_time();
-    let next_play_time_global = NEXT_PLAY_TIME.with(|cell| *cell.borrow());
+/// Queues one packet for decoding, skipping it if it arrived out of order.
+fn decode_packet(
+    audio_decoder: Option<&AudioDecoder>,
+    header: PacketHeader,
+    payload: &[u8],
+) -> Result<(), JsValue> {
+    STATS.with(|cell| cell.borrow_mut().packets += 1);
+    let arrived_at_us = (js_sys::Date::now() * 1000.0) as u64;
+    let event = RECEPTION.with(|cell| {
+        cell.borrow_mut()
+            .track(header.sequence, header.captured_at_us, arrived_at_us)
+    });
+    match event {
+        SequenceEvent::InOrder => {}
+        SequenceEvent::Gap(missed) => {
+            STATS.with(|cell| cell.borrow_mut().lost_packets += missed as u64);
+            console::warn_1(
+                &format!("{} packets lost before packet {}", missed, header.sequence).into(),
+            );
+        }
+        SequenceEvent::Late => {
+            STATS.with(|cell| cell.borrow_mut().late_packets += 1);
+            return Ok(());
+        }
+    }
+    AUDIO_CONTEXT.with(|cell| {
+        if let Some(audio_context) = cell.borrow().as_ref() {
+            let arrived_us = (audio_context.current_time() * 1_000_000.0) as u64;
+            DRIFT.with(|cell| cell.borrow_mut().observe(header.captured_at_us, arrived_us));
+        }
+    });
+    // Configuring the decoder afresh drops the state the server's previous
+    // encoder left, once the packets queued before are decoded.
+    if header.decoder_reset {
+        DECODER_FORMAT.with(|cell| *cell.borrow_mut() = None);
+    }
+    if payload.is_empty() {
+        return Ok(());
+    }
+    match header.codec {
+        Codec::Pcm => return play_pcm(header, payload),
+        Codec::Silence => return play_silence(header, payload),
+        _ => {}
+    }
+    configure_decoder_for(audio_decoder, header.codec, payload)?;
+    let Some(audio_decoder) = audio_decoder else {
+        return play_opus(header, payload);
+    };
+    let chunk_init = EncodedAudioChunkInit::new(
+        &Uint8Array::from(payload).into(),
+        header.captured_at_us as f64,
+        EncodedAudioChunkType::Key,
+    );
+    chunk_init.set_duration(header.frame_duration_us(stream_sample_rate()));
+    let chunk = EncodedAudioChunk::new(&chunk_init)?;
 
-    let start_at;
-    if next_play_time_global < current_audio_context_time {
-        start_at = current_audio_context_time + 0.005;
+    if audio_decoder.state() == web_sys::CodecState::Configured {
+        audio_decoder.decode(&chunk)?;
+        DECODES_IN_FLIGHT.with(|cell| *cell.borrow_mut() += 1);
     } else {
-        start_at = next_play_time_global;
+        console::warn_1(
+            &format!(
+                "Decoder not configured, skipping packet. State: {:?}",
+                audio_decoder.state()
+            )
+            .into(),
+        );
     }
+    Ok(())
+}
 
-    source_node.start_with_when(start_at)?;
+/// Reads the next chunk of `reader`, or `None` once the stream is done.
+async fn read_chunk(reader: &ReadableStreamDefaultReader) -> Result<Option<JsValue>, JsValue> {
+    let result_obj = JsFuture::from(reader.read()).await?.dyn_into::<Object>()?;
+    let done = Reflect::get(&result_obj, &"done".into())?
+        .as_bool()
+        .unwrap_or(true);
+    if done {
+        return Ok(None);
+    }
+    Ok(Some(Reflect::get(&result_obj, &"value".into())?))
+}
 
-    let updated_next_play_time_global = start_at + audio_buffer.duration();
-    NEXT_PLAY_TIME.with(|cell| *cell.borrow_mut() = updated_next_play_time_global);
+/// Reads the next bytes of the stream behind `reader`, or `None` once it is
+/// done.
+async fn read_bytes(reader: &ReadableStreamDefaultReader) -> Result<Option<Vec<u8>>, JsValue> {
+    match read_chunk(reader).await? {
+        Some(value) if value.is_undefined() || value.is_null() => Ok(Some(Vec::new())),
+        Some(value) => Ok(Some(value.dyn_into::<Uint8Array>()?.to_vec())),
+        None => Ok(None),
+    }
+}
 
-    audio_data.close();
+/// Reads the next control message, given the bytes of the control stream read
+/// so far.
+async fn read_control_message(
+    reader: &ReadableStreamDefaultReader,
+    pending: &mut Vec<u8>,
+) -> Result<Option<ControlMessage>, JsValue> {
+    loop {
+        if let Some(message) = protocol::next_control_message(pending)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?
+        {
+            return Ok(Some(message));
+        }
+        match read_bytes(reader).await? {
+            Some(bytes) => pending.extend(bytes),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Configures playback for the stream the server describes.
+fn apply_stream_info(
+    audio_decoder: Option<&AudioDecoder>,
+    sink: &str,
+    codec: Codec,
+    sample_rate: u32,
+    channels: u8,
+) -> Result<(), JsValue> {
+    console::log_1(
+        &format!(
+            "Sink {}: {} at {} Hz, {} channel(s)",
+            sink, codec, sample_rate, channels
+        )
+        .into(),
+    );
+    STREAM_SAMPLE_RATE.with(|cell| *cell.borrow_mut() = sample_rate);
+    media_session::set_track(Some(sink), None);
+    configure_decoder(audio_decoder, codec, channels as u32)
+}
+
+/// Shows the stream state and stats the server sends on the control stream,
+/// and follows the stream info it resends after a restart or sink switch.
+async fn read_control(
+    reader: ReadableStreamDefaultReader,
+    mut pending: Vec<u8>,
+    audio_decoder: Option<AudioDecoder>,
+) -> Result<(), JsValue> {
+    let mut last_tier = 0;
+    while let Some(message) = read_control_message(&reader, &mut pending).await? {
+        match message {
+            ControlMessage::State { paused } => {
+                media_session::set_paused(paused);
+                update_status(if paused {
+                    "Paused by server"
+                } else {
+                    "Streaming (Rust)"
+                })
+            }
+            ControlMessage::Idle { idle } => update_status(if idle {
+                "Idle, the sink is silent"
+            } else {
+                "Streaming (Rust)"
+            }),
+            ControlMessage::Stats { tier, rtt_ms, .. } => {
+                RTT_MS.with(|cell| *cell.borrow_mut() = rtt_ms);
+                if tier != last_tier {
+                    console::log_1(
+                        &format!("Moved to bitrate tier {} (RTT {:.0} ms)", tier, rtt_ms).into(),
+                    );
+                    last_tier = tier;
+                }
+            }
+            ControlMessage::StreamInfo {
+                sink,
+                codec,
+                sample_rate,
+                channels,
+                ..
+            } => apply_stream_info(audio_decoder.as_ref(), &sink, codec, sample_rate, channels)?,
+            ControlMessage::CertificateRenewed => {
+                console::log_1(&"Server renewed its certificate".into())
+            }
+            ControlMessage::TargetLatency { ms } => apply_target_latency(ms)?,
+            ControlMessage::NowPlaying(track) => show_now_playing(&track),
+            // Only ever sent first, and answered before this reader starts.
+            ControlMessage::PasswordChallenge { .. } => {
+                console::warn_1(&"Server sent a password challenge mid-session".into())
+            }
+        }
+    }
     Ok(())
 }
 
-async fn connect_and_receive() -> Result<(), JsValue> {
-    init_audio()?;
+/// Buffers what the network delay leaves of the target latency the server
+/// granted, starting playback once that much is queued and steering the queue
+/// toward it from then on.
+fn apply_target_latency(ms: u32) -> Result<(), JsValue> {
+    let rtt_ms = RTT_MS.with(|cell| *cell.borrow());
+    let jitter_ms = protocol::jitter_buffer_ms(ms, rtt_ms);
+    console::log_1(
+        &format!(
+            "Playing {} ms behind capture, {} ms of it buffered (RTT {:.0} ms)",
+            ms, jitter_ms, rtt_ms
+        )
+        .into(),
+    );
+    RING.with(|cell| {
+        let ring = cell.borrow();
+        let ring = ring
+            .as_ref()
+            .ok_or_else(|| JsValue::from_str("Playback worklet not initialized"))?;
+        ring.set_prebuffer(stream_sample_rate() * jitter_ms / 1000);
+        TARGET_DEPTH.with(|cell| *cell.borrow_mut() = Some(ring.prebuffer()));
+        Ok(())
+    })
+}
+
+/// Sends `command` to the server on a control stream of its own.
+async fn send_command(transport: &WebTransport, command: &ClientCommand) -> Result<(), JsValue> {
+    let stream = JsFuture::from(transport.create_bidirectional_stream())
+        .await?
+        .dyn_into::<WebTransportBidirectionalStream>()?;
+    let writer = stream.writable().get_writer()?;
+    let mut bytes = vec![protocol::STREAM_CONTROL];
+    bytes.extend(command.encode());
+    JsFuture::from(writer.write_with_chunk(&Uint8Array::from(&bytes[..]))).await?;
+    JsFuture::from(writer.close()).await?;
+    Ok(())
+}
+
+/// Sends the server a receiver report every `RECEIVER_REPORT_INTERVAL` until
+/// the session ends.
+async fn send_receiver_reports(transport: WebTransport) {
+    loop {
+        sleep(protocol::RECEIVER_REPORT_INTERVAL).await;
+        let queued_frames = RING.with(|cell| cell.borrow().as_ref().map_or(0, |ring| ring.depth()));
+        let buffer_ms = queued_frames as f64 * 1000.0 / SAMPLE_RATE as f64;
+        let report = RECEPTION.with(|cell| cell.borrow().report(buffer_ms));
+        if send_command(&transport, &ClientCommand::ReceiverReport(report))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Decodes the audio packets the server sends as datagrams.
+async fn read_datagrams(
+    transport: WebTransport,
+    audio_decoder: Option<AudioDecoder>,
+) -> Result<(), JsValue> {
+    let reader = transport
+        .datagrams()
+        .readable()
+        .get_reader()
+        .dyn_into::<ReadableStreamDefaultReader>()?;
+    while let Some(value) = read_bytes(&reader).await? {
+        let (header, payload) = protocol::parse_packet(&value)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?
+            .ok_or_else(|| JsValue::from_str("Truncated audio datagram"))?;
+        decode_packet(audio_decoder.as_ref(), header, payload)?;
+    }
+    Ok(())
+}
+
+/// Fetches and parses a JSON document from the server's HTTP API, `None` if
+/// the server has none.
+async fn fetch_json(window: &web_sys::Window, url: &str) -> Result<Option<JsValue>, JsValue> {
+    let response = JsFuture::from(window.fetch_with_str(url))
+        .await?
+        .dyn_into::<web_sys::Response>()?;
+    if response.status() == 404 {
+        return Ok(None);
+    }
+    if !response.ok() {
+        return Err(format!("{} failed ({})", url, response.status()).into());
+    }
+    JsFuture::from(response.json()?).await.map(Some)
+}
+
+/// Trades the pairing code for a credential at the server's HTTP API, or
+/// without one asks for a credential the server has to approve.
+async fn pair(window: &web_sys::Window, code: Option<&str>) -> Result<String, JsValue> {
+    let url = match code {
+        Some(code) => format!(
+            "/api/pair?code={}",
+            String::from(js_sys::encode_uri_component(code))
+        ),
+        None => String::from("/api/pair"),
+    };
+    let init = web_sys::RequestInit::new();
+    init.set_method("POST");
+    let response = JsFuture::from(window.fetch_with_str_and_init(&url, &init))
+        .await?
+        .dyn_into::<web_sys::Response>()?;
+    if !response.ok() {
+        return Err(format!("Pairing failed ({})", response.status()).into());
+    }
+    let body = JsFuture::from(response.json()?).await?;
+    Reflect::get(&body, &JsValue::from_str("credential"))?
+        .as_string()
+        .ok_or_else(|| "Server sent no credential".into())
+}
+
+/// The query `fetch_session_token` authenticates with: `?key=<key>` from the
+/// page, or else the credential it paired with. `?pair=<code>`, as in the URL
+/// of a server with `--pairing`, pairs first, and is taken off the URL, so
+/// reloading the page doesn't present it again. Pages with neither ask for a
+/// credential to be approved.
+async fn token_query(
+    window: &web_sys::Window,
+    page_params: &web_sys::UrlSearchParams,
+) -> Result<String, JsValue> {
+    if let Some(key) = page_params.get("key") {
+        return Ok(format!(
+            "key={}",
+            String::from(js_sys::encode_uri_component(&key))
+        ));
+    }
+    let credential = match page_params.get("pair") {
+        Some(code) => {
+            // Codes are tried once, as retrying a wrong one would only count
+            // against the code the server shows.
+            page_params.delete("pair");
+            let search = String::from(page_params.to_string());
+            let location = window.location();
+            let url = match search.as_str() {
+                "" => location.pathname()?,
+                search => format!("{}?{}", location.pathname()?, search),
+            };
+            window
+                .history()?
+                .replace_state_with_url(&JsValue::NULL, "", Some(&url))?;
+            update_status("Pairing...");
+            Some(pair(window, Some(&code)).await?)
+        }
+        None => None,
+    };
+    let stored =
+        || local_storage().and_then(|storage| storage.get_item(CREDENTIAL_STORAGE_KEY).ok()?);
+    let credential = match credential.or_else(stored) {
+        Some(credential) => credential,
+        None => pair(window, None).await?,
+    };
+    if let Some(storage) = local_storage() {
+        storage.set_item(CREDENTIAL_STORAGE_KEY, &credential)?;
+    }
+    Ok(format!(
+        "credential={}",
+        String::from(js_sys::encode_uri_component(&credential))
+    ))
+}
+
+/// Trades the query of `token_query` for a session token at the server's HTTP
+/// API, asking again until a credential waiting for approval gets it.
+async fn fetch_session_token(window: &web_sys::Window, query: &str) -> Result<String, JsValue> {
+    let url = format!("/api/token?{}", query);
+    let response = loop {
+        let response = JsFuture::from(window.fetch_with_str(&url))
+            .await?
+            .dyn_into::<web_sys::Response>()?;
+        if response.ok() {
+            break response;
+        }
+        let status = response.status();
+        let text = JsFuture::from(response.text()?).await?.as_string();
+        if text.as_deref() != Some(APPROVAL_PENDING) {
+            return Err(format!("{} failed ({})", url, status).into());
+        }
+        update_status("Waiting for the server to allow this device...");
+        sleep(APPROVAL_POLL_INTERVAL).await;
+    };
+    let body = JsFuture::from(response.json()?).await?;
+    Reflect::get(&body, &JsValue::from_str("token"))?
+        .as_string()
+        .ok_or_else(|| "Server sent no session token".into())
+}
+
+/// The password the server last let a session start with, or else the one the
+/// listener types in. A password the server turned down is asked for again.
+fn ask_password(window: &web_sys::Window) -> Result<String, JsValue> {
+    if let Some(password) = PASSWORD.with(|cell| cell.borrow_mut().take()) {
+        return Ok(password);
+    }
+    window
+        .prompt_with_message("The server needs a password:")?
+        .ok_or_else(|| JsValue::from_str("No password given"))
+}
+
+/// Accepts the next stream the server opens, returning the kind it starts
+/// with, its reader, and the bytes read after the kind, which are kept for
+/// the stream's reader.
+async fn accept_server_stream(
+    incoming_uni_streams: &ReadableStreamDefaultReader,
+) -> Result<(u8, ReadableStreamDefaultReader, Vec<u8>), JsValue> {
+    let Some(stream) = read_chunk(incoming_uni_streams).await? else {
+        update_status("Server closed connection before opening its streams.");
+        return Err(JsValue::from_str(
+            "Server didn't open the control and media streams.",
+        ));
+    };
+    let reader = stream
+        .dyn_into::<web_sys::ReadableStream>()?
+        .get_reader()
+        .dyn_into::<ReadableStreamDefaultReader>()?;
+    let mut pending = Vec::new();
+    while pending.is_empty() {
+        pending = read_bytes(&reader)
+            .await?
+            .ok_or_else(|| JsValue::from_str("Stream closed before saying what it carries"))?;
+    }
+    let kind = pending.remove(0);
+    Ok((kind, reader, pending))
+}
+
+/// Connects to the server and plays its stream until the connection is lost.
+/// The backoff starts over once the server starts streaming.
+async fn connect_and_receive(backoff: &mut Backoff) -> Result<(), JsValue> {
+    init_audio().await?;
 
     let audio_context_opt = AUDIO_CONTEXT.with(|cell| cell.borrow().clone());
     let audio_decoder_opt = AUDIO_DECODER.with(|cell| cell.borrow().clone());
 
-    if audio_context_opt.is_none() || audio_decoder_opt.is_none() {
+    if audio_context_opt.is_none() {
         update_status("Audio initialization failed. Cannot proceed.");
         return Err("Audio init failed".into());
     }
-    let audio_decoder = audio_decoder_opt.unwrap();
+    let audio_decoder = audio_decoder_opt;
 
     let window = web_sys::window().expect("no global `window` exists");
     let location = window.location();
     let hostname = location.hostname()?;
-    let server_url = format!("https://{}:13345", hostname);
+    // `?transport=datagram` asks for audio as datagrams. `?key=<key>` is the
+    // server's access key, in the URL it prints, and `?token=<token>` a
+    // session token to use instead, as in the server's QR code link.
+    // `?pair=<code>` pairs with a server that prints a pairing code instead.
+    // `?latency=<ms>` asks for a target latency.
+    let page_params = web_sys::UrlSearchParams::new_with_str(&location.search()?)?;
+    let sink = page_params.get("sink").unwrap_or_default();
+    let token = match page_params.get(protocol::TOKEN_QUERY_KEY) {
+        Some(token) => token,
+        None => fetch_session_token(&window, &token_query(&window, &page_params).await?).await?,
+    };
+    let codecs = decodable_codecs().await?;
+    console::log_1(&format!("Browser decodes {:?}", codecs).into());
+    let mut server_url = format!(
+        "https://{}:{}/{}?{}&{}={}",
+        hostname,
+        WEBTRANSPORT_PORT,
+        String::from(js_sys::encode_uri_component(&sink)),
+        protocol::codecs_query(&codecs),
+        protocol::TOKEN_QUERY_KEY,
+        token
+    );
+    if page_params.get("transport").as_deref() == Some("datagram") {
+        server_url = format!("{}&{}", server_url, protocol::DATAGRAM_QUERY);
+    }
     update_status(&format!("Connecting to {}...", server_url));
 
+    // A self-signed certificate is trusted by its hash, fetched fresh in case
+    // the certificate changed. There's none for a Let's Encrypt certificate.
     let cert_hash_js_array = Array::new();
-    let hash_obj = Object::new();
-    Reflect::set(
-        &hash_obj,
-        &JsValue::from_str("algorithm"),
-        &JsValue::from_str("sha-256"),
-    )?;
-    let cert_hash_buffer = Uint8Array::from(&SERVER_CERT_HASH_BYTES[..]).buffer();
-    Reflect::set(
-        &hash_obj,
-        &JsValue::from_str("value"),
-        &JsValue::from(cert_hash_buffer),
-    )?;
-    cert_hash_js_array.push(&hash_obj);
+    if let Some(hash_obj) = fetch_json(&window, "/api/cert-hash").await? {
+        let cert_hash_bytes =
+            Uint8Array::new(&Reflect::get(&hash_obj, &JsValue::from_str("value"))?);
+        Reflect::set(
+            &hash_obj,
+            &JsValue::from_str("value"),
+            &JsValue::from(cert_hash_bytes.buffer()),
+        )?;
+        cert_hash_js_array.push(&hash_obj);
+    }
 
     let transport_options = WebTransportOptions::new();
     transport_options.set_server_certificate_hashes(&cert_hash_js_array);
@@ -212,98 +1232,152 @@ async fn connect_and_receive() -> Result<(), JsValue> {
 
     JsFuture::from(transport.ready()).await?;
     update_status("Connected (Rust)");
-    update_status("Waiting for server to open a unidirectional stream...");
-    let incoming_uni_streams_readable: web_sys::ReadableStream =
-        transport.incoming_unidirectional_streams();
-
-    let incoming_uni_streams_reader_obj: js_sys::Object =
-        incoming_uni_streams_readable.get_reader();
-
-    let incoming_uni_streams_reader: web_sys::ReadableStreamDefaultReader =
-        incoming_uni_streams_reader_obj.dyn_into::<web_sys::ReadableStreamDefaultReader>()?;
-
-    let first_stream_read_result_js = JsFuture::from(incoming_uni_streams_reader.read()).await?;
-    let first_stream_read_result_obj = first_stream_read_result_js.dyn_into::<Object>()?;
-
-    let is_done_receiving_streams = Reflect::get(&first_stream_read_result_obj, &"done".into())?
-        .as_bool()
-        .ok_or_else(|| JsValue::from_str("Failed to read 'done' property from stream result"))?;
-
-    if is_done_receiving_streams {
-        update_status("Server closed connection or no unidirectional streams were opened.");
-        transport.close();
-        return Err(JsValue::from_str(
-            "No incoming unidirectional stream received from server.",
-        ));
-    }
-
-    let stream_value = Reflect::get(&first_stream_read_result_obj, &"value".into())?
-        .dyn_into::<web_sys::ReadableStream>()?;
-    update_status("Received incoming unidirectional stream. Reading data...");
-
-    let reader = stream_value
+    update_status("Waiting for the server's control stream...");
+    let incoming_uni_streams = transport
+        .incoming_unidirectional_streams()
         .get_reader()
         .dyn_into::<ReadableStreamDefaultReader>()?;
-
-    loop {
-        let result_js = JsFuture::from(reader.read()).await?;
-        let result_obj = result_js.dyn_into::<Object>()?;
-
-        let done = Reflect::get(&result_obj, &"done".into())?
-            .as_bool()
-            .unwrap_or(true);
-        if done {
-            update_status("Stream closed by server (Rust).");
-            break;
+    let mut media = None;
+    let (control_reader, mut control_pending) = loop {
+        match accept_server_stream(&incoming_uni_streams).await? {
+            (protocol::STREAM_CONTROL, reader, pending) => break (reader, pending),
+            (protocol::STREAM_MEDIA, reader, pending) => media = Some((reader, pending)),
+            (other, ..) => {
+                return Err(JsValue::from_str(&format!(
+                    "Server opened a stream of unknown kind {}",
+                    other
+                )));
+            }
         }
+    };
 
-        let value_js = Reflect::get(&result_obj, &"value".into())?;
-        if value_js.is_undefined() || value_js.is_null() {
-            continue;
+    // The decoder is configured from the stream info rather than the first
+    // packets.
+    let audio_decoder_for_control = audio_decoder.clone();
+    let mut message = read_control_message(&control_reader, &mut control_pending).await?;
+    // Servers with a password only send the stream info once it's proven.
+    let mut password = None;
+    if let Some(ControlMessage::PasswordChallenge { nonce }) = &message {
+        update_status("Proving the password...");
+        let given = ask_password(&window)?;
+        let proof = protocol::password_proof(&given, nonce);
+        send_command(&transport, &ClientCommand::Authenticate { proof }).await?;
+        password = Some(given);
+        message = read_control_message(&control_reader, &mut control_pending).await?;
+    }
+    let Some(ControlMessage::StreamInfo {
+        sink,
+        codec,
+        sample_rate,
+        channels,
+        datagrams,
+        ..
+    }) = message
+    else {
+        return Err(JsValue::from_str(
+            "Server didn't start the control stream with the stream info",
+        ));
+    };
+    if password.is_some() {
+        PASSWORD.with(|cell| *cell.borrow_mut() = password);
+    }
+    apply_stream_info(audio_decoder.as_ref(), &sink, codec, sample_rate, channels)?;
+    backoff.reset();
+    media_session::session_started();
+    RECEPTION.with(|cell| *cell.borrow_mut() = ReceptionStats::default());
+    wasm_bindgen_futures::spawn_local(send_receiver_reports(transport.clone()));
+    if let Some(ms) = page_params
+        .get("latency")
+        .and_then(|ms| ms.parse::<u32>().ok())
+    {
+        send_command(&transport, &ClientCommand::SetTargetLatency { ms }).await?;
+    }
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(e) =
+            read_control(control_reader, control_pending, audio_decoder_for_control).await
+        {
+            console::warn_1(&format!("Control stream reader stopped: {:?}", e).into());
         }
-        let value_uint8_array = value_js.dyn_into::<Uint8Array>()?;
-
-        if value_uint8_array.length() > 0 {
-            let current_timestamp_us = RECEIVED_CHUNK_COUNT.with(|cell| {
-                let mut count = cell.borrow_mut();
-                let ts = *count * (FRAME_DURATION_MS as u64) * 1000;
-                *count += 1;
-                ts
-            });
-
-            let chunk_init = EncodedAudioChunkInit::new(
-                &value_uint8_array.into(),
-                current_timestamp_us as f64,
-                EncodedAudioChunkType::Key,
-            );
-            chunk_init.set_duration(FRAME_DURATION_MS as f64 * 1000.0);
-
-            let chunk = EncodedAudioChunk::new(&chunk_init)?;
+    });
+    if datagrams {
+        update_status("Receiving audio as datagrams (Rust)");
+        let datagram_transport = transport.clone();
+        let datagram_decoder = audio_decoder.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Err(e) = read_datagrams(datagram_transport, datagram_decoder).await {
+                console::warn_1(&format!("Datagram reader stopped: {:?}", e).into());
+            }
+        });
+    }
 
-            if audio_decoder.state() == web_sys::CodecState::Configured {
-                audio_decoder.decode(&chunk)?;
-            } else {
-                console::warn_1(
-                    &format!(
-                        "Decoder not configured, skipping packet. State: {:?}",
-                        audio_decoder.state()
-                    )
-                    .into(),
-                );
+    // Audio packets follow on the media stream unless they come as datagrams,
+    // in which case it stays open, idle, for the length of the session.
+    let (reader, mut pending) = match media {
+        Some(media) => media,
+        None => match accept_server_stream(&incoming_uni_streams).await? {
+            (protocol::STREAM_MEDIA, reader, pending) => (reader, pending),
+            (other, ..) => {
+                return Err(JsValue::from_str(&format!(
+                    "Server opened a stream of unknown kind {}",
+                    other
+                )));
             }
+        },
+    };
+    loop {
+        while let Some((header, payload)) =
+            protocol::next_packet(&mut pending).map_err(|e| JsValue::from_str(&e.to_string()))?
+        {
+            decode_packet(audio_decoder.as_ref(), header, &payload)?;
         }
+        let Some(bytes) = read_bytes(&reader).await? else {
+            update_status("Stream closed by server (Rust).");
+            return Ok(());
+        };
+        pending.extend(bytes);
     }
+}
 
+/// Closes the session's transport and decoder. Audio already scheduled plays
+/// out.
+fn close_session() {
+    media_session::session_ended();
     TRANSPORT.with(|cell| {
         if let Some(transport) = cell.borrow_mut().take() {
             transport.close();
         }
     });
-    AUDIO_CONTEXT.with(|cell| {
-        if let Some(ctx) = cell.borrow_mut().take() {
-            let _ = ctx.close();
+    AUDIO_DECODER.with(|cell| {
+        if let Some(decoder) = cell.borrow_mut().take()
+            && decoder.state() != web_sys::CodecState::Closed
+        {
+            let _ = decoder.close();
         }
     });
-    update_status("Disconnected (Rust)");
-    Ok(())
+}
+
+async fn sleep(delay: Duration) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let _ = web_sys::window()
+            .expect("no global `window` exists")
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                &resolve,
+                delay.as_millis() as i32,
+            );
+    });
+    let _ = JsFuture::from(promise).await;
+}
+
+/// Plays the server's stream, reconnecting whenever the connection is lost.
+async fn connect_with_retries() {
+    let mut backoff = Backoff::default();
+    loop {
+        if let Err(e) = connect_and_receive(&mut backoff).await {
+            console::error_1(&format!("Connection error: {:?}", e).into());
+        }
+        close_session();
+        let delay = backoff.next_delay();
+        update_status(&format!("Reconnecting in {:.1} s...", delay.as_secs_f64()));
+        sleep(delay).await;
+    }
 }
@@ -0,0 +1,152 @@
+use crate::{TRANSPORT, autoplay, send_command};
+use js_sys::Reflect;
+use std::cell::RefCell;
+use streaming_protocol::{ClientCommand, NowPlaying};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    MediaMetadata, MediaSession, MediaSessionAction, MediaSessionPlaybackState, Navigator,
+    WakeLockSentinel, WakeLockType, console,
+};
+
+/// Shown on the lock screen when the sink doesn't say what it plays.
+const DEFAULT_ARTIST: &str = "pipewire-streaming";
+
+thread_local! {
+    /// Keeps the screen on while a session streams. Browsers release it when
+    /// the page is hidden, so it's taken again once the page is shown.
+    static WAKE_LOCK: RefCell<Option<WakeLockSentinel>> = const { RefCell::new(None) };
+    /// Whether a session is streaming, which the wake lock is held for.
+    static STREAMING: RefCell<bool> = const { RefCell::new(false) };
+    /// The sink streaming and what it plays, for the lock screen.
+    static TRACK: RefCell<(String, NowPlaying)> = RefCell::new(Default::default());
+}
+
+fn navigator() -> Option<Navigator> {
+    Some(web_sys::window()?.navigator())
+}
+
+/// The Media Session, if the browser has one.
+fn media_session() -> Option<MediaSession> {
+    let navigator = navigator()?;
+    Reflect::has(&navigator, &"mediaSession".into())
+        .unwrap_or(false)
+        .then(|| navigator.media_session())
+}
+
+/// Sends `command` on the current session, if there is one.
+fn send(command: ClientCommand) {
+    let Some(transport) = TRANSPORT.with(|cell| cell.borrow().clone()) else {
+        return;
+    };
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(e) = send_command(&transport, &command).await {
+            console::warn_1(&format!("Couldn't send {:?}: {:?}", command, e).into());
+        }
+    });
+}
+
+/// Lets the lock screen's and headset's play and pause controls pause the
+/// stream on the server, and takes the wake lock again whenever the page is
+/// shown.
+pub fn init(document: &web_sys::Document) -> Result<(), JsValue> {
+    if let Some(session) = media_session() {
+        let actions: [(MediaSessionAction, fn()); 3] = [
+            (MediaSessionAction::Play, || {
+                autoplay::resume();
+                send(ClientCommand::Resume);
+            }),
+            (MediaSessionAction::Pause, || send(ClientCommand::Pause)),
+            // A live stream has nothing to stop at, so it's paused.
+            (MediaSessionAction::Stop, || send(ClientCommand::Pause)),
+        ];
+        for (action, handler) in actions {
+            let closure = Closure::wrap(Box::new(handler) as Box<dyn FnMut()>);
+            session.set_action_handler(action, Some(closure.as_ref().unchecked_ref()));
+            closure.forget();
+        }
+    }
+    let shown_document = document.clone();
+    let closure = Closure::wrap(Box::new(move || {
+        if !shown_document.hidden() && STREAMING.with(|cell| *cell.borrow()) {
+            autoplay::resume();
+            request_wake_lock();
+        }
+    }) as Box<dyn FnMut()>);
+    document
+        .add_event_listener_with_callback("visibilitychange", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+    Ok(())
+}
+
+/// Keeps the screen on, where the browser lets pages do so.
+fn request_wake_lock() {
+    let Some(navigator) = navigator() else {
+        return;
+    };
+    if !Reflect::has(&navigator, &"wakeLock".into()).unwrap_or(false)
+        || WAKE_LOCK.with(|cell| cell.borrow().as_ref().is_some_and(|lock| !lock.released()))
+    {
+        return;
+    }
+    wasm_bindgen_futures::spawn_local(async move {
+        match JsFuture::from(navigator.wake_lock().request(WakeLockType::Screen)).await {
+            // The session may have ended while the lock was on its way.
+            Ok(lock) if !STREAMING.with(|cell| *cell.borrow()) => {
+                let _ = lock.unchecked_into::<WakeLockSentinel>().release();
+            }
+            Ok(lock) => WAKE_LOCK.with(|cell| *cell.borrow_mut() = Some(lock.unchecked_into())),
+            Err(e) => console::warn_1(&format!("Couldn't keep the screen on: {:?}", e).into()),
+        }
+    });
+}
+
+/// A session started streaming.
+pub fn session_started() {
+    STREAMING.with(|cell| *cell.borrow_mut() = true);
+    request_wake_lock();
+    set_paused(false);
+}
+
+/// The session ended, so the screen may turn off again.
+pub fn session_ended() {
+    STREAMING.with(|cell| *cell.borrow_mut() = false);
+    if let Some(lock) = WAKE_LOCK.with(|cell| cell.borrow_mut().take()) {
+        let _ = lock.release();
+    }
+    if let Some(session) = media_session() {
+        session.set_playback_state(MediaSessionPlaybackState::None);
+    }
+}
+
+pub fn set_paused(paused: bool) {
+    if let Some(session) = media_session() {
+        session.set_playback_state(match paused {
+            true => MediaSessionPlaybackState::Paused,
+            false => MediaSessionPlaybackState::Playing,
+        });
+    }
+}
+
+/// Shows the track on the lock screen, or else the sink.
+pub fn set_track(sink: Option<&str>, track: Option<&NowPlaying>) {
+    let (sink, track) = TRACK.with(|cell| {
+        let mut current = cell.borrow_mut();
+        if let Some(sink) = sink {
+            current.0 = sink.to_string();
+        }
+        if let Some(track) = track {
+            current.1 = track.clone();
+        }
+        current.clone()
+    });
+    let Some(session) = media_session() else {
+        return;
+    };
+    let Ok(metadata) = MediaMetadata::new() else {
+        return;
+    };
+    metadata.set_title(track.title.as_deref().unwrap_or(&sink));
+    metadata.set_artist(track.artist.as_deref().unwrap_or(DEFAULT_ARTIST));
+    session.set_metadata(Some(&metadata));
+}
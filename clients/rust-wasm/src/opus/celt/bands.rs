@@ -0,0 +1,904 @@
+//! Decoding the normalised shape of each band, splitting it in halves, in
+//! time or between mid and side, until the pulses fit the bits it has.
+
+use super::energy::E_MEANS;
+use super::math::{
+    bitexact_cos, bitexact_log2tan, exp2, frac_mul16, inner_prod, isqrt32, lcg_rand,
+};
+use super::rate::{Allocation, bits2pulses, cache, get_pulses, pulses2bits};
+use super::tables::{EBANDS, LOG_N};
+use super::vq::{alg_unquant, renormalise_vector};
+use super::{NB_EBANDS, SPREAD_AGGRESSIVE};
+use crate::opus::range_decoder::{BITRES, RangeDecoder};
+use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
+
+const QTHETA_OFFSET: i32 = 4;
+const QTHETA_OFFSET_TWOPHASE: i32 = 16;
+/// The widest band, the last at 20 ms.
+const MAX_BAND: usize = 176;
+
+/// The natural to ordery Hadamard index for 2, 4, 8 and 16 blocks, ordery
+/// being a bit-reversed Gray code with the DC last.
+const ORDERY_TABLE: [usize; 30] = [
+    1, 0, 3, 0, 2, 1, 7, 0, 4, 3, 6, 1, 5, 2, 15, 0, 8, 7, 12, 3, 11, 4, 14, 1, 9, 6, 13, 2, 10, 5,
+];
+
+/// What every band of a frame shares while it's decoded.
+struct BandCtx<'a, 'b> {
+    dec: &'b mut RangeDecoder<'a>,
+    i: usize,
+    intensity: usize,
+    spread: usize,
+    tf_change: i32,
+    remaining_bits: i32,
+    seed: u32,
+    disable_inv: bool,
+}
+
+/// How `compute_theta` split a band.
+struct Split {
+    inv: bool,
+    imid: i32,
+    iside: i32,
+    delta: i32,
+    itheta: i32,
+    qalloc: i32,
+}
+
+/// Scales the shapes `x` of each band to their energies `band_log_e` into
+/// `freq`, leaving the bins outside `start..end` and above what's output
+/// after `downsample` silent.
+#[allow(clippy::too_many_arguments)]
+pub fn denormalise_bands(
+    x: &[f32],
+    freq: &mut [f32],
+    band_log_e: &[f32],
+    mut start: usize,
+    mut end: usize,
+    m: usize,
+    downsample: usize,
+    silence: bool,
+) {
+    let n = m * 120;
+    let mut bound = m * EBANDS[end] as usize;
+    if downsample != 1 {
+        bound = bound.min(n / downsample);
+    }
+    if silence {
+        bound = 0;
+        start = 0;
+        end = 0;
+    }
+    let first = m * EBANDS[start] as usize;
+    freq[..first].fill(0.0);
+    for i in start..end {
+        let band = m * EBANDS[i] as usize..m * EBANDS[i + 1] as usize;
+        let lg = band_log_e[i] + E_MEANS[i];
+        let g = exp2(lg.min(32.0));
+        for (f, &x) in freq[band.clone()].iter_mut().zip(&x[band]) {
+            *f = x * g;
+        }
+    }
+    freq[bound..n].fill(0.0);
+}
+
+/// Fills the short blocks of transient bands that got no pulses with noise,
+/// so their energy doesn't collapse.
+#[allow(clippy::too_many_arguments)]
+pub fn anti_collapse(
+    x: &mut [f32],
+    collapse_masks: &[u8],
+    lm: usize,
+    channels: usize,
+    size: usize,
+    start: usize,
+    end: usize,
+    log_e: &[f32],
+    prev1_log_e: &[f32],
+    prev2_log_e: &[f32],
+    pulses: &[i32],
+    mut seed: u32,
+) {
+    for i in start..end {
+        let n0 = (EBANDS[i + 1] - EBANDS[i]) as usize;
+        // In eighths of a bit.
+        let depth = ((1 + pulses[i]) as u32 / n0 as u32) >> lm;
+        let thresh = 0.5 * exp2(-0.125 * depth as f32);
+        let sqrt_1 = 1.0 / ((n0 << lm) as f32).sqrt();
+        for c in 0..channels {
+            let mut prev1 = prev1_log_e[c * NB_EBANDS + i];
+            let mut prev2 = prev2_log_e[c * NB_EBANDS + i];
+            if channels == 1 {
+                prev1 = prev1.max(prev1_log_e[NB_EBANDS + i]);
+                prev2 = prev2.max(prev2_log_e[NB_EBANDS + i]);
+            }
+            let ediff = (log_e[c * NB_EBANDS + i] - prev1.min(prev2)).max(0.0);
+            // Short blocks don't have the energy of long ones.
+            let mut r = 2.0 * exp2(-ediff);
+            if lm == 3 {
+                r *= SQRT_2;
+            }
+            let r = r.min(thresh) * sqrt_1;
+            let offset = c * size + ((EBANDS[i] as usize) << lm);
+            let x = &mut x[offset..offset + (n0 << lm)];
+            let mut renormalize = false;
+            for k in 0..1 << lm {
+                if collapse_masks[i * channels + c] & 1 << k == 0 {
+                    for j in 0..n0 {
+                        seed = lcg_rand(seed);
+                        x[(j << lm) + k] = if seed & 0x8000 != 0 { r } else { -r };
+                    }
+                    renormalize = true;
+                }
+            }
+            if renormalize {
+                renormalise_vector(x, 1.0);
+            }
+        }
+    }
+}
+
+/// Turns the normalised mid `x`, scaled by `mid`, and side `y` back into
+/// left and right.
+fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32) {
+    let xp = mid * inner_prod(y, x);
+    let side = inner_prod(y, y);
+    let el = mid * mid + side - 2.0 * xp;
+    let er = mid * mid + side + 2.0 * xp;
+    if er < 6e-4 || el < 6e-4 {
+        y.copy_from_slice(x);
+        return;
+    }
+    let lgain = 1.0 / el.sqrt();
+    let rgain = 1.0 / er.sqrt();
+    for (x, y) in x.iter_mut().zip(y.iter_mut()) {
+        let l = mid * *x;
+        let r = *y;
+        *x = lgain * (l - r);
+        *y = rgain * (l + r);
+    }
+}
+
+/// Groups the `stride` interleaved blocks of `x` together.
+fn deinterleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let n = n0 * stride;
+    let mut tmp = [0.0; MAX_BAND];
+    for i in 0..stride {
+        let to = if hadamard {
+            ORDERY_TABLE[stride - 2 + i]
+        } else {
+            i
+        };
+        for j in 0..n0 {
+            tmp[to * n0 + j] = x[j * stride + i];
+        }
+    }
+    x[..n].copy_from_slice(&tmp[..n]);
+}
+
+fn interleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let n = n0 * stride;
+    let mut tmp = [0.0; MAX_BAND];
+    for i in 0..stride {
+        let from = if hadamard {
+            ORDERY_TABLE[stride - 2 + i]
+        } else {
+            i
+        };
+        for j in 0..n0 {
+            tmp[j * stride + i] = x[from * n0 + j];
+        }
+    }
+    x[..n].copy_from_slice(&tmp[..n]);
+}
+
+/// One level of the Haar transform on each of the `stride` interleaved
+/// blocks.
+fn haar1(x: &mut [f32], n0: usize, stride: usize) {
+    for i in 0..stride {
+        for j in 0..n0 >> 1 {
+            let tmp1 = FRAC_1_SQRT_2 * x[stride * 2 * j + i];
+            let tmp2 = FRAC_1_SQRT_2 * x[stride * (2 * j + 1) + i];
+            x[stride * 2 * j + i] = tmp1 + tmp2;
+            x[stride * (2 * j + 1) + i] = tmp1 - tmp2;
+        }
+    }
+}
+
+/// How finely a split's angle is coded.
+fn compute_qn(n: usize, b: i32, offset: i32, pulse_cap: i32, stereo: bool) -> i32 {
+    const EXP2_TABLE8: [i32; 8] = [16384, 17866, 19483, 21247, 23170, 25267, 27554, 30048];
+    let mut n2 = 2 * n as i32 - 1;
+    if stereo && n == 2 {
+        n2 -= 1;
+    }
+    // Leaves a stereo split with itheta of 16384 enough bits for a pulse in
+    // the side, which can't be folded.
+    let qb = (b + n2 * offset) / n2;
+    let qb = qb.min(b - pulse_cap - (4 << BITRES)).min(8 << BITRES);
+    if qb < (1 << BITRES >> 1) {
+        1
+    } else {
+        let qn = EXP2_TABLE8[(qb & 7) as usize] >> (14 - (qb >> BITRES));
+        (qn + 1) >> 1 << 1
+    }
+}
+
+/// Decodes the angle splitting the energy of a band's halves, or of its
+/// mid and side, and how the bits are shared between them.
+#[allow(clippy::too_many_arguments)]
+fn compute_theta(
+    ctx: &mut BandCtx,
+    n: usize,
+    b: &mut i32,
+    big_b: usize,
+    b0: usize,
+    lm: i32,
+    stereo: bool,
+    fill: &mut u32,
+) -> Split {
+    let i = ctx.i;
+    let pulse_cap = LOG_N[i] as i32 + lm * (1 << BITRES);
+    let offset = (pulse_cap >> 1)
+        - if stereo && n == 2 {
+            QTHETA_OFFSET_TWOPHASE
+        } else {
+            QTHETA_OFFSET
+        };
+    let mut qn = compute_qn(n, *b, offset, pulse_cap, stereo);
+    if stereo && i >= ctx.intensity {
+        qn = 1;
+    }
+    let dec = &mut *ctx.dec;
+    let tell = dec.tell_frac() as i32;
+    let mut itheta = 0;
+    let mut inv = false;
+    if qn != 1 {
+        if stereo && n > 2 {
+            // A step pdf, with a probability of p0 up to itheta of 8192.
+            let p0 = 3;
+            let x0 = qn / 2;
+            let ft = p0 * (x0 + 1) + x0;
+            let fs = dec.decode(ft as u32) as i32;
+            let x = if fs < (x0 + 1) * p0 {
+                fs / p0
+            } else {
+                x0 + 1 + (fs - (x0 + 1) * p0)
+            };
+            let (fl, fh) = if x <= x0 {
+                (p0 * x, p0 * (x + 1))
+            } else {
+                ((x - 1 - x0) + (x0 + 1) * p0, (x - x0) + (x0 + 1) * p0)
+            };
+            dec.update(fl as u32, fh as u32, ft as u32);
+            itheta = x;
+        } else if b0 > 1 || stereo {
+            itheta = dec.decode_uint(qn as u32 + 1) as i32;
+        } else {
+            // A triangular pdf.
+            let ft = ((qn >> 1) + 1) * ((qn >> 1) + 1);
+            let fm = dec.decode(ft as u32) as i32;
+            let (fs, fl);
+            if fm < (((qn >> 1) * ((qn >> 1) + 1)) >> 1) {
+                itheta = (isqrt32(8 * fm as u32 + 1) as i32 - 1) >> 1;
+                fs = itheta + 1;
+                fl = (itheta * (itheta + 1)) >> 1;
+            } else {
+                itheta = (2 * (qn + 1) - isqrt32(8 * (ft - fm - 1) as u32 + 1) as i32) >> 1;
+                fs = qn + 1 - itheta;
+                fl = ft - (((qn + 1 - itheta) * (qn + 2 - itheta)) >> 1);
+            }
+            dec.update(fl as u32, (fl + fs) as u32, ft as u32);
+        }
+        itheta = (itheta as u32 * 16384 / qn as u32) as i32;
+    } else if stereo {
+        if *b > 2 << BITRES && ctx.remaining_bits > 2 << BITRES {
+            inv = dec.decode_bit_logp(2);
+        }
+        // Downmixing doesn't survive an inverted channel.
+        if ctx.disable_inv {
+            inv = false;
+        }
+    }
+    let qalloc = dec.tell_frac() as i32 - tell;
+    *b -= qalloc;
+
+    let (imid, iside, delta) = match itheta {
+        0 => {
+            *fill &= (1 << big_b) - 1;
+            (32767, 0, -16384)
+        }
+        16384 => {
+            *fill &= ((1 << big_b) - 1) << big_b;
+            (0, 32767, 16384)
+        }
+        _ => {
+            let imid = bitexact_cos(itheta as i16);
+            let iside = bitexact_cos((16384 - itheta) as i16);
+            // The mid and side allocation minimising the squared error.
+            let delta = frac_mul16((n as i32 - 1) << 7, bitexact_log2tan(iside, imid));
+            (imid, iside, delta)
+        }
+    };
+    Split {
+        inv,
+        imid,
+        iside,
+        delta,
+        itheta,
+        qalloc,
+    }
+}
+
+/// A band of a single bin, which is just a sign.
+fn quant_band_n1(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    y: Option<&mut [f32]>,
+    lowband_out: Option<&mut [f32]>,
+) -> u32 {
+    let mut decode_sign = |x: &mut [f32]| {
+        let mut sign = 0;
+        if ctx.remaining_bits >= 1 << BITRES {
+            sign = ctx.dec.decode_bits(1);
+            ctx.remaining_bits -= 1 << BITRES;
+        }
+        x[0] = if sign != 0 { -1.0 } else { 1.0 };
+    };
+    decode_sign(x);
+    if let Some(y) = y {
+        decode_sign(y);
+    }
+    if let Some(lowband_out) = lowband_out {
+        lowband_out[0] = x[0];
+    }
+    1
+}
+
+/// Decodes a mono partition `x` of `n` bins, splitting it in two while it
+/// has more bits than a single one could use, and folding `lowband` into
+/// the ones that get no pulses.
+#[allow(clippy::too_many_arguments)]
+fn quant_partition(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    mut n: usize,
+    mut b: i32,
+    mut big_b: usize,
+    lowband: Option<&[f32]>,
+    mut lm: i32,
+    gain: f32,
+    mut fill: u32,
+) -> u32 {
+    let b0 = big_b;
+    let i = ctx.i;
+    let cache = cache(i, lm);
+    // Splits when there's more than 1.5 bits beyond what the band can use.
+    if lm != -1 && b > cache[cache[0] as usize] as i32 + 12 && n > 2 {
+        n >>= 1;
+        let (x, y) = x[..2 * n].split_at_mut(n);
+        lm -= 1;
+        if big_b == 1 {
+            fill = (fill & 1) | (fill << 1);
+        }
+        big_b = (big_b + 1) >> 1;
+
+        let split = compute_theta(ctx, n, &mut b, big_b, b0, lm, false, &mut fill);
+        let mid = (1.0 / 32768.0) * split.imid as f32;
+        let side = (1.0 / 32768.0) * split.iside as f32;
+        let itheta = split.itheta;
+        let mut delta = split.delta;
+
+        // Gives low-energy short blocks more bits than they'd otherwise get.
+        if b0 > 1 && itheta & 0x3fff != 0 {
+            if itheta > 8192 {
+                // Roughly how pre-echo is masked.
+                delta -= delta >> (4 - lm);
+            } else {
+                // A forward-masking slope of 1.5 dB per 10 ms.
+                delta = 0.min(delta + ((n as i32) << BITRES >> (5 - lm)));
+            }
+        }
+        let mut mbits = 0.max(b.min((b - delta) / 2));
+        let mut sbits = b - mbits;
+        ctx.remaining_bits -= split.qalloc;
+
+        let next_lowband2 = lowband.map(|lowband| &lowband[n..]);
+        let rebalance = ctx.remaining_bits;
+        let mut cm;
+        if mbits >= sbits {
+            cm = quant_partition(ctx, x, n, mbits, big_b, lowband, lm, gain * mid, fill);
+            let rebalance = mbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 0 {
+                sbits += rebalance - (3 << BITRES);
+            }
+            cm |= quant_partition(
+                ctx,
+                y,
+                n,
+                sbits,
+                big_b,
+                next_lowband2,
+                lm,
+                gain * side,
+                fill >> big_b,
+            ) << (b0 >> 1);
+        } else {
+            cm = quant_partition(
+                ctx,
+                y,
+                n,
+                sbits,
+                big_b,
+                next_lowband2,
+                lm,
+                gain * side,
+                fill >> big_b,
+            ) << (b0 >> 1);
+            let rebalance = sbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 16384 {
+                mbits += rebalance - (3 << BITRES);
+            }
+            cm |= quant_partition(ctx, x, n, mbits, big_b, lowband, lm, gain * mid, fill);
+        }
+        return cm;
+    }
+
+    let mut q = bits2pulses(i, lm, b);
+    let mut curr_bits = pulses2bits(i, lm, q);
+    ctx.remaining_bits -= curr_bits;
+    // Never busts the budget.
+    while ctx.remaining_bits < 0 && q > 0 {
+        ctx.remaining_bits += curr_bits;
+        q -= 1;
+        curr_bits = pulses2bits(i, lm, q);
+        ctx.remaining_bits -= curr_bits;
+    }
+    let x = &mut x[..n];
+    if q != 0 {
+        let k = get_pulses(q) as usize;
+        return alg_unquant(x, n, k, ctx.spread, big_b, ctx.dec, gain);
+    }
+
+    // Fills a band without pulses anyway.
+    let cm_mask = ((1u64 << big_b) - 1) as u32;
+    fill &= cm_mask;
+    if fill == 0 {
+        x.fill(0.0);
+        return 0;
+    }
+    let cm = match lowband {
+        None => {
+            for x in x.iter_mut() {
+                ctx.seed = lcg_rand(ctx.seed);
+                *x = (ctx.seed as i32 >> 20) as f32;
+            }
+            cm_mask
+        }
+        Some(lowband) => {
+            for (x, &lowband) in x.iter_mut().zip(lowband) {
+                ctx.seed = lcg_rand(ctx.seed);
+                // About 48 dB below the usual folding level.
+                let tmp = 1.0 / 256.0;
+                *x = lowband + if ctx.seed & 0x8000 != 0 { tmp } else { -tmp };
+            }
+            fill
+        }
+    };
+    renormalise_vector(x, gain);
+    cm
+}
+
+/// Decodes a mono band `x`, changing its time-frequency resolution by the
+/// band's `tf_change` around `quant_partition`, and writes it scaled for
+/// folding into `lowband_out`.
+#[allow(clippy::too_many_arguments)]
+fn quant_band(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    n: usize,
+    b: i32,
+    mut big_b: usize,
+    mut lowband: Option<&mut [f32]>,
+    lm: i32,
+    lowband_out: Option<&mut [f32]>,
+    gain: f32,
+    mut fill: u32,
+) -> u32 {
+    const BIT_INTERLEAVE_TABLE: [u32; 16] = [0, 1, 1, 1, 2, 3, 3, 3, 2, 3, 3, 3, 2, 3, 3, 3];
+    const BIT_DEINTERLEAVE_TABLE: [u32; 16] = [
+        0x00, 0x03, 0x0C, 0x0F, 0x30, 0x33, 0x3C, 0x3F, 0xC0, 0xC3, 0xCC, 0xCF, 0xF0, 0xF3, 0xFC,
+        0xFF,
+    ];
+    let n0 = n;
+    let mut n_b = n / big_b;
+    let long_blocks = big_b == 1;
+    let mut tf_change = ctx.tf_change;
+
+    if n == 1 {
+        return quant_band_n1(ctx, x, None, lowband_out);
+    }
+
+    // Recombines bands for more frequency resolution.
+    let recombine = tf_change.max(0) as usize;
+    for k in 0..recombine {
+        if let Some(lowband) = lowband.as_deref_mut() {
+            haar1(lowband, n >> k, 1 << k);
+        }
+        fill = BIT_INTERLEAVE_TABLE[(fill & 0xF) as usize]
+            | BIT_INTERLEAVE_TABLE[(fill >> 4) as usize] << 2;
+    }
+    big_b >>= recombine;
+    n_b <<= recombine;
+
+    // Splits them for more time resolution.
+    let mut time_divide = 0;
+    while n_b & 1 == 0 && tf_change < 0 {
+        if let Some(lowband) = lowband.as_deref_mut() {
+            haar1(lowband, n_b, big_b);
+        }
+        fill |= fill << big_b;
+        big_b <<= 1;
+        n_b >>= 1;
+        time_divide += 1;
+        tf_change += 1;
+    }
+    let b0 = big_b;
+    let n_b0 = n_b;
+
+    // Puts the samples in time order rather than frequency order.
+    if b0 > 1
+        && let Some(lowband) = lowband.as_deref_mut()
+    {
+        deinterleave_hadamard(lowband, n_b >> recombine, b0 << recombine, long_blocks);
+    }
+
+    let mut cm = quant_partition(ctx, x, n, b, big_b, lowband.as_deref(), lm, gain, fill);
+
+    if b0 > 1 {
+        interleave_hadamard(x, n_b >> recombine, b0 << recombine, long_blocks);
+    }
+    n_b = n_b0;
+    big_b = b0;
+    for _ in 0..time_divide {
+        big_b >>= 1;
+        n_b <<= 1;
+        cm |= cm >> big_b;
+        haar1(x, n_b, big_b);
+    }
+    for k in 0..recombine {
+        cm = BIT_DEINTERLEAVE_TABLE[cm as usize];
+        haar1(x, n0 >> k, 1 << k);
+    }
+    big_b <<= recombine;
+
+    if let Some(lowband_out) = lowband_out {
+        let scale = (n0 as f32).sqrt();
+        for (out, &x) in lowband_out.iter_mut().zip(&x[..n0]) {
+            *out = scale * x;
+        }
+    }
+    cm & ((1 << big_b) - 1)
+}
+
+/// Decodes a stereo band as its mid `x` and side `y`, then turns them into
+/// left and right.
+#[allow(clippy::too_many_arguments)]
+fn quant_band_stereo(
+    ctx: &mut BandCtx,
+    x: &mut [f32],
+    y: &mut [f32],
+    n: usize,
+    mut b: i32,
+    big_b: usize,
+    lowband: Option<&mut [f32]>,
+    lm: i32,
+    lowband_out: Option<&mut [f32]>,
+    mut fill: u32,
+) -> u32 {
+    if n == 1 {
+        return quant_band_n1(ctx, x, Some(y), lowband_out);
+    }
+    let orig_fill = fill;
+    let split = compute_theta(ctx, n, &mut b, big_b, big_b, lm, true, &mut fill);
+    let mid = (1.0 / 32768.0) * split.imid as f32;
+    let side = (1.0 / 32768.0) * split.iside as f32;
+    let itheta = split.itheta;
+    let mut cm;
+
+    if n == 2 {
+        // Mid and side being orthogonal, the side of two bins is a sign.
+        let sbits = if itheta != 0 && itheta != 16384 {
+            1 << BITRES
+        } else {
+            0
+        };
+        let mbits = b - sbits;
+        let c = itheta > 8192;
+        ctx.remaining_bits -= split.qalloc + sbits;
+        let mut sign = 0;
+        if sbits != 0 {
+            sign = ctx.dec.decode_bits(1) as i32;
+        }
+        let sign = 1 - 2 * sign;
+        let (x2, y2) = if c {
+            (&mut *y, &mut *x)
+        } else {
+            (&mut *x, &mut *y)
+        };
+        // The side is folded, so this takes the fill before itheta of 16384
+        // cleared its low bits.
+        cm = quant_band(
+            ctx,
+            x2,
+            n,
+            mbits,
+            big_b,
+            lowband,
+            lm,
+            lowband_out,
+            1.0,
+            orig_fill,
+        );
+        y2[0] = -sign as f32 * x2[1];
+        y2[1] = sign as f32 * x2[0];
+        x[0] *= mid;
+        x[1] *= mid;
+        y[0] *= side;
+        y[1] *= side;
+        for j in 0..2 {
+            let tmp = x[j];
+            x[j] = tmp - y[j];
+            y[j] += tmp;
+        }
+    } else {
+        let mut mbits = 0.max(b.min((b - split.delta) / 2));
+        let mut sbits = b - mbits;
+        ctx.remaining_bits -= split.qalloc;
+
+        // The mid isn't scaled, as it's folded from normalised. The high
+        // bits of fill are always clear for the side, so it's never folded.
+        let rebalance = ctx.remaining_bits;
+        if mbits >= sbits {
+            cm = quant_band(ctx, x, n, mbits, big_b, lowband, lm, lowband_out, 1.0, fill);
+            let rebalance = mbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 0 {
+                sbits += rebalance - (3 << BITRES);
+            }
+            cm |= quant_band(ctx, y, n, sbits, big_b, None, lm, None, side, fill >> big_b);
+        } else {
+            cm = quant_band(ctx, y, n, sbits, big_b, None, lm, None, side, fill >> big_b);
+            let rebalance = sbits - (rebalance - ctx.remaining_bits);
+            if rebalance > 3 << BITRES && itheta != 16384 {
+                mbits += rebalance - (3 << BITRES);
+            }
+            cm |= quant_band(ctx, x, n, mbits, big_b, lowband, lm, lowband_out, 1.0, fill);
+        }
+    }
+
+    if n != 2 {
+        stereo_merge(&mut x[..n], &mut y[..n], mid);
+    }
+    if split.inv {
+        for y in &mut y[..n] {
+            *y = -*y;
+        }
+    }
+    cm
+}
+
+/// Copies enough of the first band's folding data for the second band of a
+/// hybrid frame to fold from.
+fn special_hybrid_folding(
+    norm: &mut [f32],
+    norm2: &mut [f32],
+    start: usize,
+    m: usize,
+    dual_stereo: bool,
+) {
+    let n1 = m * (EBANDS[start + 1] - EBANDS[start]) as usize;
+    let n2 = m * (EBANDS[start + 2] - EBANDS[start + 1]) as usize;
+    if n2 <= n1 {
+        return;
+    }
+    norm.copy_within(2 * n1 - n2..n1, n1);
+    if dual_stereo {
+        norm2.copy_within(2 * n1 - n2..n1, n1);
+    }
+}
+
+/// A copy of the `n` bins of `norm` from `effective_lowband` to fold from,
+/// as the band's `lowband_out` may overlap them.
+fn fold_from<'a>(
+    norm: &[f32],
+    effective_lowband: Option<usize>,
+    n: usize,
+    lowband_buf: &'a mut [f32; MAX_BAND],
+) -> Option<&'a mut [f32]> {
+    let e = effective_lowband?;
+    lowband_buf[..n].copy_from_slice(&norm[e..e + n]);
+    Some(&mut lowband_buf[..n])
+}
+
+/// Decodes the shapes of the bands `start..end` into `x`, and `y` for
+/// stereo, recording which of their short blocks got pulses in
+/// `collapse_masks`.
+#[allow(clippy::too_many_arguments)]
+pub fn quant_all_bands(
+    start: usize,
+    end: usize,
+    x: &mut [f32],
+    mut y: Option<&mut [f32]>,
+    collapse_masks: &mut [u8],
+    pulses: &[i32],
+    short_blocks: bool,
+    spread: usize,
+    tf_res: &[i32],
+    alloc: &Allocation,
+    total_bits: i32,
+    dec: &mut RangeDecoder,
+    lm: i32,
+    seed: &mut u32,
+    disable_inv: bool,
+) {
+    let m = 1 << lm;
+    let big_b = if short_blocks { m } else { 1 };
+    let channels = if y.is_some() { 2 } else { 1 };
+    let norm_offset = m * EBANDS[start] as usize;
+    // The last band is never folded from.
+    let norm_len = m * EBANDS[NB_EBANDS - 1] as usize - norm_offset;
+    let mut norm_buf = [0.0; 2 * 8 * 78];
+    let (norm, norm2) = norm_buf[..2 * norm_len].split_at_mut(norm_len);
+    let mut lowband_buf = [0.0; MAX_BAND];
+
+    let mut balance = alloc.balance;
+    let mut dual_stereo = alloc.dual_stereo;
+    let mut lowband_offset = 0;
+    let mut update_lowband = true;
+    let mut ctx = BandCtx {
+        dec,
+        i: start,
+        intensity: alloc.intensity,
+        spread,
+        tf_change: 0,
+        remaining_bits: 0,
+        seed: *seed,
+        disable_inv,
+    };
+    for i in start..end {
+        ctx.i = i;
+        let last = i == end - 1;
+        let band = m * EBANDS[i] as usize..m * EBANDS[i + 1] as usize;
+        let n = band.len();
+        let tell = ctx.dec.tell_frac() as i32;
+
+        // The bits this band gets.
+        if i != start {
+            balance -= tell;
+        }
+        let remaining_bits = total_bits - tell - 1;
+        ctx.remaining_bits = remaining_bits;
+        let b = if i < alloc.coded_bands {
+            let curr_balance = balance / 3.min(alloc.coded_bands - i) as i32;
+            0.max(16383.min((remaining_bits + 1).min(pulses[i] + curr_balance)))
+        } else {
+            0
+        };
+
+        if (band.start as i32 - n as i32 >= norm_offset as i32 || i == start + 1)
+            && (update_lowband || lowband_offset == 0)
+        {
+            lowband_offset = i;
+        }
+        if i == start + 1 {
+            special_hybrid_folding(norm, norm2, start, m, dual_stereo);
+        }
+
+        let tf_change = tf_res[i];
+        ctx.tf_change = tf_change;
+
+        // A conservative estimate of the collapse masks of the bands folded
+        // from.
+        let mut effective_lowband = None;
+        let (mut x_cm, mut y_cm);
+        if lowband_offset != 0 && (spread != SPREAD_AGGRESSIVE || big_b > 1 || tf_change < 0) {
+            // Never repeats spectral content within a band.
+            let effective = (m * EBANDS[lowband_offset] as usize).saturating_sub(norm_offset + n);
+            let mut fold_start = lowband_offset - 1;
+            while m * EBANDS[fold_start] as usize > effective + norm_offset {
+                fold_start -= 1;
+            }
+            let mut fold_end = lowband_offset;
+            while fold_end < i && (m * EBANDS[fold_end] as usize) < effective + norm_offset + n {
+                fold_end += 1;
+            }
+            x_cm = 0;
+            y_cm = 0;
+            for fold_i in fold_start..fold_end {
+                x_cm |= collapse_masks[fold_i * channels] as u32;
+                y_cm |= collapse_masks[fold_i * channels + channels - 1] as u32;
+            }
+            effective_lowband = Some(effective);
+        } else {
+            // The LCG folds into every block.
+            x_cm = (1 << big_b) - 1;
+            y_cm = x_cm;
+        }
+
+        if dual_stereo && i == alloc.intensity {
+            // Intensity stereo takes over from dual stereo.
+            dual_stereo = false;
+            for (norm, &norm2) in norm[..band.start - norm_offset].iter_mut().zip(&*norm2) {
+                *norm = 0.5 * (*norm + norm2);
+            }
+        }
+
+        let out = band.start - norm_offset..band.end - norm_offset;
+        if dual_stereo {
+            let y = y.as_deref_mut().expect("dual stereo has a second channel");
+            x_cm = quant_band(
+                &mut ctx,
+                &mut x[band.clone()],
+                n,
+                b / 2,
+                big_b,
+                fold_from(norm, effective_lowband, n, &mut lowband_buf),
+                lm,
+                if last {
+                    None
+                } else {
+                    Some(&mut norm[out.clone()])
+                },
+                1.0,
+                x_cm,
+            );
+            y_cm = quant_band(
+                &mut ctx,
+                &mut y[band.clone()],
+                n,
+                b / 2,
+                big_b,
+                fold_from(norm2, effective_lowband, n, &mut lowband_buf),
+                lm,
+                if last { None } else { Some(&mut norm2[out]) },
+                1.0,
+                y_cm,
+            );
+        } else {
+            x_cm = match y.as_deref_mut() {
+                Some(y) => quant_band_stereo(
+                    &mut ctx,
+                    &mut x[band.clone()],
+                    &mut y[band.clone()],
+                    n,
+                    b,
+                    big_b,
+                    fold_from(norm, effective_lowband, n, &mut lowband_buf),
+                    lm,
+                    if last { None } else { Some(&mut norm[out]) },
+                    x_cm | y_cm,
+                ),
+                None => quant_band(
+                    &mut ctx,
+                    &mut x[band.clone()],
+                    n,
+                    b,
+                    big_b,
+                    fold_from(norm, effective_lowband, n, &mut lowband_buf),
+                    lm,
+                    if last { None } else { Some(&mut norm[out]) },
+                    1.0,
+                    x_cm | y_cm,
+                ),
+            };
+            y_cm = x_cm;
+        }
+        collapse_masks[i * channels] = x_cm as u8;
+        collapse_masks[i * channels + channels - 1] = y_cm as u8;
+        balance += pulses[i] + tell;
+
+        // Only moves the folding position on while bands have a bit per bin.
+        update_lowband = b > (n as i32) << BITRES;
+    }
+    *seed = ctx.seed;
+}
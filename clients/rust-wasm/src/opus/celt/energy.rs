@@ -0,0 +1,179 @@
+//! The band energies, coded coarsely with prediction from the previous frame,
+//! then refined with raw bits.
+
+use super::{MAX_FINE_BITS, NB_EBANDS};
+use crate::opus::range_decoder::RangeDecoder;
+
+/// Mean energy of each band, in log2 units.
+pub const E_MEANS: [f32; 25] = [
+    6.4375, 6.25, 5.75, 5.3125, 5.0625, 4.8125, 4.5, 4.375, 4.875, 4.6875, 4.5625, 4.4375, 4.875,
+    4.625, 4.3125, 4.5, 4.375, 4.625, 4.75, 4.4375, 3.75, 3.75, 3.75, 3.75, 3.75,
+];
+
+/// Weight of the previous frame's energy, per LM.
+const PRED_COEF: [f32; 4] = [
+    29440.0 / 32768.0,
+    26112.0 / 32768.0,
+    21248.0 / 32768.0,
+    16384.0 / 32768.0,
+];
+/// Weight of the previous band's energy, per LM.
+const BETA_COEF: [f32; 4] = [
+    30147.0 / 32768.0,
+    22282.0 / 32768.0,
+    12124.0 / 32768.0,
+    6554.0 / 32768.0,
+];
+const BETA_INTRA: f32 = 4915.0 / 32768.0;
+
+/// Probability of 0 and decay of the Laplace distribution of each band's
+/// coarse energy, per LM and for inter and intra frames, in Q8.
+#[rustfmt::skip]
+const E_PROB_MODEL: [[[u8; 42]; 2]; 4] = [
+    [
+        [
+            72, 127, 65, 129, 66, 128, 65, 128, 64, 128, 62, 128, 64, 128,
+            64, 128, 92, 78, 92, 79, 92, 78, 90, 79, 116, 41, 115, 40,
+            114, 40, 132, 26, 132, 26, 145, 17, 161, 12, 176, 10, 177, 11,
+        ],
+        [
+            24, 179, 48, 138, 54, 135, 54, 132, 53, 134, 56, 133, 55, 132,
+            55, 132, 61, 114, 70, 96, 74, 88, 75, 88, 87, 74, 89, 66,
+            91, 67, 100, 59, 108, 50, 120, 40, 122, 37, 97, 43, 78, 50,
+        ],
+    ],
+    [
+        [
+            83, 78, 84, 81, 88, 75, 86, 74, 87, 71, 90, 73, 93, 74,
+            93, 74, 109, 40, 114, 36, 117, 34, 117, 34, 143, 17, 145, 18,
+            146, 19, 162, 12, 165, 10, 178, 7, 189, 6, 190, 8, 177, 9,
+        ],
+        [
+            23, 178, 54, 115, 63, 102, 66, 98, 69, 99, 74, 89, 71, 91,
+            73, 91, 78, 89, 86, 80, 92, 66, 93, 64, 102, 59, 103, 60,
+            104, 60, 117, 52, 123, 44, 138, 35, 133, 31, 97, 38, 77, 45,
+        ],
+    ],
+    [
+        [
+            61, 90, 93, 60, 105, 42, 107, 41, 110, 45, 116, 38, 113, 38,
+            112, 38, 124, 26, 132, 27, 136, 19, 140, 20, 155, 14, 159, 16,
+            158, 18, 170, 13, 177, 10, 187, 8, 192, 6, 175, 9, 159, 10,
+        ],
+        [
+            21, 178, 59, 110, 71, 86, 75, 85, 84, 83, 91, 66, 88, 73,
+            87, 72, 92, 75, 98, 72, 105, 58, 107, 54, 115, 52, 114, 55,
+            112, 56, 129, 51, 132, 40, 150, 33, 140, 29, 98, 35, 77, 42,
+        ],
+    ],
+    [
+        [
+            42, 121, 96, 66, 108, 43, 111, 40, 117, 44, 123, 32, 120, 36,
+            119, 33, 127, 33, 134, 34, 139, 21, 147, 23, 152, 20, 158, 25,
+            154, 26, 166, 21, 173, 16, 184, 13, 184, 10, 150, 13, 139, 15,
+        ],
+        [
+            22, 178, 63, 114, 74, 82, 84, 83, 92, 82, 103, 62, 96, 72,
+            96, 67, 101, 73, 107, 72, 113, 55, 118, 52, 125, 52, 118, 52,
+            117, 55, 135, 49, 137, 39, 157, 32, 145, 29, 97, 33, 77, 40,
+        ],
+    ],
+];
+
+const SMALL_ENERGY_ICDF: [u8; 3] = [2, 1, 0];
+
+pub fn unquant_coarse_energy(
+    start: usize,
+    end: usize,
+    old: &mut [f32],
+    intra: bool,
+    dec: &mut RangeDecoder,
+    channels: usize,
+    lm: usize,
+) {
+    let prob_model = &E_PROB_MODEL[lm][intra as usize];
+    let mut prev = [0.0f32; 2];
+    let (coef, beta) = if intra {
+        (0.0, BETA_INTRA)
+    } else {
+        (PRED_COEF[lm], BETA_COEF[lm])
+    };
+    let budget = dec.storage as i32 * 8;
+    for i in start..end {
+        for c in 0..channels {
+            let tell = dec.tell();
+            let qi = if budget - tell >= 15 {
+                let pi = 2 * i.min(20);
+                dec.decode_laplace(
+                    (prob_model[pi] as u32) << 7,
+                    (prob_model[pi + 1] as i32) << 6,
+                )
+            } else if budget - tell >= 2 {
+                let qi = dec.decode_icdf(&SMALL_ENERGY_ICDF, 2) as i32;
+                (qi >> 1) ^ -(qi & 1)
+            } else if budget - tell >= 1 {
+                -(dec.decode_bit_logp(1) as i32)
+            } else {
+                -1
+            };
+            let q = qi as f32;
+            let band = &mut old[i + c * NB_EBANDS];
+            *band = band.max(-9.0);
+            *band = coef * *band + prev[c] + q;
+            prev[c] = prev[c] + q - beta * q;
+        }
+    }
+}
+
+pub fn unquant_fine_energy(
+    start: usize,
+    end: usize,
+    old: &mut [f32],
+    fine_quant: &[i32],
+    dec: &mut RangeDecoder,
+    channels: usize,
+) {
+    for i in start..end {
+        if fine_quant[i] <= 0 {
+            continue;
+        }
+        for c in 0..channels {
+            let q2 = dec.decode_bits(fine_quant[i] as u32);
+            let offset =
+                (q2 as f32 + 0.5) * (1 << (14 - fine_quant[i])) as f32 * (1.0 / 16384.0) - 0.5;
+            old[i + c * NB_EBANDS] += offset;
+        }
+    }
+}
+
+/// Spends the bits left at the end of the frame on one more bit of energy
+/// for the bands that rounded down, then the others.
+#[allow(clippy::too_many_arguments)]
+pub fn unquant_energy_finalise(
+    start: usize,
+    end: usize,
+    old: &mut [f32],
+    fine_quant: &[i32],
+    fine_priority: &[i32],
+    mut bits_left: i32,
+    dec: &mut RangeDecoder,
+    channels: usize,
+) {
+    for prio in 0..2 {
+        for i in start..end {
+            if bits_left < channels as i32 {
+                break;
+            }
+            if fine_quant[i] >= MAX_FINE_BITS || fine_priority[i] != prio {
+                continue;
+            }
+            for c in 0..channels {
+                let q2 = dec.decode_bits(1);
+                let offset =
+                    (q2 as f32 - 0.5) * (1 << (14 - fine_quant[i] - 1)) as f32 * (1.0 / 16384.0);
+                old[i + c * NB_EBANDS] += offset;
+                bits_left -= 1;
+            }
+        }
+    }
+}
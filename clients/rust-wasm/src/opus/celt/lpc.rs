@@ -0,0 +1,103 @@
+//! The linear prediction CELT conceals lost frames with.
+
+use super::math::inner_prod;
+
+/// The autocorrelation of `x` at lags `0..ac.len()`, after windowing
+/// `window.len()` samples at each end.
+pub fn autocorr(x: &[f32], ac: &mut [f32], window: &[f32]) {
+    let n = x.len();
+    let lag = ac.len() - 1;
+    let overlap = window.len();
+    let mut xx = x.to_vec();
+    for i in 0..overlap {
+        xx[i] = x[i] * window[i];
+        xx[n - i - 1] = x[n - i - 1] * window[i];
+    }
+    let fast_n = n - lag;
+    for (k, ac) in ac.iter_mut().enumerate() {
+        *ac = inner_prod(&xx[..fast_n], &xx[k..k + fast_n]);
+        let d = (k + fast_n..n).fold(0.0, |d, i| d + xx[i] * xx[i - k]);
+        *ac += d;
+    }
+}
+
+/// The LPC coefficients of the autocorrelation `ac`, by Levinson-Durbin.
+pub fn lpc(lpc: &mut [f32], ac: &[f32]) {
+    let p = lpc.len();
+    lpc.fill(0.0);
+    let mut error = ac[0];
+    if ac[0] == 0.0 {
+        return;
+    }
+    for i in 0..p {
+        // This iteration's reflection coefficient.
+        let mut rr = 0.0;
+        for j in 0..i {
+            rr += lpc[j] * ac[i - j];
+        }
+        rr += ac[i + 1];
+        let r = -(rr / error);
+        lpc[i] = r;
+        for j in 0..(i + 1) >> 1 {
+            let tmp1 = lpc[j];
+            let tmp2 = lpc[i - 1 - j];
+            lpc[j] = tmp1 + r * tmp2;
+            lpc[i - 1 - j] = tmp2 + r * tmp1;
+        }
+        error -= r * r * error;
+        // Stops at 30 dB of prediction gain.
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
+/// Filters `x[ord..]` with the FIR `num` into `y`, `x` starting with the
+/// `ord` samples before.
+pub fn fir(x: &[f32], num: &[f32], y: &mut [f32]) {
+    let ord = num.len();
+    for (i, y) in y.iter_mut().enumerate() {
+        let mut sum = x[ord + i];
+        for j in 0..ord {
+            sum += num[ord - j - 1] * x[i + j];
+        }
+        *y = sum;
+    }
+}
+
+/// Filters `x` in place with the IIR `den`, whose memory `mem` holds the
+/// output before it, latest first. Unrolled by 4 like libopus so it rounds
+/// the same, which needs `x.len()` to be a multiple of 4.
+pub fn iir(x: &mut [f32], den: &[f32], mem: &[f32]) {
+    let ord = den.len();
+    let n = x.len();
+    debug_assert_eq!(n % 4, 0);
+    let rden: Vec<f32> = den.iter().rev().copied().collect();
+    let mut y = vec![0.0; n + ord];
+    for i in 0..ord {
+        y[i] = -mem[ord - i - 1];
+    }
+    for i in (0..n).step_by(4) {
+        let mut sum = [x[i], x[i + 1], x[i + 2], x[i + 3]];
+        for j in 0..ord {
+            for (k, sum) in sum.iter_mut().enumerate() {
+                *sum += rden[j] * y[i + j + k];
+            }
+        }
+        // Patches in the outputs the FIR pass didn't have yet.
+        y[i + ord] = -sum[0];
+        x[i] = sum[0];
+        sum[1] += y[i + ord] * den[0];
+        y[i + ord + 1] = -sum[1];
+        x[i + 1] = sum[1];
+        sum[2] += y[i + ord + 1] * den[0];
+        sum[2] += y[i + ord] * den[1];
+        y[i + ord + 2] = -sum[2];
+        x[i + 2] = sum[2];
+        sum[3] += y[i + ord + 2] * den[0];
+        sum[3] += y[i + ord + 1] * den[1];
+        sum[3] += y[i + ord] * den[2];
+        y[i + ord + 3] = -sum[3];
+        x[i + 3] = sum[3];
+    }
+}
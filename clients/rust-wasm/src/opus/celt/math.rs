@@ -0,0 +1,66 @@
+//! The math helpers of libopus's float build, rounded the same way so the
+//! output matches it.
+
+use crate::opus::range_decoder::ilog;
+
+/// CELT's pi, in single precision.
+pub const PI: f32 = std::f32::consts::PI;
+
+/// `cos(pi/2 * x)`.
+pub fn cos_norm(x: f32) -> f32 {
+    ((0.5 * PI * x) as f64).cos() as f32
+}
+
+pub fn exp2(x: f32) -> f32 {
+    (std::f64::consts::LN_2 * x as f64).exp() as f32
+}
+
+pub fn inner_prod(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).fold(0.0, |sum, (x, y)| sum + x * y)
+}
+
+pub fn lcg_rand(seed: u32) -> u32 {
+    seed.wrapping_mul(1664525).wrapping_add(1013904223)
+}
+
+/// `floor(sqrt(val))`, `val` above 0.
+pub fn isqrt32(mut val: u32) -> u32 {
+    let mut g = 0u32;
+    let mut bshift = (ilog(val) - 1) >> 1;
+    let mut b = 1u32 << bshift;
+    loop {
+        let t = ((g << 1) + b) << bshift;
+        if t <= val {
+            g += b;
+            val -= t;
+        }
+        b >>= 1;
+        bshift -= 1;
+        if bshift < 0 {
+            return g;
+        }
+    }
+}
+
+/// Multiplies two Q15 values the way the bitstream requires.
+pub fn frac_mul16(a: i32, b: i32) -> i32 {
+    (16384 + (a as i16 as i32) * (b as i16 as i32)) >> 15
+}
+
+/// `cos(pi/2 * x/16384)` in Q15, exactly as every decoder computes it.
+pub fn bitexact_cos(x: i16) -> i32 {
+    let x = x as i32;
+    let x2 = (4096 + x * x) >> 13;
+    let x2 = (32767 - x2) + frac_mul16(x2, -7651 + frac_mul16(x2, 8277 + frac_mul16(-626, x2)));
+    1 + x2 as i16 as i32
+}
+
+/// `log2(isin/icos)` in Q11, exactly as every decoder computes it.
+pub fn bitexact_log2tan(isin: i32, icos: i32) -> i32 {
+    let lc = ilog(icos as u32);
+    let ls = ilog(isin as u32);
+    let icos = icos << (15 - lc);
+    let isin = isin << (15 - ls);
+    (ls - lc) * (1 << 11) + frac_mul16(isin, frac_mul16(isin, -2597) + 7932)
+        - frac_mul16(icos, frac_mul16(icos, -2597) + 7932)
+}
@@ -0,0 +1,371 @@
+//! The inverse MDCT CELT synthesizes its frames with, computed with a
+//! mixed-radix complex FFT of a quarter of its size.
+
+use super::tables::{FFT_TWIDDLES, MDCT_TWIDDLES};
+
+const MAX_FACTORS: usize = 8;
+const N: usize = 1920;
+const MAX_SHIFT: usize = 3;
+
+#[derive(Clone, Copy, Default)]
+struct Complex {
+    r: f32,
+    i: f32,
+}
+
+impl Complex {
+    fn add(self, other: Self) -> Self {
+        Self {
+            r: self.r + other.r,
+            i: self.i + other.i,
+        }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            r: self.r - other.r,
+            i: self.i - other.i,
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        Self {
+            r: self.r * other.r - self.i * other.i,
+            i: self.r * other.i + self.i * other.r,
+        }
+    }
+}
+
+/// One of the FFT sizes the MDCT sizes need, sharing the twiddles of the
+/// largest.
+struct Fft {
+    /// Steps through the shared twiddles, as `1 << shift`.
+    shift: usize,
+    /// Radix and remaining length of each stage.
+    factors: Vec<(usize, usize)>,
+    bitrev: Vec<usize>,
+}
+
+impl Fft {
+    fn new(nfft: usize, shift: usize) -> Self {
+        let factors = factor(nfft);
+        let mut bitrev = vec![0; nfft];
+        compute_bitrev(0, &mut bitrev, 0, 1, &factors);
+        Self {
+            shift,
+            factors,
+            bitrev,
+        }
+    }
+
+    fn process(&self, twiddles: &[Complex], fout: &mut [Complex]) {
+        let stages = self.factors.len();
+        let mut fstride = [1; MAX_FACTORS + 1];
+        for (stage, &(p, _)) in self.factors.iter().enumerate() {
+            fstride[stage + 1] = fstride[stage] * p;
+        }
+        let mut m = self.factors[stages - 1].1;
+        for stage in (0..stages).rev() {
+            let m2 = if stage != 0 {
+                self.factors[stage - 1].1
+            } else {
+                1
+            };
+            let stride = fstride[stage] << self.shift;
+            match self.factors[stage].0 {
+                2 => bfly2(fout, fstride[stage]),
+                4 => bfly4(fout, stride, twiddles, m, fstride[stage], m2),
+                3 => bfly3(fout, stride, twiddles, m, fstride[stage], m2),
+                5 => bfly5(fout, stride, twiddles, m, fstride[stage], m2),
+                _ => unreachable!("FFT sizes only have factors of 2, 3 and 5"),
+            }
+            m = m2;
+        }
+    }
+}
+
+/// Factors `n` into radix 4, 2, 3 and 5 stages, the radix 4 ones last.
+fn factor(n: usize) -> Vec<(usize, usize)> {
+    let mut radices = Vec::new();
+    let mut p = 4;
+    let mut left = n;
+    loop {
+        while !left.is_multiple_of(p) {
+            p = match p {
+                4 => 2,
+                2 => 3,
+                _ => p + 2,
+            };
+            if p * p > left {
+                p = left;
+            }
+        }
+        left /= p;
+        radices.push(p);
+        if p == 2 && radices.len() > 2 {
+            let last = radices.len() - 1;
+            radices[last] = 4;
+            radices[1] = 2;
+        }
+        if left <= 1 {
+            break;
+        }
+    }
+    radices.reverse();
+    let mut left = n;
+    radices
+        .into_iter()
+        .map(|p| {
+            left /= p;
+            (p, left)
+        })
+        .collect()
+}
+
+fn compute_bitrev(
+    fout: usize,
+    bitrev: &mut [usize],
+    offset: usize,
+    fstride: usize,
+    factors: &[(usize, usize)],
+) {
+    let (p, m) = factors[0];
+    let mut fout = fout;
+    for j in 0..p {
+        if m == 1 {
+            bitrev[offset + j * fstride] = fout + j;
+        } else {
+            compute_bitrev(
+                fout,
+                bitrev,
+                offset + j * fstride,
+                fstride * p,
+                &factors[1..],
+            );
+            fout += m;
+        }
+    }
+}
+
+fn bfly2(fout: &mut [Complex], n: usize) {
+    let tw = std::f32::consts::FRAC_1_SQRT_2;
+    for chunk in fout.chunks_exact_mut(8).take(n) {
+        let (a, b) = chunk.split_at_mut(4);
+        let t = b[0];
+        b[0] = a[0].sub(t);
+        a[0] = a[0].add(t);
+        let t = Complex {
+            r: (b[1].r + b[1].i) * tw,
+            i: (b[1].i - b[1].r) * tw,
+        };
+        b[1] = a[1].sub(t);
+        a[1] = a[1].add(t);
+        let t = Complex {
+            r: b[2].i,
+            i: -b[2].r,
+        };
+        b[2] = a[2].sub(t);
+        a[2] = a[2].add(t);
+        let t = Complex {
+            r: (b[3].i - b[3].r) * tw,
+            i: -(b[3].i + b[3].r) * tw,
+        };
+        b[3] = a[3].sub(t);
+        a[3] = a[3].add(t);
+    }
+}
+
+fn bfly4(fout: &mut [Complex], fstride: usize, tw: &[Complex], m: usize, n: usize, mm: usize) {
+    if m == 1 {
+        for f in fout.chunks_exact_mut(4).take(n) {
+            let scratch0 = f[0].sub(f[2]);
+            f[0] = f[0].add(f[2]);
+            let mut scratch1 = f[1].add(f[3]);
+            f[2] = f[0].sub(scratch1);
+            f[0] = f[0].add(scratch1);
+            scratch1 = f[1].sub(f[3]);
+            f[1] = Complex {
+                r: scratch0.r + scratch1.i,
+                i: scratch0.i - scratch1.r,
+            };
+            f[3] = Complex {
+                r: scratch0.r - scratch1.i,
+                i: scratch0.i + scratch1.r,
+            };
+        }
+        return;
+    }
+    for i in 0..n {
+        let base = i * mm;
+        for j in 0..m {
+            let k = base + j;
+            let s0 = fout[k + m].mul(tw[j * fstride]);
+            let s1 = fout[k + 2 * m].mul(tw[j * fstride * 2]);
+            let s2 = fout[k + 3 * m].mul(tw[j * fstride * 3]);
+            let s5 = fout[k].sub(s1);
+            fout[k] = fout[k].add(s1);
+            let s3 = s0.add(s2);
+            let s4 = s0.sub(s2);
+            fout[k + 2 * m] = fout[k].sub(s3);
+            fout[k] = fout[k].add(s3);
+            fout[k + m] = Complex {
+                r: s5.r + s4.i,
+                i: s5.i - s4.r,
+            };
+            fout[k + 3 * m] = Complex {
+                r: s5.r - s4.i,
+                i: s5.i + s4.r,
+            };
+        }
+    }
+}
+
+fn bfly3(fout: &mut [Complex], fstride: usize, tw: &[Complex], m: usize, n: usize, mm: usize) {
+    let epi3 = tw[fstride * m];
+    for i in 0..n {
+        let base = i * mm;
+        for j in 0..m {
+            let k = base + j;
+            let s1 = fout[k + m].mul(tw[j * fstride]);
+            let s2 = fout[k + 2 * m].mul(tw[j * fstride * 2]);
+            let s3 = s1.add(s2);
+            let mut s0 = s1.sub(s2);
+            let mut fm = Complex {
+                r: fout[k].r - s3.r * 0.5,
+                i: fout[k].i - s3.i * 0.5,
+            };
+            s0.r *= epi3.i;
+            s0.i *= epi3.i;
+            fout[k] = fout[k].add(s3);
+            fout[k + 2 * m] = Complex {
+                r: fm.r + s0.i,
+                i: fm.i - s0.r,
+            };
+            fm.r -= s0.i;
+            fm.i += s0.r;
+            fout[k + m] = fm;
+        }
+    }
+}
+
+fn bfly5(fout: &mut [Complex], fstride: usize, tw: &[Complex], m: usize, n: usize, mm: usize) {
+    let ya = tw[fstride * m];
+    let yb = tw[fstride * 2 * m];
+    for i in 0..n {
+        let base = i * mm;
+        for u in 0..m {
+            let k0 = base + u;
+            let (k1, k2, k3, k4) = (k0 + m, k0 + 2 * m, k0 + 3 * m, k0 + 4 * m);
+            let s0 = fout[k0];
+            let s1 = fout[k1].mul(tw[u * fstride]);
+            let s2 = fout[k2].mul(tw[2 * u * fstride]);
+            let s3 = fout[k3].mul(tw[3 * u * fstride]);
+            let s4 = fout[k4].mul(tw[4 * u * fstride]);
+            let s7 = s1.add(s4);
+            let s10 = s1.sub(s4);
+            let s8 = s2.add(s3);
+            let s9 = s2.sub(s3);
+            fout[k0] = Complex {
+                r: fout[k0].r + (s7.r + s8.r),
+                i: fout[k0].i + (s7.i + s8.i),
+            };
+            let s5 = Complex {
+                r: s0.r + (s7.r * ya.r + s8.r * yb.r),
+                i: s0.i + (s7.i * ya.r + s8.i * yb.r),
+            };
+            let s6 = Complex {
+                r: s10.i * ya.i + s9.i * yb.i,
+                i: -(s10.r * ya.i + s9.r * yb.i),
+            };
+            fout[k1] = s5.sub(s6);
+            fout[k4] = s5.add(s6);
+            let s11 = Complex {
+                r: s0.r + (s7.r * yb.r + s8.r * ya.r),
+                i: s0.i + (s7.i * yb.r + s8.i * ya.r),
+            };
+            let s12 = Complex {
+                r: s9.i * ya.i - s10.i * yb.i,
+                i: s10.r * yb.i - s9.r * ya.i,
+            };
+            fout[k2] = s11.add(s12);
+            fout[k3] = s11.sub(s12);
+        }
+    }
+}
+
+/// The MDCTs of CELT's 48 kHz mode, of 1920 samples and each halving down to
+/// 240.
+pub struct Mdct {
+    ffts: Vec<Fft>,
+    twiddles: Vec<Complex>,
+    scratch: Vec<Complex>,
+}
+
+impl Mdct {
+    pub fn new() -> Self {
+        let nfft = N >> 2;
+        Self {
+            ffts: (0..=MAX_SHIFT)
+                .map(|shift| Fft::new(nfft >> shift, shift))
+                .collect(),
+            twiddles: FFT_TWIDDLES
+                .iter()
+                .map(|&(r, i)| Complex { r, i })
+                .collect(),
+            scratch: vec![Complex::default(); nfft],
+        }
+    }
+
+    /// Inverse transforms the coefficients `input[0]`, `input[stride]`, ... of
+    /// an MDCT of `1920 >> shift`, windowing the `overlap` samples at each end
+    /// of `out` with `window` so they add up with the neighbouring frames.
+    pub fn backward(
+        &mut self,
+        input: &[f32],
+        out: &mut [f32],
+        window: &[f32],
+        overlap: usize,
+        shift: usize,
+        stride: usize,
+    ) {
+        let mut n = N;
+        let mut trig = &MDCT_TWIDDLES[..];
+        for _ in 0..shift {
+            trig = &trig[n / 2..];
+            n >>= 1;
+        }
+        let n2 = n >> 1;
+        let n4 = n >> 2;
+        let fft = &self.ffts[shift];
+        let scratch = &mut self.scratch[..n4];
+
+        // Pre-rotation, stored in bit-reversed order.
+        for i in 0..n4 {
+            let x1 = input[2 * i * stride];
+            let x2 = input[stride * (n2 - 1 - 2 * i)];
+            let yr = x2 * trig[i] + x1 * trig[n4 + i];
+            let yi = x1 * trig[i] - x2 * trig[n4 + i];
+            // Real and imaginary parts are swapped to use an FFT as an IFFT.
+            scratch[fft.bitrev[i]] = Complex { r: yi, i: yr };
+        }
+        fft.process(&self.twiddles, scratch);
+
+        // Post-rotation, which would scale by 2 if the windows didn't.
+        let out_half = &mut out[overlap >> 1..];
+        for (k, s) in scratch.iter().enumerate() {
+            let (re, im) = (s.i, s.r);
+            let (t0, t1) = (trig[k], trig[n4 + k]);
+            out_half[2 * k] = re * t0 + im * t1;
+            out_half[2 * (n4 - 1 - k) + 1] = re * t1 - im * t0;
+        }
+
+        // Mirrors both sides for TDAC.
+        for i in 0..overlap / 2 {
+            let x1 = out[overlap - 1 - i];
+            let x2 = out[i];
+            let (w1, w2) = (window[i], window[overlap - 1 - i]);
+            out[i] = w2 * x2 - w1 * x1;
+            out[overlap - 1 - i] = w1 * x2 + w2 * x1;
+        }
+    }
+}
@@ -0,0 +1,896 @@
+//! The CELT layer of Opus, RFC 6716 section 4.3: the MDCT codec of the
+//! CELT-only and hybrid modes, ported from libopus's float build.
+
+mod bands;
+mod cwrs;
+mod energy;
+mod lpc;
+mod math;
+mod mdct;
+mod pitch;
+mod rate;
+mod tables;
+mod vq;
+
+use super::Error;
+use super::range_decoder::{BITRES, RangeDecoder};
+use bands::{anti_collapse, denormalise_bands, quant_all_bands};
+use energy::{unquant_coarse_energy, unquant_energy_finalise, unquant_fine_energy};
+use lpc::{autocorr, fir, iir, lpc};
+use math::lcg_rand;
+use mdct::Mdct;
+use pitch::{pitch_downsample, pitch_search};
+use rate::{compute_allocation, init_caps};
+use std::cmp::Ordering;
+use tables::EBANDS;
+pub use tables::WINDOW;
+use vq::renormalise_vector;
+
+pub const NB_EBANDS: usize = 21;
+/// Most bits of fine energy a band gets.
+pub const MAX_FINE_BITS: i32 = 8;
+
+pub const SPREAD_NONE: usize = 0;
+pub const SPREAD_NORMAL: usize = 2;
+pub const SPREAD_AGGRESSIVE: usize = 3;
+
+/// Samples of the windows the MDCTs overlap by.
+const OVERLAP: usize = 120;
+/// Bins of the MDCT of a 2.5 ms frame.
+const SHORT_MDCT_SIZE: usize = 120;
+const MAX_LM: usize = 3;
+/// Past output kept for the PLC and the postfilter, per channel.
+const DECODE_BUFFER_SIZE: usize = 2048;
+const LPC_ORDER: usize = 24;
+const MAX_PERIOD: usize = 1024;
+/// The pitch lags the PLC searches, 66.67 to 480 Hz.
+const PLC_PITCH_LAG_MAX: usize = 720;
+const PLC_PITCH_LAG_MIN: usize = 100;
+const COMBFILTER_MINPERIOD: usize = 15;
+/// The deemphasis filter's coefficient.
+const PREEMPH: f32 = 0.8500061;
+/// Log energy of silent bands.
+const SILENT_LOG_E: f32 = -28.0;
+
+const TRIM_ICDF: [u8; 11] = [126, 124, 119, 109, 87, 41, 19, 9, 4, 2, 0];
+const SPREAD_ICDF: [u8; 4] = [25, 23, 2, 0];
+const TAPSET_ICDF: [u8; 3] = [2, 1, 0];
+
+/// The time-frequency resolution change of each band, per LM, by
+/// transient, tf_select and the band's coded flag.
+const TF_SELECT_TABLE: [[i8; 8]; 4] = [
+    [0, -1, 0, -1, 0, -1, 0, -1],
+    [0, -1, 0, -2, 1, 0, 1, -1],
+    [0, -2, 0, -3, 2, 0, 1, -1],
+    [0, -2, 0, -3, 3, 0, 1, -1],
+];
+
+/// The taps of the postfilter for each tapset.
+const COMB_GAINS: [[f32; 3]; 3] = [
+    [0.30664063, 0.21704102, 0.12963867],
+    [0.4638672, 0.2680664, 0.0],
+    [0.7998047, 0.100097656, 0.0],
+];
+
+/// Decodes CELT frames of 2.5 to 20 ms, at 48 kHz or downsampled to the
+/// lower rates Opus supports.
+pub struct CeltDecoder {
+    channels: usize,
+    /// Channels coded in the frames, which may be mixed up or down to
+    /// `channels`.
+    pub stream_channels: usize,
+    downsample: usize,
+    /// The bands coded, which hybrid frames start at 17.
+    pub start: usize,
+    pub end: usize,
+    disable_inv: bool,
+
+    /// The final state of the range decoder, for checking against the
+    /// encoder's.
+    pub rng: u32,
+    last_pitch_index: usize,
+    loss_count: u32,
+    skip_plc: bool,
+    postfilter_period: usize,
+    postfilter_period_old: usize,
+    postfilter_gain: f32,
+    postfilter_gain_old: f32,
+    postfilter_tapset: usize,
+    postfilter_tapset_old: usize,
+    preemph_mem: [f32; 2],
+    /// Per channel, the output history then the overlap of the next frame.
+    decode_mem: [Vec<f32>; 2],
+    lpc: [[f32; LPC_ORDER]; 2],
+    old_band_e: [f32; 2 * NB_EBANDS],
+    old_log_e: [f32; 2 * NB_EBANDS],
+    old_log_e2: [f32; 2 * NB_EBANDS],
+    background_log_e: [f32; 2 * NB_EBANDS],
+    mdct: Mdct,
+}
+
+impl CeltDecoder {
+    pub fn new(sample_rate: u32, channels: usize) -> Result<Self, Error> {
+        let downsample = match sample_rate {
+            48000 => 1,
+            24000 => 2,
+            16000 => 3,
+            12000 => 4,
+            8000 => 6,
+            _ => return Err(Error::BadArg),
+        };
+        if !(1..=2).contains(&channels) {
+            return Err(Error::BadArg);
+        }
+        let mut celt = Self {
+            channels,
+            stream_channels: channels,
+            downsample,
+            start: 0,
+            end: NB_EBANDS,
+            disable_inv: channels == 1,
+            rng: 0,
+            last_pitch_index: 0,
+            loss_count: 0,
+            skip_plc: true,
+            postfilter_period: 0,
+            postfilter_period_old: 0,
+            postfilter_gain: 0.0,
+            postfilter_gain_old: 0.0,
+            postfilter_tapset: 0,
+            postfilter_tapset_old: 0,
+            preemph_mem: [0.0; 2],
+            decode_mem: std::array::from_fn(|_| vec![0.0; DECODE_BUFFER_SIZE + OVERLAP]),
+            lpc: [[0.0; LPC_ORDER]; 2],
+            old_band_e: [0.0; 2 * NB_EBANDS],
+            old_log_e: [0.0; 2 * NB_EBANDS],
+            old_log_e2: [0.0; 2 * NB_EBANDS],
+            background_log_e: [0.0; 2 * NB_EBANDS],
+            mdct: Mdct::new(),
+        };
+        celt.reset();
+        Ok(celt)
+    }
+
+    pub fn reset(&mut self) {
+        self.rng = 0;
+        self.last_pitch_index = 0;
+        self.loss_count = 0;
+        self.skip_plc = true;
+        self.postfilter_period = 0;
+        self.postfilter_period_old = 0;
+        self.postfilter_gain = 0.0;
+        self.postfilter_gain_old = 0.0;
+        self.postfilter_tapset = 0;
+        self.postfilter_tapset_old = 0;
+        self.preemph_mem = [0.0; 2];
+        for mem in &mut self.decode_mem {
+            mem.fill(0.0);
+        }
+        self.lpc = [[0.0; LPC_ORDER]; 2];
+        self.old_band_e = [0.0; 2 * NB_EBANDS];
+        self.old_log_e = [SILENT_LOG_E; 2 * NB_EBANDS];
+        self.old_log_e2 = [SILENT_LOG_E; 2 * NB_EBANDS];
+        self.background_log_e = [0.0; 2 * NB_EBANDS];
+    }
+
+    /// Decodes the frame `dec` reads into `frame_size` interleaved samples
+    /// per channel of `pcm`, concealing a lost one when it's `None`.
+    pub fn decode(
+        &mut self,
+        dec: Option<&mut RangeDecoder>,
+        pcm: &mut [f32],
+        frame_size: usize,
+    ) -> Result<usize, Error> {
+        let cc = self.channels;
+        let channels = self.stream_channels;
+        let start = self.start;
+        let end = self.end;
+        let lm = (0..=MAX_LM)
+            .find(|&lm| SHORT_MDCT_SIZE << lm == frame_size * self.downsample)
+            .ok_or(Error::BadArg)?;
+        let m = 1 << lm;
+        let n = m * SHORT_MDCT_SIZE;
+        let eff_end = end.min(NB_EBANDS);
+
+        let dec = match dec {
+            Some(dec) if dec.storage > 1 => dec,
+            _ => {
+                self.decode_lost(n, lm);
+                self.deemphasis(pcm, n);
+                return Ok(frame_size);
+            }
+        };
+        let len = dec.storage as i32;
+
+        // Only two frames in a row make for a pitch to conceal with.
+        self.skip_plc = self.loss_count != 0;
+
+        if channels == 1 {
+            for i in 0..NB_EBANDS {
+                self.old_band_e[i] = self.old_band_e[i].max(self.old_band_e[NB_EBANDS + i]);
+            }
+        }
+
+        let mut total_bits = len * 8;
+        let mut tell = dec.tell();
+        let silence = if tell >= total_bits {
+            true
+        } else if tell == 1 {
+            dec.decode_bit_logp(15)
+        } else {
+            false
+        };
+        if silence {
+            tell = len * 8;
+            dec.skip_to_end();
+        }
+
+        let mut postfilter_gain = 0.0;
+        let mut postfilter_pitch = 0;
+        let mut postfilter_tapset = 0;
+        if start == 0 && tell + 16 <= total_bits {
+            if dec.decode_bit_logp(1) {
+                let octave = dec.decode_uint(6);
+                postfilter_pitch = ((16 << octave) + dec.decode_bits(4 + octave) - 1) as usize;
+                let qg = dec.decode_bits(3);
+                if dec.tell() + 2 <= total_bits {
+                    postfilter_tapset = dec.decode_icdf(&TAPSET_ICDF, 2);
+                }
+                postfilter_gain = 0.09375 * (qg + 1) as f32;
+            }
+            tell = dec.tell();
+        }
+
+        let transient = if lm > 0 && tell + 3 <= total_bits {
+            let transient = dec.decode_bit_logp(3);
+            tell = dec.tell();
+            transient
+        } else {
+            false
+        };
+
+        let intra = tell + 3 <= total_bits && dec.decode_bit_logp(3);
+        unquant_coarse_energy(start, end, &mut self.old_band_e, intra, dec, channels, lm);
+
+        let mut tf_res = [0; NB_EBANDS];
+        tf_decode(start, end, transient, &mut tf_res, lm, dec);
+
+        tell = dec.tell();
+        let spread = if tell + 4 <= total_bits {
+            dec.decode_icdf(&SPREAD_ICDF, 5)
+        } else {
+            SPREAD_NORMAL
+        };
+
+        let mut cap = [0; NB_EBANDS];
+        init_caps(&mut cap, lm as i32, channels as i32);
+
+        let mut offsets = [0; NB_EBANDS];
+        let mut dynalloc_logp = 6;
+        total_bits <<= BITRES;
+        let mut tell = dec.tell_frac() as i32;
+        for i in start..end {
+            let width = (channels as i32 * (EBANDS[i + 1] - EBANDS[i]) as i32) << lm;
+            // 6 bits, but no more than 1 bit and no less than 1/8 bit a bin.
+            let quanta = (width << BITRES).min((6 << BITRES).max(width));
+            let mut loop_logp = dynalloc_logp;
+            let mut boost = 0;
+            while tell + ((loop_logp as i32) << BITRES) < total_bits && boost < cap[i] {
+                let flag = dec.decode_bit_logp(loop_logp);
+                tell = dec.tell_frac() as i32;
+                if !flag {
+                    break;
+                }
+                boost += quanta;
+                total_bits -= quanta;
+                loop_logp = 1;
+            }
+            offsets[i] = boost;
+            // Makes boosting the next bands more likely.
+            if boost > 0 {
+                dynalloc_logp = 2.max(dynalloc_logp - 1);
+            }
+        }
+
+        let alloc_trim = if tell + (6 << BITRES) <= total_bits {
+            dec.decode_icdf(&TRIM_ICDF, 7) as i32
+        } else {
+            5
+        };
+
+        let mut bits = ((len * 8) << BITRES) - dec.tell_frac() as i32 - 1;
+        let anti_collapse_rsv = if transient && lm >= 2 && bits >= ((lm as i32 + 2) << BITRES) {
+            1 << BITRES
+        } else {
+            0
+        };
+        bits -= anti_collapse_rsv;
+
+        let mut pulses = [0; NB_EBANDS];
+        let mut fine_quant = [0; NB_EBANDS];
+        let mut fine_priority = [0; NB_EBANDS];
+        let alloc = compute_allocation(
+            start,
+            end,
+            &offsets,
+            &cap,
+            alloc_trim,
+            bits,
+            &mut pulses,
+            &mut fine_quant,
+            &mut fine_priority,
+            channels as i32,
+            lm as i32,
+            dec,
+        );
+
+        unquant_fine_energy(start, end, &mut self.old_band_e, &fine_quant, dec, channels);
+
+        for mem in &mut self.decode_mem[..cc] {
+            mem.copy_within(n..DECODE_BUFFER_SIZE + OVERLAP / 2, 0);
+        }
+
+        let mut collapse_masks = [0; 2 * NB_EBANDS];
+        let mut x = [0.0; 2 * 960];
+        let x = &mut x[..channels * n];
+        let (x_mid, x_side) = x.split_at_mut(n);
+        quant_all_bands(
+            start,
+            end,
+            x_mid,
+            (channels == 2).then_some(x_side),
+            &mut collapse_masks,
+            &pulses,
+            transient,
+            spread,
+            &tf_res,
+            &alloc,
+            len * (8 << BITRES) - anti_collapse_rsv,
+            dec,
+            lm as i32,
+            &mut self.rng,
+            self.disable_inv,
+        );
+
+        let anti_collapse_on = anti_collapse_rsv > 0 && dec.decode_bits(1) != 0;
+
+        unquant_energy_finalise(
+            start,
+            end,
+            &mut self.old_band_e,
+            &fine_quant,
+            &fine_priority,
+            len * 8 - dec.tell(),
+            dec,
+            channels,
+        );
+
+        if anti_collapse_on {
+            anti_collapse(
+                x,
+                &collapse_masks,
+                lm,
+                channels,
+                n,
+                start,
+                end,
+                &self.old_band_e,
+                &self.old_log_e,
+                &self.old_log_e2,
+                &pulses,
+                self.rng,
+            );
+        }
+
+        if silence {
+            self.old_band_e[..channels * NB_EBANDS].fill(SILENT_LOG_E);
+        }
+
+        self.synthesis(x, start, eff_end, channels, transient, lm, silence);
+
+        for mem in &mut self.decode_mem[..cc] {
+            self.postfilter_period = self.postfilter_period.max(COMBFILTER_MINPERIOD);
+            self.postfilter_period_old = self.postfilter_period_old.max(COMBFILTER_MINPERIOD);
+            comb_filter(
+                mem,
+                DECODE_BUFFER_SIZE - n,
+                None,
+                self.postfilter_period_old,
+                self.postfilter_period,
+                SHORT_MDCT_SIZE,
+                self.postfilter_gain_old,
+                self.postfilter_gain,
+                self.postfilter_tapset_old,
+                self.postfilter_tapset,
+                OVERLAP,
+            );
+            if lm != 0 {
+                comb_filter(
+                    mem,
+                    DECODE_BUFFER_SIZE - n + SHORT_MDCT_SIZE,
+                    None,
+                    self.postfilter_period,
+                    postfilter_pitch,
+                    n - SHORT_MDCT_SIZE,
+                    self.postfilter_gain,
+                    postfilter_gain,
+                    self.postfilter_tapset,
+                    postfilter_tapset,
+                    OVERLAP,
+                );
+            }
+        }
+        self.postfilter_period_old = self.postfilter_period;
+        self.postfilter_gain_old = self.postfilter_gain;
+        self.postfilter_tapset_old = self.postfilter_tapset;
+        self.postfilter_period = postfilter_pitch;
+        self.postfilter_gain = postfilter_gain;
+        self.postfilter_tapset = postfilter_tapset;
+        if lm != 0 {
+            self.postfilter_period_old = self.postfilter_period;
+            self.postfilter_gain_old = self.postfilter_gain;
+            self.postfilter_tapset_old = self.postfilter_tapset;
+        }
+
+        if channels == 1 {
+            self.old_band_e.copy_within(..NB_EBANDS, NB_EBANDS);
+        }
+
+        if !transient {
+            self.old_log_e2 = self.old_log_e;
+            self.old_log_e = self.old_band_e;
+            // The noise floor rises by up to 2.4 dB a second, or 6 dB a
+            // frame in DTX.
+            let max_background_increase = if self.loss_count < 10 {
+                m as f32 * 0.001
+            } else {
+                1.0
+            };
+            for (background, &band) in self.background_log_e.iter_mut().zip(&self.old_band_e) {
+                *background = (*background + max_background_increase).min(band);
+            }
+        } else {
+            for (log_e, &band) in self.old_log_e.iter_mut().zip(&self.old_band_e) {
+                *log_e = log_e.min(band);
+            }
+        }
+        // In case start or end change.
+        for c in 0..2 {
+            for i in (0..start).chain(end..NB_EBANDS) {
+                self.old_band_e[c * NB_EBANDS + i] = 0.0;
+                self.old_log_e[c * NB_EBANDS + i] = SILENT_LOG_E;
+                self.old_log_e2[c * NB_EBANDS + i] = SILENT_LOG_E;
+            }
+        }
+        self.rng = dec.rng;
+
+        self.deemphasis(pcm, n);
+        self.loss_count = 0;
+        if dec.tell() > 8 * len {
+            return Err(Error::Internal);
+        }
+        Ok(frame_size)
+    }
+
+    /// Inverse transforms the shapes `x` scaled to the band energies into
+    /// the last `n` samples of the output history, plus the overlap.
+    #[allow(clippy::too_many_arguments)]
+    fn synthesis(
+        &mut self,
+        x: &[f32],
+        start: usize,
+        eff_end: usize,
+        channels: usize,
+        transient: bool,
+        lm: usize,
+        silence: bool,
+    ) {
+        let m = 1 << lm;
+        let n = m * SHORT_MDCT_SIZE;
+        let (blocks, nb, shift) = if transient {
+            (m, SHORT_MDCT_SIZE, MAX_LM)
+        } else {
+            (1, n, MAX_LM - lm)
+        };
+        let out = DECODE_BUFFER_SIZE - n;
+        let mut freq = [0.0; 960];
+        let freq = &mut freq[..n];
+        let downsample = self.downsample;
+
+        if self.channels == 2 && channels == 1 {
+            denormalise_bands(
+                x,
+                freq,
+                &self.old_band_e,
+                start,
+                eff_end,
+                m,
+                downsample,
+                silence,
+            );
+            for mem in &mut self.decode_mem {
+                for b in 0..blocks {
+                    self.mdct.backward(
+                        &freq[b..],
+                        &mut mem[out + nb * b..],
+                        &WINDOW,
+                        OVERLAP,
+                        shift,
+                        blocks,
+                    );
+                }
+            }
+        } else if self.channels == 1 && channels == 2 {
+            let mut freq2 = [0.0; 960];
+            let freq2 = &mut freq2[..n];
+            denormalise_bands(
+                x,
+                freq,
+                &self.old_band_e,
+                start,
+                eff_end,
+                m,
+                downsample,
+                silence,
+            );
+            denormalise_bands(
+                &x[n..],
+                freq2,
+                &self.old_band_e[NB_EBANDS..],
+                start,
+                eff_end,
+                m,
+                downsample,
+                silence,
+            );
+            for (f, f2) in freq.iter_mut().zip(freq2.iter()) {
+                *f = 0.5 * *f + 0.5 * f2;
+            }
+            for b in 0..blocks {
+                let mem = &mut self.decode_mem[0][out + nb * b..];
+                self.mdct
+                    .backward(&freq[b..], mem, &WINDOW, OVERLAP, shift, blocks);
+            }
+        } else {
+            for c in 0..self.channels {
+                denormalise_bands(
+                    &x[c * n..],
+                    freq,
+                    &self.old_band_e[c * NB_EBANDS..],
+                    start,
+                    eff_end,
+                    m,
+                    downsample,
+                    silence,
+                );
+                for b in 0..blocks {
+                    let mem = &mut self.decode_mem[c][out + nb * b..];
+                    self.mdct
+                        .backward(&freq[b..], mem, &WINDOW, OVERLAP, shift, blocks);
+                }
+            }
+        }
+    }
+
+    /// Conceals a lost frame of `n` samples, extrapolating the last pitch
+    /// period for the first few and fading to comfort noise after.
+    fn decode_lost(&mut self, n: usize, lm: usize) {
+        let cc = self.channels;
+        let start = self.start;
+        let loss_count = self.loss_count;
+        let noise_based = loss_count >= 5 || start != 0 || self.skip_plc;
+        if noise_based {
+            let end = self.end;
+            let eff_end = start.max(end.min(NB_EBANDS));
+            let mut x = [0.0; 2 * 960];
+            let x = &mut x[..cc * n];
+
+            let decay = if loss_count == 0 { 1.5 } else { 0.5 };
+            for c in 0..cc {
+                for i in start..end {
+                    let i = c * NB_EBANDS + i;
+                    self.old_band_e[i] = self.background_log_e[i].max(self.old_band_e[i] - decay);
+                }
+            }
+            let mut seed = self.rng;
+            for c in 0..cc {
+                for i in start..eff_end {
+                    let band = n * c + ((EBANDS[i] as usize) << lm)
+                        ..n * c + ((EBANDS[i + 1] as usize) << lm);
+                    for x in &mut x[band.clone()] {
+                        seed = lcg_rand(seed);
+                        *x = (seed as i32 >> 20) as f32;
+                    }
+                    renormalise_vector(&mut x[band], 1.0);
+                }
+            }
+            self.rng = seed;
+
+            for mem in &mut self.decode_mem[..cc] {
+                mem.copy_within(n..DECODE_BUFFER_SIZE + OVERLAP / 2, 0);
+            }
+
+            self.synthesis(x, start, eff_end, cc, false, lm, false);
+        } else {
+            let (pitch_index, fade) = if loss_count == 0 {
+                self.last_pitch_index = self.plc_pitch_search();
+                (self.last_pitch_index, 1.0)
+            } else {
+                (self.last_pitch_index, 0.8)
+            };
+
+            // Two pitch periods of excitation show whether the signal is
+            // decaying, within what's kept.
+            let exc_length = (2 * pitch_index).min(MAX_PERIOD);
+
+            for c in 0..cc {
+                let buf = &mut self.decode_mem[c];
+                let lpc_c = &mut self.lpc[c];
+                // The excitation, after the LPC_ORDER samples before it.
+                let mut exc = [0.0; MAX_PERIOD + LPC_ORDER];
+                exc.copy_from_slice(
+                    &buf[DECODE_BUFFER_SIZE - MAX_PERIOD - LPC_ORDER..DECODE_BUFFER_SIZE],
+                );
+
+                if loss_count == 0 {
+                    // The LPC of the last MAX_PERIOD samples before the first
+                    // loss, to work in the excitation domain.
+                    let mut ac = [0.0; LPC_ORDER + 1];
+                    autocorr(&exc[LPC_ORDER..], &mut ac, &WINDOW);
+                    // A noise floor of -40 dB.
+                    ac[0] *= 1.0001;
+                    // Lag windowing stabilizes the Levinson-Durbin recursion.
+                    for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+                        *ac -= *ac * (0.008 * 0.008) * i as f32 * i as f32;
+                    }
+                    lpc(lpc_c, &ac);
+                }
+
+                let mut fir_tmp = [0.0; MAX_PERIOD];
+                let fir_tmp = &mut fir_tmp[..exc_length];
+                fir(&exc[MAX_PERIOD - exc_length..], lpc_c, fir_tmp);
+                exc[LPC_ORDER + MAX_PERIOD - exc_length..].copy_from_slice(fir_tmp);
+                let exc = &exc[LPC_ORDER..];
+
+                // Avoids adding energy to a decaying signal.
+                let decay = {
+                    let mut e1 = 1.0;
+                    let mut e2 = 1.0;
+                    let decay_length = exc_length >> 1;
+                    for i in 0..decay_length {
+                        let e = exc[MAX_PERIOD - decay_length + i];
+                        e1 += e * e;
+                        let e = exc[MAX_PERIOD - 2 * decay_length + i];
+                        e2 += e * e;
+                    }
+                    let e1 = f32::min(e1, e2);
+                    (e1 / e2).sqrt()
+                };
+
+                // Makes room for the new frame, dropping the overlap past
+                // the end that goes unused.
+                buf.copy_within(n..DECODE_BUFFER_SIZE, 0);
+
+                // Repeats the last pitch period, decaying each period, over
+                // the frame and the overlap at both ends.
+                let extrapolation_offset = MAX_PERIOD - pitch_index;
+                let extrapolation_len = n + OVERLAP;
+                let mut attenuation = fade * decay;
+                let mut s1 = 0.0;
+                let mut j = 0;
+                for i in 0..extrapolation_len {
+                    if j >= pitch_index {
+                        j -= pitch_index;
+                        attenuation *= decay;
+                    }
+                    buf[DECODE_BUFFER_SIZE - n + i] = attenuation * exc[extrapolation_offset + j];
+                    // The energy of the output the excitation was taken from.
+                    let tmp = buf[DECODE_BUFFER_SIZE - MAX_PERIOD - n + extrapolation_offset + j];
+                    s1 += tmp * tmp;
+                    j += 1;
+                }
+
+                // Filters the excitation back into a signal continuing the
+                // last output.
+                let mut lpc_mem = [0.0; LPC_ORDER];
+                for (i, mem) in lpc_mem.iter_mut().enumerate() {
+                    *mem = buf[DECODE_BUFFER_SIZE - n - 1 - i];
+                }
+                let extrapolated =
+                    &mut buf[DECODE_BUFFER_SIZE - n..DECODE_BUFFER_SIZE - n + extrapolation_len];
+                iir(extrapolated, lpc_c, &lpc_mem);
+
+                // Attenuates the synthesis if it's louder than what it came
+                // from, written to catch NaNs from the filter as well.
+                let s2: f32 = extrapolated.iter().fold(0.0, |s2, x| s2 + x * x);
+                if s1.partial_cmp(&(0.2 * s2)) != Some(Ordering::Greater) {
+                    extrapolated.fill(0.0);
+                } else if s1 < s2 {
+                    let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+                    for (i, x) in extrapolated.iter_mut().enumerate() {
+                        if i < OVERLAP {
+                            *x *= 1.0 - WINDOW[i] * (1.0 - ratio);
+                        } else {
+                            *x *= ratio;
+                        }
+                    }
+                }
+
+                // Applies the prefilter to the overlap, since the decoder
+                // applies the postfilter to it again after the next MDCT.
+                let mut etmp = [0.0; OVERLAP];
+                comb_filter(
+                    buf,
+                    DECODE_BUFFER_SIZE,
+                    Some(&mut etmp),
+                    self.postfilter_period,
+                    self.postfilter_period,
+                    OVERLAP,
+                    -self.postfilter_gain,
+                    -self.postfilter_gain,
+                    self.postfilter_tapset,
+                    self.postfilter_tapset,
+                    0,
+                );
+
+                // Simulates the TDAC of the MDCT so the next frame blends in.
+                for i in 0..OVERLAP / 2 {
+                    buf[DECODE_BUFFER_SIZE + i] =
+                        WINDOW[i] * etmp[OVERLAP - 1 - i] + WINDOW[OVERLAP - i - 1] * etmp[i];
+                }
+            }
+        }
+        self.loss_count = loss_count + 1;
+    }
+
+    fn plc_pitch_search(&self) -> usize {
+        let mut lp_pitch_buf = [0.0; DECODE_BUFFER_SIZE >> 1];
+        let channels: Vec<&[f32]> = self.decode_mem[..self.channels]
+            .iter()
+            .map(|mem| &mem[..DECODE_BUFFER_SIZE])
+            .collect();
+        pitch_downsample(&channels, &mut lp_pitch_buf);
+        let pitch_index = pitch_search(
+            &lp_pitch_buf[PLC_PITCH_LAG_MAX >> 1..],
+            &lp_pitch_buf,
+            DECODE_BUFFER_SIZE - PLC_PITCH_LAG_MAX,
+            PLC_PITCH_LAG_MAX - PLC_PITCH_LAG_MIN,
+        );
+        PLC_PITCH_LAG_MAX - pitch_index
+    }
+
+    /// Undoes the preemphasis of the last `n` samples of the output history
+    /// into `pcm`, interleaved and downsampled.
+    fn deemphasis(&mut self, pcm: &mut [f32], n: usize) {
+        let channels = self.channels;
+        for c in 0..channels {
+            let x = &self.decode_mem[c][DECODE_BUFFER_SIZE - n..DECODE_BUFFER_SIZE];
+            let mut m = self.preemph_mem[c];
+            for (j, &x) in x.iter().enumerate() {
+                // 1e-30 keeps the filter out of denormals.
+                let tmp = x + 1e-30 + m;
+                m = PREEMPH * tmp;
+                if j % self.downsample == 0 {
+                    pcm[j / self.downsample * channels + c] = tmp * (1.0 / 32768.0);
+                }
+            }
+            self.preemph_mem[c] = m;
+        }
+    }
+}
+
+/// Reads how much each band's time-frequency resolution changes.
+fn tf_decode(
+    start: usize,
+    end: usize,
+    transient: bool,
+    tf_res: &mut [i32],
+    lm: usize,
+    dec: &mut RangeDecoder,
+) {
+    let mut budget = dec.storage as i32 * 8;
+    let mut tell = dec.tell();
+    let mut logp = if transient { 2 } else { 4 };
+    let tf_select_rsv = lm > 0 && tell + (logp as i32) < budget;
+    budget -= tf_select_rsv as i32;
+    let mut tf_changed = 0;
+    let mut curr = 0;
+    for tf_res in &mut tf_res[start..end] {
+        if tell + logp as i32 <= budget {
+            curr ^= dec.decode_bit_logp(logp) as usize;
+            tell = dec.tell();
+            tf_changed |= curr;
+        }
+        *tf_res = curr as i32;
+        logp = if transient { 4 } else { 5 };
+    }
+    let table = &TF_SELECT_TABLE[lm][4 * transient as usize..];
+    let mut tf_select = 0;
+    if tf_select_rsv && table[tf_changed] != table[2 + tf_changed] {
+        tf_select = dec.decode_bit_logp(1) as usize;
+    }
+    for tf_res in &mut tf_res[start..end] {
+        *tf_res = table[2 * tf_select + *tf_res as usize] as i32;
+    }
+}
+
+/// The pitch postfilter over the `n` samples of `buf` from `x`, in place or
+/// into `y`, crossfading over `overlap` samples from the period `t0`, gain
+/// `g0` and taps `tapset0` to the others.
+#[allow(clippy::too_many_arguments)]
+fn comb_filter(
+    buf: &mut [f32],
+    x: usize,
+    mut y: Option<&mut [f32]>,
+    t0: usize,
+    t1: usize,
+    n: usize,
+    g0: f32,
+    g1: f32,
+    tapset0: usize,
+    tapset1: usize,
+    mut overlap: usize,
+) {
+    if g0 == 0.0 && g1 == 0.0 {
+        if let Some(y) = y {
+            y[..n].copy_from_slice(&buf[x..x + n]);
+        }
+        return;
+    }
+    let mut put = |buf: &mut [f32], i: usize, v: f32| match y.as_deref_mut() {
+        Some(y) => y[i] = v,
+        None => buf[x + i] = v,
+    };
+    // The period is 0 when the gain is, so this keeps it from reading
+    // garbage.
+    let t0 = t0.max(COMBFILTER_MINPERIOD);
+    let t1 = t1.max(COMBFILTER_MINPERIOD);
+    let g00 = g0 * COMB_GAINS[tapset0][0];
+    let g01 = g0 * COMB_GAINS[tapset0][1];
+    let g02 = g0 * COMB_GAINS[tapset0][2];
+    let g10 = g1 * COMB_GAINS[tapset1][0];
+    let g11 = g1 * COMB_GAINS[tapset1][1];
+    let g12 = g1 * COMB_GAINS[tapset1][2];
+    let mut x1 = buf[x - t1 + 1];
+    let mut x2 = buf[x - t1];
+    let mut x3 = buf[x - t1 - 1];
+    let mut x4 = buf[x - t1 - 2];
+    // Nothing to crossfade when the filter doesn't change.
+    if g0 == g1 && t0 == t1 && tapset0 == tapset1 {
+        overlap = 0;
+    }
+    for i in 0..overlap {
+        let x0 = buf[x + i - t1 + 2];
+        let f = WINDOW[i] * WINDOW[i];
+        let xt = |k: isize| buf[(x + i - t0).wrapping_add_signed(k)];
+        let v = buf[x + i]
+            + ((1.0 - f) * g00) * xt(0)
+            + ((1.0 - f) * g01) * (xt(1) + xt(-1))
+            + ((1.0 - f) * g02) * (xt(2) + xt(-2))
+            + (f * g10) * x2
+            + (f * g11) * (x1 + x3)
+            + (f * g12) * (x0 + x4);
+        put(buf, i, v);
+        x4 = x3;
+        x3 = x2;
+        x2 = x1;
+        x1 = x0;
+    }
+    if g1 == 0.0 {
+        for i in overlap..n {
+            let v = buf[x + i];
+            put(buf, i, v);
+        }
+        return;
+    }
+    // The part with the constant filter.
+    for i in overlap..n {
+        let x0 = buf[x + i - t1 + 2];
+        let v = buf[x + i] + g10 * x2 + g11 * (x1 + x3) + g12 * (x0 + x4);
+        put(buf, i, v);
+        x4 = x3;
+        x3 = x2;
+        x2 = x1;
+        x1 = x0;
+    }
+}
@@ -0,0 +1,139 @@
+//! Finding the pitch period a lost frame is extrapolated with.
+
+use super::lpc::{autocorr, lpc};
+use super::math::inner_prod;
+
+/// Halves the sample rate of the channels `x` into `x_lp`, mixing them down
+/// and whitening the result.
+pub fn pitch_downsample(x: &[&[f32]], x_lp: &mut [f32]) {
+    let half = x_lp.len();
+    for (c, x) in x.iter().enumerate() {
+        for i in 0..half {
+            let lp = if i == 0 {
+                0.5 * (0.5 * x[1] + x[0])
+            } else {
+                0.5 * (0.5 * (x[2 * i - 1] + x[2 * i + 1]) + x[2 * i])
+            };
+            if c == 0 {
+                x_lp[i] = lp;
+            } else {
+                x_lp[i] += lp;
+            }
+        }
+    }
+
+    let mut ac = [0.0; 5];
+    autocorr(x_lp, &mut ac, &[]);
+    // A noise floor of -40 dB.
+    ac[0] *= 1.0001;
+    // Lag windowing.
+    for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+        *ac -= *ac * (0.008 * i as f32) * (0.008 * i as f32);
+    }
+    let mut coefs = [0.0; 4];
+    lpc(&mut coefs, &ac);
+    let mut tmp = 1.0;
+    for coef in &mut coefs {
+        tmp *= 0.9;
+        *coef *= tmp;
+    }
+    // Adds a zero.
+    let c1 = 0.8;
+    let num = [
+        coefs[0] + 0.8,
+        coefs[1] + c1 * coefs[0],
+        coefs[2] + c1 * coefs[1],
+        coefs[3] + c1 * coefs[2],
+        c1 * coefs[3],
+    ];
+    let mut mem = [0.0; 5];
+    for x in x_lp {
+        let mut sum = *x;
+        for (num, mem) in num.iter().zip(&mem) {
+            sum += num * mem;
+        }
+        mem.copy_within(0..4, 1);
+        mem[0] = *x;
+        *x = sum;
+    }
+}
+
+/// The two lags in `0..xcorr.len()` whose correlations are highest for
+/// their energy.
+fn find_best_pitch(xcorr: &[f32], y: &[f32], len: usize) -> [usize; 2] {
+    let mut syy = 1.0;
+    let mut best_num = [-1.0; 2];
+    let mut best_den = [0.0; 2];
+    let mut best_pitch = [0, 1];
+    for y in &y[..len] {
+        syy += y * y;
+    }
+    for (i, &xcorr) in xcorr.iter().enumerate() {
+        if xcorr > 0.0 {
+            // Avoids both underflowing and overflowing when squared.
+            let xcorr16 = xcorr * 1e-12;
+            let num = xcorr16 * xcorr16;
+            if num * best_den[1] > best_num[1] * syy {
+                if num * best_den[0] > best_num[0] * syy {
+                    best_num[1] = best_num[0];
+                    best_den[1] = best_den[0];
+                    best_pitch[1] = best_pitch[0];
+                    best_num[0] = num;
+                    best_den[0] = syy;
+                    best_pitch[0] = i;
+                } else {
+                    best_num[1] = num;
+                    best_den[1] = syy;
+                    best_pitch[1] = i;
+                }
+            }
+        }
+        syy += y[i + len] * y[i + len] - y[i] * y[i];
+        syy = syy.max(1.0);
+    }
+    best_pitch
+}
+
+/// The lag below `max_pitch` at which `y` best matches the `len` samples
+/// of `x_lp`, searched at a quarter then half of the sample rate.
+pub fn pitch_search(x_lp: &[f32], y: &[f32], len: usize, max_pitch: usize) -> usize {
+    let lag = len + max_pitch;
+    let x_lp4: Vec<f32> = x_lp[..len >> 1].iter().step_by(2).copied().collect();
+    let y_lp4: Vec<f32> = y[..lag >> 1].iter().step_by(2).copied().collect();
+    let x_lp4 = &x_lp4[..len >> 2];
+    let y_lp4 = &y_lp4[..lag >> 2];
+
+    // The coarse search.
+    let mut xcorr = vec![0.0; max_pitch >> 1];
+    for (i, xcorr) in xcorr[..max_pitch >> 2].iter_mut().enumerate() {
+        *xcorr = inner_prod(x_lp4, &y_lp4[i..i + (len >> 2)]);
+    }
+    let best_pitch = find_best_pitch(&xcorr[..max_pitch >> 2], y_lp4, len >> 2);
+
+    // The finer one around the best two.
+    for (i, xcorr) in xcorr.iter_mut().enumerate() {
+        *xcorr = 0.0;
+        if (i as isize - 2 * best_pitch[0] as isize).abs() > 2
+            && (i as isize - 2 * best_pitch[1] as isize).abs() > 2
+        {
+            continue;
+        }
+        let sum = inner_prod(&x_lp[..len >> 1], &y[i..i + (len >> 1)]);
+        *xcorr = sum.max(-1.0);
+    }
+    let best_pitch = find_best_pitch(&xcorr, y, len >> 1);
+
+    // Refines it by pseudo-interpolation.
+    let mut offset = 0;
+    if best_pitch[0] > 0 && best_pitch[0] < (max_pitch >> 1) - 1 {
+        let a = xcorr[best_pitch[0] - 1];
+        let b = xcorr[best_pitch[0]];
+        let c = xcorr[best_pitch[0] + 1];
+        if c - a > 0.7 * (b - a) {
+            offset = 1;
+        } else if a - c > 0.7 * (b - c) {
+            offset = -1;
+        }
+    }
+    (2 * best_pitch[0] as isize - offset) as usize
+}
@@ -0,0 +1,420 @@
+//! How a frame's bits are split between the bands, which the decoder works
+//! out the same way as the encoder.
+
+use super::tables::{BAND_ALLOCATION, CACHE_BITS, CACHE_CAPS, CACHE_INDEX, EBANDS, LOG_N};
+use super::{MAX_FINE_BITS, NB_EBANDS};
+use crate::opus::range_decoder::{BITRES, RangeDecoder};
+
+const ALLOC_STEPS: i32 = 6;
+const LOG_MAX_PSEUDO: i32 = 6;
+const FINE_OFFSET: i32 = 21;
+const NB_ALLOC_VECTORS: usize = 11;
+
+/// `log2` of 0 to 23 in eighths, rounded up.
+pub const LOG2_FRAC_TABLE: [u8; 24] = [
+    0, 8, 13, 16, 19, 21, 23, 24, 26, 27, 28, 29, 30, 31, 32, 32, 33, 34, 34, 35, 36, 36, 37, 37,
+];
+
+/// The bits needed for each number of pulses in band `band` at `lm`.
+pub fn cache(band: usize, lm: i32) -> &'static [u8] {
+    let index = CACHE_INDEX[(lm + 1) as usize * NB_EBANDS + band];
+    &CACHE_BITS[index as usize..]
+}
+
+pub fn get_pulses(i: i32) -> i32 {
+    if i < 8 {
+        i
+    } else {
+        (8 + (i & 7)) << ((i >> 3) - 1)
+    }
+}
+
+/// The most pulses that fit in `bits` eighths of a bit.
+pub fn bits2pulses(band: usize, lm: i32, bits: i32) -> i32 {
+    let cache = cache(band, lm);
+    let mut lo = 0;
+    let mut hi = cache[0] as i32;
+    let bits = bits - 1;
+    for _ in 0..LOG_MAX_PSEUDO {
+        let mid = (lo + hi + 1) >> 1;
+        if cache[mid as usize] as i32 >= bits {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    let below = if lo == 0 {
+        -1
+    } else {
+        cache[lo as usize] as i32
+    };
+    if bits - below <= cache[hi as usize] as i32 - bits {
+        lo
+    } else {
+        hi
+    }
+}
+
+/// The eighths of a bit `pulses` pulses take.
+pub fn pulses2bits(band: usize, lm: i32, pulses: i32) -> i32 {
+    if pulses == 0 {
+        0
+    } else {
+        cache(band, lm)[pulses as usize] as i32 + 1
+    }
+}
+
+/// The most eighths of a bit each band can use.
+pub fn init_caps(cap: &mut [i32], lm: i32, channels: i32) {
+    for (i, cap) in cap.iter_mut().enumerate().take(NB_EBANDS) {
+        let n = ((EBANDS[i + 1] - EBANDS[i]) as i32) << lm;
+        let caps = CACHE_CAPS[NB_EBANDS * (2 * lm + channels - 1) as usize + i] as i32;
+        *cap = ((caps + 64) * channels * n) >> 2;
+    }
+}
+
+/// What `compute_allocation` decided besides each band's bits.
+pub struct Allocation {
+    pub coded_bands: usize,
+    pub intensity: usize,
+    pub dual_stereo: bool,
+    pub balance: i32,
+}
+
+/// Splits `total` eighths of a bit between the PVQ `pulses` and the
+/// `ebits` of fine energy of each band, reading the skipped bands and the
+/// stereo parameters from `dec`.
+#[allow(clippy::too_many_arguments)]
+pub fn compute_allocation(
+    start: usize,
+    end: usize,
+    offsets: &[i32],
+    cap: &[i32],
+    alloc_trim: i32,
+    total: i32,
+    pulses: &mut [i32],
+    ebits: &mut [i32],
+    fine_priority: &mut [i32],
+    channels: i32,
+    lm: i32,
+    dec: &mut RangeDecoder,
+) -> Allocation {
+    let c = channels;
+    let mut total = total.max(0);
+    let mut skip_start = start;
+    // Reserves a bit to signal the end of manually skipped bands.
+    let skip_rsv = if total >= 1 << BITRES { 1 << BITRES } else { 0 };
+    total -= skip_rsv;
+    let mut intensity_rsv = 0;
+    let mut dual_stereo_rsv = 0;
+    if c == 2 {
+        intensity_rsv = LOG2_FRAC_TABLE[end - start] as i32;
+        if intensity_rsv > total {
+            intensity_rsv = 0;
+        } else {
+            total -= intensity_rsv;
+            dual_stereo_rsv = if total >= 1 << BITRES { 1 << BITRES } else { 0 };
+            total -= dual_stereo_rsv;
+        }
+    }
+    let mut bits1 = [0; NB_EBANDS];
+    let mut bits2 = [0; NB_EBANDS];
+    let mut thresh = [0; NB_EBANDS];
+    let mut trim_offset = [0; NB_EBANDS];
+    for j in start..end {
+        let width = (EBANDS[j + 1] - EBANDS[j]) as i32;
+        // Below this, no PVQ bits are allocated for sure.
+        thresh[j] = (c << BITRES).max(((3 * width) << lm << BITRES) >> 4);
+        // Tilt of the allocation curve.
+        trim_offset[j] = (c
+            * width
+            * (alloc_trim - 5 - lm)
+            * (end as i32 - j as i32 - 1)
+            * (1 << (lm + BITRES as i32)))
+            >> 6;
+        // Single coefficient bands benefit more from a coarse value each.
+        if width << lm == 1 {
+            trim_offset[j] -= c << BITRES;
+        }
+    }
+    let alloc = |vector: usize, j: usize| {
+        let width = (EBANDS[j + 1] - EBANDS[j]) as i32;
+        (c * width * (BAND_ALLOCATION[vector * NB_EBANDS + j] as i32)) << lm >> 2
+    };
+    let mut lo = 1;
+    let mut hi = NB_ALLOC_VECTORS as i32 - 1;
+    loop {
+        let mut done = false;
+        let mut psum = 0;
+        let mid = (lo + hi) >> 1;
+        for j in (start..end).rev() {
+            let mut bitsj = alloc(mid as usize, j);
+            if bitsj > 0 {
+                bitsj = (bitsj + trim_offset[j]).max(0);
+            }
+            bitsj += offsets[j];
+            if bitsj >= thresh[j] || done {
+                done = true;
+                psum += bitsj.min(cap[j]);
+            } else if bitsj >= c << BITRES {
+                psum += c << BITRES;
+            }
+        }
+        if psum > total {
+            hi = mid - 1;
+        } else {
+            lo = mid + 1;
+        }
+        if lo > hi {
+            break;
+        }
+    }
+    hi = lo;
+    lo -= 1;
+    for j in start..end {
+        let mut bits1j = alloc(lo as usize, j);
+        let mut bits2j = if hi as usize >= NB_ALLOC_VECTORS {
+            cap[j]
+        } else {
+            alloc(hi as usize, j)
+        };
+        if bits1j > 0 {
+            bits1j = (bits1j + trim_offset[j]).max(0);
+        }
+        if bits2j > 0 {
+            bits2j = (bits2j + trim_offset[j]).max(0);
+        }
+        if lo > 0 {
+            bits1j += offsets[j];
+        }
+        bits2j += offsets[j];
+        if offsets[j] > 0 {
+            skip_start = j;
+        }
+        bits1[j] = bits1j;
+        bits2[j] = (bits2j - bits1j).max(0);
+    }
+    interp_bits2pulses(
+        start,
+        end,
+        skip_start,
+        &bits1,
+        &bits2,
+        &thresh,
+        cap,
+        total,
+        skip_rsv,
+        intensity_rsv,
+        dual_stereo_rsv,
+        pulses,
+        ebits,
+        fine_priority,
+        c,
+        lm,
+        dec,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn interp_bits2pulses(
+    start: usize,
+    end: usize,
+    skip_start: usize,
+    bits1: &[i32],
+    bits2: &[i32],
+    thresh: &[i32],
+    cap: &[i32],
+    mut total: i32,
+    skip_rsv: i32,
+    mut intensity_rsv: i32,
+    mut dual_stereo_rsv: i32,
+    bits: &mut [i32],
+    ebits: &mut [i32],
+    fine_priority: &mut [i32],
+    c: i32,
+    lm: i32,
+    dec: &mut RangeDecoder,
+) -> Allocation {
+    let alloc_floor = c << BITRES;
+    let stereo = (c > 1) as i32;
+    let log_m = lm << BITRES;
+    let mut lo = 0;
+    let mut hi = 1 << ALLOC_STEPS;
+    for _ in 0..ALLOC_STEPS {
+        let mid = (lo + hi) >> 1;
+        let mut psum = 0;
+        let mut done = false;
+        for j in (start..end).rev() {
+            let tmp = bits1[j] + ((mid * bits2[j]) >> ALLOC_STEPS);
+            if tmp >= thresh[j] || done {
+                done = true;
+                // Never more than the band can use.
+                psum += tmp.min(cap[j]);
+            } else if tmp >= alloc_floor {
+                psum += alloc_floor;
+            }
+        }
+        if psum > total {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    let mut psum = 0;
+    let mut done = false;
+    for j in (start..end).rev() {
+        let mut tmp = bits1[j] + ((lo * bits2[j]) >> ALLOC_STEPS);
+        if tmp < thresh[j] && !done {
+            tmp = if tmp >= alloc_floor { alloc_floor } else { 0 };
+        } else {
+            done = true;
+        }
+        tmp = tmp.min(cap[j]);
+        bits[j] = tmp;
+        psum += tmp;
+    }
+
+    // Decides which bands to skip, working backwards from the end.
+    let band_start = EBANDS[start] as i32;
+    let mut coded_bands = end;
+    loop {
+        let j = coded_bands - 1;
+        // Neither the first band nor ones boosted by dynalloc are skipped.
+        if j <= skip_start {
+            // Gives back the bit reserved to end skipping.
+            total += skip_rsv;
+            break;
+        }
+        // The left over bits this band would get, including those of the
+        // higher, skipped bands.
+        let (percoeff, left) = spread(total - psum, EBANDS[coded_bands] as i32 - band_start);
+        let rem = left.wrapping_sub(EBANDS[j] as i32 - band_start).max(0);
+        let band_width = (EBANDS[coded_bands] - EBANDS[j]) as i32;
+        let mut band_bits = bits[j]
+            .wrapping_add(percoeff.wrapping_mul(band_width))
+            .wrapping_add(rem);
+        // The skip flag is only coded above the band's threshold, otherwise
+        // the band is skipped.
+        if band_bits >= thresh[j].max(alloc_floor + (1 << BITRES)) {
+            if dec.decode_bit_logp(1) {
+                break;
+            }
+            // A bit was used to skip this band.
+            psum += 1 << BITRES;
+            band_bits -= 1 << BITRES;
+        }
+        // Reclaims the band's bits.
+        psum -= bits[j] + intensity_rsv;
+        if intensity_rsv > 0 {
+            intensity_rsv = LOG2_FRAC_TABLE[j - start] as i32;
+        }
+        psum += intensity_rsv;
+        if band_bits >= alloc_floor {
+            // Enough for a fine energy bit per channel.
+            psum += alloc_floor;
+            bits[j] = alloc_floor;
+        } else {
+            bits[j] = 0;
+        }
+        coded_bands -= 1;
+    }
+
+    let intensity = if intensity_rsv > 0 {
+        start + dec.decode_uint((coded_bands + 1 - start) as u32) as usize
+    } else {
+        0
+    };
+    if intensity <= start {
+        total += dual_stereo_rsv;
+        dual_stereo_rsv = 0;
+    }
+    let dual_stereo = dual_stereo_rsv > 0 && dec.decode_bit_logp(1);
+
+    // Allocates the remaining bits.
+    let (percoeff, mut left) = spread(total - psum, EBANDS[coded_bands] as i32 - band_start);
+    for j in start..coded_bands {
+        bits[j] = bits[j].wrapping_add(percoeff.wrapping_mul((EBANDS[j + 1] - EBANDS[j]) as i32));
+    }
+    for j in start..coded_bands {
+        let tmp = left.min((EBANDS[j + 1] - EBANDS[j]) as i32);
+        bits[j] += tmp;
+        left -= tmp;
+    }
+
+    let mut balance = 0;
+    for j in start..coded_bands {
+        let n0 = (EBANDS[j + 1] - EBANDS[j]) as i32;
+        let n = n0 << lm;
+        let bit = bits[j] + balance;
+        let mut excess;
+        if n > 1 {
+            excess = (bit - cap[j]).max(0);
+            bits[j] = bit - excess;
+            // Compensates for the extra degree of freedom in stereo.
+            let den = c * n + (c == 2 && n > 2 && !dual_stereo && j < intensity) as i32;
+            let nc_log_n = den * (LOG_N[j] as i32 + log_m);
+            // Offsets the fine bits by log2(N)/2 + FINE_OFFSET from their
+            // fair share of total/N.
+            let mut offset = (nc_log_n >> 1) - den * FINE_OFFSET;
+            // N=2 is the only point that doesn't match the curve.
+            if n == 2 {
+                offset += den << BITRES >> 2;
+            }
+            // Changes the offset for the second and third fine energy bits.
+            if bits[j] + offset < (den * 2) << BITRES {
+                offset += nc_log_n >> 2;
+            } else if bits[j] + offset < (den * 3) << BITRES {
+                offset += nc_log_n >> 3;
+            }
+            // Divides with rounding.
+            ebits[j] = (bits[j] + offset + (den << (BITRES - 1))).max(0);
+            ebits[j] = (ebits[j] / den) >> BITRES;
+            // Doesn't bust.
+            if c * ebits[j] > bits[j] >> BITRES {
+                ebits[j] = bits[j] >> stereo >> BITRES;
+            }
+            // PVQ can't go further than that.
+            ebits[j] = ebits[j].min(MAX_FINE_BITS);
+            // Bands that were rounded down or capped are candidates for the
+            // final fine energy pass.
+            fine_priority[j] = (ebits[j] * (den << BITRES) >= bits[j] + offset) as i32;
+            // The rest are for PVQ.
+            bits[j] -= (c * ebits[j]) << BITRES;
+        } else {
+            // Bands of one bin only take a sign bit besides fine energy.
+            excess = (bit - (c << BITRES)).max(0);
+            bits[j] = bit - excess;
+            ebits[j] = 0;
+            fine_priority[j] = 1;
+        }
+        // Fine energy can't use the rebalancing in quant_all_bands, so it's
+        // done here.
+        if excess > 0 {
+            let extra_fine = (excess >> (stereo + BITRES as i32)).min(MAX_FINE_BITS - ebits[j]);
+            ebits[j] += extra_fine;
+            let extra_bits = (extra_fine * c) << BITRES;
+            fine_priority[j] = (extra_bits >= excess - balance) as i32;
+            excess -= extra_bits;
+        }
+        balance = excess;
+    }
+    // The skipped bands spend all their bits on fine energy.
+    for j in coded_bands..end {
+        ebits[j] = bits[j] >> stereo >> BITRES;
+        bits[j] = 0;
+        fine_priority[j] = (ebits[j] < 1) as i32;
+    }
+    Allocation {
+        coded_bands,
+        intensity,
+        dual_stereo,
+        balance,
+    }
+}
+
+/// `left` bits shared between `bins` bins: how many each gets and how many
+/// are left over, dividing as unsigned like libopus does.
+fn spread(left: i32, bins: i32) -> (i32, i32) {
+    let percoeff = (left as u32 / bins as u32) as i32;
+    (percoeff, left.wrapping_sub(bins.wrapping_mul(percoeff)))
+}
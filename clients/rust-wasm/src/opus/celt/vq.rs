@@ -0,0 +1,102 @@
+//! Turning the decoded pulses of a band into its unit norm shape.
+
+use super::SPREAD_NONE;
+use super::cwrs::decode_pulses;
+use super::math::{cos_norm, inner_prod};
+use crate::opus::range_decoder::RangeDecoder;
+
+fn exp_rotation1(x: &mut [f32], len: usize, stride: usize, c: f32, s: f32) {
+    let ms = -s;
+    for i in 0..len - stride {
+        let x1 = x[i];
+        let x2 = x[i + stride];
+        x[i + stride] = c * x2 + s * x1;
+        x[i] = c * x1 + ms * x2;
+    }
+    for i in (0..(len as isize - 2 * stride as isize)).rev() {
+        let i = i as usize;
+        let x1 = x[i];
+        let x2 = x[i + stride];
+        x[i + stride] = c * x2 + s * x1;
+        x[i] = c * x1 + ms * x2;
+    }
+}
+
+/// Undoes the rotation that spreads the energy of bands with few pulses.
+pub fn exp_rotation(x: &mut [f32], len: usize, dir: i32, stride: usize, k: usize, spread: usize) {
+    const SPREAD_FACTOR: [usize; 3] = [15, 10, 5];
+    if 2 * k >= len || spread == SPREAD_NONE {
+        return;
+    }
+    let factor = SPREAD_FACTOR[spread - 1];
+    let gain = len as f32 / (len + factor * k) as f32;
+    let theta = 0.5 * (gain * gain);
+    let c = cos_norm(theta);
+    let s = cos_norm(1.0 - theta);
+    let mut stride2 = 0;
+    if len >= 8 * stride {
+        stride2 = 1;
+        // sqrt(len/stride) with rounding.
+        while (stride2 * stride2 + stride2) * stride + (stride >> 2) < len {
+            stride2 += 1;
+        }
+    }
+    let len = len / stride;
+    for i in 0..stride {
+        let x = &mut x[i * len..];
+        if dir < 0 {
+            if stride2 != 0 {
+                exp_rotation1(x, len, stride2, s, c);
+            }
+            exp_rotation1(x, len, 1, c, s);
+        } else {
+            exp_rotation1(x, len, 1, c, -s);
+            if stride2 != 0 {
+                exp_rotation1(x, len, stride2, s, -c);
+            }
+        }
+    }
+}
+
+/// Which of the `b` blocks of the band got pulses.
+fn extract_collapse_mask(iy: &[i32], n: usize, b: usize) -> u32 {
+    if b <= 1 {
+        return 1;
+    }
+    let n0 = n / b;
+    (0..b).fold(0, |mask, i| {
+        let any = iy[i * n0..(i + 1) * n0].iter().any(|&y| y != 0);
+        mask | (any as u32) << i
+    })
+}
+
+/// Decodes `k` pulses into the shape `x` of `n` bins scaled to `gain`,
+/// returning which of its `b` blocks aren't empty.
+pub fn alg_unquant(
+    x: &mut [f32],
+    n: usize,
+    k: usize,
+    spread: usize,
+    b: usize,
+    dec: &mut RangeDecoder,
+    gain: f32,
+) -> u32 {
+    let mut iy = [0; 176];
+    let iy = &mut iy[..n];
+    let ryy = decode_pulses(iy, n, k, dec);
+    let g = 1.0 / ryy.sqrt() * gain;
+    for (x, &y) in x.iter_mut().zip(iy.iter()) {
+        *x = g * y as f32;
+    }
+    exp_rotation(x, n, -1, b, k, spread);
+    extract_collapse_mask(iy, n, b)
+}
+
+/// Scales `x` to a norm of `gain`.
+pub fn renormalise_vector(x: &mut [f32], gain: f32) {
+    let e = 1e-15 + inner_prod(x, x);
+    let g = 1.0 / e.sqrt() * gain;
+    for x in x {
+        *x *= g;
+    }
+}
@@ -0,0 +1,781 @@
+//! A port of libopus's float decoder, for browsers without WebCodecs.
+
+mod celt;
+mod range_decoder;
+mod silk;
+
+use celt::{CeltDecoder, WINDOW};
+use range_decoder::RangeDecoder;
+use silk::{DecControl, SilkDecoder};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    BadArg,
+    InvalidPacket,
+    Internal,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadArg => write!(f, "bad argument"),
+            Error::InvalidPacket => write!(f, "invalid packet"),
+            Error::Internal => write!(f, "internal error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The codec a frame is coded with, from its TOC byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    SilkOnly,
+    Hybrid,
+    CeltOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bandwidth {
+    Narrow,
+    Medium,
+    Wide,
+    SuperWide,
+    Full,
+}
+
+/// Decodes an Opus stream to float samples, like `opus_decode_float`.
+pub struct Decoder {
+    sample_rate: usize,
+    channels: usize,
+    silk: SilkDecoder,
+    silk_control: DecControl,
+    celt: CeltDecoder,
+
+    /// What the last packet's TOC byte said.
+    stream_channels: usize,
+    mode: Mode,
+    bandwidth: Bandwidth,
+    frame_size: usize,
+
+    /// The mode of the last frame decoded, `None` until there is one.
+    prev_mode: Option<Mode>,
+    /// Whether the last frame ended in a CELT frame for a switch to CELT.
+    prev_redundancy: bool,
+    final_range: u32,
+}
+
+impl Decoder {
+    /// A decoder to `channels` channels at `sample_rate`, which may be 8,
+    /// 12, 16, 24 or 48 kHz whatever the stream was coded at.
+    pub fn new(sample_rate: u32, channels: usize) -> Result<Self, Error> {
+        let celt = CeltDecoder::new(sample_rate, channels)?;
+        let sample_rate = sample_rate as usize;
+        Ok(Self {
+            sample_rate,
+            channels,
+            silk: SilkDecoder::default(),
+            silk_control: DecControl {
+                n_channels_api: channels,
+                n_channels_internal: 0,
+                api_sample_rate: sample_rate as i32,
+                internal_sample_rate: 0,
+                payload_size_ms: 0,
+            },
+            celt,
+            stream_channels: channels,
+            mode: Mode::CeltOnly,
+            bandwidth: Bandwidth::Full,
+            frame_size: sample_rate / 400,
+            prev_mode: None,
+            prev_redundancy: false,
+            final_range: 0,
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The final state of the range decoder, which matches the encoder's
+    /// when the packet was decoded as it was coded.
+    #[cfg(test)]
+    fn final_range(&self) -> u32 {
+        self.final_range
+    }
+
+    /// Decodes `packet` into up to `frame_size` interleaved samples per
+    /// channel of `pcm`, or conceals `frame_size` lost ones when it's empty.
+    /// Returns the samples per channel decoded.
+    pub fn decode(
+        &mut self,
+        packet: &[u8],
+        pcm: &mut [f32],
+        frame_size: usize,
+    ) -> Result<usize, Error> {
+        let channels = self.channels;
+        if pcm.len() < frame_size * channels {
+            return Err(Error::BadArg);
+        }
+        let Some(&toc) = packet.first() else {
+            if !frame_size.is_multiple_of(self.sample_rate / 400) {
+                return Err(Error::BadArg);
+            }
+            let mut count = 0;
+            loop {
+                count +=
+                    self.decode_frame(None, &mut pcm[count * channels..], frame_size - count)?;
+                if count >= frame_size {
+                    return Ok(count);
+                }
+            }
+        };
+
+        let packet_frame_size = samples_per_frame(toc, self.sample_rate);
+        let frames = parse_packet(packet)?;
+        if frames.len() * packet_frame_size > frame_size {
+            return Err(Error::BadArg);
+        }
+        self.mode = if toc & 0x80 != 0 {
+            Mode::CeltOnly
+        } else if toc & 0x60 == 0x60 {
+            Mode::Hybrid
+        } else {
+            Mode::SilkOnly
+        };
+        self.bandwidth = bandwidth(toc);
+        self.frame_size = packet_frame_size;
+        self.stream_channels = if toc & 0x4 != 0 { 2 } else { 1 };
+
+        let mut count = 0;
+        for frame in frames {
+            count += self.decode_frame(
+                Some(frame),
+                &mut pcm[count * channels..],
+                frame_size - count,
+            )?;
+        }
+        Ok(count)
+    }
+
+    /// Decodes one frame of the last packet into `pcm`, or conceals up to
+    /// `frame_size` samples of a lost one.
+    fn decode_frame(
+        &mut self,
+        data: Option<&[u8]>,
+        pcm: &mut [f32],
+        frame_size: usize,
+    ) -> Result<usize, Error> {
+        let fs = self.sample_rate;
+        let channels = self.channels;
+        let f20 = fs / 50;
+        let f10 = f20 >> 1;
+        let f5 = f10 >> 1;
+        let f2_5 = f5 >> 1;
+        if frame_size < f2_5 {
+            return Err(Error::BadArg);
+        }
+        let mut frame_size = frame_size.min(fs / 25 * 3);
+        // A frame of a byte or none is as good as lost.
+        let data = data.filter(|data| data.len() > 1);
+        if data.is_none() {
+            frame_size = frame_size.min(self.frame_size);
+        }
+
+        let (audiosize, mode, bandwidth) = match data {
+            Some(_) => (self.frame_size, self.mode, Some(self.bandwidth)),
+            None => {
+                let Some(mode) = self.prev_mode else {
+                    pcm[..frame_size * channels].fill(0.0);
+                    return Ok(frame_size);
+                };
+                let mut audiosize = frame_size;
+                // Conceals only what the codecs can: 2.5 and 5 ms of CELT,
+                // or 10 or 20 ms.
+                if audiosize > f20 {
+                    let mut offset = 0;
+                    while offset < audiosize {
+                        offset += self.decode_frame(
+                            None,
+                            &mut pcm[offset * channels..],
+                            (audiosize - offset).min(f20),
+                        )?;
+                    }
+                    return Ok(frame_size);
+                } else if audiosize < f20 {
+                    if audiosize > f10 {
+                        audiosize = f10;
+                    } else if mode != Mode::SilkOnly && audiosize > f5 && audiosize < f10 {
+                        audiosize = f5;
+                    }
+                }
+                (audiosize, mode, None)
+            }
+        };
+        let mut dec = RangeDecoder::new(data.unwrap_or_default());
+        let mut len = data.map_or(0, <[u8]>::len) as i32;
+
+        // Switching between CELT and SILK fades from the old codec's
+        // concealment, unless a redundant CELT frame already did.
+        let mut transition = data.is_some()
+            && self.prev_mode.is_some_and(|prev_mode| {
+                (mode == Mode::CeltOnly && prev_mode != Mode::CeltOnly && !self.prev_redundancy)
+                    || (mode != Mode::CeltOnly && prev_mode == Mode::CeltOnly)
+            });
+        let mut pcm_transition = [0.0; 2 * 240];
+        if transition && mode == Mode::CeltOnly {
+            let _ = self.decode_frame(None, &mut pcm_transition, f5.min(audiosize));
+        }
+        if audiosize > frame_size {
+            return Err(Error::BadArg);
+        }
+        let frame_size = audiosize;
+
+        let mut pcm_silk = Vec::new();
+        if mode != Mode::CeltOnly {
+            pcm_silk.resize(f10.max(frame_size) * channels, 0);
+            if self.prev_mode == Some(Mode::CeltOnly) {
+                self.silk.reset();
+            }
+            // SILK conceals no less than 10 ms.
+            self.silk_control.payload_size_ms = 10.max(1000 * audiosize / fs);
+            if data.is_some() {
+                self.silk_control.n_channels_internal = self.stream_channels;
+                self.silk_control.internal_sample_rate = match (mode, bandwidth) {
+                    (Mode::SilkOnly, Some(Bandwidth::Narrow)) => 8000,
+                    (Mode::SilkOnly, Some(Bandwidth::Medium)) => 12000,
+                    _ => 16000,
+                };
+            }
+            let mut decoded = 0;
+            while decoded < frame_size {
+                decoded += self.silk.decode(
+                    &self.silk_control,
+                    data.is_none(),
+                    decoded == 0,
+                    &mut dec,
+                    &mut pcm_silk[decoded * channels..],
+                );
+            }
+        }
+
+        // A SILK or hybrid frame can end with a 5 ms CELT frame to fade to
+        // or from CELT with.
+        let mut redundancy = false;
+        let mut celt_to_silk = false;
+        let mut redundancy_bytes = 0;
+        if mode != Mode::CeltOnly
+            && data.is_some()
+            && dec.tell() + 17 + 20 * (mode == Mode::Hybrid) as i32 <= 8 * len
+        {
+            redundancy = mode == Mode::SilkOnly || dec.decode_bit_logp(12);
+            if redundancy {
+                celt_to_silk = dec.decode_bit_logp(1);
+                redundancy_bytes = if mode == Mode::Hybrid {
+                    dec.decode_uint(256) as i32 + 2
+                } else {
+                    len - ((dec.tell() + 7) >> 3)
+                };
+                len -= redundancy_bytes;
+                if len * 8 < dec.tell() {
+                    len = 0;
+                    redundancy_bytes = 0;
+                    redundancy = false;
+                }
+                dec.storage -= redundancy_bytes as u32;
+            }
+        }
+        let redundant_frame = match data {
+            Some(data) if redundancy => &data[len as usize..(len + redundancy_bytes) as usize],
+            _ => &[],
+        };
+        let start_band = if mode == Mode::CeltOnly { 0 } else { 17 };
+        if redundancy {
+            transition = false;
+        }
+        if transition && mode != Mode::CeltOnly {
+            let _ = self.decode_frame(None, &mut pcm_transition, f5.min(audiosize));
+        }
+
+        if let Some(bandwidth) = bandwidth {
+            self.celt.end = match bandwidth {
+                Bandwidth::Narrow => 13,
+                Bandwidth::Medium | Bandwidth::Wide => 17,
+                Bandwidth::SuperWide => 19,
+                Bandwidth::Full => 21,
+            };
+        }
+        self.celt.stream_channels = self.stream_channels;
+
+        let mut redundant_audio = [0.0; 2 * 240];
+        let mut redundant_rng = 0;
+        if redundancy && celt_to_silk {
+            self.celt.start = 0;
+            let _ = self.celt.decode(
+                Some(&mut RangeDecoder::new(redundant_frame)),
+                &mut redundant_audio,
+                f5,
+            );
+            redundant_rng = self.celt.rng;
+        }
+
+        self.celt.start = start_band;
+        let mut celt_result = Ok(0);
+        if mode != Mode::SilkOnly {
+            if Some(mode) != self.prev_mode && self.prev_mode.is_some() && !self.prev_redundancy {
+                self.celt.reset();
+            }
+            let dec = if data.is_some() && len > 1 {
+                Some(&mut dec)
+            } else {
+                None
+            };
+            celt_result = self.celt.decode(dec, pcm, f20.min(frame_size));
+        } else {
+            pcm[..frame_size * channels].fill(0.0);
+            // Lets CELT fade out what it had of a hybrid frame.
+            if self.prev_mode == Some(Mode::Hybrid)
+                && !(redundancy && celt_to_silk && self.prev_redundancy)
+            {
+                self.celt.start = 0;
+                let _ = self
+                    .celt
+                    .decode(Some(&mut RangeDecoder::new(&[0xFF, 0xFF])), pcm, f2_5);
+            }
+        }
+
+        for (out, &silk) in pcm[..frame_size * channels].iter_mut().zip(&pcm_silk) {
+            *out += (1.0 / 32768.0) * silk as f32;
+        }
+
+        let fade = f2_5 * channels;
+        if redundancy && !celt_to_silk {
+            self.celt.reset();
+            self.celt.start = 0;
+            let _ = self.celt.decode(
+                Some(&mut RangeDecoder::new(redundant_frame)),
+                &mut redundant_audio,
+                f5,
+            );
+            redundant_rng = self.celt.rng;
+            let end = channels * (frame_size - f2_5);
+            let mut silk_end = [0.0; 2 * 120];
+            silk_end[..fade].copy_from_slice(&pcm[end..end + fade]);
+            smooth_fade(
+                &silk_end,
+                &redundant_audio[fade..],
+                &mut pcm[end..end + fade],
+                channels,
+                fs,
+            );
+        }
+        if redundancy && celt_to_silk {
+            pcm[..fade].copy_from_slice(&redundant_audio[..fade]);
+            let mut silk_start = [0.0; 2 * 120];
+            silk_start[..fade].copy_from_slice(&pcm[fade..2 * fade]);
+            smooth_fade(
+                &redundant_audio[fade..],
+                &silk_start,
+                &mut pcm[fade..2 * fade],
+                channels,
+                fs,
+            );
+        }
+        if transition {
+            let faded = if audiosize >= f5 {
+                pcm[..fade].copy_from_slice(&pcm_transition[..fade]);
+                fade
+            } else {
+                0
+            };
+            let mut new_start = [0.0; 2 * 120];
+            new_start[..fade].copy_from_slice(&pcm[faded..faded + fade]);
+            smooth_fade(
+                &pcm_transition[faded..],
+                &new_start,
+                &mut pcm[faded..faded + fade],
+                channels,
+                fs,
+            );
+        }
+
+        self.final_range = if len <= 1 { 0 } else { dec.rng ^ redundant_rng };
+        self.prev_mode = Some(mode);
+        self.prev_redundancy = redundancy && !celt_to_silk;
+        celt_result.map(|_| audiosize)
+    }
+}
+
+/// Crossfades from `from` to `to` over `out`, with the square of CELT's
+/// window.
+fn smooth_fade(from: &[f32], to: &[f32], out: &mut [f32], channels: usize, sample_rate: usize) {
+    let inc = 48000 / sample_rate;
+    for (i, out) in out.chunks_exact_mut(channels).enumerate() {
+        let w = WINDOW[i * inc] * WINDOW[i * inc];
+        for (c, out) in out.iter_mut().enumerate() {
+            *out = w * to[i * channels + c] + (1.0 - w) * from[i * channels + c];
+        }
+    }
+}
+
+fn bandwidth(toc: u8) -> Bandwidth {
+    let bandwidths = [
+        Bandwidth::Narrow,
+        Bandwidth::Medium,
+        Bandwidth::Wide,
+        Bandwidth::SuperWide,
+        Bandwidth::Full,
+    ];
+    if toc & 0x80 != 0 {
+        // CELT has no mediumband.
+        match (toc >> 5) & 0x3 {
+            0 => Bandwidth::Narrow,
+            n => bandwidths[n as usize + 1],
+        }
+    } else if toc & 0x60 == 0x60 {
+        if toc & 0x10 != 0 {
+            Bandwidth::Full
+        } else {
+            Bandwidth::SuperWide
+        }
+    } else {
+        bandwidths[(toc >> 5) as usize & 0x3]
+    }
+}
+
+fn samples_per_frame(toc: u8, sample_rate: usize) -> usize {
+    if toc & 0x80 != 0 {
+        (sample_rate << ((toc >> 3) & 0x3)) / 400
+    } else if toc & 0x60 == 0x60 {
+        if toc & 0x08 != 0 {
+            sample_rate / 50
+        } else {
+            sample_rate / 100
+        }
+    } else {
+        match (toc >> 3) & 0x3 {
+            3 => sample_rate * 60 / 1000,
+            size => (sample_rate << size) / 100,
+        }
+    }
+}
+
+/// Splits a packet into its frames, RFC 6716 section 3.2.
+fn parse_packet(packet: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let (&toc, mut data) = packet.split_first().ok_or(Error::InvalidPacket)?;
+    let mut sizes = Vec::new();
+    let last_size = match toc & 0x3 {
+        0 => data.len(),
+        1 => {
+            if data.len() % 2 != 0 {
+                return Err(Error::InvalidPacket);
+            }
+            sizes.push(data.len() / 2);
+            data.len() / 2
+        }
+        2 => {
+            let (size, bytes) = parse_size(data)?;
+            data = &data[bytes..];
+            if size > data.len() {
+                return Err(Error::InvalidPacket);
+            }
+            sizes.push(size);
+            data.len() - size
+        }
+        _ => {
+            let (&frame_count, rest) = data.split_first().ok_or(Error::InvalidPacket)?;
+            data = rest;
+            let count = (frame_count & 0x3F) as usize;
+            if count == 0 || samples_per_frame(toc, 48000) * count > 5760 {
+                return Err(Error::InvalidPacket);
+            }
+            if frame_count & 0x40 != 0 {
+                let mut padding = 0;
+                loop {
+                    let (&p, rest) = data.split_first().ok_or(Error::InvalidPacket)?;
+                    data = rest;
+                    padding += if p == 255 { 254 } else { p as usize };
+                    if p != 255 {
+                        break;
+                    }
+                }
+                let len = data
+                    .len()
+                    .checked_sub(padding)
+                    .ok_or(Error::InvalidPacket)?;
+                data = &data[..len];
+            }
+            if frame_count & 0x80 != 0 {
+                let mut last_size = data.len();
+                for _ in 1..count {
+                    let (size, bytes) = parse_size(data)?;
+                    data = &data[bytes..];
+                    if size > data.len() {
+                        return Err(Error::InvalidPacket);
+                    }
+                    sizes.push(size);
+                    last_size = last_size
+                        .checked_sub(bytes + size)
+                        .ok_or(Error::InvalidPacket)?;
+                }
+                last_size
+            } else {
+                let size = data.len() / count;
+                if size * count != data.len() {
+                    return Err(Error::InvalidPacket);
+                }
+                sizes.resize(count - 1, size);
+                size
+            }
+        }
+    };
+    if last_size > 1275 {
+        return Err(Error::InvalidPacket);
+    }
+    sizes.push(last_size);
+    Ok(sizes
+        .into_iter()
+        .map(|size| {
+            let (frame, rest) = data.split_at(size);
+            data = rest;
+            frame
+        })
+        .collect())
+}
+
+/// Reads a frame's length in one or two bytes, returning it and the bytes
+/// read.
+fn parse_size(data: &[u8]) -> Result<(usize, usize), Error> {
+    match *data {
+        [first, ..] if first < 252 => Ok((first as usize, 1)),
+        [first, second, ..] => Ok((4 * second as usize + first as usize, 2)),
+        _ => Err(Error::InvalidPacket),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::opus::{Application, Bitrate, Channels};
+
+    /// A chord over noise that swells and fades to silence, so the encoder
+    /// switches modes, bandwidths and voice activity.
+    fn signal(sample_rate: u32, channels: usize, seconds: usize) -> Vec<f32> {
+        let mut seed = 1u32;
+        (0..sample_rate as usize * seconds)
+            .flat_map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                let envelope = (t * 1.7).sin().abs();
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (seed >> 16) as f32 / 65536.0 - 0.5;
+                (0..channels).map(move |c| {
+                    let chord: f32 = [220.0, 277.2, 329.6, 1760.0]
+                        .iter()
+                        .map(|f| (std::f32::consts::TAU * f * (c + 1) as f32 * t).sin())
+                        .sum();
+                    envelope * (0.15 * chord + 0.05 * noise)
+                })
+            })
+            .collect()
+    }
+
+    struct Stream {
+        encode_rate: u32,
+        encode_channels: usize,
+        application: Application,
+        frame_ms: usize,
+        /// Bitrates switched between every half second.
+        bitrates: &'static [i32],
+        decode_rate: u32,
+        decode_channels: usize,
+        /// Drops every this many packets.
+        loss_interval: Option<usize>,
+    }
+
+    fn channels(channels: usize) -> Channels {
+        if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        }
+    }
+
+    /// Checks we decode a stream the same as libopus, to the bit, and end
+    /// each packet with the encoder's range.
+    fn assert_decodes_like_libopus(stream: Stream) {
+        let mut encoder = ::opus::Encoder::new(
+            stream.encode_rate,
+            channels(stream.encode_channels),
+            stream.application,
+        )
+        .unwrap();
+        let mut libopus =
+            ::opus::Decoder::new(stream.decode_rate, channels(stream.decode_channels)).unwrap();
+        let mut decoder = Decoder::new(stream.decode_rate, stream.decode_channels).unwrap();
+
+        let input = signal(stream.encode_rate, stream.encode_channels, 4);
+        let frame = stream.encode_rate as usize * stream.frame_ms / 1000 * stream.encode_channels;
+        let decode_size = stream.decode_rate as usize * stream.frame_ms / 1000;
+        let packets_per_bitrate = 500 / stream.frame_ms;
+        let mut packet = [0; 1500];
+        let mut expected = vec![0.0; decode_size * stream.decode_channels];
+        let mut actual = vec![0.0; decode_size * stream.decode_channels];
+        for (i, frame) in input.chunks_exact(frame).enumerate() {
+            if i % packets_per_bitrate == 0 {
+                let bitrate = stream.bitrates[i / packets_per_bitrate % stream.bitrates.len()];
+                encoder.set_bitrate(Bitrate::Bits(bitrate)).unwrap();
+            }
+            let len = encoder.encode_float(frame, &mut packet).unwrap();
+            let lost = stream
+                .loss_interval
+                .is_some_and(|interval| i % interval == interval - 1);
+            let packet = if lost { &[][..] } else { &packet[..len] };
+
+            let expected_len = libopus.decode_float(packet, &mut expected, false).unwrap();
+            let actual_len = decoder.decode(packet, &mut actual, decode_size).unwrap();
+            assert_eq!(actual_len, expected_len, "packet {i}");
+            assert_eq!(actual, expected, "packet {i} {:02x?}", packet.first());
+            if !lost {
+                assert_eq!(
+                    decoder.final_range(),
+                    encoder.get_final_range().unwrap(),
+                    "packet {i}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_celt() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 48000,
+            encode_channels: 2,
+            application: Application::LowDelay,
+            frame_ms: 20,
+            bitrates: &[128000, 32000, 64000],
+            decode_rate: 48000,
+            decode_channels: 2,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn decodes_short_celt_frames_downsampled_to_mono() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 48000,
+            encode_channels: 2,
+            application: Application::LowDelay,
+            frame_ms: 5,
+            bitrates: &[96000, 24000],
+            decode_rate: 24000,
+            decode_channels: 1,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn decodes_narrowband_silk() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 8000,
+            encode_channels: 1,
+            application: Application::Voip,
+            frame_ms: 20,
+            bitrates: &[8000, 12000],
+            decode_rate: 8000,
+            decode_channels: 1,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn decodes_wideband_silk_in_long_packets_resampled() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 16000,
+            encode_channels: 1,
+            application: Application::Voip,
+            frame_ms: 60,
+            bitrates: &[16000, 24000],
+            decode_rate: 48000,
+            decode_channels: 1,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn decodes_stereo_silk() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 12000,
+            encode_channels: 2,
+            application: Application::Voip,
+            frame_ms: 40,
+            bitrates: &[16000, 32000],
+            decode_rate: 16000,
+            decode_channels: 2,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn decodes_hybrid() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 48000,
+            encode_channels: 2,
+            application: Application::Voip,
+            frame_ms: 20,
+            bitrates: &[24000, 40000],
+            decode_rate: 48000,
+            decode_channels: 2,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn decodes_switches_between_modes() {
+        assert_decodes_like_libopus(Stream {
+            encode_rate: 48000,
+            encode_channels: 1,
+            application: Application::Voip,
+            frame_ms: 20,
+            bitrates: &[10000, 96000, 12000, 32000, 64000, 16000],
+            decode_rate: 48000,
+            decode_channels: 1,
+            loss_interval: None,
+        });
+    }
+
+    #[test]
+    fn conceals_lost_packets() {
+        for bitrates in [&[8000][..], &[24000], &[96000], &[10000, 96000, 20000]] {
+            assert_decodes_like_libopus(Stream {
+                encode_rate: 48000,
+                encode_channels: 2,
+                application: Application::Audio,
+                frame_ms: 20,
+                bitrates,
+                decode_rate: 48000,
+                decode_channels: 2,
+                loss_interval: Some(7),
+            });
+        }
+    }
+
+    #[test]
+    fn parses_packets_of_several_frames() {
+        assert_eq!(
+            parse_packet(&[0x01, 1, 2, 3, 4]),
+            Ok(vec![&[1, 2][..], &[3, 4]])
+        );
+        assert_eq!(
+            parse_packet(&[0x02, 1, 1, 2, 3]),
+            Ok(vec![&[1][..], &[2, 3]])
+        );
+        // Three CBR frames and two bytes of padding.
+        assert_eq!(
+            parse_packet(&[0x03, 0x43, 2, 1, 2, 3, 0, 0]),
+            Ok(vec![&[1][..], &[2], &[3]])
+        );
+        assert_eq!(parse_packet(&[0x01, 1, 2, 3]), Err(Error::InvalidPacket));
+        assert_eq!(parse_packet(&[0x03, 0x00]), Err(Error::InvalidPacket));
+    }
+}
@@ -0,0 +1,258 @@
+//! The range decoder SILK and CELT read their symbols with, RFC 6716 section
+//! 4.1, and the raw bits CELT reads from the end of the frame.
+
+const SYM_BITS: u32 = 8;
+const CODE_BITS: u32 = 32;
+const SYM_MAX: u32 = (1 << SYM_BITS) - 1;
+const CODE_TOP: u32 = 1 << (CODE_BITS - 1);
+const CODE_BOT: u32 = CODE_TOP >> SYM_BITS;
+const CODE_EXTRA: u32 = (CODE_BITS - 2) % SYM_BITS + 1;
+const WINDOW_SIZE: u32 = 32;
+const UINT_BITS: u32 = 8;
+/// `tell_frac` counts eighths of a bit.
+pub const BITRES: u32 = 3;
+
+/// Most probable Laplace symbols are coded with at least this frequency, out
+/// of 32768.
+const LAPLACE_MINP: u32 = 1;
+/// Energy deltas each direction is guaranteed to represent.
+const LAPLACE_NMIN: u32 = 16;
+
+/// Bits needed to represent `v`, 0 for 0.
+pub fn ilog(v: u32) -> i32 {
+    (u32::BITS - v.leading_zeros()) as i32
+}
+
+pub struct RangeDecoder<'a> {
+    buf: &'a [u8],
+    /// Bytes of `buf` the frame takes, shrunk when redundancy follows it.
+    pub storage: u32,
+    end_offs: u32,
+    end_window: u32,
+    nend_bits: u32,
+    nbits_total: i32,
+    offs: u32,
+    pub rng: u32,
+    val: u32,
+    ext: u32,
+    rem: u32,
+    pub error: bool,
+}
+
+impl<'a> RangeDecoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        let mut dec = Self {
+            buf,
+            storage: buf.len() as u32,
+            end_offs: 0,
+            end_window: 0,
+            nend_bits: 0,
+            nbits_total: (CODE_BITS + 1 - ((CODE_BITS - CODE_EXTRA) / SYM_BITS) * SYM_BITS) as i32,
+            offs: 0,
+            rng: 1 << CODE_EXTRA,
+            val: 0,
+            ext: 0,
+            rem: 0,
+            error: false,
+        };
+        dec.rem = dec.read_byte();
+        dec.val = dec.rng - 1 - (dec.rem >> (SYM_BITS - CODE_EXTRA));
+        dec.normalize();
+        dec
+    }
+
+    fn read_byte(&mut self) -> u32 {
+        if self.offs < self.storage {
+            self.offs += 1;
+            self.buf[self.offs as usize - 1] as u32
+        } else {
+            0
+        }
+    }
+
+    fn read_byte_from_end(&mut self) -> u32 {
+        if self.end_offs < self.storage {
+            self.end_offs += 1;
+            self.buf[(self.storage - self.end_offs) as usize] as u32
+        } else {
+            0
+        }
+    }
+
+    fn normalize(&mut self) {
+        while self.rng <= CODE_BOT {
+            self.nbits_total += SYM_BITS as i32;
+            self.rng <<= SYM_BITS;
+            let sym = self.rem;
+            self.rem = self.read_byte();
+            let sym = ((sym << SYM_BITS) | self.rem) >> (SYM_BITS - CODE_EXTRA);
+            self.val = ((self.val << SYM_BITS).wrapping_add(SYM_MAX & !sym)) & (CODE_TOP - 1);
+        }
+    }
+
+    /// The cumulative frequency of the next symbol, out of `ft`, to be
+    /// followed by `update`.
+    pub fn decode(&mut self, ft: u32) -> u32 {
+        self.ext = self.rng / ft;
+        let s = self.val / self.ext;
+        ft - (s + 1).min(ft)
+    }
+
+    /// `decode` with `ft` of `1 << bits`.
+    pub fn decode_bin(&mut self, bits: u32) -> u32 {
+        self.ext = self.rng >> bits;
+        let s = self.val / self.ext;
+        (1 << bits) - (s + 1).min(1 << bits)
+    }
+
+    /// Consumes the symbol `decode` found within `fl..fh`.
+    pub fn update(&mut self, fl: u32, fh: u32, ft: u32) {
+        let s = self.ext.wrapping_mul(ft - fh);
+        self.val = self.val.wrapping_sub(s);
+        self.rng = if fl > 0 {
+            self.ext.wrapping_mul(fh - fl)
+        } else {
+            self.rng.wrapping_sub(s)
+        };
+        self.normalize();
+    }
+
+    /// A bit that's 1 with probability `1 / (1 << logp)`.
+    pub fn decode_bit_logp(&mut self, logp: u32) -> bool {
+        let r = self.rng;
+        let d = self.val;
+        let s = r >> logp;
+        let ret = d < s;
+        if !ret {
+            self.val = d - s;
+        }
+        self.rng = if ret { s } else { r - s };
+        self.normalize();
+        ret
+    }
+
+    /// A symbol of the inverse cumulative distribution `icdf`, whose total is
+    /// `1 << ftb`.
+    pub fn decode_icdf(&mut self, icdf: &[u8], ftb: u32) -> usize {
+        let mut s = self.rng;
+        let d = self.val;
+        let r = s >> ftb;
+        let mut ret = 0;
+        let mut t;
+        loop {
+            t = s;
+            s = r.wrapping_mul(icdf[ret] as u32);
+            if d >= s {
+                break;
+            }
+            ret += 1;
+        }
+        self.val = d - s;
+        self.rng = t - s;
+        self.normalize();
+        ret
+    }
+
+    /// A uniformly distributed integer below `ft`, which is above 1.
+    pub fn decode_uint(&mut self, ft: u32) -> u32 {
+        let ft = ft - 1;
+        let ftb = ilog(ft) as u32;
+        if ftb > UINT_BITS {
+            let ftb = ftb - UINT_BITS;
+            let top = (ft >> ftb) + 1;
+            let s = self.decode(top);
+            self.update(s, s + 1, top);
+            let t = (s << ftb) | self.decode_bits(ftb);
+            if t <= ft {
+                return t;
+            }
+            self.error = true;
+            ft
+        } else {
+            let s = self.decode(ft + 1);
+            self.update(s, s + 1, ft + 1);
+            s
+        }
+    }
+
+    /// `bits` raw bits from the end of the frame.
+    pub fn decode_bits(&mut self, bits: u32) -> u32 {
+        let mut window = self.end_window;
+        let mut available = self.nend_bits;
+        if available < bits {
+            loop {
+                window |= self.read_byte_from_end() << available;
+                available += SYM_BITS;
+                if available > WINDOW_SIZE - SYM_BITS {
+                    break;
+                }
+            }
+        }
+        let ret = window & ((1u64 << bits) - 1) as u32;
+        self.end_window = window.checked_shr(bits).unwrap_or(0);
+        self.nend_bits = available - bits;
+        self.nbits_total += bits as i32;
+        ret
+    }
+
+    /// An energy delta coded with a Laplace distribution whose probability of
+    /// 0 is `fs` and whose tail decays by `decay`, out of 16384.
+    pub fn decode_laplace(&mut self, fs: u32, decay: i32) -> i32 {
+        let mut val = 0;
+        let fm = self.decode_bin(15);
+        let mut fl = 0;
+        let mut fs = fs;
+        if fm >= fs {
+            val += 1;
+            fl = fs;
+            fs = laplace_freq1(fs, decay) + LAPLACE_MINP;
+            while fs > LAPLACE_MINP && fm >= fl + 2 * fs {
+                fs *= 2;
+                fl += fs;
+                fs = (((fs - 2 * LAPLACE_MINP) as i32 * decay) >> 15) as u32;
+                fs += LAPLACE_MINP;
+                val += 1;
+            }
+            if fs <= LAPLACE_MINP {
+                let di = (fm - fl) >> 1;
+                val += di as i32;
+                fl += 2 * di * LAPLACE_MINP;
+            }
+            if fm < fl + fs {
+                val = -val;
+            } else {
+                fl += fs;
+            }
+        }
+        self.update(fl, (fl + fs).min(32768), 32768);
+        val
+    }
+
+    /// Counts the rest of the frame as read, for a silent CELT frame.
+    pub fn skip_to_end(&mut self) {
+        self.nbits_total += self.storage as i32 * 8 - self.tell();
+    }
+
+    /// Whole bits read so far, rounded up.
+    pub fn tell(&self) -> i32 {
+        self.nbits_total - ilog(self.rng)
+    }
+
+    /// Bits read so far in eighths of a bit, rounded up.
+    pub fn tell_frac(&self) -> u32 {
+        const CORRECTION: [u32; 8] = [35733, 38967, 42495, 46340, 50535, 55109, 60097, 65535];
+        let nbits = (self.nbits_total as u32) << BITRES;
+        let l = ilog(self.rng);
+        let r = self.rng >> (l - 16);
+        let mut b = (r >> 12) - 8;
+        if r > CORRECTION[b as usize] {
+            b += 1;
+        }
+        nbits - (((l as u32) << 3) + b)
+    }
+}
+
+fn laplace_freq1(fs0: u32, decay: i32) -> u32 {
+    let ft = 32768 - LAPLACE_MINP * (2 * LAPLACE_NMIN) - fs0;
+    ((ft as i32 * (16384 - decay)) >> 15) as u32
+}
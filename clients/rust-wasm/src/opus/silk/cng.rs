@@ -0,0 +1,152 @@
+//! Comfort noise for lost SILK frames, shaped like the last inactive
+//! ones.
+
+use super::math::{
+    add_sat32, lshift_sat32, rand, rshift_round, sat16, smlawb, smultt, smulwb, smulww, sqrt_approx,
+};
+use super::nlsf::nlsf2a;
+use super::{
+    ChannelState, DecoderControl, MAX_FRAME_LENGTH, MAX_LPC_ORDER, TYPE_NO_VOICE_ACTIVITY,
+};
+
+/// The largest power of two less one within a frame, the noise buffer's
+/// mask.
+const CNG_BUF_MASK_MAX: usize = 255;
+/// 0.25^(1/4) in Q16, how fast the gain follows the inactive frames'.
+const CNG_GAIN_SMTH_Q16: i32 = 4634;
+/// -3 dB in Q16, the drop below the smoothed gain that's followed at once.
+const CNG_GAIN_SMTH_THRESHOLD_Q16: i32 = 46396;
+/// 0.25 in Q16, how fast the spectrum follows the inactive frames'.
+const CNG_NLSF_SMTH_Q16: i32 = 16348;
+
+#[derive(Debug, Clone)]
+pub struct Cng {
+    exc_buf_q14: [i32; MAX_FRAME_LENGTH],
+    smth_nlsf_q15: [i16; MAX_LPC_ORDER],
+    synth_state: [i32; MAX_LPC_ORDER],
+    smth_gain_q16: i32,
+    rand_seed: i32,
+    fs_khz: usize,
+}
+
+impl Default for Cng {
+    fn default() -> Self {
+        Self {
+            exc_buf_q14: [0; MAX_FRAME_LENGTH],
+            smth_nlsf_q15: [0; MAX_LPC_ORDER],
+            synth_state: [0; MAX_LPC_ORDER],
+            smth_gain_q16: 0,
+            rand_seed: 0,
+            fs_khz: 0,
+        }
+    }
+}
+
+impl ChannelState {
+    pub(super) fn cng_reset(&mut self) {
+        let order = self.lpc_order;
+        let nlsf_step_q15 = i16::MAX as i32 / (order as i32 + 1);
+        let mut nlsf_acc_q15 = 0;
+        for nlsf in &mut self.cng.smth_nlsf_q15[..order] {
+            nlsf_acc_q15 += nlsf_step_q15;
+            *nlsf = nlsf_acc_q15 as i16;
+        }
+        self.cng.smth_gain_q16 = 0;
+        self.cng.rand_seed = 3176576;
+    }
+
+    /// Learns the noise from inactive frames, and adds it to `frame` when
+    /// it was lost.
+    pub(super) fn cng(&mut self, ctrl: &DecoderControl, frame: &mut [i16]) {
+        if self.fs_khz != self.cng.fs_khz {
+            self.cng_reset();
+            self.cng.fs_khz = self.fs_khz;
+        }
+        let order = self.lpc_order;
+        let cng = &mut self.cng;
+
+        if self.loss_cnt == 0 && self.prev_signal_type == TYPE_NO_VOICE_ACTIVITY {
+            for (smth, &prev) in cng.smth_nlsf_q15[..order]
+                .iter_mut()
+                .zip(&self.prev_nlsf_q15)
+            {
+                *smth += smulwb(prev as i32 - *smth as i32, CNG_NLSF_SMTH_Q16) as i16;
+            }
+
+            // Keeps the excitation of the loudest subframe.
+            let mut max_gain_q16 = 0;
+            let mut subfr = 0;
+            for (i, &gain) in ctrl.gains_q16[..self.nb_subfr].iter().enumerate() {
+                if gain > max_gain_q16 {
+                    max_gain_q16 = gain;
+                    subfr = i;
+                }
+            }
+            let subfr_length = self.subfr_length;
+            cng.exc_buf_q14
+                .copy_within(..(self.nb_subfr - 1) * subfr_length, subfr_length);
+            cng.exc_buf_q14[..subfr_length]
+                .copy_from_slice(&self.exc_q14[subfr * subfr_length..(subfr + 1) * subfr_length]);
+
+            for &gain in &ctrl.gains_q16[..self.nb_subfr] {
+                cng.smth_gain_q16 += smulwb(gain - cng.smth_gain_q16, CNG_GAIN_SMTH_Q16);
+                if smulww(cng.smth_gain_q16, CNG_GAIN_SMTH_THRESHOLD_Q16) > gain {
+                    cng.smth_gain_q16 = gain;
+                }
+            }
+        }
+
+        if self.loss_cnt == 0 {
+            cng.synth_state[..order].fill(0);
+            return;
+        }
+
+        // Tops the concealment's noise up to the comfort noise's level.
+        let mut gain_q16 = smulww(self.plc.rand_scale_q14 as i32, self.plc.prev_gain_q16[1]);
+        if gain_q16 >= 1 << 21 || cng.smth_gain_q16 > 1 << 23 {
+            gain_q16 = smultt(gain_q16, gain_q16);
+            gain_q16 =
+                smultt(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16.wrapping_shl(5));
+            gain_q16 = sqrt_approx(gain_q16).wrapping_shl(16);
+        } else {
+            gain_q16 = smulww(gain_q16, gain_q16);
+            gain_q16 =
+                smulww(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16.wrapping_shl(5));
+            gain_q16 = sqrt_approx(gain_q16).wrapping_shl(8);
+        }
+        let gain_q10 = gain_q16 >> 6;
+
+        let length = frame.len();
+        let mut sig_q14 = [0i32; MAX_FRAME_LENGTH + MAX_LPC_ORDER];
+        let mut exc_mask = CNG_BUF_MASK_MAX;
+        while exc_mask > length {
+            exc_mask >>= 1;
+        }
+        let mut seed = cng.rand_seed;
+        for sig in &mut sig_q14[MAX_LPC_ORDER..MAX_LPC_ORDER + length] {
+            seed = rand(seed);
+            *sig = cng.exc_buf_q14[(seed >> 24) as usize & exc_mask];
+        }
+        cng.rand_seed = seed;
+
+        let mut a_q12 = [0i16; MAX_LPC_ORDER];
+        nlsf2a(&mut a_q12[..order], &cng.smth_nlsf_q15[..order]);
+
+        sig_q14[..MAX_LPC_ORDER].copy_from_slice(&cng.synth_state);
+        for (i, x) in frame.iter_mut().enumerate() {
+            let mut lpc_pred_q10 = (order >> 1) as i32;
+            for (j, &a) in a_q12[..order].iter().enumerate() {
+                lpc_pred_q10 = smlawb(lpc_pred_q10, sig_q14[MAX_LPC_ORDER + i - j - 1], a as i32);
+            }
+            sig_q14[MAX_LPC_ORDER + i] =
+                add_sat32(sig_q14[MAX_LPC_ORDER + i], lshift_sat32(lpc_pred_q10, 4));
+            let noise = sat16(rshift_round(
+                smulww(sig_q14[MAX_LPC_ORDER + i], gain_q10),
+                8,
+            ));
+            *x = x.saturating_add(noise);
+        }
+        cng.synth_state
+            .copy_from_slice(&sig_q14[length..length + MAX_LPC_ORDER]);
+    }
+}
@@ -0,0 +1,352 @@
+//! Reconstructing a SILK frame from its indices and pulses, RFC 6716
+//! sections 4.2.7.4 to 4.2.7.9.
+
+use super::indices::Indices;
+use super::lpc::{analysis_filter, bwexpander};
+use super::math::{
+    add_sat32, div32_varq, inverse32_varq, limit, log2lin, lshift_sat32, rand, rshift_round, sat16,
+    smlawb, smulbb, smulwb, smulww,
+};
+use super::nlsf::{self, nlsf2a};
+use super::pulses::{SHELL_CODEC_FRAME_LENGTH, decode_pulses};
+use super::tables::*;
+use super::{
+    ChannelState, CondCoding, DecoderControl, LTP_ORDER, MAX_FRAME_LENGTH, MAX_LPC_ORDER,
+    MAX_NB_SUBFR, TYPE_VOICED,
+};
+use crate::opus::range_decoder::RangeDecoder;
+
+/// The gains' quantizer, 2 to 88 dB in 64 levels.
+const N_LEVELS_QGAIN: i32 = 64;
+const MAX_DELTA_GAIN_QUANT: i32 = 36;
+const MIN_DELTA_GAIN_QUANT: i32 = -4;
+const OFFSET: i32 = (2 * 128) / 6 + 16 * 128;
+const INV_SCALE_Q16: i32 = (65536 * (((88 - 2) * 128) / 6)) / (N_LEVELS_QGAIN - 1);
+/// How far pulses sit in from the integers, in Q10.
+const QUANT_LEVEL_ADJUST_Q10: i32 = 80;
+/// The bandwidth expansion of the first frame after a loss.
+const BWE_AFTER_LOSS_Q16: i32 = 63570;
+/// The pitch lag's range, in milliseconds.
+const PE_MIN_LAG_MS: i32 = 2;
+const PE_MAX_LAG_MS: i32 = 18;
+
+/// The gains of each subframe from their indices, each but the first, or
+/// every one if `conditional`, relative to the last.
+fn gains_dequant(
+    gain_q16: &mut [i32],
+    ind: &[i8],
+    prev_ind: &mut i8,
+    conditional: bool,
+    nb_subfr: usize,
+) {
+    for k in 0..nb_subfr {
+        let mut prev = *prev_ind as i32;
+        if k == 0 && !conditional {
+            // The gain can't drop more than 16 steps.
+            prev = (ind[k] as i32).max(prev - 16);
+        } else {
+            // Deltas above the threshold count double.
+            let ind_tmp = ind[k] as i32 + MIN_DELTA_GAIN_QUANT;
+            let double_step_size_threshold = 2 * MAX_DELTA_GAIN_QUANT - N_LEVELS_QGAIN + prev;
+            if ind_tmp > double_step_size_threshold {
+                prev += (ind_tmp << 1) - double_step_size_threshold;
+            } else {
+                prev += ind_tmp;
+            }
+        }
+        prev = limit(prev, 0, N_LEVELS_QGAIN - 1);
+        *prev_ind = prev as i8;
+        // 3967 is 31 in Q7.
+        gain_q16[k] = log2lin((smulwb(INV_SCALE_Q16, prev) + OFFSET).min(3967));
+    }
+}
+
+/// Each subframe's pitch lag from the frame's lag and contour.
+fn decode_pitch(
+    lag_index: i16,
+    contour_index: i8,
+    pitch_lags: &mut [i32],
+    fs_khz: i32,
+    nb_subfr: usize,
+) {
+    let (lag_cb, cbk_size): (&[i8], usize) = match (fs_khz, nb_subfr) {
+        (8, MAX_NB_SUBFR) => (&CB_LAGS_STAGE2, 11),
+        (8, _) => (&CB_LAGS_STAGE2_10_MS, 3),
+        (_, MAX_NB_SUBFR) => (&CB_LAGS_STAGE3, 34),
+        _ => (&CB_LAGS_STAGE3_10_MS, 12),
+    };
+    let min_lag = smulbb(PE_MIN_LAG_MS, fs_khz);
+    let max_lag = smulbb(PE_MAX_LAG_MS, fs_khz);
+    let lag = min_lag + lag_index as i32;
+    for (k, pitch_lag) in pitch_lags[..nb_subfr].iter_mut().enumerate() {
+        let offset = lag_cb[k * cbk_size + contour_index as usize] as i32;
+        *pitch_lag = limit(lag + offset, min_lag, max_lag);
+    }
+}
+
+impl ChannelState {
+    /// Decodes frame `self.n_frames_decoded` of the packet into `out`, or
+    /// conceals it if `lost`.
+    pub(super) fn decode_frame(
+        &mut self,
+        dec: &mut RangeDecoder,
+        out: &mut [i16],
+        lost: bool,
+        cond_coding: CondCoding,
+    ) {
+        let l = self.frame_length;
+        let out = &mut out[..l];
+        let mut ctrl = DecoderControl::default();
+
+        if !lost {
+            let mut pulses = [0i16; MAX_FRAME_LENGTH.next_multiple_of(SHELL_CODEC_FRAME_LENGTH)];
+            self.decode_indices(dec, self.n_frames_decoded, false, cond_coding);
+            decode_pulses(
+                dec,
+                &mut pulses,
+                self.indices.signal_type as i32,
+                self.indices.quant_offset_type as i32,
+                l,
+            );
+            self.decode_parameters(&mut ctrl, cond_coding);
+            self.decode_core(&mut ctrl, out, &pulses);
+            self.plc(&mut ctrl, out, false);
+
+            self.loss_cnt = 0;
+            self.prev_signal_type = self.indices.signal_type;
+            self.first_frame_after_reset = false;
+        } else {
+            self.plc(&mut ctrl, out, true);
+        }
+
+        // Keeps the output for the next frame's long term prediction.
+        let mv_len = self.ltp_mem_length - l;
+        self.out_buf.copy_within(l..l + mv_len, 0);
+        self.out_buf[mv_len..mv_len + l].copy_from_slice(out);
+
+        self.cng(&ctrl, out);
+        self.plc_glue_frames(out);
+
+        self.lag_prev = ctrl.pitch_l[self.nb_subfr - 1];
+    }
+
+    /// Reads the last frame's parameters from its indices.
+    fn decode_parameters(&mut self, ctrl: &mut DecoderControl, cond_coding: CondCoding) {
+        let order = self.lpc_order;
+        gains_dequant(
+            &mut ctrl.gains_q16,
+            &self.indices.gains_indices,
+            &mut self.last_gain_index,
+            cond_coding == CondCoding::Conditionally,
+            self.nb_subfr,
+        );
+
+        let mut nlsf_q15 = [0i16; MAX_LPC_ORDER];
+        nlsf::decode(&mut nlsf_q15, &self.indices.nlsf_indices, self.nlsf_cb);
+        nlsf2a(&mut ctrl.pred_coef_q12[1][..order], &nlsf_q15[..order]);
+
+        // Interpolating from before a reset would only make things worse.
+        if self.first_frame_after_reset {
+            self.indices.nlsf_interp_coef_q2 = 4;
+        }
+
+        if self.indices.nlsf_interp_coef_q2 < 4 {
+            // The first half of the frame interpolates from the last one.
+            let mut nlsf0_q15 = [0i16; MAX_LPC_ORDER];
+            let interp = self.indices.nlsf_interp_coef_q2 as i32;
+            for i in 0..order {
+                let prev = self.prev_nlsf_q15[i] as i32;
+                nlsf0_q15[i] = (prev + ((interp * (nlsf_q15[i] as i32 - prev)) >> 2)) as i16;
+            }
+            nlsf2a(&mut ctrl.pred_coef_q12[0][..order], &nlsf0_q15[..order]);
+        } else {
+            ctrl.pred_coef_q12[0] = ctrl.pred_coef_q12[1];
+        }
+
+        self.prev_nlsf_q15[..order].copy_from_slice(&nlsf_q15[..order]);
+
+        if self.loss_cnt != 0 {
+            bwexpander(&mut ctrl.pred_coef_q12[0][..order], BWE_AFTER_LOSS_Q16);
+            bwexpander(&mut ctrl.pred_coef_q12[1][..order], BWE_AFTER_LOSS_Q16);
+        }
+
+        let indices = &mut self.indices;
+        if indices.signal_type == TYPE_VOICED {
+            decode_pitch(
+                indices.lag_index,
+                indices.contour_index,
+                &mut ctrl.pitch_l,
+                self.fs_khz as i32,
+                self.nb_subfr,
+            );
+
+            let cbk_q7: &[i8] = match indices.per_index {
+                0 => &LTP_GAIN_VQ_0,
+                1 => &LTP_GAIN_VQ_1,
+                _ => &LTP_GAIN_VQ_2,
+            };
+            for k in 0..self.nb_subfr {
+                let ix = indices.ltp_index[k] as usize;
+                for i in 0..LTP_ORDER {
+                    ctrl.ltp_coef_q14[k * LTP_ORDER + i] = (cbk_q7[ix * LTP_ORDER + i] as i16) << 7;
+                }
+            }
+
+            ctrl.ltp_scale_q14 = LTP_SCALES_Q14[indices.ltp_scale_index as usize] as i32;
+        } else {
+            ctrl.pitch_l = [0; MAX_NB_SUBFR];
+            ctrl.ltp_coef_q14 = [0; LTP_ORDER * MAX_NB_SUBFR];
+            indices.per_index = 0;
+            ctrl.ltp_scale_q14 = 0;
+        }
+    }
+
+    /// Runs the excitation through the long and short term prediction
+    /// filters into `xq`.
+    fn decode_core(&mut self, ctrl: &mut DecoderControl, xq: &mut [i16], pulses: &[i16]) {
+        let Indices {
+            signal_type,
+            quant_offset_type,
+            nlsf_interp_coef_q2,
+            seed,
+            ..
+        } = self.indices;
+        let ltp_mem_length = self.ltp_mem_length;
+        let subfr_length = self.subfr_length;
+        let order = self.lpc_order;
+        let mut s_ltp = [0i16; MAX_FRAME_LENGTH];
+        let mut s_ltp_q15 = [0i32; 2 * MAX_FRAME_LENGTH];
+        let mut res_q14 = [0i32; MAX_FRAME_LENGTH / MAX_NB_SUBFR];
+        let mut s_lpc_q14 = [0i32; MAX_FRAME_LENGTH / MAX_NB_SUBFR + MAX_LPC_ORDER];
+
+        let offset_q10 = QUANTIZATION_OFFSETS_Q10
+            [(signal_type >> 1) as usize * 2 + quant_offset_type as usize]
+            as i32;
+        let nlsf_interpolation_flag = nlsf_interp_coef_q2 < 1 << 2;
+
+        // The excitation, its signs scrambled by a pseudorandom sequence.
+        let mut rand_seed = seed as i32;
+        for (exc, &pulse) in self.exc_q14[..self.frame_length].iter_mut().zip(pulses) {
+            rand_seed = rand(rand_seed);
+            *exc = (pulse as i32) << 14;
+            if *exc > 0 {
+                *exc -= QUANT_LEVEL_ADJUST_Q10 << 4;
+            } else if *exc < 0 {
+                *exc += QUANT_LEVEL_ADJUST_Q10 << 4;
+            }
+            *exc += offset_q10 << 4;
+            if rand_seed < 0 {
+                *exc = -*exc;
+            }
+            rand_seed = rand_seed.wrapping_add(pulse as i32);
+        }
+
+        s_lpc_q14[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14_buf);
+        let mut s_ltp_buf_idx = ltp_mem_length;
+        let mut lag = 0;
+        for k in 0..self.nb_subfr {
+            let exc_q14 = &self.exc_q14[k * subfr_length..(k + 1) * subfr_length];
+            let a_q12 = ctrl.pred_coef_q12[k >> 1];
+            let b_q14 = &mut ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+            let mut signal_type = signal_type;
+
+            let gain_q10 = ctrl.gains_q16[k] >> 6;
+            let mut inv_gain_q31 = inverse32_varq(ctrl.gains_q16[k], 47);
+
+            // Rescales the filter states to the new gain.
+            let gain_adj_q16 = if ctrl.gains_q16[k] != self.prev_gain_q16 {
+                let gain_adj_q16 = div32_varq(self.prev_gain_q16, ctrl.gains_q16[k], 16);
+                for s in &mut s_lpc_q14[..MAX_LPC_ORDER] {
+                    *s = smulww(gain_adj_q16, *s);
+                }
+                gain_adj_q16
+            } else {
+                1 << 16
+            };
+            self.prev_gain_q16 = ctrl.gains_q16[k];
+
+            // Eases from a concealed voiced frame into an unvoiced one.
+            if self.loss_cnt != 0
+                && self.prev_signal_type == TYPE_VOICED
+                && self.indices.signal_type != TYPE_VOICED
+                && k < MAX_NB_SUBFR / 2
+            {
+                b_q14.fill(0);
+                // 0.25 in Q14.
+                b_q14[LTP_ORDER / 2] = 4096;
+                signal_type = TYPE_VOICED;
+                ctrl.pitch_l[k] = self.lag_prev;
+            }
+
+            if signal_type == TYPE_VOICED {
+                lag = ctrl.pitch_l[k] as usize;
+
+                if k == 0 || (k == 2 && nlsf_interpolation_flag) {
+                    // Rewhitens the past output with the new filter.
+                    let start_idx = ltp_mem_length - lag - order - LTP_ORDER / 2;
+                    if k == 2 {
+                        self.out_buf[ltp_mem_length..ltp_mem_length + 2 * subfr_length]
+                            .copy_from_slice(&xq[..2 * subfr_length]);
+                    }
+                    analysis_filter(
+                        &mut s_ltp[start_idx..ltp_mem_length],
+                        &self.out_buf
+                            [start_idx + k * subfr_length..ltp_mem_length + k * subfr_length],
+                        &a_q12[..order],
+                    );
+
+                    // Scales the first subframe's down to limit how far
+                    // errors carry over between packets.
+                    if k == 0 {
+                        inv_gain_q31 = smulwb(inv_gain_q31, ctrl.ltp_scale_q14) << 2;
+                    }
+                    for i in 0..lag + LTP_ORDER / 2 {
+                        s_ltp_q15[s_ltp_buf_idx - i - 1] =
+                            smulwb(inv_gain_q31, s_ltp[ltp_mem_length - i - 1] as i32);
+                    }
+                } else if gain_adj_q16 != 1 << 16 {
+                    for i in 0..lag + LTP_ORDER / 2 {
+                        let s = &mut s_ltp_q15[s_ltp_buf_idx - i - 1];
+                        *s = smulww(gain_adj_q16, *s);
+                    }
+                }
+            }
+
+            let res_q14: &[i32] = if signal_type == TYPE_VOICED {
+                for (i, res) in res_q14[..subfr_length].iter_mut().enumerate() {
+                    let pred_lag = s_ltp_buf_idx - lag + LTP_ORDER / 2;
+                    // Starts from 2 to cancel out the rounding down.
+                    let mut ltp_pred_q13 = 2;
+                    for (j, &b) in b_q14.iter().enumerate() {
+                        ltp_pred_q13 = smlawb(ltp_pred_q13, s_ltp_q15[pred_lag - j], b as i32);
+                    }
+                    *res = exc_q14[i].wrapping_add(ltp_pred_q13.wrapping_shl(1));
+                    s_ltp_q15[s_ltp_buf_idx] = res.wrapping_shl(1);
+                    s_ltp_buf_idx += 1;
+                }
+                &res_q14[..subfr_length]
+            } else {
+                exc_q14
+            };
+
+            let xq = &mut xq[k * subfr_length..(k + 1) * subfr_length];
+            for (i, xq) in xq.iter_mut().enumerate() {
+                let mut lpc_pred_q10 = (order >> 1) as i32;
+                for (j, &a) in a_q12[..order].iter().enumerate() {
+                    lpc_pred_q10 =
+                        smlawb(lpc_pred_q10, s_lpc_q14[MAX_LPC_ORDER + i - j - 1], a as i32);
+                }
+                s_lpc_q14[MAX_LPC_ORDER + i] = add_sat32(res_q14[i], lshift_sat32(lpc_pred_q10, 4));
+                *xq = sat16(rshift_round(
+                    smulww(s_lpc_q14[MAX_LPC_ORDER + i], gain_q10),
+                    8,
+                ));
+            }
+
+            s_lpc_q14.copy_within(subfr_length..subfr_length + MAX_LPC_ORDER, 0);
+        }
+
+        self.s_lpc_q14_buf
+            .copy_from_slice(&s_lpc_q14[..MAX_LPC_ORDER]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Decoding the side information of a SILK frame, RFC 6716 sections
+//! 4.2.7.3 to 4.2.7.7.
+
+use super::nlsf::unpack;
+use super::tables::*;
+use super::{
+    ChannelState, CondCoding, MAX_LPC_ORDER, MAX_NB_SUBFR, NLSF_QUANT_MAX_AMPLITUDE, TYPE_VOICED,
+};
+use crate::opus::range_decoder::RangeDecoder;
+
+/// A frame's quantization indices, everything but its pulses.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Indices {
+    pub gains_indices: [i8; MAX_NB_SUBFR],
+    pub ltp_index: [i8; MAX_NB_SUBFR],
+    /// The first stage vector, then each residual.
+    pub nlsf_indices: [i8; MAX_LPC_ORDER + 1],
+    pub lag_index: i16,
+    pub contour_index: i8,
+    pub signal_type: i8,
+    pub quant_offset_type: i8,
+    pub nlsf_interp_coef_q2: i8,
+    pub per_index: i8,
+    pub ltp_scale_index: i8,
+    pub seed: i8,
+}
+
+impl ChannelState {
+    /// Reads the indices of frame `frame_index` of the packet, or of its
+    /// redundant copy if `decode_lbrr`.
+    pub(super) fn decode_indices(
+        &mut self,
+        dec: &mut RangeDecoder,
+        frame_index: usize,
+        decode_lbrr: bool,
+        cond_coding: CondCoding,
+    ) {
+        let indices = &mut self.indices;
+
+        let ix = if decode_lbrr || self.vad_flags[frame_index] {
+            dec.decode_icdf(&TYPE_OFFSET_VAD_ICDF, 8) + 2
+        } else {
+            dec.decode_icdf(&TYPE_OFFSET_NO_VAD_ICDF, 8)
+        };
+        indices.signal_type = (ix >> 1) as i8;
+        indices.quant_offset_type = (ix & 1) as i8;
+
+        // The first gain is coded relative to the last frame's if there's
+        // one, and in two stages otherwise.
+        if cond_coding == CondCoding::Conditionally {
+            indices.gains_indices[0] = dec.decode_icdf(&DELTA_GAIN_ICDF, 8) as i8;
+        } else {
+            let msbs = dec.decode_icdf(&GAIN_ICDF[indices.signal_type as usize], 8) as i8;
+            indices.gains_indices[0] = (msbs << 3) + dec.decode_icdf(&UNIFORM8_ICDF, 8) as i8;
+        }
+        for i in 1..self.nb_subfr {
+            indices.gains_indices[i] = dec.decode_icdf(&DELTA_GAIN_ICDF, 8) as i8;
+        }
+
+        let cb = self.nlsf_cb;
+        let cb1_icdf = &cb.cb1_icdf[(indices.signal_type >> 1) as usize * cb.n_vectors..];
+        indices.nlsf_indices[0] = dec.decode_icdf(cb1_icdf, 8) as i8;
+        let (ec_ix, _) = unpack(cb, indices.nlsf_indices[0] as usize);
+        for (i, &ec_ix) in ec_ix[..cb.order].iter().enumerate() {
+            let mut ix = dec.decode_icdf(&cb.ec_icdf[ec_ix..], 8) as i32;
+            // The extremes extend further.
+            if ix == 0 {
+                ix -= dec.decode_icdf(&NLSF_EXT_ICDF, 8) as i32;
+            } else if ix == 2 * NLSF_QUANT_MAX_AMPLITUDE {
+                ix += dec.decode_icdf(&NLSF_EXT_ICDF, 8) as i32;
+            }
+            indices.nlsf_indices[i + 1] = (ix - NLSF_QUANT_MAX_AMPLITUDE) as i8;
+        }
+
+        indices.nlsf_interp_coef_q2 = if self.nb_subfr == MAX_NB_SUBFR {
+            dec.decode_icdf(&NLSF_INTERPOLATION_FACTOR_ICDF, 8) as i8
+        } else {
+            4
+        };
+
+        if indices.signal_type == TYPE_VOICED {
+            // The lag is coded relative to the last frame's if it's close.
+            let mut decode_absolute_lag_index = true;
+            if cond_coding == CondCoding::Conditionally && self.ec_prev_signal_type == TYPE_VOICED {
+                let delta_lag_index = dec.decode_icdf(&PITCH_DELTA_ICDF, 8) as i16;
+                if delta_lag_index > 0 {
+                    indices.lag_index = self.ec_prev_lag_index + delta_lag_index - 9;
+                    decode_absolute_lag_index = false;
+                }
+            }
+            if decode_absolute_lag_index {
+                indices.lag_index =
+                    dec.decode_icdf(&PITCH_LAG_ICDF, 8) as i16 * (self.fs_khz >> 1) as i16;
+                indices.lag_index += dec.decode_icdf(self.pitch_lag_low_bits_icdf, 8) as i16;
+            }
+            self.ec_prev_lag_index = indices.lag_index;
+
+            indices.contour_index = dec.decode_icdf(self.pitch_contour_icdf, 8) as i8;
+
+            indices.per_index = dec.decode_icdf(&LTP_PER_INDEX_ICDF, 8) as i8;
+            let ltp_gain_icdf: &[u8] = match indices.per_index {
+                0 => &LTP_GAIN_ICDF_0,
+                1 => &LTP_GAIN_ICDF_1,
+                _ => &LTP_GAIN_ICDF_2,
+            };
+            for k in 0..self.nb_subfr {
+                indices.ltp_index[k] = dec.decode_icdf(ltp_gain_icdf, 8) as i8;
+            }
+
+            indices.ltp_scale_index = if cond_coding == CondCoding::Independently {
+                dec.decode_icdf(&LTP_SCALE_ICDF, 8) as i8
+            } else {
+                0
+            };
+        }
+        self.ec_prev_signal_type = indices.signal_type;
+
+        indices.seed = dec.decode_icdf(&UNIFORM4_ICDF, 8) as i8;
+    }
+}
@@ -0,0 +1,163 @@
+//! Keeping SILK's LPC filters stable, and the analysis filter its decoder
+//! rewhitens past output with.
+
+use super::MAX_LPC_ORDER;
+use super::math::{
+    clz32, inverse32_varq, rshift_round, rshift_round64, sat16, smlabb, smmul, smulww, sub_sat32,
+};
+
+/// Q of the coefficients the stability check works in.
+const QA: u32 = 24;
+/// 0.99975 in Q24, the largest reflection coefficient that counts as stable.
+const A_LIMIT: i32 = 16773022;
+/// 1 / 1e4 in Q30, the least inverse prediction gain that does.
+const MIN_INV_GAIN_Q30: i32 = 107374;
+
+/// Bandwidth expands the Q12 filter `ar` by `chirp_q16`.
+pub fn bwexpander(ar: &mut [i16], mut chirp_q16: i32) {
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+    let d = ar.len();
+    for a in &mut ar[..d - 1] {
+        *a = rshift_round(chirp_q16 * *a as i32, 16) as i16;
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    }
+    ar[d - 1] = rshift_round(chirp_q16 * ar[d - 1] as i32, 16) as i16;
+}
+
+/// `bwexpander` for 32 bit coefficients.
+pub fn bwexpander_32(ar: &mut [i32], mut chirp_q16: i32) {
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+    let d = ar.len();
+    for a in &mut ar[..d - 1] {
+        *a = smulww(chirp_q16, *a);
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    }
+    ar[d - 1] = smulww(chirp_q16, ar[d - 1]);
+}
+
+/// Converts `a_qin` from Q`q_in` to Q`q_out` into `a_qout`, bandwidth
+/// expanding it first until it fits in 16 bits.
+pub fn lpc_fit(a_qout: &mut [i16], a_qin: &mut [i32], q_out: u32, q_in: u32) {
+    let d = a_qout.len();
+    let mut iterations = 0;
+    while iterations < 10 {
+        let mut maxabs = 0;
+        let mut idx = 0;
+        for (k, a) in a_qin.iter().enumerate() {
+            let absval = a.wrapping_abs();
+            if absval > maxabs {
+                maxabs = absval;
+                idx = k as i32;
+            }
+        }
+        let maxabs = rshift_round(maxabs, q_in - q_out);
+        if maxabs <= i16::MAX as i32 {
+            break;
+        }
+        // (i32::MAX >> 14) + i16::MAX.
+        let maxabs = maxabs.min(163838);
+        // 0.999 in Q16.
+        let chirp_q16 = 65470 - ((maxabs - i16::MAX as i32) << 14) / ((maxabs * (idx + 1)) >> 2);
+        bwexpander_32(a_qin, chirp_q16);
+        iterations += 1;
+    }
+
+    if iterations == 10 {
+        // Clips what's left.
+        for k in 0..d {
+            a_qout[k] = sat16(rshift_round(a_qin[k], q_in - q_out));
+            a_qin[k] = (a_qout[k] as i32) << (q_in - q_out);
+        }
+    } else {
+        for k in 0..d {
+            a_qout[k] = rshift_round(a_qin[k], q_in - q_out) as i16;
+        }
+    }
+}
+
+/// `(a * b) >> q`, rounded.
+fn mul32_frac_q(a: i32, b: i32, q: u32) -> i32 {
+    rshift_round64(a as i64 * b as i64, q) as i32
+}
+
+/// The inverse of the prediction gain of the Q12 filter `a_q12` in Q30,
+/// or 0 if it's unstable.
+pub fn inverse_pred_gain(a_q12: &[i16]) -> i32 {
+    let order = a_q12.len();
+    let mut a_qa = [0i32; MAX_LPC_ORDER];
+    let mut dc_resp = 0;
+    for (a_qa, &a) in a_qa.iter_mut().zip(a_q12) {
+        dc_resp += a as i32;
+        *a_qa = (a as i32) << (QA - 12);
+    }
+    // An unstable DC response needs no further checking.
+    if dc_resp >= 4096 {
+        return 0;
+    }
+    let a_qa = &mut a_qa[..order];
+
+    let mut inv_gain_q30 = 1 << 30;
+    for k in (1..order).rev() {
+        if a_qa[k] > A_LIMIT || a_qa[k] < -A_LIMIT {
+            return 0;
+        }
+        // The reflection coefficient is the negated AR coefficient.
+        let rc_q31 = -(a_qa[k] << (31 - QA));
+        let rc_mult1_q30 = (1 << 30) - smmul(rc_q31, rc_q31);
+        inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30) << 2;
+        if inv_gain_q30 < MIN_INV_GAIN_Q30 {
+            return 0;
+        }
+        let mult2q = 32 - clz32(rc_mult1_q30.wrapping_abs());
+        let rc_mult2 = inverse32_varq(rc_mult1_q30, mult2q + 30);
+
+        for n in 0..(k + 1) >> 1 {
+            let tmp1 = a_qa[n];
+            let tmp2 = a_qa[k - n - 1];
+            let tmp64 = rshift_round64(
+                sub_sat32(tmp1, mul32_frac_q(tmp2, rc_q31, 31)) as i64 * rc_mult2 as i64,
+                mult2q as u32,
+            );
+            if tmp64 > i32::MAX as i64 || tmp64 < i32::MIN as i64 {
+                return 0;
+            }
+            a_qa[n] = tmp64 as i32;
+            let tmp64 = rshift_round64(
+                sub_sat32(tmp2, mul32_frac_q(tmp1, rc_q31, 31)) as i64 * rc_mult2 as i64,
+                mult2q as u32,
+            );
+            if tmp64 > i32::MAX as i64 || tmp64 < i32::MIN as i64 {
+                return 0;
+            }
+            a_qa[k - n - 1] = tmp64 as i32;
+        }
+    }
+
+    if a_qa[0] > A_LIMIT || a_qa[0] < -A_LIMIT {
+        return 0;
+    }
+    let rc_q31 = -(a_qa[0] << (31 - QA));
+    let rc_mult1_q30 = (1 << 30) - smmul(rc_q31, rc_q31);
+    inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30) << 2;
+    if inv_gain_q30 < MIN_INV_GAIN_Q30 {
+        return 0;
+    }
+    inv_gain_q30
+}
+
+/// Filters `input` with the Q12 prediction filter `b` into `out`, the
+/// first `b.len()` samples of which are left 0 for want of history.
+pub fn analysis_filter(out: &mut [i16], input: &[i16], b: &[i16]) {
+    let d = b.len();
+    for ix in d..input.len() {
+        let mut out32_q12 = 0i32;
+        for (j, &b) in b.iter().enumerate() {
+            out32_q12 = smlabb(out32_q12, input[ix - 1 - j] as i32, b as i32);
+        }
+        // Subtracts the prediction, wrapping as an invalid stream may make
+        // it.
+        let out32_q12 = ((input[ix] as i32) << 12).wrapping_sub(out32_q12);
+        out[ix] = sat16(rshift_round(out32_q12, 12));
+    }
+    out[..d].fill(0);
+}
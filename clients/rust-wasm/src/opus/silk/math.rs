@@ -0,0 +1,193 @@
+//! SILK's fixed-point arithmetic, wrapping and rounding exactly as
+//! libopus's macros do so the output matches it bit for bit.
+
+/// `(a * (b as i16)) >> 16`.
+pub fn smulwb(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i16 as i64) >> 16) as i32
+}
+
+/// `a + smulwb(b, c)`.
+pub fn smlawb(a: i32, b: i32, c: i32) -> i32 {
+    (a as i64 + ((b as i64 * c as i16 as i64) >> 16)) as i32
+}
+
+/// `(a * b) >> 16`.
+pub fn smulww(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i64) >> 16) as i32
+}
+
+/// `a + smulww(b, c)`.
+pub fn smlaww(a: i32, b: i32, c: i32) -> i32 {
+    (a as i64 + ((b as i64 * c as i64) >> 16)) as i32
+}
+
+/// The product of the bottom halves of `a` and `b`.
+pub fn smulbb(a: i32, b: i32) -> i32 {
+    a as i16 as i32 * b as i16 as i32
+}
+
+/// `a + smulbb(b, c)`, wrapping.
+pub fn smlabb(a: i32, b: i32, c: i32) -> i32 {
+    a.wrapping_add(smulbb(b, c))
+}
+
+/// The product of the top halves of `a` and `b`.
+pub fn smultt(a: i32, b: i32) -> i32 {
+    (a >> 16).wrapping_mul(b >> 16)
+}
+
+/// `(a * b) >> 32`.
+pub fn smmul(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i64) >> 32) as i32
+}
+
+/// `a >> shift`, rounded to nearest.
+pub fn rshift_round(a: i32, shift: u32) -> i32 {
+    if shift == 1 {
+        (a >> 1) + (a & 1)
+    } else {
+        ((a >> (shift - 1)) + 1) >> 1
+    }
+}
+
+pub fn rshift_round64(a: i64, shift: u32) -> i64 {
+    if shift == 1 {
+        (a >> 1) + (a & 1)
+    } else {
+        ((a >> (shift - 1)) + 1) >> 1
+    }
+}
+
+/// Clamps `a` between `limit1` and `limit2`, whichever is lower.
+pub fn limit(a: i32, limit1: i32, limit2: i32) -> i32 {
+    if limit1 > limit2 {
+        a.clamp(limit2, limit1)
+    } else {
+        a.clamp(limit1, limit2)
+    }
+}
+
+pub fn sat16(a: i32) -> i16 {
+    a.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+pub fn add_sat32(a: i32, b: i32) -> i32 {
+    a.saturating_add(b)
+}
+
+pub fn sub_sat32(a: i32, b: i32) -> i32 {
+    a.saturating_sub(b)
+}
+
+/// `a << shift`, saturating.
+pub fn lshift_sat32(a: i32, shift: u32) -> i32 {
+    limit(a, i32::MIN >> shift, i32::MAX >> shift) << shift
+}
+
+pub fn clz32(a: i32) -> i32 {
+    (a as u32).leading_zeros() as i32
+}
+
+/// SILK's linear congruential generator.
+pub fn rand(seed: i32) -> i32 {
+    907633515i32.wrapping_add(seed.wrapping_mul(196314165))
+}
+
+/// The leading zeros of `a` and the 7 bits after its leading one.
+fn clz_frac(a: i32) -> (i32, i32) {
+    let lz = clz32(a);
+    let frac_q7 = (a as u32).rotate_right((24 - lz).rem_euclid(32) as u32) as i32 & 0x7f;
+    (lz, frac_q7)
+}
+
+/// An approximate square root, within 2.5% above 120.
+pub fn sqrt_approx(x: i32) -> i32 {
+    if x <= 0 {
+        return 0;
+    }
+    let (lz, frac_q7) = clz_frac(x);
+    let mut y = if lz & 1 != 0 { 32768 } else { 46214 };
+    y >>= lz >> 1;
+    smlawb(y, y, smulbb(213, frac_q7))
+}
+
+/// Approximately `(a << q_res) / b`.
+pub fn div32_varq(a: i32, b: i32, q_res: i32) -> i32 {
+    let a_headrm = clz32(a.wrapping_abs()) - 1;
+    let a_nrm = a << a_headrm;
+    let b_headrm = clz32(b.wrapping_abs()) - 1;
+    let b_nrm = b << b_headrm;
+    // The inverse of b to 14 bits.
+    let b_inv = (i32::MAX >> 2) / (b_nrm >> 16);
+    let result = smulwb(a_nrm, b_inv);
+    // Refines it with the residual, which is small enough not to matter
+    // when it wraps.
+    let a_nrm = a_nrm.wrapping_sub((smmul(b_nrm, result) as u32).wrapping_shl(3) as i32);
+    let result = smlawb(result, a_nrm, b_inv);
+    let lshift = 29 + a_headrm - b_headrm - q_res;
+    if lshift < 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result >> lshift
+    } else {
+        0
+    }
+}
+
+/// Approximately `(1 << q_res) / b`.
+pub fn inverse32_varq(b: i32, q_res: i32) -> i32 {
+    let b_headrm = clz32(b.wrapping_abs()) - 1;
+    let b_nrm = b << b_headrm;
+    let b_inv = (i32::MAX >> 2) / (b_nrm >> 16);
+    let result = b_inv << 16;
+    let err_q32 = ((1 << 29) - smulwb(b_nrm, b_inv)) << 3;
+    let result = smlaww(result, err_q32, b_inv);
+    let lshift = 61 - b_headrm - q_res;
+    if lshift <= 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result >> lshift
+    } else {
+        0
+    }
+}
+
+/// Approximately `2^(x / 128)`.
+pub fn log2lin(in_log_q7: i32) -> i32 {
+    if in_log_q7 < 0 {
+        return 0;
+    } else if in_log_q7 >= 3967 {
+        return i32::MAX;
+    }
+    let out = 1 << (in_log_q7 >> 7);
+    let frac_q7 = in_log_q7 & 0x7f;
+    let frac = smlawb(frac_q7, smulbb(frac_q7, 128 - frac_q7), -174);
+    if in_log_q7 < 2048 {
+        out + ((out * frac) >> 7)
+    } else {
+        out + (out >> 7) * frac
+    }
+}
+
+/// The energy of `x`, shifted right by the returned amount to fit in 31
+/// bits with two to spare.
+pub fn sum_sqr_shift(x: &[i16]) -> (i32, i32) {
+    let len = x.len() as i32;
+    let sum = |shift: i32| {
+        let mut nrg = 0u32;
+        for pair in x.chunks(2) {
+            let mut tmp = smulbb(pair[0] as i32, pair[0] as i32) as u32;
+            if let Some(&x1) = pair.get(1) {
+                tmp = tmp.wrapping_add(smulbb(x1 as i32, x1 as i32) as u32);
+            }
+            nrg = nrg.wrapping_add(tmp >> shift);
+        }
+        nrg
+    };
+    // A first pass with the most shift it could need, starting from `len`
+    // to round conservatively.
+    let shift = 31 - clz32(len);
+    let nrg = (len as u32).wrapping_add(sum(shift)) as i32;
+    let shift = (shift + 3 - clz32(nrg)).max(0);
+    (sum(shift) as i32, shift)
+}
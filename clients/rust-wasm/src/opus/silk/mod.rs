@@ -0,0 +1,457 @@
+//! A port of libopus's fixed-point SILK decoder, RFC 6716 section 4.2,
+//! without its in-band FEC.
+
+mod cng;
+mod frame;
+mod indices;
+mod lpc;
+mod math;
+mod nlsf;
+mod plc;
+mod pulses;
+mod resampler;
+mod stereo;
+mod tables;
+
+use super::range_decoder::RangeDecoder;
+use cng::Cng;
+use indices::Indices;
+use nlsf::{NLSF_CB_NB_MB, NLSF_CB_WB, NlsfCodebook};
+use plc::Plc;
+use resampler::Resampler;
+use stereo::{StereoState, decode_mid_only, decode_pred, ms_to_lr};
+use tables::{
+    LBRR_FLAGS_2_ICDF, LBRR_FLAGS_3_ICDF, PITCH_CONTOUR_10_MS_ICDF, PITCH_CONTOUR_10_MS_NB_ICDF,
+    PITCH_CONTOUR_ICDF, PITCH_CONTOUR_NB_ICDF, UNIFORM4_ICDF, UNIFORM6_ICDF, UNIFORM8_ICDF,
+};
+
+const MAX_NB_SUBFR: usize = 4;
+const MAX_LPC_ORDER: usize = 16;
+const MIN_LPC_ORDER: usize = 10;
+const LTP_ORDER: usize = 5;
+const MAX_FRAMES_PER_PACKET: usize = 3;
+const SUB_FRAME_LENGTH_MS: usize = 5;
+const LTP_MEM_LENGTH_MS: usize = 20;
+const MAX_FS_KHZ: usize = 16;
+const MAX_SUB_FRAME_LENGTH: usize = SUB_FRAME_LENGTH_MS * MAX_FS_KHZ;
+const MAX_FRAME_LENGTH: usize = MAX_SUB_FRAME_LENGTH * MAX_NB_SUBFR;
+/// The largest API sample rate in kHz, and so of 20 ms of output.
+const MAX_API_FS_KHZ: usize = 48;
+/// The most an NLSF residual is coded as without extension.
+const NLSF_QUANT_MAX_AMPLITUDE: i32 = 4;
+
+const TYPE_NO_VOICE_ACTIVITY: i8 = 0;
+const TYPE_VOICED: i8 = 2;
+
+/// How a frame's parameters are coded relative to the last frame's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CondCoding {
+    Independently,
+    /// Independently, but without the LTP scaling that limits error
+    /// propagation.
+    IndependentlyNoLtpScaling,
+    Conditionally,
+}
+
+/// The parameters of the frame being decoded.
+#[derive(Debug, Default)]
+struct DecoderControl {
+    pitch_l: [i32; MAX_NB_SUBFR],
+    gains_q16: [i32; MAX_NB_SUBFR],
+    /// The LPC filters of the first and second halves of the frame.
+    pred_coef_q12: [[i16; MAX_LPC_ORDER]; 2],
+    ltp_coef_q14: [i16; LTP_ORDER * MAX_NB_SUBFR],
+    ltp_scale_q14: i32,
+}
+
+/// The decoder of one of the stream's mid and side channels.
+#[derive(Debug, Clone)]
+struct ChannelState {
+    prev_gain_q16: i32,
+    exc_q14: [i32; MAX_FRAME_LENGTH],
+    s_lpc_q14_buf: [i32; MAX_LPC_ORDER],
+    out_buf: [i16; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+    lag_prev: i32,
+    last_gain_index: i8,
+    fs_khz: usize,
+    fs_api_hz: i32,
+    nb_subfr: usize,
+    frame_length: usize,
+    subfr_length: usize,
+    ltp_mem_length: usize,
+    lpc_order: usize,
+    prev_nlsf_q15: [i16; MAX_LPC_ORDER],
+    first_frame_after_reset: bool,
+    pitch_lag_low_bits_icdf: &'static [u8],
+    pitch_contour_icdf: &'static [u8],
+    n_frames_decoded: usize,
+    n_frames_per_packet: usize,
+    ec_prev_signal_type: i8,
+    ec_prev_lag_index: i16,
+    vad_flags: [bool; MAX_FRAMES_PER_PACKET],
+    lbrr_flag: bool,
+    lbrr_flags: [bool; MAX_FRAMES_PER_PACKET],
+    resampler: Resampler,
+    nlsf_cb: &'static NlsfCodebook,
+    indices: Indices,
+    cng: Cng,
+    loss_cnt: i32,
+    prev_signal_type: i8,
+    plc: Plc,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        let mut state = Self {
+            prev_gain_q16: 65536,
+            exc_q14: [0; MAX_FRAME_LENGTH],
+            s_lpc_q14_buf: [0; MAX_LPC_ORDER],
+            out_buf: [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+            lag_prev: 0,
+            last_gain_index: 0,
+            fs_khz: 0,
+            fs_api_hz: 0,
+            nb_subfr: 0,
+            frame_length: 0,
+            subfr_length: 0,
+            ltp_mem_length: 0,
+            lpc_order: 0,
+            prev_nlsf_q15: [0; MAX_LPC_ORDER],
+            first_frame_after_reset: true,
+            pitch_lag_low_bits_icdf: &[],
+            pitch_contour_icdf: &[],
+            n_frames_decoded: 0,
+            n_frames_per_packet: 0,
+            ec_prev_signal_type: 0,
+            ec_prev_lag_index: 0,
+            vad_flags: [false; MAX_FRAMES_PER_PACKET],
+            lbrr_flag: false,
+            lbrr_flags: [false; MAX_FRAMES_PER_PACKET],
+            resampler: Resampler::default(),
+            nlsf_cb: &NLSF_CB_NB_MB,
+            indices: Indices::default(),
+            cng: Cng::default(),
+            loss_cnt: 0,
+            prev_signal_type: 0,
+            plc: Plc::default(),
+        };
+        state.cng_reset();
+        state.plc_reset();
+        state
+    }
+
+    /// Switches to an internal rate of `fs_khz` and an output rate of
+    /// `fs_api_hz`.
+    fn set_fs(&mut self, fs_khz: usize, fs_api_hz: i32) {
+        self.subfr_length = SUB_FRAME_LENGTH_MS * fs_khz;
+        let frame_length = self.nb_subfr * self.subfr_length;
+
+        if self.fs_khz != fs_khz || self.fs_api_hz != fs_api_hz {
+            self.resampler = Resampler::new(fs_khz as i32 * 1000, fs_api_hz);
+            self.fs_api_hz = fs_api_hz;
+        }
+
+        if self.fs_khz != fs_khz || frame_length != self.frame_length {
+            self.pitch_contour_icdf = match (fs_khz, self.nb_subfr) {
+                (8, MAX_NB_SUBFR) => &PITCH_CONTOUR_NB_ICDF,
+                (8, _) => &PITCH_CONTOUR_10_MS_NB_ICDF,
+                (_, MAX_NB_SUBFR) => &PITCH_CONTOUR_ICDF,
+                _ => &PITCH_CONTOUR_10_MS_ICDF,
+            };
+            if self.fs_khz != fs_khz {
+                self.ltp_mem_length = LTP_MEM_LENGTH_MS * fs_khz;
+                (self.lpc_order, self.nlsf_cb) = if fs_khz == 16 {
+                    (MAX_LPC_ORDER, &NLSF_CB_WB)
+                } else {
+                    (MIN_LPC_ORDER, &NLSF_CB_NB_MB)
+                };
+                self.pitch_lag_low_bits_icdf = match fs_khz {
+                    16 => &UNIFORM8_ICDF,
+                    12 => &UNIFORM6_ICDF,
+                    _ => &UNIFORM4_ICDF,
+                };
+                self.first_frame_after_reset = true;
+                self.lag_prev = 100;
+                self.last_gain_index = 10;
+                self.prev_signal_type = TYPE_NO_VOICE_ACTIVITY;
+                self.out_buf.fill(0);
+                self.s_lpc_q14_buf.fill(0);
+            }
+            self.fs_khz = fs_khz;
+            self.frame_length = frame_length;
+        }
+    }
+
+    /// Forgets the channel's history ahead of its first frame with side
+    /// information after frames without.
+    fn reset_side(&mut self) {
+        self.out_buf.fill(0);
+        self.s_lpc_q14_buf.fill(0);
+        self.lag_prev = 100;
+        self.last_gain_index = 10;
+        self.prev_signal_type = TYPE_NO_VOICE_ACTIVITY;
+        self.first_frame_after_reset = true;
+    }
+}
+
+/// How the Opus layer wants the next SILK frame decoded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecControl {
+    pub n_channels_api: usize,
+    pub n_channels_internal: usize,
+    pub api_sample_rate: i32,
+    /// 8, 12 or 16 kHz.
+    pub internal_sample_rate: i32,
+    /// 10, 20, 40 or 60, or 0 to conceal a lost packet as 10 ms.
+    pub payload_size_ms: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SilkDecoder {
+    channel_state: [ChannelState; 2],
+    stereo: StereoState,
+    n_channels_api: usize,
+    n_channels_internal: usize,
+    prev_decode_only_middle: bool,
+}
+
+impl Default for SilkDecoder {
+    fn default() -> Self {
+        Self {
+            channel_state: [ChannelState::new(), ChannelState::new()],
+            stereo: StereoState::default(),
+            n_channels_api: 0,
+            n_channels_internal: 0,
+            prev_decode_only_middle: false,
+        }
+    }
+}
+
+impl SilkDecoder {
+    /// Resets everything but the channel counts.
+    pub fn reset(&mut self) {
+        self.channel_state = [ChannelState::new(), ChannelState::new()];
+        self.stereo = StereoState::default();
+        self.prev_decode_only_middle = false;
+    }
+
+    /// Decodes the next 10 or 20 ms frame of the packet in `dec`, starting
+    /// a new one if `new_packet`, or conceals it if `lost`, into `out`,
+    /// interleaved. Returns the samples per channel.
+    pub fn decode(
+        &mut self,
+        control: &DecControl,
+        lost: bool,
+        new_packet: bool,
+        dec: &mut RangeDecoder,
+        out: &mut [i16],
+    ) -> usize {
+        let n_internal = control.n_channels_internal;
+        let mut decode_only_middle = false;
+        let mut ms_pred_q13 = [0; 2];
+
+        if new_packet {
+            for state in &mut self.channel_state[..n_internal] {
+                state.n_frames_decoded = 0;
+            }
+        }
+
+        // The stream went from mono to stereo.
+        if n_internal > self.n_channels_internal {
+            self.channel_state[1] = ChannelState::new();
+        }
+
+        let stereo_to_mono = n_internal == 1
+            && self.n_channels_internal == 2
+            && control.internal_sample_rate == 1000 * self.channel_state[0].fs_khz as i32;
+
+        if self.channel_state[0].n_frames_decoded == 0 {
+            for state in &mut self.channel_state[..n_internal] {
+                (state.n_frames_per_packet, state.nb_subfr) = match control.payload_size_ms {
+                    0 | 10 => (1, 2),
+                    20 => (1, 4),
+                    40 => (2, 4),
+                    _ => (3, 4),
+                };
+                let fs_khz_dec = ((control.internal_sample_rate >> 10) + 1) as usize;
+                state.set_fs(fs_khz_dec, control.api_sample_rate);
+            }
+        }
+
+        if control.n_channels_api == 2
+            && n_internal == 2
+            && (self.n_channels_api == 1 || self.n_channels_internal == 1)
+        {
+            self.stereo.pred_prev_q13 = [0; 2];
+            self.stereo.s_side = [0; 2];
+            self.channel_state[1].resampler = self.channel_state[0].resampler.clone();
+        }
+        self.n_channels_api = control.n_channels_api;
+        self.n_channels_internal = n_internal;
+
+        if !lost && self.channel_state[0].n_frames_decoded == 0 {
+            // The packet's voice activity and redundancy flags.
+            for state in &mut self.channel_state[..n_internal] {
+                for i in 0..state.n_frames_per_packet {
+                    state.vad_flags[i] = dec.decode_bit_logp(1);
+                }
+                state.lbrr_flag = dec.decode_bit_logp(1);
+            }
+            for state in &mut self.channel_state[..n_internal] {
+                state.lbrr_flags = [false; MAX_FRAMES_PER_PACKET];
+                if state.lbrr_flag {
+                    if state.n_frames_per_packet == 1 {
+                        state.lbrr_flags[0] = true;
+                    } else {
+                        let icdf: &[u8] = if state.n_frames_per_packet == 2 {
+                            &LBRR_FLAGS_2_ICDF
+                        } else {
+                            &LBRR_FLAGS_3_ICDF
+                        };
+                        let lbrr_symbol = dec.decode_icdf(icdf, 8) + 1;
+                        for i in 0..state.n_frames_per_packet {
+                            state.lbrr_flags[i] = (lbrr_symbol >> i) & 1 != 0;
+                        }
+                    }
+                }
+            }
+
+            // Skips the redundant copies of the last packet's frames.
+            for i in 0..self.channel_state[0].n_frames_per_packet {
+                for n in 0..n_internal {
+                    if !self.channel_state[n].lbrr_flags[i] {
+                        continue;
+                    }
+                    if n_internal == 2 && n == 0 {
+                        ms_pred_q13 = decode_pred(dec);
+                        if !self.channel_state[1].lbrr_flags[i] {
+                            decode_only_middle = decode_mid_only(dec);
+                        }
+                    }
+                    let state = &mut self.channel_state[n];
+                    let cond_coding = if i > 0 && state.lbrr_flags[i - 1] {
+                        CondCoding::Conditionally
+                    } else {
+                        CondCoding::Independently
+                    };
+                    state.decode_indices(dec, i, true, cond_coding);
+                    let mut pulses = [0; MAX_FRAME_LENGTH];
+                    pulses::decode_pulses(
+                        dec,
+                        &mut pulses,
+                        state.indices.signal_type as i32,
+                        state.indices.quant_offset_type as i32,
+                        state.frame_length,
+                    );
+                }
+            }
+        }
+
+        if n_internal == 2 {
+            if !lost {
+                ms_pred_q13 = decode_pred(dec);
+                let frame = self.channel_state[0].n_frames_decoded;
+                decode_only_middle = if !self.channel_state[1].vad_flags[frame] {
+                    decode_mid_only(dec)
+                } else {
+                    false
+                };
+            } else {
+                ms_pred_q13 = self.stereo.pred_prev_q13.map(|pred| pred as i32);
+            }
+        }
+
+        if n_internal == 2 && !decode_only_middle && self.prev_decode_only_middle {
+            self.channel_state[1].reset_side();
+        }
+
+        let has_side = if !lost {
+            !decode_only_middle
+        } else {
+            !self.prev_decode_only_middle
+        };
+
+        // Each channel's output, after 2 samples of the last frame's.
+        let mut samples_out1 = [[0i16; MAX_FRAME_LENGTH + 2]; 2];
+        let mut n_samples_out_dec = 0;
+        for (n, samples_out) in samples_out1.iter_mut().enumerate().take(n_internal) {
+            if n == 0 || has_side {
+                let frame_index = self.channel_state[0].n_frames_decoded as isize - n as isize;
+                let cond_coding = if frame_index <= 0 {
+                    CondCoding::Independently
+                } else if n > 0 && self.prev_decode_only_middle {
+                    // The side channel's LTP state is well defined after
+                    // skipped frames.
+                    CondCoding::IndependentlyNoLtpScaling
+                } else {
+                    CondCoding::Conditionally
+                };
+                let state = &mut self.channel_state[n];
+                state.decode_frame(dec, &mut samples_out[2..], lost, cond_coding);
+                n_samples_out_dec = state.frame_length;
+            } else {
+                samples_out[2..2 + n_samples_out_dec].fill(0);
+            }
+            self.channel_state[n].n_frames_decoded += 1;
+        }
+
+        let fs_khz = self.channel_state[0].fs_khz;
+        if control.n_channels_api == 2 && n_internal == 2 {
+            let [x1, x2] = &mut samples_out1;
+            ms_to_lr(
+                &mut self.stereo,
+                x1,
+                x2,
+                ms_pred_q13,
+                fs_khz,
+                n_samples_out_dec,
+            );
+        } else {
+            samples_out1[0][..2].copy_from_slice(&self.stereo.s_mid);
+            self.stereo
+                .s_mid
+                .copy_from_slice(&samples_out1[0][n_samples_out_dec..n_samples_out_dec + 2]);
+        }
+
+        let n_samples_out = n_samples_out_dec * control.api_sample_rate as usize / (fs_khz * 1000);
+        let mut resample_out = [0i16; 2 * MAX_FRAME_LENGTH / MAX_FS_KHZ * MAX_API_FS_KHZ];
+        let resample_out = &mut resample_out[..n_samples_out];
+        for n in 0..control.n_channels_api.min(n_internal) {
+            self.channel_state[n]
+                .resampler
+                .resample(resample_out, &samples_out1[n][1..1 + n_samples_out_dec]);
+            for (i, &x) in resample_out.iter().enumerate() {
+                out[n + control.n_channels_api * i] = x;
+            }
+        }
+
+        // Mono streams play on both output channels.
+        if control.n_channels_api == 2 && n_internal == 1 {
+            if stereo_to_mono {
+                // The right channel may have been further behind.
+                self.channel_state[1]
+                    .resampler
+                    .resample(resample_out, &samples_out1[0][1..1 + n_samples_out_dec]);
+                for (i, &x) in resample_out.iter().enumerate() {
+                    out[1 + 2 * i] = x;
+                }
+            } else {
+                for i in 0..n_samples_out {
+                    out[1 + 2 * i] = out[2 * i];
+                }
+            }
+        }
+
+        if lost {
+            // Keeps the energy from bouncing back if losses continue as it
+            // falls.
+            for state in &mut self.channel_state[..self.n_channels_internal] {
+                state.last_gain_index = 10;
+            }
+        } else {
+            self.prev_decode_only_middle = decode_only_middle;
+        }
+
+        n_samples_out
+    }
+}
@@ -0,0 +1,241 @@
+//! Decoding SILK's normalized line spectral frequencies, RFC 6716 section
+//! 4.2.7.5, and turning them into LPC filters.
+
+use super::lpc::{bwexpander_32, inverse_pred_gain, lpc_fit};
+use super::math::{limit, rshift_round, rshift_round64, smlawb, smulbb};
+use super::tables::*;
+use super::{MAX_LPC_ORDER, NLSF_QUANT_MAX_AMPLITUDE};
+
+/// A two stage NLSF codebook.
+#[derive(Debug)]
+pub struct NlsfCodebook {
+    pub n_vectors: usize,
+    pub order: usize,
+    quant_step_size_q16: i32,
+    cb1_nlsf_q8: &'static [u8],
+    cb1_wght_q9: &'static [i16],
+    /// The first stage's distribution, for unvoiced then voiced frames.
+    pub cb1_icdf: &'static [u8],
+    pred_q8: &'static [u8],
+    ec_sel: &'static [u8],
+    /// The residuals' distributions.
+    pub ec_icdf: &'static [u8],
+    delta_min_q15: &'static [i16],
+}
+
+/// The codebook of 8 and 12 kHz frames.
+pub const NLSF_CB_NB_MB: NlsfCodebook = NlsfCodebook {
+    n_vectors: 32,
+    order: 10,
+    // 0.18 in Q16.
+    quant_step_size_q16: 11796,
+    cb1_nlsf_q8: &NLSF_CB1_NB_MB_Q8,
+    cb1_wght_q9: &NLSF_CB1_NB_MB_WGHT_Q9,
+    cb1_icdf: &NLSF_CB1_NB_MB_ICDF,
+    pred_q8: &NLSF_PRED_NB_MB_Q8,
+    ec_sel: &NLSF_CB2_SELECT_NB_MB,
+    ec_icdf: &NLSF_CB2_NB_MB_ICDF,
+    delta_min_q15: &NLSF_DELTA_MIN_NB_MB_Q15,
+};
+
+/// The codebook of 16 kHz frames.
+pub const NLSF_CB_WB: NlsfCodebook = NlsfCodebook {
+    n_vectors: 32,
+    order: 16,
+    // 0.15 in Q16.
+    quant_step_size_q16: 9830,
+    cb1_nlsf_q8: &NLSF_CB1_WB_Q8,
+    cb1_wght_q9: &NLSF_CB1_WB_WGHT_Q9,
+    cb1_icdf: &NLSF_CB1_WB_ICDF,
+    pred_q8: &NLSF_PRED_WB_Q8,
+    ec_sel: &NLSF_CB2_SELECT_WB,
+    ec_icdf: &NLSF_CB2_WB_ICDF,
+    delta_min_q15: &NLSF_DELTA_MIN_WB_Q15,
+};
+
+/// 0.1 in Q10, how far the residual levels sit in from the integers.
+const NLSF_QUANT_LEVEL_ADJ_Q10: i32 = 102;
+
+/// The offset of each residual's distribution in `ec_icdf`, and the weight
+/// it's predicted from the next one with, for the first stage vector
+/// `cb1_index`.
+pub fn unpack(
+    cb: &NlsfCodebook,
+    cb1_index: usize,
+) -> ([usize; MAX_LPC_ORDER], [u8; MAX_LPC_ORDER]) {
+    let mut ec_ix = [0; MAX_LPC_ORDER];
+    let mut pred_q8 = [0; MAX_LPC_ORDER];
+    let ec_sel = &cb.ec_sel[cb1_index * cb.order / 2..];
+    for i in (0..cb.order).step_by(2) {
+        let entry = ec_sel[i / 2] as usize;
+        let stride = 2 * NLSF_QUANT_MAX_AMPLITUDE as usize + 1;
+        ec_ix[i] = ((entry >> 1) & 7) * stride;
+        pred_q8[i] = cb.pred_q8[i + (entry & 1) * (cb.order - 1)];
+        ec_ix[i + 1] = ((entry >> 5) & 7) * stride;
+        pred_q8[i + 1] = cb.pred_q8[i + ((entry >> 4) & 1) * (cb.order - 1) + 1];
+    }
+    (ec_ix, pred_q8)
+}
+
+/// The NLSFs of the codebook path `indices`, the first stage vector then
+/// each residual.
+pub fn decode(nlsf_q15: &mut [i16], indices: &[i8], cb: &NlsfCodebook) {
+    let cb1_index = indices[0] as usize;
+    let (_, pred_q8) = unpack(cb, cb1_index);
+
+    // Dequantizes the residuals, each predicted from the one after it.
+    let mut res_q10 = [0i16; MAX_LPC_ORDER];
+    let mut out_q10 = 0;
+    for i in (0..cb.order).rev() {
+        let pred_q10 = smulbb(out_q10, pred_q8[i] as i32) >> 8;
+        out_q10 = (indices[i + 1] as i32) << 10;
+        if out_q10 > 0 {
+            out_q10 -= NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else if out_q10 < 0 {
+            out_q10 += NLSF_QUANT_LEVEL_ADJ_Q10;
+        }
+        out_q10 = smlawb(pred_q10, out_q10, cb.quant_step_size_q16);
+        res_q10[i] = out_q10 as i16;
+    }
+
+    // Adds them to the first stage, weighted.
+    let cb_element = &cb.cb1_nlsf_q8[cb1_index * cb.order..];
+    let cb_wght_q9 = &cb.cb1_wght_q9[cb1_index * cb.order..];
+    for i in 0..cb.order {
+        let nlsf_q15_tmp =
+            ((res_q10[i] as i32) << 14) / cb_wght_q9[i] as i32 + ((cb_element[i] as i32) << 7);
+        nlsf_q15[i] = nlsf_q15_tmp.clamp(0, 32767) as i16;
+    }
+
+    stabilize(&mut nlsf_q15[..cb.order], cb.delta_min_q15);
+}
+
+/// Moves the NLSFs apart until each is at least `delta_min_q15` from its
+/// neighbours and the ends of the range.
+fn stabilize(nlsf_q15: &mut [i16], delta_min_q15: &[i16]) {
+    const MAX_LOOPS: usize = 20;
+    let l = nlsf_q15.len();
+    let delta_min = |i: usize| delta_min_q15[i] as i32;
+
+    for _ in 0..MAX_LOOPS {
+        // Finds the smallest distance.
+        let mut min_diff_q15 = nlsf_q15[0] as i32 - delta_min(0);
+        let mut index = 0;
+        for i in 1..l {
+            let diff_q15 = nlsf_q15[i] as i32 - (nlsf_q15[i - 1] as i32 + delta_min(i));
+            if diff_q15 < min_diff_q15 {
+                min_diff_q15 = diff_q15;
+                index = i;
+            }
+        }
+        let diff_q15 = (1 << 15) - (nlsf_q15[l - 1] as i32 + delta_min(l));
+        if diff_q15 < min_diff_q15 {
+            min_diff_q15 = diff_q15;
+            index = l;
+        }
+
+        if min_diff_q15 >= 0 {
+            return;
+        }
+
+        if index == 0 {
+            nlsf_q15[0] = delta_min(0) as i16;
+        } else if index == l {
+            nlsf_q15[l - 1] = ((1 << 15) - delta_min(l)) as i16;
+        } else {
+            // Moves the pair apart around their center, as far as the
+            // NLSFs either side of them allow.
+            let mut min_center_q15 = (0..index).map(delta_min).sum::<i32>();
+            min_center_q15 += delta_min(index) >> 1;
+            let mut max_center_q15 = (1 << 15) - (index + 1..=l).map(delta_min).sum::<i32>();
+            max_center_q15 -= delta_min(index) >> 1;
+            let center_freq_q15 = limit(
+                rshift_round(nlsf_q15[index - 1] as i32 + nlsf_q15[index] as i32, 1),
+                min_center_q15,
+                max_center_q15,
+            ) as i16;
+            nlsf_q15[index - 1] = center_freq_q15 - (delta_min(index) >> 1) as i16;
+            nlsf_q15[index] = nlsf_q15[index - 1] + delta_min(index) as i16;
+        }
+    }
+
+    // Falls back to sorting them and pushing them apart.
+    nlsf_q15.sort_unstable();
+    nlsf_q15[0] = nlsf_q15[0].max(delta_min(0) as i16);
+    for i in 1..l {
+        let min = (nlsf_q15[i - 1] as i32 + delta_min(i)).min(i16::MAX as i32) as i16;
+        nlsf_q15[i] = nlsf_q15[i].max(min);
+    }
+    nlsf_q15[l - 1] = nlsf_q15[l - 1].min(((1 << 15) - delta_min(l)) as i16);
+    for i in (0..l - 1).rev() {
+        nlsf_q15[i] = nlsf_q15[i].min(nlsf_q15[i + 1] - delta_min(i + 1) as i16);
+    }
+}
+
+/// Q of the polynomials `nlsf2a` builds.
+const QA: u32 = 16;
+const MAX_LPC_STABILIZE_ITERATIONS: i32 = 16;
+
+/// The polynomial whose roots are the cosines `c_lsf`, every other one
+/// taken.
+fn find_poly(out: &mut [i32], c_lsf: &[i32], dd: usize) {
+    out[0] = 1 << QA;
+    out[1] = -c_lsf[0];
+    for k in 1..dd {
+        let ftmp = c_lsf[2 * k] as i64;
+        out[k + 1] =
+            (out[k - 1] << 1).wrapping_sub(rshift_round64(ftmp * out[k] as i64, QA) as i32);
+        for n in (2..=k).rev() {
+            out[n] = out[n].wrapping_add(
+                out[n - 2].wrapping_sub(rshift_round64(ftmp * out[n - 1] as i64, QA) as i32),
+            );
+        }
+        out[1] = out[1].wrapping_sub(ftmp as i32);
+    }
+}
+
+/// The Q12 LPC filter of the NLSFs `nlsf`, of order 10 or 16.
+pub fn nlsf2a(a_q12: &mut [i16], nlsf: &[i16]) {
+    // Orders that keep `find_poly` most accurate.
+    const ORDERING16: [usize; 16] = [0, 15, 8, 7, 4, 11, 12, 3, 2, 13, 10, 5, 6, 9, 14, 1];
+    const ORDERING10: [usize; 10] = [0, 9, 6, 3, 4, 5, 8, 1, 2, 7];
+    let d = nlsf.len();
+    let ordering: &[usize] = if d == 16 { &ORDERING16 } else { &ORDERING10 };
+
+    // 2 * cos(LSF), interpolated from the table.
+    let mut cos_lsf_qa = [0i32; MAX_LPC_ORDER];
+    for (k, &nlsf) in nlsf.iter().enumerate() {
+        let f_int = (nlsf >> (15 - 7)) as usize;
+        let f_frac = nlsf as i32 - ((f_int as i32) << (15 - 7));
+        let cos_val = LSF_COS_TAB_Q12[f_int] as i32;
+        let delta = LSF_COS_TAB_Q12[f_int + 1] as i32 - cos_val;
+        cos_lsf_qa[ordering[k]] = rshift_round((cos_val << 8) + delta * f_frac, 20 - QA);
+    }
+
+    let dd = d / 2;
+    let mut p = [0i32; MAX_LPC_ORDER / 2 + 1];
+    let mut q = [0i32; MAX_LPC_ORDER / 2 + 1];
+    find_poly(&mut p, &cos_lsf_qa, dd);
+    find_poly(&mut q, &cos_lsf_qa[1..], dd);
+
+    let mut a32_qa1 = [0i32; MAX_LPC_ORDER];
+    for k in 0..dd {
+        let ptmp = p[k + 1].wrapping_add(p[k]);
+        let qtmp = q[k + 1].wrapping_sub(q[k]);
+        a32_qa1[k] = qtmp.wrapping_neg().wrapping_sub(ptmp);
+        a32_qa1[d - k - 1] = qtmp.wrapping_sub(ptmp);
+    }
+    let a32_qa1 = &mut a32_qa1[..d];
+
+    lpc_fit(a_q12, a32_qa1, 12, QA + 1);
+
+    // Bandwidth expands it until it's stable.
+    let mut i = 0;
+    while inverse_pred_gain(a_q12) == 0 && i < MAX_LPC_STABILIZE_ITERATIONS {
+        bwexpander_32(a32_qa1, 65536 - (2 << i));
+        for k in 0..d {
+            a_q12[k] = rshift_round(a32_qa1[k], QA + 1 - 12) as i16;
+        }
+        i += 1;
+    }
+}
@@ -0,0 +1,300 @@
+//! Concealing lost SILK frames by extrapolating the last good one.
+
+use super::lpc::{analysis_filter, bwexpander, inverse_pred_gain};
+use super::math::{
+    add_sat32, clz32, inverse32_varq, lshift_sat32, rand, rshift_round, sat16, smlawb, smulbb,
+    smulwb, smulww, sqrt_approx, sum_sqr_shift,
+};
+use super::{
+    ChannelState, DecoderControl, LTP_ORDER, MAX_FRAME_LENGTH, MAX_LPC_ORDER, MAX_NB_SUBFR,
+    TYPE_VOICED,
+};
+
+/// How much the pitch and noise fade with each lost frame, the second
+/// entry for every frame after the first.
+const HARM_ATT_Q15: [i32; 2] = [32440, 31130];
+const PLC_RAND_ATTENUATE_V_Q15: [i32; 2] = [31130, 26214];
+const PLC_RAND_ATTENUATE_UV_Q15: [i32; 2] = [32440, 29491];
+
+/// 0.99 in Q16.
+const BWE_COEF_Q16: i32 = 64881;
+/// 0.7 and 0.95 in Q14, the range the extrapolated pitch gain starts in.
+const V_PITCH_GAIN_START_MIN_Q14: i32 = 11469;
+const V_PITCH_GAIN_START_MAX_Q14: i32 = 15565;
+const MAX_PITCH_LAG_MS: i32 = 18;
+const RAND_BUF_SIZE: usize = 128;
+const RAND_BUF_MASK: i32 = RAND_BUF_SIZE as i32 - 1;
+/// The noise is scaled down by the LPC filter's gain between 8 and 24 dB.
+const LOG2_INV_LPC_GAIN_HIGH_THRES: u32 = 3;
+const LOG2_INV_LPC_GAIN_LOW_THRES: u32 = 8;
+/// 0.01 in Q16, how fast the pitch lag drifts up.
+const PITCH_DRIFT_FAC_Q16: i32 = 655;
+
+/// What the last good frame left to extrapolate from.
+#[derive(Debug, Default, Clone)]
+pub struct Plc {
+    pub pitch_l_q8: i32,
+    ltp_coef_q14: [i16; LTP_ORDER],
+    prev_lpc_q12: [i16; MAX_LPC_ORDER],
+    last_frame_lost: bool,
+    rand_seed: i32,
+    pub rand_scale_q14: i16,
+    conc_energy: i32,
+    conc_energy_shift: i32,
+    prev_ltp_scale_q14: i16,
+    pub prev_gain_q16: [i32; 2],
+    fs_khz: usize,
+    nb_subfr: usize,
+    subfr_length: usize,
+}
+
+impl ChannelState {
+    pub(super) fn plc_reset(&mut self) {
+        self.plc.pitch_l_q8 = (self.frame_length as i32) << (8 - 1);
+        self.plc.prev_gain_q16 = [1 << 16; 2];
+        self.plc.subfr_length = 20;
+        self.plc.nb_subfr = 2;
+    }
+
+    /// Conceals `frame` if it's `lost`, and otherwise learns from it.
+    pub(super) fn plc(&mut self, ctrl: &mut DecoderControl, frame: &mut [i16], lost: bool) {
+        if self.fs_khz != self.plc.fs_khz {
+            self.plc_reset();
+            self.plc.fs_khz = self.fs_khz;
+        }
+        if lost {
+            self.plc_conceal(ctrl, frame);
+            self.loss_cnt += 1;
+        } else {
+            self.plc_update(ctrl);
+        }
+    }
+
+    fn plc_update(&mut self, ctrl: &DecoderControl) {
+        let nb_subfr = self.nb_subfr;
+        let plc = &mut self.plc;
+        self.prev_signal_type = self.indices.signal_type;
+        let mut ltp_gain_q14 = 0;
+        if self.indices.signal_type == TYPE_VOICED {
+            // Takes the strongest pitch filter of the last pitch period.
+            let mut j = 0;
+            while j * (self.subfr_length as i32) < ctrl.pitch_l[nb_subfr - 1]
+                && j != nb_subfr as i32
+            {
+                let k = nb_subfr - 1 - j as usize;
+                let coefs = &ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+                let temp_ltp_gain_q14 = coefs.iter().map(|&c| c as i32).sum::<i32>();
+                if temp_ltp_gain_q14 > ltp_gain_q14 {
+                    ltp_gain_q14 = temp_ltp_gain_q14;
+                    plc.ltp_coef_q14.copy_from_slice(coefs);
+                    plc.pitch_l_q8 = ctrl.pitch_l[k] << 8;
+                }
+                j += 1;
+            }
+
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+            plc.ltp_coef_q14[LTP_ORDER / 2] = ltp_gain_q14 as i16;
+
+            // Keeps the gain in a sensible range.
+            if ltp_gain_q14 < V_PITCH_GAIN_START_MIN_Q14 {
+                let scale_q10 = (V_PITCH_GAIN_START_MIN_Q14 << 10) / ltp_gain_q14.max(1);
+                for c in &mut plc.ltp_coef_q14 {
+                    *c = (smulbb(*c as i32, scale_q10) >> 10) as i16;
+                }
+            } else if ltp_gain_q14 > V_PITCH_GAIN_START_MAX_Q14 {
+                let scale_q14 = (V_PITCH_GAIN_START_MAX_Q14 << 14) / ltp_gain_q14.max(1);
+                for c in &mut plc.ltp_coef_q14 {
+                    *c = (smulbb(*c as i32, scale_q14) >> 14) as i16;
+                }
+            }
+        } else {
+            plc.pitch_l_q8 = smulbb(self.fs_khz as i32, 18) << 8;
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+        }
+
+        let order = self.lpc_order;
+        plc.prev_lpc_q12[..order].copy_from_slice(&ctrl.pred_coef_q12[1][..order]);
+        plc.prev_ltp_scale_q14 = ctrl.ltp_scale_q14 as i16;
+        plc.prev_gain_q16
+            .copy_from_slice(&ctrl.gains_q16[nb_subfr - 2..nb_subfr]);
+        plc.subfr_length = self.subfr_length;
+        plc.nb_subfr = nb_subfr;
+    }
+
+    /// The energies of the excitation of the last two subframes.
+    fn plc_energy(&self, prev_gain_q10: [i32; 2]) -> ((i32, i32), (i32, i32)) {
+        let subfr_length = self.subfr_length;
+        let mut exc_buf = [0i16; 2 * MAX_FRAME_LENGTH / MAX_NB_SUBFR];
+        for k in 0..2 {
+            for i in 0..subfr_length {
+                let exc = self.exc_q14[i + (k + self.nb_subfr - 2) * subfr_length];
+                exc_buf[k * subfr_length + i] = sat16(smulww(exc, prev_gain_q10[k]) >> 8);
+            }
+        }
+        (
+            sum_sqr_shift(&exc_buf[..subfr_length]),
+            sum_sqr_shift(&exc_buf[subfr_length..2 * subfr_length]),
+        )
+    }
+
+    fn plc_conceal(&mut self, ctrl: &mut DecoderControl, frame: &mut [i16]) {
+        let ltp_mem_length = self.ltp_mem_length;
+        let frame_length = self.frame_length;
+        let order = self.lpc_order;
+        let mut s_ltp_q14 = [0i32; 2 * MAX_FRAME_LENGTH];
+        let mut s_ltp = [0i16; MAX_FRAME_LENGTH];
+
+        let prev_gain_q10 = self.plc.prev_gain_q16.map(|gain| gain >> 6);
+
+        if self.first_frame_after_reset {
+            self.plc.prev_lpc_q12 = [0; MAX_LPC_ORDER];
+        }
+
+        // Takes the noise from whichever subframe had less energy.
+        let ((energy1, shift1), (energy2, shift2)) = self.plc_energy(prev_gain_q10);
+        let rand_start = if energy1 >> shift2 < energy2 >> shift1 {
+            ((self.plc.nb_subfr - 1) * self.plc.subfr_length).saturating_sub(RAND_BUF_SIZE)
+        } else {
+            (self.plc.nb_subfr * self.plc.subfr_length).saturating_sub(RAND_BUF_SIZE)
+        };
+        let rand_ptr = &self.exc_q14[rand_start..];
+
+        let plc = &mut self.plc;
+        let mut rand_scale_q14 = plc.rand_scale_q14;
+        let att = self.loss_cnt.min(1) as usize;
+        let harm_gain_q15 = HARM_ATT_Q15[att];
+        let mut rand_gain_q15 = if self.prev_signal_type == TYPE_VOICED {
+            PLC_RAND_ATTENUATE_V_Q15[att]
+        } else {
+            PLC_RAND_ATTENUATE_UV_Q15[att]
+        };
+
+        bwexpander(&mut plc.prev_lpc_q12[..order], BWE_COEF_Q16);
+        let a_q12 = plc.prev_lpc_q12;
+
+        if self.loss_cnt == 0 {
+            rand_scale_q14 = 1 << 14;
+            if self.prev_signal_type == TYPE_VOICED {
+                // Less noise the more periodic the signal was.
+                for &b in &plc.ltp_coef_q14 {
+                    rand_scale_q14 = rand_scale_q14.wrapping_sub(b);
+                }
+                // 0.2 in Q14.
+                rand_scale_q14 = rand_scale_q14.max(3277);
+                rand_scale_q14 =
+                    (smulbb(rand_scale_q14 as i32, plc.prev_ltp_scale_q14 as i32) >> 14) as i16;
+            } else {
+                // Less noise the more the LPC filter amplifies it.
+                let inv_gain_q30 = inverse_pred_gain(&plc.prev_lpc_q12[..order]);
+                let down_scale_q30 = inv_gain_q30.clamp(
+                    (1 << 30) >> LOG2_INV_LPC_GAIN_LOW_THRES,
+                    (1 << 30) >> LOG2_INV_LPC_GAIN_HIGH_THRES,
+                ) << LOG2_INV_LPC_GAIN_HIGH_THRES;
+                rand_gain_q15 = smulwb(down_scale_q30, rand_gain_q15) >> 14;
+            }
+        }
+
+        let mut rand_seed = plc.rand_seed;
+        let mut lag = rshift_round(plc.pitch_l_q8, 8) as usize;
+        let mut s_ltp_buf_idx = ltp_mem_length;
+
+        // Rewhitens the last output with the filter to extrapolate.
+        let idx = ltp_mem_length - lag - order - LTP_ORDER / 2;
+        analysis_filter(
+            &mut s_ltp[idx..ltp_mem_length],
+            &self.out_buf[idx..ltp_mem_length],
+            &a_q12[..order],
+        );
+        let inv_gain_q30 = inverse32_varq(plc.prev_gain_q16[1], 46).min(i32::MAX >> 1);
+        for i in idx + order..ltp_mem_length {
+            s_ltp_q14[i] = smulwb(inv_gain_q30, s_ltp[i] as i32);
+        }
+
+        // Repeats the pitch period with noise, both fading.
+        let b_q14 = &mut plc.ltp_coef_q14;
+        for _ in 0..self.nb_subfr {
+            for _ in 0..self.subfr_length {
+                let pred_lag = s_ltp_buf_idx - lag + LTP_ORDER / 2;
+                let mut ltp_pred_q12 = 2;
+                for (j, &b) in b_q14.iter().enumerate() {
+                    ltp_pred_q12 = smlawb(ltp_pred_q12, s_ltp_q14[pred_lag - j], b as i32);
+                }
+                rand_seed = rand(rand_seed);
+                let idx = ((rand_seed >> 25) & RAND_BUF_MASK) as usize;
+                s_ltp_q14[s_ltp_buf_idx] =
+                    smlawb(ltp_pred_q12, rand_ptr[idx], rand_scale_q14 as i32).wrapping_shl(2);
+                s_ltp_buf_idx += 1;
+            }
+
+            for b in b_q14.iter_mut() {
+                *b = (smulbb(harm_gain_q15, *b as i32) >> 15) as i16;
+            }
+            rand_scale_q14 = (smulbb(rand_scale_q14 as i32, rand_gain_q15) >> 15) as i16;
+
+            plc.pitch_l_q8 = smlawb(plc.pitch_l_q8, plc.pitch_l_q8, PITCH_DRIFT_FAC_Q16);
+            plc.pitch_l_q8 = plc
+                .pitch_l_q8
+                .min(smulbb(MAX_PITCH_LAG_MS, self.fs_khz as i32) << 8);
+            lag = rshift_round(plc.pitch_l_q8, 8) as usize;
+        }
+
+        // LPC synthesis, continuing from the last frame's state.
+        let s_lpc_q14 = &mut s_ltp_q14[ltp_mem_length - MAX_LPC_ORDER..];
+        s_lpc_q14[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14_buf);
+        for i in 0..frame_length {
+            let mut lpc_pred_q10 = (order >> 1) as i32;
+            for (j, &a) in a_q12[..order].iter().enumerate() {
+                lpc_pred_q10 = smlawb(lpc_pred_q10, s_lpc_q14[MAX_LPC_ORDER + i - j - 1], a as i32);
+            }
+            s_lpc_q14[MAX_LPC_ORDER + i] =
+                add_sat32(s_lpc_q14[MAX_LPC_ORDER + i], lshift_sat32(lpc_pred_q10, 4));
+            frame[i] = sat16(rshift_round(
+                smulww(s_lpc_q14[MAX_LPC_ORDER + i], prev_gain_q10[1]),
+                8,
+            ));
+        }
+        self.s_lpc_q14_buf
+            .copy_from_slice(&s_lpc_q14[frame_length..frame_length + MAX_LPC_ORDER]);
+
+        plc.rand_seed = rand_seed;
+        plc.rand_scale_q14 = rand_scale_q14;
+        ctrl.pitch_l = [lag as i32; MAX_NB_SUBFR];
+    }
+
+    /// Fades from a concealed frame into a good one that's louder than it.
+    pub(super) fn plc_glue_frames(&mut self, frame: &mut [i16]) {
+        let plc = &mut self.plc;
+        if self.loss_cnt != 0 {
+            (plc.conc_energy, plc.conc_energy_shift) = sum_sqr_shift(frame);
+            plc.last_frame_lost = true;
+            return;
+        }
+
+        if plc.last_frame_lost {
+            let (mut energy, energy_shift) = sum_sqr_shift(frame);
+            if energy_shift > plc.conc_energy_shift {
+                plc.conc_energy >>= energy_shift - plc.conc_energy_shift;
+            } else if energy_shift < plc.conc_energy_shift {
+                energy >>= plc.conc_energy_shift - energy_shift;
+            }
+
+            if energy > plc.conc_energy {
+                let lz = clz32(plc.conc_energy) - 1;
+                plc.conc_energy <<= lz;
+                energy >>= (24 - lz).max(0);
+                let frac_q24 = plc.conc_energy / energy.max(1);
+
+                let mut gain_q16 = sqrt_approx(frac_q24) << 4;
+                let slope_q16 = (((1 << 16) - gain_q16) / frame.len() as i32) << 2;
+                for x in frame.iter_mut() {
+                    *x = smulwb(gain_q16, *x as i32) as i16;
+                    gain_q16 += slope_q16;
+                    if gain_q16 > 1 << 16 {
+                        break;
+                    }
+                }
+            }
+        }
+        plc.last_frame_lost = false;
+    }
+}
@@ -0,0 +1,126 @@
+//! Decoding SILK's excitation pulses, RFC 6716 section 4.2.7.8.
+
+use super::tables::*;
+use crate::opus::range_decoder::RangeDecoder;
+
+/// Samples in each block of pulses.
+pub const SHELL_CODEC_FRAME_LENGTH: usize = 16;
+const N_RATE_LEVELS: usize = 10;
+const SILK_MAX_PULSES: usize = 16;
+
+/// Splits the `p` pulses of a block between its two halves.
+fn decode_split(dec: &mut RangeDecoder, p: i16, shell_table: &[u8]) -> (i16, i16) {
+    if p > 0 {
+        let offset = SHELL_CODE_TABLE_OFFSETS[p as usize] as usize;
+        let child1 = dec.decode_icdf(&shell_table[offset..], 8) as i16;
+        (child1, p - child1)
+    } else {
+        (0, 0)
+    }
+}
+
+/// The magnitudes of a block's 16 pulses, `pulses4` in all, halving the
+/// block recursively in the order libopus reads them.
+fn shell_decoder(pulses0: &mut [i16], dec: &mut RangeDecoder, pulses4: i16) {
+    let mut pulses3 = [0; 2];
+    let mut pulses2 = [0; 4];
+    let mut pulses1 = [0; 8];
+    (pulses3[0], pulses3[1]) = decode_split(dec, pulses4, &SHELL_CODE_TABLE3);
+    for i in 0..2 {
+        (pulses2[2 * i], pulses2[2 * i + 1]) = decode_split(dec, pulses3[i], &SHELL_CODE_TABLE2);
+        for j in 2 * i..2 * i + 2 {
+            (pulses1[2 * j], pulses1[2 * j + 1]) =
+                decode_split(dec, pulses2[j], &SHELL_CODE_TABLE1);
+            for k in 2 * j..2 * j + 2 {
+                (pulses0[2 * k], pulses0[2 * k + 1]) =
+                    decode_split(dec, pulses1[k], &SHELL_CODE_TABLE0);
+            }
+        }
+    }
+}
+
+/// The frame's `frame_length` pulses, signed, into `pulses`, whose length
+/// is rounded up to a whole block.
+pub fn decode_pulses(
+    dec: &mut RangeDecoder,
+    pulses: &mut [i16],
+    signal_type: i32,
+    quant_offset_type: i32,
+    frame_length: usize,
+) {
+    let rate_level_index = dec.decode_icdf(&RATE_LEVELS_ICDF[(signal_type >> 1) as usize * 9..], 8);
+
+    // 10 ms at 12 kHz ends with a partial block.
+    let iter = frame_length.div_ceil(SHELL_CODEC_FRAME_LENGTH);
+
+    // The pulses in each block, and the bits below them coded separately.
+    let mut sum_pulses = [0i32; 20];
+    let mut n_lshifts = [0i32; 20];
+    let per_block = |level: usize| &PULSES_PER_BLOCK_ICDF[level * (SILK_MAX_PULSES + 2)..];
+    for i in 0..iter {
+        sum_pulses[i] = dec.decode_icdf(per_block(rate_level_index), 8) as i32;
+        while sum_pulses[i] == SILK_MAX_PULSES as i32 + 1 {
+            n_lshifts[i] += 1;
+            // After 10 extra bits, a block can't escape again.
+            let icdf = &per_block(N_RATE_LEVELS - 1)[(n_lshifts[i] == 10) as usize..];
+            sum_pulses[i] = dec.decode_icdf(icdf, 8) as i32;
+        }
+    }
+
+    for i in 0..iter {
+        let block = &mut pulses[i * SHELL_CODEC_FRAME_LENGTH..(i + 1) * SHELL_CODEC_FRAME_LENGTH];
+        if sum_pulses[i] > 0 {
+            shell_decoder(block, dec, sum_pulses[i] as i16);
+        } else {
+            block.fill(0);
+        }
+    }
+
+    for i in 0..iter {
+        if n_lshifts[i] > 0 {
+            let block =
+                &mut pulses[i * SHELL_CODEC_FRAME_LENGTH..(i + 1) * SHELL_CODEC_FRAME_LENGTH];
+            for pulse in block {
+                let mut abs_q = *pulse as i32;
+                for _ in 0..n_lshifts[i] {
+                    abs_q = (abs_q << 1) + dec.decode_icdf(&LSB_ICDF, 8) as i32;
+                }
+                *pulse = abs_q as i16;
+            }
+            // Marks the block as having pulses for the signs.
+            sum_pulses[i] |= n_lshifts[i] << 5;
+        }
+    }
+
+    decode_signs(
+        dec,
+        pulses,
+        frame_length,
+        signal_type,
+        quant_offset_type,
+        &sum_pulses,
+    );
+}
+
+fn decode_signs(
+    dec: &mut RangeDecoder,
+    pulses: &mut [i16],
+    length: usize,
+    signal_type: i32,
+    quant_offset_type: i32,
+    sum_pulses: &[i32],
+) {
+    let icdf_ptr = &SIGN_ICDF[7 * (quant_offset_type + 2 * signal_type) as usize..];
+    let length = (length + SHELL_CODEC_FRAME_LENGTH / 2) / SHELL_CODEC_FRAME_LENGTH;
+    for (block, &p) in pulses
+        .chunks_mut(SHELL_CODEC_FRAME_LENGTH)
+        .zip(&sum_pulses[..length])
+    {
+        if p > 0 {
+            let icdf = [icdf_ptr[(p & 0x1f).min(6) as usize], 0];
+            for q in block.iter_mut().filter(|q| **q > 0) {
+                *q *= 2 * dec.decode_icdf(&icdf, 8) as i16 - 1;
+            }
+        }
+    }
+}
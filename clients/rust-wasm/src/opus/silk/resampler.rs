@@ -0,0 +1,317 @@
+//! SILK's resampler from its internal rate to the decoder's, RFC 6716
+//! section 4.2.9.
+
+use super::math::{rshift_round, sat16, smlabb, smlawb, smulbb, smulwb, smulww};
+
+/// The most input a call filters at once.
+const MAX_BATCH_SIZE_MS: usize = 10;
+const MAX_FIR_ORDER: usize = 36;
+const ORDER_FIR_12: usize = 8;
+const DOWN_ORDER_FIR0: usize = 18;
+const DOWN_ORDER_FIR1: usize = 24;
+const DOWN_ORDER_FIR2: usize = 36;
+
+/// The delay in input samples each pair of rates needs, for internal rates
+/// of 8, 12 and 16 kHz by output rates of 8, 12, 16, 24 and 48 kHz.
+const DELAY_MATRIX_DEC: [[usize; 5]; 3] = [[4, 0, 2, 0, 0], [0, 9, 4, 7, 4], [0, 3, 12, 7, 7]];
+
+/// The allpass coefficients of the 2x upsampler's even and odd outputs.
+const UP2_HQ_0: [i32; 3] = [1746, 14986, 39083 - 65536];
+const UP2_HQ_1: [i32; 3] = [6854, 25769, 55542 - 65536];
+
+/// The interpolation filter between 2x upsampled samples, in twelfths.
+const FRAC_FIR_12: [[i16; 4]; 12] = [
+    [189, -600, 617, 30567],
+    [117, -159, -1070, 29704],
+    [52, 221, -2392, 28276],
+    [-4, 529, -3350, 26341],
+    [-48, 758, -3956, 23973],
+    [-80, 905, -4235, 21254],
+    [-99, 972, -4222, 18278],
+    [-107, 967, -3957, 15143],
+    [-103, 896, -3487, 11950],
+    [-91, 773, -2865, 8798],
+    [-71, 611, -2143, 5784],
+    [-46, 425, -1375, 2996],
+];
+
+// The downsamplers' AR2 coefficients, then their FIR coefficients.
+const RESAMPLER_3_4_COEFS: [i16; 2 + 3 * DOWN_ORDER_FIR0 / 2] = [
+    -20694, -13867, -49, 64, 17, -157, 353, -496, 163, 11047, 22205, -39, 6, 91, -170, 186, 23,
+    -896, 6336, 19928, -19, -36, 102, -89, -24, 328, -951, 2568, 15909,
+];
+const RESAMPLER_2_3_COEFS: [i16; 2 + 2 * DOWN_ORDER_FIR0 / 2] = [
+    -14457, -14019, 64, 128, -122, 36, 310, -768, 584, 9267, 17733, 12, 128, 18, -142, 288, -117,
+    -865, 4123, 14459,
+];
+const RESAMPLER_1_2_COEFS: [i16; 2 + DOWN_ORDER_FIR1 / 2] = [
+    616, -14323, -10, 39, 58, -46, -84, 120, 184, -315, -541, 1284, 5380, 9024,
+];
+const RESAMPLER_1_3_COEFS: [i16; 2 + DOWN_ORDER_FIR2 / 2] = [
+    16102, -15162, -13, 0, 20, 26, 5, -31, -43, -4, 65, 90, 7, -157, -248, -44, 593, 1583, 2612,
+    3271,
+];
+const RESAMPLER_1_4_COEFS: [i16; 2 + DOWN_ORDER_FIR2 / 2] = [
+    22500, -15099, 3, -14, -20, -15, 2, 25, 37, 25, -16, -71, -107, -79, 50, 292, 623, 982, 1288,
+    1464,
+];
+const RESAMPLER_1_6_COEFS: [i16; 2 + DOWN_ORDER_FIR2 / 2] = [
+    27540, -15257, 17, 12, 8, 1, -10, -22, -30, -32, -22, 3, 44, 100, 168, 243, 317, 381, 429, 455,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Copy,
+    Up2Hq,
+    IirFir,
+    DownFir,
+}
+
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    s_iir: [i32; 6],
+    s_fir_i32: [i32; MAX_FIR_ORDER],
+    s_fir_i16: [i16; MAX_FIR_ORDER],
+    delay_buf: [i16; 48],
+    function: Function,
+    batch_size: usize,
+    inv_ratio_q16: i32,
+    fir_order: usize,
+    fir_fracs: i32,
+    fs_in_khz: usize,
+    fs_out_khz: usize,
+    input_delay: usize,
+    coefs: &'static [i16],
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self {
+            s_iir: [0; 6],
+            s_fir_i32: [0; MAX_FIR_ORDER],
+            s_fir_i16: [0; MAX_FIR_ORDER],
+            delay_buf: [0; 48],
+            function: Function::Copy,
+            batch_size: 0,
+            inv_ratio_q16: 0,
+            fir_order: 0,
+            fir_fracs: 0,
+            fs_in_khz: 0,
+            fs_out_khz: 0,
+            input_delay: 0,
+            coefs: &[],
+        }
+    }
+}
+
+/// The index of 8, 12, 16, 24 or 48 kHz in `DELAY_MATRIX_DEC`.
+fn rate_id(rate: i32) -> usize {
+    ((((rate >> 12) - (rate > 16000) as i32) >> (rate > 24000) as i32) - 1) as usize
+}
+
+impl Resampler {
+    /// A resampler from `fs_hz_in`, 8, 12 or 16 kHz, to `fs_hz_out`, which
+    /// may also be 24 or 48 kHz.
+    pub fn new(fs_hz_in: i32, fs_hz_out: i32) -> Self {
+        let mut s = Self {
+            input_delay: DELAY_MATRIX_DEC[rate_id(fs_hz_in)][rate_id(fs_hz_out)],
+            fs_in_khz: (fs_hz_in / 1000) as usize,
+            fs_out_khz: (fs_hz_out / 1000) as usize,
+            ..Self::default()
+        };
+        s.batch_size = s.fs_in_khz * MAX_BATCH_SIZE_MS;
+
+        let mut up2x = 0;
+        if fs_hz_out > fs_hz_in {
+            if fs_hz_out == fs_hz_in * 2 {
+                s.function = Function::Up2Hq;
+            } else {
+                s.function = Function::IirFir;
+                up2x = 1;
+            }
+        } else if fs_hz_out < fs_hz_in {
+            s.function = Function::DownFir;
+            (s.fir_fracs, s.fir_order, s.coefs) = if fs_hz_out * 4 == fs_hz_in * 3 {
+                (3, DOWN_ORDER_FIR0, &RESAMPLER_3_4_COEFS[..])
+            } else if fs_hz_out * 3 == fs_hz_in * 2 {
+                (2, DOWN_ORDER_FIR0, &RESAMPLER_2_3_COEFS[..])
+            } else if fs_hz_out * 2 == fs_hz_in {
+                (1, DOWN_ORDER_FIR1, &RESAMPLER_1_2_COEFS[..])
+            } else if fs_hz_out * 3 == fs_hz_in {
+                (1, DOWN_ORDER_FIR2, &RESAMPLER_1_3_COEFS[..])
+            } else if fs_hz_out * 4 == fs_hz_in {
+                (1, DOWN_ORDER_FIR2, &RESAMPLER_1_4_COEFS[..])
+            } else {
+                (1, DOWN_ORDER_FIR2, &RESAMPLER_1_6_COEFS[..])
+            };
+        }
+
+        // The ratio of input to output samples, rounded up.
+        s.inv_ratio_q16 = ((fs_hz_in << (14 + up2x)) / fs_hz_out) << 2;
+        while smulww(s.inv_ratio_q16, fs_hz_out) < fs_hz_in << up2x {
+            s.inv_ratio_q16 += 1;
+        }
+        s
+    }
+
+    /// Resamples `input`, at least 1 ms of it, into `out`.
+    pub fn resample(&mut self, out: &mut [i16], input: &[i16]) {
+        let n_samples = self.fs_in_khz - self.input_delay;
+        let delay = self.input_delay;
+        self.delay_buf[delay..self.fs_in_khz].copy_from_slice(&input[..n_samples]);
+        let delay_buf = self.delay_buf;
+        let (head, tail) = out.split_at_mut(self.fs_out_khz);
+        let rest = &input[n_samples..input.len() - delay];
+        match self.function {
+            Function::Up2Hq => {
+                up2_hq(&mut self.s_iir, head, &delay_buf[..self.fs_in_khz]);
+                up2_hq(&mut self.s_iir, tail, rest);
+            }
+            Function::IirFir => {
+                self.iir_fir(head, &delay_buf[..self.fs_in_khz]);
+                self.iir_fir(tail, rest);
+            }
+            Function::DownFir => {
+                self.down_fir(head, &delay_buf[..self.fs_in_khz]);
+                self.down_fir(tail, rest);
+            }
+            Function::Copy => {
+                head.copy_from_slice(&delay_buf[..self.fs_in_khz]);
+                tail[..rest.len()].copy_from_slice(rest);
+            }
+        }
+        self.delay_buf[..delay].copy_from_slice(&input[input.len() - delay..]);
+    }
+
+    /// Upsamples 2x with allpass filters, then interpolates from that.
+    fn iir_fir(&mut self, out: &mut [i16], mut input: &[i16]) {
+        let mut buf = [0i16; 2 * MAX_BATCH_SIZE_MS * 16 + ORDER_FIR_12];
+        buf[..ORDER_FIR_12].copy_from_slice(&self.s_fir_i16[..ORDER_FIR_12]);
+        let mut out_ix = 0;
+        let mut n_samples_in;
+        loop {
+            n_samples_in = input.len().min(self.batch_size);
+            up2_hq(
+                &mut self.s_iir,
+                &mut buf[ORDER_FIR_12..ORDER_FIR_12 + 2 * n_samples_in],
+                &input[..n_samples_in],
+            );
+            let max_index_q16 = (n_samples_in as i32) << (16 + 1);
+            let mut index_q16 = 0;
+            while index_q16 < max_index_q16 {
+                let table_index = smulwb(index_q16 & 0xffff, 12) as usize;
+                let buf_ptr = &buf[(index_q16 >> 16) as usize..];
+                let fir = &FRAC_FIR_12[table_index];
+                let fir_rev = &FRAC_FIR_12[11 - table_index];
+                let mut res_q15 = smulbb(buf_ptr[0] as i32, fir[0] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[1] as i32, fir[1] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[2] as i32, fir[2] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[3] as i32, fir[3] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[4] as i32, fir_rev[3] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[5] as i32, fir_rev[2] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[6] as i32, fir_rev[1] as i32);
+                res_q15 = smlabb(res_q15, buf_ptr[7] as i32, fir_rev[0] as i32);
+                out[out_ix] = sat16(rshift_round(res_q15, 15));
+                out_ix += 1;
+                index_q16 += self.inv_ratio_q16;
+            }
+            input = &input[n_samples_in..];
+            if input.is_empty() {
+                break;
+            }
+            buf.copy_within(n_samples_in << 1..(n_samples_in << 1) + ORDER_FIR_12, 0);
+        }
+        self.s_fir_i16[..ORDER_FIR_12]
+            .copy_from_slice(&buf[n_samples_in << 1..(n_samples_in << 1) + ORDER_FIR_12]);
+    }
+
+    /// Low passes with an AR2 filter, then interpolates from that.
+    fn down_fir(&mut self, out: &mut [i16], mut input: &[i16]) {
+        let order = self.fir_order;
+        let mut buf = [0i32; MAX_BATCH_SIZE_MS * 16 + MAX_FIR_ORDER];
+        buf[..order].copy_from_slice(&self.s_fir_i32[..order]);
+        let fir_coefs = &self.coefs[2..];
+        let mut out_ix = 0;
+        let mut n_samples_in;
+        loop {
+            n_samples_in = input.len().min(self.batch_size);
+            ar2(
+                &mut self.s_iir,
+                &mut buf[order..order + n_samples_in],
+                &input[..n_samples_in],
+                self.coefs,
+            );
+            let max_index_q16 = (n_samples_in as i32) << 16;
+            let mut index_q16 = 0;
+            while index_q16 < max_index_q16 {
+                let buf_ptr = &buf[(index_q16 >> 16) as usize..];
+                let mut res_q6;
+                if order == DOWN_ORDER_FIR0 {
+                    let interpol_ind = smulwb(index_q16 & 0xffff, self.fir_fracs) as usize;
+                    let interpol = &fir_coefs[DOWN_ORDER_FIR0 / 2 * interpol_ind..];
+                    res_q6 = smulwb(buf_ptr[0], interpol[0] as i32);
+                    for i in 1..9 {
+                        res_q6 = smlawb(res_q6, buf_ptr[i], interpol[i] as i32);
+                    }
+                    let interpol = &fir_coefs
+                        [DOWN_ORDER_FIR0 / 2 * (self.fir_fracs as usize - 1 - interpol_ind)..];
+                    for i in 0..9 {
+                        res_q6 = smlawb(res_q6, buf_ptr[17 - i], interpol[i] as i32);
+                    }
+                } else {
+                    // The filter is symmetric.
+                    let sym = |i: usize| buf_ptr[i].wrapping_add(buf_ptr[order - 1 - i]);
+                    res_q6 = smulwb(sym(0), fir_coefs[0] as i32);
+                    for (i, &coef) in fir_coefs.iter().enumerate().take(order / 2).skip(1) {
+                        res_q6 = smlawb(res_q6, sym(i), coef as i32);
+                    }
+                }
+                out[out_ix] = sat16(rshift_round(res_q6, 6));
+                out_ix += 1;
+                index_q16 += self.inv_ratio_q16;
+            }
+            input = &input[n_samples_in..];
+            if input.len() <= 1 {
+                break;
+            }
+            buf.copy_within(n_samples_in..n_samples_in + order, 0);
+        }
+        self.s_fir_i32[..order].copy_from_slice(&buf[n_samples_in..n_samples_in + order]);
+    }
+}
+
+/// Upsamples `input` 2x into `out` with two chains of allpass filters, one
+/// for the even samples and one for the odd.
+fn up2_hq(s: &mut [i32; 6], out: &mut [i16], input: &[i16]) {
+    let allpass = |s: &mut [i32], x: i32, coefs: &[i32; 3]| {
+        let mut x = x;
+        for (k, &coef) in coefs.iter().enumerate() {
+            let y = x.wrapping_sub(s[k]);
+            let delta = if k == 2 {
+                smlawb(y, y, coef)
+            } else {
+                smulwb(y, coef)
+            };
+            let out = s[k].wrapping_add(delta);
+            s[k] = x.wrapping_add(delta);
+            x = out;
+        }
+        sat16(rshift_round(x, 10))
+    };
+    for (k, &x) in input.iter().enumerate() {
+        let in32 = (x as i32) << 10;
+        out[2 * k] = allpass(&mut s[..3], in32, &UP2_HQ_0);
+        out[2 * k + 1] = allpass(&mut s[3..], in32, &UP2_HQ_1);
+    }
+}
+
+/// A second order AR filter, its output in Q8.
+fn ar2(s: &mut [i32; 6], out_q8: &mut [i32], input: &[i16], a_q14: &[i16]) {
+    for (out, &x) in out_q8.iter_mut().zip(input) {
+        let out32 = s[0].wrapping_add((x as i32) << 8);
+        *out = out32;
+        let out32 = out32.wrapping_shl(2);
+        s[0] = smlawb(s[1], out32, a_q14[0] as i32);
+        s[1] = smulwb(out32, a_q14[1] as i32);
+    }
+}
@@ -0,0 +1,104 @@
+//! SILK's mid/side stereo, RFC 6716 sections 4.2.7.1 and 4.2.8.
+
+use super::math::{rshift_round, sat16, smlabb, smlawb, smulbb, smulwb};
+use super::tables::{
+    STEREO_ONLY_CODE_MID_ICDF, STEREO_PRED_JOINT_ICDF, STEREO_PRED_QUANT_Q13, UNIFORM3_ICDF,
+    UNIFORM5_ICDF,
+};
+use crate::opus::range_decoder::RangeDecoder;
+
+/// The time the predictors are interpolated over after they change.
+const STEREO_INTERP_LEN_MS: usize = 8;
+/// 0.5 / 5 in Q16, the sub step each predictor index picks.
+const STEREO_QUANT_SUB_STEP_Q16: i32 = 6554;
+
+/// What carries over between frames of a stereo stream.
+#[derive(Debug, Default, Clone)]
+pub struct StereoState {
+    pub pred_prev_q13: [i16; 2],
+    pub s_mid: [i16; 2],
+    pub s_side: [i16; 2],
+}
+
+/// The predictors of the side channel from the mid channel, the first
+/// made relative to the second.
+pub fn decode_pred(dec: &mut RangeDecoder) -> [i32; 2] {
+    let n = dec.decode_icdf(&STEREO_PRED_JOINT_ICDF, 8);
+    let mut ix = [[0; 3]; 2];
+    ix[0][2] = n / 5;
+    ix[1][2] = n - 5 * ix[0][2];
+    for ix in &mut ix {
+        ix[0] = dec.decode_icdf(&UNIFORM3_ICDF, 8);
+        ix[1] = dec.decode_icdf(&UNIFORM5_ICDF, 8);
+    }
+
+    let mut pred_q13 = [0; 2];
+    for (pred_q13, ix) in pred_q13.iter_mut().zip(&ix) {
+        let i = ix[0] + 3 * ix[2];
+        let low_q13 = STEREO_PRED_QUANT_Q13[i] as i32;
+        let step_q13 = smulwb(
+            STEREO_PRED_QUANT_Q13[i + 1] as i32 - low_q13,
+            STEREO_QUANT_SUB_STEP_Q16,
+        );
+        *pred_q13 = smlabb(low_q13, step_q13, 2 * ix[1] as i32 + 1);
+    }
+    pred_q13[0] -= pred_q13[1];
+    pred_q13
+}
+
+/// Whether the frame codes only the mid channel.
+pub fn decode_mid_only(dec: &mut RangeDecoder) -> bool {
+    dec.decode_icdf(&STEREO_ONLY_CODE_MID_ICDF, 8) != 0
+}
+
+/// Turns the mid and side channels `x1` and `x2` into left and right.
+///
+/// Both start with 2 samples of room for the end of the last frame, the
+/// side channel's prediction looking one sample ahead of the mid's.
+pub fn ms_to_lr(
+    state: &mut StereoState,
+    x1: &mut [i16],
+    x2: &mut [i16],
+    pred_q13: [i32; 2],
+    fs_khz: usize,
+    frame_length: usize,
+) {
+    x1[..2].copy_from_slice(&state.s_mid);
+    x2[..2].copy_from_slice(&state.s_side);
+    state
+        .s_mid
+        .copy_from_slice(&x1[frame_length..frame_length + 2]);
+    state
+        .s_side
+        .copy_from_slice(&x2[frame_length..frame_length + 2]);
+
+    let predict = |x1: &[i16], x2: &mut [i16], n: usize, pred0_q13: i32, pred1_q13: i32| {
+        let mut sum = ((x1[n] as i32 + x1[n + 2] as i32) + ((x1[n + 1] as i32) << 1)) << 9;
+        sum = smlawb((x2[n + 1] as i32) << 8, sum, pred0_q13);
+        sum = smlawb(sum, (x1[n + 1] as i32) << 11, pred1_q13);
+        x2[n + 1] = sat16(rshift_round(sum, 8));
+    };
+
+    // Interpolates from the last frame's predictors.
+    let mut pred0_q13 = state.pred_prev_q13[0] as i32;
+    let mut pred1_q13 = state.pred_prev_q13[1] as i32;
+    let interp_len = STEREO_INTERP_LEN_MS * fs_khz;
+    let denom_q16 = (1 << 16) / interp_len as i32;
+    let delta0_q13 = rshift_round(smulbb(pred_q13[0] - pred0_q13, denom_q16), 16);
+    let delta1_q13 = rshift_round(smulbb(pred_q13[1] - pred1_q13, denom_q16), 16);
+    for n in 0..interp_len {
+        pred0_q13 += delta0_q13;
+        pred1_q13 += delta1_q13;
+        predict(x1, x2, n, pred0_q13, pred1_q13);
+    }
+    for n in interp_len..frame_length {
+        predict(x1, x2, n, pred_q13[0], pred_q13[1]);
+    }
+    state.pred_prev_q13 = pred_q13.map(|pred| pred as i16);
+
+    for n in 1..=frame_length {
+        let (mid, side) = (x1[n] as i32, x2[n] as i32);
+        x1[n] = sat16(mid + side);
+        x2[n] = sat16(mid - side);
+    }
+}
@@ -0,0 +1,111 @@
+use js_sys::{Atomics, Float32Array, Int32Array, Object, Reflect, SharedArrayBuffer};
+use wasm_bindgen::JsValue;
+
+/// Slots of the ring's state, shared with `web/playback-worklet.js`. The read
+/// index is the worklet's to move, the write index ours.
+const READ_INDEX: u32 = 0;
+const WRITE_INDEX: u32 = 1;
+/// Times the worklet ran out of audio.
+const UNDERRUNS: u32 = 2;
+/// Holds an f32: ring frames the worklet plays per output frame.
+const RATE: u32 = 3;
+/// Frames queued before the worklet starts playing, and resumes after running
+/// dry.
+const PREBUFFER: u32 = 4;
+const STATE_SLOTS: u32 = 5;
+/// Frames are stereo, mono audio is written to both channels.
+const CHANNELS: u32 = 2;
+
+/// Decoded audio on its way to the AudioWorklet playing it, as interleaved
+/// frames in shared memory the worklet pulls from on the audio thread. One
+/// frame is always left free, so a full ring can't look empty.
+pub struct RingBuffer {
+    samples: Float32Array,
+    state: Int32Array,
+    rate: Float32Array,
+    capacity: u32,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: u32, prebuffer: u32) -> Self {
+        let samples = SharedArrayBuffer::new(capacity * CHANNELS * 4);
+        let state = SharedArrayBuffer::new(STATE_SLOTS * 4);
+        let ring = Self {
+            samples: Float32Array::new(&samples),
+            state: Int32Array::new(&state),
+            rate: Float32Array::new(&state),
+            capacity,
+        };
+        ring.set_rate(1.0);
+        ring.set_prebuffer(prebuffer);
+        ring
+    }
+
+    /// The options the worklet's processor is constructed with.
+    pub fn processor_options(&self) -> Result<Object, JsValue> {
+        let options = Object::new();
+        Reflect::set(&options, &"samples".into(), &self.samples.buffer())?;
+        Reflect::set(&options, &"state".into(), &self.state.buffer())?;
+        Ok(options)
+    }
+
+    fn load(&self, slot: u32) -> u32 {
+        Atomics::load(&self.state, slot).expect("Ring state is an Int32Array") as u32
+    }
+
+    /// Frames waiting to be played.
+    pub fn depth(&self) -> u32 {
+        (self.load(WRITE_INDEX) + self.capacity - self.load(READ_INDEX)) % self.capacity
+    }
+
+    pub fn underruns(&self) -> u32 {
+        self.load(UNDERRUNS)
+    }
+
+    pub fn set_rate(&self, rate: f32) {
+        self.rate.set_index(RATE, rate);
+    }
+
+    pub fn prebuffer(&self) -> u32 {
+        self.load(PREBUFFER)
+    }
+
+    pub fn set_prebuffer(&self, frames: u32) {
+        Atomics::store(&self.state, PREBUFFER, frames.min(self.capacity / 2) as i32)
+            .expect("Ring state is an Int32Array");
+    }
+
+    /// Queues the frames of `planes`, one per channel, unless there's no room
+    /// for them.
+    pub fn push(&self, planes: &[Vec<f32>]) -> bool {
+        let frames = planes.first().map_or(0, Vec::len) as u32;
+        if frames == 0 {
+            return true;
+        }
+        if self.depth() + frames >= self.capacity {
+            return false;
+        }
+        let interleaved: Vec<f32> = (0..frames as usize)
+            .flat_map(|frame| {
+                (0..CHANNELS as usize)
+                    .map(move |channel| planes[channel.min(planes.len() - 1)][frame])
+            })
+            .collect();
+        let write = self.load(WRITE_INDEX);
+        let until_end = (self.capacity - write).min(frames);
+        let (first, rest) = interleaved.split_at((until_end * CHANNELS) as usize);
+        self.samples
+            .subarray(write * CHANNELS, (write + until_end) * CHANNELS)
+            .copy_from(first);
+        if !rest.is_empty() {
+            self.samples.subarray(0, rest.len() as u32).copy_from(rest);
+        }
+        Atomics::store(
+            &self.state,
+            WRITE_INDEX,
+            ((write + frames) % self.capacity) as i32,
+        )
+        .expect("Ring state is an Int32Array");
+        true
+    }
+}
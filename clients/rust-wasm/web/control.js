@@ -0,0 +1,60 @@
+// Administers the server through /api/control with the access key from the
+// page's `?key=<key>`.
+const key = new URLSearchParams(window.location.search).get("key") ?? "";
+const statusElement = document.getElementById("status");
+const bitrateElement = document.getElementById("bitrate");
+const sourceElement = document.getElementById("source");
+
+function showState(state) {
+    const bitrate = state.bitrate === null ? "automatic bitrate" : `${state.bitrate / 1000} kbit/s`;
+    const source = state.capture_node ?? "the virtual sink";
+    statusElement.textContent = `${state.paused ? "Paused" : "Streaming"}, ${bitrate}, capturing ${source}`;
+    bitrateElement.value = state.bitrate === null ? "" : state.bitrate / 1000;
+    sourceElement.value = state.capture_node ?? "";
+    sourceElement.disabled = !state.capture_switchable;
+    document.getElementById("sourceButton").disabled = !state.capture_switchable;
+}
+
+// Sends a control request, showing the state the server answers with.
+async function control(method, path, body) {
+    const options = { method };
+    if (body !== undefined) {
+        options.headers = { "Content-Type": "application/json" };
+        options.body = JSON.stringify(body);
+    }
+    const response = await fetch(`/api/control/${path}?key=${encodeURIComponent(key)}`, options);
+    if (!response.ok) {
+        statusElement.textContent = `Server refused: ${await response.text()}`;
+        return;
+    }
+    showState(await response.json());
+}
+
+async function load() {
+    const nodes = await (await fetch("/api/nodes")).json();
+    const virtualSink = document.createElement("option");
+    virtualSink.value = "";
+    virtualSink.textContent = "Virtual sink";
+    const options = nodes.map((node) => {
+        const option = document.createElement("option");
+        option.value = node.name;
+        option.textContent = `${node.description} (${node.media_class})`;
+        return option;
+    });
+    sourceElement.replaceChildren(virtualSink, ...options);
+    showState(await (await fetch("/api/control")).json());
+}
+
+document.getElementById("pauseButton").onclick = () => control("POST", "pause");
+document.getElementById("resumeButton").onclick = () => control("POST", "resume");
+document.getElementById("restartButton").onclick = () => control("POST", "restart");
+document.getElementById("bitrateButton").onclick = () => {
+    const kbps = bitrateElement.value;
+    control("PUT", "bitrate", { bitrate: kbps === "" ? null : Math.round(kbps * 1000) });
+};
+document.getElementById("sourceButton").onclick = () => {
+    control("PUT", "capture-node", { node: sourceElement.value === "" ? null : sourceElement.value });
+};
+load().catch((e) => {
+    statusElement.textContent = `Couldn't load the server's state: ${e}`;
+});
@@ -0,0 +1,91 @@
+// Lists the server's clients as it reports them at /api/sessions/events, and
+// kicks them, with the access key from the page's `?key=<key>`.
+const key = new URLSearchParams(window.location.search).get("key") ?? "";
+const statusElement = document.getElementById("status");
+const sessionsElement = document.getElementById("sessions");
+
+// Each client's bytes sent and duration at its previous update, and its
+// bitrate since the one before, as `{ bytes_sent, duration_secs, kbps }`.
+let previous = new Map();
+
+function formatDuration(secs) {
+    const minutes = Math.floor(secs / 60);
+    return minutes > 0 ? `${minutes} min ${Math.floor(secs % 60)} s` : `${secs.toFixed(0)} s`;
+}
+
+function bitrateKbps(session) {
+    const last = previous.get(session.id);
+    if (!last) {
+        return null;
+    }
+    // Other clients' updates repeat this one's numbers.
+    if (session.duration_secs <= last.duration_secs) {
+        return last.kbps;
+    }
+    const bits = (session.bytes_sent - last.bytes_sent) * 8;
+    return bits / (session.duration_secs - last.duration_secs) / 1000;
+}
+
+// Share of its packets the client reports lost, and its jitter.
+function receptionCells(report) {
+    if (report === null) {
+        return ["", ""];
+    }
+    const expected = report.packets_received + report.packets_lost;
+    const loss = expected > 0 ? report.packets_lost / expected * 100 : 0;
+    return [`${loss.toFixed(1)} %`, `${report.jitter_ms.toFixed(1)} ms`];
+}
+
+async function kick(id) {
+    const response = await fetch(`/api/sessions/${id}/kick?key=${encodeURIComponent(key)}`, { method: "POST" });
+    if (!response.ok) {
+        statusElement.textContent = `Couldn't kick client ${id}: ${await response.text()}`;
+    }
+}
+
+function showSessions(sessions) {
+    const bitrates = sessions.map(bitrateKbps);
+    const rows = sessions.map((session, index) => {
+        const bitrate = bitrates[index];
+        const cells = [
+            session.id,
+            session.address,
+            session.sink,
+            session.codec,
+            session.tier,
+            bitrate === null ? "" : `${bitrate.toFixed(0)} kbit/s`,
+            `${session.rtt_ms.toFixed(1)} ms`,
+            session.target_latency_ms === null ? "" : `${session.target_latency_ms} ms`,
+            session.lag_events,
+            session.missed_packets,
+            ...receptionCells(session.receiver_report),
+            formatDuration(session.duration_secs),
+        ].map((text) => {
+            const cell = document.createElement("td");
+            cell.textContent = text;
+            return cell;
+        });
+        const button = document.createElement("button");
+        button.textContent = "Kick";
+        button.onclick = () => kick(session.id);
+        const buttonCell = document.createElement("td");
+        buttonCell.append(button);
+        const row = document.createElement("tr");
+        row.append(...cells, buttonCell);
+        return row;
+    });
+    sessionsElement.replaceChildren(...rows);
+    previous = new Map(sessions.map((session, index) => [session.id, {
+        bytes_sent: session.bytes_sent,
+        duration_secs: session.duration_secs,
+        kbps: bitrates[index],
+    }]));
+    statusElement.textContent = `${sessions.length} connected`;
+}
+
+const events = new EventSource(`/api/sessions/events?key=${encodeURIComponent(key)}`);
+events.onmessage = (event) => showSessions(JSON.parse(event.data));
+// EventSource reconnects by itself.
+events.onerror = () => {
+    statusElement.textContent = "Lost the server, reconnecting...";
+};
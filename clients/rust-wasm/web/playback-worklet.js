@@ -0,0 +1,61 @@
+// Plays the decoded audio the WASM client writes into a ring buffer in shared
+// memory, see `src/ring_buffer.rs` for its layout.
+const READ_INDEX = 0;
+const WRITE_INDEX = 1;
+const UNDERRUNS = 2;
+const RATE = 3;
+const PREBUFFER = 4;
+const CHANNELS = 2;
+
+class RingBufferPlayer extends AudioWorkletProcessor {
+    constructor({ processorOptions }) {
+        super();
+        this.samples = new Float32Array(processorOptions.samples);
+        this.state = new Int32Array(processorOptions.state);
+        this.rate = new Float32Array(processorOptions.state);
+        this.capacity = this.samples.length / CHANNELS;
+        // Set until the prebuffer is queued, at the start and after an
+        // underrun.
+        this.buffering = true;
+        // Position between the frame at the read index and the next one.
+        this.phase = 0;
+    }
+
+    process(inputs, outputs) {
+        const [left, right] = outputs[0];
+        left.fill(0);
+        right.fill(0);
+        let read = Atomics.load(this.state, READ_INDEX);
+        const write = Atomics.load(this.state, WRITE_INDEX);
+        let available = (write - read + this.capacity) % this.capacity;
+        if (this.buffering) {
+            if (available < Atomics.load(this.state, PREBUFFER)) {
+                return true;
+            }
+            this.buffering = false;
+        }
+        // Ring frames per output frame, which absorbs the stream's sample rate
+        // and clock drift.
+        const step = this.rate[RATE];
+        for (let i = 0; i < left.length; i++) {
+            if (available < 2) {
+                Atomics.add(this.state, UNDERRUNS, 1);
+                this.buffering = true;
+                break;
+            }
+            const current = read * CHANNELS;
+            const next = ((read + 1) % this.capacity) * CHANNELS;
+            left[i] = this.samples[current] + (this.samples[next] - this.samples[current]) * this.phase;
+            right[i] = this.samples[current + 1] + (this.samples[next + 1] - this.samples[current + 1]) * this.phase;
+            this.phase += step;
+            const whole = Math.min(Math.floor(this.phase), available);
+            this.phase -= whole;
+            read = (read + whole) % this.capacity;
+            available -= whole;
+        }
+        Atomics.store(this.state, READ_INDEX, read);
+        return true;
+    }
+}
+
+registerProcessor("ring-buffer-player", RingBufferPlayer);
@@ -1,25 +1,75 @@
-const SERVER_CERT_HASH_BYTES = [
-    13, 168, 113, 2, 213, 136, 124, 10, 80, 208, 200, 56, 29, 68, 119, 16,
-    194, 119, 112, 219, 4, 102, 187, 137, 91, 248, 119, 10, 167, 127, 119, 240,
-];
+// Defaults from the streaming-protocol crate.
 const SAMPLE_RATE = 48000;
 const NUMBER_OF_CHANNELS = 1;
-const FRAME_DURATION_MS = 10;
+// First byte of each stream the server opens, saying what it carries.
+const STREAM_CONTROL = 0;
+const STREAM_MEDIA = 1;
+// Packet framing on the media stream, see streaming-protocol/src/lib.rs.
+const PACKET_MAGIC = 0x5057; // "PW"
+const PROTOCOL_VERSION = 5;
+const PACKET_HEADER_LEN = 20;
+// Set in the codec byte when the decoder must start afresh at the packet.
+const DECODER_RESET_FLAG = 0x80;
+const CODEC_OPUS = 0;
+const CODEC_FLAC = 1;
+const CODEC_PCM = 2;
+const CODEC_AAC = 3;
+// Stands in for a frame of digital silence in any codec: a channel count byte
+// and the samples per channel as a big-endian u32.
+const CODEC_SILENCE = 4;
+// Samples per channel in the longest frame, 120 ms, at the highest rate, 192
+// kHz, which no packet exceeds.
+const MAX_FRAME_SAMPLES = 192 * 120;
+// Codecs this client can play if the browser decodes them, in the order it
+// prefers them. PCM needs no decoder.
+const PREFERRED_CODECS = [
+    [CODEC_OPUS, 'opus'],
+    [CODEC_AAC, 'aac'],
+    [CODEC_FLAC, 'flac'],
+    [CODEC_PCM, 'pcm'],
+];
+const LATENCY_REPORT_INTERVAL = 100;
+// Silence packets held back beyond this many are played regardless, in case
+// the decoder swallowed a frame rather than output it.
+const MAX_PENDING_SILENCE = 50;
 
 let audioContext = null;
 let audioDecoder = null;
+// Format of the stream, from the server's stream info.
+let streamSampleRate = SAMPLE_RATE;
+let streamChannels = NUMBER_OF_CHANNELS;
+// Codec and channel count the decoder is configured for, e.g. "1/2".
+let decoderFormat = null;
 let nextPlayTime = 0.0;
 let receivedChunkCount = 0;
+let decodedChunkCount = 0;
+// Packets handed to the decoder and not yet output.
+let decodesInFlight = 0;
+// Silence packets waiting for the audio being decoded ahead of them.
+let pendingSilence = [];
 let transport = null;
+// The server's password, once it let a session start with it.
+let password = null;
 
 const connectButton = document.getElementById('connectButton');
 const statusElement = document.getElementById('status');
+const latencyElement = document.getElementById('latency');
+const nowPlayingElement = document.getElementById('now-playing');
 
 function updateStatus(message) {
     console.log(message);
     statusElement.textContent = message;
 }
 
+// Shows the track the sink plays, or nothing when no player is playing.
+function showNowPlaying({ title, artist }) {
+    if (!title) {
+        nowPlayingElement.textContent = '';
+    } else {
+        nowPlayingElement.textContent = artist ? `Now playing: ${title} by ${artist}` : `Now playing: ${title}`;
+    }
+}
+
 async function initAudio() {
     try {
         audioContext = new AudioContext({ sampleRate: SAMPLE_RATE });
@@ -36,14 +86,11 @@ async function initAudio() {
                 updateStatus(`Decoder Error: ${e.message}`);
             },
         });
+        decoderFormat = null;
 
-        audioDecoder.configure({
-            codec: 'opus',
-            sampleRate: SAMPLE_RATE,
-            numberOfChannels: NUMBER_OF_CHANNELS,
-        });
-        
         nextPlayTime = audioContext.currentTime;
+        decodesInFlight = 0;
+        pendingSilence = [];
         receivedChunkCount = 0;
         updateStatus("Audio initialized.");
     } catch (e) {
@@ -53,6 +100,82 @@ async function initAudio() {
     }
 }
 
+// FLAC decoders need a stream header, which the server leaves out of its
+// packets. Mirrors flac_stream_header in streaming-protocol/src/lib.rs.
+function flacStreamHeader(sampleRate, channels) {
+    if (!(sampleRate >= 1 && sampleRate <= 655350 && channels >= 1 && channels <= 8)) {
+        throw new Error(`FLAC doesn't support ${channels} channel(s) at ${sampleRate} Hz`);
+    }
+    const header = new Uint8Array(42);
+    // "fLaC", then the last metadata block: a 34 byte STREAMINFO.
+    header.set([0x66, 0x4c, 0x61, 0x43, 0x80, 0, 0, 34]);
+    const view = new DataView(header.buffer);
+    view.setUint16(8, 16);
+    view.setUint16(10, 0xffff);
+    // 20 bits of sample rate, 3 of channels - 1 and 5 of bits per sample - 1,
+    // followed by an unknown sample count and MD5.
+    view.setUint32(18, (sampleRate << 12) | ((channels - 1) << 9) | (15 << 4));
+    return header;
+}
+
+// AAC decoders need the AudioSpecificConfig of the raw frames the server
+// sends. Mirrors aac_audio_specific_config in streaming-protocol/src/lib.rs.
+function aacAudioSpecificConfig(sampleRate, channels) {
+    const rateIndex = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350]
+        .indexOf(sampleRate);
+    // Object type 2 (low complexity), rate index and channel configuration.
+    const packed = (2 << 11) | (rateIndex << 7) | (channels << 3);
+    return new Uint8Array([packed >> 8, packed & 0xff]);
+}
+
+function decoderConfig(codec, channels) {
+    const config = { codec: 'opus', sampleRate: streamSampleRate, numberOfChannels: channels };
+    if (codec === CODEC_FLAC) {
+        config.codec = 'flac';
+        config.description = flacStreamHeader(streamSampleRate, channels);
+    } else if (codec === CODEC_AAC) {
+        config.codec = 'mp4a.40.2';
+        config.description = aacAudioSpecificConfig(streamSampleRate, channels);
+    }
+    return config;
+}
+
+// Names of the preferred codecs the browser can decode, for the session path.
+// Safari, for one, often lacks Opus.
+async function decodableCodecs() {
+    const names = [];
+    for (const [codec, name] of PREFERRED_CODECS) {
+        if (codec === CODEC_PCM || (await AudioDecoder.isConfigSupported(decoderConfig(codec, 2))).supported) {
+            names.push(name);
+        }
+    }
+    return names;
+}
+
+// Configures the decoder for codec with channels, unless it already is.
+function configureDecoder(codec, channels) {
+    const format = `${codec}/${channels}`;
+    if (format === decoderFormat) {
+        return;
+    }
+    decoderFormat = format;
+    audioDecoder.configure(decoderConfig(codec, channels));
+}
+
+// Reconfigures the decoder when a packet's codec, or the channel count in its
+// FLAC or AAC frame header, differs from the stream info's.
+function configureDecoderFor(codec, payload) {
+    const assignment = payload[3] >> 4;
+    let channels = streamChannels;
+    if (codec === CODEC_FLAC) {
+        channels = assignment < 8 ? assignment + 1 : 2;
+    } else if (codec === CODEC_AAC) {
+        // A channel pair element rather than a single channel one.
+        channels = payload[0] >> 5 === 1 ? 2 : 1;
+    }
+    configureDecoder(codec, channels);
+}
+
 function handleDecodedChunk(audioData) {
     if (!audioContext || audioContext.state === 'closed') {
         console.warn("AudioContext closed, cannot play decoded chunk.");
@@ -71,7 +194,66 @@ function handleDecodedChunk(audioData) {
         audioData.copyTo(planeData, { planeIndex: i, frameOffset: 0, frameCount: audioData.numberOfFrames });
         audioBuffer.copyToChannel(planeData, i, 0);
     }
-    
+    const timestampUs = audioData.timestamp;
+    audioData.close();
+    decodesInFlight = Math.max(decodesInFlight - 1, 0);
+    playPendingSilence(capturedAtUs => capturedAtUs < timestampUs);
+    scheduleBuffer(audioBuffer, timestampUs);
+    if (decodesInFlight === 0) {
+        playPendingSilence(() => true);
+    }
+}
+
+// Keeps time through a silence packet, which needs no decoding. Audio still in
+// the decoder plays first.
+function playSilence(capturedAtUs, payload) {
+    const view = new DataView(payload.buffer, payload.byteOffset, payload.length);
+    const frames = view.getUint32(1);
+    if (payload[0] === 0 || frames > MAX_FRAME_SAMPLES) {
+        return;
+    }
+    pendingSilence.push({ capturedAtUs, channels: payload[0], frames });
+    if (decodesInFlight === 0 || pendingSilence.length > MAX_PENDING_SILENCE) {
+        decodesInFlight = 0;
+        playPendingSilence(() => true);
+    }
+}
+
+// Schedules the held back silence packets captured at times due accepts.
+function playPendingSilence(due) {
+    if (!audioContext || audioContext.state === 'closed') {
+        return;
+    }
+    while (pendingSilence.length > 0 && due(pendingSilence[0].capturedAtUs)) {
+        const { capturedAtUs, channels, frames } = pendingSilence.shift();
+        // New buffers are silent.
+        scheduleBuffer(audioContext.createBuffer(channels, frames, streamSampleRate), capturedAtUs);
+    }
+}
+
+// Plays a PCM packet, whose samples need no decoding: a channel count byte
+// followed by interleaved s16le samples.
+function playPcm(capturedAtUs, payload) {
+    if (!audioContext || audioContext.state === 'closed') {
+        return;
+    }
+    const channels = payload[0];
+    const samples = new DataView(payload.buffer, payload.byteOffset + 1, payload.length - 1);
+    const frames = samples.byteLength / 2 / channels;
+    const audioBuffer = audioContext.createBuffer(channels, frames, streamSampleRate);
+    for (let channel = 0; channel < channels; channel++) {
+        const plane = new Float32Array(frames);
+        for (let i = 0; i < frames; i++) {
+            plane[i] = samples.getInt16((i * channels + channel) * 2, true) / 32768;
+        }
+        audioBuffer.copyToChannel(plane, channel, 0);
+    }
+    scheduleBuffer(audioBuffer, capturedAtUs);
+}
+
+// Queues audioBuffer right after the previously scheduled audio, reporting the
+// latency from timestampUs, the server's capture time.
+function scheduleBuffer(audioBuffer, timestampUs) {
     const sourceNode = audioContext.createBufferSource();
     sourceNode.buffer = audioBuffer;
     sourceNode.connect(audioContext.destination);
@@ -81,8 +263,161 @@ function handleDecodedChunk(audioData) {
 
     sourceNode.start(scheduleTime);
     nextPlayTime = scheduleTime + audioBuffer.duration;
-    
-    audioData.close();
+
+    // The chunk timestamp is the server's capture time, so this spans capture
+    // to playback (assuming both clocks are in sync).
+    decodedChunkCount++;
+    if (decodedChunkCount % LATENCY_REPORT_INTERVAL === 0) {
+        const playbackAtMs = Date.now() + (scheduleTime - currentTime) * 1000;
+        const latencyMs = playbackAtMs - timestampUs / 1000;
+        latencyElement.textContent = `End-to-end latency: ${latencyMs.toFixed(0)} ms`;
+    }
+}
+
+// Returns a function that takes the media stream's bytes as they arrive and
+// calls onPacket(codec, capturedAtUs, frameSamples, payload, decoderReset) for
+// every complete packet, in sequence order. See streaming-protocol/src/lib.rs for the header layout.
+function createPacketParser(onPacket) {
+    let pending = new Uint8Array(0);
+    let expectedSequence = null;
+    return (bytes) => {
+        const buffered = new Uint8Array(pending.length + bytes.length);
+        buffered.set(pending);
+        buffered.set(bytes, pending.length);
+        const view = new DataView(buffered.buffer);
+        let offset = 0;
+        while (buffered.length - offset >= PACKET_HEADER_LEN) {
+            if (view.getUint16(offset) !== PACKET_MAGIC || view.getUint8(offset + 2) !== PROTOCOL_VERSION) {
+                throw new Error("Unsupported packet framing from server");
+            }
+            const end = offset + PACKET_HEADER_LEN + view.getUint16(offset + 18);
+            if (end > buffered.length) {
+                break;
+            }
+            const codec = view.getUint8(offset + 3) & ~DECODER_RESET_FLAG;
+            const decoderReset = (view.getUint8(offset + 3) & DECODER_RESET_FLAG) !== 0;
+            const sequence = view.getUint32(offset + 4);
+            const capturedAtUs = Number(view.getBigUint64(offset + 8));
+            const frameSamples = view.getUint16(offset + 16);
+            const payload = buffered.subarray(offset + PACKET_HEADER_LEN, end);
+            offset = end;
+            // Signed 32-bit difference, so sequence numbers can wrap around.
+            const skipped = expectedSequence === null ? 0 : (sequence - expectedSequence) | 0;
+            if (skipped < 0) {
+                console.warn(`Packet ${sequence} arrived late, skipping it.`);
+                continue;
+            }
+            if (skipped > 0) {
+                console.warn(`${skipped} packets lost before packet ${sequence}.`);
+            }
+            expectedSequence = (sequence + 1) >>> 0;
+            onPacket(codec, capturedAtUs, frameSamples, payload, decoderReset);
+        }
+        pending = buffered.slice(offset);
+    };
+}
+
+// Configures playback for the stream the server describes, at the start and
+// again after a restart or a switch to another sink.
+function applyStreamInfo(info) {
+    updateStatus(`Sink ${info.sink}: ${info.codec} at ${info.sample_rate} Hz, ${info.channels} channel(s)`);
+    streamSampleRate = info.sample_rate;
+    streamChannels = info.channels;
+    const codec = PREFERRED_CODECS.find(([, name]) => name === info.codec)?.[0];
+    if (codec !== undefined && codec !== CODEC_PCM) {
+        configureDecoder(codec, streamChannels);
+    }
+}
+
+// Returns a function that takes the control stream's bytes as they arrive and
+// calls onMessage with every complete message, one line of JSON each.
+function createControlParser(onMessage) {
+    const textDecoder = new TextDecoder();
+    let pending = '';
+    return (bytes) => {
+        pending += textDecoder.decode(bytes, { stream: true });
+        let end;
+        while ((end = pending.indexOf('\n')) >= 0) {
+            const line = pending.slice(0, end);
+            pending = pending.slice(end + 1);
+            onMessage(JSON.parse(line));
+        }
+    };
+}
+
+// Reads the next stream the server opens, returning its reader, what it
+// carries and the bytes read after that.
+async function acceptStream(uniStreamsReader) {
+    const { value: stream, done } = await uniStreamsReader.read();
+    if (done) {
+        throw new Error("Server didn't open the control and media streams.");
+    }
+    const reader = stream.getReader();
+    while (true) {
+        const { value, done } = await reader.read();
+        if (done) {
+            throw new Error("Stream closed before saying what it carries.");
+        }
+        if (value && value.byteLength > 0) {
+            return { reader, kind: value[0], rest: value.subarray(1) };
+        }
+    }
+}
+
+// Sends `command` to the server on a control stream of its own.
+async function sendCommand(transport, command) {
+    const stream = await transport.createBidirectionalStream();
+    const writer = stream.writable.getWriter();
+    const line = new TextEncoder().encode(JSON.stringify(command) + "\n");
+    const bytes = new Uint8Array(1 + line.length);
+    bytes[0] = STREAM_CONTROL;
+    bytes.set(line, 1);
+    await writer.write(bytes);
+    await writer.close();
+}
+
+// Proves the password without sending it: the hex HMAC-SHA256 of the server's
+// nonce under the password, see `password_proof` in the streaming-protocol crate.
+async function passwordProof(password, nonce) {
+    const encoder = new TextEncoder();
+    const key = await crypto.subtle.importKey(
+        'raw', encoder.encode(password), { name: 'HMAC', hash: 'SHA-256' }, false, ['sign']);
+    const tag = new Uint8Array(await crypto.subtle.sign('HMAC', key, encoder.encode(nonce)));
+    return Array.from(tag, (byte) => byte.toString(16).padStart(2, '0')).join('');
+}
+
+// Shows the stream state and stats the server sends after the stream info.
+async function readControl(reader, parseControl) {
+    while (true) {
+        const { value, done } = await reader.read();
+        if (done) {
+            break;
+        }
+        parseControl(value);
+    }
+}
+
+// Trades the server's access key for a session token at its HTTP API.
+async function fetchSessionToken(key) {
+    const response = await fetch(`/api/token?key=${encodeURIComponent(key)}`);
+    if (!response.ok) {
+        throw new Error(`Server refused a session token (${response.status})`);
+    }
+    return (await response.json()).token;
+}
+
+// A self-signed server certificate is trusted by its hash, fetched fresh in
+// case the certificate changed. There's none for a Let's Encrypt certificate.
+async function fetchCertHashes() {
+    const response = await fetch("/api/cert-hash");
+    if (response.status === 404) {
+        return [];
+    }
+    if (!response.ok) {
+        throw new Error(`Couldn't fetch the certificate hash (${response.status})`);
+    }
+    const { algorithm, value } = await response.json();
+    return [{ algorithm, value: new Uint8Array(value).buffer }];
 }
 
 async function connectAndReceive() {
@@ -90,29 +425,128 @@ async function connectAndReceive() {
     try {
         await initAudio();
 
-        const serverUrl = `https://${window.location.hostname}:13345`;
+        // `?key=<key>` is the server's access key, in the URL it prints.
+        const pageParams = new URLSearchParams(window.location.search);
+        const sink = pageParams.get("sink") ?? "";
+        const token = await fetchSessionToken(pageParams.get("key") ?? "");
+        const codecs = await decodableCodecs();
+        const serverUrl = `https://${window.location.hostname}:13345/${encodeURIComponent(sink)}?codecs=${codecs.join(',')}&token=${token}`;
         updateStatus(`Connecting to ${serverUrl}...`);
 
-        const serverCertificateHashes = [{
-            algorithm: "sha-256",
-            value: new Uint8Array(SERVER_CERT_HASH_BYTES).buffer
-        }];
+        const serverCertificateHashes = await fetchCertHashes();
 
         transport = new WebTransport(serverUrl, { serverCertificateHashes });
         await transport.ready;
 
         const uniStreamsReader = transport.incomingUnidirectionalStreams.getReader();
-        const { value: stream, done: noStreams } = await uniStreamsReader.read();
-        uniStreamsReader.releaseLock();
+        const streams = {};
+        while (!streams[STREAM_CONTROL]) {
+            const accepted = await acceptStream(uniStreamsReader);
+            streams[accepted.kind] = accepted;
+        }
 
-        if (noStreams || !stream) {
-            updateStatus("Server closed connection or no unidirectional streams were opened.");
-            if(transport) transport.close();
-            return;
+        // The decoder is configured from the stream info, the first control
+        // message, rather than from the first packets. Servers with a password
+        // only send it once the password is proven.
+        const control = streams[STREAM_CONTROL];
+        let streamInfo = null;
+        let challenge = null;
+        let lastTier = 0;
+        const parseControl = createControlParser((message) => {
+            if (!streamInfo) {
+                if (message.type === 'password_challenge') {
+                    challenge = message;
+                    return;
+                }
+                if (message.type !== 'stream_info') {
+                    throw new Error("Server didn't start the control stream with the stream info.");
+                }
+                streamInfo = message;
+                applyStreamInfo(message);
+                return;
+            }
+            if (message.type === 'stream_info') {
+                applyStreamInfo(message);
+            } else if (message.type === 'state') {
+                updateStatus(message.paused ? "Paused by server" : "Streaming (JS)");
+            } else if (message.type === 'idle') {
+                updateStatus(message.idle ? "Idle, the sink is silent" : "Streaming (JS)");
+            } else if (message.type === 'stats' && message.tier !== lastTier) {
+                lastTier = message.tier;
+                console.log(`Moved to bitrate tier ${message.tier} (RTT ${message.rtt_ms.toFixed(0)} ms)`);
+            } else if (message.type === 'now_playing') {
+                showNowPlaying(message);
+            } else if (message.type === 'certificate_renewed') {
+                console.log("Server renewed its certificate");
+            }
+        });
+        parseControl(control.rest);
+        let givenPassword = null;
+        while (!streamInfo) {
+            if (challenge) {
+                // A password the server turned down is asked for again.
+                givenPassword = password ?? window.prompt("The server needs a password:");
+                password = null;
+                if (givenPassword === null) {
+                    throw new Error("No password given.");
+                }
+                const proof = await passwordProof(givenPassword, challenge.nonce);
+                await sendCommand(transport, { type: 'authenticate', proof });
+                challenge = null;
+            }
+            const { value, done } = await control.reader.read();
+            if (done) {
+                throw new Error("Control stream closed before the stream info.");
+            }
+            parseControl(value);
         }
-        updateStatus("Received incoming unidirectional stream. Reading data...");
+        password = givenPassword ?? password;
+        readControl(control.reader, parseControl).catch((e) => console.warn("Control stream reader stopped:", e));
+        while (!streams[STREAM_MEDIA]) {
+            const accepted = await acceptStream(uniStreamsReader);
+            streams[accepted.kind] = accepted;
+        }
+        uniStreamsReader.releaseLock();
 
-        const reader = stream.getReader();
+        const parsePackets = createPacketParser((codec, capturedAtUs, frameSamples, payload, decoderReset) => {
+            receivedChunkCount++;
+            // Configuring the decoder afresh drops the state the server's
+            // previous encoder left.
+            if (decoderReset) {
+                decoderFormat = null;
+            }
+            if (codec === CODEC_PCM) {
+                playPcm(capturedAtUs, payload);
+                return;
+            }
+            if (codec === CODEC_SILENCE) {
+                playSilence(capturedAtUs, payload);
+                return;
+            }
+            if (codec !== CODEC_OPUS && codec !== CODEC_FLAC && codec !== CODEC_AAC) {
+                console.warn(`Packet in unknown codec ${codec}, skipping it.`);
+                return;
+            }
+            configureDecoderFor(codec, payload);
+
+            const chunk = new EncodedAudioChunk({
+                type: 'key',
+                timestamp: capturedAtUs,
+                duration: frameSamples * 1e6 / streamSampleRate,
+                data: payload
+            });
+
+            if (audioDecoder && audioDecoder.state === 'configured') {
+                audioDecoder.decode(chunk);
+                decodesInFlight++;
+            } else {
+                console.warn(`Decoder not configured or null, skipping packet. State: ${audioDecoder?.state}`);
+            }
+        });
+        // This client never asks for datagrams, so the packets always follow
+        // on the media stream.
+        const { reader, rest } = streams[STREAM_MEDIA];
+        parsePackets(rest);
         while (true) {
             const { value, done } = await reader.read();
             if (done) {
@@ -120,21 +554,7 @@ async function connectAndReceive() {
             }
 
             if (value && value.byteLength > 0) {
-                const timestamp = receivedChunkCount * FRAME_DURATION_MS * 1000;
-                receivedChunkCount++;
-
-                const chunk = new EncodedAudioChunk({
-                    type: 'key',
-                    timestamp: timestamp,
-                    duration: FRAME_DURATION_MS * 1000,
-                    data: value
-                });
-                
-                if (audioDecoder && audioDecoder.state === 'configured') {
-                    audioDecoder.decode(chunk);
-                } else {
-                    console.warn(`Decoder not configured or null, skipping packet. State: ${audioDecoder?.state}`);
-                }
+                parsePackets(value);
             }
         }
     } catch (e) {
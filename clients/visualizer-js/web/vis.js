@@ -1,24 +1,53 @@
 (async () => {
-    const serverUrl = `https://${location.hostname}:13345`;
+    // `?key=<key>` is the server's access key, in the URL it prints.
+    const pageParams = new URLSearchParams(location.search);
+    const sink = pageParams.get("sink") ?? "";
+    const accessKey = pageParams.get("key") ?? "";
+    // Defaults from the streaming-protocol crate.
     const sampleRate = 48000;
     const numberOfChannels = 1;
-    const frameDurationMs = 5;
+    // First byte of each stream the server opens, saying what it carries.
+    const streamControl = 0;
+    const streamMedia = 1;
+    // Packet framing on the media stream, see streaming-protocol/src/lib.rs.
+    const packetMagic = 0x5057; // "PW"
+    const protocolVersion = 5;
+    const packetHeaderLen = 20;
+    // Set in the codec byte when the decoder must start afresh at the packet.
+    const decoderResetFlag = 0x80;
+    const codecOpus = 0;
+    const codecFlac = 1;
+    const codecPcm = 2;
+    const codecAac = 3;
+    // Stands in for a frame of digital silence in any codec.
+    const codecSilence = 4;
+    // Codecs this client can show if the browser decodes them, in the order it
+    // prefers them. PCM needs no decoder.
+    const preferredCodecs = [[codecOpus, "opus"], [codecAac, "aac"], [codecFlac, "flac"], [codecPcm, "pcm"]];
+    const latencyReportInterval = 100;
 
     let audioContext;
     let audioDecoder;
+    // Format of the stream, from the server's stream info.
+    let streamSampleRate = sampleRate;
+    let streamChannels = numberOfChannels;
+    // Codec and channel count the decoder is configured for, e.g. "1/2".
+    let decoderFormat = null;
     let transport;
+    // The server's password, once it let a session start with it.
+    let password = null;
     let connected = false;
     let analyser;
     let dataArray;
     let bufferLength;
+    let decodedChunkCount = 0;
 
     const statusDisplay = document.getElementById('status');
+    const latencyDisplay = document.getElementById('latency');
     const connectButton = document.getElementById('connectButton');
     const canvas = document.getElementById('visualizerCanvas');
     const canvasCtx = canvas.getContext('2d');
 
-    const HASH = new Uint8Array([13, 168, 113, 2, 213, 136, 124, 10, 80, 208, 200, 56, 29, 68, 119, 16, 194, 119, 112, 219, 4, 102, 187, 137, 91, 248, 119, 10, 167, 127, 119, 240]);
-
 
     function initAudioAndVisualizer() {
         try {
@@ -33,12 +62,7 @@
                 output: handleDecodedChunk,
                 error: (e) => console.error("AudioDecoder error:", e.message, e),
             });
-
-            audioDecoder.configure({
-                codec: "opus",
-                sampleRate: sampleRate,
-                numberOfChannels: numberOfChannels,
-            });
+            decoderFormat = null;
 
             console.log("Audio and Visualizer initialized.");
             drawWaveform();
@@ -52,6 +76,79 @@
         }
     }
 
+    // FLAC decoders need a stream header, which the server leaves out of its
+    // packets. Mirrors flac_stream_header in streaming-protocol/src/lib.rs.
+    function flacStreamHeader(channels) {
+        const header = new Uint8Array(42);
+        // "fLaC", then the last metadata block: a 34 byte STREAMINFO.
+        header.set([0x66, 0x4c, 0x61, 0x43, 0x80, 0, 0, 34]);
+        const view = new DataView(header.buffer);
+        view.setUint16(8, 16);
+        view.setUint16(10, 0xffff);
+        // 20 bits of sample rate, 3 of channels - 1 and 5 of bits per sample - 1,
+        // followed by an unknown sample count and MD5.
+        view.setUint32(18, (streamSampleRate << 12) | ((channels - 1) << 9) | (15 << 4));
+        return header;
+    }
+
+    // AAC decoders need the AudioSpecificConfig of the raw frames the server
+    // sends. Mirrors aac_audio_specific_config in streaming-protocol/src/lib.rs.
+    function aacAudioSpecificConfig(channels) {
+        const rateIndex = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350]
+            .indexOf(streamSampleRate);
+        // Object type 2 (low complexity), rate index and channel configuration.
+        const packed = (2 << 11) | (rateIndex << 7) | (channels << 3);
+        return new Uint8Array([packed >> 8, packed & 0xff]);
+    }
+
+    function decoderConfig(codec, channels) {
+        const config = { codec: "opus", sampleRate: streamSampleRate, numberOfChannels: channels };
+        if (codec === codecFlac) {
+            config.codec = "flac";
+            config.description = flacStreamHeader(channels);
+        } else if (codec === codecAac) {
+            config.codec = "mp4a.40.2";
+            config.description = aacAudioSpecificConfig(channels);
+        }
+        return config;
+    }
+
+    // Names of the preferred codecs the browser can decode, for the session
+    // path. Safari, for one, often lacks Opus.
+    async function decodableCodecs() {
+        const names = [];
+        for (const [codec, name] of preferredCodecs) {
+            if (codec === codecPcm || (await AudioDecoder.isConfigSupported(decoderConfig(codec, 2))).supported) {
+                names.push(name);
+            }
+        }
+        return names;
+    }
+
+    // Configures the decoder for codec with channels, unless it already is.
+    function configureDecoder(codec, channels) {
+        const format = `${codec}/${channels}`;
+        if (format === decoderFormat) {
+            return;
+        }
+        decoderFormat = format;
+        audioDecoder.configure(decoderConfig(codec, channels));
+    }
+
+    // Reconfigures the decoder when a packet's codec, or the channel count in
+    // its FLAC or AAC frame header, differs from the stream info's.
+    function configureDecoderFor(codec, payload) {
+        const assignment = payload[3] >> 4;
+        let channels = streamChannels;
+        if (codec === codecFlac) {
+            channels = assignment < 8 ? assignment + 1 : 2;
+        } else if (codec === codecAac) {
+            // A channel pair element rather than a single channel one.
+            channels = payload[0] >> 5 === 1 ? 2 : 1;
+        }
+        configureDecoder(codec, channels);
+    }
+
     function handleDecodedChunk(audioData) {
         if (!audioContext || !analyser) {
             console.warn("AudioContext or Analyser not ready, skipping chunk.");
@@ -68,13 +165,7 @@
                 audioData.sampleRate
             );
             audioBuffer.copyToChannel(pcmData, 0);
-
-            const sourceNode = audioContext.createBufferSource();
-            sourceNode.buffer = audioBuffer;
-
-            sourceNode.connect(analyser);
-            sourceNode.start();
-
+            visualizeBuffer(audioBuffer, audioData.timestamp);
             audioData.close();
 
         } catch (err) {
@@ -82,6 +173,40 @@
         }
     }
 
+    // Visualizes a PCM packet, whose samples need no decoding: a channel count
+    // byte followed by interleaved s16le samples. Only the first channel is shown.
+    function visualizePcm(capturedAtUs, payload) {
+        if (!audioContext || !analyser) {
+            return;
+        }
+        const channels = payload[0];
+        const samples = new DataView(payload.buffer, payload.byteOffset + 1, payload.length - 1);
+        const frames = samples.byteLength / 2 / channels;
+        const audioBuffer = audioContext.createBuffer(1, frames, streamSampleRate);
+        const plane = new Float32Array(frames);
+        for (let i = 0; i < frames; i++) {
+            plane[i] = samples.getInt16(i * channels * 2, true) / 32768;
+        }
+        audioBuffer.copyToChannel(plane, 0);
+        visualizeBuffer(audioBuffer, capturedAtUs);
+    }
+
+    function visualizeBuffer(audioBuffer, timestampUs) {
+        const sourceNode = audioContext.createBufferSource();
+        sourceNode.buffer = audioBuffer;
+
+        sourceNode.connect(analyser);
+        sourceNode.start();
+
+        // The chunk timestamp is the server's capture time (assuming both
+        // clocks are in sync), and chunks are played as soon as they arrive.
+        decodedChunkCount++;
+        if (decodedChunkCount % latencyReportInterval === 0) {
+            const latencyMs = Date.now() - timestampUs / 1000;
+            latencyDisplay.textContent = `End-to-end latency: ${latencyMs.toFixed(0)} ms`;
+        }
+    }
+
     function drawWaveform() {
         requestAnimationFrame(drawWaveform);
 
@@ -128,6 +253,153 @@
     }
 
 
+    // Returns a function that takes the media stream's bytes as they arrive and
+    // calls onPacket(codec, capturedAtUs, frameSamples, payload, decoderReset) for
+    // every complete packet, in sequence order. See streaming-protocol/src/lib.rs for the header layout.
+    function createPacketParser(onPacket) {
+        let pending = new Uint8Array(0);
+        let expectedSequence = null;
+        return (bytes) => {
+            const buffered = new Uint8Array(pending.length + bytes.length);
+            buffered.set(pending);
+            buffered.set(bytes, pending.length);
+            const view = new DataView(buffered.buffer);
+            let offset = 0;
+            while (buffered.length - offset >= packetHeaderLen) {
+                if (view.getUint16(offset) !== packetMagic || view.getUint8(offset + 2) !== protocolVersion) {
+                    throw new Error("Unsupported packet framing from server");
+                }
+                const end = offset + packetHeaderLen + view.getUint16(offset + 18);
+                if (end > buffered.length) {
+                    break;
+                }
+                const codec = view.getUint8(offset + 3) & ~decoderResetFlag;
+                const decoderReset = (view.getUint8(offset + 3) & decoderResetFlag) !== 0;
+                const sequence = view.getUint32(offset + 4);
+                const capturedAtUs = Number(view.getBigUint64(offset + 8));
+                const frameSamples = view.getUint16(offset + 16);
+                const payload = buffered.subarray(offset + packetHeaderLen, end);
+                offset = end;
+                // Signed 32-bit difference, so sequence numbers can wrap around.
+                const skipped = expectedSequence === null ? 0 : (sequence - expectedSequence) | 0;
+                if (skipped < 0) {
+                    console.warn(`Packet ${sequence} arrived late, skipping it.`);
+                    continue;
+                }
+                if (skipped > 0) {
+                    console.warn(`${skipped} packets lost before packet ${sequence}.`);
+                }
+                expectedSequence = (sequence + 1) >>> 0;
+                onPacket(codec, capturedAtUs, frameSamples, payload, decoderReset);
+            }
+            pending = buffered.slice(offset);
+        };
+    }
+
+    // Configures the decoder for the stream the server describes, at the start
+    // and again after a restart or a switch to another sink.
+    function applyStreamInfo(info) {
+        console.log(`Sink ${info.sink}: ${info.codec} at ${info.sample_rate} Hz, ${info.channels} channel(s)`);
+        streamSampleRate = info.sample_rate;
+        streamChannels = info.channels;
+        const codec = preferredCodecs.find(([, name]) => name === info.codec)?.[0];
+        if (codec !== undefined && codec !== codecPcm) {
+            configureDecoder(codec, streamChannels);
+        }
+    }
+
+    // Returns a function that takes the control stream's bytes as they arrive
+    // and calls onMessage with every complete message, one line of JSON each.
+    function createControlParser(onMessage) {
+        const textDecoder = new TextDecoder();
+        let pending = "";
+        return (bytes) => {
+            pending += textDecoder.decode(bytes, { stream: true });
+            let end;
+            while ((end = pending.indexOf("\n")) >= 0) {
+                const line = pending.slice(0, end);
+                pending = pending.slice(end + 1);
+                onMessage(JSON.parse(line));
+            }
+        };
+    }
+
+    // Reads the next stream the server opens, returning its reader, what it
+    // carries and the bytes read after that.
+    async function acceptStream(uniStreamReader) {
+        const { value: stream, done } = await uniStreamReader.read();
+        if (done) {
+            throw new Error("Server didn't open the control and media streams.");
+        }
+        const reader = stream.getReader();
+        while (true) {
+            const { value, done } = await reader.read();
+            if (done) {
+                throw new Error("Stream closed before saying what it carries.");
+            }
+            if (value && value.length > 0) {
+                return { reader, kind: value[0], rest: value.subarray(1) };
+            }
+        }
+    }
+
+    // Sends `command` to the server on a control stream of its own.
+    async function sendCommand(transport, command) {
+        const stream = await transport.createBidirectionalStream();
+        const writer = stream.writable.getWriter();
+        const line = new TextEncoder().encode(JSON.stringify(command) + "\n");
+        const bytes = new Uint8Array(1 + line.length);
+        bytes[0] = streamControl;
+        bytes.set(line, 1);
+        await writer.write(bytes);
+        await writer.close();
+    }
+
+    // Proves the password without sending it: the hex HMAC-SHA256 of the
+    // server's nonce under the password, see `password_proof` in the
+    // streaming-protocol crate.
+    async function passwordProof(password, nonce) {
+        const encoder = new TextEncoder();
+        const key = await crypto.subtle.importKey(
+            "raw", encoder.encode(password), { name: "HMAC", hash: "SHA-256" }, false, ["sign"]);
+        const tag = new Uint8Array(await crypto.subtle.sign("HMAC", key, encoder.encode(nonce)));
+        return Array.from(tag, (byte) => byte.toString(16).padStart(2, "0")).join("");
+    }
+
+    // Shows the stream state the server sends after the stream info.
+    async function readControl(reader, parseControl) {
+        while (true) {
+            const { value, done } = await reader.read();
+            if (done) {
+                break;
+            }
+            parseControl(value);
+        }
+    }
+
+    // Trades the access key for a session token at the server's HTTP API.
+    async function fetchSessionToken() {
+        const response = await fetch(`/api/token?key=${encodeURIComponent(accessKey)}`);
+        if (!response.ok) {
+            throw new Error(`Server refused a session token (${response.status})`);
+        }
+        return (await response.json()).token;
+    }
+
+    // Hash of the server's self-signed certificate, fetched fresh in case it
+    // changed. There's none for a Let's Encrypt certificate.
+    async function fetchCertHashes() {
+        const response = await fetch("/api/cert-hash");
+        if (response.status === 404) {
+            return [];
+        }
+        if (!response.ok) {
+            throw new Error(`Couldn't fetch the certificate hash (${response.status})`);
+        }
+        const { algorithm, value } = await response.json();
+        return [{ algorithm, value: new Uint8Array(value).buffer }];
+    }
+
     async function connectAndReceive() {
         if (connected) return;
 
@@ -142,8 +414,11 @@
 
         try {
             statusDisplay.textContent = "Connecting...";
+            const token = await fetchSessionToken();
+            const codecs = await decodableCodecs();
+            const serverUrl = `https://${location.hostname}:13345/${encodeURIComponent(sink)}?codecs=${codecs.join(",")}&token=${token}`;
             transport = new WebTransport(serverUrl, {
-                serverCertificateHashes: [{ algorithm: "sha-256", value: HASH.buffer }]
+                serverCertificateHashes: await fetchCertHashes()
             });
             await transport.ready;
             statusDisplay.textContent = "Connected";
@@ -151,17 +426,106 @@
             connectButton.disabled = true;
 
             const uniStreamReader = transport.incomingUnidirectionalStreams.getReader();
-            const { value: stream, done: streamDone } = await uniStreamReader.read();
-            uniStreamReader.releaseLock();
-
-            if (streamDone) {
-                console.log("No unidirectional stream received.");
-                statusDisplay.textContent = "Failed to get stream.";
-                return;
+            const streams = {};
+            while (!streams[streamControl]) {
+                const accepted = await acceptStream(uniStreamReader);
+                streams[accepted.kind] = accepted;
             }
 
-            const reader = stream.getReader();
+            // The decoder is configured from the stream info, the first control
+            // message, rather than from the first packets. Servers with a
+            // password only send it once the password is proven.
+            const control = streams[streamControl];
+            let streamInfo = null;
+            let challenge = null;
+            const parseControl = createControlParser((message) => {
+                if (!streamInfo) {
+                    if (message.type === "password_challenge") {
+                        challenge = message;
+                        return;
+                    }
+                    if (message.type !== "stream_info") {
+                        throw new Error("Server didn't start the control stream with the stream info.");
+                    }
+                    streamInfo = message;
+                    applyStreamInfo(message);
+                } else if (message.type === "stream_info") {
+                    applyStreamInfo(message);
+                } else if (message.type === "state") {
+                    statusDisplay.textContent = message.paused ? "Paused by server" : "Connected";
+                } else if (message.type === "idle") {
+                    statusDisplay.textContent = message.idle ? "Idle, the sink is silent" : "Connected";
+                } else if (message.type === "certificate_renewed") {
+                    console.log("Server renewed its certificate");
+                }
+            });
+            parseControl(control.rest);
+            let givenPassword = null;
+            while (!streamInfo) {
+                if (challenge) {
+                    // A password the server turned down is asked for again.
+                    givenPassword = password ?? window.prompt("The server needs a password:");
+                    password = null;
+                    if (givenPassword === null) {
+                        throw new Error("No password given.");
+                    }
+                    const proof = await passwordProof(givenPassword, challenge.nonce);
+                    await sendCommand(transport, { type: "authenticate", proof });
+                    challenge = null;
+                }
+                const { value, done } = await control.reader.read();
+                if (done) {
+                    throw new Error("Control stream closed before the stream info.");
+                }
+                parseControl(value);
+            }
+            password = givenPassword ?? password;
+            readControl(control.reader, parseControl).catch((e) => console.warn("Control stream reader stopped:", e));
+            while (!streams[streamMedia]) {
+                const accepted = await acceptStream(uniStreamReader);
+                streams[accepted.kind] = accepted;
+            }
+            uniStreamReader.releaseLock();
 
+            const parsePackets = createPacketParser((codec, capturedAtUs, frameSamples, payload, decoderReset) => {
+                // Configuring the decoder afresh drops the state the server's
+                // previous encoder left.
+                if (decoderReset) {
+                    decoderFormat = null;
+                }
+                if (codec === codecPcm) {
+                    visualizePcm(capturedAtUs, payload);
+                    return;
+                }
+                // Nothing to draw.
+                if (codec === codecSilence) {
+                    return;
+                }
+                if (codec !== codecOpus && codec !== codecFlac && codec !== codecAac) {
+                    console.warn(`Packet in unknown codec ${codec}, skipping it.`);
+                    return;
+                }
+                configureDecoderFor(codec, payload);
+                const chunk = new EncodedAudioChunk({
+                    type: 'key',
+                    timestamp: capturedAtUs,
+                    duration: frameSamples * 1e6 / streamSampleRate,
+                    data: payload
+                });
+                try {
+                    if (audioDecoder.state === "configured") {
+                        audioDecoder.decode(chunk);
+                    } else {
+                        console.warn("Decoder not configured, skipping packet. State: %s", audioDecoder.state);
+                    }
+                } catch (decodeError) {
+                    console.error("Error during decode call:", decodeError);
+                }
+            });
+            // This client never asks for datagrams, so the packets always follow
+            // on the media stream.
+            const { reader, rest } = streams[streamMedia];
+            parsePackets(rest);
             while (true) {
                 const { value, done } = await reader.read();
                 if (done) {
@@ -170,21 +534,7 @@
                     break;
                 }
                 if (value && value.length > 0) {
-                    const chunk = new EncodedAudioChunk({
-                        type: 'key',
-                        timestamp: audioContext.currentTime * 1000000,
-                        duration: frameDurationMs * 1000,
-                        data: value
-                    });
-                    try {
-                        if (audioDecoder.state === "configured") {
-                            audioDecoder.decode(chunk);
-                        } else {
-                            console.warn("Decoder not configured, skipping packet. State: %s", audioDecoder.state);
-                        }
-                    } catch (decodeError) {
-                        console.error("Error during decode call:", decodeError);
-                    }
+                    parsePackets(value);
                 }
             }
 
@@ -0,0 +1,217 @@
+use crossbeam_channel::{Receiver, SendError, Sender, TryIter, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// How often channels that dropped messages are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a full channel does with one more message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drops the new message, for data where any recent one will do.
+    DropNewest,
+    /// Makes room by dropping the oldest message, keeping the consumer at the
+    /// live edge.
+    DropOldest,
+}
+
+/// Counts the messages a channel dropped because its consumer fell behind.
+pub struct DropCounter {
+    pub name: String,
+    pub capacity: usize,
+    dropped: AtomicU64,
+}
+
+impl DropCounter {
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// The sending half of a bounded channel. It never blocks, so it's safe in
+/// the PipeWire process callback: a full channel drops a message as its
+/// `Overflow` says, and counts it.
+pub struct BoundedSender<T> {
+    sender: Sender<T>,
+    /// Takes the oldest message out with `Overflow::DropOldest`. It keeps the
+    /// channel open, so these senders never see the consumer hang up.
+    oldest: Option<Receiver<T>>,
+    counter: Arc<DropCounter>,
+    /// Declared after `sender`, so it's dropped after it.
+    wake: Wake,
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            oldest: self.oldest.clone(),
+            counter: self.counter.clone(),
+            wake: Wake(self.wake.0.clone()),
+        }
+    }
+}
+
+/// Wakes an async receiver, and again when its sender is dropped, so the
+/// receiver can find out it was the last one.
+struct Wake(Arc<Notify>);
+
+impl Drop for Wake {
+    fn drop(&mut self) {
+        self.0.notify_one();
+    }
+}
+
+/// The receiving half of a bounded channel, awaited by a task or drained
+/// without blocking by the PipeWire loop.
+pub struct BoundedReceiver<T> {
+    receiver: Receiver<T>,
+    notify: Arc<Notify>,
+}
+
+impl<T> BoundedReceiver<T> {
+    /// The next message, or `None` once every sender is gone.
+    pub async fn recv(&self) -> Option<T> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(message) => return Some(message),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => self.notify.notified().await,
+            }
+        }
+    }
+
+    /// The messages waiting, without blocking.
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        self.receiver.try_iter()
+    }
+}
+
+/// A channel holding up to `capacity` messages, named in drop reports.
+pub fn channel<T>(
+    name: impl Into<String>,
+    capacity: usize,
+    overflow: Overflow,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let (sender, receiver) = crossbeam_channel::bounded(capacity);
+    let counter = Arc::new(DropCounter {
+        name: name.into(),
+        capacity,
+        dropped: AtomicU64::new(0),
+    });
+    let oldest = (overflow == Overflow::DropOldest).then(|| receiver.clone());
+    let notify = Arc::new(Notify::new());
+    (
+        BoundedSender {
+            sender,
+            oldest,
+            counter,
+            wake: Wake(notify.clone()),
+        },
+        BoundedReceiver { receiver, notify },
+    )
+}
+
+impl<T> BoundedSender<T> {
+    /// Queues `message`, failing only if the consumer hung up.
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let message = match self.sender.try_send(message) {
+            Ok(()) => {
+                self.wake.0.notify_one();
+                return Ok(());
+            }
+            Err(TrySendError::Disconnected(message)) => return Err(SendError(message)),
+            Err(TrySendError::Full(message)) => message,
+        };
+        self.counter.dropped.fetch_add(1, Ordering::Relaxed);
+        if let Some(oldest) = &self.oldest {
+            let _ = oldest.try_recv();
+            // Another sender may have taken the room, dropping this message
+            // after all.
+            let _ = self.sender.try_send(message);
+            self.wake.0.notify_one();
+        }
+        Ok(())
+    }
+
+    pub fn counter(&self) -> Arc<DropCounter> {
+        self.counter.clone()
+    }
+}
+
+/// Warns about the channels that dropped messages, every `REPORT_INTERVAL`.
+pub fn spawn_overflow_task(counters: Vec<Arc<DropCounter>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut reported = vec![0; counters.len()];
+        loop {
+            tokio::time::sleep(REPORT_INTERVAL).await;
+            for (counter, reported) in counters.iter().zip(&mut reported) {
+                let dropped = counter.dropped();
+                if dropped > *reported {
+                    warn!(
+                        "{} dropped {} messages in {:?}, its consumer can't keep up \
+                         with {} queued ({} dropped in all)",
+                        counter.name,
+                        dropped - *reported,
+                        REPORT_INTERVAL,
+                        counter.capacity,
+                        dropped
+                    );
+                    *reported = dropped;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_channels_drop_and_count_as_their_policy_says() {
+        let (newest, receiver) = channel("newest", 2, Overflow::DropNewest);
+        for message in 0..5 {
+            newest.send(message).unwrap();
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(newest.counter().dropped(), 3);
+
+        let (oldest, receiver) = channel("oldest", 2, Overflow::DropOldest);
+        for message in 0..5 {
+            oldest.send(message).unwrap();
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(oldest.counter().dropped(), 3);
+
+        let (sender, receiver) = channel("closed", 2, Overflow::DropNewest);
+        drop(receiver);
+        assert!(sender.send(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn tasks_receive_until_every_sender_is_gone() {
+        let (sender, receiver) = channel("async", 4, Overflow::DropOldest);
+        let receiving = tokio::spawn(async move {
+            let mut messages = Vec::new();
+            while let Some(message) = receiver.recv().await {
+                messages.push(message);
+            }
+            messages
+        });
+        let other = sender.clone();
+        std::thread::spawn(move || {
+            for message in 0..3 {
+                sender.send(message).unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        })
+        .join()
+        .unwrap();
+        drop(other);
+        assert_eq!(receiving.await.unwrap(), [0, 1, 2]);
+    }
+}
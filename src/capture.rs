@@ -0,0 +1,34 @@
+pub mod downmix;
+pub mod dsp;
+pub mod levels;
+pub mod pipewire_registry;
+pub mod resample;
+pub mod sample_format;
+mod streams;
+pub mod xrun;
+
+use crate::bounded::BoundedSender;
+use crate::encode::compress::CapturedAudio;
+use levels::ChannelLevels;
+use std::sync::Arc;
+pub use streams::Capture;
+use xrun::CaptureGlitches;
+
+/// What a sink's capture feeds, kept to connect it to another node later.
+#[derive(Clone)]
+pub struct SinkOutputs {
+    /// One per codec.
+    pub senders: Vec<BoundedSender<CapturedAudio>>,
+    pub level_sender: BoundedSender<ChannelLevels>,
+    pub glitches: Arc<CaptureGlitches>,
+}
+
+impl SinkOutputs {
+    /// Hands a process cycle of audio to every encoder of the sink.
+    pub fn send(&self, audio: CapturedAudio) {
+        for sender in &self.senders {
+            // Senders that drop the oldest audio never fail.
+            let _ = sender.send(audio.clone());
+        }
+    }
+}
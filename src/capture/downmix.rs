@@ -0,0 +1,164 @@
+use anyhow::{Result, bail};
+use std::fmt;
+use std::str::FromStr;
+
+/// Channel layout exposed by the PipeWire sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    Surround51,
+    Surround71,
+}
+
+impl ChannelLayout {
+    /// Channel names in PipeWire position order.
+    pub fn channel_names(self) -> &'static [&'static str] {
+        match self {
+            ChannelLayout::Mono => &["MONO"],
+            ChannelLayout::Stereo => &["FL", "FR"],
+            ChannelLayout::Surround51 => &["FL", "FR", "FC", "LFE", "SL", "SR"],
+            ChannelLayout::Surround71 => &["FL", "FR", "FC", "LFE", "RL", "RR", "SL", "SR"],
+        }
+    }
+
+    pub fn channel_count(self) -> usize {
+        self.channel_names().len()
+    }
+}
+
+impl FromStr for ChannelLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mono" | "1.0" => Ok(ChannelLayout::Mono),
+            "stereo" | "2.0" => Ok(ChannelLayout::Stereo),
+            "5.1" | "surround51" => Ok(ChannelLayout::Surround51),
+            "7.1" | "surround71" => Ok(ChannelLayout::Surround71),
+            _ => bail!("Unknown channel layout {s:?}, expected mono, stereo, 5.1 or 7.1"),
+        }
+    }
+}
+
+impl fmt::Display for ChannelLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChannelLayout::Mono => "mono",
+            ChannelLayout::Stereo => "stereo",
+            ChannelLayout::Surround51 => "5.1",
+            ChannelLayout::Surround71 => "7.1",
+        })
+    }
+}
+
+/// Mixes the sink's input channels down to the encoder's output channels.
+///
+/// Coefficients are stored row-major, one row of `inputs` gains per output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownmixMatrix {
+    inputs: usize,
+    outputs: usize,
+    coefficients: Vec<f32>,
+}
+
+impl DownmixMatrix {
+    /// Standard downmix to mono or stereo: centre and surrounds at -3 dB, LFE
+    /// dropped, each output normalised so full-scale input on every channel
+    /// can't clip.
+    pub fn standard(layout: ChannelLayout, outputs: usize) -> Self {
+        assert!(
+            outputs == 1 || outputs == 2,
+            "Only mono and stereo downmixes are supported"
+        );
+        let names = layout.channel_names();
+        let mut coefficients = Vec::with_capacity(names.len() * outputs);
+        for output in 0..outputs {
+            let gains: Vec<f32> = names
+                .iter()
+                .map(|name| match (outputs, output, *name) {
+                    (_, _, "LFE") => 0.0,
+                    (_, _, "MONO") | (1, _, "FL" | "FR") => 1.0,
+                    (2, 0, "FL") | (2, 1, "FR") => 1.0,
+                    (2, 0, "FR" | "RR" | "SR") | (2, 1, "FL" | "RL" | "SL") => 0.0,
+                    _ => std::f32::consts::FRAC_1_SQRT_2,
+                })
+                .collect();
+            let total: f32 = gains.iter().sum();
+            coefficients.extend(gains.into_iter().map(|gain| gain / total));
+        }
+        Self {
+            inputs: names.len(),
+            outputs,
+            coefficients,
+        }
+    }
+
+    /// Keeps every channel of `layout` as it is, only interleaving them.
+    pub fn passthrough(layout: ChannelLayout) -> Self {
+        let inputs = layout.channel_count();
+        let coefficients = (0..inputs * inputs)
+            .map(|index| if index % (inputs + 1) == 0 { 1.0 } else { 0.0 })
+            .collect();
+        Self {
+            inputs,
+            outputs: inputs,
+            coefficients,
+        }
+    }
+
+    /// Parses a user-supplied matrix: rows (one per output channel) separated by
+    /// `;`, each holding one comma-separated gain per input channel.
+    pub fn parse(matrix: &str, layout: ChannelLayout) -> Result<Self> {
+        let inputs = layout.channel_count();
+        let mut coefficients = Vec::new();
+        let mut outputs = 0;
+        for row in matrix
+            .split(';')
+            .map(str::trim)
+            .filter(|row| !row.is_empty())
+        {
+            let gains = row
+                .split(',')
+                .map(|gain| gain.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()?;
+            if gains.len() != inputs {
+                bail!(
+                    "Downmix row {row:?} has {} gains, but the {layout} layout has {inputs} channels",
+                    gains.len()
+                );
+            }
+            coefficients.extend(gains);
+            outputs += 1;
+        }
+        if outputs == 0 {
+            bail!("Downmix matrix is empty");
+        }
+        Ok(Self {
+            inputs,
+            outputs,
+            coefficients,
+        })
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    /// Mixes planar input, one plane per input channel, into interleaved output
+    /// samples. Missing planes are treated as silence.
+    pub fn apply_planes(&self, planes: &[Vec<i16>], out: &mut Vec<i16>) {
+        let frames = planes.iter().map(Vec::len).max().unwrap_or(0);
+        out.reserve(frames * self.outputs);
+        for frame in 0..frames {
+            for row in self.coefficients.chunks_exact(self.inputs) {
+                let mixed: f32 = row
+                    .iter()
+                    .zip(planes)
+                    .filter_map(|(gain, plane)| Some(gain * *plane.get(frame)? as f32))
+                    .sum();
+                out.push(mixed.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+/// Q of high- and low-pass filters given without one, the flattest passband
+/// (Butterworth).
+const DEFAULT_PASS_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+/// Boosts and cuts are limited to this many decibels.
+const MAX_GAIN_DB: f32 = 30.0;
+const MAX_Q: f32 = 20.0;
+
+/// One stage of the chain applied to the audio before it's encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Filter {
+    HighPass {
+        freq_hz: f32,
+        q: f32,
+    },
+    LowPass {
+        freq_hz: f32,
+        q: f32,
+    },
+    /// A parametric EQ band, boosting or cutting around `freq_hz`.
+    Peaking {
+        freq_hz: f32,
+        gain_db: f32,
+        q: f32,
+    },
+    Gain {
+        gain_db: f32,
+    },
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    /// Parses `highpass:<Hz>[:<Q>]`, `lowpass:<Hz>[:<Q>]`,
+    /// `eq:<Hz>:<dB>[:<Q>]` or `gain:<dB>`.
+    fn from_str(filter: &str) -> Result<Self> {
+        let mut parts = filter.split(':');
+        let kind = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let values = parts
+            .map(|value| {
+                value
+                    .trim()
+                    .parse::<f32>()
+                    .map_err(|_| anyhow!("Invalid number {:?} in filter {:?}", value, filter))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let filter = match (kind.as_str(), &values[..]) {
+            ("highpass", &[freq_hz]) => Filter::HighPass {
+                freq_hz,
+                q: DEFAULT_PASS_Q,
+            },
+            ("highpass", &[freq_hz, q]) => Filter::HighPass { freq_hz, q },
+            ("lowpass", &[freq_hz]) => Filter::LowPass {
+                freq_hz,
+                q: DEFAULT_PASS_Q,
+            },
+            ("lowpass", &[freq_hz, q]) => Filter::LowPass { freq_hz, q },
+            ("eq", &[freq_hz, gain_db]) => Filter::Peaking {
+                freq_hz,
+                gain_db,
+                q: 1.0,
+            },
+            ("eq", &[freq_hz, gain_db, q]) => Filter::Peaking {
+                freq_hz,
+                gain_db,
+                q,
+            },
+            ("gain", &[gain_db]) => Filter::Gain { gain_db },
+            _ => bail!(
+                "Invalid filter {:?}, expected highpass:<Hz>[:<Q>], lowpass:<Hz>[:<Q>], \
+                 eq:<Hz>:<dB>[:<Q>] or gain:<dB>",
+                filter
+            ),
+        };
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    pub fn validate(&self, sample_rate: u32) -> Result<()> {
+        let nyquist = sample_rate as f32 / 2.0;
+        let (freq_hz, q, gain_db) = match *self {
+            Filter::HighPass { freq_hz, q } | Filter::LowPass { freq_hz, q } => {
+                (Some(freq_hz), Some(q), None)
+            }
+            Filter::Peaking {
+                freq_hz,
+                gain_db,
+                q,
+            } => (Some(freq_hz), Some(q), Some(gain_db)),
+            Filter::Gain { gain_db } => (None, None, Some(gain_db)),
+        };
+        if let Some(freq_hz) = freq_hz
+            && !(freq_hz > 0.0 && freq_hz < nyquist)
+        {
+            bail!(
+                "Filter frequency {} Hz is outside 0 to {} Hz",
+                freq_hz,
+                nyquist
+            );
+        }
+        if let Some(q) = q
+            && !(q > 0.0 && q <= MAX_Q)
+        {
+            bail!("Filter Q {} is outside 0 to {}", q, MAX_Q);
+        }
+        if let Some(gain_db) = gain_db
+            && !(-MAX_GAIN_DB..=MAX_GAIN_DB).contains(&gain_db)
+        {
+            bail!("Filter gain {} dB is outside ±{} dB", gain_db, MAX_GAIN_DB);
+        }
+        Ok(())
+    }
+}
+
+pub fn validate(filters: &[Filter], sample_rate: u32) -> Result<()> {
+    filters
+        .iter()
+        .try_for_each(|filter| filter.validate(sample_rate))
+}
+
+/// Biquad coefficients, normalized so `a0` is 1.
+#[derive(Clone, Copy, Debug)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// From the formulas of Robert Bristow-Johnson's Audio EQ Cookbook.
+    fn new(filter: Filter, sample_rate: u32) -> Option<Self> {
+        let (freq_hz, q) = match filter {
+            Filter::HighPass { freq_hz, q }
+            | Filter::LowPass { freq_hz, q }
+            | Filter::Peaking { freq_hz, q, .. } => (freq_hz, q),
+            Filter::Gain { .. } => return None,
+        };
+        let w0 = 2.0 * PI * freq_hz / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let (b0, b1, b2, a0, a1, a2) = match filter {
+            Filter::HighPass { .. } => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            Filter::LowPass { .. } => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            Filter::Peaking { gain_db, .. } => {
+                let a = 10f32.powf(gain_db / 40.0);
+                (
+                    1.0 + alpha * a,
+                    -2.0 * cos,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos,
+                    1.0 - alpha / a,
+                )
+            }
+            Filter::Gain { .. } => unreachable!("Gains have no coefficients"),
+        };
+        Some(Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        })
+    }
+}
+
+enum Stage {
+    /// A biquad filter, with the state of each channel in transposed direct
+    /// form II.
+    Biquad {
+        coefficients: Coefficients,
+        state: Vec<[f32; 2]>,
+    },
+    Gain(f32),
+}
+
+/// The filters applied to one sink's interleaved audio, remembering each
+/// channel's history across calls.
+pub struct DspChain {
+    stages: Vec<Stage>,
+    channels: usize,
+}
+
+impl DspChain {
+    pub fn new(filters: &[Filter], sample_rate: u32, channels: usize) -> Self {
+        let stages = filters
+            .iter()
+            .map(|&filter| match filter {
+                Filter::Gain { gain_db } => Stage::Gain(10f32.powf(gain_db / 20.0)),
+                filter => Stage::Biquad {
+                    coefficients: Coefficients::new(filter, sample_rate)
+                        .expect("Filters other than gains are biquads"),
+                    state: vec![[0.0; 2]; channels],
+                },
+            })
+            .collect();
+        Self { stages, channels }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn process(&mut self, samples: &mut [i16]) {
+        if self.is_empty() {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut value = *sample as f32;
+                for stage in &mut self.stages {
+                    value = match stage {
+                        Stage::Biquad {
+                            coefficients: c,
+                            state,
+                        } => {
+                            let [z1, z2] = &mut state[channel];
+                            let output = c.b0 * value + *z1;
+                            *z1 = c.b1 * value - c.a1 * output + *z2;
+                            *z2 = c.b2 * value - c.a2 * output;
+                            output
+                        }
+                        Stage::Gain(gain) => value * *gain,
+                    };
+                }
+                *sample = value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Peak of the last half of a stereo sine at `freq_hz` run through
+    /// `filters`, relative to its input amplitude.
+    fn response(filters: &[Filter], freq_hz: f32) -> f32 {
+        let mut chain = DspChain::new(filters, SAMPLE_RATE, 2);
+        let mut samples: Vec<i16> = (0..SAMPLE_RATE as usize)
+            .flat_map(|n| {
+                let value = (2.0 * PI * freq_hz * n as f32 / SAMPLE_RATE as f32).sin() * 8000.0;
+                [value as i16; 2]
+            })
+            .collect();
+        chain.process(&mut samples);
+        let settled = &samples[samples.len() / 2..];
+        let peak = settled
+            .iter()
+            .map(|sample| sample.unsigned_abs())
+            .max()
+            .unwrap();
+        // Both channels are filtered alike.
+        assert!(settled.chunks(2).all(|frame| frame[0] == frame[1]));
+        peak as f32 / 8000.0
+    }
+
+    #[test]
+    fn filters_shape_the_frequencies_they_target() {
+        let high_pass = [Filter::HighPass {
+            freq_hz: 200.0,
+            q: DEFAULT_PASS_Q,
+        }];
+        assert!(response(&high_pass, 20.0) < 0.02);
+        assert!((response(&high_pass, 5000.0) - 1.0).abs() < 0.01);
+        let low_pass = [Filter::LowPass {
+            freq_hz: 1000.0,
+            q: DEFAULT_PASS_Q,
+        }];
+        assert!(response(&low_pass, 10_000.0) < 0.02);
+        assert!((response(&low_pass, 50.0) - 1.0).abs() < 0.01);
+        let eq = [Filter::Peaking {
+            freq_hz: 1000.0,
+            gain_db: 6.0,
+            q: 1.0,
+        }];
+        assert!((response(&eq, 1000.0) - 2.0).abs() < 0.02);
+        assert!((response(&eq, 50.0) - 1.0).abs() < 0.02);
+        let gain = [Filter::Gain { gain_db: -6.0 }];
+        assert!((response(&gain, 440.0) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn filters_parse_from_the_command_line() {
+        assert_eq!(
+            "eq:1000:-3.5:2".parse::<Filter>().unwrap(),
+            Filter::Peaking {
+                freq_hz: 1000.0,
+                gain_db: -3.5,
+                q: 2.0
+            }
+        );
+        assert_eq!(
+            "HighPass:80".parse::<Filter>().unwrap(),
+            Filter::HighPass {
+                freq_hz: 80.0,
+                q: DEFAULT_PASS_Q
+            }
+        );
+        assert!("gain:x".parse::<Filter>().is_err());
+        assert!("lowpass".parse::<Filter>().is_err());
+        assert!(
+            "lowpass:30000"
+                .parse::<Filter>()
+                .unwrap()
+                .validate(SAMPLE_RATE)
+                .is_err()
+        );
+        assert!(
+            "gain:40"
+                .parse::<Filter>()
+                .unwrap()
+                .validate(SAMPLE_RATE)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use crate::bounded::BoundedReceiver;
+use ringbuf::HeapRb;
+use ringbuf::traits::{Consumer, Observer, RingBuffer};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+/// Resolution of the level history.
+pub const LEVEL_INTERVAL: Duration = Duration::from_millis(100);
+/// How far back the level history reaches.
+pub const LEVEL_HISTORY: Duration = Duration::from_secs(300);
+const INTERVALS_IN_HISTORY: usize =
+    (LEVEL_HISTORY.as_millis() / LEVEL_INTERVAL.as_millis()) as usize;
+
+/// Peak and RMS level of one channel over one `LEVEL_INTERVAL`, linear in 0.0..=1.0.
+#[derive(Clone, Copy, Default, Serialize, ToSchema)]
+pub struct Level {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// One `Level` per sink channel.
+pub type ChannelLevels = Vec<Level>;
+
+/// Accumulates per-channel levels from the PipeWire process callback, emitting
+/// one set of levels per `LEVEL_INTERVAL`.
+pub struct LevelAccumulator {
+    peak: Vec<i32>,
+    sum_squares: Vec<f64>,
+    frames: usize,
+    frames_per_interval: usize,
+}
+
+impl LevelAccumulator {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            peak: vec![0; channels],
+            sum_squares: vec![0.0; channels],
+            frames: 0,
+            frames_per_interval: (sample_rate as u128 * LEVEL_INTERVAL.as_millis() / 1000) as usize,
+        }
+    }
+
+    /// Feeds one period of samples for `channel`.
+    pub fn add_samples(&mut self, channel: usize, samples: &[i16]) {
+        if channel >= self.peak.len() {
+            return;
+        }
+        for &sample in samples {
+            let sample = sample as i32;
+            self.peak[channel] = self.peak[channel].max(sample.abs());
+            self.sum_squares[channel] += (sample * sample) as f64;
+        }
+    }
+
+    /// Marks `frames` frames as fed on every channel, returning the finished
+    /// levels once a full interval has been accumulated.
+    pub fn advance(&mut self, frames: usize) -> Option<ChannelLevels> {
+        self.frames += frames;
+        if self.frames < self.frames_per_interval {
+            return None;
+        }
+        let full_scale = i16::MAX as f32;
+        let levels = self
+            .peak
+            .iter()
+            .zip(&self.sum_squares)
+            .map(|(&peak, &sum_squares)| Level {
+                peak: (peak as f32 / full_scale).min(1.0),
+                rms: ((sum_squares / self.frames as f64).sqrt() as f32 / full_scale).min(1.0),
+            })
+            .collect();
+        self.peak.fill(0);
+        self.sum_squares.fill(0.0);
+        self.frames = 0;
+        Some(levels)
+    }
+}
+
+/// Per-channel circular buffers holding the last `LEVEL_HISTORY` of levels.
+pub struct LevelHistory {
+    names: &'static [&'static str],
+    channels: Vec<HeapRb<Level>>,
+}
+
+pub type SharedLevelHistory = Arc<Mutex<LevelHistory>>;
+
+/// The level history of one sink, keyed by its stream id.
+pub struct SinkLevelHistory {
+    pub id: String,
+    pub history: SharedLevelHistory,
+}
+
+impl LevelHistory {
+    pub fn new(names: &'static [&'static str]) -> Self {
+        Self {
+            names,
+            channels: names
+                .iter()
+                .map(|_| HeapRb::new(INTERVALS_IN_HISTORY))
+                .collect(),
+        }
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        self.names
+    }
+
+    pub fn push(&mut self, levels: ChannelLevels) {
+        for (history, level) in self.channels.iter_mut().zip(levels) {
+            history.push_overwrite(level);
+        }
+    }
+
+    /// Returns the levels recorded within the last `window`, oldest first,
+    /// one `Vec` per channel in `channel_names` order.
+    pub fn window(&self, window: Duration) -> Vec<Vec<Level>> {
+        let wanted = (window.as_millis() / LEVEL_INTERVAL.as_millis()) as usize;
+        self.channels
+            .iter()
+            .map(|history| {
+                let skip = history.occupied_len().saturating_sub(wanted);
+                history.iter().skip(skip).copied().collect()
+            })
+            .collect()
+    }
+}
+
+pub fn spawn_levels_task(
+    rx: BoundedReceiver<ChannelLevels>,
+    history: SharedLevelHistory,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(levels) = rx.recv().await {
+            history
+                .lock()
+                .expect("Level history lock poisoned")
+                .push(levels);
+        }
+    })
+}
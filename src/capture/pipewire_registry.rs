@@ -0,0 +1,131 @@
+use pipewire as pw;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+/// An Audio/Sink or Audio/Source node seen on the PipeWire registry.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct NodeInfo {
+    pub id: u32,
+    pub name: String,
+    pub description: String,
+    pub media_class: String,
+}
+
+impl NodeInfo {
+    pub fn is_sink(&self) -> bool {
+        self.media_class == "Audio/Sink"
+    }
+}
+
+pub type SharedNodeList = Arc<Mutex<Vec<NodeInfo>>>;
+
+/// Keeps a `SharedNodeList` in sync with the registry for as long as it's alive.
+pub struct NodeWatcher {
+    _registry: pw::registry::Registry,
+    _listener: pw::registry::Listener,
+}
+
+impl NodeWatcher {
+    pub fn new(core: &pw::core::Core, nodes: SharedNodeList) -> Self {
+        let registry = core.get_registry().expect("Couldn't get PipeWire registry");
+        let listener = registry
+            .add_listener_local()
+            .global({
+                let nodes = nodes.clone();
+                move |global| {
+                    if global.type_ != pw::types::ObjectType::Node {
+                        return;
+                    }
+                    let Some(props) = global.props else {
+                        return;
+                    };
+                    let (Some(name), Some(media_class)) = (
+                        props.get(*pw::keys::NODE_NAME),
+                        props.get(*pw::keys::MEDIA_CLASS),
+                    ) else {
+                        return;
+                    };
+                    if media_class != "Audio/Sink" && media_class != "Audio/Source" {
+                        return;
+                    }
+                    nodes
+                        .lock()
+                        .expect("Node list lock poisoned")
+                        .push(NodeInfo {
+                            id: global.id,
+                            name: name.to_owned(),
+                            description: props
+                                .get(*pw::keys::NODE_DESCRIPTION)
+                                .unwrap_or(name)
+                                .to_owned(),
+                            media_class: media_class.to_owned(),
+                        });
+                }
+            })
+            .global_remove(move |id| {
+                nodes
+                    .lock()
+                    .expect("Node list lock poisoned")
+                    .retain(|node| node.id != id);
+            })
+            .register();
+        Self {
+            _registry: registry,
+            _listener: listener,
+        }
+    }
+}
+
+/// Runs the main loop until PipeWire has processed everything sent so far, so
+/// that e.g. the initial registry globals have all been announced.
+pub fn roundtrip(main_loop: &pw::main_loop::MainLoop, core: &pw::core::Core) {
+    let pending = core.sync(0).expect("Couldn't sync with PipeWire");
+    let _listener = core
+        .add_listener_local()
+        .done({
+            let main_loop = main_loop.clone();
+            move |id, seq| {
+                if id == pw::core::PW_ID_CORE && seq == pending {
+                    main_loop.quit();
+                }
+            }
+        })
+        .register();
+    main_loop.run();
+}
+
+/// Matches `text` against `pattern`, where `*` matches any run of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard, so the whole text must have matched.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Returns the first node whose name matches `pattern`.
+pub fn find_node<'a>(nodes: &'a [NodeInfo], pattern: &str) -> Option<&'a NodeInfo> {
+    nodes.iter().find(|node| glob_match(pattern, &node.name))
+}
+
+pub fn print_nodes(nodes: &[NodeInfo]) {
+    for node in nodes {
+        println!(
+            "{:>5}  {:<12}  {} ({})",
+            node.id, node.media_class, node.name, node.description
+        );
+    }
+}
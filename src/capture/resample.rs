@@ -0,0 +1,54 @@
+use anyhow::Result;
+use rubato::{FftFixedInOut, Resampler as _};
+
+/// Converts interleaved audio from the rate PipeWire negotiated to the
+/// encoder's rate, holding input back until a full resampler chunk is ready.
+pub struct Resampler {
+    inner: FftFixedInOut<f32>,
+    channels: usize,
+    pending: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32, channels: usize) -> Result<Self> {
+        // Chunks of roughly 10 ms, rounded by rubato to fit the rate ratio.
+        let inner = FftFixedInOut::new(
+            input_rate as usize,
+            output_rate as usize,
+            (input_rate / 100) as usize,
+            channels,
+        )?;
+        let output = inner.output_buffer_allocate(true);
+        Ok(Self {
+            inner,
+            channels,
+            pending: vec![Vec::new(); channels],
+            output,
+        })
+    }
+
+    /// Resamples interleaved `samples`, appending any finished output to `out`.
+    pub fn process(&mut self, samples: &[i16], out: &mut Vec<i16>) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (pending, &sample) in self.pending.iter_mut().zip(frame) {
+                pending.push(sample as f32 / i16::MAX as f32);
+            }
+        }
+        while self.pending[0].len() >= self.inner.input_frames_next() {
+            let (consumed, produced) = self
+                .inner
+                .process_into_buffer(&self.pending, &mut self.output, None)
+                .expect("Resampler buffers are sized by the resampler");
+            for frame in 0..produced {
+                for channel in &self.output {
+                    let sample = channel[frame].clamp(-1.0, 1.0) * i16::MAX as f32;
+                    out.push(sample.round() as i16);
+                }
+            }
+            for pending in &mut self.pending {
+                pending.drain(..consumed);
+            }
+        }
+    }
+}
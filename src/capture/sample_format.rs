@@ -0,0 +1,79 @@
+/// Little-endian sample formats the sink accepts from PipeWire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    S16,
+    /// Packed 24-bit, three bytes per sample.
+    S24,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::F32 => 4,
+        }
+    }
+
+    /// Converts one sample of `bytes_per_sample` bytes to the encoder's i16.
+    fn to_i16(self, bytes: &[u8]) -> i16 {
+        match self {
+            SampleFormat::S16 => i16::from_le_bytes([bytes[0], bytes[1]]),
+            SampleFormat::S24 => {
+                (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 16) as i16
+            }
+            SampleFormat::F32 => {
+                let sample = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+            }
+        }
+    }
+}
+
+/// Layout of the buffers negotiated with PipeWire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample: SampleFormat,
+    /// One data block per channel, rather than one block of interleaved frames.
+    pub planar: bool,
+    pub channels: usize,
+}
+
+impl PcmFormat {
+    /// Bytes per frame in each data block.
+    pub fn stride(&self) -> usize {
+        match self.planar {
+            true => self.sample.bytes_per_sample(),
+            false => self.sample.bytes_per_sample() * self.channels,
+        }
+    }
+
+    /// Converts the data blocks of one PipeWire buffer into one plane of i16
+    /// samples per channel.
+    pub fn decode_planes(&self, blocks: &[&[u8]]) -> Vec<Vec<i16>> {
+        let width = self.sample.bytes_per_sample();
+        if self.planar {
+            return blocks
+                .iter()
+                .map(|block| {
+                    block
+                        .chunks_exact(width)
+                        .map(|bytes| self.sample.to_i16(bytes))
+                        .collect()
+                })
+                .collect();
+        }
+        let Some(block) = blocks.first() else {
+            return Vec::new();
+        };
+        let frames = block.len() / (width * self.channels);
+        let mut planes = vec![Vec::with_capacity(frames); self.channels];
+        for frame in block.chunks_exact(width * self.channels) {
+            for (plane, bytes) in planes.iter_mut().zip(frame.chunks_exact(width)) {
+                plane.push(self.sample.to_i16(bytes));
+            }
+        }
+        planes
+    }
+}
@@ -0,0 +1,864 @@
+use super::SinkOutputs;
+use super::downmix::DownmixMatrix;
+use super::dsp::{DspChain, Filter};
+use super::levels::LevelAccumulator;
+use super::pipewire_registry::{
+    NodeInfo, NodeWatcher, SharedNodeList, find_node, print_nodes, roundtrip,
+};
+use super::resample::Resampler;
+use super::sample_format::{PcmFormat, SampleFormat};
+use super::xrun::XrunDetector;
+use crate::bounded::BoundedReceiver;
+use crate::config::{Config, SinkSpec};
+use crate::control::ControlBus;
+use crate::encode::compress::CapturedAudio;
+use crate::health::Health;
+use libspa::buffer::ChunkFlags;
+use libspa::param::audio::AudioFormat;
+use libspa::pod;
+use libspa::utils::Direction;
+use pipewire as pw;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// Delay before the first retry with `--wait-for-pipewire` or after losing the
+/// connection, doubling up to `PIPEWIRE_RETRY_MAX`.
+const PIPEWIRE_RETRY_MIN: Duration = Duration::from_secs(1);
+const PIPEWIRE_RETRY_MAX: Duration = Duration::from_secs(30);
+
+struct SinkData {
+    outputs: SinkOutputs,
+    downmix: DownmixMatrix,
+    levels: LevelAccumulator,
+    format: PcmFormat,
+    /// Rate the encoder expects.
+    sample_rate: u32,
+    /// Set while PipeWire delivers audio at a rate other than `sample_rate`.
+    resampler: Option<Resampler>,
+    /// Interleaves the sink's channels for `--surround`, which are resampled
+    /// on their own.
+    surround: Option<DownmixMatrix>,
+    surround_resampler: Option<Resampler>,
+    dsp: DspChain,
+    dsp_filters: watch::Receiver<Vec<Filter>>,
+    xruns: XrunDetector,
+    /// Rate PipeWire delivers audio at.
+    capture_rate: u32,
+    reconnect: Reconnect,
+}
+
+/// Microphone audio from clients, waiting to be played into the virtual source.
+struct SourceData {
+    receiver: Rc<BoundedReceiver<Vec<i16>>>,
+    pending: VecDeque<i16>,
+    /// Older samples are dropped beyond this, so latency can't build up.
+    max_pending: usize,
+}
+
+/// Quits PipeWire's loop so that `Capture::run` connects again, once the
+/// daemon is gone or a stream failed.
+#[derive(Clone)]
+struct Reconnect {
+    main_loop: pw::main_loop::MainLoop,
+    requested: Rc<Cell<bool>>,
+}
+
+impl Reconnect {
+    fn request(&self) {
+        self.requested.set(true);
+        self.main_loop.quit();
+    }
+}
+
+/// Formats offered to PipeWire, most preferred first.
+const ACCEPTED_FORMATS: [AudioFormat; 6] = [
+    AudioFormat::S16P,
+    AudioFormat::S16LE,
+    AudioFormat::F32P,
+    AudioFormat::F32LE,
+    AudioFormat::S24P,
+    AudioFormat::S24LE,
+];
+
+fn pcm_format(format: AudioFormat, channels: usize) -> Option<PcmFormat> {
+    let (sample, planar) = match format {
+        AudioFormat::S16P => (SampleFormat::S16, true),
+        AudioFormat::S16LE => (SampleFormat::S16, false),
+        AudioFormat::F32P => (SampleFormat::F32, true),
+        AudioFormat::F32LE => (SampleFormat::F32, false),
+        AudioFormat::S24P => (SampleFormat::S24, true),
+        AudioFormat::S24LE => (SampleFormat::S24, false),
+        _ => return None,
+    };
+    Some(PcmFormat {
+        sample,
+        planar,
+        channels,
+    })
+}
+
+/// Wall clock time the audio of the current process cycle was captured, in
+/// microseconds since the Unix epoch, from the delay PipeWire reports, and
+/// the graph clock in microseconds, if PipeWire reports it.
+fn capture_time_us(stream: &pw::stream::StreamRef) -> (u64, Option<u64>) {
+    // SAFETY: pw_time is plain data, and the stream pointer is valid for the
+    // duration of the process callback.
+    let mut time: pw::sys::pw_time = unsafe { mem::zeroed() };
+    let result = unsafe {
+        pw::sys::pw_stream_get_time_n(
+            stream.as_raw_ptr(),
+            &mut time,
+            mem::size_of::<pw::sys::pw_time>(),
+        )
+    };
+    let delay = if result == 0 && time.rate.denom != 0 && time.delay > 0 {
+        Duration::from_secs_f64(time.delay as f64 * time.rate.num as f64 / time.rate.denom as f64)
+    } else {
+        Duration::ZERO
+    };
+    let graph_us = (result == 0 && time.rate.denom != 0).then(|| {
+        (time.ticks as u128 * time.rate.num as u128 * 1_000_000 / time.rate.denom as u128) as u64
+    });
+    let captured_at = SystemTime::now() - delay;
+    let captured_at_us = captured_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    (captured_at_us, graph_us)
+}
+
+fn spa_channel_position(name: &str) -> u32 {
+    match name {
+        "MONO" => pw::spa::sys::SPA_AUDIO_CHANNEL_MONO,
+        "FL" => pw::spa::sys::SPA_AUDIO_CHANNEL_FL,
+        "FR" => pw::spa::sys::SPA_AUDIO_CHANNEL_FR,
+        "FC" => pw::spa::sys::SPA_AUDIO_CHANNEL_FC,
+        "LFE" => pw::spa::sys::SPA_AUDIO_CHANNEL_LFE,
+        "RL" => pw::spa::sys::SPA_AUDIO_CHANNEL_RL,
+        "RR" => pw::spa::sys::SPA_AUDIO_CHANNEL_RR,
+        "SL" => pw::spa::sys::SPA_AUDIO_CHANNEL_SL,
+        "SR" => pw::spa::sys::SPA_AUDIO_CHANNEL_SR,
+        _ => pw::spa::sys::SPA_AUDIO_CHANNEL_UNKNOWN,
+    }
+}
+
+/// Serializes the EnumFormat param offered to PipeWire: every accepted format
+/// and any rate, preferring the defaults.
+fn format_param(config: &Config) -> Vec<u8> {
+    let mut audio_info = libspa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(ACCEPTED_FORMATS[0]);
+    audio_info.set_channels(config.layout.channel_count() as u32);
+    audio_info.set_rate(config.sample_rate);
+    let mut positions = [0; pw::spa::param::audio::MAX_CHANNELS];
+    for (position, name) in positions.iter_mut().zip(config.layout.channel_names()) {
+        *position = spa_channel_position(name);
+    }
+    audio_info.set_position(positions);
+
+    let mut properties: Vec<pw::spa::pod::Property> = audio_info.into();
+    for property in &mut properties {
+        if property.key == pw::spa::sys::SPA_FORMAT_AUDIO_rate {
+            property.value = pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Int(
+                pw::spa::utils::Choice(
+                    pw::spa::utils::ChoiceFlags::empty(),
+                    pw::spa::utils::ChoiceEnum::Range {
+                        default: config.sample_rate as i32,
+                        min: 1,
+                        max: i32::MAX,
+                    },
+                ),
+            ));
+        } else if property.key == pw::spa::sys::SPA_FORMAT_AUDIO_format {
+            property.value =
+                pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Id(pw::spa::utils::Choice(
+                    pw::spa::utils::ChoiceFlags::empty(),
+                    pw::spa::utils::ChoiceEnum::Enum {
+                        default: pw::spa::utils::Id(ACCEPTED_FORMATS[0].as_raw()),
+                        alternatives: ACCEPTED_FORMATS
+                            .iter()
+                            .map(|format| pw::spa::utils::Id(format.as_raw()))
+                            .collect(),
+                    },
+                )));
+        }
+    }
+    let obj = pw::spa::pod::Object {
+        type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+        id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+        properties,
+    };
+    pw::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(obj),
+    )
+    .unwrap()
+    .0
+    .into_inner()
+}
+
+fn stream_properties(
+    config: &Config,
+    sink: &SinkSpec,
+    capture_target: Option<&NodeInfo>,
+) -> pw::properties::Properties {
+    let mut props = pw::properties::properties! {
+        *pw::keys::AUDIO_CHANNELS => config.layout.channel_count().to_string(),
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::NODE_NAME => sink.node_name.as_str(),
+        *pw::keys::NODE_DESCRIPTION => sink.description.as_str(),
+        *pw::keys::NODE_LATENCY => format!("1000/{}", config.sample_rate),
+    };
+    match capture_target {
+        Some(target) => {
+            info!("Capturing {} ({})", target.name, target.description);
+            props.insert(*pw::keys::MEDIA_CATEGORY, "Capture");
+            props.insert(*pw::keys::TARGET_OBJECT, target.name.as_str());
+            if target.is_sink() {
+                props.insert(*pw::keys::STREAM_CAPTURE_SINK, "true");
+            }
+        }
+        None => {
+            props.insert(*pw::keys::MEDIA_CLASS, "Audio/Sink");
+            props.insert(*pw::keys::MEDIA_CATEGORY, "Playback");
+        }
+    }
+    props
+}
+
+/// Registers the callbacks feeding one sink's audio into its pipeline.
+fn add_sink_listener(
+    stream: &pw::stream::Stream,
+    sink_data: SinkData,
+) -> pw::stream::StreamListener<SinkData> {
+    stream
+        .add_local_listener_with_user_data(sink_data)
+        .state_changed(|_, user_data, _old, new| {
+            // A suspended sink's clock runs on without it, which is no xrun.
+            user_data.xruns.reset();
+            if let pw::stream::StreamState::Error(message) = new {
+                warn!("PipeWire stream failed: {}", message);
+                user_data.reconnect.request();
+            }
+        })
+        .param_changed(|_, user_data, id, param| {
+            let Some(param) = param else {
+                return;
+            };
+            if id != pw::spa::param::ParamType::Format.as_raw() {
+                return;
+            }
+            let mut audio_info = libspa::param::audio::AudioInfoRaw::new();
+            if audio_info.parse(param).is_err() {
+                return;
+            }
+            match pcm_format(audio_info.format(), audio_info.channels() as usize) {
+                Some(format) => {
+                    info!(
+                        "Negotiated {:?} audio at {} Hz",
+                        audio_info.format(),
+                        audio_info.rate()
+                    );
+                    user_data.format = format;
+                }
+                None => warn!("Unsupported format {:?}", audio_info.format()),
+            }
+            let rate = audio_info.rate();
+            user_data.capture_rate = rate;
+            user_data.xruns.reset();
+            user_data.levels = LevelAccumulator::new(user_data.format.channels, rate);
+            let sample_rate = user_data.sample_rate;
+            let resampler = |channels| {
+                if rate == sample_rate {
+                    return None;
+                }
+                match Resampler::new(rate, sample_rate, channels) {
+                    Ok(resampler) => Some(resampler),
+                    Err(e) => {
+                        warn!("Can't resample {rate} Hz audio: {e}");
+                        None
+                    }
+                }
+            };
+            user_data.resampler = resampler(user_data.downmix.outputs());
+            user_data.surround_resampler = user_data
+                .surround
+                .as_ref()
+                .and_then(|passthrough| resampler(passthrough.outputs()));
+        })
+        .process(move |stream, user_data| {
+            let (captured_at_us, graph_us) = capture_time_us(stream);
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                user_data.xruns.missing_buffer();
+                return;
+            };
+            let stride = user_data.format.stride();
+            let xruns = &user_data.xruns;
+            let blocks: Option<Vec<&[u8]>> = buffer
+                .datas_mut()
+                .iter_mut()
+                .map(|data| {
+                    let chunk = data.chunk();
+                    let size = chunk.size() as usize;
+                    let corrupted = chunk.flags().contains(ChunkFlags::CORRUPTED);
+                    let bytes: &[u8] = data.data().map_or(&[][..], |bytes| bytes);
+                    xruns
+                        .check_chunk(size, bytes.len(), stride, corrupted)
+                        .then(|| &bytes[..size])
+                })
+                .collect();
+            // A bad chunk leaves a gap, which the graph clock shows next cycle.
+            let Some(blocks) = blocks.filter(|blocks| !blocks.is_empty()) else {
+                return;
+            };
+
+            let planes = user_data.format.decode_planes(&blocks);
+            // An empty buffer is a gap too, once audio follows it.
+            let captured_frames = planes.first().map_or(0, Vec::len);
+            let gap_us = graph_us
+                .filter(|_| captured_frames > 0)
+                .map_or(0, |graph_us| {
+                    let rate = user_data.capture_rate;
+                    user_data.xruns.cycle(graph_us, captured_frames, rate)
+                });
+            let mut packet = Vec::new();
+            user_data.downmix.apply_planes(&planes, &mut packet);
+            let frames = packet.len() / user_data.downmix.outputs();
+            if let Some(resampler) = &mut user_data.resampler {
+                let mut resampled = Vec::new();
+                resampler.process(&packet, &mut resampled);
+                packet = resampled;
+            }
+            if user_data.dsp_filters.has_changed().unwrap_or(false) {
+                let filters = user_data.dsp_filters.borrow_and_update();
+                user_data.dsp =
+                    DspChain::new(&filters, user_data.sample_rate, user_data.downmix.outputs());
+            }
+            user_data.dsp.process(&mut packet);
+            let mut surround = Vec::new();
+            if let Some(passthrough) = &user_data.surround {
+                passthrough.apply_planes(&planes, &mut surround);
+                if let Some(resampler) = &mut user_data.surround_resampler {
+                    let mut resampled = Vec::new();
+                    resampler.process(&surround, &mut resampled);
+                    surround = resampled;
+                }
+            }
+            let audio = CapturedAudio {
+                captured_at_us,
+                samples: packet,
+                surround,
+                gap_us,
+            };
+            user_data.outputs.send(audio);
+
+            for (channel, plane) in planes.iter().enumerate() {
+                user_data.levels.add_samples(channel, plane);
+            }
+            if let Some(levels) = user_data.levels.advance(frames) {
+                let _ = user_data.outputs.level_sender.send(levels);
+            }
+        })
+        .register()
+        .expect("Couldn't register stream listener")
+}
+
+/// Creates and connects the stream of `sink`, capturing `capture_target` if
+/// given and exposing a virtual sink otherwise.
+fn connect_sink(
+    core: &pw::core::Core,
+    config: &Config,
+    sink: &SinkSpec,
+    capture_target: Option<&NodeInfo>,
+    outputs: SinkOutputs,
+    control: &ControlBus,
+    reconnect: Reconnect,
+) -> (pw::stream::Stream, pw::stream::StreamListener<SinkData>) {
+    let stream = pw::stream::Stream::new(
+        core,
+        "fake-audio-sink",
+        stream_properties(config, sink, capture_target),
+    )
+    .expect("Couldn't create PipeWire stream");
+    let sink_data = SinkData {
+        xruns: XrunDetector::new(outputs.glitches.clone()),
+        outputs,
+        downmix: config
+            .downmix_matrix()
+            .expect("Downmix matrix was validated with the config"),
+        levels: LevelAccumulator::new(config.layout.channel_count(), config.sample_rate),
+        format: pcm_format(ACCEPTED_FORMATS[0], config.layout.channel_count())
+            .expect("Default format is supported"),
+        sample_rate: config.sample_rate,
+        resampler: None,
+        surround: config
+            .surround
+            .then(|| DownmixMatrix::passthrough(config.layout)),
+        surround_resampler: None,
+        dsp: DspChain::new(
+            &control.dsp(),
+            config.sample_rate,
+            config.stream_channels() as usize,
+        ),
+        dsp_filters: control.subscribe_dsp(),
+        capture_rate: config.sample_rate,
+        reconnect,
+    };
+    let listener = add_sink_listener(&stream, sink_data);
+    let format_param = format_param(config);
+    let mut params = [pod::Pod::from_bytes(&format_param).unwrap()];
+    stream
+        .connect(
+            Direction::Input,
+            None,
+            pw::stream::StreamFlags::AUTOCONNECT
+                | pw::stream::StreamFlags::MAP_BUFFERS
+                | pw::stream::StreamFlags::RT_PROCESS,
+            &mut params,
+        )
+        .expect("Failed to connect stream");
+    (stream, listener)
+}
+
+/// Creates the virtual microphone, playing mono S16 audio at the configured
+/// rate from `receiver`.
+fn create_mic_source(
+    core: &pw::core::Core,
+    config: &Config,
+    receiver: Rc<BoundedReceiver<Vec<i16>>>,
+) -> (pw::stream::Stream, pw::stream::StreamListener<SourceData>) {
+    let stream = pw::stream::Stream::new(
+        core,
+        "remote-microphone",
+        pw::properties::properties! {
+            *pw::keys::MEDIA_CLASS => "Audio/Source",
+            *pw::keys::AUDIO_CHANNELS => "1",
+            *pw::keys::MEDIA_TYPE => "Audio",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Communication",
+            *pw::keys::NODE_NAME => format!("{}-mic", config.node_name),
+            *pw::keys::NODE_DESCRIPTION => "Remote Microphone",
+            *pw::keys::NODE_LATENCY => format!("1000/{}", config.sample_rate),
+        },
+    )
+    .expect("Couldn't create PipeWire microphone stream");
+    let source_data = SourceData {
+        receiver,
+        pending: VecDeque::new(),
+        max_pending: config.sample_rate as usize / 5,
+    };
+    let listener = stream
+        .add_local_listener_with_user_data(source_data)
+        .process(|stream, user_data| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return;
+            };
+            for samples in user_data.receiver.try_iter() {
+                user_data.pending.extend(samples);
+            }
+            let excess = user_data
+                .pending
+                .len()
+                .saturating_sub(user_data.max_pending);
+            user_data.pending.drain(..excess);
+
+            let requested = buffer.requested() as usize;
+            let data = &mut buffer.datas_mut()[0];
+            let stride = mem::size_of::<i16>();
+            let mut frames = 0;
+            if let Some(bytes) = data.data() {
+                frames = bytes.len() / stride;
+                if requested > 0 {
+                    frames = frames.min(requested);
+                }
+                for sample in bytes.chunks_exact_mut(stride).take(frames) {
+                    let value = user_data.pending.pop_front().unwrap_or(0);
+                    sample.copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            let chunk = data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = stride as i32;
+            *chunk.size_mut() = (frames * stride) as u32;
+        })
+        .register()
+        .expect("Couldn't register microphone listener");
+
+    let mut audio_info = libspa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(AudioFormat::S16LE);
+    audio_info.set_channels(1);
+    audio_info.set_rate(config.sample_rate);
+    let mut positions = [0; pw::spa::param::audio::MAX_CHANNELS];
+    positions[0] = pw::spa::sys::SPA_AUDIO_CHANNEL_MONO;
+    audio_info.set_position(positions);
+    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(pw::spa::pod::Object {
+            type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+            id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+            properties: audio_info.into(),
+        }),
+    )
+    .unwrap()
+    .0
+    .into_inner();
+    let mut params = [pod::Pod::from_bytes(&values).unwrap()];
+    stream
+        .connect(
+            Direction::Output,
+            None,
+            pw::stream::StreamFlags::AUTOCONNECT
+                | pw::stream::StreamFlags::MAP_BUFFERS
+                | pw::stream::StreamFlags::RT_PROCESS,
+            &mut params,
+        )
+        .expect("Failed to connect microphone stream");
+    (stream, listener)
+}
+
+/// Connects to the PipeWire daemon `config` names, retrying if `wait` and
+/// exiting otherwise.
+fn connect_pipewire(context: &pw::context::Context, config: &Config, wait: bool) -> pw::core::Core {
+    let remote = config.pipewire_remote();
+    let mut delay = PIPEWIRE_RETRY_MIN;
+    loop {
+        let properties = remote.as_ref().map(|remote| {
+            pw::properties::properties! {
+                *pw::keys::REMOTE_NAME => remote.as_str(),
+            }
+        });
+        match context.connect(properties) {
+            Ok(core) => return core,
+            Err(e) if wait => {
+                warn!(
+                    "Couldn't connect to PipeWire ({}), retrying in {:?}",
+                    e, delay
+                );
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(PIPEWIRE_RETRY_MAX);
+            }
+            Err(e) => {
+                eprintln!(
+                    "Couldn't connect to PipeWire ({}). Is it running? --wait-for-pipewire \
+                     keeps retrying, and --pipewire-remote picks another daemon.",
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+type SinkStream = (pw::stream::Stream, pw::stream::StreamListener<SinkData>);
+
+/// Everything a client gets from the PipeWire daemon, all gone when the
+/// daemon restarts.
+struct Connection {
+    core: pw::core::Core,
+    _node_watcher: NodeWatcher,
+    _core_listener: pw::core::Listener,
+    _context: pw::context::Context,
+}
+
+impl Connection {
+    /// Connects to the PipeWire daemon `config` names, retrying if `wait`,
+    /// and fills `nodes` from its registry.
+    fn new(
+        main_loop: &pw::main_loop::MainLoop,
+        config: &Config,
+        wait: bool,
+        nodes: SharedNodeList,
+        reconnect: Reconnect,
+    ) -> Self {
+        let context =
+            pw::context::Context::new(main_loop).expect("Couldn't create PipeWire Context");
+        let core = connect_pipewire(&context, config, wait);
+        let core_listener = core
+            .add_listener_local()
+            .error(move |id, _seq, _res, message| {
+                // Errors on the core itself mean the connection is gone.
+                if id == pw::core::PW_ID_CORE {
+                    warn!("Lost the PipeWire connection: {}", message);
+                    reconnect.request();
+                }
+            })
+            .register();
+        nodes.lock().expect("Node list lock poisoned").clear();
+        let node_watcher = NodeWatcher::new(&core, nodes);
+        roundtrip(main_loop, &core);
+        Self {
+            core,
+            _node_watcher: node_watcher,
+            _core_listener: core_listener,
+            _context: context,
+        }
+    }
+}
+
+/// A connection to PipeWire, with the streams capturing the sinks into the
+/// pipeline and the virtual microphone playing what clients send. The
+/// connection and streams are made again when the daemon restarts.
+pub struct Capture {
+    config: Arc<Config>,
+    main_loop: pw::main_loop::MainLoop,
+    reconnect: Reconnect,
+    /// Only `None` while connecting again.
+    connection: Option<Connection>,
+    nodes: SharedNodeList,
+    health: Option<Health>,
+    sinks: Vec<(SinkSpec, SinkOutputs)>,
+    /// The node captured without `--sink`s, which clients can change.
+    target: Rc<RefCell<Option<NodeInfo>>>,
+    control: Option<ControlBus>,
+    streams: Rc<RefCell<Vec<SinkStream>>>,
+    /// Node names to capture, sent from tokio to PipeWire's loop.
+    switch: Option<pw::channel::Receiver<Option<String>>>,
+    mic: Option<Rc<BoundedReceiver<Vec<i16>>>>,
+    mic_source: Option<(pw::stream::Stream, pw::stream::StreamListener<SourceData>)>,
+}
+
+impl Capture {
+    /// Connects to the PipeWire daemon `config` names and lists its audio
+    /// nodes.
+    pub fn connect(config: &Arc<Config>) -> Self {
+        pw::init();
+        let main_loop =
+            pw::main_loop::MainLoop::new(None).expect("Couldn't create PipeWire MainLoop");
+        let reconnect = Reconnect {
+            main_loop: main_loop.clone(),
+            requested: Rc::default(),
+        };
+        let nodes = Arc::new(Mutex::new(Vec::new()));
+        let connection = Connection::new(
+            &main_loop,
+            config,
+            config.wait_for_pipewire,
+            nodes.clone(),
+            reconnect.clone(),
+        );
+        Self {
+            config: config.clone(),
+            main_loop,
+            reconnect,
+            connection: Some(connection),
+            nodes,
+            health: None,
+            sinks: Vec::new(),
+            target: Rc::default(),
+            control: None,
+            streams: Rc::default(),
+            switch: None,
+            mic: None,
+            mic_source: None,
+        }
+    }
+
+    /// PipeWire's audio sinks and sources, kept up to date.
+    pub fn nodes(&self) -> SharedNodeList {
+        self.nodes.clone()
+    }
+
+    /// The node `--capture-node` picks, if given. Exits if none matches.
+    pub fn capture_target(&self) -> Option<NodeInfo> {
+        self.config.capture_node.as_ref().map(|pattern| {
+            let nodes = self.nodes.lock().expect("Node list lock poisoned");
+            match find_node(&nodes, pattern) {
+                Some(target) => target.clone(),
+                None => {
+                    eprintln!("No audio sink or source matches {pattern:?}. Available nodes:");
+                    print_nodes(&nodes);
+                    std::process::exit(1);
+                }
+            }
+        })
+    }
+
+    /// Tells `health` whether PipeWire is still connected.
+    pub fn watch_health(&mut self, health: Health) {
+        health.set_pipewire_connected(true);
+        self.health = Some(health);
+    }
+
+    /// Connects a stream for each of `sinks`, feeding its outputs, which
+    /// captures `target` if given and exposes a virtual sink otherwise.
+    /// Without `--sink`s, the stream follows the node `control` picks.
+    /// Must be called within a tokio runtime.
+    pub fn start(
+        &mut self,
+        sinks: Vec<(SinkSpec, SinkOutputs)>,
+        target: Option<&NodeInfo>,
+        control: &ControlBus,
+    ) {
+        self.sinks = sinks;
+        *self.target.borrow_mut() = target.cloned();
+        self.control = Some(control.clone());
+        self.connect_sinks();
+        if !self.config.sinks.is_empty() {
+            return;
+        }
+        // Capturing another node takes a new stream, made on PipeWire's loop.
+        let (capture_tx, capture_rx) = pw::channel::channel::<Option<String>>();
+        self.switch = Some(capture_rx);
+        let mut capture_node = control.subscribe_capture_node();
+        let _capture_handle = tokio::spawn(async move {
+            while capture_node.changed().await.is_ok() {
+                let name = capture_node.borrow_and_update().clone();
+                if capture_tx.send(name).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Plays the microphone audio from `receiver` into a virtual source.
+    pub fn play_mic(&mut self, receiver: BoundedReceiver<Vec<i16>>) {
+        let receiver = Rc::new(receiver);
+        self.mic_source = Some(create_mic_source(
+            self.core(),
+            &self.config,
+            receiver.clone(),
+        ));
+        self.mic = Some(receiver);
+    }
+
+    fn core(&self) -> &pw::core::Core {
+        &self
+            .connection
+            .as_ref()
+            .expect("Connected to PipeWire")
+            .core
+    }
+
+    /// Connects the streams of the sinks `start` was given.
+    fn connect_sinks(&self) {
+        let Some(control) = &self.control else {
+            return;
+        };
+        let target = self.target.borrow();
+        *self.streams.borrow_mut() = self
+            .sinks
+            .iter()
+            .map(|(sink, outputs)| {
+                connect_sink(
+                    self.core(),
+                    &self.config,
+                    sink,
+                    target.as_ref(),
+                    outputs.clone(),
+                    control,
+                    self.reconnect.clone(),
+                )
+            })
+            .collect();
+    }
+
+    /// Drops the streams and the lost connection, then connects again, with
+    /// the streams captured and played as before.
+    fn connect_again(&mut self) {
+        self.streams.borrow_mut().clear();
+        self.mic_source = None;
+        self.connection = None;
+        self.connection = Some(Connection::new(
+            &self.main_loop,
+            &self.config,
+            true,
+            self.nodes.clone(),
+            self.reconnect.clone(),
+        ));
+        info!("Reconnected to PipeWire");
+        self.connect_sinks();
+        if let Some(mic) = &self.mic {
+            self.mic_source = Some(create_mic_source(self.core(), &self.config, mic.clone()));
+        }
+    }
+
+    /// Runs PipeWire's loop, connecting again with a growing delay whenever
+    /// the daemon goes away, until it quits. Then disconnects the streams.
+    pub fn run(mut self) {
+        let mut delay = PIPEWIRE_RETRY_MIN;
+        loop {
+            let connected_at = Instant::now();
+            let core = self.core().clone();
+            let capture_receiver = self.switch.take().map(|receiver| {
+                let (config, nodes, streams, target, reconnect) = (
+                    self.config.clone(),
+                    self.nodes.clone(),
+                    self.streams.clone(),
+                    self.target.clone(),
+                    self.reconnect.clone(),
+                );
+                let (sink, outputs) = self.sinks[0].clone();
+                let control = self
+                    .control
+                    .clone()
+                    .expect("Capture switches are set up by start");
+                receiver.attach(self.main_loop.loop_(), move |name| {
+                    let node = match name {
+                        Some(name) => {
+                            let nodes = nodes.lock().expect("Node list lock poisoned");
+                            match nodes.iter().find(|node| node.name == name) {
+                                Some(node) => Some(node.clone()),
+                                None => {
+                                    warn!("Node {} is gone, can't capture it", name);
+                                    return;
+                                }
+                            }
+                        }
+                        None => {
+                            info!("Exposing the {} sink again", sink.description);
+                            None
+                        }
+                    };
+                    let mut streams = streams.borrow_mut();
+                    streams[0]
+                        .0
+                        .disconnect()
+                        .expect("Couldn't disconnect stream");
+                    streams[0] = connect_sink(
+                        &core,
+                        &config,
+                        &sink,
+                        node.as_ref(),
+                        outputs.clone(),
+                        &control,
+                        reconnect.clone(),
+                    );
+                    *target.borrow_mut() = node;
+                })
+            });
+            // The connection may have been lost before the loop ran.
+            if !self.reconnect.requested.get() {
+                self.main_loop.run();
+            }
+            self.switch = capture_receiver.map(|receiver| receiver.deattach());
+            if !self.reconnect.requested.replace(false) {
+                break;
+            }
+            if let Some(health) = &self.health {
+                health.set_pipewire_connected(false);
+            }
+            // Connections that last start over at the shortest delay.
+            if connected_at.elapsed() > PIPEWIRE_RETRY_MAX {
+                delay = PIPEWIRE_RETRY_MIN;
+            }
+            warn!("Reconnecting to PipeWire in {:?}", delay);
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(PIPEWIRE_RETRY_MAX);
+            self.connect_again();
+            if let Some(health) = &self.health {
+                health.set_pipewire_connected(true);
+            }
+        }
+        for (stream, _listener) in self.streams.borrow().iter() {
+            stream.disconnect().expect("Couldn't disconnect stream");
+        }
+        if let Some((stream, _listener)) = &self.mic_source {
+            stream.disconnect().expect("Couldn't disconnect stream");
+        }
+    }
+}
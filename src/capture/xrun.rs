@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// How often `spawn_xrun_task` warns about new glitches.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Counts what went wrong capturing a sink, as opposed to on the network:
+/// cycles PipeWire ran without handing over a buffer, chunks that couldn't
+/// hold whole frames of audio, and gaps in the graph clock where audio went
+/// missing.
+#[derive(Default)]
+pub struct CaptureGlitches {
+    pub sink: String,
+    missing_buffers: AtomicU64,
+    bad_chunks: AtomicU64,
+    gaps: AtomicU64,
+    gap_us: AtomicU64,
+}
+
+impl CaptureGlitches {
+    pub fn new(sink: impl Into<String>) -> Arc<Self> {
+        Arc::new(Self {
+            sink: sink.into(),
+            ..Default::default()
+        })
+    }
+
+    pub fn missing_buffers(&self) -> u64 {
+        self.missing_buffers.load(Ordering::Relaxed)
+    }
+
+    pub fn bad_chunks(&self) -> u64 {
+        self.bad_chunks.load(Ordering::Relaxed)
+    }
+
+    pub fn gaps(&self) -> u64 {
+        self.gaps.load(Ordering::Relaxed)
+    }
+
+    /// Audio lost in all the gaps.
+    pub fn gap_us(&self) -> u64 {
+        self.gap_us.load(Ordering::Relaxed)
+    }
+}
+
+/// Watches a sink's process callback for xruns. It only touches atomics, so
+/// it's safe on PipeWire's realtime thread.
+pub struct XrunDetector {
+    glitches: Arc<CaptureGlitches>,
+    /// Where the graph clock should be next cycle, in microseconds.
+    next_graph_us: Option<u64>,
+}
+
+impl XrunDetector {
+    pub fn new(glitches: Arc<CaptureGlitches>) -> Self {
+        Self {
+            glitches,
+            next_graph_us: None,
+        }
+    }
+
+    /// The cycle had no buffer to take. A gap shows up next cycle.
+    pub fn missing_buffer(&self) {
+        self.glitches
+            .missing_buffers
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Checks a chunk of `size` bytes from a block holding `max_size`, which
+    /// should hold whole frames of `stride` bytes. Bad ones are counted.
+    pub fn check_chunk(
+        &self,
+        size: usize,
+        max_size: usize,
+        stride: usize,
+        corrupted: bool,
+    ) -> bool {
+        let ok = !corrupted && size <= max_size && size.is_multiple_of(stride);
+        if !ok {
+            self.glitches.bad_chunks.fetch_add(1, Ordering::Relaxed);
+        }
+        ok
+    }
+
+    /// Follows the graph clock, at `graph_us` this cycle, over a buffer of
+    /// `frames` at `rate`, and returns how much audio went missing before
+    /// this one. Clock jumps within half the buffer are jitter, not gaps.
+    pub fn cycle(&mut self, graph_us: u64, frames: usize, rate: u32) -> u64 {
+        let duration_us = frames as u64 * 1_000_000 / rate.max(1) as u64;
+        let gap_us = match self.next_graph_us {
+            Some(expected) if graph_us > expected + duration_us / 2 => graph_us - expected,
+            _ => 0,
+        };
+        if gap_us > 0 {
+            self.glitches.gaps.fetch_add(1, Ordering::Relaxed);
+            self.glitches.gap_us.fetch_add(gap_us, Ordering::Relaxed);
+        }
+        self.next_graph_us = Some(graph_us + duration_us);
+        gap_us
+    }
+
+    /// Forgets the clock, as the stream paused or changed format.
+    pub fn reset(&mut self) {
+        self.next_graph_us = None;
+    }
+}
+
+/// Warns about the sinks whose capture glitched, every `REPORT_INTERVAL`.
+pub fn spawn_xrun_task(glitches: Vec<Arc<CaptureGlitches>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut reported = vec![[0; 3]; glitches.len()];
+        loop {
+            tokio::time::sleep(REPORT_INTERVAL).await;
+            for (sink, reported) in glitches.iter().zip(&mut reported) {
+                let counts = [sink.missing_buffers(), sink.bad_chunks(), sink.gaps()];
+                if counts != *reported {
+                    warn!(
+                        "Capturing {} glitched in {:?}: {} missing buffers, {} bad \
+                         chunks and {} gaps ({} ms of audio lost in all)",
+                        sink.sink,
+                        REPORT_INTERVAL,
+                        counts[0] - reported[0],
+                        counts[1] - reported[1],
+                        counts[2] - reported[2],
+                        sink.gap_us() / 1000
+                    );
+                    *reported = counts;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_jumps_beyond_jitter_count_as_gaps() {
+        let glitches = CaptureGlitches::new("default");
+        let mut detector = XrunDetector::new(glitches.clone());
+        // 1024 frames at 48 kHz last 21333 us.
+        assert_eq!(detector.cycle(1_000_000, 1024, 48_000), 0);
+        assert_eq!(detector.cycle(1_021_400, 1024, 48_000), 0);
+        // Two cycles went by without a buffer.
+        detector.missing_buffer();
+        detector.missing_buffer();
+        assert_eq!(detector.cycle(1_085_400, 1024, 48_000), 42_667);
+        assert_eq!(detector.cycle(1_106_733, 1024, 48_000), 0);
+        detector.reset();
+        assert_eq!(detector.cycle(5_000_000, 1024, 48_000), 0);
+        assert_eq!(glitches.missing_buffers(), 2);
+        assert_eq!(glitches.gaps(), 1);
+        assert_eq!(glitches.gap_us(), 42_667);
+
+        assert!(detector.check_chunk(4096, 8192, 4, false));
+        assert!(!detector.check_chunk(4098, 8192, 4, false));
+        assert!(!detector.check_chunk(4096, 2048, 4, false));
+        assert!(!detector.check_chunk(4096, 8192, 4, true));
+        assert_eq!(glitches.bad_chunks(), 3);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::config::{Config, FileOptions};
+use crate::control::ControlBus;
+use crate::logging;
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// How long the `--config` file has to stay unchanged before it's read, so
+/// an editor saving it in several writes has it read once, complete.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watches the directory holding `path`, since editors often replace a file
+/// rather than write to it, sending on `changes` whenever `path` changes.
+fn watch(path: &Path, changes: mpsc::UnboundedSender<()>) -> notify::Result<impl Watcher> {
+    let name = path.file_name().map(ToOwned::to_owned);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if event.kind.is_access() {
+            return;
+        }
+        if event
+            .paths
+            .iter()
+            .any(|changed| changed.file_name() == name.as_deref())
+        {
+            let _ = changes.send(());
+        }
+    })?;
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+/// Applies the options whose arguments differ between `old` and `new` from
+/// `config`: the encoder settings, DSP filters, startup burst, log level,
+/// access key and password. Returns the rest, which take a restart.
+fn apply_changes(
+    config: &Config,
+    old: &FileOptions,
+    new: &FileOptions,
+    control: &ControlBus,
+) -> Vec<String> {
+    let mut restart = Vec::new();
+    let mut access = control.access();
+    let changed: BTreeSet<&String> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|name| old.get(*name) != new.get(*name))
+        .collect();
+    for name in changed {
+        let settings = config.encoder_settings();
+        let result = match name.as_str() {
+            "bitrate" => control.update_encoder_settings(|s| s.bitrate = settings.bitrate),
+            "tier-bitrates" => control
+                .update_encoder_settings(|s| s.lower_tier_bitrates = settings.lower_tier_bitrates),
+            "cbr" => control.update_encoder_settings(|s| s.vbr = settings.vbr),
+            "complexity" => control.update_encoder_settings(|s| s.complexity = settings.complexity),
+            "fec" => control.update_encoder_settings(|s| s.fec = settings.fec),
+            "dtx" => control.update_encoder_settings(|s| s.dtx = settings.dtx),
+            "packet-loss-percent" => control
+                .update_encoder_settings(|s| s.packet_loss_percent = settings.packet_loss_percent),
+            "volume" => control.update_encoder_settings(|s| s.volume = settings.volume),
+            "dsp" => {
+                control.set_dsp(config.dsp_filters.clone());
+                Ok(())
+            }
+            "startup-burst-ms" => {
+                control.set_startup_burst_ms(config.startup_burst_ms);
+                Ok(())
+            }
+            "log-level" => {
+                logging::set_level(config.log_level);
+                Ok(())
+            }
+            "access-key" => {
+                access.key = Arc::from(config.access_key.as_str());
+                Ok(())
+            }
+            "password" => {
+                access.password = config.password.as_deref().map(Arc::from);
+                Ok(())
+            }
+            _ => {
+                restart.push(name.clone());
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            warn!("Couldn't apply {}: {:#}", name, e);
+        }
+    }
+    control.set_access(access);
+    restart
+}
+
+/// Watches the `--config` file, applying what can change while streaming and
+/// warning about changes to the rest. A file that no longer parses leaves
+/// everything as it was.
+pub fn spawn_config_reload_task(
+    config: Arc<Config>,
+    options: FileOptions,
+    control: ControlBus,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let path = config
+            .config
+            .clone()
+            .expect("Only --config files are watched");
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let mut options = options;
+        let (changes_tx, mut changes) = mpsc::unbounded_channel();
+        let _watcher = match watch(&path, changes_tx) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("Couldn't watch {}: {}", path.display(), e);
+                return;
+            }
+        };
+        while changes.recv().await.is_some() {
+            // Changes keep coming while the file is written.
+            while let Ok(change) = tokio::time::timeout(DEBOUNCE, changes.recv()).await {
+                if change.is_none() {
+                    return;
+                }
+            }
+            let (config, new_options) = match Config::load(&args) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!("Ignoring the changes to {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            info!("Reloaded {}", path.display());
+            for name in apply_changes(&config, &options, &new_options, &control) {
+                warn!(
+                    "{} changed in {}, which takes a restart",
+                    name,
+                    path.display()
+                );
+            }
+            options = new_options;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::Access;
+    use crate::logging::LogLevel;
+    use std::fs;
+
+    #[test]
+    fn live_options_apply_and_the_rest_ask_for_a_restart() {
+        let path = std::env::temp_dir().join(format!("reload-test-{}.toml", rand::random::<u32>()));
+        let args: Vec<OsString> = ["pwtester", "--config", path.to_str().unwrap(), "--fec"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let write = |text: &str| fs::write(&path, text).unwrap();
+        write("bitrate = 64000\nhttp-port = 8000\naccess-key = \"old\"\nfec = false\n");
+        let (config, old) = Config::load(&args).unwrap();
+        assert_eq!(config.bitrate, Some(64_000));
+        // The command line overrides the file.
+        assert!(config.fec && !old.contains_key("fec"));
+        let control = ControlBus::new(
+            config.encoder_settings(),
+            Vec::new(),
+            None,
+            Access {
+                key: Arc::from("old"),
+                password: None,
+            },
+        );
+
+        write(
+            "bitrate = 96000\nhttp-port = 8001\naccess-key = \"new\"\nvolume = 50\n\
+             startup-burst-ms = 100\nlog-level = \"warn\"\n",
+        );
+        let startup_burst_ms = control.subscribe_startup_burst_ms();
+        let (config, new) = Config::load(&args).unwrap();
+        assert_eq!(apply_changes(&config, &old, &new, &control), ["http-port"]);
+        let settings = control.encoder_settings();
+        assert_eq!(settings.bitrate, Some(96_000));
+        assert_eq!(settings.volume, 50);
+        assert!(settings.fec);
+        assert_eq!(&*control.access().key, "new");
+        assert_eq!(*startup_burst_ms.borrow(), 100);
+        assert!(!logging::enabled(LogLevel::Info));
+        logging::set_level(LogLevel::Info);
+
+        write("bitrate = \"lots\"\n");
+        assert!(Config::load(&args).is_err());
+        write("no-such-option = 1\n");
+        assert!(Config::load(&args).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_to_the_file_are_seen() {
+        let directory = std::env::temp_dir().join(format!("reload-watch-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("config.toml");
+        fs::write(&path, "bitrate = 64000\n").unwrap();
+        let (changes_tx, mut changes) = mpsc::unbounded_channel();
+        let _watcher = watch(&path, changes_tx).unwrap();
+        fs::write(directory.join("other.toml"), "").unwrap();
+        fs::write(&path, "bitrate = 96000\n").unwrap();
+        let change = tokio::time::timeout(Duration::from_secs(5), changes.recv()).await;
+        assert_eq!(change.unwrap(), Some(()));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use crate::capture::dsp::Filter;
+use crate::encode::compress::EncoderSettings;
+use anyhow::Result;
+use std::sync::Arc;
+use streaming_protocol::NowPlaying;
+use tokio::sync::watch;
+
+/// What clients need to connect: the access key their session tokens are
+/// signed with, and the password, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Access {
+    pub key: Arc<str>,
+    pub password: Option<Arc<str>>,
+}
+
+/// Shared control/event bus for the streaming pipeline.
+///
+/// Frontends (D-Bus, HTTP, ...) flip state here; the pipeline tasks hold
+/// receivers and react to changes.
+#[derive(Clone)]
+pub struct ControlBus {
+    paused: Arc<watch::Sender<bool>>,
+    encoder_settings: Arc<watch::Sender<EncoderSettings>>,
+    /// The filters applied to every sink before encoding.
+    dsp: Arc<watch::Sender<Vec<Filter>>>,
+    /// Bumped whenever new certificate files are written.
+    certificate: Arc<watch::Sender<u64>>,
+    /// Bumped whenever the encoders should start afresh.
+    restart: Arc<watch::Sender<u64>>,
+    /// Name of the node captured instead of exposing a virtual sink.
+    capture_node: Arc<watch::Sender<Option<String>>>,
+    /// The track the media players on the session bus play.
+    now_playing: Arc<watch::Sender<NowPlaying>>,
+    /// Replaced as the `--config` file changes.
+    access: Arc<watch::Sender<Access>>,
+    /// Audio sent to clients ahead of the live audio as they connect.
+    startup_burst_ms: Arc<watch::Sender<u32>>,
+}
+
+impl ControlBus {
+    pub fn new(
+        encoder_settings: EncoderSettings,
+        dsp: Vec<Filter>,
+        capture_node: Option<String>,
+        access: Access,
+    ) -> Self {
+        let (paused, _) = watch::channel(false);
+        let (encoder_settings, _) = watch::channel(encoder_settings);
+        let (dsp, _) = watch::channel(dsp);
+        let (certificate, _) = watch::channel(0);
+        let (restart, _) = watch::channel(0);
+        let (capture_node, _) = watch::channel(capture_node);
+        let (now_playing, _) = watch::channel(NowPlaying::default());
+        let (access, _) = watch::channel(access);
+        let (startup_burst_ms, _) = watch::channel(0);
+        Self {
+            paused: Arc::new(paused),
+            encoder_settings: Arc::new(encoder_settings),
+            dsp: Arc::new(dsp),
+            certificate: Arc::new(certificate),
+            restart: Arc::new(restart),
+            capture_node: Arc::new(capture_node),
+            now_playing: Arc::new(now_playing),
+            access: Arc::new(access),
+            startup_burst_ms: Arc::new(startup_burst_ms),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        let changed = self.paused.send_if_modified(|current| {
+            let changed = *current != paused;
+            *current = paused;
+            changed
+        });
+        if changed {
+            log_paused(paused);
+        }
+    }
+
+    /// Flips the paused state and returns the new value.
+    pub fn toggle_paused(&self) -> bool {
+        let mut paused = false;
+        self.paused.send_modify(|current| {
+            *current = !*current;
+            paused = *current;
+        });
+        log_paused(paused);
+        paused
+    }
+
+    pub fn subscribe_paused(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+
+    pub fn encoder_settings(&self) -> EncoderSettings {
+        *self.encoder_settings.borrow()
+    }
+
+    /// Applies `update` to the encoder settings, leaving them untouched if the
+    /// result is invalid.
+    pub fn update_encoder_settings(&self, update: impl FnOnce(&mut EncoderSettings)) -> Result<()> {
+        let mut settings = self.encoder_settings();
+        update(&mut settings);
+        settings.validate()?;
+        if self.encoder_settings.send_replace(settings) != settings {
+            info!("Encoder settings: {:?}", settings);
+        }
+        Ok(())
+    }
+
+    pub fn subscribe_encoder_settings(&self) -> watch::Receiver<EncoderSettings> {
+        self.encoder_settings.subscribe()
+    }
+
+    pub fn dsp(&self) -> Vec<Filter> {
+        self.dsp.borrow().clone()
+    }
+
+    /// Replaces the filter chain, which the caller has validated.
+    pub fn set_dsp(&self, filters: Vec<Filter>) {
+        info!("DSP filters: {:?}", filters);
+        self.dsp.send_replace(filters);
+    }
+
+    pub fn subscribe_dsp(&self) -> watch::Receiver<Vec<Filter>> {
+        self.dsp.subscribe()
+    }
+
+    /// Tells the servers to reload the certificate files.
+    pub fn certificate_renewed(&self) {
+        self.certificate.send_modify(|generation| *generation += 1);
+    }
+
+    pub fn subscribe_certificate(&self) -> watch::Receiver<u64> {
+        self.certificate.subscribe()
+    }
+
+    /// Tells the encoders to drop what they buffered and start afresh.
+    pub fn restart_encoders(&self) {
+        info!("Restarting the encoders");
+        self.restart.send_modify(|generation| *generation += 1);
+    }
+
+    pub fn subscribe_restart(&self) -> watch::Receiver<u64> {
+        self.restart.subscribe()
+    }
+
+    pub fn capture_node(&self) -> Option<String> {
+        self.capture_node.borrow().clone()
+    }
+
+    /// Captures the node named `node`, which the caller has checked exists,
+    /// or exposes the virtual sink again with `None`.
+    pub fn set_capture_node(&self, node: Option<String>) {
+        self.capture_node.send_if_modified(|current| {
+            let changed = *current != node;
+            *current = node;
+            changed
+        });
+    }
+
+    pub fn subscribe_capture_node(&self) -> watch::Receiver<Option<String>> {
+        self.capture_node.subscribe()
+    }
+
+    pub fn set_now_playing(&self, track: NowPlaying) {
+        self.now_playing.send_if_modified(|current| {
+            if *current == track {
+                return false;
+            }
+            if let Some(title) = &track.title {
+                info!("Now playing {}", title);
+            }
+            *current = track;
+            true
+        });
+    }
+
+    pub fn subscribe_now_playing(&self) -> watch::Receiver<NowPlaying> {
+        self.now_playing.subscribe()
+    }
+
+    pub fn access(&self) -> Access {
+        self.access.borrow().clone()
+    }
+
+    /// Replaces the access key and password. Sessions already running keep
+    /// going, but new ones need the new ones.
+    pub fn set_access(&self, access: Access) {
+        self.access.send_if_modified(|current| {
+            if *current == access {
+                return false;
+            }
+            if current.key != access.key {
+                info!("Access key changed to {}", access.key);
+            }
+            if current.password != access.password {
+                info!("Password changed");
+            }
+            *current = access;
+            true
+        });
+    }
+
+    /// Sets how much audio new sessions get at once, which the encoders keep
+    /// from then on.
+    pub fn set_startup_burst_ms(&self, ms: u32) {
+        self.startup_burst_ms.send_replace(ms);
+    }
+
+    pub fn subscribe_startup_burst_ms(&self) -> watch::Receiver<u32> {
+        self.startup_burst_ms.subscribe()
+    }
+}
+
+fn log_paused(paused: bool) {
+    info!("Streaming {}", if paused { "paused" } else { "resumed" });
+}
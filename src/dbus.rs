@@ -0,0 +1,144 @@
+use crate::control::ControlBus;
+use crate::encode::compress::EncoderSettings;
+use tokio::task::JoinHandle;
+use zbus::fdo;
+use zbus::object_server::SignalEmitter;
+
+pub const DBUS_NAME: &str = "io.github.actuday6418.PipewireStreaming";
+pub const DBUS_PATH: &str = "/io/github/actuday6418/PipewireStreaming";
+
+struct StreamControl {
+    control: ControlBus,
+}
+
+impl StreamControl {
+    fn update_encoder_settings(
+        &self,
+        update: impl FnOnce(&mut EncoderSettings),
+    ) -> fdo::Result<()> {
+        self.control
+            .update_encoder_settings(update)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+    }
+}
+
+#[zbus::interface(name = "io.github.actuday6418.PipewireStreaming")]
+impl StreamControl {
+    async fn pause(&self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) -> fdo::Result<()> {
+        self.control.set_paused(true);
+        Ok(self.paused_changed(&emitter).await?)
+    }
+
+    async fn resume(&self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) -> fdo::Result<()> {
+        self.control.set_paused(false);
+        Ok(self.paused_changed(&emitter).await?)
+    }
+
+    /// Returns the new paused state.
+    async fn toggle_pause(
+        &self,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<bool> {
+        let paused = self.control.toggle_paused();
+        self.paused_changed(&emitter).await?;
+        Ok(paused)
+    }
+
+    #[zbus(property)]
+    fn paused(&self) -> bool {
+        self.control.is_paused()
+    }
+
+    /// Opus bitrate in bits per second, 0 to let the encoder pick.
+    #[zbus(property)]
+    fn bitrate(&self) -> i32 {
+        self.control.encoder_settings().bitrate.unwrap_or(0)
+    }
+
+    #[zbus(property)]
+    fn set_bitrate(&self, bitrate: i32) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| {
+            settings.bitrate = (bitrate != 0).then_some(bitrate)
+        })
+    }
+
+    #[zbus(property)]
+    fn vbr(&self) -> bool {
+        self.control.encoder_settings().vbr
+    }
+
+    #[zbus(property)]
+    fn set_vbr(&self, vbr: bool) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| settings.vbr = vbr)
+    }
+
+    #[zbus(property)]
+    fn complexity(&self) -> i32 {
+        self.control.encoder_settings().complexity
+    }
+
+    #[zbus(property)]
+    fn set_complexity(&self, complexity: i32) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| settings.complexity = complexity)
+    }
+
+    #[zbus(property)]
+    fn fec(&self) -> bool {
+        self.control.encoder_settings().fec
+    }
+
+    #[zbus(property)]
+    fn set_fec(&self, fec: bool) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| settings.fec = fec)
+    }
+
+    #[zbus(property)]
+    fn dtx(&self) -> bool {
+        self.control.encoder_settings().dtx
+    }
+
+    #[zbus(property)]
+    fn set_dtx(&self, dtx: bool) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| settings.dtx = dtx)
+    }
+
+    #[zbus(property)]
+    fn packet_loss_percent(&self) -> i32 {
+        self.control.encoder_settings().packet_loss_percent
+    }
+
+    #[zbus(property)]
+    fn set_packet_loss_percent(&self, percent: i32) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| settings.packet_loss_percent = percent)
+    }
+
+    /// Volume the audio is encoded at, in percent.
+    #[zbus(property)]
+    fn volume(&self) -> u8 {
+        self.control.encoder_settings().volume
+    }
+
+    #[zbus(property)]
+    fn set_volume(&self, percent: u8) -> fdo::Result<()> {
+        self.update_encoder_settings(|settings| settings.volume = percent)
+    }
+}
+
+pub fn spawn_dbus_task(control: ControlBus) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let connection = async {
+            zbus::connection::Builder::session()?
+                .name(DBUS_NAME)?
+                .serve_at(DBUS_PATH, StreamControl { control })?
+                .build()
+                .await
+        };
+        match connection.await {
+            Ok(_connection) => {
+                info!("D-Bus control available at {DBUS_NAME} {DBUS_PATH}");
+                std::future::pending::<()>().await;
+            }
+            Err(e) => warn!("D-Bus control unavailable: {e}"),
+        }
+    })
+}
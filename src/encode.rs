@@ -0,0 +1,98 @@
+pub mod aac;
+pub mod bit_writer;
+pub mod compress;
+pub mod decompress;
+pub mod flac;
+pub mod opus_encoder;
+pub mod packet_history;
+pub mod recent_packets;
+
+use crate::bounded::{self, BoundedSender, Overflow};
+use crate::config::{Config, SinkSpec};
+use crate::control::ControlBus;
+use crate::health::Health;
+use compress::{CapturedAudio, EncodedPacket, TierDemand, spawn_compress_task};
+use packet_history::{PacketHistory, spawn_packet_history_task};
+use recent_packets::{RecentPackets, spawn_recent_packets_task};
+use std::sync::Arc;
+use streaming_protocol::Codec;
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Process cycles of audio queued for each encoder, over a second at
+/// PipeWire's usual quantum of 1024 frames. The oldest go first.
+pub const CAPTURED_AUDIO_CAPACITY: usize = 64;
+/// Encoded packets queued for each of the encoder's listeners.
+const ENCODED_PACKETS_CAPACITY: usize = 200;
+
+/// A sink's audio in one codec.
+pub struct CodecPackets {
+    pub codec: Codec,
+    pub receiver: broadcast::Receiver<EncodedPacket>,
+    /// Asks the codec's compress task for the bitrate tiers clients need.
+    pub tier_demand: mpsc::UnboundedSender<TierDemand>,
+    /// Whether the compress task stopped for `--idle-after-secs` of silence.
+    pub idle: watch::Receiver<bool>,
+    /// The last `--startup-burst-ms` of audio.
+    pub recent: RecentPackets,
+    /// The last `--retransmit-history` packets.
+    pub history: PacketHistory,
+}
+
+/// Encodes a sink's audio in one codec, on a task of its own.
+pub struct Encoder {
+    /// Takes the sink's audio as it's captured.
+    pub input: BoundedSender<CapturedAudio>,
+    /// What's encoded, for the transports to send.
+    pub output: CodecPackets,
+}
+
+impl Encoder {
+    /// Starts encoding `sink` in `codec` on the current tokio runtime,
+    /// following the settings on `control`.
+    pub fn spawn(
+        config: &Arc<Config>,
+        sink: &SinkSpec,
+        codec: Codec,
+        control: &ControlBus,
+        health: &Health,
+    ) -> Self {
+        let (input, raw_packet_rx) = bounded::channel(
+            format!("The {} encoder of {}", codec, sink.id),
+            CAPTURED_AUDIO_CAPACITY,
+            Overflow::DropOldest,
+        );
+        let (compressed_packet_tx, receiver) = broadcast::channel(ENCODED_PACKETS_CAPACITY);
+        let (tier_demand, tier_demand_rx) = mpsc::unbounded_channel();
+        let (idle_tx, idle) = watch::channel(false);
+        let recent = RecentPackets::new(control.subscribe_startup_burst_ms());
+        let _recent_handle = spawn_recent_packets_task(recent.clone(), receiver.resubscribe());
+        let history = PacketHistory::new(config.retransmit_history);
+        if config.retransmit_history > 0 {
+            let _history_handle =
+                spawn_packet_history_task(history.clone(), receiver.resubscribe());
+        }
+        let _worker_handle = spawn_compress_task(
+            config.clone(),
+            codec,
+            raw_packet_rx,
+            compressed_packet_tx,
+            control.subscribe_paused(),
+            idle_tx,
+            tier_demand_rx,
+            control.subscribe_encoder_settings(),
+            control.subscribe_restart(),
+            health.encoder(format!("{} encoder of {}", codec, sink.id)),
+        );
+        Self {
+            input,
+            output: CodecPackets {
+                codec,
+                receiver,
+                tier_demand,
+                idle,
+                recent,
+                history,
+            },
+        }
+    }
+}
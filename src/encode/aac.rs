@@ -0,0 +1,483 @@
+use crate::encode::bit_writer::BitWriter;
+use crate::encode::compress::{AudioEncoder, EncoderSettings};
+use anyhow::Result;
+use bytes::Bytes;
+use opus::Bitrate;
+use rustfft::num_complex::Complex32;
+use rustfft::{Fft, FftPlanner};
+use std::f32::consts::PI;
+use std::sync::Arc;
+use streaming_protocol::Codec;
+
+/// Samples per channel in one AAC frame.
+pub const FRAME_LEN: usize = streaming_protocol::AAC_FRAME_SAMPLES as usize;
+/// Sample rates sharing the scalefactor bands below.
+pub const SAMPLE_RATES: [u32; 2] = [44_100, 48_000];
+/// Bitrate per channel when none is configured.
+const DEFAULT_CHANNEL_BITRATE: i32 = 64_000;
+/// Most bits one channel of a frame may take.
+const MAX_CHANNEL_FRAME_BITS: usize = 6144;
+/// Offsets of the scalefactor bands of long windows at 44.1 and 48 kHz.
+const BAND_OFFSETS: [usize; 50] = [
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 48, 56, 64, 72, 80, 88, 96, 108, 120, 132, 144, 160,
+    176, 196, 216, 240, 264, 292, 320, 352, 384, 416, 448, 480, 512, 544, 576, 608, 640, 672, 704,
+    736, 768, 800, 832, 864, 896, 928, 1024,
+];
+/// Scalefactors are applied relative to this.
+const SCALEFACTOR_OFFSET: i32 = 100;
+/// Largest magnitude the escape codebook codes.
+const MAX_QUANTIZED: f32 = 8191.0;
+/// Magnitudes from here up are followed by an escape sequence.
+const ESCAPE: u32 = 16;
+const ZERO_CODEBOOK: u64 = 0;
+const ESC_CODEBOOK: u64 = 11;
+/// Section lengths of long windows are coded in 5-bit steps, this value
+/// meaning that another step follows.
+const SECTION_ESCAPE: usize = 31;
+const ID_SCE: u64 = 0;
+const ID_CPE: u64 = 1;
+const ID_END: u64 = 7;
+
+/// Spectral codebook 11 of ISO/IEC 14496-3, indexed by 17 * y + z for the
+/// magnitudes y and z of a pair of coefficients, 16 standing for an escape.
+#[rustfmt::skip]
+const ESC_CODEBOOK_LENS: [u8; 289] = [
+     4,  5,  6,  7,  8,  8,  9, 10, 10, 10, 11, 11, 12, 11, 12, 12, 10,
+     5,  4,  5,  6,  7,  7,  8,  8,  9,  9,  9, 10, 10, 10, 10, 11,  8,
+     6,  5,  5,  6,  7,  7,  8,  8,  8,  9,  9,  9, 10, 10, 10, 10,  8,
+     7,  6,  6,  6,  7,  7,  8,  8,  8,  9,  9,  9, 10, 10, 10, 10,  8,
+     8,  7,  7,  7,  7,  8,  8,  8,  8,  9,  9,  9, 10, 10, 10, 10,  8,
+     8,  7,  7,  7,  7,  8,  8,  8,  9,  9,  9,  9, 10, 10, 10, 10,  8,
+     9,  8,  8,  8,  8,  8,  8,  8,  9,  9,  9, 10, 10, 10, 10, 10,  8,
+     9,  8,  8,  8,  8,  8,  8,  9,  9,  9, 10, 10, 10, 10, 10, 10,  8,
+    10,  9,  8,  8,  9,  9,  9,  9,  9, 10, 10, 10, 10, 10, 10, 11,  8,
+    10,  9,  9,  9,  9,  9,  9,  9, 10, 10, 10, 10, 10, 10, 11, 11,  8,
+    11,  9,  9,  9,  9,  9,  9, 10, 10, 10, 10, 10, 11, 10, 11, 11,  8,
+    11, 10,  9,  9, 10,  9, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11,  8,
+    11, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11,  9,
+    11, 10,  9,  9, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11, 11,  9,
+    11, 10, 10, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11, 11,  9,
+    12, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11, 11, 12, 12,  9,
+     9,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  8,  9,  5,
+];
+
+#[rustfmt::skip]
+const ESC_CODEBOOK_CODES: [u16; 289] = [
+    0x000, 0x006, 0x019, 0x03d, 0x09c, 0x0c6, 0x1a7, 0x390, 0x3c2, 0x3df,
+    0x7e6, 0x7f3, 0xffb, 0x7ec, 0xffa, 0xffe, 0x38e, 0x005, 0x001, 0x008,
+    0x014, 0x037, 0x042, 0x092, 0x0af, 0x191, 0x1a5, 0x1b5, 0x39e, 0x3c0,
+    0x3a2, 0x3cd, 0x7d6, 0x0ae, 0x017, 0x007, 0x009, 0x018, 0x039, 0x040,
+    0x08e, 0x0a3, 0x0b8, 0x199, 0x1ac, 0x1c1, 0x3b1, 0x396, 0x3be, 0x3ca,
+    0x09d, 0x03c, 0x015, 0x016, 0x01a, 0x03b, 0x044, 0x091, 0x0a5, 0x0be,
+    0x196, 0x1ae, 0x1b9, 0x3a1, 0x391, 0x3a5, 0x3d5, 0x094, 0x09a, 0x036,
+    0x038, 0x03a, 0x041, 0x08c, 0x09b, 0x0b0, 0x0c3, 0x19e, 0x1ab, 0x1bc,
+    0x39f, 0x38f, 0x3a9, 0x3cf, 0x093, 0x0bf, 0x03e, 0x03f, 0x043, 0x045,
+    0x09e, 0x0a7, 0x0b9, 0x194, 0x1a2, 0x1ba, 0x1c3, 0x3a6, 0x3a7, 0x3bb,
+    0x3d4, 0x09f, 0x1a0, 0x08f, 0x08d, 0x090, 0x098, 0x0a6, 0x0b6, 0x0c4,
+    0x19f, 0x1af, 0x1bf, 0x399, 0x3bf, 0x3b4, 0x3c9, 0x3e7, 0x0a8, 0x1b6,
+    0x0ab, 0x0a4, 0x0aa, 0x0b2, 0x0c2, 0x0c5, 0x198, 0x1a4, 0x1b8, 0x38c,
+    0x3a4, 0x3c4, 0x3c6, 0x3dd, 0x3e8, 0x0ad, 0x3af, 0x192, 0x0bd, 0x0bc,
+    0x18e, 0x197, 0x19a, 0x1a3, 0x1b1, 0x38d, 0x398, 0x3b7, 0x3d3, 0x3d1,
+    0x3db, 0x7dd, 0x0b4, 0x3de, 0x1a9, 0x19b, 0x19c, 0x1a1, 0x1aa, 0x1ad,
+    0x1b3, 0x38b, 0x3b2, 0x3b8, 0x3ce, 0x3e1, 0x3e0, 0x7d2, 0x7e5, 0x0b7,
+    0x7e3, 0x1bb, 0x1a8, 0x1a6, 0x1b0, 0x1b2, 0x1b7, 0x39b, 0x39a, 0x3ba,
+    0x3b5, 0x3d6, 0x7d7, 0x3e4, 0x7d8, 0x7ea, 0x0ba, 0x7e8, 0x3a0, 0x1bd,
+    0x1b4, 0x38a, 0x1c4, 0x392, 0x3aa, 0x3b0, 0x3bc, 0x3d7, 0x7d4, 0x7dc,
+    0x7db, 0x7d5, 0x7f0, 0x0c1, 0x7fb, 0x3c8, 0x3a3, 0x395, 0x39d, 0x3ac,
+    0x3ae, 0x3c5, 0x3d8, 0x3e2, 0x3e6, 0x7e4, 0x7e7, 0x7e0, 0x7e9, 0x7f7,
+    0x190, 0x7f2, 0x393, 0x1be, 0x1c0, 0x394, 0x397, 0x3ad, 0x3c3, 0x3c1,
+    0x3d2, 0x7da, 0x7d9, 0x7df, 0x7eb, 0x7f4, 0x7fa, 0x195, 0x7f8, 0x3bd,
+    0x39c, 0x3ab, 0x3a8, 0x3b3, 0x3b9, 0x3d0, 0x3e3, 0x3e5, 0x7e2, 0x7de,
+    0x7ed, 0x7f1, 0x7f9, 0x7fc, 0x193, 0xffd, 0x3dc, 0x3b6, 0x3c7, 0x3cc,
+    0x3cb, 0x3d9, 0x3da, 0x7d3, 0x7e1, 0x7ee, 0x7ef, 0x7f5, 0x7f6, 0xffc,
+    0xfff, 0x19d, 0x1c2, 0x0b5, 0x0a1, 0x096, 0x097, 0x095, 0x099, 0x0a0,
+    0x0a2, 0x0ac, 0x0a9, 0x0b1, 0x0b3, 0x0bb, 0x0c0, 0x18f, 0x004,
+];
+
+/// The MDCT of 2048 windowed samples, computed as a DCT-IV through an FFT a
+/// quarter of the size.
+struct Mdct {
+    fft: Arc<dyn Fft<f32>>,
+    twiddles: Vec<Complex32>,
+    window: Vec<f32>,
+}
+
+impl Mdct {
+    fn new() -> Self {
+        let len = 2 * FRAME_LEN;
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(len / 4),
+            twiddles: (0..len / 4)
+                .map(|n| Complex32::from_polar(1.0, -PI * (n as f32 + 0.125) / FRAME_LEN as f32))
+                .collect(),
+            // The sine window, which AAC signals with a window shape of 0.
+            window: (0..len)
+                .map(|n| (PI * (n as f32 + 0.5) / len as f32).sin())
+                .collect(),
+        }
+    }
+
+    fn forward(&self, input: &[f32], output: &mut [f32]) {
+        let quarter = FRAME_LEN / 2;
+        let x = |n: usize| input[n] * self.window[n];
+        // Folds the block in half, which turns the MDCT into a DCT-IV.
+        let folded: Vec<f32> = (0..FRAME_LEN)
+            .map(|n| {
+                if n < quarter {
+                    -x(3 * quarter + n) - x(3 * quarter - 1 - n)
+                } else {
+                    x(n - quarter) - x(3 * quarter - 1 - n)
+                }
+            })
+            .collect();
+        let mut spectrum: Vec<Complex32> = (0..quarter)
+            .map(|n| {
+                Complex32::new(folded[2 * n], folded[FRAME_LEN - 1 - 2 * n]) * self.twiddles[n]
+            })
+            .collect();
+        self.fft.process(&mut spectrum);
+        // AAC's analysis filterbank doubles the coefficients.
+        for (k, value) in spectrum.iter().enumerate() {
+            let value = value * self.twiddles[k] * 2.0;
+            output[2 * k] = value.re;
+            output[FRAME_LEN - 1 - 2 * k] = -value.im;
+        }
+    }
+}
+
+/// Quantizes `coefficients` so that decoders scale them back by `global_gain`.
+fn quantize(coefficients: &[f32], global_gain: i32, quantized: &mut [i32]) {
+    let step = 2f32.powf(-0.25 * (global_gain - SCALEFACTOR_OFFSET) as f32);
+    for (quantized, &coefficient) in quantized.iter_mut().zip(coefficients) {
+        let magnitude = ((coefficient.abs() * step).powf(0.75) + 0.4054).min(MAX_QUANTIZED) as i32;
+        *quantized = if coefficient < 0.0 {
+            -magnitude
+        } else {
+            magnitude
+        };
+    }
+}
+
+/// Writes the escape sequence following a magnitude of `ESCAPE` or more.
+fn write_escape(writer: &mut BitWriter, magnitude: u32) {
+    let bits = u32::BITS - 1 - magnitude.leading_zeros();
+    // One bit per extra bit of the magnitude above 4, a zero, then all but
+    // the magnitude's leading bit.
+    writer.write((1 << (bits - 4)) - 1, bits - 4);
+    writer.write(0, 1);
+    writer.write(magnitude as u64, bits);
+}
+
+/// Writes one channel's individual channel stream, in which every band
+/// shares `global_gain` as its scalefactor.
+fn write_channel(writer: &mut BitWriter, quantized: &[i32], max_bands: usize, global_gain: i32) {
+    let bands = BAND_OFFSETS.windows(2).take(max_bands);
+    let mut codebooks: Vec<u64> = bands
+        .map(|band| {
+            if quantized[band[0]..band[1]].iter().all(|&q| q == 0) {
+                ZERO_CODEBOOK
+            } else {
+                ESC_CODEBOOK
+            }
+        })
+        .collect();
+    // Silent bands at the top need not be sent.
+    while codebooks.last() == Some(&ZERO_CODEBOOK) {
+        codebooks.pop();
+    }
+
+    writer.write(global_gain as u64, 8);
+    // ICS info: reserved bit, a single long window of sine shape, the band
+    // count and no prediction.
+    writer.write(0, 4);
+    writer.write(codebooks.len() as u64, 6);
+    writer.write(0, 1);
+    for section in codebooks.chunk_by(|a, b| a == b) {
+        writer.write(section[0], 4);
+        let mut len = section.len();
+        while len >= SECTION_ESCAPE {
+            writer.write(SECTION_ESCAPE as u64, 5);
+            len -= SECTION_ESCAPE;
+        }
+        writer.write(len as u64, 5);
+    }
+    // The scalefactors of bands with coefficients, each coded as no change.
+    for _ in codebooks
+        .iter()
+        .filter(|&&codebook| codebook != ZERO_CODEBOOK)
+    {
+        writer.write(0, 1);
+    }
+    // No pulse, TNS or gain control data.
+    writer.write(0, 3);
+    for (band, &codebook) in BAND_OFFSETS.windows(2).zip(&codebooks) {
+        if codebook == ZERO_CODEBOOK {
+            continue;
+        }
+        for pair in quantized[band[0]..band[1]].chunks_exact(2) {
+            let (y, z) = (pair[0].unsigned_abs(), pair[1].unsigned_abs());
+            let index = (y.min(ESCAPE) * 17 + z.min(ESCAPE)) as usize;
+            writer.write(
+                ESC_CODEBOOK_CODES[index] as u64,
+                ESC_CODEBOOK_LENS[index] as u32,
+            );
+            for &value in pair.iter().filter(|&&value| value != 0) {
+                writer.write((value < 0) as u64, 1);
+            }
+            for magnitude in [y, z] {
+                if magnitude >= ESCAPE {
+                    write_escape(writer, magnitude);
+                }
+            }
+        }
+    }
+}
+
+/// Encodes 16-bit audio into raw AAC-LC frames of `FRAME_LEN` samples, for
+/// decoders given `aac_audio_specific_config`. Only long windows and a single
+/// scalefactor per frame are used, picked as the finest that fits the
+/// bitrate. Output lags the input by one frame.
+pub struct AacEncoder {
+    sample_rate: u32,
+    channels: usize,
+    /// Bits one frame may take at the configured bitrate.
+    frame_bits: usize,
+    /// Bands below the cutoff frequency, which drops with the bitrate.
+    max_bands: usize,
+    mdct: Mdct,
+    /// The previous frame of each channel, which the next window overlaps.
+    previous: Vec<Vec<f32>>,
+}
+
+impl AacEncoder {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let mut encoder = Self {
+            sample_rate,
+            channels,
+            frame_bits: 0,
+            max_bands: 0,
+            mdct: Mdct::new(),
+            previous: vec![vec![0.0; FRAME_LEN]; channels],
+        };
+        encoder.set_bitrate(DEFAULT_CHANNEL_BITRATE * channels as i32);
+        encoder
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: i32) {
+        self.frame_bits = (bitrate as usize * FRAME_LEN / self.sample_rate as usize)
+            .min(MAX_CHANNEL_FRAME_BITS * self.channels);
+        // A quarter of the bitrate per channel, in Hz, keeps enough bits per
+        // coefficient for the uniform quantizer.
+        let cutoff_hz = (bitrate as usize / self.channels / 4).clamp(4_000, 20_000);
+        let cutoff = cutoff_hz * 2 * FRAME_LEN / self.sample_rate as usize;
+        self.max_bands = BAND_OFFSETS[1..]
+            .iter()
+            .take_while(|&&end| end <= cutoff)
+            .count();
+    }
+
+    /// Encodes one frame of `FRAME_LEN` interleaved samples per channel.
+    pub fn encode(&mut self, input: &[i16]) -> Vec<u8> {
+        let mut block = vec![0.0; 2 * FRAME_LEN];
+        let coefficients: Vec<Vec<f32>> = (0..self.channels)
+            .map(|channel| {
+                let current: Vec<f32> = input
+                    .iter()
+                    .skip(channel)
+                    .step_by(self.channels)
+                    .map(|&sample| sample as f32)
+                    .collect();
+                block[..FRAME_LEN].copy_from_slice(&self.previous[channel]);
+                block[FRAME_LEN..FRAME_LEN + current.len()].copy_from_slice(&current);
+                block[FRAME_LEN + current.len()..].fill(0.0);
+                let mut coefficients = vec![0.0; FRAME_LEN];
+                self.mdct.forward(&block, &mut coefficients);
+                self.previous[channel] = block[FRAME_LEN..].to_vec();
+                coefficients
+            })
+            .collect();
+        // The finest global gain whose frame fits the bitrate.
+        let (mut low, mut high) = (0, u8::MAX as i32);
+        while low < high {
+            let gain = (low + high) / 2;
+            if self.write_frame(&coefficients, gain).len() * 8 <= self.frame_bits {
+                high = gain;
+            } else {
+                low = gain + 1;
+            }
+        }
+        self.write_frame(&coefficients, low)
+    }
+
+    fn write_frame(&self, coefficients: &[Vec<f32>], global_gain: i32) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // Element instance tag 0, and for pairs, separate windows per channel.
+        if self.channels == 1 {
+            writer.write(ID_SCE, 3);
+            writer.write(0, 4);
+        } else {
+            writer.write(ID_CPE, 3);
+            writer.write(0, 5);
+        }
+        let mut quantized = vec![0; BAND_OFFSETS[self.max_bands]];
+        for coefficients in coefficients {
+            quantize(coefficients, global_gain, &mut quantized);
+            write_channel(&mut writer, &quantized, self.max_bands, global_gain);
+        }
+        writer.write(ID_END, 3);
+        writer.align();
+        writer.into_bytes()
+    }
+}
+
+impl AudioEncoder for AacEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Aac
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<Bytes> {
+        Ok(self.encode(frame).into())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        for previous in &mut self.previous {
+            previous.fill(0.0);
+        }
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings, tier: usize) -> Result<()> {
+        self.set_bitrate(match settings.tier_bitrate(tier) {
+            Bitrate::Bits(bitrate) => bitrate,
+            _ => DEFAULT_CHANNEL_BITRATE * self.channels as i32,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia_core::audio::SampleBuffer;
+    use symphonia_core::codecs::{CODEC_TYPE_AAC, CodecParameters, Decoder, DecoderOptions};
+    use symphonia_core::formats::Packet;
+
+    /// Decodes `frames` given only the AudioSpecificConfig, like WebCodecs.
+    fn decode(sample_rate: u32, channels: usize, frames: &[Vec<u8>]) -> Vec<f32> {
+        let config =
+            streaming_protocol::aac_audio_specific_config(sample_rate, channels as u8).unwrap();
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_AAC)
+            .with_extra_data(Box::new(config));
+        let mut decoder =
+            symphonia_codec_aac::AacDecoder::try_new(&params, &DecoderOptions::default()).unwrap();
+        let mut decoded = Vec::new();
+        for (index, frame) in frames.iter().enumerate() {
+            let packet =
+                Packet::new_from_slice(0, (index * FRAME_LEN) as u64, FRAME_LEN as u64, frame);
+            let buffer = decoder.decode(&packet).unwrap();
+            let mut samples = SampleBuffer::<f32>::new(buffer.capacity() as u64, *buffer.spec());
+            samples.copy_interleaved_ref(buffer);
+            decoded.extend_from_slice(samples.samples());
+        }
+        decoded
+    }
+
+    fn sine(frequency: f32, amplitude: f32, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / 48_000.0;
+                (amplitude * i16::MAX as f32 * (2.0 * PI * frequency * t).sin()) as i16
+            })
+            .collect()
+    }
+
+    /// Signal to noise ratio of `decoded` against `reference`, given as samples
+    /// of 16-bit audio.
+    fn snr(reference: &[i16], decoded: &[f32]) -> f32 {
+        let (signal, noise) = reference.iter().zip(decoded).fold(
+            (0.0, 0.0),
+            |(signal, noise), (&reference, &decoded)| {
+                let reference = reference as f32 / 32_768.0;
+                (
+                    signal + reference * reference,
+                    noise + (reference - decoded).powi(2),
+                )
+            },
+        );
+        10.0 * (signal / noise).log10()
+    }
+
+    fn encode(encoder: &mut AacEncoder, input: &[i16], channels: usize) -> Vec<Vec<u8>> {
+        input
+            .chunks(FRAME_LEN * channels)
+            .map(|frame| encoder.encode(frame))
+            .collect()
+    }
+
+    #[test]
+    fn sine_decodes_one_frame_late() {
+        let input = sine(1000.0, 0.5, FRAME_LEN * 20);
+        let mut encoder = AacEncoder::new(48_000, 1);
+        let decoded = decode(48_000, 1, &encode(&mut encoder, &input, 1));
+        assert_eq!(decoded.len(), input.len());
+        let snr = snr(&input[..input.len() - FRAME_LEN], &decoded[FRAME_LEN..]);
+        assert!(snr > 30.0, "SNR too low: {snr:.1} dB");
+    }
+
+    #[test]
+    fn stereo_channels_stay_separate() {
+        let len = FRAME_LEN * 20;
+        let left = sine(440.0, 0.5, len);
+        let right = sine(3000.0, 0.3, len);
+        let input: Vec<i16> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(&l, &r)| [l, r])
+            .collect();
+        let mut encoder = AacEncoder::new(48_000, 2);
+        let frames = encode(&mut encoder, &input, 2);
+        assert!(frames.iter().all(|frame| aac_channels(frame) == 2));
+        let decoded = decode(48_000, 2, &frames);
+        for (channel, reference) in [left, right].iter().enumerate() {
+            let decoded: Vec<f32> = decoded.iter().skip(channel).step_by(2).copied().collect();
+            let snr = snr(&reference[..len - FRAME_LEN], &decoded[FRAME_LEN..]);
+            assert!(snr > 30.0, "channel {channel} SNR too low: {snr:.1} dB");
+        }
+    }
+
+    fn aac_channels(frame: &[u8]) -> u8 {
+        streaming_protocol::aac_frame_channels(frame).unwrap()
+    }
+
+    #[test]
+    fn frames_fit_the_bitrate() {
+        let mut noise: u32 = 3;
+        let input: Vec<i16> = (0..FRAME_LEN * 2 * 10)
+            .map(|_| {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (noise >> 17) as i16
+            })
+            .collect();
+        for bitrate in [32_000, 128_000, 256_000] {
+            let mut encoder = AacEncoder::new(44_100, 2);
+            encoder.set_bitrate(bitrate);
+            let frames = encode(&mut encoder, &input, 2);
+            let limit = bitrate as usize * FRAME_LEN / 44_100 / 8;
+            assert!(frames.iter().all(|frame| frame.len() <= limit));
+            assert_eq!(decode(44_100, 2, &frames).len(), input.len());
+        }
+    }
+
+    #[test]
+    fn silence_stays_silent_and_small() {
+        let mut encoder = AacEncoder::new(48_000, 2);
+        let frames = encode(&mut encoder, &vec![0; FRAME_LEN * 2 * 4], 2);
+        assert!(frames.iter().all(|frame| frame.len() < 8));
+        let peak = decode(48_000, 2, &frames)
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert_eq!(peak, 0.0);
+    }
+}
@@ -0,0 +1,1641 @@
+use crate::bounded::BoundedReceiver;
+use crate::config::Config;
+use crate::encode::aac::{self, AacEncoder};
+use crate::encode::flac::FlacEncoder;
+use crate::encode::opus_encoder::OpusEncoder;
+use crate::health::EncoderHeartbeat;
+use anyhow::{Context, Result, bail};
+use bytes::{Bytes, BytesMut};
+use futures::FutureExt;
+use opus::{Application, Bitrate};
+use ringbuf::traits::{Consumer, Observer, RingBuffer};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use streaming_protocol::{Codec, SURROUND_CHANNELS};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, interval_at};
+
+/// Interleaved samples from one PipeWire process cycle.
+#[derive(Clone)]
+pub struct CapturedAudio {
+    /// Wall clock time the first sample was captured, in microseconds since the
+    /// Unix epoch.
+    pub captured_at_us: u64,
+    pub samples: Vec<i16>,
+    /// The sink's six 5.1 channels before the downmix and DSP, with
+    /// `--surround`. Empty otherwise.
+    pub surround: Vec<i16>,
+    /// Audio PipeWire lost before this, in an xrun, in microseconds.
+    pub gap_us: u64,
+}
+
+/// Bitrates clients can be moved down to as their links get congested, or
+/// pin themselves to, unless `--tier-bitrates` says otherwise. Tier 0 is the
+/// full quality stream and always encoded; these lower tiers only while some
+/// client needs them.
+pub const LOWER_TIER_BITRATES: [i32; 2] = [48_000, 16_000];
+pub const TIER_COUNT: usize = LOWER_TIER_BITRATES.len() + 1;
+/// The Opus multistream encoding of a 5.1 sink's channels, past the
+/// downmixed tiers. Encoded with `--surround` while some client needs it.
+pub const SURROUND_TIER: usize = TIER_COUNT;
+/// How long a failed encoder rests before it's recreated, so one failing on
+/// every frame doesn't spin.
+const ENCODER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest Opus packet the encoders are allowed to produce.
+const MAX_OPUS_PACKET_LEN: usize = 8192;
+/// Opus packets are split off buffers of this size, each holding a couple of
+/// seconds of packets, rather than allocated one by one.
+const OPUS_POOL_LEN: usize = 64 * 1024;
+
+/// Encoder tuning, adjustable while streaming.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncoderSettings {
+    /// Bitrate of the full quality tier in bits per second, or `None` to let
+    /// the encoder pick.
+    pub bitrate: Option<i32>,
+    pub vbr: bool,
+    /// From 0 (fastest) to 10 (best quality).
+    pub complexity: i32,
+    /// Inband forward error correction, letting decoders recover lost packets.
+    pub fec: bool,
+    /// Discontinuous transmission, sending silence in as few bytes as possible.
+    pub dtx: bool,
+    /// Packet loss the encoder should expect. Makes FEC spend more on redundancy.
+    pub packet_loss_percent: i32,
+    /// Percentage the audio is scaled to before encoding, in every codec.
+    pub volume: u8,
+    /// Bitrates of the tiers below the full quality one, highest first.
+    pub lower_tier_bitrates: [i32; TIER_COUNT - 1],
+}
+
+impl EncoderSettings {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(bitrate) = self.bitrate
+            && !(500..=512_000).contains(&bitrate)
+        {
+            bail!("Bitrate {} is outside 500 to 512000 bits/s", bitrate);
+        }
+        if !(0..=10).contains(&self.complexity) {
+            bail!("Complexity {} is outside 0 to 10", self.complexity);
+        }
+        if !(0..=100).contains(&self.packet_loss_percent) {
+            bail!(
+                "Packet loss {}% is outside 0 to 100%",
+                self.packet_loss_percent
+            );
+        }
+        if self.volume > 100 {
+            bail!("Volume {}% is outside 0 to 100%", self.volume);
+        }
+        for bitrate in self.lower_tier_bitrates {
+            if !(500..=512_000).contains(&bitrate) {
+                bail!("Tier bitrate {} is outside 500 to 512000 bits/s", bitrate);
+            }
+        }
+        if !self.lower_tier_bitrates.is_sorted_by(|a, b| a > b) {
+            bail!("Tier bitrates must go from highest to lowest");
+        }
+        Ok(())
+    }
+
+    /// Lower tiers never ask for more than the full quality tier.
+    pub fn tier_bitrate(&self, tier: usize) -> Bitrate {
+        match (tier, self.bitrate) {
+            (0, None) => Bitrate::Auto,
+            (0, Some(bitrate)) => Bitrate::Bits(bitrate),
+            (tier, bitrate) => {
+                Bitrate::Bits(self.lower_tier_bitrates[tier - 1].min(bitrate.unwrap_or(i32::MAX)))
+            }
+        }
+    }
+}
+
+/// Turns frames of interleaved samples into packets of one codec.
+pub trait AudioEncoder: Send {
+    fn codec(&self) -> Codec;
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<Bytes>;
+
+    /// Forgets earlier frames, so the next one doesn't depend on them.
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Applies `settings` as meant for bitrate `tier`. Lossless codecs have
+    /// nothing to tune.
+    fn configure(&mut self, _settings: &EncoderSettings, _tier: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An Opus encoder writing its packets into a shared buffer. Its allocation
+/// is reused once every packet split off it has been dropped.
+struct PooledOpusEncoder {
+    encoder: OpusEncoder,
+    pool: BytesMut,
+    /// Bitrate kept whatever the tier, as the surround encoder's is.
+    bitrate: Option<Bitrate>,
+}
+
+impl AudioEncoder for PooledOpusEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Opus
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<Bytes> {
+        if self.pool.capacity() < MAX_OPUS_PACKET_LEN {
+            self.pool.reserve(OPUS_POOL_LEN);
+        }
+        self.pool.resize(MAX_OPUS_PACKET_LEN, 0);
+        let len = self.encoder.encode(frame, &mut self.pool)?;
+        self.pool.truncate(len);
+        Ok(self.pool.split().freeze())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.encoder.reset_state()
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings, tier: usize) -> Result<()> {
+        let encoder = &mut self.encoder;
+        encoder.set_bitrate(self.bitrate.unwrap_or_else(|| settings.tier_bitrate(tier)))?;
+        encoder.set_vbr(settings.vbr)?;
+        encoder.set_complexity(settings.complexity)?;
+        encoder.set_inband_fec(settings.fec)?;
+        encoder.set_dtx(settings.dtx)?;
+        encoder.set_packet_loss_perc(settings.packet_loss_percent)
+    }
+}
+
+/// Passes frames through as raw samples.
+struct PcmEncoder {
+    channels: u8,
+}
+
+impl AudioEncoder for PcmEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Pcm
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<Bytes> {
+        Ok(streaming_protocol::pcm_payload(self.channels, frame).into())
+    }
+}
+
+/// One encoder per bitrate tier for lossy codecs, and with `--surround` one
+/// for `SURROUND_TIER` after them. Lossless ones and raw PCM have a single
+/// tier, which every client gets.
+fn tier_encoders(
+    config: &Config,
+    codec: Codec,
+    settings: &EncoderSettings,
+) -> Result<Vec<Box<dyn AudioEncoder>>> {
+    let channels = config.stream_channels();
+    match codec {
+        Codec::Opus => {
+            let mut encoders = (0..TIER_COUNT)
+                .map(|_| {
+                    Ok(PooledOpusEncoder {
+                        encoder: OpusEncoder::new(
+                            config.sample_rate,
+                            channels,
+                            Application::Audio,
+                        )?,
+                        pool: BytesMut::new(),
+                        bitrate: None,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if config.surround {
+                encoders.push(PooledOpusEncoder {
+                    encoder: OpusEncoder::new_surround(config.sample_rate, Application::Audio)?,
+                    pool: BytesMut::new(),
+                    bitrate: Some(Bitrate::Bits(config.surround_bitrate)),
+                });
+            }
+            encoders
+                .into_iter()
+                .enumerate()
+                .map(|(tier, mut encoder)| {
+                    encoder
+                        .configure(settings, tier)
+                        .context("Couldn't configure encoder")?;
+                    Ok(Box::new(encoder) as Box<dyn AudioEncoder>)
+                })
+                .collect()
+        }
+        Codec::Flac => Ok(vec![Box::new(FlacEncoder::new(
+            config.sample_rate,
+            channels as usize,
+        ))]),
+        Codec::Pcm => Ok(vec![Box::new(PcmEncoder {
+            channels: channels as u8,
+        })]),
+        Codec::Aac => (0..TIER_COUNT)
+            .map(|tier| {
+                let mut encoder = AacEncoder::new(config.sample_rate, channels as usize);
+                encoder
+                    .configure(settings, tier)
+                    .context("Couldn't configure encoder")?;
+                Ok(Box::new(encoder) as Box<dyn AudioEncoder>)
+            })
+            .collect(),
+        Codec::Silence | Codec::OpusSurround => {
+            unreachable!("{} is never a stream's codec", codec)
+        }
+    }
+}
+
+/// Samples per channel in one frame of `codec`. AAC frames have a fixed length,
+/// the others last `--frame-ms`.
+pub fn samples_per_frame(config: &Config, codec: Codec) -> usize {
+    match codec {
+        Codec::Aac => aac::FRAME_LEN,
+        _ => config.samples_per_frame(),
+    }
+}
+
+/// Scales `samples` to `volume` percent.
+fn apply_volume(samples: &mut [i16], volume: u8) {
+    if volume != 100 {
+        let gain = volume as f32 / 100.0;
+        for sample in samples.iter_mut() {
+            *sample = (*sample as f32 * gain).round() as i16;
+        }
+    }
+}
+
+/// One encoded frame, stamped with the capture time of its first sample.
+#[derive(Clone, Debug)]
+pub struct EncodedPacket {
+    pub codec: Codec,
+    /// Counts up by one per frame, so clients can spot packets they missed.
+    pub sequence: u32,
+    pub captured_at_us: u64,
+    /// Samples per channel in the frame.
+    pub frame_samples: u16,
+    /// The frame encoded at each bitrate tier that's currently in use.
+    pub payloads: [Option<Bytes>; TIER_COUNT],
+    /// The frame at `SURROUND_TIER`, while it's in use.
+    pub surround: Option<Bytes>,
+    /// The encoders were reset before this frame, so decoders must be too.
+    pub decoder_reset: bool,
+}
+
+impl EncodedPacket {
+    /// The frame at `tier`, or at the closest higher quality tier if it wasn't
+    /// encoded, e.g. because a client only just asked for it.
+    pub fn payload(&self, tier: usize) -> &[u8] {
+        match self.encoded_tier(tier) {
+            SURROUND_TIER => self.surround.as_ref(),
+            tier => self.payloads[tier].as_ref(),
+        }
+        .expect("Tier 0 is always encoded")
+    }
+
+    /// The tier `payload` takes the frame at `tier` from. The surround tier
+    /// falls back to the full quality downmix.
+    pub fn encoded_tier(&self, tier: usize) -> usize {
+        if tier == SURROUND_TIER {
+            return match self.surround {
+                Some(_) => SURROUND_TIER,
+                None => 0,
+            };
+        }
+        (0..=tier)
+            .rev()
+            .find(|&tier| self.payloads[tier].is_some())
+            .unwrap_or(0)
+    }
+
+    /// The codec of the frame at `tier`.
+    pub fn codec(&self, tier: usize) -> Codec {
+        match (self.codec, self.encoded_tier(tier)) {
+            (Codec::Opus, SURROUND_TIER) => Codec::OpusSurround,
+            (codec, _) => codec,
+        }
+    }
+
+    /// A 10 ms packet with a three byte frame at tier 0, for tests.
+    #[cfg(test)]
+    pub fn for_test(codec: Codec, sequence: u32, captured_at_us: u64) -> Self {
+        let mut payloads: [Option<Bytes>; TIER_COUNT] = Default::default();
+        payloads[0] = Some(Bytes::from_static(&[0xfc, 1, 2]));
+        EncodedPacket {
+            codec,
+            sequence,
+            captured_at_us,
+            frame_samples: 480,
+            payloads,
+            surround: None,
+            decoder_reset: false,
+        }
+    }
+}
+
+/// Tells the compress task that a client started or stopped using a tier.
+pub enum TierDemand {
+    Join(usize),
+    Leave(usize),
+}
+
+/// Keeps a client's bitrate tier registered with the compress task until
+/// it's dropped.
+pub struct TierSubscription {
+    tier: usize,
+    demand: mpsc::UnboundedSender<TierDemand>,
+}
+
+impl TierSubscription {
+    pub fn new(demand: mpsc::UnboundedSender<TierDemand>, tier: usize) -> Self {
+        let _ = demand.send(TierDemand::Join(tier));
+        Self { tier, demand }
+    }
+
+    pub fn tier(&self) -> usize {
+        self.tier
+    }
+
+    pub fn set_tier(&mut self, tier: usize) {
+        if tier != self.tier {
+            let _ = self.demand.send(TierDemand::Join(tier));
+            let _ = self.demand.send(TierDemand::Leave(self.tier));
+            self.tier = tier;
+        }
+    }
+}
+
+impl Drop for TierSubscription {
+    fn drop(&mut self) {
+        let _ = self.demand.send(TierDemand::Leave(self.tier));
+    }
+}
+
+/// What the compress task keeps while its encoders are recreated: its
+/// channels, and the tiers and sequence numbers clients rely on.
+struct CompressIo {
+    rx: BoundedReceiver<CapturedAudio>,
+    tx: broadcast::Sender<EncodedPacket>,
+    paused: watch::Receiver<bool>,
+    idle: watch::Sender<bool>,
+    demand: mpsc::UnboundedReceiver<TierDemand>,
+    settings: watch::Receiver<EncoderSettings>,
+    restart: watch::Receiver<u64>,
+    heartbeat: EncoderHeartbeat,
+    /// Number of clients on each tier, the surround one last.
+    listeners: [usize; SURROUND_TIER + 1],
+    sequence: u32,
+}
+
+/// Encodes the audio of `rx` until it's closed. An encoder that fails or
+/// panics is recreated, with the next packet telling decoders to reset, so
+/// clients hear a gap rather than the stream dying under them.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_compress_task(
+    config: Arc<Config>,
+    codec: Codec,
+    rx: BoundedReceiver<CapturedAudio>,
+    tx: broadcast::Sender<EncodedPacket>,
+    paused: watch::Receiver<bool>,
+    idle: watch::Sender<bool>,
+    demand: mpsc::UnboundedReceiver<TierDemand>,
+    settings: watch::Receiver<EncoderSettings>,
+    restart: watch::Receiver<u64>,
+    heartbeat: EncoderHeartbeat,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut io = CompressIo {
+            rx,
+            tx,
+            paused,
+            idle,
+            demand,
+            settings,
+            restart,
+            heartbeat,
+            listeners: [0; SURROUND_TIER + 1],
+            sequence: 0,
+        };
+        loop {
+            let error = match AssertUnwindSafe(compress(&config, codec, &mut io))
+                .catch_unwind()
+                .await
+            {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => format!("{:#}", e),
+                Err(panic) => panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("panicked")),
+            };
+            warn!(
+                "{} encoder failed, restarting it in {:?}: {}",
+                codec, ENCODER_RESTART_DELAY, error
+            );
+            io.heartbeat.restarted();
+            // The frame it failed on is lost, which clients conceal.
+            io.sequence = io.sequence.wrapping_add(1);
+            tokio::time::sleep(ENCODER_RESTART_DELAY).await;
+        }
+    })
+}
+
+/// Runs fresh encoders over the audio of `io` until it's closed, or until an
+/// encoder fails.
+async fn compress(config: &Config, codec: Codec, io: &mut CompressIo) -> Result<()> {
+    let CompressIo {
+        rx,
+        tx,
+        paused,
+        idle,
+        demand,
+        settings,
+        restart,
+        heartbeat,
+        listeners,
+        sequence,
+    } = io;
+    let channels = config.stream_channels();
+    let mut encoders = tier_encoders(config, codec, &settings.borrow_and_update())?;
+    let mut dtx = settings.borrow().dtx;
+    let mut volume = settings.borrow().volume;
+    let mut count: usize = 0;
+    let mut compressed_count: usize = 0;
+    // Samples the buffer had to drop because capture outpaced encoding.
+    let mut overrun_count: usize = 0;
+    let mut ticker = interval_at(
+        Instant::now() + Duration::from_secs(1),
+        Duration::from_secs(1),
+    );
+    // Samples arrive interleaved, so one frame holds samples_per_frame per channel.
+    let samples_per_frame = samples_per_frame(config, codec);
+    let frame_len = samples_per_frame * channels as usize;
+    // Room for a frame short of completion plus a second of audio, far more
+    // than PipeWire hands over per cycle whatever the frame duration.
+    let mut buff = ringbuf::rb::local::LocalRb::new(
+        frame_len + config.sample_rate as usize * channels as usize,
+    );
+    let mut input_buffer = vec![0; frame_len];
+    // With a surround encoder, the six channels are buffered alongside,
+    // frame for frame.
+    let surround_frame_len = samples_per_frame * SURROUND_CHANNELS as usize;
+    let mut surround_buff = (encoders.len() > SURROUND_TIER).then(|| {
+        ringbuf::rb::local::LocalRb::new(
+            surround_frame_len + config.sample_rate as usize * SURROUND_CHANNELS as usize,
+        )
+    });
+    let mut surround_input = match surround_buff {
+        Some(_) => vec![0; surround_frame_len],
+        None => Vec::new(),
+    };
+    // Capture time of the oldest sample in `buff` when it last ran empty,
+    // and the frames taken from it since. Frames need not last a whole
+    // number of microseconds, so their times are counted from there.
+    let mut buffered_at_us = 0;
+    let mut frames_since_buffered: u64 = 0;
+    // Encoders look ahead or lag behind by up to a frame, so only a silent
+    // frame following another is sure to encode to silence.
+    let mut previous_silent = false;
+    // With --idle-after-secs, encoding stops after this many silent frames
+    // in a row.
+    let idle_after_frames = config
+        .idle_after_secs
+        .map(|secs| secs as u64 * config.sample_rate as u64 / samples_per_frame as u64);
+    let mut silent_frames: u64 = 0;
+    // Set whenever the encoders start afresh, to mark the next packet.
+    let mut decoder_reset = true;
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(_) if *paused.borrow() => {
+                    buff.clear();
+                    if let Some(surround_buff) = &mut surround_buff {
+                        surround_buff.clear();
+                    }
+                },
+                Some(audio) => {
+                    if settings.has_changed().unwrap_or(false) {
+                        let settings = *settings.borrow_and_update();
+                        dtx = settings.dtx;
+                        volume = settings.volume;
+                        for (tier, encoder) in encoders.iter_mut().enumerate() {
+                            encoder.configure(&settings, tier).context("Couldn't configure encoder")?;
+                        }
+                    }
+                    if audio.gap_us > 0 {
+                        // The frames the lost audio would have filled are
+                        // skipped, along with the one it cut short, so
+                        // clients conceal them as they would lost packets.
+                        let partial_us = (buff.occupied_len() / channels as usize) as u64 * 1_000_000 / config.sample_rate as u64;
+                        let frame_us = samples_per_frame as u64 * 1_000_000 / config.sample_rate as u64;
+                        let skipped = ((partial_us + audio.gap_us + frame_us / 2) / frame_us).max(1);
+                        *sequence = sequence.wrapping_add(skipped as u32);
+                        buff.clear();
+                        if let Some(surround_buff) = &mut surround_buff {
+                            surround_buff.clear();
+                        }
+                        previous_silent = false;
+                    }
+                    count += audio.samples.len();
+                    if buff.is_empty() {
+                        buffered_at_us = audio.captured_at_us;
+                        frames_since_buffered = 0;
+                    }
+                    let overrun = audio.samples.len().saturating_sub(buff.vacant_len());
+                    if overrun > 0 {
+                        overrun_count += overrun;
+                        // The oldest samples make room, so the rest were captured later.
+                        buffered_at_us += (overrun / channels as usize) as u64 * 1_000_000 / config.sample_rate as u64;
+                    }
+                    buff.push_slice_overwrite(&audio.samples);
+                    if let Some(surround_buff) = &mut surround_buff {
+                        surround_buff.push_slice_overwrite(&audio.surround);
+                    }
+                    while buff.occupied_len() >= frame_len {
+                        let len = buff.pop_slice(&mut input_buffer);
+                        input_buffer[len..].fill(0);
+                        apply_volume(&mut input_buffer, volume);
+                        if let Some(surround_buff) = &mut surround_buff {
+                            let len = surround_buff.pop_slice(&mut surround_input);
+                            surround_input[len..].fill(0);
+                            apply_volume(&mut surround_input, volume);
+                        }
+                        let mut payloads: [Option<Bytes>; TIER_COUNT] = Default::default();
+                        let mut surround = None;
+                        // The downmix drops the LFE, so it's checked too.
+                        let silent = input_buffer.iter().chain(&surround_input).all(|&sample| sample == 0);
+                        silent_frames = if silent { silent_frames + 1 } else { 0 };
+                        let is_idle = idle_after_frames.is_some_and(|frames| silent_frames > frames);
+                        if idle.send_if_modified(|idle| std::mem::replace(idle, is_idle) != is_idle) {
+                            if is_idle {
+                                info!("{} stream went idle", codec);
+                            } else {
+                                info!("{} stream resumed", codec);
+                                // Start afresh rather than from the last sound.
+                                for encoder in encoders.iter_mut() {
+                                    encoder.reset().context("Couldn't reset encoder")?;
+                                }
+                                decoder_reset = true;
+                            }
+                        }
+                        if is_idle {
+                            frames_since_buffered += 1;
+                            continue;
+                        }
+                        let send_silence = dtx && silent && previous_silent;
+                        previous_silent = silent;
+                        if send_silence {
+                            let payload = streaming_protocol::silence_payload(channels as u8, samples_per_frame as u32);
+                            compressed_count += payload.len();
+                            payloads[0] = Some(Bytes::copy_from_slice(&payload));
+                            if surround_buff.is_some() && listeners[SURROUND_TIER] > 0 {
+                                let payload = streaming_protocol::silence_payload(SURROUND_CHANNELS, samples_per_frame as u32);
+                                surround = Some(Bytes::copy_from_slice(&payload));
+                            }
+                        }
+                        for (tier, encoder) in encoders.iter_mut().enumerate() {
+                            if send_silence || (tier > 0 && listeners[tier] == 0) {
+                                continue;
+                            }
+                            if tier == SURROUND_TIER {
+                                surround = Some(encoder.encode_frame(&surround_input).context("Couldn't encode")?);
+                                continue;
+                            }
+                            let payload = encoder.encode_frame(&input_buffer).context("Couldn't encode")?;
+                            if tier == 0 {
+                                compressed_count += payload.len();
+                            }
+                            payloads[tier] = Some(payload);
+                        }
+                        let offset_us = frames_since_buffered * samples_per_frame as u64 * 1_000_000 / config.sample_rate as u64;
+                        tx.send(EncodedPacket {
+                            codec: if send_silence { Codec::Silence } else { codec },
+                            sequence: *sequence,
+                            captured_at_us: buffered_at_us + offset_us,
+                            frame_samples: samples_per_frame as u16,
+                            payloads,
+                            surround,
+                            decoder_reset: std::mem::take(&mut decoder_reset),
+                        }).unwrap();
+                        frames_since_buffered += 1;
+                        *sequence = sequence.wrapping_add(1);
+                    }
+                },
+                None => return Ok(()),
+            },
+            // Once every sender is gone nobody can ask for other tiers,
+            // and this branch stays disabled.
+            Some(msg) = demand.recv() => match msg {
+                TierDemand::Join(tier) => {
+                    // Don't let a tier pick up where it left off long ago.
+                    if tier > 0 && listeners[tier] == 0 && let Some(encoder) = encoders.get_mut(tier) {
+                        encoder.reset().context("Couldn't reset encoder")?;
+                    }
+                    listeners[tier] += 1;
+                }
+                TierDemand::Leave(tier) => listeners[tier] -= 1,
+            },
+            Ok(()) = restart.changed() => {
+                info!("{} encoder restarted", codec);
+                for encoder in encoders.iter_mut() {
+                    encoder.reset().context("Couldn't reset encoder")?;
+                }
+                buff.clear();
+                if let Some(surround_buff) = &mut surround_buff {
+                    surround_buff.clear();
+                }
+                previous_silent = false;
+                decoder_reset = true;
+            }
+            _ = ticker.tick() => {
+                heartbeat.beat();
+                debug!("Bytes/sec: {}, Compressed {}/sec: {}", count, codec, compressed_count);
+                if overrun_count > 0 {
+                    let overrun_ms = overrun_count / channels as usize * 1000 / config.sample_rate as usize;
+                    warn!("{} encoder overrun, {} ms of audio dropped to keep up with capture", codec, overrun_ms);
+                }
+                count = 0;
+                compressed_count = 0;
+                overrun_count = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounded::{self, BoundedSender, Overflow};
+    use crate::health::Health;
+    use clap::Parser;
+    use opus::{Channels, Encoder};
+
+    const SAMPLE_RATE: u32 = 48_000;
+    const SAMPLES_PER_FRAME: u32 = 480;
+
+    /// Mirrors the decoder set-up in `clients/rust-native`.
+    const MAX_PCM_SAMPLES_PER_FRAME: usize = (48_000 * 120 * 2) / 1000;
+    const FRAMES: usize = 100;
+
+    /// Holds everything a test sends, however fast.
+    fn captured_audio() -> (BoundedSender<CapturedAudio>, BoundedReceiver<CapturedAudio>) {
+        bounded::channel("test", 4096, Overflow::DropNewest)
+    }
+
+    /// Settings that stay as configured for the whole test.
+    fn encoder_settings(config: &Config) -> watch::Receiver<EncoderSettings> {
+        watch::channel(config.encoder_settings()).1
+    }
+
+    /// Runs `input` through the real compress task, using the broadcast channel
+    /// as the in-memory transport, and decodes every packet like the native client.
+    async fn loopback(
+        input: &[i16],
+        chunk_len: usize,
+        channels: Channels,
+    ) -> (Vec<i16>, Vec<usize>) {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES * 2);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from(["pwtester", "--channels", &(channels as u8).to_string()]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        for chunk in input.chunks(chunk_len) {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us: 0,
+                    samples: chunk.to_vec(),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut decoder = opus::Decoder::new(SAMPLE_RATE, channels).unwrap();
+        let mut pcm_out = vec![0i16; MAX_PCM_SAMPLES_PER_FRAME];
+        let mut decoded = Vec::new();
+        let mut frame_lengths = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            let len = decoder
+                .decode(packet.payload(0), &mut pcm_out, false)
+                .unwrap();
+            frame_lengths.push(len);
+            decoded.extend_from_slice(&pcm_out[..len * channels as usize]);
+        }
+        (decoded, frame_lengths)
+    }
+
+    fn lookahead() -> usize {
+        Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Audio)
+            .unwrap()
+            .get_lookahead()
+            .unwrap() as usize
+    }
+
+    fn sine(frequency: f32, amplitude: f32, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / SAMPLE_RATE as f32;
+                (amplitude * i16::MAX as f32 * (2.0 * std::f32::consts::PI * frequency * t).sin())
+                    as i16
+            })
+            .collect()
+    }
+
+    fn psnr(reference: &[i16], decoded: &[i16]) -> f64 {
+        let mse = reference
+            .iter()
+            .zip(decoded)
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum::<f64>()
+            / reference.len() as f64;
+        10.0 * ((i16::MAX as f64).powi(2) / mse).log10()
+    }
+
+    #[tokio::test]
+    async fn every_packet_decodes_to_one_frame() {
+        let input = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * FRAMES);
+        let (decoded, frame_lengths) = loopback(&input, 128, Channels::Mono).await;
+        assert_eq!(frame_lengths.len(), FRAMES);
+        assert!(
+            frame_lengths
+                .iter()
+                .all(|&len| len == SAMPLES_PER_FRAME as usize)
+        );
+        assert_eq!(decoded.len(), input.len());
+    }
+
+    #[tokio::test]
+    async fn partial_frames_are_held_back() {
+        let frame = SAMPLES_PER_FRAME as usize;
+        let input = sine(440.0, 0.5, frame * 3 + frame / 2);
+        let (_, frame_lengths) = loopback(&input, frame / 3, Channels::Mono).await;
+        assert_eq!(frame_lengths.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn sine_survives_round_trip() {
+        let input = sine(1000.0, 0.5, SAMPLES_PER_FRAME as usize * FRAMES);
+        let (decoded, _) = loopback(&input, 1000, Channels::Mono).await;
+        let delay = lookahead();
+        // Skip the first few frames while the codec settles.
+        let settle = SAMPLES_PER_FRAME as usize * 5;
+        let reference = &input[settle..input.len() - delay];
+        let aligned = &decoded[settle + delay..];
+        let psnr = psnr(reference, aligned);
+        assert!(psnr > 30.0, "PSNR too low: {psnr:.1} dB");
+    }
+
+    #[tokio::test]
+    async fn silence_stays_silent() {
+        let input = vec![0i16; SAMPLES_PER_FRAME as usize * FRAMES];
+        let (decoded, _) = loopback(&input, 480, Channels::Mono).await;
+        let peak = decoded.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!(peak < 16, "silence decoded with peak {peak}");
+    }
+
+    #[tokio::test]
+    async fn stereo_channels_stay_separate() {
+        let frames = SAMPLES_PER_FRAME as usize * FRAMES;
+        let left = sine(440.0, 0.5, frames);
+        let right = sine(1000.0, 0.5, frames);
+        let input: Vec<i16> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(&l, &r)| [l, r])
+            .collect();
+        let (decoded, frame_lengths) = loopback(&input, 960, Channels::Stereo).await;
+        assert_eq!(frame_lengths.len(), FRAMES);
+        assert!(
+            frame_lengths
+                .iter()
+                .all(|&len| len == SAMPLES_PER_FRAME as usize)
+        );
+        assert_eq!(decoded.len(), input.len());
+
+        let delay = lookahead();
+        let settle = SAMPLES_PER_FRAME as usize * 5;
+        for (channel, reference) in [left, right].iter().enumerate() {
+            let decoded_channel: Vec<i16> =
+                decoded.iter().skip(channel).step_by(2).copied().collect();
+            let psnr = psnr(
+                &reference[settle..frames - delay],
+                &decoded_channel[settle + delay..],
+            );
+            assert!(psnr > 30.0, "channel {channel} PSNR too low: {psnr:.1} dB");
+        }
+    }
+
+    #[tokio::test]
+    async fn packets_carry_the_capture_time_of_their_first_sample() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from(["pwtester", "--channels", "1"]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        // Two and a half frames per chunk, so the third frame straddles both.
+        let chunk_len = SAMPLES_PER_FRAME as usize * 5 / 2;
+        for captured_at_us in [1_000_000, 1_025_000] {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us,
+                    samples: sine(440.0, 0.5, chunk_len),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut timestamps = Vec::new();
+        let mut sequences = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            timestamps.push(packet.captured_at_us);
+            sequences.push(packet.sequence);
+        }
+        assert_eq!(
+            timestamps,
+            [1_000_000, 1_010_000, 1_020_000, 1_030_000, 1_040_000]
+        );
+        assert_eq!(sequences, [0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn lower_tiers_are_encoded_while_clients_need_them() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let (demand_tx, demand_rx) = mpsc::unbounded_channel();
+        let config = Config::parse_from(["pwtester", "--channels", "1"]);
+        let subscription = TierSubscription::new(demand_tx, TIER_COUNT - 1);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            demand_rx,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        let input = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * FRAMES);
+        for chunk in input.chunks(SAMPLES_PER_FRAME as usize) {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us: 0,
+                    samples: chunk.to_vec(),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+        drop(subscription);
+
+        let mut packets = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            packets.push(packet);
+        }
+        let last = packets.last().unwrap();
+        assert!(last.payloads[1].is_none());
+        assert_eq!(last.payload(1), last.payload(0));
+        let full: usize = packets.iter().map(|p| p.payload(0).len()).sum();
+        let lowest: usize = packets
+            .iter()
+            .map(|p| p.payload(TIER_COUNT - 1).len())
+            .sum();
+        assert!(
+            lowest < full,
+            "lowest tier {lowest} bytes, full {full} bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn surround_keeps_the_lfe_the_downmix_drops() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let (demand_tx, demand_rx) = mpsc::unbounded_channel();
+        let config = Config::parse_from(["pwtester", "--surround"]);
+        let subscription = TierSubscription::new(demand_tx, SURROUND_TIER);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            Codec::Opus,
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            demand_rx,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        // A tone on the LFE alone, which downmixes to silence.
+        let frame_samples = SAMPLES_PER_FRAME as usize;
+        let tone = sine(60.0, 0.5, frame_samples * FRAMES);
+        for chunk in tone.chunks(frame_samples) {
+            let surround = chunk
+                .iter()
+                .flat_map(|&sample| [0, 0, 0, sample, 0, 0])
+                .collect();
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us: 0,
+                    samples: vec![0; chunk.len() * 2],
+                    surround,
+                    gap_us: 0,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+        drop(subscription);
+
+        let mut error = 0;
+        let decoder = unsafe {
+            audiopus_sys::opus_multistream_decoder_create(
+                SAMPLE_RATE as i32,
+                SURROUND_CHANNELS as i32,
+                streaming_protocol::SURROUND_STREAMS as i32,
+                streaming_protocol::SURROUND_COUPLED_STREAMS as i32,
+                streaming_protocol::SURROUND_MAPPING.as_ptr(),
+                &mut error,
+            )
+        };
+        assert_eq!(error, audiopus_sys::OPUS_OK);
+        let mut energy = [0f64; SURROUND_CHANNELS as usize];
+        let mut pcm = vec![0i16; frame_samples * SURROUND_CHANNELS as usize];
+        let mut surround_packets = 0;
+        while let Ok(packet) = packet_rx.try_recv() {
+            // The task may encode a few frames before it hears of the client.
+            if packet.surround.is_none() {
+                assert_eq!(surround_packets, 0);
+                continue;
+            }
+            surround_packets += 1;
+            assert_eq!(packet.codec(SURROUND_TIER), Codec::OpusSurround);
+            let payload = packet.payload(SURROUND_TIER);
+            let len = unsafe {
+                audiopus_sys::opus_multistream_decode(
+                    decoder,
+                    payload.as_ptr(),
+                    payload.len() as i32,
+                    pcm.as_mut_ptr(),
+                    frame_samples as i32,
+                    0,
+                )
+            };
+            assert_eq!(len, frame_samples as i32);
+            for frame in pcm.chunks_exact(SURROUND_CHANNELS as usize) {
+                for (channel, &sample) in frame.iter().enumerate() {
+                    energy[channel] += (sample as f64).powi(2);
+                }
+            }
+        }
+        unsafe { audiopus_sys::opus_multistream_decoder_destroy(decoder) };
+        assert!(surround_packets > 0);
+        let lfe = energy[3];
+        assert!(lfe > 0.0);
+        for (channel, &energy) in energy.iter().enumerate() {
+            if channel != 3 {
+                assert!(energy < lfe / 1000.0, "channel {channel}: {energy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn tier_bitrates_are_configurable_and_capped_at_the_full_bitrate() {
+        let config = Config::parse_from([
+            "pwtester",
+            "--bitrate",
+            "256000",
+            "--tier-bitrates",
+            "96000,24000",
+        ]);
+        config.validate().unwrap();
+        let settings = config.encoder_settings();
+        assert!(matches!(settings.tier_bitrate(0), Bitrate::Bits(256_000)));
+        assert!(matches!(settings.tier_bitrate(1), Bitrate::Bits(96_000)));
+        assert!(matches!(settings.tier_bitrate(2), Bitrate::Bits(24_000)));
+        let capped = EncoderSettings {
+            bitrate: Some(64_000),
+            ..settings
+        };
+        assert!(matches!(capped.tier_bitrate(1), Bitrate::Bits(64_000)));
+        for invalid in ["16000,48000", "96000", "96000,48000,16000"] {
+            let config = Config::parse_from(["pwtester", "--tier-bitrates", invalid]);
+            assert!(config.validate().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn uncompressed_frames_fit_in_a_packet() {
+        let config = |channels: &str, frame_ms: &str| {
+            Config::parse_from([
+                "pwtester",
+                "--codec",
+                "pcm",
+                "--sample-rate",
+                "192000",
+                "--channels",
+                channels,
+                "--frame-ms",
+                frame_ms,
+            ])
+        };
+        config("2", "60").validate().unwrap();
+        config("1", "120").validate().unwrap();
+        assert!(config("2", "120").validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn opus_frames_last_the_configured_duration() {
+        for (frame_ms, frame_samples) in [("2.5", 120), ("120", 5760)] {
+            let (raw_tx, raw_rx) = captured_audio();
+            let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES * 4);
+            let (_paused_tx, paused_rx) = watch::channel(false);
+            let config =
+                Config::parse_from(["pwtester", "--channels", "1", "--frame-ms", frame_ms]);
+            config.validate().unwrap();
+            let handle = spawn_compress_task(
+                Arc::new(config.clone()),
+                config.codecs[0],
+                raw_rx,
+                packet_tx,
+                paused_rx,
+                watch::channel(false).0,
+                mpsc::unbounded_channel().1,
+                encoder_settings(&config),
+                watch::channel(0).1,
+                Health::new().encoder("test"),
+            );
+            // A second of audio in PipeWire sized chunks.
+            let input = sine(440.0, 0.5, SAMPLE_RATE as usize);
+            for chunk in input.chunks(1024) {
+                raw_tx
+                    .send(CapturedAudio {
+                        captured_at_us: 0,
+                        samples: chunk.to_vec(),
+                        surround: Vec::new(),
+                        gap_us: 0,
+                    })
+                    .unwrap();
+            }
+            drop(raw_tx);
+            handle.await.unwrap();
+
+            let mut decoder = opus::Decoder::new(SAMPLE_RATE, Channels::Mono).unwrap();
+            let mut pcm_out = vec![0i16; MAX_PCM_SAMPLES_PER_FRAME];
+            let mut packets = 0;
+            while let Ok(packet) = packet_rx.try_recv() {
+                assert_eq!(packet.frame_samples, frame_samples);
+                let len = decoder
+                    .decode(packet.payload(0), &mut pcm_out, false)
+                    .unwrap();
+                assert_eq!(len, frame_samples as usize);
+                packets += 1;
+            }
+            assert_eq!(packets, SAMPLE_RATE as usize / frame_samples as usize);
+        }
+    }
+
+    #[test]
+    fn frame_durations_are_checked_against_the_codecs() {
+        let parse = |args: &[&str]| {
+            Config::parse_from(["pwtester"].iter().chain(args))
+                .validate()
+                .is_ok()
+        };
+        assert!(parse(&["--frame-ms", "2.5"]));
+        assert!(!parse(&["--frame-ms", "1"]));
+        assert!(!parse(&["--frame-ms", "150", "--codec", "flac"]));
+        // Opus only takes a few durations, FLAC any with whole samples.
+        assert!(!parse(&["--frame-ms", "15"]));
+        assert!(parse(&["--frame-ms", "15", "--codec", "flac"]));
+        assert!(!parse(&[
+            "--frame-ms",
+            "2.5",
+            "--codec",
+            "flac",
+            "--sample-rate",
+            "44100"
+        ]));
+    }
+
+    /// Encodes `input` in whole frames with `--dtx`, returning every packet.
+    async fn dtx_packets(input: &[i16]) -> Vec<EncodedPacket> {
+        let config = Config::parse_from(["pwtester", "--channels", "1", "--dtx"]);
+        encoded_frames(&config, input, watch::channel(false).0).await
+    }
+
+    /// Sends `input` a frame at a time, each captured 10 ms after the last,
+    /// and returns every packet.
+    async fn encoded_frames(
+        config: &Config,
+        input: &[i16],
+        idle: watch::Sender<bool>,
+    ) -> Vec<EncodedPacket> {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(input.len());
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            idle,
+            mpsc::unbounded_channel().1,
+            encoder_settings(config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        for (index, chunk) in input.chunks(SAMPLES_PER_FRAME as usize).enumerate() {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us: index as u64 * 10_000,
+                    samples: chunk.to_vec(),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+        let mut packets = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn digital_silence_is_sent_as_silence_packets() {
+        let frame = SAMPLES_PER_FRAME as usize;
+        let tone = sine(440.0, 0.5, frame * 5);
+        let input = [tone.clone(), vec![0; frame * 10], tone].concat();
+        let packets = dtx_packets(&input).await;
+        let codecs: Vec<Codec> = packets.iter().map(|packet| packet.codec).collect();
+        // The first silent frame still carries the tail of the tone.
+        let expected = [
+            vec![Codec::Opus; 6],
+            vec![Codec::Silence; 9],
+            vec![Codec::Opus; 5],
+        ]
+        .concat();
+        assert_eq!(codecs, expected);
+        for packet in &packets[6..15] {
+            assert_eq!(
+                streaming_protocol::parse_silence_payload(packet.payload(TIER_COUNT - 1)),
+                Some((1, SAMPLES_PER_FRAME))
+            );
+        }
+        let sequences: Vec<u32> = packets.iter().map(|packet| packet.sequence).collect();
+        assert_eq!(sequences, (0..20).collect::<Vec<u32>>());
+    }
+
+    #[tokio::test]
+    async fn overruns_drop_the_oldest_samples() {
+        let config = Config::parse_from(["pwtester", "--channels", "1"]);
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES * 3);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        // Two seconds at once, more than the buffer holds.
+        raw_tx
+            .send(CapturedAudio {
+                captured_at_us: 0,
+                samples: sine(440.0, 0.5, SAMPLE_RATE as usize * 2),
+                surround: Vec::new(),
+                gap_us: 0,
+            })
+            .unwrap();
+        drop(raw_tx);
+        handle.await.unwrap();
+        let mut packets = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            packets.push(packet);
+        }
+        // A second and a frame are kept, the rest dropped from the start.
+        assert_eq!(packets.len(), 101);
+        assert_eq!(packets[0].captured_at_us, 990_000);
+        assert_eq!(packets[100].captured_at_us, 1_990_000);
+    }
+
+    #[tokio::test]
+    async fn long_silence_stops_encoding_until_sound_returns() {
+        let frame = SAMPLES_PER_FRAME as usize;
+        let tone = sine(440.0, 0.5, frame * 5);
+        let input = [tone.clone(), vec![0; frame * 150], tone].concat();
+        let config = Config::parse_from(["pwtester", "--channels", "1", "--idle-after-secs", "1"]);
+        let (idle_tx, mut idle_rx) = watch::channel(false);
+        let packets = encoded_frames(&config, &input, idle_tx).await;
+        // A second of silence is still sent, then nothing until the tone.
+        assert_eq!(packets.len(), 5 + 100 + 5);
+        assert!(packets.iter().all(|packet| packet.codec == Codec::Opus));
+        let sequences: Vec<u32> = packets.iter().map(|packet| packet.sequence).collect();
+        assert_eq!(sequences, (0..110).collect::<Vec<u32>>());
+        assert_eq!(packets[104].captured_at_us, 104 * 10_000);
+        assert_eq!(packets[105].captured_at_us, 155 * 10_000);
+        // Decoders start afresh with the stream and once it resumes.
+        let resets: Vec<usize> = (0..packets.len())
+            .filter(|&i| packets[i].decoder_reset)
+            .collect();
+        assert_eq!(resets, [0, 105]);
+        // Went idle and back.
+        let idle = idle_rx.borrow_and_update();
+        assert!(idle.has_changed() && !*idle);
+    }
+
+    #[tokio::test]
+    async fn failed_encoders_are_recreated_and_decoders_reset() {
+        let config = Config::parse_from(["pwtester", "--channels", "1"]);
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let (settings_tx, settings_rx) = watch::channel(config.encoder_settings());
+        let health = Health::new();
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            Codec::Opus,
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            settings_rx,
+            watch::channel(0).1,
+            health.encoder("test"),
+        );
+        let tone = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize);
+        let send = |index: u64| {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us: index * 10_000,
+                    samples: tone.clone(),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .unwrap()
+        };
+        send(0);
+        assert!(packet_rx.recv().await.unwrap().decoder_reset);
+
+        // A setting the encoder turns down fails it on the next frame.
+        settings_tx.send_modify(|settings| settings.complexity = 42);
+        send(1);
+        while health.report().encoders[0].restarts == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        settings_tx.send_modify(|settings| settings.complexity = 10);
+        send(2);
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let packet = packet_rx.try_recv().unwrap();
+        assert!(packet.decoder_reset);
+        // The frame it failed on counts as lost.
+        assert_eq!(packet.sequence, 2);
+        assert_eq!(packet.captured_at_us, 20_000);
+        assert_eq!(health.report().encoders[0].restarts, 1);
+    }
+
+    #[test]
+    fn opus_packets_are_split_off_one_buffer() {
+        let config = Config::parse_from(["pwtester", "--channels", "1"]);
+        let mut encoder = tier_encoders(&config, Codec::Opus, &config.encoder_settings())
+            .unwrap()
+            .swap_remove(0);
+        let tone = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * 2);
+        let (first, second) = tone.split_at(SAMPLES_PER_FRAME as usize);
+        let first = encoder.encode_frame(first).unwrap();
+        let second = encoder.encode_frame(second).unwrap();
+        assert_eq!(first.as_ptr_range().end, second.as_ptr());
+    }
+
+    #[test]
+    fn dtx_shrinks_silent_opus_frames() {
+        let config = Config::parse_from(["pwtester", "--channels", "1", "--dtx"]);
+        let mut encoder = tier_encoders(&config, Codec::Opus, &config.encoder_settings())
+            .unwrap()
+            .swap_remove(0);
+        let silence = vec![0; SAMPLES_PER_FRAME as usize];
+        let lengths: Vec<usize> = (0..FRAMES)
+            .map(|_| encoder.encode_frame(&silence).unwrap().len())
+            .collect();
+        // Opus waits a few hundred milliseconds before it stops transmitting,
+        // then sends little but the TOC byte.
+        let empty = lengths.iter().filter(|&&len| len == 1).count();
+        assert!(empty > FRAMES / 2, "{lengths:?}");
+    }
+
+    #[tokio::test]
+    async fn flac_serves_every_tier_the_lossless_frame() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let (demand_tx, demand_rx) = mpsc::unbounded_channel();
+        let config = Config::parse_from(["pwtester", "--channels", "1", "--codec", "flac"]);
+        let _subscription = TierSubscription::new(demand_tx, TIER_COUNT - 1);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            demand_rx,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        let input = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * 10);
+        for chunk in input.chunks(SAMPLES_PER_FRAME as usize) {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us: 0,
+                    samples: chunk.to_vec(),
+                    surround: Vec::new(),
+                    gap_us: 0,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut stream = streaming_protocol::flac_stream_header(SAMPLE_RATE, 1).unwrap();
+        while let Ok(packet) = packet_rx.try_recv() {
+            assert_eq!(packet.codec, Codec::Flac);
+            assert_eq!(packet.payload(TIER_COUNT - 1), packet.payload(0));
+            stream.extend_from_slice(packet.payload(0));
+        }
+        let mut reader = claxon::FlacReader::new(std::io::Cursor::new(stream)).unwrap();
+        let decoded: Vec<i16> = reader.samples().map(|s| s.unwrap() as i16).collect();
+        assert_eq!(decoded, input);
+    }
+
+    #[tokio::test]
+    async fn pcm_passes_samples_through() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from(["pwtester", "--channels", "2", "--codec", "pcm"]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        let input = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * 2 * 4);
+        raw_tx
+            .send(CapturedAudio {
+                captured_at_us: 0,
+                samples: input.clone(),
+                surround: Vec::new(),
+                gap_us: 0,
+            })
+            .unwrap();
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut decoded = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            assert_eq!(packet.codec, Codec::Pcm);
+            let (channels, samples) =
+                streaming_protocol::parse_pcm_payload(packet.payload(0)).unwrap();
+            assert_eq!(channels, 2);
+            decoded.extend(samples);
+        }
+        assert_eq!(decoded, input);
+    }
+
+    #[tokio::test]
+    async fn capture_gaps_skip_the_frames_they_lost() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from(["pwtester", "--channels", "1", "--codec", "pcm"]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        let frame = SAMPLES_PER_FRAME as usize;
+        // A frame and a half, then 25 ms lost, then two frames.
+        for (captured_at_us, len, gap_us) in [(0, frame * 3 / 2, 0), (40_000, frame * 2, 25_000)] {
+            raw_tx
+                .send(CapturedAudio {
+                    captured_at_us,
+                    samples: vec![1; len],
+                    surround: Vec::new(),
+                    gap_us,
+                })
+                .unwrap();
+        }
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut packets = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            packets.push((packet.sequence, packet.captured_at_us));
+        }
+        // The half frame and the lost 25 ms round to three frames.
+        assert_eq!(packets, [(0, 0), (4, 40_000), (5, 50_000)]);
+    }
+
+    #[tokio::test]
+    async fn volume_scales_samples_before_encoding() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from([
+            "pwtester",
+            "--channels",
+            "1",
+            "--codec",
+            "pcm",
+            "--volume",
+            "25",
+        ]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        let input = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * 4);
+        raw_tx
+            .send(CapturedAudio {
+                captured_at_us: 0,
+                samples: input.clone(),
+                surround: Vec::new(),
+                gap_us: 0,
+            })
+            .unwrap();
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut decoded = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            decoded.extend(
+                streaming_protocol::parse_pcm_payload(packet.payload(0))
+                    .unwrap()
+                    .1,
+            );
+        }
+        let expected: Vec<i16> = input
+            .iter()
+            .map(|&sample| (sample as f32 * 0.25).round() as i16)
+            .collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[tokio::test]
+    async fn aac_frames_are_timed_by_their_sample_count() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from(["pwtester", "--channels", "1", "--codec", "aac"]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        raw_tx
+            .send(CapturedAudio {
+                captured_at_us: 1_000_000,
+                samples: sine(440.0, 0.5, aac::FRAME_LEN * 3),
+                surround: Vec::new(),
+                gap_us: 0,
+            })
+            .unwrap();
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        let mut timestamps = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            assert_eq!(packet.codec, Codec::Aac);
+            assert_eq!(
+                streaming_protocol::aac_frame_channels(packet.payload(0)),
+                Some(1)
+            );
+            timestamps.push(packet.captured_at_us);
+        }
+        // 1024 samples last 21333.3 µs at 48 kHz.
+        assert_eq!(timestamps, [1_000_000, 1_021_333, 1_042_666]);
+    }
+
+    #[tokio::test]
+    async fn constant_bitrate_fixes_the_packet_size() {
+        let (raw_tx, raw_rx) = captured_audio();
+        let (packet_tx, mut packet_rx) = broadcast::channel(FRAMES);
+        let (_paused_tx, paused_rx) = watch::channel(false);
+        let config = Config::parse_from([
+            "pwtester",
+            "--channels",
+            "1",
+            "--bitrate",
+            "64000",
+            "--cbr",
+            "--complexity",
+            "5",
+        ]);
+        let handle = spawn_compress_task(
+            Arc::new(config.clone()),
+            config.codecs[0],
+            raw_rx,
+            packet_tx,
+            paused_rx,
+            watch::channel(false).0,
+            mpsc::unbounded_channel().1,
+            encoder_settings(&config),
+            watch::channel(0).1,
+            Health::new().encoder("test"),
+        );
+        let input = sine(440.0, 0.5, SAMPLES_PER_FRAME as usize * FRAMES);
+        raw_tx
+            .send(CapturedAudio {
+                captured_at_us: 0,
+                samples: input,
+                surround: Vec::new(),
+                gap_us: 0,
+            })
+            .unwrap();
+        drop(raw_tx);
+        handle.await.unwrap();
+
+        // 64 kbit/s over 10 ms frames.
+        while let Ok(packet) = packet_rx.try_recv() {
+            assert_eq!(packet.payload(0).len(), 80);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use crate::bounded::{BoundedReceiver, BoundedSender};
+use crate::config::Config;
+use opus::{Channels, Decoder};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// Largest Opus frame at 48 kHz, in mono samples.
+const MAX_SAMPLES_PER_PACKET: usize =
+    (streaming_protocol::SAMPLE_RATE * streaming_protocol::MAX_FRAME_MS / 1000) as usize;
+
+/// Decodes the Opus packets clients send for the virtual microphone into mono
+/// PCM at the configured sample rate.
+pub fn spawn_decompress_task(
+    config: Arc<Config>,
+    rx: BoundedReceiver<Vec<u8>>,
+    tx: BoundedSender<Vec<i16>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut opus_decoder = Decoder::new(config.sample_rate, Channels::Mono).unwrap();
+        let mut output_buffer = vec![0; MAX_SAMPLES_PER_PACKET];
+        while let Some(packet) = rx.recv().await {
+            match opus_decoder.decode(&packet, &mut output_buffer, false) {
+                Ok(len) => {
+                    if tx.send(output_buffer[..len].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("Couldn't decode microphone packet: {}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,288 @@
+use crate::encode::bit_writer::BitWriter;
+use crate::encode::compress::AudioEncoder;
+use anyhow::Result;
+use bytes::Bytes;
+use streaming_protocol::Codec;
+
+/// Sample rates with a code of their own in the frame header.
+const SAMPLE_RATE_CODES: [(u32, u64); 11] = [
+    (88_200, 1),
+    (176_400, 2),
+    (192_000, 3),
+    (8_000, 4),
+    (16_000, 5),
+    (22_050, 6),
+    (24_000, 7),
+    (32_000, 8),
+    (44_100, 9),
+    (48_000, 10),
+    (96_000, 11),
+];
+/// Highest order of the fixed predictors FLAC defines.
+const MAX_FIXED_ORDER: usize = 4;
+/// Rice parameters fit in 4 bits, with 15 reserved as an escape code.
+const MAX_RICE_PARAMETER: u32 = 14;
+const BITS_PER_SAMPLE: u32 = 16;
+
+/// The coded frame number, in the UTF-8 like encoding FLAC uses.
+fn write_utf8(writer: &mut BitWriter, value: u32) {
+    if value < 0x80 {
+        writer.write(value as u64, 8);
+        return;
+    }
+    let significant = u32::BITS - value.leading_zeros();
+    // A sequence of n bytes holds 5n + 1 bits.
+    let len = (2..=7).find(|len| 5 * len + 1 >= significant).unwrap();
+    let prefix = (0xFF00u64 >> len) & 0xFF;
+    let value = value as u64;
+    writer.write(prefix | (value >> (6 * (len - 1))), 8);
+    for byte in (0..len - 1).rev() {
+        writer.write(0x80 | ((value >> (6 * byte)) & 0x3F), 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// What's left of `samples` after prediction by the fixed predictor of `order`.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+    let mut residual = samples.to_vec();
+    // Each order is the difference of the one below it.
+    for _ in 0..order {
+        residual = residual.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    }
+    residual
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// The Rice parameter coding `residual` in the fewest bits, and that size.
+fn rice_parameter(residual: &[i32]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAMETER)
+        .map(|parameter| {
+            let bits = residual
+                .iter()
+                .map(|&value| (zigzag(value) >> parameter) as u64 + 1 + parameter as u64)
+                .sum();
+            (parameter, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i32]) {
+    if samples.iter().all(|&sample| sample == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    // Warm-up samples, then residual coding method, partition order and
+    // Rice parameter.
+    let (order, residual, parameter, bits) = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (parameter, bits) = rice_parameter(&residual);
+            let bits = bits + order as u64 * BITS_PER_SAMPLE as u64 + 10;
+            (order, residual, parameter, bits)
+        })
+        .min_by_key(|&(_, _, _, bits)| bits)
+        .unwrap();
+    if bits >= verbatim_bits {
+        writer.write(0b0000_0010, 8);
+        for &sample in samples {
+            writer.write_signed(sample, BITS_PER_SAMPLE);
+        }
+        return;
+    }
+    writer.write(0b0001_0000 | (order as u64) << 1, 8);
+    for &sample in &samples[..order] {
+        writer.write_signed(sample, BITS_PER_SAMPLE);
+    }
+    // Rice coding with 4-bit parameters, in a single partition.
+    writer.write(0, 2);
+    writer.write(0, 4);
+    writer.write(parameter as u64, 4);
+    for &value in &residual {
+        let value = zigzag(value);
+        writer.write_unary(value >> parameter);
+        writer.write(value as u64, parameter);
+    }
+}
+
+/// Encodes 16-bit audio into FLAC frames, one per call, without a stream
+/// header. Channels are coded independently with FLAC's fixed predictors,
+/// which keeps it cheap enough to run per frame.
+pub struct FlacEncoder {
+    sample_rate: u32,
+    channels: usize,
+    frame_number: u32,
+}
+
+impl FlacEncoder {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            frame_number: 0,
+        }
+    }
+
+    /// Encodes one frame of interleaved samples.
+    pub fn encode(&mut self, input: &[i16]) -> Vec<u8> {
+        let block_size = input.len() / self.channels;
+        let mut writer = BitWriter::default();
+        // Sync code and fixed block size strategy.
+        writer.write(0xFFF8, 16);
+        // The block size follows the frame number as 16 bits.
+        writer.write(0b0111, 4);
+        let (rate_code, rate_extra) = self.sample_rate_code();
+        writer.write(rate_code, 4);
+        writer.write(self.channels as u64 - 1, 4);
+        writer.write(0b100, 3);
+        writer.write(0, 1);
+        write_utf8(&mut writer, self.frame_number);
+        writer.write(block_size as u64 - 1, 16);
+        if let Some((value, bits)) = rate_extra {
+            writer.write(value, bits);
+        }
+        writer.write(crc8(writer.bytes()) as u64, 8);
+
+        for channel in 0..self.channels {
+            let samples: Vec<i32> = input
+                .iter()
+                .skip(channel)
+                .step_by(self.channels)
+                .map(|&sample| sample as i32)
+                .collect();
+            write_subframe(&mut writer, &samples);
+        }
+        writer.align();
+        writer.write(crc16(writer.bytes()) as u64, 16);
+        // Frame numbers are 31 bits.
+        self.frame_number = (self.frame_number + 1) & 0x7FFF_FFFF;
+        writer.into_bytes()
+    }
+
+    /// The header's sample rate code, and the value following the header
+    /// for rates without a code of their own.
+    fn sample_rate_code(&self) -> (u64, Option<(u64, u32)>) {
+        let rate = self.sample_rate;
+        if let Some(&(_, code)) = SAMPLE_RATE_CODES.iter().find(|(r, _)| *r == rate) {
+            (code, None)
+        } else if rate.is_multiple_of(1000) && rate / 1000 <= 0xFF {
+            (0b1100, Some((rate as u64 / 1000, 8)))
+        } else if rate <= 0xFFFF {
+            (0b1101, Some((rate as u64, 16)))
+        } else if rate.is_multiple_of(10) && rate / 10 <= 0xFFFF {
+            (0b1110, Some((rate as u64 / 10, 16)))
+        } else {
+            // Read from the stream header.
+            (0b0000, None)
+        }
+    }
+}
+
+impl AudioEncoder for FlacEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Flac
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<Bytes> {
+        Ok(self.encode(frame).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_protocol::flac_stream_header;
+
+    /// Decodes `frames` the way the native client does, with the stream header
+    /// in front.
+    fn decode(sample_rate: u32, channels: usize, frames: &[Vec<u8>]) -> Vec<i16> {
+        let mut stream = flac_stream_header(sample_rate, channels as u8).unwrap();
+        for frame in frames {
+            stream.extend_from_slice(frame);
+        }
+        let mut reader = claxon::FlacReader::new(std::io::Cursor::new(stream)).unwrap();
+        reader
+            .samples()
+            .map(|sample| sample.unwrap() as i16)
+            .collect()
+    }
+
+    fn noisy_sine(len: usize, channels: usize) -> Vec<i16> {
+        let mut noise: u32 = 1;
+        (0..len * channels)
+            .map(|n| {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let t = (n / channels) as f32 / 48_000.0;
+                let tone = 12_000.0 * (2.0 * std::f32::consts::PI * 440.0 * t).sin();
+                tone as i16 + (noise >> 24) as i16 - 128
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_decode_losslessly() {
+        for channels in [1, 2] {
+            let input = noisy_sine(480 * 20, channels);
+            let mut encoder = FlacEncoder::new(48_000, channels);
+            let frames: Vec<Vec<u8>> = input
+                .chunks(480 * channels)
+                .map(|frame| encoder.encode(frame))
+                .collect();
+            assert_eq!(decode(48_000, channels, &frames), input);
+            let encoded: usize = frames.iter().map(Vec::len).sum();
+            assert!(encoded < input.len() * 2, "{encoded} bytes");
+        }
+    }
+
+    #[test]
+    fn silence_and_full_scale_noise_decode_losslessly() {
+        let mut noise: u32 = 7;
+        let loud: Vec<i16> = (0..960)
+            .map(|_| {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (noise >> 16) as i16
+            })
+            .collect();
+        let input = [vec![0; 960], loud, vec![i16::MIN; 960]].concat();
+        let mut encoder = FlacEncoder::new(44_100, 2);
+        let frames: Vec<Vec<u8>> = input.chunks(960).map(|f| encoder.encode(f)).collect();
+        assert_eq!(decode(44_100, 2, &frames), input);
+    }
+
+    #[test]
+    fn frame_numbers_past_one_byte_are_coded() {
+        let mut encoder = FlacEncoder::new(16_000, 1);
+        encoder.frame_number = 100_000;
+        let input = noisy_sine(160, 1);
+        let frame = encoder.encode(&input);
+        assert_eq!(decode(16_000, 1, &[frame]), input);
+    }
+}
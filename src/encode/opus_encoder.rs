@@ -0,0 +1,182 @@
+use anyhow::{Result, bail};
+use audiopus_sys as ffi;
+use opus::{Application, Bitrate, Channels};
+use std::ffi::{CStr, c_int};
+use std::ptr::NonNull;
+use streaming_protocol::{
+    SURROUND_CHANNELS, SURROUND_COUPLED_STREAMS, SURROUND_MAPPING, SURROUND_STREAMS,
+};
+
+/// The libopus encoder behind an `OpusEncoder`.
+enum Encoder {
+    Single(NonNull<ffi::OpusEncoder>),
+    /// Several streams in one packet, for more than two channels.
+    Multistream(NonNull<ffi::OpusMSEncoder>),
+}
+
+/// An Opus encoder exposing the tuning the `opus` crate doesn't, like the
+/// complexity setting, and multistream encoding.
+pub struct OpusEncoder {
+    encoder: Encoder,
+    channels: usize,
+}
+
+// SAFETY: libopus encoder state isn't tied to the thread that created it, and
+// every call goes through `&mut self`.
+unsafe impl Send for OpusEncoder {}
+
+fn check(function: &str, code: c_int) -> Result<c_int> {
+    if code < ffi::OPUS_OK {
+        // SAFETY: opus_strerror returns a static, nul terminated string.
+        let message = unsafe { CStr::from_ptr(ffi::opus_strerror(code)) };
+        bail!("{} failed: {}", function, message.to_string_lossy());
+    }
+    Ok(code)
+}
+
+impl OpusEncoder {
+    pub fn new(sample_rate: u32, channels: Channels, application: Application) -> Result<Self> {
+        let mut error = 0;
+        // SAFETY: the arguments are plain values and `error` outlives the call.
+        let ptr = unsafe {
+            ffi::opus_encoder_create(
+                sample_rate as i32,
+                channels as c_int,
+                application as c_int,
+                &mut error,
+            )
+        };
+        check("opus_encoder_create", error)?;
+        let Some(ptr) = NonNull::new(ptr) else {
+            bail!("opus_encoder_create returned no encoder");
+        };
+        Ok(Self {
+            encoder: Encoder::Single(ptr),
+            channels: channels as usize,
+        })
+    }
+
+    /// Encodes the six 5.1 channels into multistream packets laid out as
+    /// `SURROUND_MAPPING` says.
+    pub fn new_surround(sample_rate: u32, application: Application) -> Result<Self> {
+        let mut error = 0;
+        // SAFETY: the mapping holds one entry per channel, and `error`
+        // outlives the call.
+        let ptr = unsafe {
+            ffi::opus_multistream_encoder_create(
+                sample_rate as i32,
+                SURROUND_CHANNELS as c_int,
+                SURROUND_STREAMS as c_int,
+                SURROUND_COUPLED_STREAMS as c_int,
+                SURROUND_MAPPING.as_ptr(),
+                application as c_int,
+                &mut error,
+            )
+        };
+        check("opus_multistream_encoder_create", error)?;
+        let Some(ptr) = NonNull::new(ptr) else {
+            bail!("opus_multistream_encoder_create returned no encoder");
+        };
+        Ok(Self {
+            encoder: Encoder::Multistream(ptr),
+            channels: SURROUND_CHANNELS as usize,
+        })
+    }
+
+    /// Encodes one frame of interleaved samples into `output`, returning the
+    /// packet's length.
+    pub fn encode(&mut self, input: &[i16], output: &mut [u8]) -> Result<usize> {
+        let frame_size = (input.len() / self.channels) as c_int;
+        let max_len = output.len().min(i32::MAX as usize) as i32;
+        // SAFETY: the frame size and output length keep libopus within both
+        // buffers.
+        let len = unsafe {
+            match self.encoder {
+                Encoder::Single(ptr) => ffi::opus_encode(
+                    ptr.as_ptr(),
+                    input.as_ptr(),
+                    frame_size,
+                    output.as_mut_ptr(),
+                    max_len,
+                ),
+                Encoder::Multistream(ptr) => ffi::opus_multistream_encode(
+                    ptr.as_ptr(),
+                    input.as_ptr(),
+                    frame_size,
+                    output.as_mut_ptr(),
+                    max_len,
+                ),
+            }
+        };
+        Ok(check("opus_encode", len)? as usize)
+    }
+
+    fn set(&mut self, request: c_int, value: c_int) -> Result<()> {
+        // SAFETY: every request used here takes a single int argument, and
+        // multistream encoders pass them on to each of their streams.
+        let code = unsafe {
+            match self.encoder {
+                Encoder::Single(ptr) => ffi::opus_encoder_ctl(ptr.as_ptr(), request, value),
+                Encoder::Multistream(ptr) => {
+                    ffi::opus_multistream_encoder_ctl(ptr.as_ptr(), request, value)
+                }
+            }
+        };
+        check("opus_encoder_ctl", code).map(|_| ())
+    }
+
+    pub fn reset_state(&mut self) -> Result<()> {
+        // SAFETY: OPUS_RESET_STATE takes no arguments.
+        let code = unsafe {
+            match self.encoder {
+                Encoder::Single(ptr) => ffi::opus_encoder_ctl(ptr.as_ptr(), ffi::OPUS_RESET_STATE),
+                Encoder::Multistream(ptr) => {
+                    ffi::opus_multistream_encoder_ctl(ptr.as_ptr(), ffi::OPUS_RESET_STATE)
+                }
+            }
+        };
+        check("opus_encoder_ctl", code).map(|_| ())
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<()> {
+        let value = match bitrate {
+            Bitrate::Bits(bits) => bits,
+            Bitrate::Max => ffi::OPUS_BITRATE_MAX,
+            Bitrate::Auto => ffi::OPUS_AUTO,
+        };
+        self.set(ffi::OPUS_SET_BITRATE_REQUEST, value)
+    }
+
+    pub fn set_vbr(&mut self, vbr: bool) -> Result<()> {
+        self.set(ffi::OPUS_SET_VBR_REQUEST, vbr as c_int)
+    }
+
+    pub fn set_complexity(&mut self, complexity: i32) -> Result<()> {
+        self.set(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity)
+    }
+
+    pub fn set_inband_fec(&mut self, fec: bool) -> Result<()> {
+        self.set(ffi::OPUS_SET_INBAND_FEC_REQUEST, fec as c_int)
+    }
+
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<()> {
+        self.set(ffi::OPUS_SET_DTX_REQUEST, dtx as c_int)
+    }
+
+    pub fn set_packet_loss_perc(&mut self, percent: i32) -> Result<()> {
+        self.set(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, percent)
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        // SAFETY: the encoder was created by the matching create function and
+        // is only destroyed here.
+        unsafe {
+            match self.encoder {
+                Encoder::Single(ptr) => ffi::opus_encoder_destroy(ptr.as_ptr()),
+                Encoder::Multistream(ptr) => ffi::opus_multistream_encoder_destroy(ptr.as_ptr()),
+            }
+        }
+    }
+}
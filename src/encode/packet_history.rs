@@ -0,0 +1,84 @@
+use crate::encode::compress::EncodedPacket;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// The last `--retransmit-history` of a codec's packets, by sequence number,
+/// for resending what clients report lost on their way as datagrams.
+#[derive(Clone)]
+pub struct PacketHistory {
+    packets: Arc<Mutex<VecDeque<EncodedPacket>>>,
+    capacity: usize,
+}
+
+impl PacketHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: Arc::default(),
+            capacity,
+        }
+    }
+
+    /// Adds the newest packet, dropping the oldest beyond the capacity.
+    pub fn push(&self, packet: EncodedPacket) {
+        let mut packets = self.packets.lock().expect("Packet history lock poisoned");
+        if packets.len() == self.capacity {
+            packets.pop_front();
+        }
+        packets.push_back(packet);
+    }
+
+    /// The packet numbered `sequence`, if it's still kept. Sequence numbers
+    /// skip the frames lost in capture glitches, so it's searched for from
+    /// where it would be without gaps on.
+    pub fn get(&self, sequence: u32) -> Option<EncodedPacket> {
+        let packets = self.packets.lock().expect("Packet history lock poisoned");
+        let newest = packets.back()?.sequence;
+        let age = newest.wrapping_sub(sequence) as usize;
+        packets
+            .iter()
+            .skip(packets.len().saturating_sub(age.saturating_add(1)))
+            .find(|packet| packet.sequence == sequence)
+            .cloned()
+    }
+}
+
+pub fn spawn_packet_history_task(
+    history: PacketHistory,
+    mut receiver: broadcast::Receiver<EncodedPacket>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(packet) => history.push(packet),
+                // Packets missed here just can't be resent.
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_protocol::Codec;
+
+    #[test]
+    fn keeps_the_newest_packets_by_sequence() {
+        let history = PacketHistory::new(4);
+        assert!(history.get(0).is_none());
+        // 4 and 5 were lost in a capture glitch.
+        for sequence in [u32::MAX - 1, u32::MAX, 0, 1, 2, 3, 6] {
+            history.push(EncodedPacket::for_test(Codec::Opus, sequence, 0));
+        }
+        let sequence = |sequence| history.get(sequence).map(|packet| packet.sequence);
+        assert_eq!(sequence(6), Some(6));
+        assert_eq!(sequence(1), Some(1));
+        assert_eq!(sequence(3), Some(3));
+        assert_eq!(sequence(u32::MAX), None);
+        assert_eq!(sequence(5), None);
+        assert_eq!(sequence(7), None);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::encode::compress::EncodedPacket;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+
+/// The last `--startup-burst-ms` of a codec's packets, sent to clients ahead
+/// of the live ones so their jitter buffers start out full.
+#[derive(Clone)]
+pub struct RecentPackets {
+    packets: Arc<Mutex<VecDeque<EncodedPacket>>>,
+    /// Follows `--startup-burst-ms` as the `--config` file changes.
+    span_ms: watch::Receiver<u32>,
+}
+
+fn unix_time_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+impl RecentPackets {
+    pub fn new(span_ms: watch::Receiver<u32>) -> Self {
+        Self {
+            packets: Arc::default(),
+            span_ms,
+        }
+    }
+
+    fn span_us(&self) -> u64 {
+        *self.span_ms.borrow() as u64 * 1000
+    }
+
+    /// Adds the newest packet, dropping the ones captured more than the span
+    /// before it.
+    pub fn push(&self, packet: EncodedPacket) {
+        let oldest_us = packet.captured_at_us.saturating_sub(self.span_us());
+        let mut packets = self.packets.lock().expect("Recent packets lock poisoned");
+        packets.push_back(packet);
+        while packets
+            .front()
+            .is_some_and(|packet| packet.captured_at_us < oldest_us)
+        {
+            packets.pop_front();
+        }
+    }
+
+    /// The packets captured within the span before `now_us`, oldest first.
+    /// None are left once the sink went idle or the stream was paused.
+    fn since(&self, now_us: u64) -> Vec<EncodedPacket> {
+        let span_us = self.span_us();
+        if span_us == 0 {
+            return Vec::new();
+        }
+        let oldest_us = now_us.saturating_sub(span_us);
+        self.packets
+            .lock()
+            .expect("Recent packets lock poisoned")
+            .iter()
+            .filter(|packet| packet.captured_at_us >= oldest_us)
+            .cloned()
+            .collect()
+    }
+
+    /// The packets captured within the span before now, oldest first.
+    pub fn burst(&self) -> Vec<EncodedPacket> {
+        self.since(unix_time_us())
+    }
+}
+
+pub fn spawn_recent_packets_task(
+    recent: RecentPackets,
+    mut receiver: broadcast::Receiver<EncodedPacket>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(packet) => recent.push(packet),
+                // A gap in the history only shortens bursts for a moment.
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_protocol::Codec;
+
+    #[test]
+    fn only_the_span_before_now_is_kept_and_sent() {
+        let (span_tx, span_rx) = watch::channel(20);
+        let recent = RecentPackets::new(span_rx);
+        for sequence in 0..5 {
+            let captured_at_us = 1_000_000 + sequence as u64 * 10_000;
+            recent.push(EncodedPacket::for_test(
+                Codec::Opus,
+                sequence,
+                captured_at_us,
+            ));
+        }
+        let sequences = |packets: Vec<EncodedPacket>| -> Vec<u32> {
+            packets.iter().map(|packet| packet.sequence).collect()
+        };
+        assert_eq!(sequences(recent.since(1_040_000)), [2, 3, 4]);
+        assert_eq!(sequences(recent.since(1_055_000)), [4]);
+        assert!(recent.since(2_000_000).is_empty());
+
+        // A shorter span takes effect at once, and none sends nothing.
+        span_tx.send_replace(10);
+        assert_eq!(sequences(recent.since(1_045_000)), [4]);
+        span_tx.send_replace(0);
+        assert!(recent.since(1_040_000).is_empty());
+    }
+}
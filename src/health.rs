@@ -0,0 +1,178 @@
+use crate::capture::xrun::CaptureGlitches;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// An encoder that hasn't checked in for this long counts as stuck. They
+/// check in every second, audio or not.
+const ENCODER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What `/healthz` and `/readyz` report: whether PipeWire, the encoders and
+/// the WebTransport endpoint are up.
+#[derive(Clone, Default)]
+pub struct Health {
+    pipewire_connected: Arc<AtomicBool>,
+    webtransport_listening: Arc<AtomicBool>,
+    /// When each encoder last checked in, by name.
+    encoders: Arc<Mutex<BTreeMap<String, EncoderState>>>,
+    captures: Arc<Mutex<Vec<Arc<CaptureGlitches>>>>,
+}
+
+struct EncoderState {
+    last_beat: Instant,
+    /// Times the encoder failed and was recreated.
+    restarts: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EncoderHealth {
+    pub name: String,
+    pub alive: bool,
+    pub secs_since_heartbeat: f64,
+    pub restarts: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// The process works: PipeWire is connected and every encoder is alive.
+    pub healthy: bool,
+    /// It's healthy and clients can connect over WebTransport.
+    pub ready: bool,
+    pub pipewire_connected: bool,
+    pub webtransport_listening: bool,
+    pub encoders: Vec<EncoderHealth>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_pipewire_connected(&self, connected: bool) {
+        self.pipewire_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn set_webtransport_listening(&self, listening: bool) {
+        self.webtransport_listening
+            .store(listening, Ordering::Relaxed);
+    }
+
+    /// Lists an encoder, alive for now. It stays listed if its task dies, so
+    /// that shows as a missing heartbeat.
+    pub fn encoder(&self, name: impl Into<String>) -> EncoderHeartbeat {
+        let heartbeat = EncoderHeartbeat {
+            health: self.clone(),
+            name: name.into(),
+        };
+        heartbeat.beat();
+        heartbeat
+    }
+
+    /// The glitch counters of `sink`'s capture, which last as long as the
+    /// process does, whatever node the sink captures.
+    pub fn capture(&self, sink: &str) -> Arc<CaptureGlitches> {
+        let mut captures = self.captures.lock().expect("Capture health lock poisoned");
+        if let Some(glitches) = captures.iter().find(|glitches| glitches.sink == sink) {
+            return glitches.clone();
+        }
+        let glitches = CaptureGlitches::new(sink);
+        captures.push(glitches.clone());
+        glitches
+    }
+
+    pub fn captures(&self) -> Vec<Arc<CaptureGlitches>> {
+        self.captures
+            .lock()
+            .expect("Capture health lock poisoned")
+            .clone()
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let now = Instant::now();
+        let encoders: Vec<EncoderHealth> = self
+            .encoders
+            .lock()
+            .expect("Encoder health lock poisoned")
+            .iter()
+            .map(|(name, state)| EncoderHealth {
+                name: name.clone(),
+                alive: now.duration_since(state.last_beat) < ENCODER_TIMEOUT,
+                secs_since_heartbeat: now.duration_since(state.last_beat).as_secs_f64(),
+                restarts: state.restarts,
+            })
+            .collect();
+        let pipewire_connected = self.pipewire_connected.load(Ordering::Relaxed);
+        let webtransport_listening = self.webtransport_listening.load(Ordering::Relaxed);
+        let healthy = pipewire_connected && encoders.iter().all(|encoder| encoder.alive);
+        HealthReport {
+            healthy,
+            ready: healthy && webtransport_listening,
+            pipewire_connected,
+            webtransport_listening,
+            encoders,
+        }
+    }
+}
+
+/// Lets an encoder task tell `Health` it's still running.
+pub struct EncoderHeartbeat {
+    health: Health,
+    name: String,
+}
+
+impl EncoderHeartbeat {
+    fn update(&self, update: impl FnOnce(&mut EncoderState)) {
+        let mut encoders = self
+            .health
+            .encoders
+            .lock()
+            .expect("Encoder health lock poisoned");
+        let state = encoders
+            .entry(self.name.clone())
+            .or_insert_with(|| EncoderState {
+                last_beat: Instant::now(),
+                restarts: 0,
+            });
+        update(state);
+    }
+
+    pub fn beat(&self) {
+        self.update(|state| state.last_beat = Instant::now());
+    }
+
+    /// Counts a failure the encoder was recreated after.
+    pub fn restarted(&self) {
+        self.update(|state| state.restarts += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_once_everything_is_up_and_encoders_keep_beating() {
+        let health = Health::new();
+        let heartbeat = health.encoder("opus encoder of default");
+        let report = health.report();
+        assert!(!report.healthy && !report.ready);
+        assert_eq!(report.encoders[0].name, "opus encoder of default");
+
+        health.set_pipewire_connected(true);
+        assert!(health.report().healthy && !health.report().ready);
+        health.set_webtransport_listening(true);
+        assert!(health.report().ready);
+
+        // A heartbeat from long ago, as if the task had died.
+        heartbeat.update(|state| state.last_beat = Instant::now() - ENCODER_TIMEOUT);
+        let report = health.report();
+        assert!(!report.healthy && !report.ready && !report.encoders[0].alive);
+        heartbeat.beat();
+        heartbeat.restarted();
+        let report = health.report();
+        assert!(report.ready);
+        assert_eq!(report.encoders[0].restarts, 1);
+    }
+}
@@ -0,0 +1,18 @@
+//! The streaming server as building blocks: `capture::Capture` feeds PipeWire
+//! audio to an `encode::Encoder` per sink and codec, whose packets a
+//! `transport::Transport` serves. `pipeline::Pipeline` wires the last two up
+//! as the server binary does.
+#[macro_use]
+pub mod logging;
+pub mod bounded;
+pub mod capture;
+pub mod config;
+pub mod config_reload;
+pub mod control;
+pub mod dbus;
+pub mod encode;
+pub mod health;
+pub mod now_playing;
+pub mod pipeline;
+pub mod realtime;
+pub mod transport;
@@ -1,121 +1,94 @@
 use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
 
-use compress::spawn_compress_thread;
-use http::spawn_http_thread;
-use libspa::pod;
-use libspa::utils::Direction;
-use pipewire as pw;
-use tokio::sync::{broadcast, watch};
-use webtransport::spawn_webtransport_thread;
+use pipewire_streaming::capture::Capture;
+use pipewire_streaming::capture::pipewire_registry::print_nodes;
+use pipewire_streaming::config::Config;
+use pipewire_streaming::config_reload::spawn_config_reload_task;
+use pipewire_streaming::dbus::spawn_dbus_task;
+use pipewire_streaming::logging;
+use pipewire_streaming::now_playing::spawn_now_playing_task;
+use pipewire_streaming::pipeline::Pipeline;
+use pipewire_streaming::realtime::{RealtimeThread, spawn_realtime_task};
+use pipewire_streaming::transport::mdns;
+use tokio::sync::mpsc;
 
-mod compress;
-mod http;
-mod webtransport;
-
-const SAMPLE_RATE: u32 = 48_000;
-const OPUS_FRAME_MS: u32 = 10;
-const SAMPLES_PER_FRAME: u32 = (SAMPLE_RATE * OPUS_FRAME_MS) / 1000;
-const WEBTRANSPORT_PORT: u16 = 13345;
-const HTTP_PORT: u16 = 13346;
-
-struct SinkData {
-    sender: crossbeam_channel::Sender<Vec<i16>>,
-}
+/// How long the tasks get to finish once PipeWire's loop quits.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() {
-    let (raw_packet_tx, raw_packet_rx) = crossbeam_channel::unbounded();
-    let (compressed_packet_tx, compressed_packet_rx) = broadcast::channel(200);
-    let _webtransport_handle = spawn_webtransport_thread(compressed_packet_rx, WEBTRANSPORT_PORT);
-    let _worker_handle = spawn_compress_thread(raw_packet_rx, compressed_packet_tx);
-    let _http_handle = spawn_http_thread();
-
-    pw::init();
-    let main_loop = pw::main_loop::MainLoop::new(None).expect("Couldn't create PipeWire MainLoop");
-    let context = pw::context::Context::new(&main_loop).expect("Couldn't create PipeWire Context");
-    let core = context.connect(None).expect("Couldn't connect to PipeWire");
-    let stream = pw::stream::Stream::new(
-        &core,
-        "fake-audio-sink",
-        pw::properties::properties! {
-            *pw::keys::MEDIA_CLASS => "Audio/Sink",
-            *pw::keys::AUDIO_CHANNELS => "6",
-            *pw::keys::MEDIA_TYPE => "Audio",
-            *pw::keys::MEDIA_CATEGORY => "Playback",
-            *pw::keys::MEDIA_ROLE => "Music",
-            *pw::keys::NODE_NAME => "fake-speaker",
-            *pw::keys::NODE_DESCRIPTION => "Fake Speaker",
-            *pw::keys::NODE_LATENCY => "1000/48000",
-        },
-    )
-    .expect("Couldn't create PipeWire stream");
-
-    let sink_data = SinkData {
-        sender: raw_packet_tx.clone(),
-    };
-    let _listener = stream
-        .add_local_listener_with_user_data(sink_data)
-        .process(move |stream, user_data| {
-            stream.dequeue_buffer().map(|mut buffer| {
-                let channels = buffer.datas_mut();
-                let data = &mut channels[0];
-                let actual_size = data.chunk().size();
-
-                if !channels.is_empty() {
-                    if let Some(samples) = channels[0].data() {
-                        let packet_bytes: Vec<_> = samples[0..actual_size as usize]
-                            .chunks_exact(2)
-                            .map(|chunk| {
-                                let bytes: [u8; 2] = chunk.try_into().unwrap();
-                                i16::from_le_bytes(bytes)
-                            })
-                            .collect();
-                        user_data.sender.send(packet_bytes).unwrap()
-                    }
-                }
-            });
-        })
-        .register()
-        .expect("Couldn't register stream listener");
+    let (config, config_options) = Config::from_args();
+    logging::set_level(config.log_level);
+    let config = Arc::new(config);
+    // Everything but PipeWire's loop runs on this runtime.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't start tokio!");
+    let runtime_guard = runtime.enter();
+    let (realtime_tx, realtime_rx) = mpsc::unbounded_channel();
+    let _realtime_handle = config
+        .realtime
+        .then(|| spawn_realtime_task(realtime_rx, config.realtime_priority));
+    // Encoders get threads of their own, which `--realtime` raises.
+    let mut encoders = tokio::runtime::Builder::new_multi_thread();
+    encoders.enable_all().thread_name("encoder");
+    if let Some(threads) = config.encoder_threads {
+        encoders.worker_threads(threads.into());
+    }
+    if config.realtime {
+        let realtime_tx = realtime_tx.clone();
+        encoders.on_thread_start(move || {
+            let _ = realtime_tx.send(RealtimeThread::current("Encoder thread"));
+        });
+    }
+    let encoders = encoders
+        .build()
+        .expect("Couldn't start the encoder runtime!");
 
-    let mut audio_info = libspa::param::audio::AudioInfoRaw::new();
-    audio_info.set_format(libspa::param::audio::AudioFormat::S16P);
-    audio_info.set_channels(6);
-    audio_info.set_rate(SAMPLE_RATE);
-    let mut positions = [0; pw::spa::param::audio::MAX_CHANNELS];
-    positions[0..6].copy_from_slice(&[
-        pw::spa::sys::SPA_AUDIO_CHANNEL_FL,
-        pw::spa::sys::SPA_AUDIO_CHANNEL_FR,
-        pw::spa::sys::SPA_AUDIO_CHANNEL_FC,
-        pw::spa::sys::SPA_AUDIO_CHANNEL_LFE,
-        pw::spa::sys::SPA_AUDIO_CHANNEL_SL,
-        pw::spa::sys::SPA_AUDIO_CHANNEL_SR,
-    ]);
-    audio_info.set_position(positions);
+    let mut capture = Capture::connect(&config);
+    if config.list_nodes {
+        print_nodes(&capture.nodes().lock().expect("Node list lock poisoned"));
+        return;
+    }
+    let capture_target = capture.capture_target();
 
-    let obj = pw::spa::pod::Object {
-        type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
-        id: pw::spa::param::ParamType::EnumFormat.as_raw(),
-        properties: audio_info.into(),
-    };
-    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
-        std::io::Cursor::new(Vec::new()),
-        &pw::spa::pod::Value::Object(obj),
-    )
-    .unwrap()
-    .0
-    .into_inner();
-    let mut params = [pod::Pod::from_bytes(&values).unwrap()];
+    let mut pipeline = Pipeline::new(
+        &config,
+        capture_target.as_ref().map(|target| target.name.clone()),
+        encoders.handle(),
+    );
+    let (control, health) = (pipeline.control.clone(), pipeline.health.clone());
+    capture.watch_health(health);
+    capture.start(
+        mem::take(&mut pipeline.sinks),
+        capture_target.as_ref(),
+        &control,
+    );
+    if let Some(mic) = pipeline.mic.take() {
+        capture.play_mic(mic);
+    }
+    // The servers start once there's a certificate.
+    runtime.block_on(pipeline.serve(&config, capture.nodes()));
+    let _now_playing_handle = spawn_now_playing_task(control.clone());
+    let _config_reload_handle = config
+        .config
+        .is_some()
+        .then(|| spawn_config_reload_task(config.clone(), config_options, control.clone()));
+    let _dbus_handle = spawn_dbus_task(control);
+    let _mdns_daemon = (!config.no_mdns).then(|| {
+        mdns::advertise(&config)
+            .inspect_err(|e| pipewire_streaming::warn!("Couldn't advertise over mDNS: {:#}", e))
+            .ok()
+    });
 
-    stream
-        .connect(
-            Direction::Input,
-            None,
-            pw::stream::StreamFlags::AUTOCONNECT
-                | pw::stream::StreamFlags::MAP_BUFFERS
-                | pw::stream::StreamFlags::RT_PROCESS,
-            &mut params,
-        )
-        .expect("Failed to connect stream");
-    main_loop.run();
-    stream.disconnect().expect("Couldn't disconnect stream");
+    if config.realtime {
+        let _ = realtime_tx.send(RealtimeThread::current("PipeWire's main loop"));
+    }
+    drop(realtime_tx);
+    capture.run();
+    drop(runtime_guard);
+    encoders.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
 }
@@ -0,0 +1,108 @@
+use crate::control::ControlBus;
+use std::collections::HashMap;
+use std::time::Duration;
+use streaming_protocol::NowPlaying;
+use tokio::task::JoinHandle;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedValue;
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+/// The native client's own player, which would echo the stream's title back.
+const CLIENT_PREFIX: &str = "org.mpris.MediaPlayer2.pipewire_streaming";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+/// How often the media players are asked what they play.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The title and artists of MPRIS `metadata`.
+fn track(metadata: &HashMap<String, OwnedValue>) -> NowPlaying {
+    let title = metadata
+        .get("xesam:title")
+        .and_then(|title| title.downcast_ref::<String>().ok())
+        .filter(|title| !title.is_empty());
+    let artist = metadata
+        .get("xesam:artist")
+        .and_then(|artists| artists.try_clone().ok())
+        .and_then(|artists| Vec::<String>::try_from(artists).ok())
+        .map(|artists| artists.join(", "))
+        .filter(|artist| !artist.is_empty());
+    NowPlaying { title, artist }
+}
+
+/// What the first media player playing plays, if any is.
+async fn playing_track(connection: &zbus::Connection) -> zbus::Result<NowPlaying> {
+    let names = zbus::fdo::DBusProxy::new(connection)
+        .await?
+        .list_names()
+        .await?;
+    let players = names
+        .iter()
+        .filter(|name| name.starts_with(MPRIS_PREFIX) && !name.starts_with(CLIENT_PREFIX));
+    for name in players {
+        let player = zbus::proxy::Builder::<zbus::Proxy<'_>>::new(connection)
+            .destination(name.as_str())?
+            .path(MPRIS_PATH)?
+            .interface(PLAYER_INTERFACE)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        // Players may vanish or misbehave between the listing and the call.
+        let Ok(status) = player.get_property::<String>("PlaybackStatus").await else {
+            continue;
+        };
+        if status != "Playing" {
+            continue;
+        }
+        if let Ok(metadata) = player.get_property("Metadata").await {
+            return Ok(track(&metadata));
+        }
+    }
+    Ok(NowPlaying::default())
+}
+
+/// Keeps the control bus' now playing up to date with the media players on
+/// the session bus. They aren't tied to a sink, so every sink gets the track
+/// of the first player playing.
+pub fn spawn_now_playing_task(control: ControlBus) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let connection = match zbus::Connection::session().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Can't see what media players play: {e}");
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match playing_track(&connection).await {
+                Ok(track) => control.set_now_playing(track),
+                Err(e) => warn!("Failed to list the media players: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zbus::zvariant::{Str, Value};
+
+    #[test]
+    fn metadata_gives_the_title_and_artists() {
+        let artists = Value::from(vec!["A", "B"]).try_into().unwrap();
+        let metadata = HashMap::from([
+            (String::from("xesam:title"), Str::from("Song").into()),
+            (String::from("xesam:artist"), artists),
+        ]);
+        assert_eq!(
+            track(&metadata),
+            NowPlaying {
+                title: Some(String::from("Song")),
+                artist: Some(String::from("A, B")),
+            }
+        );
+        let untitled = HashMap::from([(String::from("xesam:title"), Str::from("").into())]);
+        assert_eq!(track(&untitled), NowPlaying::default());
+    }
+}
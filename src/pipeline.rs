@@ -0,0 +1,125 @@
+use crate::bounded::{self, BoundedReceiver, DropCounter, Overflow, spawn_overflow_task};
+use crate::capture::SinkOutputs;
+use crate::capture::levels::{LevelHistory, SinkLevelHistory, spawn_levels_task};
+use crate::capture::pipewire_registry::SharedNodeList;
+use crate::capture::xrun::spawn_xrun_task;
+use crate::config::{Config, SinkSpec};
+use crate::control::{Access, ControlBus};
+use crate::encode::Encoder;
+use crate::encode::decompress::spawn_decompress_task;
+use crate::health::Health;
+use crate::transport::Transport;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+
+/// Levels queued for the levels task, a few seconds' worth.
+const LEVELS_CAPACITY: usize = 32;
+/// Microphone packets, and the audio decoded from them, queued on the way to
+/// the virtual source. The oldest go first, so latency can't build up.
+const MIC_CAPACITY: usize = 50;
+
+/// Everything between capturing the sinks and serving their audio: an
+/// `Encoder` per sink and codec, and the `Transport` getting what they encode
+/// to listeners. Whatever captures the audio feeds it through `sinks`.
+pub struct Pipeline {
+    pub control: ControlBus,
+    pub health: Health,
+    /// What each sink's capture feeds, in the order of `Config::sinks`.
+    pub sinks: Vec<(SinkSpec, SinkOutputs)>,
+    /// Microphone audio decoded from clients, with `--mic-source`.
+    pub mic: Option<BoundedReceiver<Vec<i16>>>,
+    transport: Transport,
+    drop_counters: Vec<Arc<DropCounter>>,
+}
+
+impl Pipeline {
+    /// Starts encoding every sink of `config` on `encoders`, along with the
+    /// tasks recording and republishing the sinks. `capture_node` is the
+    /// node captured in place of the virtual sinks, if any. Must be called
+    /// within a tokio runtime.
+    pub fn new(config: &Arc<Config>, capture_node: Option<String>, encoders: &Handle) -> Self {
+        let control = ControlBus::new(
+            config.encoder_settings(),
+            config.dsp_filters.clone(),
+            capture_node,
+            Access {
+                key: Arc::from(config.access_key.as_str()),
+                password: config.password.as_deref().map(Arc::from),
+            },
+        );
+        control.set_startup_burst_ms(config.startup_burst_ms);
+        let health = Health::new();
+        let mut transport = Transport::new();
+        let mut sinks = Vec::new();
+        let mut drop_counters = Vec::new();
+        for sink in config.sinks() {
+            // Each codec is encoded by its own task, from its own copy of the audio.
+            let mut raw_packet_txs = Vec::new();
+            let mut codec_packets = Vec::new();
+            for &codec in &config.codecs {
+                let _encoders_guard = encoders.enter();
+                let encoder = Encoder::spawn(config, &sink, codec, &control, &health);
+                drop_counters.push(encoder.input.counter());
+                raw_packet_txs.push(encoder.input);
+                codec_packets.push(encoder.output);
+            }
+            let (level_tx, level_rx) = bounded::channel(
+                format!("The level meter of {}", sink.id),
+                LEVELS_CAPACITY,
+                Overflow::DropNewest,
+            );
+            drop_counters.push(level_tx.counter());
+            let level_history =
+                Arc::new(Mutex::new(LevelHistory::new(config.layout.channel_names())));
+            let _levels_handle = spawn_levels_task(level_rx, level_history.clone());
+            let levels = SinkLevelHistory {
+                id: sink.id.clone(),
+                history: level_history,
+            };
+            if let Some(snapcast_tx) =
+                transport.add_sink(config, &sink, codec_packets, levels, &control)
+            {
+                drop_counters.push(snapcast_tx.counter());
+                raw_packet_txs.push(snapcast_tx);
+            }
+
+            let outputs = SinkOutputs {
+                senders: raw_packet_txs,
+                level_sender: level_tx,
+                glitches: health.capture(&sink.id),
+            };
+            sinks.push((sink, outputs));
+        }
+
+        let mut mic = None;
+        if config.mic_source {
+            let (packet_tx, packet_rx) =
+                bounded::channel("The microphone decoder", MIC_CAPACITY, Overflow::DropOldest);
+            let (pcm_tx, pcm_rx) =
+                bounded::channel("The virtual microphone", MIC_CAPACITY, Overflow::DropOldest);
+            drop_counters.extend([packet_tx.counter(), pcm_tx.counter()]);
+            let _decompress_handle = spawn_decompress_task(config.clone(), packet_rx, pcm_tx);
+            transport.set_mic(packet_tx);
+            mic = Some(pcm_rx);
+        }
+        Self {
+            control,
+            health,
+            sinks,
+            mic,
+            transport,
+            drop_counters,
+        }
+    }
+
+    /// Starts serving clients, once there's a certificate, and the tasks
+    /// watching over the pipeline. `nodes` are the audio nodes clients may
+    /// pick to capture.
+    pub async fn serve(self, config: &Arc<Config>, nodes: SharedNodeList) {
+        let _overflow_handle = spawn_overflow_task(self.drop_counters);
+        let _xrun_handle = spawn_xrun_task(self.health.captures());
+        self.transport
+            .serve(config, self.control, self.health, nodes)
+            .await;
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use std::io;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// CPU time a realtime thread may use without blocking before the kernel
+/// steps in, which rtkit only promotes processes that promise. PipeWire
+/// limits its own threads the same way.
+const RTTIME_LIMIT_US: u64 = 200_000;
+/// Nice level asked for when realtime scheduling isn't allowed.
+const HIGH_PRIORITY_NICE: i32 = -11;
+
+/// A thread asking for realtime priority with `--realtime`.
+pub struct RealtimeThread {
+    pub name: String,
+    id: libc::pid_t,
+}
+
+impl RealtimeThread {
+    /// The calling thread.
+    pub fn current(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            // SAFETY: gettid has no preconditions.
+            id: unsafe { libc::gettid() },
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Schedules `thread` with SCHED_FIFO at `priority`, leaving any process it
+/// forks at normal priority.
+fn set_fifo(thread: libc::pid_t, priority: u32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority as libc::c_int,
+    };
+    // SAFETY: `param` outlives the call.
+    check(unsafe {
+        libc::sched_setscheduler(thread, libc::SCHED_FIFO | libc::SCHED_RESET_ON_FORK, &param)
+    })
+}
+
+fn set_nice(thread: libc::pid_t, nice: i32) -> io::Result<()> {
+    // SAFETY: setpriority takes plain values. On Linux a thread id names the
+    // thread alone.
+    check(unsafe { libc::setpriority(libc::PRIO_PROCESS, thread as libc::id_t, nice) })
+}
+
+/// Promises rtkit that realtime threads won't spin, lowering the limit if
+/// it's higher.
+fn limit_rttime() -> io::Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` outlives both calls.
+    check(unsafe { libc::getrlimit(libc::RLIMIT_RTTIME, &mut limit) })?;
+    let max = limit.rlim_max.min(RTTIME_LIMIT_US as libc::rlim_t);
+    limit = libc::rlimit {
+        rlim_cur: max,
+        rlim_max: max,
+    };
+    check(unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limit) })
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.RealtimeKit1",
+    default_service = "org.freedesktop.RealtimeKit1",
+    default_path = "/org/freedesktop/RealtimeKit1",
+    gen_blocking = false
+)]
+trait RealtimeKit {
+    fn make_thread_realtime(&self, thread: u64, priority: u32) -> zbus::Result<()>;
+
+    fn make_thread_high_priority(&self, thread: u64, priority: i32) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn max_realtime_priority(&self) -> zbus::Result<i32>;
+
+    #[zbus(property)]
+    fn min_nice_level(&self) -> zbus::Result<i32>;
+}
+
+async fn connect_rtkit() -> Result<RealtimeKitProxy<'static>> {
+    let connection = zbus::Connection::system().await.context("No system bus")?;
+    Ok(RealtimeKitProxy::new(&connection).await?)
+}
+
+/// The realtime priority rtkit grants, which is capped at its maximum.
+fn rtkit_priority(priority: u32, max: i32) -> u32 {
+    priority.min(max.max(1) as u32)
+}
+
+/// Raises `thread` as far as allowed, saying how far.
+async fn promote(
+    thread: &RealtimeThread,
+    priority: u32,
+    rtkit: Option<&RealtimeKitProxy<'_>>,
+) -> io::Result<String> {
+    if set_fifo(thread.id, priority).is_ok() {
+        return Ok(format!("realtime priority {}", priority));
+    }
+    if let Some(rtkit) = rtkit {
+        let max = rtkit.max_realtime_priority().await.unwrap_or(1);
+        let priority = rtkit_priority(priority, max);
+        if rtkit
+            .make_thread_realtime(thread.id as u64, priority)
+            .await
+            .is_ok()
+        {
+            return Ok(format!("realtime priority {} through rtkit", priority));
+        }
+        let min_nice = rtkit.min_nice_level().await.unwrap_or(HIGH_PRIORITY_NICE);
+        let nice = HIGH_PRIORITY_NICE.max(min_nice);
+        if rtkit
+            .make_thread_high_priority(thread.id as u64, nice)
+            .await
+            .is_ok()
+        {
+            return Ok(format!("nice level {} through rtkit", nice));
+        }
+    }
+    set_nice(thread.id, HIGH_PRIORITY_NICE)?;
+    Ok(format!("nice level {}", HIGH_PRIORITY_NICE))
+}
+
+/// Raises each thread sent on `threads` to SCHED_FIFO at `priority`, asking
+/// rtkit when the process may not itself, and settling for a high nice
+/// level, or else normal priority, when that fails too.
+pub fn spawn_realtime_task(
+    mut threads: mpsc::UnboundedReceiver<RealtimeThread>,
+    priority: u32,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = limit_rttime() {
+            warn!("Couldn't limit realtime CPU time: {}", e);
+        }
+        let rtkit = connect_rtkit()
+            .await
+            .inspect_err(|e| warn!("rtkit is unavailable: {:#}", e))
+            .ok();
+        while let Some(thread) = threads.recv().await {
+            match promote(&thread, priority, rtkit.as_ref()).await {
+                Ok(how) => info!("{} runs at {}", thread.name, how),
+                Err(e) => warn!(
+                    "{} runs at normal priority, as it can't be raised: {}",
+                    thread.name, e
+                ),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priorities_are_capped_at_what_rtkit_grants() {
+        assert_eq!(rtkit_priority(10, 20), 10);
+        assert_eq!(rtkit_priority(50, 20), 20);
+        assert_eq!(rtkit_priority(50, 0), 1);
+    }
+}
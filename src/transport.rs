@@ -0,0 +1,182 @@
+pub mod acme;
+pub mod cert_manager;
+pub mod fmp4;
+pub mod hls;
+pub mod http;
+pub mod icecast;
+pub mod mdns;
+pub mod metrics;
+pub mod multicast;
+pub mod ogg;
+pub mod pairing;
+pub mod recorder;
+pub mod session_limits;
+pub mod session_stats;
+pub mod snapcast;
+pub mod web_assets;
+pub mod webtransport;
+
+use crate::bounded::{self, BoundedSender, Overflow};
+use crate::capture::levels::SinkLevelHistory;
+use crate::capture::pipewire_registry::SharedNodeList;
+use crate::config::{Config, SinkSpec};
+use crate::control::ControlBus;
+use crate::encode::compress::CapturedAudio;
+use crate::encode::{CAPTURED_AUDIO_CAPACITY, CodecPackets};
+use crate::health::Health;
+use acme::spawn_acme_task;
+use cert_manager::spawn_cert_task;
+use hls::{HlsStream, spawn_hls_task};
+use http::spawn_http_task;
+use icecast::spawn_icecast_task;
+use multicast::spawn_multicast_task;
+use recorder::{Recorder, spawn_recorder_task};
+use session_stats::SessionRegistry;
+use snapcast::spawn_snapcast_task;
+use std::sync::Arc;
+use streaming_protocol::Codec;
+use webtransport::{SinkPackets, spawn_webtransport_task};
+
+/// Gets the sinks' audio to listeners: WebTransport clients, the HTTPS API
+/// and web client, and whichever of HLS, Snapcast, Icecast, multicast and
+/// recording are configured.
+#[derive(Default)]
+pub struct Transport {
+    sinks: Vec<SinkPackets>,
+    level_histories: Vec<SinkLevelHistory>,
+    hls_streams: Vec<HlsStream>,
+    recorders: Vec<Recorder>,
+    mic: Option<BoundedSender<Vec<u8>>>,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offers `sink`, encoded in `codecs`, with levels from `levels`, and
+    /// starts packaging it for HLS, Icecast, multicast or recording as
+    /// configured.
+    /// Returns the input of the Snapcast server if it serves this sink, as
+    /// it takes the audio before encoding.
+    pub fn add_sink(
+        &mut self,
+        config: &Arc<Config>,
+        sink: &SinkSpec,
+        codecs: Vec<CodecPackets>,
+        levels: SinkLevelHistory,
+        control: &ControlBus,
+    ) -> Option<BoundedSender<CapturedAudio>> {
+        let packets = |codec| {
+            codecs
+                .iter()
+                .find(|packets: &&CodecPackets| packets.codec == codec)
+                .map(|packets| packets.receiver.resubscribe())
+        };
+        if let Some(codec) = config.hls_codec().filter(|_| config.hls) {
+            let packets = packets(codec).expect("The HLS codec is one of the sink's codecs");
+            let hls_stream = HlsStream::new(config, sink.id.clone(), codec)
+                .expect("The HLS codec fits in MP4 files");
+            let _hls_handle = spawn_hls_task(config.clone(), hls_stream.clone(), codec, packets);
+            self.hls_streams.push(hls_stream);
+        }
+        if config.icecast.is_some() && sink.id == config.icecast_sink_id() {
+            let packets = packets(Codec::Opus).expect("Icecast streams are Opus");
+            let _icecast_handle =
+                spawn_icecast_task(config.clone(), sink.description.clone(), packets);
+        }
+        if config.multicast.is_some() && sink.id == config.multicast_sink_id() {
+            let packets = packets(Codec::Opus).expect("Multicast streams are Opus");
+            let _multicast_handle =
+                spawn_multicast_task(config.clone(), sink.description.clone(), packets);
+        }
+        if config.record_dir.is_some() {
+            let packets = packets(config.record_codec)
+                .expect("The recording codec is one of the sink's codecs");
+            let recorder = Recorder::new(config.clone(), sink.id.clone());
+            let _recorder_handle = spawn_recorder_task(recorder.clone(), packets);
+            self.recorders.push(recorder);
+        }
+        self.sinks.push(SinkPackets {
+            id: sink.id.clone(),
+            codecs,
+        });
+        self.level_histories.push(levels);
+        (config.snapcast && sink.id == config.snapcast_sink_id()).then(|| {
+            let (raw_packet_tx, raw_packet_rx) = bounded::channel(
+                format!("The Snapcast server of {}", sink.id),
+                CAPTURED_AUDIO_CAPACITY,
+                Overflow::DropOldest,
+            );
+            let _snapcast_handle =
+                spawn_snapcast_task(config.clone(), raw_packet_rx, control.clone());
+            raw_packet_tx
+        })
+    }
+
+    /// Passes the microphone audio clients send on to `mic`, with
+    /// `--mic-source`.
+    pub fn set_mic(&mut self, mic: BoundedSender<Vec<u8>>) {
+        self.mic = Some(mic);
+    }
+
+    /// Starts the WebTransport and HTTPS servers, once there's a certificate.
+    /// `nodes` are the audio nodes clients may pick to capture.
+    pub async fn serve(
+        self,
+        config: &Arc<Config>,
+        control: ControlBus,
+        health: Health,
+        nodes: SharedNodeList,
+    ) {
+        let _cert_handle = match config.renew_cert {
+            true => Some(spawn_cert_task(config.clone(), control.clone()).await),
+            false => None,
+        };
+        let _acme_handle = match config.acme_domains.is_empty() {
+            false => Some(spawn_acme_task(config.clone(), control.clone()).await),
+            true => None,
+        };
+        let sessions = SessionRegistry::new();
+        let _webtransport_handle = spawn_webtransport_task(
+            config.clone(),
+            self.sinks,
+            control.clone(),
+            sessions.clone(),
+            self.mic,
+            health.clone(),
+        );
+        let _http_handle = spawn_http_task(
+            config.clone(),
+            self.level_histories,
+            self.hls_streams,
+            self.recorders,
+            nodes,
+            sessions,
+            control,
+            health,
+        );
+    }
+}
+
+/// Compares `a` and `b` in time independent of where they differ, so
+/// guessers can't learn a key, code or credential a character at a time.
+pub(crate) fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_match_whole() {
+        assert!(secrets_match("123456", "123456"));
+        assert!(!secrets_match("123456", "123457"));
+        assert!(!secrets_match("12345", "123456"));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::config::Config;
+use crate::control::ControlBus;
+use anyhow::{Context, Result, anyhow};
+use futures::StreamExt;
+use rustls_acme::acme::{LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY};
+use rustls_acme::caches::DirCache;
+use rustls_acme::futures_rustls::LazyConfigAcceptor;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use rustls_acme::futures_rustls::rustls::server::Acceptor;
+use rustls_acme::{AcmeConfig, CertCache, EventOk, is_tls_alpn_challenge};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+const KEY_END: &str = "-----END PRIVATE KEY-----";
+
+fn directory_url(config: &Config) -> &'static str {
+    if config.acme_staging {
+        LETS_ENCRYPT_STAGING_DIRECTORY
+    } else {
+        LETS_ENCRYPT_PRODUCTION_DIRECTORY
+    }
+}
+
+/// Copies the certificate cached for the configured domains, if any, to
+/// `--cert` and `--key`. The cache holds the private key followed by the
+/// certificate chain in one PEM file.
+async fn deploy_cached(config: &Config, cache: &DirCache<PathBuf>) -> Result<bool> {
+    let Some(pem) = cache
+        .load_cert(&config.acme_domains, directory_url(config))
+        .await?
+    else {
+        return Ok(false);
+    };
+    let pem = String::from_utf8(pem).context("Cached certificate isn't PEM")?;
+    let key_len = pem
+        .find(KEY_END)
+        .ok_or_else(|| anyhow!("Cached certificate has no private key"))?
+        + KEY_END.len();
+    let (key, chain) = pem.split_at(key_len);
+    std::fs::write(&config.key, format!("{}\n", key.trim()))
+        .with_context(|| format!("Couldn't write {}", config.key.display()))?;
+    std::fs::write(&config.cert, format!("{}\n", chain.trim()))
+        .with_context(|| format!("Couldn't write {}", config.cert.display()))?;
+    Ok(true)
+}
+
+/// Answers Let's Encrypt's TLS-ALPN-01 challenges, dropping every other
+/// connection.
+async fn serve_challenges(port: u16, challenge_config: Arc<ServerConfig>) -> Result<()> {
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    loop {
+        let (tcp, _) = listener.accept().await?;
+        let challenge_config = challenge_config.clone();
+        tokio::spawn(async move {
+            let Ok(handshake) = LazyConfigAcceptor::new(Acceptor::default(), tcp.compat()).await
+            else {
+                return;
+            };
+            if is_tls_alpn_challenge(&handshake.client_hello()) {
+                // The handshake itself answers the challenge.
+                let _ = handshake.into_stream(challenge_config).await;
+            }
+        });
+    }
+}
+
+/// Keeps `--cert` and `--key` holding a Let's Encrypt certificate for
+/// `--acme-domain`, ordering and renewing it in the background. A cached
+/// certificate is deployed before returning, so the servers start with it.
+pub async fn spawn_acme_task(config: Arc<Config>, control: ControlBus) -> JoinHandle<()> {
+    let cache = DirCache::new(config.acme_cache.clone());
+    match deploy_cached(&config, &cache).await {
+        Ok(true) => info!("Using the cached certificate for {:?}", config.acme_domains),
+        Ok(false) => info!("Ordering a certificate for {:?}", config.acme_domains),
+        Err(e) => warn!("Couldn't deploy the cached certificate: {:#}", e),
+    }
+    tokio::spawn(async move {
+        let mut state = AcmeConfig::new(&config.acme_domains)
+            .contact(
+                config
+                    .acme_email
+                    .iter()
+                    .map(|email| format!("mailto:{}", email)),
+            )
+            .cache(DirCache::new(config.acme_cache.clone()))
+            .directory(directory_url(&config))
+            .state();
+        let challenge_config = state.challenge_rustls_config();
+        let port = config.acme_challenge_port;
+        tokio::spawn(async move {
+            if let Err(e) = serve_challenges(port, challenge_config).await {
+                warn!("Can't answer ACME challenges on port {}: {}", port, e);
+            }
+        });
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(EventOk::CertCacheStore) => match deploy_cached(&config, &cache).await {
+                    Ok(_) => {
+                        info!("Got a new certificate for {:?}", config.acme_domains);
+                        control.certificate_renewed();
+                    }
+                    Err(e) => warn!("Couldn't deploy the new certificate: {:#}", e),
+                },
+                Ok(_) => {}
+                Err(e) => warn!("ACME: {}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,82 @@
+use crate::config::Config;
+use crate::control::ControlBus;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use wtransport::Identity;
+use wtransport::tls::CertificateChain;
+
+/// Validity of generated certificates. Browsers only trust certificates by
+/// their hash if they're valid for at most 14 days.
+const VALIDITY_DAYS: u32 = 13;
+/// Certificates are renewed once they have less than this left.
+const RENEW_BEFORE: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Time left until the certificate in `path` expires, or `None` if it can't
+/// be read.
+async fn remaining_validity(path: &Path) -> Option<Duration> {
+    let chain = CertificateChain::load_pemfile(path).await.ok()?;
+    let (_, certificate) =
+        x509_parser::parse_x509_certificate(chain.as_slice().first()?.der()).ok()?;
+    let not_after = certificate.validity().not_after.timestamp();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    let remaining = not_after.saturating_sub(now).max(0) as u64;
+    Some(Duration::from_secs(remaining))
+}
+
+/// Writes a new self-signed certificate for this host and its key.
+async fn generate(config: &Config) -> Result<()> {
+    let mut names = vec![String::from("localhost"), String::from("127.0.0.1")];
+    if let Ok(addr) = local_ip_address::local_ip() {
+        names.push(addr.to_string());
+    }
+    let identity = Identity::self_signed_builder()
+        .subject_alt_names(&names)
+        .from_now_utc()
+        .validity_days(VALIDITY_DAYS)
+        .build()?;
+    identity
+        .private_key()
+        .store_secret_pemfile(&config.key)
+        .await
+        .with_context(|| format!("Couldn't write {}", config.key.display()))?;
+    identity
+        .certificate_chain()
+        .store_pemfile(&config.cert)
+        .await
+        .with_context(|| format!("Couldn't write {}", config.cert.display()))?;
+    Ok(())
+}
+
+/// Generates the certificate if it's missing or about to expire, and tells
+/// the servers to reload it.
+async fn renew_if_needed(config: &Config, control: &ControlBus) {
+    if remaining_validity(&config.cert)
+        .await
+        .is_some_and(|remaining| remaining >= RENEW_BEFORE)
+    {
+        return;
+    }
+    match generate(config).await {
+        Ok(()) => {
+            info!("Generated a certificate valid for {} days", VALIDITY_DAYS);
+            control.certificate_renewed();
+        }
+        Err(e) => warn!("Couldn't renew the certificate: {:#}", e),
+    }
+}
+
+/// Keeps `--cert` and `--key` holding a valid self-signed certificate. The
+/// first check happens before returning, so the servers start with one.
+pub async fn spawn_cert_task(config: Arc<Config>, control: ControlBus) -> JoinHandle<()> {
+    renew_if_needed(&config, &control).await;
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            renew_if_needed(&config, &control).await;
+        }
+    })
+}
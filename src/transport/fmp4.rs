@@ -0,0 +1,329 @@
+use anyhow::{Result, bail};
+use streaming_protocol::Codec;
+
+/// The only track of the files.
+const TRACK_ID: u32 = 1;
+/// Opus tracks count time in 48 kHz samples, whatever the encoder's rate.
+const OPUS_TIMESCALE: u32 = 48_000;
+/// Identity transform of `mvhd` and `tkhd`.
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// The audio a fragmented MP4 file carries.
+#[derive(Clone, Copy, Debug)]
+pub struct TrackFormat {
+    codec: Codec,
+    sample_rate: u32,
+    channels: u8,
+}
+
+/// One encoded frame in a media segment, lasting `duration` in the track's
+/// timescale.
+pub struct Sample {
+    pub duration: u32,
+    pub data: Vec<u8>,
+}
+
+impl TrackFormat {
+    /// Only Opus and AAC frames have a standard place in MP4 files.
+    pub fn new(codec: Codec, sample_rate: u32, channels: u8) -> Result<Self> {
+        if !matches!(codec, Codec::Opus | Codec::Aac) {
+            bail!("Can't put {} audio in MP4 files", codec);
+        }
+        Ok(Self {
+            codec,
+            sample_rate,
+            channels,
+        })
+    }
+
+    pub fn timescale(&self) -> u32 {
+        match self.codec {
+            Codec::Opus => OPUS_TIMESCALE,
+            _ => self.sample_rate,
+        }
+    }
+
+    /// Duration of `samples` samples per channel, in the track's timescale.
+    pub fn duration(&self, samples: u64) -> u64 {
+        samples * self.timescale() as u64 / self.sample_rate as u64
+    }
+}
+
+/// Writes a box of type `kind`, its contents written by `body`.
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Writes a box starting with a version and flags.
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&((version as u32) << 24 | flags).to_be_bytes());
+        body(out);
+    });
+}
+
+fn write_u16s(out: &mut Vec<u8>, values: &[u16]) {
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// An MPEG-4 descriptor, which `esds` boxes nest. Ours are all short enough
+/// for a one byte size.
+fn write_descriptor(out: &mut Vec<u8>, tag: u8, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[tag, 0]);
+    body(out);
+    out[start + 1] = (out.len() - start - 2) as u8;
+}
+
+/// The `Opus` or `mp4a` sample entry describing the track's frames.
+fn write_sample_entry(out: &mut Vec<u8>, format: &TrackFormat) {
+    let kind = match format.codec {
+        Codec::Opus => b"Opus",
+        _ => b"mp4a",
+    };
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&[0; 6]);
+        // Data reference index, then two reserved words.
+        write_u16s(out, &[1]);
+        write_u32s(out, &[0, 0]);
+        write_u16s(out, &[format.channels as u16, 16, 0, 0]);
+        // 16.16 fixed point, so rates above 65535 Hz don't fit.
+        write_u32s(out, &[format.timescale().min(u16::MAX as u32) << 16]);
+        match format.codec {
+            Codec::Opus => write_box(out, b"dOps", |out| {
+                out.extend_from_slice(&[0, format.channels]);
+                // No pre-skip, since players join the live stream mid-way.
+                write_u16s(out, &[0]);
+                write_u32s(out, &[format.sample_rate]);
+                // No output gain, channel mapping family 0.
+                out.extend_from_slice(&[0, 0, 0]);
+            }),
+            _ => write_full_box(out, b"esds", 0, 0, |out| {
+                let config = streaming_protocol::aac_audio_specific_config(
+                    format.sample_rate,
+                    format.channels,
+                )
+                .expect("AAC sample rates are validated with the config");
+                write_descriptor(out, 0x03, |out| {
+                    // ES id, no stream dependence, URL or OCR stream.
+                    write_u16s(out, &[0]);
+                    out.push(0);
+                    write_descriptor(out, 0x04, |out| {
+                        // MPEG-4 audio in an audio stream, unknown buffer size
+                        // and bitrates.
+                        out.extend_from_slice(&[0x40, 0x15, 0, 0, 0]);
+                        write_u32s(out, &[0, 0]);
+                        write_descriptor(out, 0x05, |out| out.extend_from_slice(&config));
+                    });
+                    write_descriptor(out, 0x06, |out| out.push(0x02));
+                });
+            }),
+        }
+    });
+}
+
+/// The initialization segment: the file type and a movie box describing the
+/// track, with its samples left to the media segments.
+pub fn init_segment(format: &TrackFormat) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"iso6");
+        write_u32s(out, &[0]);
+        out.extend_from_slice(b"iso6mp41");
+    });
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            // Creation and modification time, timescale, unknown duration,
+            // normal rate and volume.
+            write_u32s(out, &[0, 0, format.timescale(), 0, 0x0001_0000]);
+            write_u16s(out, &[0x0100, 0]);
+            write_u32s(out, &[0, 0]);
+            write_u32s(out, &MATRIX);
+            write_u32s(out, &[0; 6]);
+            write_u32s(out, &[TRACK_ID + 1]);
+        });
+        write_box(out, b"trak", |out| {
+            // Enabled and in the movie.
+            write_full_box(out, b"tkhd", 0, 0x3, |out| {
+                write_u32s(out, &[0, 0, TRACK_ID, 0, 0, 0, 0]);
+                // Layer, alternate group, full volume.
+                write_u16s(out, &[0, 0, 0x0100, 0]);
+                write_u32s(out, &MATRIX);
+                // No width or height.
+                write_u32s(out, &[0, 0]);
+            });
+            write_box(out, b"mdia", |out| {
+                write_full_box(out, b"mdhd", 0, 0, |out| {
+                    write_u32s(out, &[0, 0, format.timescale(), 0]);
+                    // Undetermined language, packed as three 5 bit letters.
+                    write_u16s(out, &[0x55c4, 0]);
+                });
+                write_full_box(out, b"hdlr", 0, 0, |out| {
+                    write_u32s(out, &[0]);
+                    out.extend_from_slice(b"soun");
+                    write_u32s(out, &[0; 3]);
+                    out.extend_from_slice(b"SoundHandler\0");
+                });
+                write_box(out, b"minf", |out| {
+                    write_full_box(out, b"smhd", 0, 0, |out| write_u32s(out, &[0]));
+                    write_box(out, b"dinf", |out| {
+                        write_full_box(out, b"dref", 0, 0, |out| {
+                            write_u32s(out, &[1]);
+                            // The samples are in this file.
+                            write_full_box(out, b"url ", 0, 0x1, |_| {});
+                        });
+                    });
+                    write_box(out, b"stbl", |out| {
+                        write_full_box(out, b"stsd", 0, 0, |out| {
+                            write_u32s(out, &[1]);
+                            write_sample_entry(out, format);
+                        });
+                        write_full_box(out, b"stts", 0, 0, |out| write_u32s(out, &[0]));
+                        write_full_box(out, b"stsc", 0, 0, |out| write_u32s(out, &[0]));
+                        write_full_box(out, b"stsz", 0, 0, |out| write_u32s(out, &[0, 0]));
+                        write_full_box(out, b"stco", 0, 0, |out| write_u32s(out, &[0]));
+                    });
+                });
+            });
+        });
+        write_box(out, b"mvex", |out| {
+            // Samples use the first sample entry and give their own duration,
+            // size and flags.
+            write_full_box(out, b"trex", 0, 0, |out| {
+                write_u32s(out, &[TRACK_ID, 1, 0, 0, 0]);
+            });
+        });
+    });
+    out
+}
+
+/// A media segment holding `samples`, the first decoded at `decode_time` in
+/// the track's timescale. `sequence` counts up by one per segment.
+pub fn media_segment(sequence: u32, decode_time: u64, samples: &[Sample]) -> Vec<u8> {
+    let mut out = Vec::new();
+    // Where in `out` the offset of the first sample goes, once it's known.
+    let mut data_offset_at = 0;
+    write_box(&mut out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| write_u32s(out, &[sequence]));
+        write_box(out, b"traf", |out| {
+            // Sample offsets count from the start of the `moof` box.
+            write_full_box(out, b"tfhd", 0, 0x02_0000, |out| {
+                write_u32s(out, &[TRACK_ID])
+            });
+            write_full_box(out, b"tfdt", 1, 0, |out| {
+                out.extend_from_slice(&decode_time.to_be_bytes());
+            });
+            // A data offset, then each sample's duration and size.
+            write_full_box(out, b"trun", 0, 0x301, |out| {
+                write_u32s(out, &[samples.len() as u32]);
+                data_offset_at = out.len();
+                write_u32s(out, &[0]);
+                for sample in samples {
+                    write_u32s(out, &[sample.duration, sample.data.len() as u32]);
+                }
+            });
+        });
+    });
+    // The samples follow the `mdat` box's header.
+    let data_offset = (out.len() + 8) as u32;
+    out[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+    write_box(&mut out, b"mdat", |out| {
+        for sample in samples {
+            out.extend_from_slice(&sample.data);
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::aac::AacEncoder;
+    use std::io::Cursor;
+    use symphonia_core::codecs::{CODEC_TYPE_AAC, CODEC_TYPE_OPUS};
+    use symphonia_core::formats::{FormatOptions, FormatReader};
+    use symphonia_core::io::MediaSourceStream;
+    use symphonia_format_isomp4::IsoMp4Reader;
+
+    fn read(file: Vec<u8>) -> IsoMp4Reader {
+        let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+        IsoMp4Reader::try_new(source, &FormatOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn aac_segments_demux_to_the_frames_muxed() {
+        let format = TrackFormat::new(Codec::Aac, 48_000, 2).unwrap();
+        let mut encoder = AacEncoder::new(48_000, 2);
+        let frames: Vec<Vec<u8>> = (0..10)
+            .map(|_| encoder.encode(&vec![0; 2 * 1024]))
+            .collect();
+        let mut file = init_segment(&format);
+        for (sequence, chunk) in frames.chunks(5).enumerate() {
+            let samples: Vec<Sample> = chunk
+                .iter()
+                .map(|frame| Sample {
+                    duration: format.duration(1024) as u32,
+                    data: frame.clone(),
+                })
+                .collect();
+            let decode_time = sequence as u64 * 5 * 1024;
+            file.extend(media_segment(sequence as u32 + 1, decode_time, &samples));
+        }
+
+        let mut reader = read(file);
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.codec, CODEC_TYPE_AAC);
+        assert_eq!(params.sample_rate, Some(48_000));
+        for (index, frame) in frames.iter().enumerate() {
+            let packet = reader.next_packet().unwrap();
+            assert_eq!(packet.ts, index as u64 * 1024);
+            assert_eq!(packet.dur, 1024);
+            assert_eq!(&packet.data[..], &frame[..]);
+        }
+        assert!(reader.next_packet().is_err());
+    }
+
+    #[test]
+    fn opus_tracks_count_time_at_48_khz() {
+        let format = TrackFormat::new(Codec::Opus, 24_000, 1).unwrap();
+        assert_eq!(format.duration(240), 480);
+        let mut file = init_segment(&format);
+        let samples = [Sample {
+            duration: 480,
+            data: vec![0xf8, 0xff, 0xfe],
+        }];
+        file.extend(media_segment(1, 0, &samples));
+
+        let mut reader = read(file);
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.codec, CODEC_TYPE_OPUS);
+        // The `dOps` box, after the magic of the Ogg header it mirrors.
+        assert_eq!(
+            params.extra_data.as_deref(),
+            Some(&b"OpusHead\0\x01\0\0\0\0\x5d\xc0\0\0\0"[..])
+        );
+        let packet = reader.next_packet().unwrap();
+        assert_eq!((packet.ts, packet.dur), (0, 480));
+        assert_eq!(&packet.data[..], &[0xf8, 0xff, 0xfe]);
+        assert!(TrackFormat::new(Codec::Flac, 48_000, 2).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::config::Config;
+use crate::encode::compress::{EncodedPacket, samples_per_frame};
+use crate::transport::fmp4::{self, Sample, TrackFormat};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use streaming_protocol::Codec;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// Segments listed in the playlist, and kept for players to fetch.
+const LIVE_SEGMENTS: usize = 6;
+
+/// A finished media segment.
+struct Segment {
+    sequence: u32,
+    duration_secs: f64,
+    data: Vec<u8>,
+}
+
+/// The live playlist of one sink and the segments it lists.
+pub struct Playlist {
+    format: TrackFormat,
+    init: Vec<u8>,
+    target_duration_secs: u32,
+    segments: VecDeque<Segment>,
+    next_sequence: u32,
+}
+
+impl Playlist {
+    pub fn new(format: TrackFormat, target_duration_secs: u32) -> Self {
+        Self {
+            format,
+            init: fmp4::init_segment(&format),
+            target_duration_secs,
+            segments: VecDeque::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Lists a new segment, dropping the oldest beyond `LIVE_SEGMENTS`.
+    fn push(&mut self, duration_secs: f64, data: Vec<u8>) {
+        self.segments.push_back(Segment {
+            sequence: self.next_sequence,
+            duration_secs,
+            data,
+        });
+        self.next_sequence += 1;
+        if self.segments.len() > LIVE_SEGMENTS {
+            self.segments.pop_front();
+        }
+    }
+
+    pub fn init(&self) -> &[u8] {
+        &self.init
+    }
+
+    pub fn segment(&self, sequence: u32) -> Option<&[u8]> {
+        self.segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| segment.data.as_slice())
+    }
+
+    /// The playlist as served, adding `query` to the URIs of the files it
+    /// lists.
+    pub fn render(&self, query: &str) -> String {
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+        let first = self.segments.front().map_or(0, |segment| segment.sequence);
+        // Writing to a string can't fail.
+        let _ = write!(
+            playlist,
+            "#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-MAP:URI=\"init.mp4?{}\"\n",
+            self.target_duration_secs, first, query
+        );
+        for segment in &self.segments {
+            let _ = write!(
+                playlist,
+                "#EXTINF:{:.3},\n{}.m4s?{}\n",
+                segment.duration_secs, segment.sequence, query
+            );
+        }
+        playlist
+    }
+
+    pub fn format(&self) -> &TrackFormat {
+        &self.format
+    }
+}
+
+/// The playlist of one sink, shared by the task filling it and the HTTPS
+/// server serving it.
+#[derive(Clone)]
+pub struct HlsStream {
+    pub sink: String,
+    pub playlist: Arc<Mutex<Playlist>>,
+}
+
+impl HlsStream {
+    pub fn new(config: &Config, sink: String, codec: Codec) -> anyhow::Result<Self> {
+        let format = TrackFormat::new(codec, config.sample_rate, config.channels)?;
+        Ok(Self {
+            sink,
+            playlist: Arc::new(Mutex::new(Playlist::new(format, config.hls_segment_secs))),
+        })
+    }
+}
+
+/// Gathers encoded frames into media segments of at least the target
+/// duration.
+struct Segmenter {
+    format: TrackFormat,
+    /// Target duration in the track's timescale.
+    target: u64,
+    samples: Vec<Sample>,
+    /// Decode time of the first sample gathered, and of the next one.
+    start: u64,
+    decode_time: u64,
+    /// Counts segments for their `mfhd` box, from 1.
+    sequence: u32,
+}
+
+impl Segmenter {
+    fn new(format: TrackFormat, target_secs: u32) -> Self {
+        Self {
+            format,
+            target: target_secs as u64 * format.timescale() as u64,
+            samples: Vec::new(),
+            start: 0,
+            decode_time: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Adds a frame, returning the segment it completes with its duration in
+    /// seconds. Silence packets carry no frame, so they end the segment and
+    /// leave a gap in the timeline.
+    fn push(&mut self, packet: &EncodedPacket) -> Option<(f64, Vec<u8>)> {
+        if packet.codec == Codec::Silence {
+            return self.skip(packet.frame_samples as u64);
+        }
+        if self.samples.is_empty() {
+            self.start = self.decode_time;
+        }
+        let duration = self.format.duration(packet.frame_samples as u64);
+        self.samples.push(Sample {
+            duration: duration as u32,
+            data: packet.payload(0).to_vec(),
+        });
+        self.decode_time += duration;
+        (self.decode_time - self.start >= self.target)
+            .then(|| self.finish())
+            .flatten()
+    }
+
+    /// Leaves out `samples` samples per channel, ending the segment.
+    fn skip(&mut self, samples: u64) -> Option<(f64, Vec<u8>)> {
+        let finished = self.finish();
+        self.decode_time += self.format.duration(samples);
+        finished
+    }
+
+    fn finish(&mut self) -> Option<(f64, Vec<u8>)> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        self.sequence += 1;
+        let segment = fmp4::media_segment(self.sequence, self.start, &self.samples);
+        self.samples.clear();
+        let duration = (self.decode_time - self.start) as f64 / self.format.timescale() as f64;
+        Some((duration, segment))
+    }
+}
+
+/// Packages the packets of `receiver` into the stream's playlist.
+pub fn spawn_hls_task(
+    config: Arc<Config>,
+    stream: HlsStream,
+    codec: Codec,
+    mut receiver: broadcast::Receiver<EncodedPacket>,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let format = *stream
+            .playlist
+            .lock()
+            .expect("HLS playlist lock poisoned")
+            .format();
+        let mut segmenter = Segmenter::new(format, config.hls_segment_secs);
+        loop {
+            let finished = match receiver.blocking_recv() {
+                Ok(packet) => segmenter.push(&packet),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(
+                        "HLS packager for {} lagged, {} packets missed",
+                        stream.sink, missed
+                    );
+                    segmenter.skip(missed * samples_per_frame(&config, codec) as u64)
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if let Some((duration_secs, data)) = finished {
+                stream
+                    .playlist
+                    .lock()
+                    .expect("HLS playlist lock poisoned")
+                    .push(duration_secs, data);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence_ends_segments_and_old_ones_leave_the_playlist() {
+        let format = TrackFormat::new(Codec::Opus, 48_000, 2).unwrap();
+        let mut segmenter = Segmenter::new(format, 1);
+        let opus = EncodedPacket::for_test(Codec::Opus, 0, 0);
+        let silence = EncodedPacket::for_test(Codec::Silence, 0, 0);
+        // 100 frames of 10 ms make up a segment.
+        for _ in 0..99 {
+            assert!(segmenter.push(&opus).is_none());
+        }
+        let (duration, _) = segmenter.push(&opus).unwrap();
+        assert_eq!(duration, 1.0);
+        segmenter.push(&opus);
+        let (duration, _) = segmenter.push(&silence).unwrap();
+        assert_eq!(duration, 0.01);
+        assert!(segmenter.push(&silence).is_none());
+        segmenter.push(&opus);
+        assert_eq!(segmenter.start, 48_000 + 3 * 480);
+
+        let mut playlist = Playlist::new(format, 1);
+        for _ in 0..LIVE_SEGMENTS + 2 {
+            playlist.push(1.0, Vec::new());
+        }
+        assert!(playlist.segment(1).is_none());
+        assert!(playlist.segment(7).is_some());
+        let rendered = playlist.render("token=t");
+        assert!(rendered.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+        assert!(rendered.contains("#EXT-X-MAP:URI=\"init.mp4?token=t\"\n"));
+        assert!(rendered.ends_with("#EXTINF:1.000,\n7.m4s?token=t\n"));
+    }
+}
@@ -0,0 +1,1595 @@
+use crate::capture::dsp::{self, Filter};
+use crate::capture::levels::{LEVEL_HISTORY, LEVEL_INTERVAL, Level, SinkLevelHistory};
+use crate::capture::pipewire_registry::{NodeInfo, SharedNodeList};
+use crate::config::Config;
+use crate::control::ControlBus;
+use crate::health::{Health, HealthReport};
+use crate::transport::hls::HlsStream;
+use crate::transport::metrics;
+use crate::transport::pairing::{ClientAccess, ClientInfo, PairOutcome, Pairing};
+use crate::transport::recorder::{RecordedFile, Recorder, RecordingInfo, recorded_files};
+use crate::transport::secrets_match;
+use crate::transport::session_stats::{SessionRegistry, SessionStats};
+use crate::transport::web_assets;
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{HeaderName, HeaderValue, StatusCode, Uri, header};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::Stream;
+use image::DynamicImage;
+use image::{ImageFormat, Luma};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{net::SocketAddr, path::PathBuf};
+use tokio::task::JoinHandle;
+use tower_http::services::ServeDir;
+use tower_http::set_header::SetResponseHeaderLayer;
+use utoipa::{IntoParams, OpenApi, ToResponse, ToSchema};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+use viuer::print;
+
+const DEFAULT_LEVEL_WINDOW: Duration = Duration::from_secs(60);
+/// How long the link in `/api/qr` lets clients connect for.
+const QR_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+/// Pixels per module of the QR codes served, big enough to scan off a screen.
+const QR_MODULE_PIXELS: u32 = 8;
+
+#[derive(Clone)]
+struct AppState {
+    /// Where clients on the network find the web client, if known.
+    page_origin: Option<Arc<str>>,
+    /// The certificate clients trust by its hash, unless it's from Let's
+    /// Encrypt and browsers trust it anyway.
+    self_signed_cert: Option<Arc<PathBuf>>,
+    level_histories: Arc<Vec<SinkLevelHistory>>,
+    hls: Arc<Vec<HlsStream>>,
+    recorders: Arc<Vec<Recorder>>,
+    /// Where recordings go, if they're enabled.
+    record_dir: Option<Arc<PathBuf>>,
+    nodes: SharedNodeList,
+    /// Whether the server runs a single sink, which can capture another node.
+    capture_switchable: bool,
+    sessions: SessionRegistry,
+    control: ControlBus,
+    health: Health,
+    /// Rate of the audio the DSP filters run at.
+    sample_rate: u32,
+    /// Set with `--pairing`.
+    pairing: Option<Pairing>,
+}
+
+impl AppState {
+    fn access_key(&self) -> Arc<str> {
+        self.control.access().key
+    }
+}
+
+impl FromRef<AppState> for Arc<Vec<SinkLevelHistory>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.level_histories.clone()
+    }
+}
+
+impl FromRef<AppState> for SharedNodeList {
+    fn from_ref(state: &AppState) -> Self {
+        state.nodes.clone()
+    }
+}
+
+impl FromRef<AppState> for Health {
+    fn from_ref(state: &AppState) -> Self {
+        state.health.clone()
+    }
+}
+
+impl FromRef<AppState> for SessionRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.sessions.clone()
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LevelsQuery {
+    /// How far back to go, e.g. `60s`, `2m` or `500ms`. 60 seconds by default.
+    window: Option<String>,
+    /// Stream id of the sink, the first one by default.
+    sink: Option<String>,
+}
+
+/// Clients get session tokens with the access key or, once paired, their
+/// credential.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TokenQuery {
+    /// The server's access key.
+    key: Option<String>,
+    /// A paired client's credential, in place of the key.
+    credential: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PairQuery {
+    /// The 6-digit code the server shows. Without it the credential works once
+    /// the client is allowed through `/clients/{id}`.
+    code: Option<String>,
+    /// What the client calls itself, e.g. "kitchen-phone".
+    name: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ClientUpdate {
+    name: Option<String>,
+    access: Option<ClientAccess>,
+}
+
+/// Players get HLS files with the access key or a session token.
+#[derive(Deserialize)]
+struct HlsQuery {
+    key: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct KeyQuery {
+    /// The server's access key.
+    key: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct QrQuery {
+    /// The server's access key.
+    key: String,
+    /// Stream id of the sink the link plays, the first one by default.
+    sink: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RecordingQuery {
+    /// The server's access key.
+    key: String,
+    /// Stream id of the sink, the first one by default.
+    sink: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RecordingsResponse {
+    /// The recordings in progress.
+    recording: Vec<RecordingInfo>,
+    files: Vec<RecordedFile>,
+}
+
+/// What `/api/control` reports and its endpoints change.
+#[derive(Serialize, ToSchema)]
+struct ControlState {
+    paused: bool,
+    /// Opus bitrate in bits per second, `null` letting the encoder pick.
+    bitrate: Option<i32>,
+    /// The node captured instead of exposing a virtual sink.
+    capture_node: Option<String>,
+    /// Whether the server runs a single sink, which can capture another node.
+    capture_switchable: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BitrateRequest {
+    /// Bits per second, or `null` to let the encoder pick.
+    bitrate: Option<i32>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CaptureNodeRequest {
+    /// Name of the node to capture, or `null` for the virtual sink.
+    node: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PairResponse {
+    credential: String,
+    access: ClientAccess,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TokenResponse {
+    token: String,
+    /// Seconds since the Unix epoch.
+    expires_at: u64,
+}
+
+/// A certificate hash in the shape of WebTransport's `serverCertificateHashes`
+/// entries.
+#[derive(Serialize, ToSchema)]
+struct CertHashResponse {
+    algorithm: &'static str,
+    value: [u8; 32],
+}
+
+#[derive(Serialize, ToSchema)]
+struct ChannelLevelHistory {
+    channel: &'static str,
+    levels: Vec<Level>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LevelsResponse {
+    sink: String,
+    interval_ms: u64,
+    window_ms: u64,
+    channels: Vec<ChannelLevelHistory>,
+}
+
+/// What endpoints taking the access key answer when it's wrong.
+#[derive(ToResponse)]
+#[response(description = "The access key is wrong.")]
+struct WrongKey(#[allow(dead_code)] String);
+
+/// Parses durations like `60s`, `2m`, `500ms` or a bare number of seconds.
+fn parse_window(window: &str) -> Option<Duration> {
+    let window = window.trim();
+    if let Some(ms) = window.strip_suffix("ms") {
+        ms.parse().ok().map(Duration::from_millis)
+    } else if let Some(secs) = window.strip_suffix('s') {
+        secs.parse().ok().map(Duration::from_secs)
+    } else if let Some(mins) = window.strip_suffix('m') {
+        mins.parse::<u64>()
+            .ok()
+            .map(|mins| Duration::from_secs(mins * 60))
+    } else {
+        window.parse().ok().map(Duration::from_secs)
+    }
+}
+
+/// Peak and RMS levels of a sink's channels over a recent window.
+#[utoipa::path(
+    get,
+    path = "/levels",
+    tag = "audio",
+    params(LevelsQuery),
+    responses(
+        (status = OK, body = LevelsResponse, description = "The levels, oldest first."),
+        (status = BAD_REQUEST, body = String, description = "The window doesn't parse."),
+        (status = NOT_FOUND, body = String, description = "The sink is unknown."),
+    )
+)]
+async fn get_levels(
+    State(histories): State<Arc<Vec<SinkLevelHistory>>>,
+    Query(query): Query<LevelsQuery>,
+) -> Result<Json<LevelsResponse>, (StatusCode, String)> {
+    let sink = match &query.sink {
+        Some(id) => histories.iter().find(|sink| &sink.id == id),
+        None => histories.first(),
+    }
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!(
+                "Unknown sink {:?}",
+                query.sink.as_deref().unwrap_or_default()
+            ),
+        )
+    })?;
+    let window = match query.window {
+        Some(window) => parse_window(&window).ok_or((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid window {window:?}, expected e.g. 60s, 2m or 500ms"),
+        ))?,
+        None => DEFAULT_LEVEL_WINDOW,
+    }
+    .min(LEVEL_HISTORY);
+    let history = sink.history.lock().expect("Level history lock poisoned");
+    let levels = history.window(window);
+    Ok(Json(LevelsResponse {
+        sink: sink.id.clone(),
+        interval_ms: LEVEL_INTERVAL.as_millis() as u64,
+        window_ms: window.as_millis() as u64,
+        channels: history
+            .channel_names()
+            .iter()
+            .copied()
+            .zip(levels)
+            .map(|(channel, levels)| ChannelLevelHistory { channel, levels })
+            .collect(),
+    }))
+}
+
+/// Hands out a session token to clients that know the access key, or that
+/// paired.
+#[utoipa::path(
+    get,
+    path = "/token",
+    tag = "sessions",
+    params(TokenQuery),
+    responses(
+        (status = OK, body = TokenResponse, description = "The token."),
+        (status = FORBIDDEN, body = String, description = "The key or credential is wrong, or the client waits for approval."),
+    )
+)]
+async fn get_token(
+    State(state): State<AppState>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Json<TokenResponse>, (StatusCode, String)> {
+    let access_key = state.access_key();
+    if query
+        .key
+        .as_deref()
+        .is_none_or(|key| check_key(&state, key).is_err())
+    {
+        let access = query
+            .credential
+            .as_deref()
+            .zip(state.pairing.as_ref())
+            .and_then(|(credential, pairing)| pairing.access(credential));
+        match access {
+            Some(ClientAccess::Allowed) => {}
+            Some(ClientAccess::Pending) => {
+                return Err((StatusCode::FORBIDDEN, String::from("Waiting for approval")));
+            }
+            Some(ClientAccess::Denied) | None => {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    String::from("Wrong access key or credential"),
+                ));
+            }
+        }
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let expires_at = now + streaming_protocol::TOKEN_LIFETIME_SECS;
+    Ok(Json(TokenResponse {
+        token: streaming_protocol::session_token(&access_key, expires_at),
+        expires_at,
+    }))
+}
+
+fn pairing(state: &AppState) -> Result<&Pairing, (StatusCode, String)> {
+    state.pairing.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        String::from("Pairing is off, see --pairing"),
+    ))
+}
+
+/// Trades the pairing code the server shows for a credential `get_token`
+/// takes in place of the access key. Clients without the code get one that
+/// works once they're allowed through `/api/clients`.
+#[utoipa::path(
+    post,
+    path = "/pair",
+    tag = "sessions",
+    params(PairQuery),
+    responses(
+        (status = OK, body = PairResponse, description = "The credential, which `/token` takes in place of the key."),
+        (status = FORBIDDEN, body = String, description = "The code is wrong or was used already."),
+        (status = NOT_FOUND, body = String, description = "Pairing is off."),
+        (status = TOO_MANY_REQUESTS, body = String, description = "Too many clients are waiting for approval, or too many wrong codes were tried lately, which locks pairing for 30 s, twice as long each time after, up to an hour."),
+    )
+)]
+async fn pair(
+    State(state): State<AppState>,
+    Query(query): Query<PairQuery>,
+) -> Result<Json<PairResponse>, (StatusCode, String)> {
+    let pairing = pairing(&state)?;
+    let internal_error = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"));
+    let Some(code) = &query.code else {
+        return match pairing.request_access(query.name).map_err(internal_error)? {
+            Some(credential) => Ok(Json(PairResponse {
+                credential,
+                access: ClientAccess::Pending,
+            })),
+            None => Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                String::from("Too many clients are waiting for approval"),
+            )),
+        };
+    };
+    match pairing.pair(code, query.name).map_err(internal_error)? {
+        PairOutcome::Paired(credential) => Ok(Json(PairResponse {
+            credential,
+            access: ClientAccess::Allowed,
+        })),
+        PairOutcome::WrongCode => Err((
+            StatusCode::FORBIDDEN,
+            String::from("Wrong or used pairing code"),
+        )),
+        PairOutcome::LockedOut(left) => Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "Too many wrong pairing codes, try again in {} s",
+                left.as_secs() + 1
+            ),
+        )),
+    }
+}
+
+/// Lists the clients with credentials, for callers that know the access key.
+#[utoipa::path(
+    get,
+    path = "/clients",
+    tag = "sessions",
+    params(KeyQuery),
+    responses(
+        (status = OK, body = Vec<ClientInfo>, description = "The clients."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "Pairing is off."),
+    )
+)]
+async fn get_clients(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+) -> Result<Json<Vec<ClientInfo>>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    Ok(Json(pairing(&state)?.clients()))
+}
+
+/// Names a client, or allows or denies it. Sessions it already runs keep
+/// going, but its next session token follows the change.
+#[utoipa::path(
+    put,
+    path = "/clients/{id}",
+    tag = "sessions",
+    params(KeyQuery, ("id" = u32, Path, description = "The client's id, as `/clients` lists it.")),
+    request_body = ClientUpdate,
+    responses(
+        (status = OK, body = ClientInfo, description = "The client as changed."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "There's no such client, or pairing is off."),
+    )
+)]
+async fn update_client(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    Query(query): Query<KeyQuery>,
+    Json(update): Json<ClientUpdate>,
+) -> Result<Json<ClientInfo>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    pairing(&state)?
+        .update(id, update.name, update.access)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))?
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No client {id}")))
+}
+
+/// Forgets a client, whose credential stops working.
+#[utoipa::path(
+    delete,
+    path = "/clients/{id}",
+    tag = "sessions",
+    params(KeyQuery, ("id" = u32, Path, description = "The client's id, as `/clients` lists it.")),
+    responses(
+        (status = NO_CONTENT, description = "The client was forgotten."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "There's no such client, or pairing is off."),
+    )
+)]
+async fn forget_client(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    Query(query): Query<KeyQuery>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    let forgotten = pairing(&state)?
+        .forget(id)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))?;
+    if !forgotten {
+        return Err((StatusCode::NOT_FOUND, format!("No client {id}")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The link `/api/qr` encodes: the web client at `origin`, connecting with
+/// `token`, to `sink` if given.
+fn qr_link(origin: &str, token: &str, sink: Option<&str>) -> String {
+    let mut url = format!(
+        "{}/?autoconnect=1&{}={}",
+        origin,
+        streaming_protocol::TOKEN_QUERY_KEY,
+        token
+    );
+    if let Some(sink) = sink {
+        let sink: String = form_urlencoded::byte_serialize(sink.as_bytes()).collect();
+        url = format!("{}&sink={}", url, sink);
+    }
+    url
+}
+
+/// A PNG QR code of a link opening the web client, which connects right away.
+/// The link carries a session token valid for `QR_TOKEN_LIFETIME` instead of
+/// the access key, so whoever scans it can't keep using the server.
+#[utoipa::path(
+    get,
+    path = "/qr",
+    tag = "sessions",
+    params(QrQuery),
+    responses(
+        (status = OK, content_type = "image/png", description = "The QR code."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "The server doesn't know its address."),
+    )
+)]
+async fn get_qr(
+    State(state): State<AppState>,
+    Query(query): Query<QrQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    let origin = state.page_origin.clone().ok_or((
+        StatusCode::NOT_FOUND,
+        String::from("The server doesn't know its address"),
+    ))?;
+    let expires_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        + QR_TOKEN_LIFETIME.as_secs();
+    let token = streaming_protocol::session_token(&state.access_key(), expires_at);
+    let url = qr_link(&origin, &token, query.sink.as_deref());
+    let qr = qrcode::QrCode::new(url).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Couldn't encode the link: {e}"),
+        )
+    })?;
+    let image = qr
+        .render::<Luma<u8>>()
+        .module_dimensions(QR_MODULE_PIXELS, QR_MODULE_PIXELS)
+        .build();
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Couldn't encode the QR code: {e}"),
+            )
+        })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+/// Hashes the certificate file on every request, so clients get the current
+/// one after it's replaced. There's none to trust by hash with Let's Encrypt.
+#[utoipa::path(
+    get,
+    path = "/cert-hash",
+    tag = "sessions",
+    responses(
+        (status = OK, body = CertHashResponse, description = "The SHA-256 hash, for WebTransport's `serverCertificateHashes`."),
+        (status = NOT_FOUND, body = String, description = "The certificate is publicly trusted."),
+    )
+)]
+async fn get_cert_hash(
+    State(state): State<AppState>,
+) -> Result<Json<CertHashResponse>, (StatusCode, String)> {
+    let cert = state.self_signed_cert.ok_or((
+        StatusCode::NOT_FOUND,
+        String::from("The certificate is publicly trusted"),
+    ))?;
+    let chain = wtransport::tls::CertificateChain::load_pemfile(cert.as_path())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Couldn't load the certificate: {e}"),
+            )
+        })?;
+    let certificate = chain.as_slice().first().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        String::from("Certificate file holds no certificate"),
+    ))?;
+    Ok(Json(CertHashResponse {
+        algorithm: "sha-256",
+        value: *certificate.hash().as_ref(),
+    }))
+}
+
+/// Serves the web client built into the binary, `index.html` for directories.
+async fn get_web_asset(uri: Uri) -> Result<Response, StatusCode> {
+    let mut path = uri.path().trim_start_matches('/').to_string();
+    if path.is_empty() || path.ends_with('/') {
+        path.push_str("index.html");
+    }
+    let data = web_assets::get(&path).ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        [(header::CONTENT_TYPE, web_assets::content_type(&path))],
+        data,
+    )
+        .into_response())
+}
+
+/// Serves a sink's HLS playlist and the files it lists. Players request those
+/// without the playlist's query, so the playlist adds a fresh token to their
+/// URIs.
+async fn get_hls_file(
+    State(state): State<AppState>,
+    Path((sink, file)): Path<(String, String)>,
+    Query(query): Query<HlsQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let authorized = query
+        .key
+        .as_deref()
+        .is_some_and(|key| check_key(&state, key).is_ok())
+        || query.token.as_deref().is_some_and(|token| {
+            streaming_protocol::verify_session_token(&state.access_key(), token, now)
+        });
+    if !authorized {
+        return Err((
+            StatusCode::FORBIDDEN,
+            String::from("Wrong access key or token"),
+        ));
+    }
+    let stream = state
+        .hls
+        .iter()
+        .find(|stream| stream.sink == sink)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("No HLS stream of sink {sink:?}"),
+            )
+        })?;
+    let playlist = stream.playlist.lock().expect("HLS playlist lock poisoned");
+    if file == "playlist.m3u8" {
+        let token = streaming_protocol::session_token(
+            &state.access_key(),
+            now + streaming_protocol::TOKEN_LIFETIME_SECS,
+        );
+        let query = format!("{}={}", streaming_protocol::TOKEN_QUERY_KEY, token);
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            playlist.render(&query),
+        )
+            .into_response());
+    }
+    let data = match file.strip_suffix(".m4s") {
+        _ if file == "init.mp4" => Some(playlist.init()),
+        Some(sequence) => sequence
+            .parse()
+            .ok()
+            .and_then(|sequence| playlist.segment(sequence)),
+        None => None,
+    }
+    .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No HLS file {file:?}")))?;
+    Ok(([(header::CONTENT_TYPE, "audio/mp4")], data.to_vec()).into_response())
+}
+
+/// The recorder of the queried sink, for callers that know the access key.
+fn queried_recorder<'a>(
+    state: &'a AppState,
+    query: &RecordingQuery,
+) -> Result<&'a Recorder, (StatusCode, String)> {
+    check_key(state, &query.key)?;
+    if state.record_dir.is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            String::from("Recording is off, see --record-dir"),
+        ));
+    }
+    match &query.sink {
+        Some(id) => state.recorders.iter().find(|recorder| &recorder.sink == id),
+        None => state.recorders.first(),
+    }
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!(
+                "Unknown sink {:?}",
+                query.sink.as_deref().unwrap_or_default()
+            ),
+        )
+    })
+}
+
+/// Starts recording a sink.
+#[utoipa::path(
+    post,
+    path = "/recordings/start",
+    tag = "recordings",
+    params(RecordingQuery),
+    responses(
+        (status = OK, body = RecordingInfo, description = "The recording."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "Recording is off or the sink is unknown."),
+        (status = CONFLICT, body = String, description = "The sink is already being recorded."),
+    )
+)]
+async fn start_recording(
+    State(state): State<AppState>,
+    Query(query): Query<RecordingQuery>,
+) -> Result<Json<RecordingInfo>, (StatusCode, String)> {
+    let recorder = queried_recorder(&state, &query)?;
+    if recorder.active().is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Sink {:?} is already being recorded", recorder.sink),
+        ));
+    }
+    recorder
+        .start()
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))
+}
+
+/// Stops recording a sink.
+#[utoipa::path(
+    post,
+    path = "/recordings/stop",
+    tag = "recordings",
+    params(RecordingQuery),
+    responses(
+        (status = OK, body = RecordingInfo, description = "The recording."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "Recording is off or the sink is unknown."),
+        (status = CONFLICT, body = String, description = "The sink isn't being recorded."),
+    )
+)]
+async fn stop_recording(
+    State(state): State<AppState>,
+    Query(query): Query<RecordingQuery>,
+) -> Result<Json<RecordingInfo>, (StatusCode, String)> {
+    let recorder = queried_recorder(&state, &query)?;
+    recorder.stop().map(Json).ok_or_else(|| {
+        (
+            StatusCode::CONFLICT,
+            format!("Sink {:?} isn't being recorded", recorder.sink),
+        )
+    })
+}
+
+/// Lists the recordings in progress and the files in `--record-dir`.
+#[utoipa::path(
+    get,
+    path = "/recordings",
+    tag = "recordings",
+    params(RecordingQuery),
+    responses(
+        (status = OK, body = RecordingsResponse, description = "The recordings."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "Recording is off or the sink is unknown."),
+    )
+)]
+async fn get_recordings(
+    State(state): State<AppState>,
+    Query(query): Query<RecordingQuery>,
+) -> Result<Json<RecordingsResponse>, (StatusCode, String)> {
+    queried_recorder(&state, &query)?;
+    let dir = state.record_dir.as_ref().expect("Checked with the query");
+    let files = recorded_files(dir).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Couldn't list {}: {e}", dir.display()),
+        )
+    })?;
+    Ok(Json(RecordingsResponse {
+        recording: state
+            .recorders
+            .iter()
+            .filter_map(Recorder::active)
+            .collect(),
+        files,
+    }))
+}
+
+/// The filters applied before encoding.
+#[utoipa::path(
+    get,
+    path = "/dsp",
+    tag = "audio",
+    responses((status = OK, body = Vec<Filter>, description = "The filters, in order.")),
+)]
+async fn get_dsp(State(state): State<AppState>) -> Json<Vec<Filter>> {
+    Json(state.control.dsp())
+}
+
+/// Replaces the filter chain with the one in the body, for callers that know
+/// the access key.
+#[utoipa::path(
+    put,
+    path = "/dsp",
+    tag = "audio",
+    params(KeyQuery),
+    request_body = Vec<Filter>,
+    responses(
+        (status = OK, body = Vec<Filter>, description = "The filters, in order."),
+        (status = BAD_REQUEST, body = String, description = "A filter is out of range."),
+        (status = FORBIDDEN, response = WrongKey),
+    )
+)]
+async fn put_dsp(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+    Json(filters): Json<Vec<Filter>>,
+) -> Result<Json<Vec<Filter>>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    dsp::validate(&filters, state.sample_rate)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    state.control.set_dsp(filters.clone());
+    Ok(Json(filters))
+}
+
+fn control_state(state: &AppState) -> Json<ControlState> {
+    Json(ControlState {
+        paused: state.control.is_paused(),
+        bitrate: state.control.encoder_settings().bitrate,
+        capture_node: state.control.capture_node(),
+        capture_switchable: state.capture_switchable,
+    })
+}
+
+fn check_key(state: &AppState, key: &str) -> Result<(), (StatusCode, String)> {
+    if !secrets_match(key, &state.access_key()) {
+        return Err((StatusCode::FORBIDDEN, String::from("Wrong access key")));
+    }
+    Ok(())
+}
+
+/// Whether streaming is paused, the bitrate and the node captured.
+#[utoipa::path(
+    get,
+    path = "/control",
+    tag = "control",
+    responses((status = OK, body = ControlState, description = "The control state.")),
+)]
+async fn get_control(State(state): State<AppState>) -> Json<ControlState> {
+    control_state(&state)
+}
+
+/// Pauses streaming.
+#[utoipa::path(
+    post,
+    path = "/control/pause",
+    tag = "control",
+    params(KeyQuery),
+    responses((status = OK, body = ControlState, description = "The control state after the change."), (status = FORBIDDEN, response = WrongKey))
+)]
+async fn pause(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+) -> Result<Json<ControlState>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    state.control.set_paused(true);
+    Ok(control_state(&state))
+}
+
+/// Resumes streaming.
+#[utoipa::path(
+    post,
+    path = "/control/resume",
+    tag = "control",
+    params(KeyQuery),
+    responses((status = OK, body = ControlState, description = "The control state after the change."), (status = FORBIDDEN, response = WrongKey))
+)]
+async fn resume(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+) -> Result<Json<ControlState>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    state.control.set_paused(false);
+    Ok(control_state(&state))
+}
+
+/// Makes the encoders drop what they buffered and start afresh.
+#[utoipa::path(
+    post,
+    path = "/control/restart",
+    tag = "control",
+    params(KeyQuery),
+    responses((status = OK, body = ControlState, description = "The control state after the change."), (status = FORBIDDEN, response = WrongKey))
+)]
+async fn restart_encoders(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+) -> Result<Json<ControlState>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    state.control.restart_encoders();
+    Ok(control_state(&state))
+}
+
+/// Sets the Opus bitrate.
+#[utoipa::path(
+    put,
+    path = "/control/bitrate",
+    tag = "control",
+    params(KeyQuery),
+    request_body = BitrateRequest,
+    responses(
+        (status = OK, body = ControlState, description = "The control state after the change."),
+        (status = BAD_REQUEST, body = String, description = "The bitrate is out of range."),
+        (status = FORBIDDEN, response = WrongKey),
+    )
+)]
+async fn put_bitrate(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+    Json(request): Json<BitrateRequest>,
+) -> Result<Json<ControlState>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    state
+        .control
+        .update_encoder_settings(|settings| settings.bitrate = request.bitrate)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(control_state(&state))
+}
+
+/// Captures another of the nodes `/api/nodes` lists, or exposes the virtual
+/// sink again.
+#[utoipa::path(
+    put,
+    path = "/control/capture-node",
+    tag = "control",
+    params(KeyQuery),
+    request_body = CaptureNodeRequest,
+    responses(
+        (status = OK, body = ControlState, description = "The control state after the change."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "There's no such node."),
+        (status = CONFLICT, body = String, description = "Only a server without `--sink` can capture other nodes."),
+    )
+)]
+async fn put_capture_node(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+    Json(request): Json<CaptureNodeRequest>,
+) -> Result<Json<ControlState>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    if !state.capture_switchable {
+        return Err((
+            StatusCode::CONFLICT,
+            String::from("Only a server without --sink can capture other nodes"),
+        ));
+    }
+    if let Some(name) = &request.node {
+        let nodes = state.nodes.lock().expect("Node list lock poisoned");
+        if !nodes.iter().any(|node| &node.name == name) {
+            return Err((StatusCode::NOT_FOUND, format!("No node {name:?}")));
+        }
+    }
+    state.control.set_capture_node(request.node);
+    Ok(control_state(&state))
+}
+
+/// Lists the PipeWire sinks and sources that `--capture-node` can select.
+#[utoipa::path(
+    get,
+    path = "/nodes",
+    tag = "control",
+    responses((status = OK, body = Vec<NodeInfo>, description = "The nodes.")),
+)]
+async fn get_nodes(State(nodes): State<SharedNodeList>) -> Json<Vec<NodeInfo>> {
+    Json(nodes.lock().expect("Node list lock poisoned").clone())
+}
+
+/// Lists the connected clients and how their sessions are doing, for callers
+/// that know the access key, as it shows where clients listen from.
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "sessions",
+    params(KeyQuery),
+    responses(
+        (status = OK, body = Vec<SessionStats>, description = "The sessions."),
+        (status = FORBIDDEN, response = WrongKey),
+    )
+)]
+async fn get_sessions(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+) -> Result<Json<Vec<SessionStats>>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    Ok(Json(state.sessions.snapshot()))
+}
+
+/// The same as `/sessions`, under the name it had first.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "sessions",
+    params(KeyQuery),
+    responses(
+        (status = OK, body = Vec<SessionStats>, description = "The sessions."),
+        (status = FORBIDDEN, response = WrongKey),
+    )
+)]
+async fn get_stats(
+    state: State<AppState>,
+    query: Query<KeyQuery>,
+) -> Result<Json<Vec<SessionStats>>, (StatusCode, String)> {
+    get_sessions(state, query).await
+}
+
+/// The sessions' stats, capture glitches and encoder restarts for Prometheus
+/// to scrape.
+async fn get_metrics(
+    State(sessions): State<SessionRegistry>,
+    State(health): State<Health>,
+) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(
+            &sessions.snapshot(),
+            &health.captures(),
+            &health.report().encoders,
+        ),
+    )
+}
+
+/// Sends the list of `get_sessions` now and whenever it changes, as server-sent
+/// events, for callers that know the access key.
+#[utoipa::path(
+    get,
+    path = "/sessions/events",
+    tag = "sessions",
+    params(KeyQuery),
+    responses(
+        (status = OK, body = String, content_type = "text/event-stream", description = "Events whose data is the JSON array `/sessions` returns."),
+        (status = FORBIDDEN, response = WrongKey),
+    )
+)]
+async fn get_session_events(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    let sessions = state.sessions;
+    let mut changes = sessions.subscribe();
+    changes.mark_changed();
+    let events =
+        futures::stream::unfold((sessions, changes), |(sessions, mut changes)| async move {
+            // The registry keeps the sender, so this only fails once it's gone.
+            changes.changed().await.ok()?;
+            let event = Event::default()
+                .json_data(sessions.snapshot())
+                .expect("Session stats serialize");
+            Some((Ok(event), (sessions, changes)))
+        });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Ends a client's session, for callers that know the access key.
+#[utoipa::path(
+    post,
+    path = "/sessions/{id}/kick",
+    tag = "sessions",
+    params(KeyQuery, ("id" = usize, Path, description = "The session's id, as `/sessions` lists it.")),
+    responses(
+        (status = NO_CONTENT, description = "The session was ended."),
+        (status = FORBIDDEN, response = WrongKey),
+        (status = NOT_FOUND, body = String, description = "There's no such session."),
+    )
+)]
+async fn kick_session(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Query(query): Query<KeyQuery>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_key(&state, &query.key)?;
+    if !state.sessions.kick(id) {
+        return Err((StatusCode::NOT_FOUND, format!("No session {id}")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Answers 200 while the process works, for restarting it when it doesn't.
+async fn get_healthz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report();
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Answers 200 once clients can be sent here.
+async fn get_readyz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Describes `api_routes`, which add their paths to it.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "pipewire-streaming",
+        version = "1",
+        description = "Controls and monitors a pipewire-streaming server. Endpoints that change anything take the server's access key as `key`."
+    ),
+    servers((url = "/api/v1")),
+    components(responses(WrongKey))
+)]
+struct ApiDoc;
+
+/// The JSON API, served under `/api/v1` and, for clients from before it was
+/// versioned, `/api`, with its OpenAPI document at `/openapi.json`.
+fn api_routes() -> Router<AppState> {
+    let (router, document) = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(get_levels))
+        .routes(routes!(get_nodes))
+        .routes(routes!(get_token))
+        .routes(routes!(pair))
+        .routes(routes!(get_clients))
+        .routes(routes!(update_client, forget_client))
+        .routes(routes!(get_stats))
+        .routes(routes!(get_sessions))
+        .routes(routes!(get_session_events))
+        .routes(routes!(kick_session))
+        .routes(routes!(get_cert_hash))
+        .routes(routes!(get_qr))
+        .routes(routes!(get_control))
+        .routes(routes!(pause))
+        .routes(routes!(resume))
+        .routes(routes!(restart_encoders))
+        .routes(routes!(put_bitrate))
+        .routes(routes!(put_capture_node))
+        .routes(routes!(get_dsp, put_dsp))
+        .routes(routes!(get_recordings))
+        .routes(routes!(start_recording))
+        .routes(routes!(stop_recording))
+        .split_for_parts();
+    let document = Json(document);
+    router.route("/openapi.json", get(move || async move { document }))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_http_task(
+    config: Arc<Config>,
+    level_histories: Vec<SinkLevelHistory>,
+    hls: Vec<HlsStream>,
+    recorders: Vec<Recorder>,
+    nodes: SharedNodeList,
+    sessions: SessionRegistry,
+    control: ControlBus,
+    health: Health,
+) -> JoinHandle<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+    let pairing = config
+        .pairing
+        .then(|| Pairing::load(&config.pairing_state).expect("Couldn't load the pairing state"));
+    print_how_to_connect(&config, pairing.as_ref());
+    tokio::spawn(async move {
+        let tls_config = RustlsConfig::from_pem_file(&config.cert, &config.key)
+            .await
+            .expect("Certificate files not found!");
+        let mut certificate = control.subscribe_certificate();
+        let reloaded_config = tls_config.clone();
+        let cert_config = config.clone();
+        tokio::spawn(async move {
+            while certificate.changed().await.is_ok() {
+                if let Err(e) = reloaded_config
+                    .reload_from_pem_file(&cert_config.cert, &cert_config.key)
+                    .await
+                {
+                    warn!("Couldn't reload the HTTPS certificate: {}", e);
+                }
+            }
+        });
+        let app = Router::new()
+            .route("/healthz", get(get_healthz))
+            .route("/readyz", get(get_readyz))
+            .route("/metrics", get(get_metrics))
+            .nest("/api/v1", api_routes())
+            .nest("/api", api_routes())
+            .route("/hls/{sink}/{file}", get(get_hls_file))
+            .with_state(AppState {
+                page_origin: page_origin(&config).map(Arc::from),
+                self_signed_cert: config
+                    .acme_domains
+                    .is_empty()
+                    .then(|| Arc::new(config.cert.clone())),
+                level_histories: Arc::new(level_histories),
+                hls: Arc::new(hls),
+                recorders: Arc::new(recorders),
+                record_dir: config.record_dir.clone().map(Arc::new),
+                nodes,
+                capture_switchable: config.sinks.is_empty(),
+                sessions,
+                control: control.clone(),
+                health,
+                sample_rate: config.sample_rate,
+                pairing,
+            });
+        // The client built into the binary, unless there's none or another
+        // directory is asked for.
+        let app = match &config.web_dir {
+            None if !web_assets::is_empty() => app.fallback(get_web_asset),
+            web_dir => app.fallback_service(ServeDir::new(
+                web_dir.clone().unwrap_or_else(|| PathBuf::from("web")),
+            )),
+        };
+        let app = app
+            // The WASM client hands audio to its playback worklet in a
+            // SharedArrayBuffer, which needs a cross-origin isolated page.
+            .layer(SetResponseHeaderLayer::overriding(
+                HeaderName::from_static("cross-origin-opener-policy"),
+                HeaderValue::from_static("same-origin"),
+            ))
+            .layer(SetResponseHeaderLayer::overriding(
+                HeaderName::from_static("cross-origin-embedder-policy"),
+                HeaderValue::from_static("require-corp"),
+            ));
+        let addr = SocketAddr::from(([0, 0, 0, 0], config.http_port));
+        axum_server::bind_rustls(addr, tls_config)
+            .serve(app.into_make_service())
+            .await
+            .expect("HTTP server failed");
+    })
+}
+
+/// Where clients on the network find the web client, e.g.
+/// `https://192.168.1.20:13346`.
+fn page_origin(config: &Config) -> Option<String> {
+    // Let's Encrypt certificates are only valid for their domain.
+    let host = match config.acme_domains.first() {
+        Some(domain) => domain.clone(),
+        None => local_ip_address::local_ip().ok()?.to_string(),
+    };
+    Some(format!("https://{host}:{}", config.http_port))
+}
+
+/// Prints the page URL, with the access key or else the pairing code, and a
+/// QR code of it.
+fn print_how_to_connect(config: &Config, pairing: Option<&Pairing>) {
+    let query = match pairing {
+        Some(pairing) => {
+            println!("Pairing code: {}", pairing.code());
+            format!("pair={}", pairing.code())
+        }
+        None => format!("key={}", config.access_key),
+    };
+    let maybe_url = page_origin(config).map(|origin| format!("{origin}/?{query}"));
+    let maybe_qr = maybe_url
+        .clone()
+        .and_then(|url| qrcode::QrCode::new(url).ok())
+        .map(|qr| qr.render::<Luma<u8>>().module_dimensions(1, 1).build());
+    let conf = viuer::Config {
+        absolute_offset: false,
+        ..Default::default()
+    };
+    println!(
+        "Connect to: {}",
+        maybe_url.unwrap_or(String::from("I don't know :("))
+    );
+    if let Some(qr) = maybe_qr {
+        let img = DynamicImage::ImageLuma8(qr);
+        print(&img, &conf).expect("Image printing failed.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use clap::Parser;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    fn app_state() -> AppState {
+        let config = Config::parse_from(["pwtester"]);
+        AppState {
+            page_origin: None,
+            self_signed_cert: None,
+            level_histories: Arc::new(Vec::new()),
+            hls: Arc::new(Vec::new()),
+            recorders: Arc::new(Vec::new()),
+            record_dir: None,
+            nodes: Arc::new(Mutex::new(Vec::new())),
+            capture_switchable: true,
+            sessions: SessionRegistry::new(),
+            control: ControlBus::new(
+                config.encoder_settings(),
+                Vec::new(),
+                None,
+                crate::control::Access {
+                    key: Arc::from("key"),
+                    password: None,
+                },
+            ),
+            health: Health::new(),
+            sample_rate: config.sample_rate,
+            pairing: None,
+        }
+    }
+
+    /// The OpenAPI document `api_routes` serves.
+    async fn openapi_document() -> serde_json::Value {
+        let app = api_routes().with_state(app_state());
+        let uri = String::from("/openapi.json");
+        let (status, document) = request(&app, Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        document.unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_openapi_document_describes_every_route() {
+        let document = openapi_document().await;
+        assert_eq!(document["servers"][0]["url"], "/api/v1");
+        let pause = &document["paths"]["/control/pause"]["post"];
+        assert_eq!(pause["parameters"][0]["name"], "key");
+        assert_eq!(pause["parameters"][0]["required"], true);
+        assert_eq!(
+            pause["responses"]["403"]["$ref"],
+            "#/components/responses/WrongKey"
+        );
+        assert!(document["components"]["responses"]["WrongKey"].is_object());
+        let unrouted = || async { StatusCode::IM_A_TEAPOT };
+        let app = Router::new()
+            .nest(
+                "/api/v1",
+                api_routes().method_not_allowed_fallback(unrouted),
+            )
+            .fallback(unrouted)
+            .with_state(app_state());
+        let routed = |method: Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("/api/v1{}", path.replace("{id}", "1")))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request);
+            async { response.await.unwrap().status() != StatusCode::IM_A_TEAPOT }
+        };
+        let paths = document["paths"].as_object().unwrap();
+        for (path, operations) in paths {
+            for method in operations.as_object().unwrap().keys() {
+                let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+                assert!(routed(method.clone(), path).await, "{method} {path}");
+            }
+        }
+    }
+
+    /// The properties of the schema `name` in `document`.
+    fn schema_properties(document: &serde_json::Value, name: &str) -> Vec<String> {
+        let properties = document["components"]["schemas"][name]["properties"]
+            .as_object()
+            .unwrap_or_else(|| panic!("No schema {name}"));
+        let mut properties: Vec<String> = properties.keys().cloned().collect();
+        properties.sort();
+        properties
+    }
+
+    fn fields(value: impl Serialize) -> Vec<String> {
+        let value = serde_json::to_value(value).unwrap();
+        let mut fields: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn qr_links_encode_the_sink() {
+        assert_eq!(
+            qr_link("https://host:8443", "1.ab", Some("Living Room & Co=#1")),
+            "https://host:8443/?autoconnect=1&token=1.ab&sink=Living+Room+%26+Co%3D%231"
+        );
+        assert_eq!(
+            qr_link("https://host:8443", "1.ab", None),
+            "https://host:8443/?autoconnect=1&token=1.ab"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_openapi_schemas_match_the_responses() {
+        let document = openapi_document().await;
+        let schema_properties = |name| schema_properties(&document, name);
+        let state = app_state();
+        assert_eq!(
+            fields(control_state(&state).0),
+            schema_properties("ControlState")
+        );
+        let pairing = PairResponse {
+            credential: String::new(),
+            access: ClientAccess::Allowed,
+        };
+        assert_eq!(fields(pairing), schema_properties("PairResponse"));
+        let token = TokenResponse {
+            token: String::new(),
+            expires_at: 0,
+        };
+        assert_eq!(fields(token), schema_properties("TokenResponse"));
+        let cert_hash = CertHashResponse {
+            algorithm: "sha-256",
+            value: [0; 32],
+        };
+        assert_eq!(fields(cert_hash), schema_properties("CertHashResponse"));
+        let client = ClientInfo {
+            id: 1,
+            name: None,
+            access: ClientAccess::Pending,
+            paired_at: 0,
+        };
+        assert_eq!(fields(client), schema_properties("ClientInfo"));
+        let node = NodeInfo {
+            id: 1,
+            name: String::new(),
+            description: String::new(),
+            media_class: String::new(),
+        };
+        assert_eq!(fields(node), schema_properties("NodeInfo"));
+        let session = SessionStats {
+            id: 1,
+            address: "127.0.0.1:1".parse().unwrap(),
+            sink: String::new(),
+            codec: streaming_protocol::Codec::Opus,
+            datagrams: false,
+            tier: 0,
+            packets_sent: 0,
+            bytes_sent: 0,
+            lag_events: 0,
+            missed_packets: 0,
+            packets_retransmitted: 0,
+            rtt_ms: 0.0,
+            target_latency_ms: None,
+            receiver_report: None,
+            duration_secs: 0.0,
+        };
+        assert_eq!(fields(session), schema_properties("SessionStats"));
+        let levels = LevelsResponse {
+            sink: String::new(),
+            interval_ms: 0,
+            window_ms: 0,
+            channels: Vec::new(),
+        };
+        assert_eq!(fields(levels), schema_properties("LevelsResponse"));
+        let recordings = RecordingsResponse {
+            recording: Vec::new(),
+            files: Vec::new(),
+        };
+        assert_eq!(fields(recordings), schema_properties("RecordingsResponse"));
+    }
+
+    /// The status and JSON body `app` answers with.
+    async fn request(
+        app: &Router,
+        method: Method,
+        uri: String,
+        json: Option<serde_json::Value>,
+    ) -> (StatusCode, Option<serde_json::Value>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json.map_or_else(Body::empty, |json| Body::from(json.to_string())))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    #[tokio::test]
+    async fn session_lists_need_the_access_key() {
+        let app = api_routes().with_state(app_state());
+        for path in ["/stats", "/sessions", "/sessions/events"] {
+            let (status, _) = request(&app, Method::GET, path.to_owned(), None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            let uri = format!("{}?key=wrong", path);
+            let (status, _) = request(&app, Method::GET, uri, None).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+        }
+        for path in ["/stats", "/sessions"] {
+            let uri = format!("{}?key=key", path);
+            let (status, sessions) = request(&app, Method::GET, uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(sessions, Some(serde_json::json!([])));
+        }
+    }
+
+    fn pairing_app(name: &str) -> (Router, Pairing, PathBuf) {
+        let path = std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+        let pairing = Pairing::load(&path).unwrap();
+        let app = api_routes().with_state(AppState {
+            pairing: Some(pairing.clone()),
+            ..app_state()
+        });
+        (app, pairing, path)
+    }
+
+    #[tokio::test]
+    async fn paired_clients_get_tokens_with_their_credential() {
+        let (app, pairing, path) = pairing_app("http-pairing");
+        let code = pairing.code();
+        let (status, body) = request(
+            &app,
+            Method::POST,
+            format!("/pair?code={code}&name=phone"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let credential = body.unwrap()["credential"].as_str().unwrap().to_string();
+
+        let (status, _) = request(&app, Method::POST, format!("/pair?code={code}"), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let token = |query: &str| request(&app, Method::GET, format!("/token?{query}"), None);
+        assert_eq!(token("credential=stranger").await.0, StatusCode::FORBIDDEN);
+        let (status, body) = token(&format!("credential={credential}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.unwrap()["token"].is_string());
+        assert_eq!(token("key=key").await.0, StatusCode::OK);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clients_without_a_code_connect_once_allowed() {
+        let (app, _, path) = pairing_app("http-approval");
+        let (status, body) = request(&app, Method::POST, String::from("/pair"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["access"], "pending");
+        let token = format!("/token?credential={}", body["credential"].as_str().unwrap());
+        let (status, _) = request(&app, Method::GET, token.clone(), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let update = serde_json::json!({"name": "bedroom-pi", "access": "allowed"});
+        let (status, _) = request(
+            &app,
+            Method::PUT,
+            String::from("/clients/1"),
+            Some(update.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = request(
+            &app,
+            Method::PUT,
+            String::from("/clients/1?key=key"),
+            Some(update),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["name"], "bedroom-pi");
+        assert_eq!(
+            request(&app, Method::GET, token.clone(), None).await.0,
+            StatusCode::OK
+        );
+
+        let (status, body) =
+            request(&app, Method::GET, String::from("/clients?key=key"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()[0]["access"], "allowed");
+        let (status, _) = request(
+            &app,
+            Method::DELETE,
+            String::from("/clients/1?key=key"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            request(&app, Method::GET, token, None).await.0,
+            StatusCode::FORBIDDEN
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
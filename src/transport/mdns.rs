@@ -0,0 +1,70 @@
+use crate::config::Config;
+use crate::transport::snapcast;
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use streaming_protocol as protocol;
+
+/// Name the server advertises itself under, from `--mdns-name` or the host
+/// name.
+fn instance_name(config: &Config) -> String {
+    config.mdns_name.clone().unwrap_or_else(|| {
+        std::fs::read_to_string("/etc/hostname")
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| String::from("pipewire-streaming"))
+    })
+}
+
+/// Advertises the WebTransport and HTTPS endpoints, and the Snapcast one if
+/// enabled, on the LAN for as long as
+/// the returned daemon is alive.
+pub fn advertise(config: &Config) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    let name = instance_name(config);
+    let host = format!(
+        "{}.local.",
+        name.replace(|c: char| !c.is_ascii_alphanumeric(), "-")
+    );
+    let sinks: Vec<String> = config.sinks().into_iter().map(|sink| sink.id).collect();
+    let properties = [
+        (protocol::MDNS_VERSION_KEY, protocol::VERSION.to_string()),
+        (protocol::MDNS_HTTP_PORT_KEY, config.http_port.to_string()),
+        (protocol::MDNS_SINKS_KEY, sinks.join(",")),
+    ];
+    let webtransport = ServiceInfo::new(
+        protocol::MDNS_SERVICE_TYPE,
+        &name,
+        &host,
+        "",
+        config.webtransport_port,
+        &properties[..],
+    )?
+    .enable_addr_auto();
+    daemon.register(webtransport)?;
+    let https = ServiceInfo::new(
+        protocol::MDNS_HTTPS_SERVICE_TYPE,
+        &name,
+        &host,
+        "",
+        config.http_port,
+        &[("path", "/")][..],
+    )?
+    .enable_addr_auto();
+    daemon.register(https)?;
+    if config.snapcast {
+        // Snapclient finds servers by this type on its own.
+        let snapcast = ServiceInfo::new(
+            snapcast::MDNS_SERVICE_TYPE,
+            &name,
+            &host,
+            "",
+            config.snapcast_port,
+            None,
+        )?
+        .enable_addr_auto();
+        daemon.register(snapcast)?;
+    }
+    info!("Advertising {:?} over mDNS", name);
+    Ok(daemon)
+}
@@ -0,0 +1,249 @@
+use crate::capture::xrun::CaptureGlitches;
+use crate::health::EncoderHealth;
+use crate::transport::session_stats::SessionStats;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Prefix of every metric's name.
+const PREFIX: &str = "pipewire_streaming";
+
+/// A per-session metric: its name, type, help and value, if the session has
+/// one yet.
+type SessionMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&SessionStats) -> Option<f64>,
+);
+
+const SESSION_METRICS: [SessionMetric; 10] = [
+    (
+        "session_packets_sent_total",
+        "counter",
+        "Audio packets sent to the client.",
+        |stats| Some(stats.packets_sent as f64),
+    ),
+    (
+        "session_bytes_sent_total",
+        "counter",
+        "Bytes of audio sent to the client, headers included.",
+        |stats| Some(stats.bytes_sent as f64),
+    ),
+    (
+        "session_missed_packets_total",
+        "counter",
+        "Packets skipped because the session fell behind the encoder.",
+        |stats| Some(stats.missed_packets as f64),
+    ),
+    (
+        "session_retransmitted_packets_total",
+        "counter",
+        "Packets resent because the client reported them lost.",
+        |stats| Some(stats.packets_retransmitted as f64),
+    ),
+    (
+        "session_rtt_seconds",
+        "gauge",
+        "Round trip time to the client.",
+        |stats| Some(stats.rtt_ms / 1000.0),
+    ),
+    (
+        "session_bitrate_tier",
+        "gauge",
+        "Bitrate tier the client gets, 0 being the highest.",
+        |stats| Some(stats.tier as f64),
+    ),
+    (
+        "session_received_packets_total",
+        "counter",
+        "Packets the client reports receiving.",
+        |stats| Some(stats.receiver_report.as_ref()?.packets_received as f64),
+    ),
+    (
+        "session_lost_packets_total",
+        "counter",
+        "Packets the client reports lost or too late to play.",
+        |stats| Some(stats.receiver_report.as_ref()?.packets_lost as f64),
+    ),
+    (
+        "session_jitter_seconds",
+        "gauge",
+        "Interarrival jitter the client reports.",
+        |stats| Some(stats.receiver_report.as_ref()?.jitter_ms / 1000.0),
+    ),
+    (
+        "session_buffer_seconds",
+        "gauge",
+        "Audio the client reports queued for playback.",
+        |stats| Some(stats.receiver_report.as_ref()?.buffer_ms / 1000.0),
+    ),
+];
+
+/// A per-sink counter of capture glitches: its name, help and value.
+type CaptureMetric = (&'static str, &'static str, fn(&CaptureGlitches) -> f64);
+
+const CAPTURE_METRICS: [CaptureMetric; 4] = [
+    (
+        "capture_missing_buffers_total",
+        "Process cycles PipeWire ran without a buffer for the sink.",
+        |glitches| glitches.missing_buffers() as f64,
+    ),
+    (
+        "capture_bad_chunks_total",
+        "Buffers dropped as corrupted or not holding whole frames.",
+        |glitches| glitches.bad_chunks() as f64,
+    ),
+    (
+        "capture_gaps_total",
+        "Jumps in the graph clock where captured audio went missing.",
+        |glitches| glitches.gaps() as f64,
+    ),
+    (
+        "capture_gap_seconds_total",
+        "Audio lost in capture gaps, which clients conceal as lost packets.",
+        |glitches| glitches.gap_us() as f64 / 1_000_000.0,
+    ),
+];
+
+/// Escapes a label value for the text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The sessions' stats, the sinks' capture glitches and the encoders'
+/// restarts in Prometheus' text exposition format.
+pub fn render(
+    sessions: &[SessionStats],
+    captures: &[Arc<CaptureGlitches>],
+    encoders: &[EncoderHealth],
+) -> String {
+    let mut text = String::new();
+    // Writing to a String can't fail.
+    let _ = writeln!(text, "# HELP {PREFIX}_sessions Client sessions running.");
+    let _ = writeln!(text, "# TYPE {PREFIX}_sessions gauge");
+    let _ = writeln!(text, "{PREFIX}_sessions {}", sessions.len());
+    for (name, kind, help, value) in SESSION_METRICS {
+        let _ = writeln!(text, "# HELP {PREFIX}_{name} {help}");
+        let _ = writeln!(text, "# TYPE {PREFIX}_{name} {kind}");
+        for stats in sessions {
+            let Some(value) = value(stats) else {
+                continue;
+            };
+            let _ = writeln!(
+                text,
+                "{PREFIX}_{name}{{id=\"{}\",sink=\"{}\",codec=\"{}\"}} {}",
+                stats.id,
+                escape_label(&stats.sink),
+                stats.codec,
+                value
+            );
+        }
+    }
+    for (name, help, value) in CAPTURE_METRICS {
+        let _ = writeln!(text, "# HELP {PREFIX}_{name} {help}");
+        let _ = writeln!(text, "# TYPE {PREFIX}_{name} counter");
+        for glitches in captures {
+            let _ = writeln!(
+                text,
+                "{PREFIX}_{name}{{sink=\"{}\"}} {}",
+                escape_label(&glitches.sink),
+                value(glitches)
+            );
+        }
+    }
+    let _ = writeln!(
+        text,
+        "# HELP {PREFIX}_encoder_restarts_total Times the encoder failed and was recreated."
+    );
+    let _ = writeln!(text, "# TYPE {PREFIX}_encoder_restarts_total counter");
+    for encoder in encoders {
+        let _ = writeln!(
+            text,
+            "{PREFIX}_encoder_restarts_total{{encoder=\"{}\"}} {}",
+            escape_label(&encoder.name),
+            encoder.restarts
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::xrun::XrunDetector;
+    use std::net::SocketAddr;
+    use streaming_protocol::{Codec, ReceiverReport};
+
+    #[test]
+    fn sessions_are_labelled_and_reports_appear_once_sent() {
+        let mut stats = SessionStats {
+            id: 7,
+            address: SocketAddr::from(([192, 168, 1, 10], 50_000)),
+            sink: String::from("living \"room\""),
+            codec: Codec::Opus,
+            datagrams: true,
+            tier: 1,
+            packets_sent: 500,
+            bytes_sent: 40_000,
+            lag_events: 0,
+            missed_packets: 0,
+            packets_retransmitted: 0,
+            rtt_ms: 12.5,
+            target_latency_ms: None,
+            receiver_report: None,
+            duration_secs: 5.0,
+        };
+        let text = render(std::slice::from_ref(&stats), &[], &[]);
+        assert!(text.contains("pipewire_streaming_sessions 1\n"));
+        assert!(text.contains(
+            "pipewire_streaming_session_rtt_seconds{id=\"7\",sink=\"living \\\"room\\\"\",codec=\"opus\"} 0.0125\n"
+        ));
+        assert!(!text.contains("pipewire_streaming_session_lost_packets_total{"));
+
+        stats.receiver_report = Some(ReceiverReport {
+            packets_received: 490,
+            packets_lost: 10,
+            jitter_ms: 2.0,
+            buffer_ms: 40.0,
+        });
+        let text = render(&[stats], &[], &[]);
+        assert!(text.contains(
+            "_session_lost_packets_total{id=\"7\",sink=\"living \\\"room\\\"\",codec=\"opus\"} 10\n"
+        ));
+        assert!(text.contains("# TYPE pipewire_streaming_session_jitter_seconds gauge\n"));
+    }
+
+    #[test]
+    fn capture_glitches_are_counted_per_sink() {
+        let glitches = CaptureGlitches::new("default");
+        let mut detector = XrunDetector::new(glitches.clone());
+        detector.missing_buffer();
+        detector.cycle(0, 480, 48_000);
+        detector.cycle(30_000, 480, 48_000);
+        let text = render(&[], &[glitches], &[]);
+        assert!(
+            text.contains("pipewire_streaming_capture_missing_buffers_total{sink=\"default\"} 1\n")
+        );
+        assert!(
+            text.contains("pipewire_streaming_capture_gap_seconds_total{sink=\"default\"} 0.02\n")
+        );
+        assert!(text.contains("# TYPE pipewire_streaming_capture_bad_chunks_total counter\n"));
+    }
+
+    #[test]
+    fn encoder_restarts_are_counted_per_encoder() {
+        let encoder = EncoderHealth {
+            name: String::from("opus encoder of default"),
+            alive: true,
+            secs_since_heartbeat: 0.5,
+            restarts: 2,
+        };
+        let text = render(&[], &[], &[encoder]);
+        assert!(text.contains(
+            "pipewire_streaming_encoder_restarts_total{encoder=\"opus encoder of default\"} 2\n"
+        ));
+    }
+}
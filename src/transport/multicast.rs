@@ -0,0 +1,209 @@
+use crate::config::Config;
+use crate::encode::compress::EncodedPacket;
+use anyhow::Result;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use streaming_protocol::Codec;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// RTP version 2, without padding, extension or CSRCs.
+const RTP_VERSION: u8 = 0x80;
+/// Set on the first packet after a gap, as RFC 3551 has it for silence.
+const MARKER: u8 = 0x80;
+/// The dynamic payload type Opus is announced as in the SDP.
+const PAYLOAD_TYPE: u8 = 96;
+const RTP_HEADER_LEN: usize = 12;
+/// Opus RTP timestamps count at 48 kHz whatever the rate encoded at, see
+/// RFC 7587.
+const RTP_CLOCK_RATE: u64 = 48_000;
+
+/// Wraps a sink's Opus packets in RTP, sequence numbers and timestamps
+/// running on across the frames skipped as silence or lost in capture.
+pub struct RtpPacketizer {
+    ssrc: u32,
+    sample_rate: u32,
+    /// The stream's sequence number of the last packet sent, and its RTP
+    /// timestamp.
+    last: Option<(u32, u32)>,
+    rtp_sequence: u16,
+    /// Set once a frame went unsent, so the next one carries the marker.
+    gap: bool,
+}
+
+impl RtpPacketizer {
+    pub fn new(ssrc: u32, sample_rate: u32) -> Self {
+        Self {
+            ssrc,
+            sample_rate,
+            last: None,
+            rtp_sequence: 0,
+            gap: true,
+        }
+    }
+
+    /// The RTP packet carrying `packet`'s downmixed frame, or `None` for
+    /// silence, which goes unsent as with DTX.
+    pub fn packetize(&mut self, packet: &EncodedPacket) -> Option<Vec<u8>> {
+        let frame_ticks = packet.frame_samples as u64 * RTP_CLOCK_RATE / self.sample_rate as u64;
+        let timestamp = match self.last {
+            // Frames since the last one sent, counting the ones skipped.
+            Some((sequence, timestamp)) => {
+                let frames = packet.sequence.wrapping_sub(sequence) as u64;
+                self.gap |= frames > 1;
+                timestamp.wrapping_add((frames * frame_ticks) as u32)
+            }
+            None => rand::random(),
+        };
+        self.last = Some((packet.sequence, timestamp));
+        if packet.codec != Codec::Opus {
+            self.gap = true;
+            return None;
+        }
+        let marker = if std::mem::take(&mut self.gap) {
+            MARKER
+        } else {
+            0
+        };
+        let payload = packet.payload(0);
+        let mut rtp = Vec::with_capacity(RTP_HEADER_LEN + payload.len());
+        rtp.extend([RTP_VERSION, marker | PAYLOAD_TYPE]);
+        rtp.extend(self.rtp_sequence.to_be_bytes());
+        rtp.extend(timestamp.to_be_bytes());
+        rtp.extend(self.ssrc.to_be_bytes());
+        rtp.extend(payload);
+        self.rtp_sequence = self.rtp_sequence.wrapping_add(1);
+        Some(rtp)
+    }
+}
+
+/// The SDP description players like ffplay and VLC open the stream with.
+pub fn sdp(config: &Config, name: &str, group: SocketAddrV4, ttl: u32) -> String {
+    let stereo = (config.stream_channels() as u8 == 2) as u8;
+    format!(
+        "v=0\r\no=- {session} {session} IN IP4 0.0.0.0\r\ns={name}\r\n\
+         c=IN IP4 {address}/{ttl}\r\nt=0 0\r\nm=audio {port} RTP/AVP {PAYLOAD_TYPE}\r\n\
+         a=rtpmap:{PAYLOAD_TYPE} opus/48000/2\r\n\
+         a=fmtp:{PAYLOAD_TYPE} stereo={stereo}; sprop-stereo={stereo}\r\n\
+         a=ptime:{ptime}\r\na=recvonly\r\n",
+        session = rand::random::<u32>(),
+        address = group.ip(),
+        port = group.port(),
+        ptime = config.frame_ms,
+    )
+}
+
+fn open_socket(ttl: u32) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(ttl)?;
+    Ok(socket)
+}
+
+/// Sends the Opus packets of `receiver` as RTP to the `--multicast` group,
+/// for any number of listeners on the LAN at no cost per listener.
+pub fn spawn_multicast_task(
+    config: Arc<Config>,
+    name: String,
+    mut receiver: broadcast::Receiver<EncodedPacket>,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let group = config
+            .multicast
+            .expect("The multicast task only runs with --multicast");
+        let socket = open_socket(config.multicast_ttl).expect("Couldn't open the multicast socket");
+        println!(
+            "Multicasting {:?} as RTP to {}, with this SDP:\n{}",
+            name,
+            group,
+            sdp(&config, &name, group, config.multicast_ttl)
+        );
+        let mut packetizer = RtpPacketizer::new(rand::random(), config.sample_rate);
+        let mut sending = true;
+        loop {
+            let packet = match receiver.blocking_recv() {
+                Ok(packet) => packet,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Multicast sender lagged, {} packets missed", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let Some(rtp) = packetizer.packetize(&packet) else {
+                continue;
+            };
+            // Warned about once per outage, e.g. while the network is down.
+            match socket.send_to(&rtp, group) {
+                Ok(_) => sending = true,
+                Err(e) if sending => {
+                    warn!("Couldn't multicast to {}: {}", group, e);
+                    sending = false;
+                }
+                Err(_) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(rtp: &[u8]) -> (bool, u16, u32) {
+        (
+            rtp[1] & MARKER != 0,
+            u16::from_be_bytes([rtp[2], rtp[3]]),
+            u32::from_be_bytes([rtp[4], rtp[5], rtp[6], rtp[7]]),
+        )
+    }
+
+    #[test]
+    fn timestamps_run_on_across_skipped_frames() {
+        // At 24 kHz, so each 480 sample frame is 960 ticks of 48 kHz.
+        let mut packetizer = RtpPacketizer::new(0x1234_5678, 24_000);
+        let first = packetizer
+            .packetize(&EncodedPacket::for_test(Codec::Opus, 10, 0))
+            .unwrap();
+        let (marker, sequence, start) = header(&first);
+        assert!(marker);
+        assert_eq!(first[1] & !MARKER, PAYLOAD_TYPE);
+        assert_eq!(&first[8..12], &0x1234_5678u32.to_be_bytes());
+        assert_eq!(&first[RTP_HEADER_LEN..], &[0xfc, 1, 2]);
+
+        let second = packetizer
+            .packetize(&EncodedPacket::for_test(Codec::Opus, 11, 0))
+            .unwrap();
+        assert_eq!(
+            header(&second),
+            (false, sequence.wrapping_add(1), start.wrapping_add(960))
+        );
+        assert!(
+            packetizer
+                .packetize(&EncodedPacket::for_test(Codec::Silence, 12, 0))
+                .is_none()
+        );
+        // 13 was lost in capture.
+        let after_gap = packetizer
+            .packetize(&EncodedPacket::for_test(Codec::Opus, 14, 0))
+            .unwrap();
+        assert_eq!(
+            header(&after_gap),
+            (true, sequence.wrapping_add(2), start.wrapping_add(4 * 960))
+        );
+    }
+
+    #[test]
+    fn sdp_announces_the_group_and_opus() {
+        let args = ["pwtester", "--no-mdns", "--multicast", "239.255.77.1:5004"];
+        let (config, _) = Config::load(&args.map(Into::into)).unwrap();
+        let sdp = sdp(
+            &config,
+            "Living Room",
+            "239.255.77.1:5004".parse().unwrap(),
+            1,
+        );
+        assert!(sdp.contains("c=IN IP4 239.255.77.1/1\r\n"));
+        assert!(sdp.contains("m=audio 5004 RTP/AVP 96\r\n"));
+        assert!(sdp.contains("a=rtpmap:96 opus/48000/2\r\n"));
+        assert!(sdp.contains("s=Living Room\r\n"));
+    }
+}
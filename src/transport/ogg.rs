@@ -0,0 +1,168 @@
+/// Marks the first page of a stream.
+const BEGINNING_OF_STREAM: u8 = 0x02;
+/// Marks the last page of a stream.
+const END_OF_STREAM: u8 = 0x04;
+/// Lacing values a page holds at most.
+const MAX_SEGMENTS: usize = 255;
+/// Samples Opus decoders drop from the start of the stream, the encoder's
+/// lookahead at 48 kHz.
+const OPUS_PRE_SKIP: u16 = 312;
+
+/// The CRC-32 Ogg pages are checked with: polynomial 0x04C11DB7, neither the
+/// input nor the result reflected.
+fn crc32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Packs packets into the pages of one logical Ogg stream. Packets must fit
+/// on a single page, which holds up to 64 KiB.
+pub struct OggWriter {
+    serial: u32,
+    sequence: u32,
+    /// Lacing values and data of the packets waiting for their page.
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+    /// Position of the last packet added, in the codec's granules.
+    granule: u64,
+}
+
+impl OggWriter {
+    pub fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            sequence: 0,
+            lacing: Vec::new(),
+            data: Vec::new(),
+            granule: 0,
+        }
+    }
+
+    /// Adds a packet ending at `granule`, returning the page it didn't fit on
+    /// any more.
+    pub fn push(&mut self, packet: &[u8], granule: u64) -> Option<Vec<u8>> {
+        let segments = packet.len() / 255 + 1;
+        assert!(segments <= MAX_SEGMENTS, "Ogg packet too long");
+        let full = (self.lacing.len() + segments > MAX_SEGMENTS)
+            .then(|| self.flush())
+            .flatten();
+        self.lacing
+            .extend(std::iter::repeat_n(255, packet.len() / 255));
+        self.lacing.push((packet.len() % 255) as u8);
+        self.data.extend(packet);
+        self.granule = granule;
+        full
+    }
+
+    /// Whether packets are waiting for their page.
+    pub fn is_empty(&self) -> bool {
+        self.lacing.is_empty()
+    }
+
+    /// The page holding the packets added since the last one, if any.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        (!self.is_empty()).then(|| self.page(0))
+    }
+
+    /// The last page, holding any packets still waiting.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.page(END_OF_STREAM)
+    }
+
+    fn page(&mut self, flags: u8) -> Vec<u8> {
+        let flags = if self.sequence == 0 {
+            flags | BEGINNING_OF_STREAM
+        } else {
+            flags
+        };
+        let mut page = Vec::with_capacity(27 + self.lacing.len() + self.data.len());
+        page.extend(b"OggS\0");
+        page.push(flags);
+        page.extend(self.granule.to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        // The checksum, filled in below.
+        page.extend([0; 4]);
+        page.push(self.lacing.len() as u8);
+        page.append(&mut self.lacing);
+        page.append(&mut self.data);
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.sequence += 1;
+        page
+    }
+}
+
+/// The pages starting an Ogg Opus stream: the identification header and the
+/// comment header, each on a page of its own as the format requires.
+pub fn opus_header_pages(writer: &mut OggWriter, channels: u8, sample_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend(b"OpusHead\x01");
+    head.push(channels);
+    head.extend(OPUS_PRE_SKIP.to_le_bytes());
+    head.extend(sample_rate.to_le_bytes());
+    // No output gain, and channel mapping family 0 for mono and stereo.
+    head.extend([0, 0, 0]);
+    let vendor = concat!("pipewire-streaming ", env!("CARGO_PKG_VERSION"));
+    let mut tags = Vec::new();
+    tags.extend(b"OpusTags");
+    tags.extend((vendor.len() as u32).to_le_bytes());
+    tags.extend(vendor.as_bytes());
+    tags.extend(0u32.to_le_bytes());
+
+    let mut pages = Vec::new();
+    for header in [head, tags] {
+        writer.push(&header, 0);
+        pages.extend(writer.flush().expect("The header was just added"));
+    }
+    pages
+}
+
+/// Opus granule positions count samples at 48 kHz, whatever the input rate.
+pub fn opus_granules(samples: u64, sample_rate: u32) -> u64 {
+    samples * 48_000 / sample_rate as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia_core::codecs::CODEC_TYPE_OPUS;
+    use symphonia_core::formats::{FormatOptions, FormatReader};
+    use symphonia_core::io::MediaSourceStream;
+    use symphonia_format_ogg::OggReader;
+
+    #[test]
+    fn opus_streams_demux_to_the_packets_written() {
+        let mut writer = OggWriter::new(0x1234);
+        let mut file = opus_header_pages(&mut writer, 2, 48_000);
+        let packets: Vec<Vec<u8>> = (0..300u32).map(|n| vec![n as u8; n as usize]).collect();
+        for (index, packet) in packets.iter().enumerate() {
+            if let Some(page) = writer.push(packet, opus_granules(480 * (index as u64 + 1), 48_000))
+            {
+                file.extend(page);
+            }
+            if index % 10 == 9 {
+                file.extend(writer.flush().unwrap());
+            }
+        }
+        file.extend(writer.finish());
+
+        let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+        let mut reader = OggReader::try_new(source, &FormatOptions::default()).unwrap();
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.codec, CODEC_TYPE_OPUS);
+        assert_eq!(params.channels.unwrap().count(), 2);
+        for packet in &packets {
+            assert_eq!(&reader.next_packet().unwrap().data[..], &packet[..]);
+        }
+        assert!(reader.next_packet().is_err());
+    }
+}
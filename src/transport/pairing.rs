@@ -0,0 +1,452 @@
+use super::secrets_match;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Wrong codes tried before pairing is locked, leaving whoever guesses this
+/// many chances in a million per lockout.
+const MAX_WRONG_CODES: u32 = 5;
+/// How long the first lockout lasts, each one after it twice as long up to
+/// `MAX_LOCKOUT`. Lockouts lock every client out, so guessing from many
+/// addresses is no faster, and keep the code, so guesses can't make the one
+/// the server printed stop working.
+const MIN_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+/// Clients waiting for approval at most, so strangers asking over and over
+/// can't grow the state file without end.
+const MAX_PENDING_CLIENTS: usize = 16;
+
+/// Whether a known client's credential gets it session tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAccess {
+    /// Paired with a code, or approved through the API.
+    #[default]
+    Allowed,
+    /// Asked to connect without a code, and not approved yet.
+    Pending,
+    Denied,
+}
+
+/// A client the server handed a credential to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct KnownClient {
+    /// Identifies the client in the API, unlike the credential, which stays
+    /// between it and the server. Entries of files from before clients had
+    /// ids get theirs as they're read.
+    #[serde(default)]
+    id: u32,
+    credential: String,
+    /// What the client called itself, or was named through the API.
+    name: Option<String>,
+    #[serde(default)]
+    access: ClientAccess,
+    /// Seconds since the Unix epoch.
+    paired_at: u64,
+}
+
+/// What `/api/clients` lists about a known client.
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ClientInfo {
+    pub id: u32,
+    pub name: Option<String>,
+    pub access: ClientAccess,
+    /// Seconds since the Unix epoch.
+    pub paired_at: u64,
+}
+
+impl From<&KnownClient> for ClientInfo {
+    fn from(client: &KnownClient) -> Self {
+        Self {
+            id: client.id,
+            name: client.name.clone(),
+            access: client.access,
+            paired_at: client.paired_at,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PairingFile {
+    clients: Vec<KnownClient>,
+}
+
+/// What presenting a pairing code came to.
+#[derive(Debug, PartialEq)]
+pub enum PairOutcome {
+    /// The client's new credential.
+    Paired(String),
+    /// The code was wrong or already used.
+    WrongCode,
+    /// Too many wrong codes were tried, so none is taken for this long.
+    LockedOut(Duration),
+}
+
+struct PairingState {
+    code: String,
+    wrong_codes: u32,
+    /// Lockouts since a client last paired, which lengthen the next one.
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    clients: Vec<KnownClient>,
+}
+
+impl PairingState {
+    /// Draws a code other than the current one, which stops working.
+    fn replace_code(&mut self) {
+        let old = std::mem::take(&mut self.code);
+        while self.code.is_empty() || self.code == old {
+            self.code = random_code();
+        }
+    }
+
+    /// Time left of the current lockout, if any.
+    fn lockout_left(&self) -> Option<Duration> {
+        self.locked_until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Counts a wrong code, locking pairing after too many.
+    fn wrong_code(&mut self) {
+        self.wrong_codes += 1;
+        if self.wrong_codes < MAX_WRONG_CODES {
+            return;
+        }
+        let lockout = MIN_LOCKOUT
+            .saturating_mul(1 << self.lockouts.min(16))
+            .min(MAX_LOCKOUT);
+        self.wrong_codes = 0;
+        self.lockouts += 1;
+        self.locked_until = Some(Instant::now() + lockout);
+        warn!(
+            "Too many wrong pairing codes, pairing is locked for {:?}",
+            lockout
+        );
+    }
+
+    fn next_id(&self) -> u32 {
+        self.clients
+            .iter()
+            .map(|client| client.id)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+}
+
+/// Pairs clients that present the one-time code the server shows with a
+/// long-lived credential, and lets others ask to be approved through the API.
+/// The clients are kept in the `--pairing-state` file so they connect again
+/// by themselves after restarts.
+#[derive(Clone)]
+pub struct Pairing {
+    path: Arc<PathBuf>,
+    state: Arc<Mutex<PairingState>>,
+}
+
+fn random_code() -> String {
+    format!("{:06}", rand::random_range(0..1_000_000))
+}
+
+fn random_credential() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Pairing {
+    /// Reads the clients known before from `path`, which needn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("Couldn't parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => PairingFile::default(),
+            Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+        };
+        let mut state = PairingState {
+            code: random_code(),
+            wrong_codes: 0,
+            lockouts: 0,
+            locked_until: None,
+            clients: Vec::new(),
+        };
+        for mut client in file.clients {
+            if client.id == 0 || state.clients.iter().any(|known| known.id == client.id) {
+                client.id = state.next_id();
+            }
+            state.clients.push(client);
+        }
+        Ok(Self {
+            path: Arc::new(path.to_path_buf()),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// The code the next client to pair has to present.
+    pub fn code(&self) -> String {
+        self.state
+            .lock()
+            .expect("Pairing state lock poisoned")
+            .code
+            .clone()
+    }
+
+    /// Trades the current code for a new credential, which is saved before
+    /// it's handed out. Each code pairs one client and is replaced after it
+    /// did. After too many wrong codes, no code is taken for a while, the
+    /// right one included.
+    pub fn pair(&self, code: &str, name: Option<String>) -> Result<PairOutcome> {
+        let mut state = self.state.lock().expect("Pairing state lock poisoned");
+        if let Some(left) = state.lockout_left() {
+            return Ok(PairOutcome::LockedOut(left));
+        }
+        if !secrets_match(code, &state.code) {
+            state.wrong_code();
+            return Ok(PairOutcome::WrongCode);
+        }
+        let credential = self.add(&mut state, name, ClientAccess::Allowed)?;
+        state.replace_code();
+        state.wrong_codes = 0;
+        state.lockouts = 0;
+        info!("Client paired, the next pairing code is {}", state.code);
+        Ok(PairOutcome::Paired(credential))
+    }
+
+    /// Hands a client without a code a credential that works once it's
+    /// allowed through the API, or `None` if too many are waiting already.
+    pub fn request_access(&self, name: Option<String>) -> Result<Option<String>> {
+        let mut state = self.state.lock().expect("Pairing state lock poisoned");
+        let pending = state
+            .clients
+            .iter()
+            .filter(|client| client.access == ClientAccess::Pending)
+            .count();
+        if pending >= MAX_PENDING_CLIENTS {
+            return Ok(None);
+        }
+        let credential = self.add(&mut state, name, ClientAccess::Pending)?;
+        let client = state.clients.last().expect("Just added");
+        info!(
+            "Client {} ({}) asks to connect, allow it with PUT /api/v1/clients/{}",
+            client.id,
+            client.name.as_deref().unwrap_or("unnamed"),
+            client.id
+        );
+        Ok(Some(credential))
+    }
+
+    fn add(
+        &self,
+        state: &mut PairingState,
+        name: Option<String>,
+        access: ClientAccess,
+    ) -> Result<String> {
+        let credential = random_credential();
+        let mut clients = state.clients.clone();
+        clients.push(KnownClient {
+            id: state.next_id(),
+            credential: credential.clone(),
+            name,
+            access,
+            paired_at: now_secs(),
+        });
+        self.save(state, clients)?;
+        Ok(credential)
+    }
+
+    /// The access of the client `credential` was handed to, if any.
+    pub fn access(&self, credential: &str) -> Option<ClientAccess> {
+        self.state
+            .lock()
+            .expect("Pairing state lock poisoned")
+            .clients
+            .iter()
+            .find(|client| secrets_match(&client.credential, credential))
+            .map(|client| client.access)
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.state
+            .lock()
+            .expect("Pairing state lock poisoned")
+            .clients
+            .iter()
+            .map(ClientInfo::from)
+            .collect()
+    }
+
+    /// Renames client `id` and changes its access, each if given. `None` if
+    /// there's no such client.
+    pub fn update(
+        &self,
+        id: u32,
+        name: Option<String>,
+        access: Option<ClientAccess>,
+    ) -> Result<Option<ClientInfo>> {
+        let mut state = self.state.lock().expect("Pairing state lock poisoned");
+        let mut clients = state.clients.clone();
+        let Some(client) = clients.iter_mut().find(|client| client.id == id) else {
+            return Ok(None);
+        };
+        if let Some(name) = name {
+            client.name = Some(name);
+        }
+        if let Some(access) = access {
+            client.access = access;
+        }
+        let info = ClientInfo::from(&*client);
+        self.save(&mut state, clients)?;
+        Ok(Some(info))
+    }
+
+    /// Forgets client `id`, whose credential stops working. `false` if
+    /// there's no such client.
+    pub fn forget(&self, id: u32) -> Result<bool> {
+        let mut state = self.state.lock().expect("Pairing state lock poisoned");
+        let mut clients = state.clients.clone();
+        let count = clients.len();
+        clients.retain(|client| client.id != id);
+        if clients.len() == count {
+            return Ok(false);
+        }
+        self.save(&mut state, clients)?;
+        Ok(true)
+    }
+
+    /// Writes `clients` to the state file, and takes them on once they're
+    /// written.
+    fn save(&self, state: &mut PairingState, clients: Vec<KnownClient>) -> Result<()> {
+        let file = PairingFile { clients };
+        let text = toml::to_string(&file).expect("Pairing state serializes");
+        // Written aside and renamed, so a crash can't lose the known clients.
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, text)
+            .and_then(|()| fs::rename(&partial, self.path.as_path()))
+            .with_context(|| format!("Couldn't write {}", self.path.display()))?;
+        state.clients = file.clients;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()))
+    }
+
+    #[test]
+    fn codes_pair_one_client_each_and_credentials_persist() {
+        let path = state_path("pairing");
+        let pairing = Pairing::load(&path).unwrap();
+        let code = pairing.code();
+        assert_eq!(code.len(), 6);
+
+        let PairOutcome::Paired(credential) =
+            pairing.pair(&code, Some(String::from("phone"))).unwrap()
+        else {
+            panic!("The code didn't pair");
+        };
+        assert_eq!(pairing.access(&credential), Some(ClientAccess::Allowed));
+        assert_eq!(pairing.access("stranger"), None);
+        assert_ne!(pairing.code(), code);
+        assert_eq!(pairing.pair(&code, None).unwrap(), PairOutcome::WrongCode);
+
+        let reloaded = Pairing::load(&path).unwrap();
+        assert_eq!(reloaded.access(&credential), Some(ClientAccess::Allowed));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wrong_guesses_lock_pairing_for_longer_each_time() {
+        let path = state_path("pairing-lockout");
+        let pairing = Pairing::load(&path).unwrap();
+        let code = pairing.code();
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        let guess = |code: &str| pairing.pair(code, None).unwrap();
+        let expire_lockout = || pairing.state.lock().unwrap().locked_until = None;
+        for _ in 0..MAX_WRONG_CODES {
+            assert_eq!(guess(&wrong), PairOutcome::WrongCode);
+        }
+        // The right code waits too, and stays the same.
+        let PairOutcome::LockedOut(left) = guess(&code) else {
+            panic!("Pairing wasn't locked");
+        };
+        assert!(left <= MIN_LOCKOUT);
+        assert_eq!(pairing.code(), code);
+
+        expire_lockout();
+        for _ in 0..MAX_WRONG_CODES {
+            assert_eq!(guess(&wrong), PairOutcome::WrongCode);
+        }
+        let PairOutcome::LockedOut(left) = guess(&wrong) else {
+            panic!("Pairing wasn't locked again");
+        };
+        assert!(left > MIN_LOCKOUT && left <= 2 * MIN_LOCKOUT);
+
+        expire_lockout();
+        assert!(matches!(guess(&code), PairOutcome::Paired(_)));
+        assert_eq!(pairing.state.lock().unwrap().lockouts, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn clients_asking_to_connect_wait_for_approval() {
+        let path = state_path("pairing-approval");
+        let pairing = Pairing::load(&path).unwrap();
+        let PairOutcome::Paired(paired) = pairing.pair(&pairing.code(), None).unwrap() else {
+            panic!("The code didn't pair");
+        };
+        let stranger = pairing.request_access(None).unwrap().unwrap();
+        assert_eq!(pairing.access(&stranger), Some(ClientAccess::Pending));
+
+        let info = pairing
+            .update(
+                2,
+                Some(String::from("bedroom-pi")),
+                Some(ClientAccess::Allowed),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.name.as_deref(), Some("bedroom-pi"));
+        assert_eq!(pairing.access(&stranger), Some(ClientAccess::Allowed));
+        pairing.update(1, None, Some(ClientAccess::Denied)).unwrap();
+        assert!(pairing.update(3, None, None).unwrap().is_none());
+
+        let reloaded = Pairing::load(&path).unwrap();
+        assert_eq!(reloaded.clients(), pairing.clients());
+        assert_eq!(reloaded.access(&paired), Some(ClientAccess::Denied));
+        assert!(reloaded.forget(2).unwrap());
+        assert!(!reloaded.forget(2).unwrap());
+        assert_eq!(reloaded.access(&stranger), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn entries_without_ids_get_them() {
+        let path = state_path("pairing-ids");
+        fs::write(
+            &path,
+            "[[clients]]\ncredential = \"a\"\npaired_at = 1\n\n\
+             [[clients]]\ncredential = \"b\"\npaired_at = 2\n",
+        )
+        .unwrap();
+        let pairing = Pairing::load(&path).unwrap();
+        let clients = pairing.clients();
+        assert_eq!(clients[0].id, 1);
+        assert_eq!(clients[1].id, 2);
+        assert_eq!(pairing.access("b"), Some(ClientAccess::Allowed));
+        fs::remove_file(&path).unwrap();
+    }
+}
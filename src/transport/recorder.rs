@@ -0,0 +1,383 @@
+use crate::config::Config;
+use crate::encode::compress::EncodedPacket;
+use crate::transport::ogg::{self, OggWriter};
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use streaming_protocol::Codec;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+/// A recording as listed by the HTTP API.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct RecordingInfo {
+    pub sink: String,
+    /// Name of the file being written, in `--record-dir`.
+    pub file: String,
+    /// Seconds since the Unix epoch the recording started at.
+    pub started_at: u64,
+    /// Files written so far, counting the current one.
+    pub files: u32,
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// `secs` since the Unix epoch as a UTC date and time usable in file names,
+/// e.g. `2026-10-17T09-30-00Z`.
+fn file_timestamp(secs: u64) -> String {
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Converts days to a civil date, counting in eras of 400 years from
+    // 0000-03-01, as described at
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}-{:02}-{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// One file of a recording, Ogg Opus or plain FLAC.
+struct RecordingFile {
+    name: String,
+    writer: BufWriter<File>,
+    /// Pages packets into the file for Opus, FLAC frames are written as they
+    /// are.
+    ogg: Option<OggWriter>,
+    samples: u64,
+    bytes: u64,
+}
+
+impl RecordingFile {
+    /// Creates part `part` of the recording whose file names start with
+    /// `stem`, counting from 1.
+    fn create(config: &Config, stem: &str, part: u32) -> Result<Self> {
+        let extension = match config.record_codec {
+            Codec::Opus => "opus",
+            _ => "flac",
+        };
+        // Files of recordings that rotate are numbered, padded so they list in
+        // order.
+        let rotates = config.record_max_minutes.is_some() || config.record_max_mb.is_some();
+        let name = if rotates {
+            format!("{}-{:03}.{}", stem, part, extension)
+        } else {
+            format!("{}.{}", stem, extension)
+        };
+        let path = config
+            .record_dir
+            .as_ref()
+            .expect("Recording needs --record-dir")
+            .join(&name);
+        let file = File::create_new(&path)
+            .with_context(|| format!("Couldn't create {}", path.display()))?;
+        let mut recording = Self {
+            name,
+            writer: BufWriter::new(file),
+            ogg: None,
+            samples: 0,
+            bytes: 0,
+        };
+        let channels = config.stream_channels() as u8;
+        let header = match config.record_codec {
+            Codec::Opus => {
+                let mut ogg = OggWriter::new(rand::random());
+                let header = ogg::opus_header_pages(&mut ogg, channels, config.sample_rate);
+                recording.ogg = Some(ogg);
+                header
+            }
+            _ => streaming_protocol::flac_stream_header(config.sample_rate, channels)
+                .expect("FLAC holds the stream's rate and channels"),
+        };
+        recording.write(&header)?;
+        Ok(recording)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn push(&mut self, config: &Config, packet: &EncodedPacket) -> Result<()> {
+        self.samples += packet.frame_samples as u64;
+        match &mut self.ogg {
+            Some(ogg) => {
+                let granule = ogg::opus_granules(self.samples, config.sample_rate);
+                if let Some(page) = ogg.push(packet.payload(0), granule) {
+                    self.write(&page)?;
+                }
+                Ok(())
+            }
+            None => self.write(packet.payload(0)),
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(ogg) = self.ogg.take() {
+            self.write(&ogg.finish())?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A recording in progress, split over several files once they reach
+/// `--record-max-minutes` or `--record-max-mb`.
+struct Recording {
+    started_at: u64,
+    /// Start of the file names, from the sink and the start time.
+    stem: String,
+    file: RecordingFile,
+    /// Files finished before the current one, and their size and samples.
+    finished_files: u32,
+    finished_bytes: u64,
+    finished_samples: u64,
+}
+
+/// Records one sink's stream on demand.
+#[derive(Clone)]
+pub struct Recorder {
+    pub sink: String,
+    config: Arc<Config>,
+    recording: Arc<Mutex<Option<Recording>>>,
+}
+
+impl Recorder {
+    pub fn new(config: Arc<Config>, sink: String) -> Self {
+        Self {
+            sink,
+            config,
+            recording: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn info(&self, recording: &Recording) -> RecordingInfo {
+        let samples = recording.finished_samples + recording.file.samples;
+        RecordingInfo {
+            sink: self.sink.clone(),
+            file: recording.file.name.clone(),
+            started_at: recording.started_at,
+            files: recording.finished_files + 1,
+            bytes: recording.finished_bytes + recording.file.bytes,
+            duration_ms: samples * 1000 / self.config.sample_rate as u64,
+        }
+    }
+
+    /// The recording in progress, if any.
+    pub fn active(&self) -> Option<RecordingInfo> {
+        let recording = self.recording.lock().expect("Recording lock poisoned");
+        recording.as_ref().map(|recording| self.info(recording))
+    }
+
+    pub fn start(&self) -> Result<RecordingInfo> {
+        let mut recording = self.recording.lock().expect("Recording lock poisoned");
+        if recording.is_some() {
+            bail!("Sink {:?} is already being recorded", self.sink);
+        }
+        let dir = self
+            .config
+            .record_dir
+            .as_ref()
+            .expect("Recording needs --record-dir");
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Couldn't create {}", dir.display()))?;
+        let started_at = now_secs();
+        let stem = format!("{}-{}", self.sink, file_timestamp(started_at));
+        let started = Recording {
+            started_at,
+            file: RecordingFile::create(&self.config, &stem, 1)?,
+            stem,
+            finished_files: 0,
+            finished_bytes: 0,
+            finished_samples: 0,
+        };
+        let info = self.info(&started);
+        info!("Recording {} to {}", self.sink, info.file);
+        *recording = Some(started);
+        Ok(info)
+    }
+
+    /// Finishes the recording in progress, returning what it amounted to.
+    pub fn stop(&self) -> Option<RecordingInfo> {
+        let recording = self
+            .recording
+            .lock()
+            .expect("Recording lock poisoned")
+            .take()?;
+        let info = self.info(&recording);
+        match recording.file.finish() {
+            Ok(()) => info!("Stopped recording {}", self.sink),
+            Err(e) => warn!("Couldn't finish recording {}: {:#}", self.sink, e),
+        }
+        Some(info)
+    }
+
+    /// Whether the current file reached a limit and the next packet starts
+    /// a new one.
+    fn is_full(&self, file: &RecordingFile) -> bool {
+        let minutes = file.samples / self.config.sample_rate as u64 / 60;
+        let megabytes = file.bytes / 1_000_000;
+        self.config
+            .record_max_minutes
+            .is_some_and(|max| minutes >= max as u64)
+            || self
+                .config
+                .record_max_mb
+                .is_some_and(|max| megabytes >= max as u64)
+    }
+
+    fn write(&self, packet: &EncodedPacket) -> Result<()> {
+        let mut recording = self.recording.lock().expect("Recording lock poisoned");
+        let Some(recording) = recording.as_mut() else {
+            return Ok(());
+        };
+        if self.is_full(&recording.file) {
+            let next =
+                RecordingFile::create(&self.config, &recording.stem, recording.finished_files + 2)?;
+            let full = std::mem::replace(&mut recording.file, next);
+            recording.finished_files += 1;
+            recording.finished_bytes += full.bytes;
+            recording.finished_samples += full.samples;
+            full.finish()?;
+            info!("Recording {} to {}", self.sink, recording.file.name);
+        }
+        recording.file.push(&self.config, packet)
+    }
+}
+
+/// Writes the packets of `receiver` to the recorder's files while it's
+/// recording.
+pub fn spawn_recorder_task(
+    recorder: Recorder,
+    mut receiver: broadcast::Receiver<EncodedPacket>,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        loop {
+            match receiver.blocking_recv() {
+                // Silence only comes with `--dtx` toggled on while streaming,
+                // and has no place in the files.
+                Ok(packet) if packet.codec == Codec::Silence => {}
+                Ok(packet) => {
+                    if let Err(e) = recorder.write(&packet) {
+                        warn!("Recording {} failed: {:#}", recorder.sink, e);
+                        recorder.stop();
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(
+                        "Recorder of {} lagged, {} packets missing from the recording",
+                        recorder.sink, missed
+                    );
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    recorder.stop();
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// A finished or growing file in `--record-dir`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecordedFile {
+    pub name: String,
+    pub bytes: u64,
+}
+
+/// The files in `dir`, by name.
+pub fn recorded_files(dir: &Path) -> Result<Vec<RecordedFile>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push(RecordedFile {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                bytes: metadata.len(),
+            });
+        }
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use clap::Parser;
+
+    #[test]
+    fn file_names_carry_the_utc_date_and_time() {
+        assert_eq!(file_timestamp(0), "1970-01-01T00-00-00Z");
+        assert_eq!(file_timestamp(951_782_400), "2000-02-29T00-00-00Z");
+        assert_eq!(file_timestamp(1_792_229_405), "2026-10-17T09-30-05Z");
+    }
+
+    #[test]
+    fn recordings_rotate_to_a_new_file_at_the_size_limit() {
+        let dir = std::env::temp_dir().join(format!("recorder-test-{}", rand::random::<u32>()));
+        let config = Config::parse_from([
+            "pwtester",
+            "--codec=flac",
+            "--record-codec=flac",
+            "--record-max-mb=1",
+            "--record-dir",
+            dir.to_str().unwrap(),
+        ]);
+        let recorder = Recorder::new(Arc::new(config), String::from("desk"));
+        let mut packet = EncodedPacket::for_test(Codec::Flac, 0, 0);
+        packet.payloads[0] = Some(Bytes::from(vec![0; 400_000]));
+        recorder.write(&packet).unwrap();
+        assert!(recorder.active().is_none());
+
+        recorder.start().unwrap();
+        assert!(recorder.start().is_err());
+        // The third packet fills the first file, so the fourth starts the next.
+        for _ in 0..4 {
+            recorder.write(&packet).unwrap();
+        }
+        let info = recorder.stop().unwrap();
+        assert!(recorder.stop().is_none());
+        assert_eq!((info.files, info.duration_ms), (2, 40));
+        let files = recorded_files(&dir).unwrap();
+        let sizes: Vec<u64> = files.iter().map(|file| file.bytes).collect();
+        assert_eq!(sizes, [42 + 3 * 400_000, 42 + 400_000]);
+        assert_eq!(info.bytes, sizes.iter().sum::<u64>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
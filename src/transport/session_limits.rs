@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Window the connect rate is counted over.
+const CONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Why a client can't start another session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    ServerFull,
+    TooManySessions,
+}
+
+impl Rejection {
+    /// Code the session is closed with, from the protocol's `CLOSE_*` codes.
+    pub fn close_code(self) -> u32 {
+        match self {
+            Rejection::ServerFull => streaming_protocol::CLOSE_SERVER_FULL,
+            Rejection::TooManySessions => streaming_protocol::CLOSE_TOO_MANY_SESSIONS,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::ServerFull => "Server has no room for more sessions",
+            Rejection::TooManySessions => "Too many sessions from this address",
+        })
+    }
+}
+
+#[derive(Default)]
+struct LimitState {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Recent connection attempts of each address, oldest first.
+    connects: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+/// Caps on concurrent sessions, overall and per client address, and on how
+/// often one address may connect.
+pub struct SessionLimits {
+    max_sessions: usize,
+    max_sessions_per_ip: usize,
+    max_connects_per_minute: usize,
+    state: Mutex<LimitState>,
+}
+
+impl SessionLimits {
+    pub fn new(
+        max_sessions: usize,
+        max_sessions_per_ip: usize,
+        max_connects_per_minute: usize,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            max_sessions,
+            max_sessions_per_ip,
+            max_connects_per_minute,
+            state: Mutex::new(LimitState::default()),
+        })
+    }
+
+    /// Counts a connection attempt from `ip`, and whether the address made few
+    /// enough in the last minute to allow it. Refused attempts count too.
+    pub fn allow_connect(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut state = self.state.lock().expect("Session limits lock poisoned");
+        state.connects.retain(|_, attempts| {
+            while attempts
+                .front()
+                .is_some_and(|&attempt| now.duration_since(attempt) >= CONNECT_WINDOW)
+            {
+                attempts.pop_front();
+            }
+            !attempts.is_empty()
+        });
+        let attempts = state.connects.entry(ip).or_default();
+        attempts.push_back(now);
+        if attempts.len() > self.max_connects_per_minute {
+            // Only the window's worth is needed to keep refusing.
+            attempts.pop_front();
+            return false;
+        }
+        true
+    }
+
+    /// Takes a session slot for `ip`, given back when the slot is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<SessionSlot, Rejection> {
+        let mut state = self.state.lock().expect("Session limits lock poisoned");
+        if state.total >= self.max_sessions {
+            return Err(Rejection::ServerFull);
+        }
+        let sessions = state.per_ip.entry(ip).or_default();
+        if *sessions >= self.max_sessions_per_ip {
+            return Err(Rejection::TooManySessions);
+        }
+        *sessions += 1;
+        state.total += 1;
+        Ok(SessionSlot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// One running session, counted against the limits until dropped.
+pub struct SessionSlot {
+    limits: Arc<SessionLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        let mut state = self
+            .limits
+            .state
+            .lock()
+            .expect("Session limits lock poisoned");
+        state.total -= 1;
+        if let Some(sessions) = state.per_ip.get_mut(&self.ip) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ALICE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+    const BOB: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 11));
+
+    #[test]
+    fn slots_are_limited_per_address_and_overall() {
+        let limits = SessionLimits::new(3, 2, 100);
+        let first = limits.acquire(ALICE).unwrap();
+        let _second = limits.acquire(ALICE).unwrap();
+        assert_eq!(
+            limits.acquire(ALICE).err(),
+            Some(Rejection::TooManySessions)
+        );
+        let _third = limits.acquire(BOB).unwrap();
+        assert_eq!(limits.acquire(BOB).err(), Some(Rejection::ServerFull));
+
+        drop(first);
+        assert!(limits.acquire(ALICE).is_ok());
+    }
+
+    #[test]
+    fn connect_rate_recovers_after_a_minute() {
+        let limits = SessionLimits::new(10, 10, 2);
+        let start = Instant::now();
+        assert!(limits.allow_connect(ALICE, start));
+        assert!(limits.allow_connect(ALICE, start));
+        assert!(!limits.allow_connect(ALICE, start + Duration::from_secs(30)));
+        assert!(limits.allow_connect(BOB, start));
+        assert!(limits.allow_connect(ALICE, start + CONNECT_WINDOW + Duration::from_secs(1)));
+    }
+}
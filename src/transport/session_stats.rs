@@ -0,0 +1,202 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use streaming_protocol::{Codec, ReceiverReport};
+use tokio::sync::{Notify, watch};
+use utoipa::ToSchema;
+
+/// How one client session is doing, as `/api/stats` reports it.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct SessionStats {
+    pub id: usize,
+    #[schema(value_type = String)]
+    pub address: SocketAddr,
+    pub sink: String,
+    pub codec: Codec,
+    pub datagrams: bool,
+    pub tier: usize,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// Times the session fell behind the encoder, skipping `missed_packets`
+    /// in total.
+    pub lag_events: u64,
+    pub missed_packets: u64,
+    /// Packets resent because the client reported them lost.
+    pub packets_retransmitted: u64,
+    pub rtt_ms: f64,
+    /// The playback latency the client asked for, if any.
+    pub target_latency_ms: Option<u32>,
+    /// The client's last report of how the stream reaches it.
+    pub receiver_report: Option<ReceiverReport>,
+    pub duration_secs: f64,
+}
+
+struct Session {
+    stats: SessionStats,
+    kick: Arc<Notify>,
+}
+
+/// Stats of the sessions running right now, by session id.
+#[derive(Clone)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<BTreeMap<usize, Session>>>,
+    /// Marked changed whenever a session starts, ends or publishes its stats.
+    changes: Arc<watch::Sender<()>>,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self {
+            sessions: Default::default(),
+            changes: Arc::new(watch::channel(()).0),
+        }
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> Vec<SessionStats> {
+        let sessions = self.sessions.lock().expect("Session stats lock poisoned");
+        sessions
+            .values()
+            .map(|session| session.stats.clone())
+            .collect()
+    }
+
+    /// Changes whenever the snapshot might have.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Asks session `id` to end, returning whether it's running.
+    pub fn kick(&self, id: usize) -> bool {
+        let sessions = self.sessions.lock().expect("Session stats lock poisoned");
+        match sessions.get(&id) {
+            Some(session) => {
+                session.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Lists a new session until the returned handle is dropped.
+    pub fn register(&self, stats: SessionStats) -> SessionStatsHandle {
+        let mut handle = SessionStatsHandle {
+            registry: self.clone(),
+            stats,
+            started: Instant::now(),
+            kick: Arc::new(Notify::new()),
+        };
+        handle.publish();
+        handle
+    }
+}
+
+/// A session's own copy of its stats, which it updates freely and publishes
+/// to the registry every so often.
+pub struct SessionStatsHandle {
+    registry: SessionRegistry,
+    pub stats: SessionStats,
+    started: Instant,
+    kick: Arc<Notify>,
+}
+
+impl SessionStatsHandle {
+    pub fn publish(&mut self) {
+        self.stats.duration_secs = self.started.elapsed().as_secs_f64();
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .expect("Session stats lock poisoned");
+        sessions.insert(
+            self.stats.id,
+            Session {
+                stats: self.stats.clone(),
+                kick: self.kick.clone(),
+            },
+        );
+        self.registry.changes.send_replace(());
+    }
+
+    /// Notified once the session is kicked.
+    pub fn kick_signal(&self) -> Arc<Notify> {
+        self.kick.clone()
+    }
+}
+
+impl Drop for SessionStatsHandle {
+    fn drop(&mut self) {
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .expect("Session stats lock poisoned");
+        sessions.remove(&self.stats.id);
+        self.registry.changes.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(id: usize) -> SessionStats {
+        SessionStats {
+            id,
+            address: SocketAddr::from(([192, 168, 1, 10], 50_000)),
+            sink: String::from("fake-speaker"),
+            codec: Codec::Opus,
+            datagrams: false,
+            tier: 0,
+            packets_sent: 0,
+            bytes_sent: 0,
+            lag_events: 0,
+            missed_packets: 0,
+            packets_retransmitted: 0,
+            rtt_ms: 0.0,
+            target_latency_ms: None,
+            receiver_report: None,
+            duration_secs: 0.0,
+        }
+    }
+
+    #[test]
+    fn sessions_are_listed_until_they_end() {
+        let registry = SessionRegistry::new();
+        let mut first = registry.register(stats(1));
+        let second = registry.register(stats(2));
+        first.stats.packets_sent = 5;
+        assert_eq!(registry.snapshot()[0].packets_sent, 0);
+        first.publish();
+        assert_eq!(registry.snapshot()[0].packets_sent, 5);
+
+        drop(second);
+        let ids: Vec<usize> = registry.snapshot().iter().map(|stats| stats.id).collect();
+        assert_eq!(ids, [1]);
+    }
+
+    #[tokio::test]
+    async fn kicked_sessions_are_told_and_watchers_see_changes() {
+        let registry = SessionRegistry::new();
+        let mut changes = registry.subscribe();
+        let session = registry.register(stats(1));
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        let kicked = session.kick_signal();
+        assert!(registry.kick(1));
+        assert!(!registry.kick(2));
+        kicked.notified().await;
+
+        drop(session);
+        assert!(changes.has_changed().unwrap());
+        assert!(!registry.kick(1));
+    }
+}
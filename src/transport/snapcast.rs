@@ -0,0 +1,340 @@
+use crate::bounded::BoundedReceiver;
+use crate::config::Config;
+use crate::control::ControlBus;
+use crate::encode::compress::CapturedAudio;
+use anyhow::{Result, bail};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+/// Service type Snapcast clients look for on the LAN.
+pub const MDNS_SERVICE_TYPE: &str = "_snapcast._tcp.local.";
+
+/// Message types of the Snapcast protocol used here.
+const CODEC_HEADER: u16 = 1;
+const WIRE_CHUNK: u16 = 2;
+const SERVER_SETTINGS: u16 = 3;
+const TIME: u16 = 4;
+const HELLO: u16 = 5;
+
+/// Length of the header every message starts with.
+const HEADER_LEN: usize = 26;
+/// Longest message accepted from a client. Hellos and client infos are short
+/// JSON documents.
+const MAX_MESSAGE_LEN: u32 = 64 * 1024;
+/// How far behind their timestamps clients play the chunks, Snapserver's
+/// default. Covers the clients' own buffering and the network.
+const BUFFER_MS: u32 = 1000;
+/// Replies queued for a client. Beyond this its requests aren't read until
+/// the replies are written.
+const REPLIES_CAPACITY: usize = 16;
+
+/// Wall clock time in microseconds since the Unix epoch, the clock capture
+/// times are stamped with.
+fn now_us() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i64
+}
+
+/// The header of a message, with its times in microseconds.
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+    kind: u16,
+    id: u16,
+    refers_to: u16,
+    sent_us: i64,
+    received_us: i64,
+    size: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8; HEADER_LEN]) -> Self {
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        Self {
+            kind: u16_at(0),
+            id: u16_at(2),
+            refers_to: u16_at(4),
+            sent_us: read_time(&bytes[6..14]),
+            received_us: read_time(&bytes[14..22]),
+            size: u32::from_le_bytes(bytes[22..26].try_into().expect("Four bytes")),
+        }
+    }
+}
+
+/// Times go over the wire as seconds and microseconds.
+fn write_time(out: &mut Vec<u8>, us: i64) {
+    out.extend((us.div_euclid(1_000_000) as i32).to_le_bytes());
+    out.extend((us.rem_euclid(1_000_000) as i32).to_le_bytes());
+}
+
+fn read_time(bytes: &[u8]) -> i64 {
+    let secs = i32::from_le_bytes(bytes[..4].try_into().expect("Four bytes"));
+    let usecs = i32::from_le_bytes(bytes[4..8].try_into().expect("Four bytes"));
+    secs as i64 * 1_000_000 + usecs as i64
+}
+
+/// A message from the server, stamped as sent at `sent_us`.
+fn message(kind: u16, refers_to: u16, received_us: i64, sent_us: i64, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend(kind.to_le_bytes());
+    out.extend(0u16.to_le_bytes());
+    out.extend(refers_to.to_le_bytes());
+    write_time(&mut out, sent_us);
+    write_time(&mut out, received_us);
+    out.extend((payload.len() as u32).to_le_bytes());
+    out.extend(payload);
+    out
+}
+
+/// Strings and blobs are prefixed with their length.
+fn write_sized(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+fn server_settings(refers_to: u16) -> Vec<u8> {
+    let settings = format!(
+        "{{\"bufferMs\":{},\"latency\":0,\"muted\":false,\"volume\":100}}",
+        BUFFER_MS
+    );
+    let mut payload = Vec::new();
+    write_sized(&mut payload, settings.as_bytes());
+    message(SERVER_SETTINGS, refers_to, 0, now_us(), &payload)
+}
+
+/// Announces raw 16-bit PCM, described by the header of a WAV file.
+fn codec_header(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut wav = Vec::with_capacity(44);
+    wav.extend(b"RIFF");
+    wav.extend(36u32.to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(channels.to_le_bytes());
+    wav.extend(sample_rate.to_le_bytes());
+    wav.extend((sample_rate * block_align as u32).to_le_bytes());
+    wav.extend(block_align.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(0u32.to_le_bytes());
+    let mut payload = Vec::new();
+    write_sized(&mut payload, b"pcm");
+    write_sized(&mut payload, &wav);
+    message(CODEC_HEADER, 0, 0, now_us(), &payload)
+}
+
+/// Audio stamped with the capture time of its first sample, which clients
+/// play `BUFFER_MS` later by the server's clock.
+fn wire_chunk(audio: &CapturedAudio) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(12 + audio.samples.len() * 2);
+    write_time(&mut payload, audio.captured_at_us as i64);
+    payload.extend(((audio.samples.len() * 2) as u32).to_le_bytes());
+    payload.extend(audio.samples.iter().flat_map(|sample| sample.to_le_bytes()));
+    message(WIRE_CHUNK, 0, 0, now_us(), &payload)
+}
+
+/// Answers a client's time request, which clients use to work out the
+/// offset between their clock and the server's.
+fn time_reply(request: &Header, received_us: i64, sent_us: i64) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_time(&mut payload, received_us - request.sent_us);
+    message(TIME, request.id, received_us, sent_us, &payload)
+}
+
+/// What the reading half of a connection asks the writing half to send.
+enum Reply {
+    /// The client said hello, so it's sent the stream's settings and audio.
+    Hello {
+        id: u16,
+    },
+    Message(Vec<u8>),
+}
+
+async fn read_messages(mut reader: OwnedReadHalf, replies: mpsc::Sender<Reply>) -> Result<()> {
+    let mut header = [0; HEADER_LEN];
+    loop {
+        reader.read_exact(&mut header).await?;
+        let received_us = now_us();
+        let header = Header::parse(&header);
+        if header.size > MAX_MESSAGE_LEN {
+            bail!("Message of {} bytes is too long", header.size);
+        }
+        // Hellos and client infos carry nothing the server needs.
+        let mut payload = vec![0; header.size as usize];
+        reader.read_exact(&mut payload).await?;
+        let reply = match header.kind {
+            HELLO => Reply::Hello { id: header.id },
+            TIME => Reply::Message(time_reply(&header, received_us, now_us())),
+            _ => continue,
+        };
+        if replies.send(reply).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends the client what its requests ask for, and the audio once it said
+/// hello. Returns once the reading half stops.
+async fn write_messages(
+    config: &Config,
+    mut writer: OwnedWriteHalf,
+    mut replies: mpsc::Receiver<Reply>,
+    mut audio: broadcast::Receiver<Arc<CapturedAudio>>,
+) -> Result<()> {
+    let mut streaming = false;
+    loop {
+        tokio::select! {
+            reply = replies.recv() => match reply {
+                Some(Reply::Hello { id }) => {
+                    writer.write_all(&server_settings(id)).await?;
+                    writer
+                        .write_all(&codec_header(config.sample_rate, config.stream_channels() as u16))
+                        .await?;
+                    // Start at the live edge.
+                    audio = audio.resubscribe();
+                    streaming = true;
+                }
+                Some(Reply::Message(message)) => writer.write_all(&message).await?,
+                None => return Ok(()),
+            },
+            chunk = audio.recv(), if streaming => match chunk {
+                Ok(chunk) => writer.write_all(&wire_chunk(&chunk)).await?,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Snapcast client lagged, {} chunks missed", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => bail!("The sink's audio ended"),
+            },
+        }
+    }
+}
+
+async fn serve_client(
+    config: Arc<Config>,
+    stream: TcpStream,
+    audio: broadcast::Receiver<Arc<CapturedAudio>>,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let (replies_tx, replies) = mpsc::channel(REPLIES_CAPACITY);
+    let reading = tokio::spawn(read_messages(reader, replies_tx));
+    if let Err(e) = write_messages(&config, writer, replies, audio).await {
+        reading.abort();
+        return Err(e);
+    }
+    // Why the reading half stopped, usually the client hanging up.
+    reading.await?
+}
+
+/// Serves the audio of `receiver` to Snapcast clients as raw PCM, taken
+/// before any encoder. Pausing and the volume apply like for the encoded
+/// streams.
+pub fn spawn_snapcast_task(
+    config: Arc<Config>,
+    receiver: BoundedReceiver<CapturedAudio>,
+    control: ControlBus,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (audio_tx, _) = broadcast::channel(200);
+        let forward_tx = audio_tx.clone();
+        tokio::spawn(async move {
+            let paused = control.subscribe_paused();
+            let settings = control.subscribe_encoder_settings();
+            while let Some(mut audio) = receiver.recv().await {
+                if *paused.borrow() {
+                    continue;
+                }
+                let volume = settings.borrow().volume;
+                if volume != 100 {
+                    let gain = volume as f32 / 100.0;
+                    for sample in audio.samples.iter_mut() {
+                        *sample = (*sample as f32 * gain).round() as i16;
+                    }
+                }
+                // Nobody may be listening.
+                let _ = forward_tx.send(Arc::new(audio));
+            }
+        });
+
+        let address = SocketAddr::from(([0, 0, 0, 0], config.snapcast_port));
+        let listener = TcpListener::bind(address)
+            .await
+            .expect("Couldn't bind the Snapcast port");
+        info!("Serving Snapcast clients on port {}", config.snapcast_port);
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Couldn't accept a Snapcast client: {}", e);
+                    continue;
+                }
+            };
+            info!("Snapcast client {} connected", address);
+            let config = config.clone();
+            let audio = audio_tx.subscribe();
+            tokio::spawn(async move {
+                match serve_client(config, stream, audio).await {
+                    Ok(()) => info!("Snapcast client {} disconnected", address),
+                    Err(e) => info!("Snapcast client {} disconnected: {}", address, e),
+                }
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_requests_are_answered_with_the_client_to_server_latency() {
+        let mut request = message(TIME, 0, 0, -1_500_000, &[0; 8]);
+        // Clients number their requests.
+        request[2..4].copy_from_slice(&7u16.to_le_bytes());
+        let request = Header::parse(request[..HEADER_LEN].try_into().unwrap());
+        assert_eq!(request.sent_us, -1_500_000);
+
+        let reply = time_reply(&request, 2_250_000, 2_500_000);
+        let header = Header::parse(reply[..HEADER_LEN].try_into().unwrap());
+        assert_eq!(
+            header,
+            Header {
+                kind: TIME,
+                id: 0,
+                refers_to: 7,
+                sent_us: 2_500_000,
+                received_us: 2_250_000,
+                size: 8,
+            }
+        );
+        assert_eq!(read_time(&reply[HEADER_LEN..]), 3_750_000);
+    }
+
+    #[test]
+    fn chunks_carry_their_capture_time_and_little_endian_samples() {
+        let chunk = wire_chunk(&CapturedAudio {
+            captured_at_us: 1_700_000_000_250_000,
+            samples: vec![1, -2],
+            surround: Vec::new(),
+            gap_us: 0,
+        });
+        let header = Header::parse(chunk[..HEADER_LEN].try_into().unwrap());
+        assert_eq!((header.kind, header.size), (WIRE_CHUNK, 16));
+        let payload = &chunk[HEADER_LEN..];
+        assert_eq!(read_time(payload), 1_700_000_000_250_000);
+        assert_eq!(&payload[8..], &[4, 0, 0, 0, 1, 0, 0xfe, 0xff]);
+
+        let header = codec_header(48_000, 2);
+        let payload = &header[HEADER_LEN..];
+        assert_eq!(&payload[..7], b"\x03\0\0\0pcm");
+        assert_eq!(&payload[11..15], b"RIFF");
+        assert_eq!(payload.len(), 7 + 4 + 44);
+    }
+}
@@ -0,0 +1,45 @@
+// The files of `web/` when the binary was built, as `(path, contents)`.
+include!(concat!(env!("OUT_DIR"), "/web_assets.rs"));
+
+/// The embedded file at `path`, relative to `web/`.
+pub fn get(path: &str) -> Option<&'static [u8]> {
+    WEB_ASSETS
+        .iter()
+        .find(|(name, _)| *name == path)
+        .map(|(_, data)| *data)
+}
+
+pub fn is_empty() -> bool {
+    WEB_ASSETS.is_empty()
+}
+
+/// The `Content-Type` of a web client file, by its extension.
+pub fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_served_with_their_type() {
+        assert_eq!(content_type("pkg/client_bg.wasm"), "application/wasm");
+        assert_eq!(content_type("index.html"), "text/html; charset=utf-8");
+        assert_eq!(content_type("LICENSE"), "application/octet-stream");
+        for (name, data) in WEB_ASSETS {
+            assert_eq!(get(name), Some(*data));
+        }
+        assert_eq!(get("not-a-file.html"), None);
+    }
+}
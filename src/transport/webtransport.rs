@@ -0,0 +1,775 @@
+use crate::bounded::BoundedSender;
+use crate::config::Config;
+use crate::control::ControlBus;
+use crate::encode::CodecPackets;
+use crate::encode::compress::{
+    EncodedPacket, SURROUND_TIER, TIER_COUNT, TierDemand, TierSubscription, samples_per_frame,
+};
+use crate::health::Health;
+use crate::transport::session_limits::SessionLimits;
+use crate::transport::session_stats::{SessionRegistry, SessionStats};
+use anyhow::{Result, bail};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use streaming_protocol::{
+    self as protocol, ClientCommand, Codec, ControlMessage, NowPlaying, PacketHeader,
+    SequenceEvent, SequenceTracker,
+};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use wtransport::endpoint::IncomingSession;
+use wtransport::error::StreamReadExactError;
+use wtransport::{Connection, RecvStream, SendStream, VarInt};
+
+/// How often each client's bitrate tier is re-evaluated, and stats sent.
+const ADAPT_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets queued for a client beyond which its link counts as congested.
+const CONGESTED_BACKLOG: usize = 10;
+/// Round trip time above the lowest one seen that counts as congestion. This
+/// is what catches congestion in datagram mode, where nothing queues up.
+const CONGESTED_RTT_INCREASE: Duration = Duration::from_millis(50);
+/// Uncongested intervals before a client is moved back up a tier.
+const RECOVERY_INTERVALS: u32 = 5;
+/// Commands queued for a session. Beyond this the client's control streams
+/// aren't read until the session catches up.
+const COMMANDS_CAPACITY: usize = 16;
+/// How long clients of a server with a password have to answer its challenge.
+const PASSWORD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Moves a client down a bitrate tier when it falls behind, and back up once
+/// its link has been clear for a while.
+struct BitrateAdapter {
+    subscription: TierSubscription,
+    /// The tier the client asked for, which congestion doesn't change.
+    pinned: Option<usize>,
+    /// Held while the client asked for surround, sent in place of tier 0.
+    surround: Option<TierSubscription>,
+    demand: mpsc::UnboundedSender<TierDemand>,
+    congested: bool,
+    clear_intervals: u32,
+    min_rtt: Duration,
+}
+
+impl BitrateAdapter {
+    fn new(demand: mpsc::UnboundedSender<TierDemand>) -> Self {
+        Self {
+            subscription: TierSubscription::new(demand.clone(), 0),
+            pinned: None,
+            surround: None,
+            demand,
+            congested: false,
+            clear_intervals: 0,
+            min_rtt: Duration::MAX,
+        }
+    }
+
+    fn tier(&self) -> usize {
+        self.subscription.tier()
+    }
+
+    /// The tier packets are sent at: surround, if the client asked for it
+    /// and keeps up with the highest tier, otherwise the downmixed one.
+    fn send_tier(&self) -> usize {
+        match (&self.surround, self.tier()) {
+            (Some(_), 0) => SURROUND_TIER,
+            (_, tier) => tier,
+        }
+    }
+
+    fn set_surround(&mut self, surround: bool) {
+        if surround != self.surround.is_some() {
+            self.surround =
+                surround.then(|| TierSubscription::new(self.demand.clone(), SURROUND_TIER));
+        }
+    }
+
+    /// Keeps the client on `tier`, or adapts again from the current one.
+    fn pin(&mut self, tier: Option<usize>) {
+        self.pinned = tier.map(|tier| tier.min(TIER_COUNT - 1));
+        if let Some(tier) = self.pinned {
+            self.subscription.set_tier(tier);
+        }
+        self.congested = false;
+        self.clear_intervals = 0;
+    }
+
+    fn observe_backlog(&mut self, backlog: usize) {
+        if backlog > CONGESTED_BACKLOG {
+            self.congested = true;
+        }
+    }
+
+    fn mark_congested(&mut self) {
+        self.congested = true;
+    }
+
+    /// Re-evaluates the tier, returning it if it changed.
+    fn adapt(&mut self, rtt: Duration) -> Option<usize> {
+        self.min_rtt = self.min_rtt.min(rtt);
+        if self.pinned.is_some() {
+            return None;
+        }
+        if rtt > self.min_rtt + CONGESTED_RTT_INCREASE {
+            self.congested = true;
+        }
+        let tier = self.tier();
+        let new_tier = if self.congested {
+            self.clear_intervals = 0;
+            (tier + 1).min(TIER_COUNT - 1)
+        } else {
+            self.clear_intervals += 1;
+            if self.clear_intervals < RECOVERY_INTERVALS {
+                return None;
+            }
+            self.clear_intervals = 0;
+            tier.saturating_sub(1)
+        };
+        self.congested = false;
+        self.subscription.set_tier(new_tier);
+        (new_tier != tier).then_some(new_tier)
+    }
+}
+
+/// Compressed audio of one sink. Clients pick a sink through the session path,
+/// e.g. `/living-room`; the root path gets the first sink.
+pub struct SinkPackets {
+    pub id: String,
+    /// The sink's audio in each configured codec, in order of preference.
+    pub codecs: Vec<CodecPackets>,
+}
+
+fn select_sink<'a>(sinks: &'a [SinkPackets], path: &str) -> Option<&'a SinkPackets> {
+    let id = path.split('?').next().unwrap_or_default().trim_matches('/');
+    if id.is_empty() {
+        sinks.first()
+    } else {
+        sinks.iter().find(|sink| sink.id == id)
+    }
+}
+
+/// Value of the `key=value` parameter in the session path's query.
+fn query_param<'a>(path: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = path.split_once('?')?;
+    query.split('&').find_map(|param| {
+        param
+            .strip_prefix(key)
+            .and_then(|value| value.strip_prefix('='))
+    })
+}
+
+/// The first of the sink's codecs among those the client lists in the session
+/// path, e.g. `/?codecs=aac,opus`. Clients that don't list any get the first.
+fn select_codec<'a>(codecs: &'a [CodecPackets], path: &str) -> Option<&'a CodecPackets> {
+    let Some(decodable) = query_param(path, protocol::CODECS_QUERY_KEY) else {
+        return codecs.first();
+    };
+    let decodable: Vec<Codec> = decodable
+        .split(',')
+        .filter_map(|name| name.parse().ok())
+        .collect();
+    codecs
+        .iter()
+        .find(|packets| decodable.contains(&packets.codec))
+}
+
+/// Whether the session path carries a valid token signed with the access key.
+fn is_authenticated(path: &str, access_key: &str) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    query_param(path, protocol::TOKEN_QUERY_KEY)
+        .is_some_and(|token| protocol::verify_session_token(access_key, token, now))
+}
+
+/// Whether the client asked for audio packets as datagrams in the session path.
+fn asks_for_datagrams(path: &str) -> bool {
+    path.split_once('?').is_some_and(|(_, query)| {
+        query
+            .split('&')
+            .any(|param| param == protocol::DATAGRAM_QUERY)
+    })
+}
+
+async fn send_control(stream: &mut SendStream, message: &ControlMessage) -> Result<()> {
+    stream.write_all(&message.encode()).await?;
+    Ok(())
+}
+
+/// Forwards the framed Opus packets a client sends on a bidirectional stream to
+/// the microphone decoder. Packets in other codecs are dropped.
+async fn receive_mic(mut stream: RecvStream, mic: BoundedSender<Vec<u8>>) -> Result<()> {
+    let mut header_bytes = [0; protocol::HEADER_LEN];
+    let mut sequence = SequenceTracker::default();
+    loop {
+        match stream.read_exact(&mut header_bytes).await {
+            Ok(()) => {}
+            Err(StreamReadExactError::FinishedEarly(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let header = PacketHeader::decode(&header_bytes)?;
+        let mut packet = vec![0; header.payload_len as usize];
+        stream.read_exact(&mut packet).await?;
+        match sequence.track(header.sequence) {
+            SequenceEvent::InOrder => {}
+            SequenceEvent::Gap(missed) => {
+                warn!("{} microphone packets missed", missed)
+            }
+            SequenceEvent::Late => continue,
+        }
+        if header.codec != Codec::Opus {
+            warn!("Dropped {} microphone packet", header.codec);
+            continue;
+        }
+        mic.send(packet)?;
+    }
+}
+
+/// Reads a bidirectional stream a client opened, which starts with a byte
+/// saying whether it carries commands or microphone audio.
+async fn receive_client_stream(
+    mut stream: RecvStream,
+    id: usize,
+    commands: mpsc::Sender<ClientCommand>,
+    mic: Option<BoundedSender<Vec<u8>>>,
+) -> Result<()> {
+    let mut kind = [0; 1];
+    stream.read_exact(&mut kind).await?;
+    match (kind[0], mic) {
+        (protocol::STREAM_CONTROL, _) => {
+            receive_commands(BufReader::new(stream).lines(), commands).await
+        }
+        (protocol::STREAM_MIC, Some(mic)) => {
+            info!("Client {} is sending microphone audio", id);
+            receive_mic(stream, mic).await
+        }
+        (protocol::STREAM_MIC, None) => bail!("Microphone audio sent without --mic"),
+        (other, _) => bail!("Unknown stream kind {}", other),
+    }
+}
+
+/// Forwards the commands on a client's control stream to its session, waiting
+/// while the session is behind.
+async fn receive_commands(
+    mut lines: Lines<BufReader<RecvStream>>,
+    commands: mpsc::Sender<ClientCommand>,
+) -> Result<()> {
+    while let Some(line) = lines.next_line().await? {
+        if commands
+            .send(ClientCommand::decode(line.as_bytes())?)
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the client to prove it knows the password with the first command
+/// on its first bidirectional stream. Returns the rest of that stream's
+/// commands, or `None` if the proof is wrong.
+async fn authenticate(
+    connection: &Connection,
+    password: &str,
+    nonce: &str,
+) -> Result<Option<Lines<BufReader<RecvStream>>>> {
+    let (_, mut stream) = connection.accept_bi().await?;
+    let mut kind = [0; 1];
+    stream.read_exact(&mut kind).await?;
+    if kind[0] != protocol::STREAM_CONTROL {
+        bail!(
+            "Stream of kind {} opened before the password was proven",
+            kind[0]
+        );
+    }
+    let mut lines = BufReader::new(stream).lines();
+    let Some(line) = lines.next_line().await? else {
+        bail!("Control stream closed before the password was proven");
+    };
+    match ClientCommand::decode(line.as_bytes())? {
+        ClientCommand::Authenticate { proof }
+            if protocol::verify_password_proof(password, nonce, &proof) =>
+        {
+            Ok(Some(lines))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Frames `packet` at `tier` into `framed` and sends it on the media stream,
+/// or as a datagram. Returns whether it was sent, a datagram that couldn't be
+/// being skipped.
+///
+/// `sent_tier` is the tier of the last packet sent, if the client's decoder
+/// can go on from it. Packets from another tier's encoder, or from one that
+/// was reset, tell the client to reset its decoder first.
+async fn send_packet(
+    connection: &Connection,
+    send_stream: &mut SendStream,
+    framed: &mut Vec<u8>,
+    packet: &EncodedPacket,
+    tier: usize,
+    sent_tier: &mut Option<usize>,
+    datagrams: bool,
+) -> Result<bool> {
+    let codec = packet.codec(tier);
+    let tier = packet.encoded_tier(tier);
+    let decoder_reset = packet.decoder_reset || *sent_tier != Some(tier);
+    protocol::frame_into(
+        framed,
+        codec,
+        packet.sequence,
+        packet.captured_at_us,
+        packet.frame_samples,
+        decoder_reset,
+        packet.payload(tier),
+    );
+    // A datagram that's skipped or lost leaves the decoder's state behind
+    // too, but decoders conceal single losses well enough.
+    *sent_tier = Some(tier);
+    if !datagrams {
+        send_stream.write_all(framed).await?;
+    } else if let Err(e) = connection.send_datagram(&*framed) {
+        warn!(
+            "Couldn't send audio datagram to client {}: {}",
+            connection.stable_id(),
+            e
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn stream_info(
+    config: &Config,
+    sink: &SinkPackets,
+    codec: Codec,
+    datagrams: bool,
+) -> ControlMessage {
+    ControlMessage::StreamInfo {
+        sink: sink.id.clone(),
+        codec,
+        sample_rate: config.sample_rate,
+        channels: config.channels,
+        frame_samples: samples_per_frame(config, codec) as u16,
+        datagrams,
+        surround: config.surround && codec == Codec::Opus,
+    }
+}
+
+async fn handle_connection(
+    config: Arc<Config>,
+    incoming_session: IncomingSession,
+    sinks: Arc<Vec<SinkPackets>>,
+    control: ControlBus,
+    limits: Arc<SessionLimits>,
+    registry: SessionRegistry,
+    mic: Option<BoundedSender<Vec<u8>>>,
+) -> Result<()> {
+    let session_request = incoming_session.await?;
+    let path = session_request.path().to_string();
+    let access = control.access();
+    if !is_authenticated(&path, &access.key) {
+        warn!(
+            "Rejected session from {} without a valid token",
+            session_request.remote_address()
+        );
+        session_request.forbidden().await;
+        return Ok(());
+    }
+    let Some(mut sink) = select_sink(&sinks, &path) else {
+        warn!("Client asked for unknown sink {}", path);
+        session_request.not_found().await;
+        return Ok(());
+    };
+    let Some(mut packets) = select_codec(&sink.codecs, &path) else {
+        warn!("Client can't decode any of the codecs of {}", path);
+        session_request.forbidden().await;
+        return Ok(());
+    };
+    let mut rx = packets.receiver.resubscribe();
+    let slot = limits.acquire(session_request.remote_address().ip());
+    let connection = session_request.accept().await?;
+    // Accepted anyway, so the client learns why it's turned away.
+    let _slot = match slot {
+        Ok(slot) => slot,
+        Err(rejection) => {
+            warn!(
+                "Turned away client {}: {}",
+                connection.remote_address(),
+                rejection
+            );
+            connection.close(
+                VarInt::from_u32(rejection.close_code()),
+                rejection.to_string().as_bytes(),
+            );
+            return Ok(());
+        }
+    };
+    let id = connection.stable_id();
+    // Datagrams trade reliability for latency, but only if the connection
+    // negotiated them. Otherwise the reliable stream is the fallback. Frames
+    // of the other codecs are often too big for one datagram.
+    let datagrams = asks_for_datagrams(&path)
+        && packets.codec == Codec::Opus
+        && connection.max_datagram_size().is_some();
+    let mut paused = control.subscribe_paused();
+    let mut certificate = control.subscribe_certificate();
+    let mut now_playing = control.subscribe_now_playing();
+    // Set by the client's own pause command, stopping its audio only.
+    let mut session_paused = false;
+    // The control stream describes the audio before any of it is sent.
+    let mut control_stream = connection.open_uni().await?.await?;
+    control_stream
+        .write_all(&[protocol::STREAM_CONTROL])
+        .await?;
+    let (commands_tx, mut commands) = mpsc::channel(COMMANDS_CAPACITY);
+    if let Some(password) = &access.password {
+        let nonce = protocol::password_nonce(rand::random());
+        let challenge = ControlMessage::PasswordChallenge {
+            nonce: nonce.clone(),
+        };
+        send_control(&mut control_stream, &challenge).await?;
+        let proof = tokio::time::timeout(
+            PASSWORD_TIMEOUT,
+            authenticate(&connection, password, &nonce),
+        )
+        .await;
+        let rejection = match proof {
+            Ok(Ok(Some(lines))) => {
+                let commands_tx = commands_tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = receive_commands(lines, commands_tx).await {
+                        warn!("Stream from client {} failed: {}", id, e);
+                    }
+                });
+                None
+            }
+            Ok(Ok(None)) => Some(String::from("wrong password")),
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(String::from("no answer to the password challenge")),
+        };
+        if let Some(rejection) = rejection {
+            warn!(
+                "Turned away client {}: {}",
+                connection.remote_address(),
+                rejection
+            );
+            connection.close(
+                VarInt::from_u32(protocol::CLOSE_WRONG_PASSWORD),
+                b"Wrong password",
+            );
+            return Ok(());
+        }
+    }
+    send_control(
+        &mut control_stream,
+        &stream_info(&config, sink, packets.codec, datagrams),
+    )
+    .await?;
+    let state = ControlMessage::State {
+        paused: *paused.borrow_and_update(),
+    };
+    send_control(&mut control_stream, &state).await?;
+    let mut idle = packets.idle.clone();
+    if *idle.borrow_and_update() {
+        send_control(&mut control_stream, &ControlMessage::Idle { idle: true }).await?;
+    }
+    let track = now_playing.borrow_and_update().clone();
+    if track != NowPlaying::default() {
+        send_control(&mut control_stream, &ControlMessage::NowPlaying(track)).await?;
+    }
+    let mut send_stream = connection.open_uni().await?.await?;
+    send_stream.write_all(&[protocol::STREAM_MEDIA]).await?;
+    info!("Client {} gets {} audio", id, packets.codec);
+    let mut session_stats = registry.register(SessionStats {
+        id,
+        address: connection.remote_address(),
+        sink: sink.id.clone(),
+        codec: packets.codec,
+        datagrams,
+        tier: 0,
+        packets_sent: 0,
+        bytes_sent: 0,
+        lag_events: 0,
+        missed_packets: 0,
+        packets_retransmitted: 0,
+        rtt_ms: 0.0,
+        target_latency_ms: None,
+        receiver_report: None,
+        duration_secs: 0.0,
+    });
+    let mut bitrate = BitrateAdapter::new(packets.tier_demand.clone());
+    let mut adapt_ticker = tokio::time::interval(ADAPT_INTERVAL);
+    let kicked = session_stats.kick_signal();
+    let mut missed_packets = 0;
+    // Every packet is framed into this buffer in turn.
+    let mut framed = Vec::new();
+    // The last packet of the startup burst, which the live packets catch up
+    // with. Sent unless the stream is paused.
+    let mut burst_end = None;
+    // The tier of the last packet sent, `None` until the client's decoder
+    // has something to go on from.
+    let mut sent_tier = None;
+    if !*paused.borrow() {
+        for packet in packets.recent.burst() {
+            if send_packet(
+                &connection,
+                &mut send_stream,
+                &mut framed,
+                &packet,
+                0,
+                &mut sent_tier,
+                datagrams,
+            )
+            .await?
+            {
+                session_stats.stats.packets_sent += 1;
+                session_stats.stats.bytes_sent += framed.len() as u64;
+            }
+            burst_end = Some(packet.sequence);
+        }
+    }
+    loop {
+        tokio::select! {
+            changed = paused.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                let state = ControlMessage::State { paused: *paused.borrow_and_update() || session_paused };
+                send_control(&mut control_stream, &state).await?;
+            }
+            Ok(()) = idle.changed() => {
+                let idle = ControlMessage::Idle { idle: *idle.borrow_and_update() };
+                send_control(&mut control_stream, &idle).await?;
+            }
+            _ = adapt_ticker.tick() => {
+                let rtt = connection.rtt();
+                if let Some(tier) = bitrate.adapt(rtt) {
+                    info!("Client {} moved to bitrate tier {}", id, tier);
+                }
+                let stats = ControlMessage::Stats {
+                    tier: bitrate.tier(),
+                    rtt_ms: rtt.as_secs_f64() * 1000.0,
+                    missed_packets: std::mem::take(&mut missed_packets),
+                };
+                send_control(&mut control_stream, &stats).await?;
+                session_stats.stats.tier = bitrate.tier();
+                session_stats.stats.rtt_ms = rtt.as_secs_f64() * 1000.0;
+                session_stats.publish();
+            }
+            Ok(()) = now_playing.changed() => {
+                let track = now_playing.borrow_and_update().clone();
+                send_control(&mut control_stream, &ControlMessage::NowPlaying(track)).await?;
+            }
+            Ok(()) = certificate.changed() => {
+                send_control(&mut control_stream, &ControlMessage::CertificateRenewed).await?;
+            }
+            _ = kicked.notified() => {
+                info!("Kicked client {}", id);
+                connection.close(VarInt::from_u32(protocol::CLOSE_KICKED), b"Kicked by the server's operator");
+                return Ok(());
+            }
+            stream = connection.accept_bi() => {
+                let (_, recv_stream) = stream?;
+                let (commands_tx, mic) = (commands_tx.clone(), mic.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = receive_client_stream(recv_stream, id, commands_tx, mic).await {
+                        warn!("Stream from client {} failed: {}", id, e);
+                    }
+                });
+            }
+            Some(command) = commands.recv() => {
+                match command {
+                    ClientCommand::Pause | ClientCommand::Resume => {
+                        session_paused = command == ClientCommand::Pause;
+                        let state = ControlMessage::State { paused: *paused.borrow() || session_paused };
+                        send_control(&mut control_stream, &state).await?;
+                    }
+                    ClientCommand::SetVolume { percent } => {
+                        if let Err(e) = control.update_encoder_settings(|settings| settings.volume = percent) {
+                            warn!("Client {} set an invalid volume: {}", id, e);
+                        }
+                    }
+                    ClientCommand::Restart => {
+                        rx = packets.receiver.resubscribe();
+                        burst_end = None;
+                        sent_tier = None;
+                        send_control(&mut control_stream, &stream_info(&config, sink, packets.codec, datagrams)).await?;
+                    }
+                    ClientCommand::SelectSource { sink: new_id } => {
+                        // Datagram sessions stay on codecs that fit in one.
+                        let selected = select_sink(&sinks, &new_id)
+                            .and_then(|sink| Some((sink, select_codec(&sink.codecs, &path)?)))
+                            .filter(|(_, packets)| !datagrams || packets.codec == Codec::Opus);
+                        let Some((new_sink, new_packets)) = selected else {
+                            warn!("Client {} can't switch to sink {}", id, new_id);
+                            continue;
+                        };
+                        (sink, packets) = (new_sink, new_packets);
+                        rx = packets.receiver.resubscribe();
+                        burst_end = None;
+                        sent_tier = None;
+                        let (pinned, surround) = (bitrate.pinned, bitrate.surround.is_some());
+                        bitrate = BitrateAdapter::new(packets.tier_demand.clone());
+                        bitrate.pin(pinned);
+                        bitrate.set_surround(surround && packets.codec == Codec::Opus);
+                        info!("Client {} switched to {} audio of {}", id, packets.codec, sink.id);
+                        session_stats.stats.sink = sink.id.clone();
+                        session_stats.stats.codec = packets.codec;
+                        send_control(&mut control_stream, &stream_info(&config, sink, packets.codec, datagrams)).await?;
+                        let was_idle = *idle.borrow();
+                        idle = packets.idle.clone();
+                        if *idle.borrow_and_update() != was_idle {
+                            send_control(&mut control_stream, &ControlMessage::Idle { idle: !was_idle }).await?;
+                        }
+                    }
+                    ClientCommand::SetTargetLatency { ms } => {
+                        let ms = ms.clamp(protocol::MIN_TARGET_LATENCY_MS, protocol::MAX_TARGET_LATENCY_MS);
+                        info!("Client {} plays {} ms behind capture", id, ms);
+                        session_stats.stats.target_latency_ms = Some(ms);
+                        send_control(&mut control_stream, &ControlMessage::TargetLatency { ms }).await?;
+                    }
+                    ClientCommand::SelectTier { tier } => {
+                        bitrate.pin(tier);
+                        match tier {
+                            Some(_) => info!("Client {} pinned to bitrate tier {}", id, bitrate.tier()),
+                            None => info!("Client {} follows its link's bitrate tier", id),
+                        }
+                        session_stats.stats.tier = bitrate.tier();
+                    }
+                    ClientCommand::SetSurround { enabled } => {
+                        if enabled && !(config.surround && packets.codec == Codec::Opus) {
+                            warn!("Client {} asked for surround, which {} audio of {} isn't in", id, packets.codec, sink.id);
+                            continue;
+                        }
+                        bitrate.set_surround(enabled);
+                        match enabled {
+                            true => info!("Client {} gets surround while its link keeps up", id),
+                            false => info!("Client {} gets the downmix", id),
+                        }
+                    }
+                    ClientCommand::ReceiverReport(report) => {
+                        session_stats.stats.receiver_report = Some(report);
+                    }
+                    // Only answers the challenge before the session starts.
+                    ClientCommand::Authenticate { .. } => {}
+                    ClientCommand::Nack { sequences } if datagrams => {
+                        for sequence in sequences.into_iter().take(protocol::MAX_NACK_SEQUENCES) {
+                            let Some(packet) = packets.history.get(sequence) else {
+                                continue;
+                            };
+                            if send_packet(&connection, &mut send_stream, &mut framed, &packet, bitrate.send_tier(), &mut sent_tier, datagrams).await? {
+                                session_stats.stats.packets_retransmitted += 1;
+                                session_stats.stats.bytes_sent += framed.len() as u64;
+                            }
+                        }
+                    }
+                    ClientCommand::Nack { .. } => {}
+                }
+            }
+            msg = rx.recv() => {
+                match msg {
+                    Ok(_) if session_paused => {}
+                    Ok(packet) => {
+                        if let Some(last) = burst_end {
+                            // Already sent in the startup burst.
+                            if packet.sequence.wrapping_sub(last) as i32 <= 0 {
+                                continue;
+                            }
+                            burst_end = None;
+                        }
+                        bitrate.observe_backlog(rx.len());
+                        if !send_packet(&connection, &mut send_stream, &mut framed, &packet, bitrate.send_tier(), &mut sent_tier, datagrams).await? {
+                            continue;
+                        }
+                        session_stats.stats.packets_sent += 1;
+                        session_stats.stats.bytes_sent += framed.len() as u64;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        bitrate.mark_congested();
+                        missed_packets += n;
+                        session_stats.stats.lag_events += 1;
+                        session_stats.stats.missed_packets += n;
+                        warn!("Audio receiver for client {} lagged, {} messages missed. Stream may be distorted.", id, n);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// Loads the certificate files into a server config, printing the
+/// certificate's hash for clients to trust.
+async fn load_server_config(config: &Config) -> Result<wtransport::ServerConfig> {
+    let identity = wtransport::Identity::load_pemfiles(&config.cert, &config.key).await?;
+    println!(
+        "{}",
+        identity.certificate_chain().as_slice()[0]
+            .hash()
+            .fmt(wtransport::tls::Sha256DigestFmt::BytesArray),
+    );
+    Ok(wtransport::ServerConfig::builder()
+        .with_bind_default(config.webtransport_port)
+        .with_identity(identity)
+        .keep_alive_interval(Some(Duration::from_secs(3)))
+        .build())
+}
+
+pub fn spawn_webtransport_task(
+    config: Arc<Config>,
+    sinks: Vec<SinkPackets>,
+    control: ControlBus,
+    registry: SessionRegistry,
+    mic: Option<BoundedSender<Vec<u8>>>,
+    health: Health,
+) -> JoinHandle<()> {
+    let sinks = Arc::new(sinks);
+    let limits = SessionLimits::new(
+        config.max_sessions as usize,
+        config.max_sessions_per_ip as usize,
+        config.max_connects_per_minute as usize,
+    );
+    tokio::spawn(async move {
+        let server_config = load_server_config(&config).await.unwrap();
+
+        let server = wtransport::Endpoint::server(server_config).unwrap();
+        health.set_webtransport_listening(true);
+        let mut certificate = control.subscribe_certificate();
+        loop {
+            let incoming_session = tokio::select! {
+                incoming_session = server.accept() => incoming_session,
+                Ok(()) = certificate.changed() => {
+                    // Running sessions keep the certificate they started with.
+                    let reloaded = match load_server_config(&config).await {
+                        Ok(server_config) => server.reload_config(server_config, false).map_err(Into::into),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = reloaded {
+                        warn!("Couldn't reload the WebTransport certificate: {}", e);
+                    }
+                    continue;
+                }
+            };
+            // Refused before the handshake, so floods cost next to nothing.
+            let address = incoming_session.remote_address();
+            if !limits.allow_connect(address.ip(), Instant::now()) {
+                warn!("Refused {} for connecting too often", address);
+                incoming_session.refuse();
+                continue;
+            }
+            tokio::spawn(handle_connection(
+                config.clone(),
+                incoming_session,
+                sinks.clone(),
+                control.clone(),
+                limits.clone(),
+                registry.clone(),
+                mic.clone(),
+            ));
+        }
+    })
+}
@@ -0,0 +1,20 @@
+//! Control stream bytes, as a hostile or broken server could send them.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use streaming_protocol::{ControlMessage, next_control_message};
+
+fuzz_target!(|data: &[u8]| {
+    let mut pending = data.to_vec();
+    loop {
+        match next_control_message(&mut pending) {
+            Ok(Some(message)) => {
+                // Whatever parses reads back the same once encoded.
+                let line = message.encode();
+                assert_eq!(ControlMessage::decode(&line), Ok(message));
+            }
+            Ok(None) => break,
+            Err(_) => continue,
+        }
+    }
+});
@@ -0,0 +1,23 @@
+//! Media stream bytes, played as the clients play them short of the audio
+//! device, so a hostile server can't make them allocate without bound.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use streaming_protocol::{Codec, MAX_FRAME_SAMPLES, decode_uncompressed, next_packet};
+
+fuzz_target!(|data: &[u8]| {
+    let mut pending = data.to_vec();
+    while let Ok(Some((header, payload))) = next_packet(&mut pending) {
+        let Some((channels, samples)) = decode_uncompressed(header.codec, &payload) else {
+            continue;
+        };
+        assert!(channels > 0);
+        assert_eq!(samples.len() % channels as usize, 0);
+        let frames = samples.len() / channels as usize;
+        match header.codec {
+            Codec::Silence => assert!(frames <= MAX_FRAME_SAMPLES as usize),
+            // PCM samples come from the payload, which a u16 length bounds.
+            _ => assert!(samples.len() * 2 < payload.len()),
+        }
+    }
+});
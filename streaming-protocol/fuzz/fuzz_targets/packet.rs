@@ -0,0 +1,25 @@
+//! Media stream bytes and datagrams, as a hostile or broken server could send
+//! them.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use streaming_protocol::{HEADER_LEN, PacketHeader, next_packet, parse_packet};
+
+fuzz_target!(|data: &[u8]| {
+    // A datagram.
+    if let Ok(Some((header, payload))) = parse_packet(data) {
+        assert_eq!(payload.len(), header.payload_len as usize);
+        assert_eq!(PacketHeader::decode(&header.encode()), Ok(header));
+    }
+    // The media stream, read in chunks of any size.
+    let mut pending = Vec::new();
+    let mut read = 0;
+    for chunk in data.chunks(data.first().map_or(1, |&len| len as usize + 1)) {
+        pending.extend_from_slice(chunk);
+        while let Ok(Some((header, payload))) = next_packet(&mut pending) {
+            assert_eq!(payload.len(), header.payload_len as usize);
+            read += HEADER_LEN + payload.len();
+        }
+    }
+    assert!(read <= data.len());
+});
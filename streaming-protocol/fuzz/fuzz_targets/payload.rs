@@ -0,0 +1,20 @@
+//! Packet payloads, which the clients inspect before handing them to decoders.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use streaming_protocol::{
+    aac_frame_channels, flac_frame_channels, parse_pcm_payload, parse_silence_payload,
+    pcm_payload, silence_payload,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((channels, samples)) = parse_pcm_payload(data) {
+        assert_eq!(samples.len() % channels as usize, 0);
+        assert_eq!(pcm_payload(channels, &samples), data);
+    }
+    if let Some((channels, frames)) = parse_silence_payload(data) {
+        assert_eq!(silence_payload(channels, frames), data);
+    }
+    let _ = flac_frame_channels(data);
+    let _ = aac_frame_channels(data);
+});
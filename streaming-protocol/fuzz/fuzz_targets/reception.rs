@@ -0,0 +1,22 @@
+//! Sequence numbers and capture times from packet headers, which the clients
+//! track as they arrive.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use streaming_protocol::{DriftEstimator, ReceptionStats};
+
+fuzz_target!(|data: &[u8]| {
+    let mut reception = ReceptionStats::default();
+    let mut drift = DriftEstimator::default();
+    // Each packet is a sequence number, a capture time and an arrival time.
+    for packet in data.chunks_exact(20) {
+        let sequence = u32::from_be_bytes(packet[..4].try_into().unwrap());
+        let captured_at_us = u64::from_be_bytes(packet[4..12].try_into().unwrap());
+        let arrived_at_us = u64::from_be_bytes(packet[12..].try_into().unwrap());
+        reception.track(sequence, captured_at_us, arrived_at_us);
+        drift.observe(captured_at_us, arrived_at_us);
+    }
+    let report = reception.report(0.0);
+    assert!(report.packets_lost <= u32::MAX as u64 * report.packets_received);
+    let _ = drift.ratio();
+});
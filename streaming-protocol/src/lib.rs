@@ -0,0 +1,1333 @@
+//! Wire format and defaults shared by the server and the Rust clients.
+//!
+//! Media and microphone streams carry audio packets, each preceded by a fixed
+//! header, all fields big-endian:
+//!
+//! | bytes  | field                                          |
+//! |--------|------------------------------------------------|
+//! | 0..2   | magic, `PW`                                    |
+//! | 2      | protocol version                               |
+//! | 3      | codec of the payload, see `Codec`, and flags   |
+//! | 4..8   | sequence number (u32, wrapping)                |
+//! | 8..16  | capture time (u64 µs since the Unix epoch)     |
+//! | 16..18 | samples per channel in the frame (u16)         |
+//! | 18..20 | payload length (u16)                           |
+//!
+//! The top bit of the codec byte, `DECODER_RESET_FLAG`, marks packets the
+//! client's decoder must start afresh at: the first one of a session, and
+//! the first one after the server restarted its encoder or switched the
+//! client to another sink or bitrate tier.
+//!
+//! Frames may last anything from 2.5 to 120 ms, so clients time them by their
+//! sample count rather than assuming a duration.
+//!
+//! The server opens two unidirectional streams per session, each starting with
+//! a byte saying what it carries: `STREAM_CONTROL` or `STREAM_MEDIA`. The
+//! control stream carries newline-delimited JSON `ControlMessage`s, starting
+//! with a `ControlMessage::StreamInfo` describing the audio, which clients
+//! read before configuring their decoders. Audio packets follow on the media
+//! stream, or arrive as datagrams, one packet per datagram. Clients ask for
+//! datagrams with `DATAGRAM_QUERY`; the server falls back to the stream when
+//! the connection doesn't support them. Clients list the codecs they can
+//! decode with `codecs_query`, and the server streams the first of its codecs
+//! among them. Frames of digital silence may be sent as `Codec::Silence`
+//! packets in any stream, whatever its codec. Opus streams of 5.1 sinks may
+//! offer the six channels as `Codec::OpusSurround` packets, which clients
+//! that can play them ask for with `ClientCommand::SetSurround`.
+//!
+//! Sessions need a token from `session_token`, passed as `TOKEN_QUERY_KEY` in
+//! the session path's query. The server's HTTP API hands them out to clients
+//! that know its access key, and clients given the key can mint their own.
+//! Servers with a password also start the control stream with a
+//! `ControlMessage::PasswordChallenge`, and only send the stream info once the
+//! client answered it with a `ClientCommand::Authenticate` as the first
+//! command on its first bidirectional stream, closing the session with
+//! `CLOSE_WRONG_PASSWORD` otherwise.
+//!
+//! Clients open bidirectional streams the same way: `STREAM_CONTROL` streams
+//! carry newline-delimited JSON `ClientCommand`s, which the server answers on
+//! its control stream, and `STREAM_MIC` streams carry microphone packets.
+//! Clients send a `ClientCommand::ReceiverReport` every
+//! `RECEIVER_REPORT_INTERVAL`, telling the server how the stream reaches them.
+//! Datagram clients may ask for lost packets again with a `ClientCommand::Nack`.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+/// Default Opus sample rate of the stream.
+pub const SAMPLE_RATE: u32 = 48_000;
+/// Default duration of one frame in milliseconds.
+pub const FRAME_MS: u32 = 10;
+/// Shortest and longest frames, which are also Opus' limits. Decode buffers
+/// are sized for the longest.
+pub const MIN_FRAME_MS: f32 = 2.5;
+pub const MAX_FRAME_MS: u32 = 120;
+/// Highest sample rate the server streams at.
+pub const MAX_SAMPLE_RATE: u32 = 192_000;
+/// Samples per channel in the longest frame at the highest rate, which no
+/// packet exceeds.
+pub const MAX_FRAME_SAMPLES: u32 = MAX_SAMPLE_RATE / 1000 * MAX_FRAME_MS;
+/// Samples per channel in one AAC frame, whatever the frame duration.
+pub const AAC_FRAME_SAMPLES: u32 = 1024;
+/// Default UDP port of the WebTransport server.
+pub const WEBTRANSPORT_PORT: u16 = 13345;
+/// Default TCP port of the HTTPS server.
+pub const HTTP_PORT: u16 = 13346;
+
+/// mDNS service types the server advertises its WebTransport and HTTPS
+/// endpoints under.
+pub const MDNS_SERVICE_TYPE: &str = "_pipewire-stream._udp.local.";
+pub const MDNS_HTTPS_SERVICE_TYPE: &str = "_https._tcp.local.";
+/// TXT properties of the WebTransport service: the protocol version, the
+/// HTTPS port and the comma-separated stream ids of the sinks.
+pub const MDNS_VERSION_KEY: &str = "version";
+pub const MDNS_HTTP_PORT_KEY: &str = "http_port";
+pub const MDNS_SINKS_KEY: &str = "sinks";
+
+/// Query parameter of the session path, e.g. `/?transport=datagram`, asking for
+/// audio packets as datagrams.
+pub const DATAGRAM_QUERY: &str = "transport=datagram";
+/// First byte of each stream, saying what it carries. The server opens a
+/// control and a media stream, clients control and microphone streams.
+pub const STREAM_CONTROL: u8 = 0;
+pub const STREAM_MEDIA: u8 = 1;
+pub const STREAM_MIC: u8 = 2;
+/// Key of the session path's query parameter listing the codecs a client can
+/// decode, e.g. `/?codecs=opus,flac`. Without it any codec may be sent.
+pub const CODECS_QUERY_KEY: &str = "codecs";
+/// Key of the session path's query parameter holding the session token.
+pub const TOKEN_QUERY_KEY: &str = "token";
+/// How long session tokens are valid for, in seconds. Only the start of a
+/// session needs one.
+pub const TOKEN_LIFETIME_SECS: u64 = 60;
+/// Codes the server closes sessions with when it has no room for more, or
+/// the client's address has too many, with a reason in the close message.
+pub const CLOSE_SERVER_FULL: u32 = 0x100;
+pub const CLOSE_TOO_MANY_SESSIONS: u32 = 0x101;
+/// Code the server closes a session with when an operator kicked it.
+pub const CLOSE_KICKED: u32 = 0x102;
+/// Code the server closes a session with when the client didn't prove it
+/// knows the password.
+pub const CLOSE_WRONG_PASSWORD: u32 = 0x103;
+/// Bounds of the target latency clients may ask for, in milliseconds.
+pub const MIN_TARGET_LATENCY_MS: u32 = 10;
+pub const MAX_TARGET_LATENCY_MS: u32 = 1000;
+
+/// How often clients send a `ClientCommand::ReceiverReport`.
+pub const RECEIVER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Most packets one `ClientCommand::Nack` gets resent.
+pub const MAX_NACK_SEQUENCES: usize = 32;
+
+pub const MAGIC: [u8; 2] = *b"PW";
+pub const VERSION: u8 = 5;
+pub const HEADER_LEN: usize = 20;
+/// Set in the codec byte of packets encoded afresh, see `PacketHeader`.
+pub const DECODER_RESET_FLAG: u8 = 0x80;
+
+/// Channels `Codec::OpusSurround` packets decode to, in PipeWire's 5.1 order:
+/// front left, front right, centre, LFE, side left and side right.
+pub const SURROUND_CHANNELS: u8 = 6;
+/// Opus multistream layout of `Codec::OpusSurround` packets, for decoders to
+/// be created with. The front and side pairs are coupled streams, centre and
+/// LFE mono ones, and the mapping gives each channel's decoded channel.
+pub const SURROUND_STREAMS: u8 = 4;
+pub const SURROUND_COUPLED_STREAMS: u8 = 2;
+pub const SURROUND_MAPPING: [u8; SURROUND_CHANNELS as usize] = [0, 1, 4, 5, 2, 3];
+
+/// What a packet's payload is encoded with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Codec {
+    Opus = 0,
+    /// One FLAC frame of 16-bit samples, without the stream header. See
+    /// `flac_stream_header` for decoders that need one.
+    Flac = 1,
+    /// Raw s16le samples, interleaved and preceded by their channel count. See
+    /// `pcm_payload`.
+    Pcm = 2,
+    /// One raw AAC-LC frame of `AAC_FRAME_SAMPLES`, without an ADTS header. See
+    /// `aac_audio_specific_config` for the decoder configuration.
+    Aac = 3,
+    /// A frame of silence in place of one of the stream's codec, so clients
+    /// keep time without decoding anything. Never a stream's codec itself. See
+    /// `silence_payload`.
+    Silence = 4,
+    /// One Opus multistream packet of the sink's six 5.1 channels, laid out as
+    /// `SURROUND_MAPPING` says. Sent in place of `Codec::Opus` packets to
+    /// clients that asked with `ClientCommand::SetSurround`, never a stream's
+    /// codec itself.
+    OpusSurround = 5,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        match value {
+            0 => Ok(Codec::Opus),
+            1 => Ok(Codec::Flac),
+            2 => Ok(Codec::Pcm),
+            3 => Ok(Codec::Aac),
+            4 => Ok(Codec::Silence),
+            5 => Ok(Codec::OpusSurround),
+            other => Err(ProtocolError::UnknownCodec(other)),
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "opus" => Ok(Codec::Opus),
+            "flac" => Ok(Codec::Flac),
+            "pcm" => Ok(Codec::Pcm),
+            "aac" => Ok(Codec::Aac),
+            _ => Err(format!(
+                "Unknown codec {s:?}, expected opus, flac, pcm or aac"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Opus => "opus",
+            Codec::Flac => "flac",
+            Codec::Pcm => "pcm",
+            Codec::Aac => "aac",
+            Codec::Silence => "silence",
+            Codec::OpusSurround => "opus surround",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub codec: Codec,
+    pub sequence: u32,
+    pub captured_at_us: u64,
+    /// Samples per channel the payload decodes to.
+    pub frame_samples: u16,
+    pub payload_len: u16,
+    /// The encoder started afresh at this packet, so decoding from the state
+    /// earlier packets left would produce artifacts. Decoders are reset
+    /// before decoding it.
+    pub decoder_reset: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    BadMagic([u8; 2]),
+    UnsupportedVersion(u8),
+    UnknownCodec(u8),
+    BadControlMessage(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic(magic) => write!(f, "bad packet magic {:?}", magic),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            ProtocolError::UnknownCodec(codec) => write!(f, "unknown codec {}", codec),
+            ProtocolError::BadControlMessage(e) => write!(f, "bad control message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl PacketHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2] = VERSION;
+        bytes[3] = self.codec as u8
+            | if self.decoder_reset {
+                DECODER_RESET_FLAG
+            } else {
+                0
+            };
+        bytes[4..8].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.captured_at_us.to_be_bytes());
+        bytes[16..18].copy_from_slice(&self.frame_samples.to_be_bytes());
+        bytes[18..20].copy_from_slice(&self.payload_len.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Result<Self, ProtocolError> {
+        let magic = [bytes[0], bytes[1]];
+        if magic != MAGIC {
+            return Err(ProtocolError::BadMagic(magic));
+        }
+        if bytes[2] != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(bytes[2]));
+        }
+        Ok(Self {
+            codec: Codec::try_from(bytes[3] & !DECODER_RESET_FLAG)?,
+            sequence: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            captured_at_us: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            frame_samples: u16::from_be_bytes(bytes[16..18].try_into().unwrap()),
+            payload_len: u16::from_be_bytes(bytes[18..20].try_into().unwrap()),
+            decoder_reset: bytes[3] & DECODER_RESET_FLAG != 0,
+        })
+    }
+
+    /// How long the packet's frame plays at `sample_rate`, in microseconds.
+    pub fn frame_duration_us(&self, sample_rate: u32) -> f64 {
+        self.frame_samples as f64 * 1_000_000.0 / sample_rate as f64
+    }
+}
+
+/// Returns `payload`, a frame of `frame_samples` per channel, preceded by its
+/// header.
+pub fn frame(
+    codec: Codec,
+    sequence: u32,
+    captured_at_us: u64,
+    frame_samples: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame_into(
+        &mut framed,
+        codec,
+        sequence,
+        captured_at_us,
+        frame_samples,
+        false,
+        payload,
+    );
+    framed
+}
+
+/// Like `frame`, but replaces the contents of `framed`, so senders can reuse
+/// one buffer for every packet, and marks the packet with `decoder_reset`.
+/// Panics if the payload is longer than `u16::MAX` bytes.
+pub fn frame_into(
+    framed: &mut Vec<u8>,
+    codec: Codec,
+    sequence: u32,
+    captured_at_us: u64,
+    frame_samples: u16,
+    decoder_reset: bool,
+    payload: &[u8],
+) {
+    let header = PacketHeader {
+        codec,
+        sequence,
+        captured_at_us,
+        frame_samples,
+        payload_len: u16::try_from(payload.len()).expect("Payloads fit in the packet header"),
+        decoder_reset,
+    };
+    framed.clear();
+    framed.extend_from_slice(&header.encode());
+    framed.extend_from_slice(payload);
+}
+
+/// Reads the packet at the start of `bytes`, returning its header and payload,
+/// or `None` if the packet isn't complete yet. The packet spans `HEADER_LEN`
+/// plus the payload's length.
+pub fn parse_packet(bytes: &[u8]) -> Result<Option<(PacketHeader, &[u8])>, ProtocolError> {
+    let Some((header, rest)) = bytes.split_first_chunk::<HEADER_LEN>() else {
+        return Ok(None);
+    };
+    let header = PacketHeader::decode(header)?;
+    Ok(rest
+        .get(..header.payload_len as usize)
+        .map(|payload| (header, payload)))
+}
+
+/// Removes the first complete packet from `pending`, the bytes of the media
+/// stream read so far, returning its header and payload.
+pub fn next_packet(
+    pending: &mut Vec<u8>,
+) -> Result<Option<(PacketHeader, Vec<u8>)>, ProtocolError> {
+    let Some((header, payload)) = parse_packet(pending)? else {
+        return Ok(None);
+    };
+    let payload = payload.to_vec();
+    pending.drain(..HEADER_LEN + payload.len());
+    Ok(Some((header, payload)))
+}
+
+/// A message on the control stream.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// The first message, after the password challenge if there is one:
+    /// what the media stream carries.
+    StreamInfo {
+        sink: String,
+        codec: Codec,
+        sample_rate: u32,
+        channels: u8,
+        /// Samples per channel in a frame, though packets carry their own.
+        frame_samples: u16,
+        /// Whether audio packets arrive as datagrams rather than on the media
+        /// stream.
+        datagrams: bool,
+        /// Whether the sink's 5.1 channels can be had with
+        /// `ClientCommand::SetSurround`.
+        surround: bool,
+    },
+    /// Whether the server is paused, sent after the stream info and on every
+    /// change.
+    State { paused: bool },
+    /// Whether the sink has been silent for `--idle-after-secs`, so no audio
+    /// packets are sent until it plays again. Sent on every change, and after
+    /// the stream info while the sink is idle.
+    Idle { idle: bool },
+    /// How the client's stream is doing, sent every second.
+    Stats {
+        /// Bitrate tier, 0 being the highest.
+        tier: usize,
+        rtt_ms: f64,
+        /// Packets dropped for falling behind since the last stats.
+        missed_packets: u64,
+    },
+    /// The server renewed its certificate. The session goes on, but clients
+    /// that trust the server by its certificate hash need the new one to
+    /// reconnect.
+    CertificateRenewed,
+    /// Answers `ClientCommand::SetTargetLatency` with the latency granted,
+    /// within `MIN_TARGET_LATENCY_MS` and `MAX_TARGET_LATENCY_MS`.
+    TargetLatency { ms: u32 },
+    /// What the sink plays, as the media player playing reports over MPRIS.
+    /// Sent after the stream info once known, and on every change.
+    NowPlaying(NowPlaying),
+    /// Sent first by servers with a password, which wait for the client's
+    /// `ClientCommand::Authenticate` before the stream info. The nonce is
+    /// random hex, fresh for every session.
+    PasswordChallenge { nonce: String },
+}
+
+/// The track playing, both fields `None` when no player is playing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NowPlaying {
+    pub title: Option<String>,
+    /// The artists, comma-separated.
+    pub artist: Option<String>,
+}
+
+/// A command on a client's control stream, applying to its session only
+/// unless noted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    /// Stop sending audio to this client, leaving everyone else's alone.
+    Pause,
+    Resume,
+    /// Sets the volume the server encodes at, in percent. Applies to every
+    /// client, since they share the encoded audio.
+    SetVolume {
+        percent: u8,
+    },
+    /// Skip to the newest audio and resend the stream info, e.g. after the
+    /// client's decoder failed. Every frame is a keyframe in these codecs, so
+    /// decoding starts over right away.
+    Restart,
+    /// Switch to another of the server's sinks, answered with its stream info.
+    SelectSource {
+        sink: String,
+    },
+    /// Asks to play this far behind capture, e.g. 30 ms on a wired desktop
+    /// or 150 ms on flaky Wi-Fi. See `jitter_buffer_ms`.
+    SetTargetLatency {
+        ms: u32,
+    },
+    /// Pins the client to a bitrate tier, 0 being the highest, e.g. a low one
+    /// for phones on mobile data. `None` hands the choice back to the server,
+    /// which moves congested clients down. Stats report the tier in use.
+    SelectTier {
+        tier: Option<usize>,
+    },
+    /// How the stream reaches the client, sent every
+    /// `RECEIVER_REPORT_INTERVAL`.
+    ReceiverReport(ReceiverReport),
+    /// Asks for `Codec::OpusSurround` packets in place of the downmixed Opus
+    /// ones, if the stream info offers them. They're sent while the client's
+    /// link keeps up with the highest bitrate tier, the downmix otherwise.
+    SetSurround {
+        enabled: bool,
+    },
+    /// Answers `ControlMessage::PasswordChallenge` with `password_proof`.
+    /// Ignored once the session started.
+    Authenticate {
+        proof: String,
+    },
+    /// Asks for the packets with these sequence numbers again, lost on their
+    /// way as datagrams. The server resends the first `MAX_NACK_SEQUENCES`
+    /// of them it still has as datagrams, unchanged but for the bitrate tier,
+    /// for clients holding packets back to slot them in. Ignored by sessions
+    /// on the media stream, which loses nothing.
+    Nack {
+        sequences: Vec<u32>,
+    },
+}
+
+/// A client's view of its stream, like an RTCP receiver report. Counts run
+/// from the start of the session.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReceiverReport {
+    pub packets_received: u64,
+    /// Packets that never arrived, or arrived too late to play.
+    pub packets_lost: u64,
+    /// Interarrival jitter, see `ReceptionStats`.
+    pub jitter_ms: f64,
+    /// Audio queued for playback.
+    pub buffer_ms: f64,
+}
+
+/// One line of JSON, as messages and commands are sent on control streams.
+fn encode_line(message: &impl Serialize) -> Vec<u8> {
+    let mut line = serde_json::to_vec(message).expect("Control messages serialize");
+    line.push(b'\n');
+    line
+}
+
+fn decode_line<T: serde::de::DeserializeOwned>(line: &[u8]) -> Result<T, ProtocolError> {
+    serde_json::from_slice(line).map_err(|e| ProtocolError::BadControlMessage(e.to_string()))
+}
+
+impl ControlMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        encode_line(self)
+    }
+
+    pub fn decode(line: &[u8]) -> Result<Self, ProtocolError> {
+        decode_line(line)
+    }
+}
+
+impl ClientCommand {
+    pub fn encode(&self) -> Vec<u8> {
+        encode_line(self)
+    }
+
+    pub fn decode(line: &[u8]) -> Result<Self, ProtocolError> {
+        decode_line(line)
+    }
+}
+
+/// How much audio a client buffers to play `target_ms` behind capture: the
+/// target less the network's one-way delay, taken as half the round trip, but
+/// never less than a frame.
+pub fn jitter_buffer_ms(target_ms: u32, rtt_ms: f64) -> u32 {
+    (target_ms as f64 - rtt_ms / 2.0)
+        .max(FRAME_MS as f64)
+        .round() as u32
+}
+
+/// Removes the first complete message from `pending`, the bytes of the control
+/// stream read so far.
+pub fn next_control_message(
+    pending: &mut Vec<u8>,
+) -> Result<Option<ControlMessage>, ProtocolError> {
+    let Some(end) = pending.iter().position(|&byte| byte == b'\n') else {
+        return Ok(None);
+    };
+    let line: Vec<u8> = pending.drain(..=end).collect();
+    ControlMessage::decode(&line).map(Some)
+}
+
+/// A FLAC stream header (the `fLaC` marker and a STREAMINFO block) for frames
+/// of 16-bit audio with the given rate and channel count, which FLAC decoders
+/// need before the first frame. Returns `None` for rates and channel counts
+/// FLAC can't hold: up to 655350 Hz and 1 to 8 channels.
+pub fn flac_stream_header(sample_rate: u32, channels: u8) -> Option<Vec<u8>> {
+    if !(1..=655_350).contains(&sample_rate) || !(1..=8).contains(&channels) {
+        return None;
+    }
+    let mut header = b"fLaC".to_vec();
+    // Last metadata block, type STREAMINFO, 34 bytes long.
+    header.extend_from_slice(&[0x80, 0, 0, 34]);
+    // Any block size FLAC allows, and unknown frame sizes.
+    header.extend_from_slice(&16u16.to_be_bytes());
+    header.extend_from_slice(&u16::MAX.to_be_bytes());
+    header.extend_from_slice(&[0; 6]);
+    // 20 bits of sample rate, 3 of channels - 1, 5 of bits per sample - 1 and
+    // 36 of total samples, left unknown.
+    let packed = ((sample_rate as u64) << 44) | ((channels as u64 - 1) << 41) | ((16 - 1) << 36);
+    header.extend_from_slice(&packed.to_be_bytes());
+    // Unknown MD5 of the audio.
+    header.extend_from_slice(&[0; 16]);
+    Some(header)
+}
+
+/// Channel count of a FLAC frame, read from its header.
+pub fn flac_frame_channels(frame: &[u8]) -> Option<u8> {
+    match frame.get(3)? >> 4 {
+        // Independent channels.
+        assignment @ 0..=7 => Some(assignment + 1),
+        // Left/side, right/side or mid/side stereo.
+        8..=10 => Some(2),
+        _ => None,
+    }
+}
+
+/// The payload of a PCM packet holding `samples`, interleaved across `channels`.
+pub fn pcm_payload(channels: u8, samples: &[i16]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + samples.len() * 2);
+    payload.push(channels);
+    for sample in samples {
+        payload.extend_from_slice(&sample.to_le_bytes());
+    }
+    payload
+}
+
+/// Channel count and interleaved samples of a PCM packet's payload.
+pub fn parse_pcm_payload(payload: &[u8]) -> Option<(u8, Vec<i16>)> {
+    let (&channels, samples) = payload.split_first()?;
+    if channels == 0 || samples.len() % (2 * channels as usize) != 0 {
+        return None;
+    }
+    let samples = samples
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect();
+    Some((channels, samples))
+}
+
+/// The payload of a silence packet standing in for `frames` samples per
+/// channel of `channels` channels.
+pub fn silence_payload(channels: u8, frames: u32) -> [u8; 5] {
+    let mut payload = [0; 5];
+    payload[0] = channels;
+    payload[1..].copy_from_slice(&frames.to_be_bytes());
+    payload
+}
+
+/// Channel count and samples per channel a silence packet stands in for, at
+/// most `MAX_FRAME_SAMPLES`.
+pub fn parse_silence_payload(payload: &[u8]) -> Option<(u8, u32)> {
+    let (&channels, frames) = payload.split_first()?;
+    let frames = u32::from_be_bytes(frames.try_into().ok()?);
+    if channels == 0 || frames > MAX_FRAME_SAMPLES {
+        return None;
+    }
+    Some((channels, frames))
+}
+
+/// Channel count and interleaved samples of a PCM or silence packet, which
+/// clients play without a decoder. `None` for other codecs and malformed
+/// payloads.
+pub fn decode_uncompressed(codec: Codec, payload: &[u8]) -> Option<(u8, Vec<i16>)> {
+    match codec {
+        Codec::Pcm => parse_pcm_payload(payload),
+        Codec::Silence => parse_silence_payload(payload)
+            .map(|(channels, frames)| (channels, vec![0; frames as usize * channels as usize])),
+        _ => None,
+    }
+}
+
+/// The AudioSpecificConfig describing AAC-LC frames with the given rate and
+/// channel count, which decoders need to be configured with. Returns `None`
+/// for rates AAC has no index for.
+pub fn aac_audio_specific_config(sample_rate: u32, channels: u8) -> Option<[u8; 2]> {
+    const RATES: [u32; 13] = [
+        96_000, 88_200, 64_000, 48_000, 44_100, 32_000, 24_000, 22_050, 16_000, 12_000, 11_025,
+        8_000, 7_350,
+    ];
+    let rate_index = RATES.iter().position(|&rate| rate == sample_rate)? as u16;
+    // 5 bits of object type (2, low complexity), 4 of rate index, 4 of
+    // channel configuration, then 3 zero bits of GASpecificConfig.
+    let packed = (2 << 11) | (rate_index << 7) | ((channels as u16 & 0xF) << 3);
+    Some(packed.to_be_bytes())
+}
+
+/// Channel count of a raw AAC frame, read from the id of its first element.
+pub fn aac_frame_channels(frame: &[u8]) -> Option<u8> {
+    match frame.first()? >> 5 {
+        // Single channel element.
+        0 => Some(1),
+        // Channel pair element.
+        1 => Some(2),
+        _ => None,
+    }
+}
+
+/// The query parameter telling the server which of `codecs` a client can
+/// decode.
+pub fn codecs_query(codecs: &[Codec]) -> String {
+    let names: Vec<String> = codecs.iter().map(Codec::to_string).collect();
+    format!("{}={}", CODECS_QUERY_KEY, names.join(","))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn token_mac(key: &str, expires_at_secs: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes any key");
+    mac.update(expires_at_secs.to_string().as_bytes());
+    mac
+}
+
+/// A token for sessions starting until `expires_at_secs` (since the Unix
+/// epoch): the expiry and its HMAC-SHA256 under the server's access key, in
+/// hex, e.g. `1700000060.3f9a…`.
+pub fn session_token(key: &str, expires_at_secs: u64) -> String {
+    let tag = token_mac(key, expires_at_secs).finalize().into_bytes();
+    format!("{}.{}", expires_at_secs, to_hex(&tag))
+}
+
+/// Whether `token` was signed with `key` and is still valid at `now_secs`.
+pub fn verify_session_token(key: &str, token: &str, now_secs: u64) -> bool {
+    let Some((expires_at, hex)) = token.split_once('.') else {
+        return false;
+    };
+    let Ok(expires_at_secs) = expires_at.parse::<u64>() else {
+        return false;
+    };
+    if expires_at_secs < now_secs {
+        return false;
+    }
+    let Some(tag) = from_hex(hex) else {
+        return false;
+    };
+    token_mac(key, expires_at_secs).verify_slice(&tag).is_ok()
+}
+
+/// A fresh nonce for `ControlMessage::PasswordChallenge`.
+pub fn password_nonce(random: [u8; 16]) -> String {
+    to_hex(&random)
+}
+
+fn password_mac(password: &str, nonce: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(password.as_bytes()).expect("HMAC takes any key");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+/// Proves the client knows `password` without sending it: the HMAC-SHA256 of
+/// the server's `nonce` under the password, in hex. The nonce differing per
+/// session, proofs can't be replayed.
+pub fn password_proof(password: &str, nonce: &str) -> String {
+    to_hex(&password_mac(password, nonce).finalize().into_bytes())
+}
+
+/// Whether `proof` answers the challenge with `nonce` for `password`.
+pub fn verify_password_proof(password: &str, nonce: &str, proof: &str) -> bool {
+    from_hex(proof).is_some_and(|tag| password_mac(password, nonce).verify_slice(&tag).is_ok())
+}
+
+/// Where a packet falls relative to the ones received before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceEvent {
+    InOrder,
+    /// The packet is in order, but this many packets before it never arrived.
+    Gap(u32),
+    /// The packet is older than one already received, so it should be dropped.
+    Late,
+}
+
+#[derive(Default)]
+pub struct SequenceTracker {
+    expected: Option<u32>,
+}
+
+impl SequenceTracker {
+    pub fn track(&mut self, sequence: u32) -> SequenceEvent {
+        let Some(expected) = self.expected else {
+            self.expected = Some(sequence.wrapping_add(1));
+            return SequenceEvent::InOrder;
+        };
+        // Interpreted as signed so sequence numbers can wrap around.
+        let offset = sequence.wrapping_sub(expected) as i32;
+        if offset < 0 {
+            return SequenceEvent::Late;
+        }
+        self.expected = Some(sequence.wrapping_add(1));
+        if offset == 0 {
+            SequenceEvent::InOrder
+        } else {
+            SequenceEvent::Gap(offset as u32)
+        }
+    }
+}
+
+/// Counts the packets of a stream as they arrive, for `ReceiverReport`s.
+#[derive(Default)]
+pub struct ReceptionStats {
+    sequence: SequenceTracker,
+    received: u64,
+    lost: u64,
+    /// Interarrival jitter as RTCP estimates it (RFC 3550): the average change
+    /// in transit time from one packet to the next.
+    jitter_us: f64,
+    /// The last packet's arrival time less its capture time. The clocks needn't
+    /// agree, since only its changes count.
+    last_transit_us: Option<i64>,
+}
+
+impl ReceptionStats {
+    /// Counts a packet arriving at `arrived_at_us` on the client's clock.
+    pub fn track(
+        &mut self,
+        sequence: u32,
+        captured_at_us: u64,
+        arrived_at_us: u64,
+    ) -> SequenceEvent {
+        let event = self.sequence.track(sequence);
+        match event {
+            SequenceEvent::InOrder => {}
+            SequenceEvent::Gap(missed) => self.lost += missed as u64,
+            SequenceEvent::Late => return event,
+        }
+        self.received += 1;
+        // Wrapping, since a broken server may send any capture time.
+        let transit_us = arrived_at_us.wrapping_sub(captured_at_us) as i64;
+        if let Some(last_transit_us) = self.last_transit_us {
+            let change = transit_us.wrapping_sub(last_transit_us).unsigned_abs() as f64;
+            self.jitter_us += (change - self.jitter_us) / 16.0;
+        }
+        self.last_transit_us = Some(transit_us);
+        event
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    pub fn report(&self, buffer_ms: f64) -> ReceiverReport {
+        ReceiverReport {
+            packets_received: self.received,
+            packets_lost: self.lost,
+            jitter_ms: self.jitter_us / 1000.0,
+            buffer_ms,
+        }
+    }
+}
+
+/// Server time over which the lowest clock offset is kept. Network jitter only
+/// ever delays packets, so the lowest offset is the least disturbed.
+const DRIFT_BUCKET_US: u64 = 1_000_000;
+/// Buckets the drift is fitted over, and the fewest it takes.
+const DRIFT_BUCKETS: usize = 60;
+const MIN_DRIFT_BUCKETS: usize = 10;
+/// Drift beyond this is taken for a glitch rather than a clock running off.
+const MAX_DRIFT: f64 = 0.002;
+/// Offsets changing by more than this mean a clock was set, so the estimate
+/// starts over.
+const MAX_OFFSET_JUMP_US: f64 = 500_000.0;
+
+/// Estimates how fast a client's playback clock runs against the server's
+/// capture clock, from the capture times of packets and the playback clock's
+/// reading as each arrives.
+pub struct DriftEstimator {
+    /// Server time of each completed bucket, and the lowest offset seen in it.
+    buckets: VecDeque<(u64, f64)>,
+    /// Index of the bucket being filled, and its lowest offset so far.
+    current: Option<(u64, f64)>,
+    ratio: f64,
+}
+
+impl Default for DriftEstimator {
+    fn default() -> Self {
+        Self {
+            buckets: VecDeque::new(),
+            current: None,
+            ratio: 1.0,
+        }
+    }
+}
+
+impl DriftEstimator {
+    /// Records a packet captured at `server_us` arriving at `client_us` on the
+    /// playback clock, both in microseconds.
+    pub fn observe(&mut self, server_us: u64, client_us: u64) {
+        let offset = client_us as f64 - server_us as f64;
+        let bucket = server_us / DRIFT_BUCKET_US;
+        match self.current {
+            Some((index, lowest)) if index == bucket => {
+                self.current = Some((index, lowest.min(offset)));
+            }
+            Some((index, lowest)) if index < bucket => {
+                self.current = Some((bucket, offset));
+                self.complete_bucket(index * DRIFT_BUCKET_US, lowest);
+            }
+            // The first packet, or the server's clock went back.
+            _ => {
+                self.buckets.clear();
+                self.current = Some((bucket, offset));
+            }
+        }
+    }
+
+    fn complete_bucket(&mut self, server_us: u64, offset: f64) {
+        if self
+            .buckets
+            .back()
+            .is_some_and(|&(_, last)| (offset - last).abs() > MAX_OFFSET_JUMP_US)
+        {
+            self.buckets.clear();
+            self.ratio = 1.0;
+        }
+        self.buckets.push_back((server_us, offset));
+        if self.buckets.len() > DRIFT_BUCKETS {
+            self.buckets.pop_front();
+        }
+        if self.buckets.len() < MIN_DRIFT_BUCKETS {
+            return;
+        }
+        // Least squares slope of the offset over server time, counted from
+        // the first bucket to keep the precision.
+        let origin = self.buckets[0].0;
+        let points: Vec<(f64, f64)> = self
+            .buckets
+            .iter()
+            .map(|&(server_us, offset)| ((server_us - origin) as f64, offset))
+            .collect();
+        let count = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+        let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+            (
+                cov + (x - mean_x) * (y - mean_y),
+                var + (x - mean_x) * (x - mean_x),
+            )
+        });
+        self.ratio = 1.0 + (covariance / variance).clamp(-MAX_DRIFT, MAX_DRIFT);
+    }
+
+    /// Playback clock time passing per unit of server time, above 1 when the
+    /// playback clock runs fast. Playing at `1 / ratio` of the nominal rate
+    /// keeps up with the server. 1 until enough packets were seen.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+}
+
+const FIRST_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Delays between a client's reconnection attempts, doubling after every
+/// failed one.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            next: FIRST_RECONNECT_DELAY,
+        }
+    }
+}
+
+impl Backoff {
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+
+    /// Starts over after a connection that worked.
+    pub fn reset(&mut self) {
+        self.next = FIRST_RECONNECT_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = PacketHeader {
+            codec: Codec::Flac,
+            sequence: 0xdead_beef,
+            captured_at_us: 1_700_000_000_000_000,
+            frame_samples: 5760,
+            payload_len: 321,
+            decoder_reset: false,
+        };
+        assert_eq!(PacketHeader::decode(&header.encode()), Ok(header));
+        let reset = PacketHeader {
+            decoder_reset: true,
+            ..header
+        };
+        assert_eq!(reset.encode()[3], Codec::Flac as u8 | DECODER_RESET_FLAG);
+        assert_eq!(PacketHeader::decode(&reset.encode()), Ok(reset));
+        assert_eq!(header.frame_duration_us(48_000), 120_000.0);
+        let short = PacketHeader {
+            frame_samples: 120,
+            ..header
+        };
+        assert_eq!(short.frame_duration_us(48_000), 2_500.0);
+    }
+
+    #[test]
+    fn rejects_foreign_headers() {
+        let mut bytes = PacketHeader {
+            codec: Codec::Opus,
+            sequence: 0,
+            captured_at_us: 0,
+            frame_samples: 480,
+            payload_len: 0,
+            decoder_reset: false,
+        }
+        .encode();
+        bytes[3] = 9;
+        assert_eq!(
+            PacketHeader::decode(&bytes),
+            Err(ProtocolError::UnknownCodec(9))
+        );
+        bytes[2] = VERSION + 1;
+        assert_eq!(
+            PacketHeader::decode(&bytes),
+            Err(ProtocolError::UnsupportedVersion(VERSION + 1))
+        );
+        bytes[0] = b'X';
+        assert_eq!(
+            PacketHeader::decode(&bytes),
+            Err(ProtocolError::BadMagic([b'X', b'W']))
+        );
+    }
+
+    #[test]
+    fn parses_packets_as_they_complete() {
+        let mut bytes = frame(Codec::Opus, 7, 42, 480, b"opus");
+        bytes.extend(frame(Codec::Opus, 8, 52, 960, b"more"));
+        assert_eq!(parse_packet(&bytes[..HEADER_LEN + 3]), Ok(None));
+        let (header, payload) = parse_packet(&bytes).unwrap().unwrap();
+        assert_eq!((header.sequence, header.captured_at_us), (7, 42));
+        assert_eq!(payload, b"opus");
+        let (header, payload) = parse_packet(&bytes[HEADER_LEN + 4..]).unwrap().unwrap();
+        assert_eq!((header.sequence, payload), (8, &b"more"[..]));
+        assert_eq!(header.frame_samples, 960);
+        let mut reused = b"stale".to_vec();
+        frame_into(&mut reused, Codec::Opus, 7, 42, 480, false, b"opus");
+        assert_eq!(reused, &bytes[..HEADER_LEN + 4]);
+
+        let mut pending = bytes[..HEADER_LEN + 6].to_vec();
+        let (header, payload) = next_packet(&mut pending).unwrap().unwrap();
+        assert_eq!((header.sequence, &payload[..]), (7, &b"opus"[..]));
+        assert_eq!(next_packet(&mut pending), Ok(None));
+        pending.extend(&bytes[HEADER_LEN + 6..]);
+        let (header, payload) = next_packet(&mut pending).unwrap().unwrap();
+        assert_eq!((header.sequence, &payload[..]), (8, &b"more"[..]));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn control_messages_round_trip_line_by_line() {
+        let info = ControlMessage::StreamInfo {
+            sink: "living-room".into(),
+            codec: Codec::Flac,
+            sample_rate: 96_000,
+            channels: 2,
+            frame_samples: 960,
+            datagrams: false,
+            surround: true,
+        };
+        let line = info.encode();
+        assert!(line.starts_with(br#"{"type":"stream_info","sink":"living-room","codec":"flac","#));
+        let mut pending = line.clone();
+        pending.extend(ControlMessage::State { paused: true }.encode());
+        pending.extend(ControlMessage::Idle { idle: false }.encode());
+        pending.extend(&line[..5]);
+        assert_eq!(next_control_message(&mut pending), Ok(Some(info)));
+        assert_eq!(
+            next_control_message(&mut pending),
+            Ok(Some(ControlMessage::State { paused: true }))
+        );
+        assert_eq!(
+            next_control_message(&mut pending),
+            Ok(Some(ControlMessage::Idle { idle: false }))
+        );
+        assert_eq!(next_control_message(&mut pending), Ok(None));
+        assert_eq!(pending, &line[..5]);
+        let now_playing = ControlMessage::NowPlaying(NowPlaying {
+            title: Some("Song".into()),
+            artist: None,
+        });
+        assert_eq!(
+            now_playing.encode(),
+            b"{\"type\":\"now_playing\",\"title\":\"Song\",\"artist\":null}\n"
+        );
+        assert_eq!(
+            ControlMessage::decode(&now_playing.encode()),
+            Ok(now_playing)
+        );
+        assert!(matches!(
+            ControlMessage::decode(br#"{"type":"shutdown"}"#),
+            Err(ProtocolError::BadControlMessage(_))
+        ));
+    }
+
+    #[test]
+    fn session_tokens_expire_and_resist_tampering() {
+        let token = session_token("secret", 1_000);
+        assert!(verify_session_token("secret", &token, 1_000));
+        assert!(!verify_session_token("secret", &token, 1_001));
+        assert!(!verify_session_token("other", &token, 1_000));
+        let extended = token.replacen("1000", "9000", 1);
+        assert!(!verify_session_token("secret", &extended, 1_000));
+        for malformed in ["", "1000", "1000.", "x.00", "1000.0g", "1000.é"] {
+            assert!(!verify_session_token("secret", malformed, 0), "{malformed}");
+        }
+    }
+
+    #[test]
+    fn password_proofs_only_answer_their_challenge() {
+        let nonce = password_nonce([7; 16]);
+        assert_eq!(nonce, "07".repeat(16));
+        let proof = password_proof("hunter2", &nonce);
+        assert!(verify_password_proof("hunter2", &nonce, &proof));
+        assert!(!verify_password_proof("hunter3", &nonce, &proof));
+        assert!(!verify_password_proof(
+            "hunter2",
+            &password_nonce([8; 16]),
+            &proof
+        ));
+        assert!(!verify_password_proof("hunter2", &nonce, &proof[2..]));
+        assert!(!verify_password_proof("hunter2", &nonce, ""));
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"authenticate","proof":"00"}"#),
+            Ok(ClientCommand::Authenticate { proof: "00".into() })
+        );
+    }
+
+    #[test]
+    fn client_commands_round_trip() {
+        let command = ClientCommand::SelectSource {
+            sink: "kitchen".into(),
+        };
+        assert_eq!(
+            command.encode(),
+            b"{\"type\":\"select_source\",\"sink\":\"kitchen\"}\n"
+        );
+        assert_eq!(ClientCommand::decode(&command.encode()), Ok(command));
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"set_volume","percent":40}"#),
+            Ok(ClientCommand::SetVolume { percent: 40 })
+        );
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"pause"}"#),
+            Ok(ClientCommand::Pause)
+        );
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"set_target_latency","ms":150}"#),
+            Ok(ClientCommand::SetTargetLatency { ms: 150 })
+        );
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"select_tier","tier":null}"#),
+            Ok(ClientCommand::SelectTier { tier: None })
+        );
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"set_surround","enabled":true}"#),
+            Ok(ClientCommand::SetSurround { enabled: true })
+        );
+        assert_eq!(
+            ClientCommand::decode(br#"{"type":"nack","sequences":[7,9]}"#),
+            Ok(ClientCommand::Nack {
+                sequences: vec![7, 9]
+            })
+        );
+    }
+
+    #[test]
+    fn receiver_reports_count_losses_and_jitter() {
+        let mut reception = ReceptionStats::default();
+        // Every packet takes 20 ms, give or take 4.
+        for (sequence, transit_us) in [(0, 20_000), (1, 24_000), (2, 16_000), (5, 20_000)] {
+            let captured_at_us = sequence as u64 * 10_000;
+            reception.track(sequence, captured_at_us, captured_at_us + transit_us);
+        }
+        assert_eq!(reception.track(3, 30_000, 70_000), SequenceEvent::Late);
+        let report = reception.report(40.0);
+        assert_eq!((report.packets_received, report.packets_lost), (4, 2));
+        assert!(report.jitter_ms > 0.0 && report.jitter_ms < 4.0);
+        assert_eq!(report.buffer_ms, 40.0);
+
+        let command = ClientCommand::ReceiverReport(report);
+        assert_eq!(ClientCommand::decode(&command.encode()), Ok(command));
+
+        // Capture times from a broken server don't overflow the transit times.
+        reception.track(6, u64::MAX / 2 + 1, 0);
+        reception.track(7, 0, u64::MAX);
+    }
+
+    #[test]
+    fn jitter_buffers_make_up_the_rest_of_the_target_latency() {
+        assert_eq!(jitter_buffer_ms(150, 40.0), 130);
+        assert_eq!(jitter_buffer_ms(30, 0.0), 30);
+        assert_eq!(jitter_buffer_ms(30, 100.0), FRAME_MS);
+    }
+
+    #[test]
+    fn pcm_payloads_round_trip() {
+        let samples = [0, -1, i16::MAX, i16::MIN];
+        let payload = pcm_payload(2, &samples);
+        assert_eq!(payload.len(), 9);
+        assert_eq!(parse_pcm_payload(&payload), Some((2, samples.to_vec())));
+        assert_eq!(parse_pcm_payload(&payload[..8]), None);
+        assert_eq!(parse_pcm_payload(&[]), None);
+    }
+
+    #[test]
+    fn silence_payloads_round_trip() {
+        let payload = silence_payload(2, 5760);
+        assert_eq!(parse_silence_payload(&payload), Some((2, 5760)));
+        assert_eq!(parse_silence_payload(&payload[..4]), None);
+        assert_eq!(parse_silence_payload(&silence_payload(0, 480)), None);
+        assert_eq!(Codec::try_from(Codec::Silence as u8), Ok(Codec::Silence));
+        // Only ever stands in for a stream's codec.
+        assert!("silence".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn silence_payloads_stand_in_for_one_frame_at_most() {
+        let longest = silence_payload(8, MAX_FRAME_SAMPLES);
+        assert_eq!(
+            parse_silence_payload(&longest),
+            Some((8, MAX_FRAME_SAMPLES))
+        );
+        let oversized = silence_payload(2, MAX_FRAME_SAMPLES + 1);
+        assert_eq!(parse_silence_payload(&oversized), None);
+        assert_eq!(parse_silence_payload(&silence_payload(2, u32::MAX)), None);
+    }
+
+    #[test]
+    fn uncompressed_packets_decode_to_interleaved_samples() {
+        let pcm = pcm_payload(2, &[1, -1, 2, -2]);
+        assert_eq!(
+            decode_uncompressed(Codec::Pcm, &pcm),
+            Some((2, vec![1, -1, 2, -2]))
+        );
+        let silence = silence_payload(2, 480);
+        assert_eq!(
+            decode_uncompressed(Codec::Silence, &silence),
+            Some((2, vec![0; 960]))
+        );
+        assert_eq!(decode_uncompressed(Codec::Opus, &pcm), None);
+    }
+
+    #[test]
+    fn surround_mapping_covers_every_decoded_channel() {
+        let mut decoded = SURROUND_MAPPING;
+        decoded.sort();
+        assert_eq!(decoded, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            (SURROUND_STREAMS + SURROUND_COUPLED_STREAMS) as usize,
+            SURROUND_MAPPING.len()
+        );
+        assert_eq!(
+            Codec::try_from(Codec::OpusSurround as u8),
+            Ok(Codec::OpusSurround)
+        );
+        assert!("opus surround".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn codecs_round_trip_through_names() {
+        for codec in [Codec::Opus, Codec::Flac, Codec::Pcm, Codec::Aac] {
+            assert_eq!(codec.to_string().parse(), Ok(codec));
+            assert_eq!(Codec::try_from(codec as u8), Ok(codec));
+        }
+        assert_eq!(codecs_query(&[Codec::Aac, Codec::Opus]), "codecs=aac,opus");
+    }
+
+    #[test]
+    fn aac_configs_describe_low_complexity_frames() {
+        assert_eq!(aac_audio_specific_config(48_000, 2), Some([0x11, 0x90]));
+        assert_eq!(aac_audio_specific_config(44_100, 1), Some([0x12, 0x08]));
+        assert_eq!(aac_audio_specific_config(47_999, 2), None);
+        assert_eq!(aac_frame_channels(&[0x00]), Some(1));
+        assert_eq!(aac_frame_channels(&[0x20]), Some(2));
+        assert_eq!(aac_frame_channels(&[0xE0]), None);
+    }
+
+    #[test]
+    fn flac_headers_hold_one_to_eight_channels() {
+        let header = flac_stream_header(48_000, 2).unwrap();
+        assert_eq!(header.len(), 42);
+        assert_eq!(&header[18..22], &[0x0B, 0xB8, 0x02, 0xF0]);
+        assert!(flac_stream_header(48_000, 8).is_some());
+        assert_eq!(flac_stream_header(48_000, 0), None);
+        assert_eq!(flac_stream_header(48_000, 9), None);
+        assert_eq!(flac_stream_header(0, 2), None);
+        assert_eq!(flac_stream_header(1 << 20, 2), None);
+    }
+
+    #[test]
+    fn estimates_drift_through_jitter() {
+        for drift in [-0.0005, 0.0, 0.0003] {
+            let mut estimator = DriftEstimator::default();
+            let mut noise: u32 = 5;
+            let start_us = 1_700_000_000_000_000;
+            for packet in 0..3_000u64 {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let elapsed_us = packet * 10_000;
+                // Up to 30 ms of network delay on a clock started elsewhere.
+                let client_us = 5_000_000
+                    + (elapsed_us as f64 * (1.0 + drift)) as u64
+                    + (noise >> 8) as u64 % 30_000;
+                estimator.observe(start_us + elapsed_us, client_us);
+            }
+            let error = estimator.ratio() - (1.0 + drift);
+            assert!(error.abs() < 0.00005, "drift {drift} estimated {error} off");
+        }
+    }
+
+    #[test]
+    fn drift_estimates_start_over_when_a_clock_jumps() {
+        let mut estimator = DriftEstimator::default();
+        for packet in 0..2_000u64 {
+            let elapsed_us = packet * 10_000;
+            let jump_us = if packet >= 1_500 { 3_000_000 } else { 0 };
+            estimator.observe(elapsed_us, (elapsed_us as f64 * 1.001) as u64 + jump_us);
+        }
+        // Only the 5 buckets since the jump count, too few to estimate from.
+        assert_eq!(estimator.ratio(), 1.0);
+    }
+
+    #[test]
+    fn reconnect_delays_double_up_to_the_maximum() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..9)
+            .map(|_| backoff.next_delay().as_millis() as u64)
+            .collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 16_000, 30_000, 30_000, 30_000]
+        );
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), FIRST_RECONNECT_DELAY);
+    }
+
+    #[test]
+    fn tracks_gaps_and_late_packets_across_wraparound() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.track(u32::MAX - 1), SequenceEvent::InOrder);
+        assert_eq!(tracker.track(u32::MAX), SequenceEvent::InOrder);
+        assert_eq!(tracker.track(2), SequenceEvent::Gap(2));
+        assert_eq!(tracker.track(1), SequenceEvent::Late);
+        assert_eq!(tracker.track(3), SequenceEvent::InOrder);
+    }
+}
@@ -0,0 +1,212 @@
+//! Runs the server's pipeline without PipeWire, feeding it audio in its
+//! place, and a headless client for it.
+use anyhow::{Context, Result, bail};
+use pipewire_streaming::capture::SinkOutputs;
+use pipewire_streaming::config::Config;
+use pipewire_streaming::encode::compress::CapturedAudio;
+use pipewire_streaming::pipeline::Pipeline;
+use std::f32::consts::TAU;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use streaming_protocol::{self as protocol, ControlMessage, PacketHeader};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::task::JoinHandle;
+use wtransport::error::StreamReadExactError;
+use wtransport::tls::Sha256Digest;
+use wtransport::{ClientConfig, Connection, Endpoint, Identity, RecvStream};
+
+pub const ACCESS_KEY: &str = "end-to-end";
+/// Frames handed over at a time, PipeWire's usual quantum.
+pub const QUANTUM: usize = 1024;
+
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is after the Unix epoch")
+        .as_micros() as u64
+}
+
+/// A port nothing listens on, as far as the OS knows.
+fn free_port() -> u16 {
+    let socket = UdpSocket::bind("[::]:0").expect("Couldn't bind a UDP socket");
+    socket.local_addr().unwrap().port()
+}
+
+/// A server whose sinks are fed by the test rather than PipeWire.
+pub struct TestServer {
+    pub config: Arc<Config>,
+    pub sinks: Vec<SinkOutputs>,
+    pub certificate_hash: Sha256Digest,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    /// Starts the pipeline with a self-signed certificate and free ports,
+    /// plus `args` on the command line, and waits until clients can connect.
+    pub async fn start(args: &[&str]) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("end-to-end-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir)?;
+        let identity = Identity::self_signed(["localhost", "127.0.0.1", "::1"])?;
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        identity.certificate_chain().store_pemfile(&cert).await?;
+        identity.private_key().store_secret_pemfile(&key).await?;
+        let (webtransport_port, http_port) = (free_port().to_string(), free_port().to_string());
+        let mut all_args = vec![
+            "pwtester",
+            "--cert",
+            cert.to_str().unwrap(),
+            "--key",
+            key.to_str().unwrap(),
+            "--webtransport-port",
+            &webtransport_port,
+            "--http-port",
+            &http_port,
+            "--access-key",
+            ACCESS_KEY,
+            "--no-mdns",
+        ];
+        all_args.extend(args);
+        let (config, _) = Config::load(&all_args.iter().map(Into::into).collect::<Vec<_>>())?;
+        let config = Arc::new(config);
+
+        let mut pipeline = Pipeline::new(&config, None, &tokio::runtime::Handle::current());
+        let sinks = std::mem::take(&mut pipeline.sinks)
+            .into_iter()
+            .map(|(_, outputs)| outputs)
+            .collect();
+        let health = pipeline.health.clone();
+        pipeline
+            .serve(&config, Arc::new(Mutex::new(Vec::new())))
+            .await;
+        while !health.report().webtransport_listening {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Ok(Self {
+            certificate_hash: identity.certificate_chain().as_slice()[0].hash(),
+            config,
+            sinks,
+            dir,
+        })
+    }
+
+    /// Feeds `sink` a sine wave of `freq_hz` at half of full scale in every
+    /// channel, a quantum at a time as PipeWire would, captured from now on.
+    pub fn play_sine(&self, sink: usize, freq_hz: f32) -> JoinHandle<()> {
+        let outputs = self.sinks[sink].clone();
+        let channels = self.config.stream_channels() as usize;
+        let rate = self.config.sample_rate;
+        tokio::spawn(async move {
+            let quantum = Duration::from_secs_f64(QUANTUM as f64 / rate as f64);
+            // Each quantum is handed over once it's all been captured.
+            let started_at_us = now_us() - quantum.as_micros() as u64;
+            let mut ticker = tokio::time::interval(quantum);
+            for cycle in 0.. {
+                ticker.tick().await;
+                let first = cycle * QUANTUM;
+                let samples = (first..first + QUANTUM)
+                    .flat_map(|frame| {
+                        let phase = (frame as f64 * freq_hz as f64 / rate as f64).fract() as f32;
+                        let sample = ((phase * TAU).sin() * i16::MAX as f32 / 2.0) as i16;
+                        std::iter::repeat_n(sample, channels)
+                    })
+                    .collect();
+                outputs.send(CapturedAudio {
+                    captured_at_us: started_at_us + first as u64 * 1_000_000 / rate as u64,
+                    samples,
+                    surround: Vec::new(),
+                    gap_us: 0,
+                });
+            }
+        })
+    }
+
+    /// Connects a client to `sink`, on the root path if `None`.
+    pub async fn connect(&self, sink: Option<&str>) -> Result<TestClient> {
+        let client_config = ClientConfig::builder()
+            .with_bind_default()
+            .with_server_certificate_hashes([self.certificate_hash.clone()])
+            .build();
+        let endpoint = Endpoint::client(client_config)?;
+        let expires_at = now_us() / 1_000_000 + protocol::TOKEN_LIFETIME_SECS;
+        let url = format!(
+            "https://127.0.0.1:{}/{}?{}=opus&{}={}",
+            self.config.webtransport_port,
+            sink.unwrap_or_default(),
+            protocol::CODECS_QUERY_KEY,
+            protocol::TOKEN_QUERY_KEY,
+            protocol::session_token(ACCESS_KEY, expires_at)
+        );
+        let connection = endpoint.connect(&url).await?;
+        // The streams are opened in order but may be accepted in any.
+        let (mut control, mut media) = (None, None);
+        while control.is_none() || media.is_none() {
+            let mut stream = connection.accept_uni().await?;
+            let mut kind = [0];
+            stream.read_exact(&mut kind).await?;
+            match kind[0] {
+                protocol::STREAM_CONTROL => control = Some(stream),
+                protocol::STREAM_MEDIA => media = Some(stream),
+                other => bail!("Server opened a stream of unknown kind {}", other),
+            }
+        }
+        let mut control = BufReader::new(control.unwrap()).lines();
+        let info = loop {
+            match next_message(&mut control).await? {
+                info @ ControlMessage::StreamInfo { .. } => break info,
+                _ => continue,
+            }
+        };
+        Ok(TestClient {
+            _connection: connection,
+            info,
+            media: media.unwrap(),
+        })
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn next_message(lines: &mut Lines<BufReader<RecvStream>>) -> Result<ControlMessage> {
+    let line = lines.next_line().await?.context("Control stream ended")?;
+    Ok(ControlMessage::decode(line.as_bytes())?)
+}
+
+/// A session with the test server.
+pub struct TestClient {
+    _connection: Connection,
+    /// The `StreamInfo` the session started with.
+    pub info: ControlMessage,
+    media: RecvStream,
+}
+
+/// A packet off the media stream, and when it arrived.
+pub struct ReceivedPacket {
+    pub header: PacketHeader,
+    pub payload: Vec<u8>,
+    pub received_at_us: u64,
+}
+
+impl TestClient {
+    pub async fn next_packet(&mut self) -> Result<ReceivedPacket> {
+        let mut header = [0; protocol::HEADER_LEN];
+        match self.media.read_exact(&mut header).await {
+            Ok(()) => {}
+            Err(StreamReadExactError::FinishedEarly(_)) => bail!("Media stream ended"),
+            Err(e) => return Err(e.into()),
+        }
+        let header = PacketHeader::decode(&header)?;
+        let mut payload = vec![0; header.payload_len as usize];
+        self.media.read_exact(&mut payload).await?;
+        Ok(ReceivedPacket {
+            header,
+            payload,
+            received_at_us: now_us(),
+        })
+    }
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::TestServer;
+use std::time::Duration;
+use streaming_protocol::{Codec, ControlMessage};
+
+/// Packets received, a second of audio at the default 10 ms frames.
+const PACKETS: usize = 100;
+/// Packets left out of the checks while the decoder settles and the client
+/// catches up with the startup burst.
+const SETTLE_PACKETS: usize = 20;
+
+/// Frequency of `samples` of one channel, from its upward zero crossings.
+fn frequency(samples: &[i16], rate: u32) -> f64 {
+    let crossings = samples
+        .windows(2)
+        .filter(|pair| pair[0] < 0 && pair[1] >= 0)
+        .count();
+    crossings as f64 * rate as f64 / samples.len() as f64
+}
+
+fn rms(samples: &[i16]) -> f64 {
+    let sum: f64 = samples.iter().map(|&sample| (sample as f64).powi(2)).sum();
+    (sum / samples.len() as f64).sqrt()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn captured_sine_arrives_decodable_and_on_time() {
+    let server = TestServer::start(&[]).await.unwrap();
+    let _player = server.play_sine(0, 440.0);
+    let mut client = server.connect(None).await.unwrap();
+    let ControlMessage::StreamInfo {
+        codec,
+        sample_rate,
+        channels,
+        frame_samples,
+        datagrams,
+        ..
+    } = client.info
+    else {
+        unreachable!("Clients start with the stream info");
+    };
+    assert_eq!((codec, channels, datagrams), (Codec::Opus, 2, false));
+    let mut decoder = opus::Decoder::new(sample_rate, opus::Channels::Stereo).unwrap();
+    let frame_us = frame_samples as u64 * 1_000_000 / sample_rate as u64;
+
+    let mut left = Vec::new();
+    let mut previous = None;
+    let mut output = vec![0; frame_samples as usize * channels as usize];
+    for received in 0..PACKETS {
+        let packet = tokio::time::timeout(Duration::from_secs(5), client.next_packet())
+            .await
+            .expect("Audio keeps coming")
+            .unwrap();
+        let header = packet.header;
+        assert_eq!(header.codec, Codec::Opus);
+        assert_eq!(header.frame_samples, frame_samples);
+        if header.decoder_reset {
+            decoder.reset_state().unwrap();
+        }
+        let decoded = decoder.decode(&packet.payload, &mut output, false).unwrap();
+        assert_eq!(decoded, frame_samples as usize);
+        if let Some((sequence, captured_at_us)) = previous {
+            assert_eq!(header.sequence, sequence + 1, "No packet goes missing");
+            let step: u64 = header.captured_at_us - captured_at_us;
+            assert!(step.abs_diff(frame_us) <= 1, "Frames are {step} us apart");
+        }
+        previous = Some((header.sequence, header.captured_at_us));
+        if received < SETTLE_PACKETS {
+            continue;
+        }
+        // Encoding and sending take a frame or so, plus a quantum of capture.
+        let latency_us = packet.received_at_us - header.captured_at_us;
+        assert!(
+            latency_us < 250_000,
+            "Packet {} arrived {} ms after capture",
+            header.sequence,
+            latency_us / 1000
+        );
+        left.extend(output[..decoded * 2].iter().step_by(2));
+    }
+
+    let freq = frequency(&left, sample_rate);
+    assert!((freq - 440.0).abs() < 5.0, "Decoded a {freq} Hz tone");
+    // Half of full scale, as played.
+    let expected_rms = i16::MAX as f64 / 2.0 / 2f64.sqrt();
+    let level = rms(&left) / expected_rms;
+    assert!(
+        (0.8..1.2).contains(&level),
+        "Decoded at {level} of the level played"
+    );
+}